@@ -0,0 +1,8 @@
+#!/usr/bin/env sh
+# miri-test.sh runs the codec and mmap unit tests under miri, see the
+# `miri-test` alias in .cargo/config.toml. The mmap tests create files, which
+# the isolation of miri forbids.
+set -e
+
+cd "$(dirname "$0")/.."
+MIRIFLAGS="${MIRIFLAGS} -Zmiri-disable-isolation" exec cargo +nightly miri-test "$@"
@@ -0,0 +1,262 @@
+//! common is shared by the binaries: the exit codes of a failure and how the
+//! error is printed.
+//!
+//! The exit codes are a contract for the tools wrapping the binaries:
+//!
+//! | code | kind          | failure                                                |
+//! |------|---------------|--------------------------------------------------------|
+//! | 0    |               | none                                                   |
+//! | 1    | `error`       | any other failure                                      |
+//! | 2    | `usage`       | invalid arguments                                      |
+//! | 3    | `not_found`   | a file, key or series which does not exist             |
+//! | 4    | `corrupt`     | a damaged file or malformed input                      |
+//! | 5    | `io`          | an error of the file system or the storage             |
+//! | 6    | `unsupported` | a format, version or limit the binary does not support |
+
+use std::process::ExitCode;
+
+use clap::ValueEnum;
+use common_base::point::{KeyTooLong, ParseError};
+use influxdb_storage::opendal;
+use influxdb_tsdb::engine::tsm1::file_store::reader::block_reader::BlockReadError;
+use influxdb_tsdb::engine::tsm1::file_store::reader::tsm_reader::TSMFileError;
+use serde::{Deserialize, Serialize};
+
+/// ErrorKind classifies the failure of a binary, see `error_kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    Usage,
+    NotFound,
+    Corrupt,
+    Io,
+    Unsupported,
+}
+
+impl ErrorKind {
+    /// code returns the exit code of the process.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::Usage => 2,
+            Self::NotFound => 3,
+            Self::Corrupt => 4,
+            Self::Io => 5,
+            Self::Unsupported => 6,
+        }
+    }
+
+    /// name returns the `kind` of the JSON error.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Other => "error",
+            Self::Usage => "usage",
+            Self::NotFound => "not_found",
+            Self::Corrupt => "corrupt",
+            Self::Io => "io",
+            Self::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// UsageError is an invalid combination of arguments, which clap can not check.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct UsageError(pub String);
+
+/// NotFound is something the arguments name which does not exist, e.g. a key.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} not found")]
+pub struct NotFound(pub String);
+
+/// error_kind classifies err by the first error of its chain of causes which
+/// is known.
+pub fn error_kind(err: &anyhow::Error) -> ErrorKind {
+    for cause in err.chain() {
+        if cause.is::<UsageError>() || cause.is::<clap::Error>() {
+            return ErrorKind::Usage;
+        }
+        if cause.is::<NotFound>() {
+            return ErrorKind::NotFound;
+        }
+        if let Some(e) = cause.downcast_ref::<TSMFileError>() {
+            return if e.is_corruption() {
+                ErrorKind::Corrupt
+            } else {
+                ErrorKind::Unsupported
+            };
+        }
+        if cause.is::<BlockReadError>() || cause.is::<ParseError>() {
+            return ErrorKind::Corrupt;
+        }
+        if cause.is::<KeyTooLong>() {
+            return ErrorKind::Unsupported;
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                    ErrorKind::Corrupt
+                }
+                _ => ErrorKind::Io,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<opendal::Error>() {
+            return match e.kind() {
+                opendal::ErrorKind::NotFound => ErrorKind::NotFound,
+                opendal::ErrorKind::Unsupported => ErrorKind::Unsupported,
+                _ => ErrorKind::Io,
+            };
+        }
+    }
+
+    ErrorKind::Other
+}
+
+/// ErrorFormat is how a failure is printed to stderr.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// Text prints the error and its causes.
+    #[default]
+    Text,
+    /// Json prints an `ErrorReport` object.
+    Json,
+}
+
+impl ErrorFormat {
+    /// from_args returns the `--error-format` of the command line args, looked up
+    /// without parsing them so that the errors of the parsing are formatted too.
+    pub fn from_args(args: &[String]) -> Self {
+        let mut format = None;
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(value) = arg.strip_prefix("--error-format=") {
+                format = Some(value);
+            } else if arg == "--error-format" {
+                format = args.get(i + 1).map(|x| x.as_str());
+            }
+        }
+
+        format
+            .and_then(|x| Self::from_str(x, true).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// ErrorReport is the JSON object printed for a failure with `--error-format json`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ErrorReport {
+    /// code is the exit code.
+    pub code: u8,
+    /// kind names the class of the failure, see `ErrorKind::name`.
+    pub kind: &'static str,
+    /// message is the error.
+    pub message: String,
+    /// path is the file the binary was working on, if known.
+    pub path: Option<String>,
+    /// detail is the error with all its causes.
+    pub detail: String,
+}
+
+impl ErrorReport {
+    pub fn new(err: &anyhow::Error, path: Option<&str>) -> Self {
+        let kind = error_kind(err);
+        Self {
+            code: kind.code(),
+            kind: kind.name(),
+            message: err.to_string(),
+            path: path.map(|x| x.to_string()),
+            detail: format!("{:#}", err),
+        }
+    }
+}
+
+/// parse_args parses the command line args into a config. Help and version
+/// requests are printed and return None, invalid args are an error of kind `usage`.
+pub fn parse_args<C: clap::Parser>(args: &[String]) -> anyhow::Result<Option<C>> {
+    match C::try_parse_from(args) {
+        Ok(config) => Ok(Some(config)),
+        Err(e) if e.use_stderr() => Err(e.into()),
+        Err(e) => {
+            e.print()?;
+            Ok(None)
+        }
+    }
+}
+
+/// exit prints the failure of a binary to stderr in format and returns its
+/// exit code.
+pub fn exit(result: anyhow::Result<()>, format: ErrorFormat, path: Option<&str>) -> ExitCode {
+    let err = match result {
+        Ok(()) => return ExitCode::SUCCESS,
+        Err(e) => e,
+    };
+
+    let report = ErrorReport::new(&err, path);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {:?}", err),
+        ErrorFormat::Json => eprintln!("{}", serde_json::to_string(&report).unwrap()),
+    }
+    ExitCode::from(report.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error_kind, ErrorFormat, ErrorKind, ErrorReport, NotFound, UsageError};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let err = anyhow::Error::new(UsageError("--a and --b are exclusive".to_string()));
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+
+        // the first known cause classifies the error
+        let err = anyhow::Error::new(NotFound("key cpu".to_string())).context("dump");
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(error_kind(&err), ErrorKind::Io);
+
+        assert_eq!(error_kind(&anyhow::anyhow!("boom")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_error_report() {
+        let err = anyhow::Error::new(NotFound("key cpu".to_string())).context("dump");
+        let report = ErrorReport::new(&err, Some("/data/1.tsm"));
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "code": 3,
+                "kind": "not_found",
+                "message": "dump",
+                "path": "/data/1.tsm",
+                "detail": "dump: key cpu not found",
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_format_from_args() {
+        assert_eq!(
+            ErrorFormat::from_args(&args(&["tsm", "--path", "a"])),
+            ErrorFormat::Text
+        );
+        assert_eq!(
+            ErrorFormat::from_args(&args(&["tsm", "--error-format", "json"])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            ErrorFormat::from_args(&args(&["tsm", "--error-format=json", "--bad"])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            ErrorFormat::from_args(&args(&["tsm", "--error-format", "yaml"])),
+            ErrorFormat::Text
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use common_base::point::DEFAULT_MAX_SERIES_KEY_LENGTH;
+use influxdb_binaries::{exit, parse_args, ErrorFormat, UsageError};
+use influxdb_tsdb::engine::tsm1::line_protocol::import_lp_file;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Parser)]
+#[clap(about, version, author)]
+struct Config {
+    /// path of the line protocol file, `-` for stdin.
+    #[clap(long)]
+    pub path: String,
+
+    /// output is the TSM file written, it must not exist.
+    #[clap(long)]
+    pub output: String,
+
+    /// max_series_key_length rejects the import if a series key is longer.
+    #[clap(long, default_value_t = DEFAULT_MAX_SERIES_KEY_LENGTH)]
+    pub max_series_key_length: usize,
+
+    /// error_format of the error printed to stderr on failure.
+    #[clap(long, value_enum, default_value_t = ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let format = ErrorFormat::from_args(&args);
+    let config = match parse_args::<Config>(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => return ExitCode::SUCCESS,
+        Err(e) => return exit(Err(e), format, None),
+    };
+
+    exit(run(&config).await, format, Some(config.path.as_str()))
+}
+
+/// run imports the line protocol file of config.
+async fn run(config: &Config) -> anyhow::Result<()> {
+    if config.path.is_empty() || config.output.is_empty() {
+        return Err(UsageError("path and output MUST not be empty!".to_string()).into());
+    }
+
+    let mut lines = String::new();
+    if config.path == "-" {
+        tokio::io::stdin().read_to_string(&mut lines).await?;
+    } else {
+        tokio::fs::File::open(config.path.as_str())
+            .await?
+            .read_to_string(&mut lines)
+            .await?;
+    }
+
+    let summary = import_lp_file(
+        lines.as_str(),
+        config.max_series_key_length,
+        config.output.as_str(),
+    )
+    .await?;
+
+    println!(
+        "imported {} keys, {} values in {} blocks",
+        summary.keys, summary.points, summary.blocks
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_binaries::{error_kind, parse_args, ErrorKind, ErrorReport};
+
+    use crate::{run, Config};
+
+    fn config(args: &[&str]) -> anyhow::Result<Config> {
+        let mut cmd = vec!["influxdb-tsdb-import".to_string()];
+        cmd.extend(args.iter().map(|x| x.to_string()));
+        Ok(parse_args::<Config>(&cmd)?.unwrap())
+    }
+
+    async fn run_args(args: &[&str]) -> anyhow::Result<()> {
+        run(&config(args)?).await
+    }
+
+    #[tokio::test]
+    async fn test_import_error_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.lp");
+        let input = input.to_str().unwrap();
+        let output = dir.path().join("000000001-000000001.tsm");
+        let output = output.to_str().unwrap();
+
+        std::fs::write(input, "cpu,host=a value=1 1\n").unwrap();
+        run_args(&["--path", input, "--output", output])
+            .await
+            .unwrap();
+
+        let err = run_args(&["--path", input]).await.unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+        let err = run_args(&["--path", "", "--output", output])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+
+        let missing = dir.path().join("missing.lp");
+        let err = run_args(&["--path", missing.to_str().unwrap(), "--output", output])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+
+        let output = dir.path().join("000000002-000000001.tsm");
+        let output = output.to_str().unwrap();
+        std::fs::write(input, "cpu,host=a value=\n").unwrap();
+        let err = run_args(&["--path", input, "--output", output])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Corrupt);
+
+        std::fs::write(input, "cpu,host=a value=1 1\n").unwrap();
+        let err = run_args(&[
+            "--path",
+            input,
+            "--output",
+            output,
+            "--max-series-key-length",
+            "4",
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Unsupported);
+
+        let report = serde_json::to_value(ErrorReport::new(&err, Some(input))).unwrap();
+        assert_eq!(report["code"], 6);
+        assert_eq!(report["kind"], "unsupported");
+        assert_eq!(report["path"], input);
+        assert!(report["message"].is_string());
+        assert!(report["detail"].is_string());
+    }
+}
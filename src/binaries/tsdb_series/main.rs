@@ -0,0 +1,218 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use common_base::iterator::AsyncIterator;
+use influxdb_binaries::{exit, parse_args, ErrorFormat, NotFound, UsageError};
+use influxdb_storage::StorageOperator;
+use influxdb_tsdb::series::series_file::SeriesFile;
+use influxdb_tsdb::series::series_segment::{split_series_offset, SeriesSegmentStats};
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Parser)]
+#[clap(about, version, author)]
+struct Config {
+    /// path of the series file directory, holding the partitions `00`, `01`, ...
+    /// of segments `0000`, `0001`, ...
+    #[clap(long)]
+    pub path: String,
+
+    /// measurement only prints the entries inserting a series of this measurement.
+    #[clap(long, conflicts_with_all = ["id", "stats"])]
+    pub measurement: Option<String>,
+
+    /// id prints the last entry of a single series.
+    #[clap(long, conflicts_with = "stats")]
+    pub id: Option<u64>,
+
+    /// stats prints the number of entries and tombstones of every segment of
+    /// every partition.
+    #[clap(long)]
+    pub stats: bool,
+
+    /// error_format of the error printed to stderr on failure.
+    #[clap(long, value_enum, default_value_t = ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let format = ErrorFormat::from_args(&args);
+    let config = match parse_args::<Config>(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => return ExitCode::SUCCESS,
+        Err(e) => return exit(Err(e), format, None),
+    };
+
+    exit(run(&config).await, format, Some(config.path.as_str()))
+}
+
+/// run dispatches the command selected by config.
+async fn run(config: &Config) -> anyhow::Result<()> {
+    if config.path.is_empty() {
+        return Err(UsageError("path MUST not be empty!".to_string()).into());
+    }
+
+    let path = std::fs::canonicalize(config.path.as_str())?;
+    let op = StorageOperator::root(path.to_string_lossy().as_ref())?;
+    let file = SeriesFile::open(op).await?;
+
+    if config.stats {
+        stats(&file).await
+    } else if let Some(id) = config.id {
+        find_series(&file, id).await
+    } else {
+        dump(&file, config.measurement.as_ref().map(|x| x.as_bytes())).await
+    }
+}
+
+async fn dump(file: &SeriesFile, measurement: Option<&[u8]>) -> anyhow::Result<()> {
+    for partition in file.partitions() {
+        let mut itr = partition.series_iterator().await?;
+        let mut i = 0;
+        while let Some((entry, offset, size)) = itr.try_next().await? {
+            if measurement.is_none() || entry.measurement() == measurement {
+                let (segment_id, pos) = split_series_offset(offset);
+                println!(
+                    "{:02}/{:04}:{:06}>{:?} @ {}, {}",
+                    partition.id(),
+                    segment_id,
+                    i,
+                    entry,
+                    pos,
+                    size
+                );
+            }
+            i += 1;
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_series(file: &SeriesFile, id: u64) -> anyhow::Result<()> {
+    let partition = file.series_id_partition(id);
+    match (partition, file.find_series(id).await?) {
+        (Some(partition), Some((entry, offset))) => {
+            let (segment_id, pos) = split_series_offset(offset);
+            println!(
+                "{:02}/{:04}>{:?} @ {}",
+                partition.id(),
+                segment_id,
+                entry,
+                pos
+            );
+            Ok(())
+        }
+        _ => Err(NotFound(format!("series {}", id)).into()),
+    }
+}
+
+async fn stats(file: &SeriesFile) -> anyhow::Result<()> {
+    let mut total = SeriesSegmentStats::default();
+    let mut segment_n = 0;
+    for partition in file.partitions() {
+        let segments = partition.segments().await;
+        for segment in segments.iter() {
+            let stats = segment.stats().await?;
+            println!(
+                "{:02}/{:04}: version {:?}, size {}, entries {}, tombstones {}",
+                partition.id(),
+                segment.id(),
+                segment.version(),
+                segment.size(),
+                stats.entries(),
+                stats.tombstones
+            );
+            total.inserts += stats.inserts;
+            total.tombstones += stats.tombstones;
+        }
+        segment_n += segments.len();
+    }
+    println!(
+        "total: partitions {}, segments {}, entries {}, tombstones {}",
+        file.partitions().len(),
+        segment_n,
+        total.entries(),
+        total.tombstones
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_binaries::{error_kind, parse_args, ErrorKind, ErrorReport};
+    use influxdb_storage::StorageOperator;
+    use influxdb_tsdb::series::series_file::SeriesFile;
+
+    use crate::{run, Config};
+
+    fn config(args: &[&str]) -> anyhow::Result<Config> {
+        let mut cmd = vec!["influxdb-tsdb-series".to_string()];
+        cmd.extend(args.iter().map(|x| x.to_string()));
+        Ok(parse_args::<Config>(&cmd)?.unwrap())
+    }
+
+    async fn run_args(args: &[&str]) -> anyhow::Result<()> {
+        run(&config(args)?).await
+    }
+
+    #[tokio::test]
+    async fn test_series_error_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("series");
+        let path = path.to_str().unwrap();
+        let (id, partition_id) = {
+            let file = SeriesFile::open(StorageOperator::root(path).unwrap())
+                .await
+                .unwrap();
+            let id = file
+                .create_series_if_not_exists(b"cpu,host=a")
+                .await
+                .unwrap();
+            let partition_id = file.series_id_partition(id).unwrap().id();
+            file.close().await.unwrap();
+            (id.to_string(), partition_id)
+        };
+
+        run_args(&["--path", path, "--id", id.as_str()])
+            .await
+            .unwrap();
+
+        let err = run_args(&["--path", path, "--id", id.as_str(), "--stats"])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+
+        let err = run_args(&["--path", path, "--id", "1000"])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+        let missing = dir.path().join("missing");
+        let err = run_args(&["--path", missing.to_str().unwrap(), "--stats"])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+
+        // an insert entry cut short after its flag
+        let segment = dir
+            .path()
+            .join("series")
+            .join(format!("{:02}", partition_id))
+            .join("0000");
+        let mut data = std::fs::read(&segment).unwrap();
+        data.extend_from_slice(&[0x01, 0, 0]);
+        std::fs::write(&segment, data).unwrap();
+        let err = run_args(&["--path", path, "--stats"]).await.unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Corrupt);
+
+        let report = serde_json::to_value(ErrorReport::new(&err, Some(path))).unwrap();
+        assert_eq!(report["code"], 4);
+        assert_eq!(report["kind"], "corrupt");
+        assert_eq!(report["path"], path);
+        assert!(report["message"].is_string());
+        assert!(report["detail"].is_string());
+    }
+}
@@ -1,4 +1,442 @@
+use std::process::ExitCode;
+
+use clap::{Parser, ValueEnum};
+use common_base::iterator::AsyncIterator;
+use influxdb_binaries::{exit, parse_args, ErrorFormat, NotFound, UsageError};
+use influxdb_storage::StorageOperator;
+use influxdb_tsdb::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+use influxdb_tsdb::engine::tsm1::export::export_parquet;
+use influxdb_tsdb::engine::tsm1::file_store::file_store::new_values;
+use influxdb_tsdb::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use influxdb_tsdb::engine::tsm1::file_store::reader::tsm_reader::new_default_tsm_reader;
+use influxdb_tsdb::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+use influxdb_tsdb::engine::tsm1::file_store::TimeRange;
+use influxdb_tsdb::engine::tsm1::line_protocol::export_lp;
+use influxdb_tsdb::engine::tsm1::value::Values;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Table prints tab separated columns.
+    Table,
+    /// Json prints one JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Parser)]
+#[clap(about, version, author)]
+struct Config {
+    /// path of the TSM file.
+    #[clap(long)]
+    pub path: String,
+
+    /// key dumps the values of a single key, e.g. `cpu,host=a#!~#value`.
+    #[clap(long)]
+    pub key: Option<String>,
+
+    /// dump_all dumps the values of every key of the file.
+    #[clap(long)]
+    pub dump_all: bool,
+
+    /// list_keys prints the keys of the file.
+    #[clap(long)]
+    pub list_keys: bool,
+
+    /// dump_index prints the index entries of every key, or of `--key`.
+    #[clap(long)]
+    pub dump_index: bool,
+
+    /// time_range restricts the values and index entries dumped to [min, max].
+    #[clap(long, num_args = 2, value_names = ["MIN", "MAX"], allow_negative_numbers = true)]
+    pub time_range: Option<Vec<i64>>,
+
+    /// format of the output.
+    #[clap(long, value_enum, default_value_t = Format::Table)]
+    pub format: Format,
+
+    /// export_parquet writes the values of the file as Parquet files, one per
+    /// value type, into this directory.
+    #[clap(long)]
+    pub export_parquet: Option<String>,
+
+    /// export_lp writes the values of the file as line protocol into this file,
+    /// `-` for stdout.
+    #[clap(long)]
+    pub export_lp: Option<String>,
+
+    /// error_format of the error printed to stderr on failure.
+    #[clap(long, value_enum, default_value_t = ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+}
+
+/// Command is what the tool was asked to do, selected by the flags of `Config`.
+enum Command {
+    Info,
+    ListKeys,
+    DumpIndex(Option<Vec<u8>>),
+    Dump(Option<Vec<u8>>),
+    ExportParquet(String),
+    ExportLp(String),
+}
+
+impl Config {
+    fn command(&self) -> anyhow::Result<Command> {
+        let key = self.key.as_ref().map(|x| x.as_bytes().to_vec());
+        let mut commands = vec![];
+        if self.list_keys {
+            commands.push(Command::ListKeys);
+        }
+        if self.dump_index {
+            commands.push(Command::DumpIndex(key.clone()));
+        }
+        if !self.dump_index && (key.is_some() || self.dump_all) {
+            if key.is_some() && self.dump_all {
+                return Err(UsageError("--key and --dump-all are exclusive".to_string()).into());
+            }
+            commands.push(Command::Dump(key));
+        }
+        if let Some(out) = &self.export_parquet {
+            commands.push(Command::ExportParquet(out.clone()));
+        }
+        if let Some(out) = &self.export_lp {
+            commands.push(Command::ExportLp(out.clone()));
+        }
+
+        match commands.len() {
+            0 => Ok(Command::Info),
+            1 => Ok(commands.pop().unwrap()),
+            _ => Err(UsageError(
+                "only one of --key/--dump-all, --list-keys, --dump-index, --export-parquet and --export-lp can be given".to_string()
+            ).into()),
+        }
+    }
+
+    fn time_range(&self) -> TimeRange {
+        match self.time_range.as_deref() {
+            Some([min, max]) => TimeRange::new(*min, *max),
+            _ => TimeRange::unbound(),
+        }
+    }
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let format = ErrorFormat::from_args(&args);
+    let config = match parse_args::<Config>(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => return ExitCode::SUCCESS,
+        Err(e) => return exit(Err(e), format, None),
+    };
+
+    exit(run(&config).await, format, Some(config.path.as_str()))
+}
+
+/// run dispatches the command selected by config.
+async fn run(config: &Config) -> anyhow::Result<()> {
+    if config.path.is_empty() {
+        return Err(UsageError("path MUST not be empty!".to_string()).into());
+    }
+    let command = config.command()?;
+
+    let path = std::fs::canonicalize(config.path.as_str())?;
+    let op = StorageOperator::root(path.to_string_lossy().as_ref())?;
+    let tsm_reader = new_default_tsm_reader(op).await?;
+
+    match command {
+        Command::Info => info(&tsm_reader, config.format).await,
+        Command::ListKeys => list_keys(&tsm_reader, config.format).await,
+        Command::DumpIndex(key) => {
+            let keys = selected_keys(&tsm_reader, key).await?;
+            for key in keys.iter() {
+                dump_index(&tsm_reader, key, &config.time_range(), config.format).await?;
+            }
+            Ok(())
+        }
+        Command::Dump(key) => {
+            let keys = selected_keys(&tsm_reader, key).await?;
+            for key in keys.iter() {
+                dump_values(&tsm_reader, key, &config.time_range(), config.format).await?;
+            }
+            Ok(())
+        }
+        Command::ExportParquet(out) => {
+            std::fs::create_dir_all(out.as_str())?;
+            let out = format!("{}/", std::fs::canonicalize(out)?.to_string_lossy());
+            let stats = export_parquet(&tsm_reader, &StorageOperator::root(out.as_str())?).await?;
+            for file in stats.files.iter() {
+                println!("{}", file);
+            }
+            println!("exported {} keys, {} values", stats.keys, stats.rows);
+            Ok(())
+        }
+        Command::ExportLp(out) => {
+            let stats = if out == "-" {
+                export_lp(&tsm_reader, &mut tokio::io::stdout()).await?
+            } else {
+                let f = tokio::fs::File::create(out).await?;
+                export_lp(&tsm_reader, &mut tokio::io::BufWriter::new(f)).await?
+            };
+            // stdout may hold the exported lines
+            eprintln!("exported {} keys, {} values", stats.keys, stats.points);
+            Ok(())
+        }
+    }
+}
+
+async fn info(reader: &dyn TSMReader, format: Format) -> anyhow::Result<()> {
+    let keys = reader.key_count().await;
+    let time_range = reader.time_range().await;
+    match format {
+        Format::Table => {
+            println!("keys: {}", keys);
+            println!("time range: {} {}", time_range.min(), time_range.max());
+        }
+        Format::Json => println!(
+            "{}",
+            json!({"keys": keys, "min_time": time_range.min(), "max_time": time_range.max()})
+        ),
+    }
+    Ok(())
+}
+
+/// selected_keys returns key if it is in the file, or all keys of the file.
+async fn selected_keys(
+    reader: &dyn TSMReader,
+    key: Option<Vec<u8>>,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    if let Some(key) = key {
+        if !reader.contains(key.as_slice()).await? {
+            return Err(
+                NotFound(format!("key {}", String::from_utf8_lossy(key.as_slice()))).into(),
+            );
+        }
+        return Ok(vec![key]);
+    }
+
+    let mut keys = vec![];
+    let mut itr = reader.key_iterator().await?;
+    while let Some(key) = itr.try_next().await? {
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+async fn list_keys(reader: &dyn TSMReader, format: Format) -> anyhow::Result<()> {
+    let mut itr = reader.key_iterator().await?;
+    while let Some(key) = itr.try_next().await? {
+        let key = String::from_utf8_lossy(key.as_slice());
+        match format {
+            Format::Table => println!("{}", key),
+            Format::Json => println!("{}", json!({ "key": key })),
+        }
+    }
+    Ok(())
+}
+
+async fn dump_index(
+    reader: &dyn TSMReader,
+    key: &[u8],
+    time_range: &TimeRange,
+    format: Format,
+) -> anyhow::Result<()> {
+    let mut entries = IndexEntries::default();
+    reader.read_entries(key, &mut entries).await?;
+    let selected: Vec<IndexEntry> = entries
+        .iter()
+        .filter(|x| x.overlaps_time_range(time_range.min(), time_range.max()))
+        .collect();
+
+    let key = String::from_utf8_lossy(key);
+    match format {
+        Format::Table => {
+            for entry in selected.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    key,
+                    type_name(entries.typ),
+                    entry.min_time,
+                    entry.max_time,
+                    entry.offset,
+                    entry.size
+                );
+            }
+        }
+        Format::Json => {
+            let selected: Vec<Value> = selected
+                .iter()
+                .map(|x| {
+                    json!({
+                        "min_time": x.min_time,
+                        "max_time": x.max_time,
+                        "offset": x.offset,
+                        "size": x.size,
+                    })
+                })
+                .collect();
+            println!(
+                "{}",
+                json!({"key": key, "type": type_name(entries.typ), "entries": selected})
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn dump_values(
+    reader: &dyn TSMReader,
+    key: &[u8],
+    time_range: &TimeRange,
+    format: Format,
+) -> anyhow::Result<()> {
+    let mut entries = IndexEntries::default();
+    reader.read_entries(key, &mut entries).await?;
+
+    let mut values = new_values(entries.typ)?;
+    for entry in entries.iter() {
+        if !entry.overlaps_time_range(time_range.min(), time_range.max()) {
+            continue;
+        }
+        let mut block = new_values(entries.typ)?;
+        reader.read_block_at(&entry, &mut block).await?;
+        values = values.merge(block.clamp_time(time_range.clone()))?;
+    }
+    let values = json_values(values);
+
+    let key = String::from_utf8_lossy(key);
+    match format {
+        Format::Table => {
+            for (time, value) in values.iter() {
+                println!("{}\t{}\t{}", key, time, value);
+            }
+        }
+        Format::Json => {
+            let values: Vec<Value> = values
+                .into_iter()
+                .map(|(time, value)| json!({"time": time, "value": value}))
+                .collect();
+            println!(
+                "{}",
+                json!({"key": key, "type": type_name(entries.typ), "values": values})
+            );
+        }
+    }
     Ok(())
 }
+
+/// json_values returns the decoded values as JSON values, strings which are not
+/// valid utf8 are converted lossily.
+fn json_values(values: Values) -> Vec<(i64, Value)> {
+    match values {
+        Values::Float(values) => values
+            .into_iter()
+            .map(|x| (x.unix_nano, json!(x.value)))
+            .collect(),
+        Values::Integer(values) => values
+            .into_iter()
+            .map(|x| (x.unix_nano, json!(x.value)))
+            .collect(),
+        Values::Unsigned(values) => values
+            .into_iter()
+            .map(|x| (x.unix_nano, json!(x.value)))
+            .collect(),
+        Values::Bool(values) => values
+            .into_iter()
+            .map(|x| (x.unix_nano, json!(x.value)))
+            .collect(),
+        Values::String(values) => values
+            .into_iter()
+            .map(|x| (x.unix_nano, json!(String::from_utf8_lossy(&x.value))))
+            .collect(),
+    }
+}
+
+fn type_name(typ: u8) -> &'static str {
+    match typ {
+        BLOCK_FLOAT64 => "float",
+        BLOCK_INTEGER => "integer",
+        BLOCK_BOOLEAN => "boolean",
+        BLOCK_STRING => "string",
+        BLOCK_UNSIGNED => "unsigned",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_binaries::{error_kind, parse_args, ErrorKind, ErrorReport};
+    use influxdb_tsdb::engine::tsm1::line_protocol::import_lp_file;
+
+    use crate::{run, Config};
+
+    fn config(args: &[&str]) -> anyhow::Result<Config> {
+        let mut cmd = vec!["influxdb-tsdb-tsm".to_string()];
+        cmd.extend(args.iter().map(|x| x.to_string()));
+        Ok(parse_args::<Config>(&cmd)?.unwrap())
+    }
+
+    async fn run_args(args: &[&str]) -> anyhow::Result<()> {
+        run(&config(args)?).await
+    }
+
+    #[tokio::test]
+    async fn test_tsm_error_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+        import_lp_file("cpu,host=a value=1 1\ncpu,host=a value=2 2\n", 4096, path)
+            .await
+            .unwrap();
+        let data = std::fs::read(path).unwrap();
+
+        run_args(&["--path", path, "--list-keys"]).await.unwrap();
+
+        let err = run_args(&["--path", path, "--unknown"]).await.unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+        let err = run_args(&["--path", path, "--key", "cpu", "--dump-all"])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+
+        let missing = dir.path().join("missing.tsm");
+        let err = run_args(&["--path", missing.to_str().unwrap()])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+        let err = run_args(&["--path", path, "--key", "mem,host=a#!~#value"])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::NotFound);
+
+        // the footer points past the end of the file
+        let corrupt = dir.path().join("corrupt.tsm");
+        let mut footer = data.clone();
+        let len = footer.len();
+        footer[len - 8..].copy_from_slice(&(len as u64).to_be_bytes());
+        std::fs::write(&corrupt, footer).unwrap();
+        let err = run_args(&["--path", corrupt.to_str().unwrap()])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Corrupt);
+
+        let unsupported = dir.path().join("unsupported.tsm");
+        let mut version = data;
+        version[4] = 9;
+        std::fs::write(&unsupported, version).unwrap();
+        let err = run_args(&["--path", unsupported.to_str().unwrap()])
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Unsupported);
+
+        let report = serde_json::to_value(ErrorReport::new(&err, Some(path))).unwrap();
+        assert_eq!(report["code"], 6);
+        assert_eq!(report["kind"], "unsupported");
+        assert_eq!(report["message"], "file is version 9, expected 1");
+        assert_eq!(report["path"], path);
+        assert!(report["detail"].is_string());
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Sleep is the future returned by `Clock::sleep`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Clock is the source of time used for policy decisions (snapshot age, retention,
+/// rate limiting, ...). Timestamps supplied by clients never go through a Clock.
+pub trait Clock: Send + Sync + Debug {
+    /// now_nanos returns the current unix time in nanoseconds.
+    fn now_nanos(&self) -> i64;
+
+    /// now_instant returns the current monotonic instant.
+    fn now_instant(&self) -> Instant;
+
+    /// sleep returns a future that completes once the clock has advanced by d.
+    fn sleep(&self, d: Duration) -> Sleep;
+}
+
+/// SystemClock reads the wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before Unix epoch")
+            .as_nanos() as i64
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, d: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(d))
+    }
+}
+
+#[derive(Debug)]
+struct SimulatedState {
+    elapsed: Duration,
+    next_timer_id: u64,
+    /// timers are the pending sleeps by id, with their deadline.
+    timers: Vec<(u64, Duration, Waker)>,
+}
+
+/// SimulatedClock is a manually driven clock for tests. Time only moves when
+/// `advance` or `set_nanos` is called, at which point due timers are woken.
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    start_nanos: i64,
+    start_instant: Instant,
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+impl SimulatedClock {
+    pub fn new(start_nanos: i64) -> Self {
+        Self {
+            start_nanos,
+            start_instant: Instant::now(),
+            state: Arc::new(Mutex::new(SimulatedState {
+                elapsed: Duration::ZERO,
+                next_timer_id: 0,
+                timers: vec![],
+            })),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /// advance moves the clock forward by d and wakes every timer that became due.
+    pub fn advance(&self, d: Duration) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.elapsed += d;
+            let elapsed = state.elapsed;
+
+            let (due, pending) = state
+                .timers
+                .drain(..)
+                .partition::<Vec<_>, _>(|(_, deadline, _)| *deadline <= elapsed);
+            state.timers = pending;
+            due
+        };
+
+        for (_, _, waker) in due {
+            waker.wake();
+        }
+    }
+
+    /// set_nanos moves the clock to the given unix time. The clock never goes backwards.
+    pub fn set_nanos(&self, nanos: i64) {
+        let target = (nanos - self.start_nanos).max(0) as u64;
+        let elapsed = self.elapsed();
+        let target = Duration::from_nanos(target);
+        if target > elapsed {
+            self.advance(target - elapsed);
+        }
+    }
+
+    /// sleep returns a future that completes once the clock has advanced by d.
+    pub fn sleep(&self, d: Duration) -> SimulatedSleep {
+        SimulatedSleep {
+            deadline: self.elapsed() + d,
+            timer_id: None,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now_nanos(&self) -> i64 {
+        self.start_nanos + self.elapsed().as_nanos() as i64
+    }
+
+    fn now_instant(&self) -> Instant {
+        self.start_instant + self.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) -> Sleep {
+        Box::pin(SimulatedClock::sleep(self, d))
+    }
+}
+
+/// SimulatedSleep is the future returned by `SimulatedClock::sleep`. It registers
+/// a single timer, whose waker is replaced when polled again, and removes it when
+/// dropped.
+pub struct SimulatedSleep {
+    deadline: Duration,
+    timer_id: Option<u64>,
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+impl Future for SimulatedSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let timer = self
+            .timer_id
+            .and_then(|id| state.timers.iter_mut().find(|(x, _, _)| *x == id));
+        match timer {
+            Some((_, _, waker)) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let id = state.next_timer_id;
+                state.next_timer_id += 1;
+                state.timers.push((id, self.deadline, cx.waker().clone()));
+                self.timer_id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for SimulatedSleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer_id {
+            let mut state = self.state.lock().unwrap();
+            state.timers.retain(|(x, _, _)| *x != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+    use std::time::Duration;
+
+    use crate::clock::{Clock, SimulatedClock};
+
+    #[test]
+    fn test_simulated_clock_advance() {
+        let clock = SimulatedClock::new(1_000);
+        let start = clock.now_instant();
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now_nanos(), 1_000 + 60_000_000_000);
+        assert_eq!(clock.now_instant() - start, Duration::from_secs(60));
+
+        // never goes backwards
+        clock.set_nanos(0);
+        assert_eq!(clock.now_nanos(), 1_000 + 60_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_simulated_clock_sleep() {
+        let clock = SimulatedClock::new(0);
+        let fired = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let clock = clock.clone();
+            let fired = fired.clone();
+            tokio::spawn(async move {
+                clock.sleep(Duration::from_secs(3600)).await;
+                fired.store(true, Ordering::SeqCst);
+            })
+        };
+
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(1800));
+        tokio::task::yield_now().await;
+        assert!(!fired.load(Ordering::SeqCst));
+
+        clock.advance(Duration::from_secs(1800));
+        handle.await.unwrap();
+        assert!(fired.load(Ordering::SeqCst));
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_simulated_sleep_registers_once() {
+        let clock = SimulatedClock::new(0);
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sleep = Box::pin(clock.sleep(Duration::from_secs(1)));
+        for _ in 0..10 {
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(clock.state.lock().unwrap().timers.len(), 1);
+
+        drop(sleep);
+        assert!(clock.state.lock().unwrap().timers.is_empty());
+
+        let mut sleep = Box::pin(Clock::sleep(&clock, Duration::from_secs(1)));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(sleep.as_mut().poll(&mut cx).is_ready());
+        assert!(clock.state.lock().unwrap().timers.is_empty());
+    }
+}
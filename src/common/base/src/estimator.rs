@@ -0,0 +1,36 @@
+//! Cardinality estimation. The sketches live in `influxdb_utils::estimator`;
+//! this module re-exports them so there is a single `Sketch` trait.
+
+pub use influxdb_utils::estimator::*;
+
+#[cfg(test)]
+mod tests {
+    use crate::estimator::hll::Plus;
+    use crate::estimator::Sketch;
+
+    fn new_sketch<S: Sketch>(s: S) -> S {
+        s
+    }
+
+    #[test]
+    fn test_sketch_reexport() {
+        let mut s = new_sketch(Plus::new().unwrap());
+        assert_eq!(s.count(), 0);
+
+        for i in 0..1000_u32 {
+            s.add(i.to_be_bytes().as_slice());
+            // duplicates do not change the estimate
+            s.add(i.to_be_bytes().as_slice());
+        }
+
+        let count = s.count();
+        assert!((980..=1020).contains(&count), "count {}", count);
+        // count is stable between calls
+        assert_eq!(s.count(), count);
+
+        let decoded = Plus::decode(s.encode().unwrap().as_slice()).unwrap();
+        let mut merged = Plus::new().unwrap();
+        merged.merge(&decoded).unwrap();
+        assert_eq!(merged.count(), count);
+    }
+}
@@ -71,11 +71,7 @@ where
     type Item = ITEM;
 
     async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
-        if self.itrs.len() == 0 {
-            return Ok(None);
-        }
-
-        loop {
+        while self.i < self.itrs.len() {
             let itr = &mut self.itrs[self.i];
             if let Some(v) = itr.try_next().await? {
                 return Ok(Some(v));
@@ -83,5 +79,7 @@ where
 
             self.i += 1;
         }
+
+        Ok(None)
     }
 }
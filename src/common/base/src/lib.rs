@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate async_trait;
 
+pub mod clock;
+pub mod estimator;
 pub mod influxql;
 pub mod iterator;
 pub mod point;
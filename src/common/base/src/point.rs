@@ -1,6 +1,7 @@
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
-use std::str::from_utf8_unchecked;
+
+use crate::clock::{Clock, SystemClock};
 
 /// ZERO_TIME is the Unix nanosecond timestamp for no time.
 /// This time is not used by the query engine or the storage engine as a valid time.
@@ -10,9 +11,72 @@ pub const ZERO_TIME: i64 = i64::MIN;
 /// that identifies a specific field in series
 pub const KEY_FIELD_SEPARATOR: &'static str = "#!~#";
 
-pub fn series_field_key(series: &[u8], field: &[u8]) -> Vec<u8> {
-    let mut key = Vec::with_capacity(series.len() + KEY_FIELD_SEPARATOR.len() + field.len());
-    key.extend_from_slice(series);
+/// MAX_KEY_LENGTH is the max length of a TSM key, longer keys are rejected by the
+/// TSM writer.
+pub const MAX_KEY_LENGTH: usize = u16::MAX as usize;
+
+/// DEFAULT_MAX_SERIES_KEY_LENGTH is the default max length of a series key
+/// accepted by a write, well below `MAX_KEY_LENGTH` which caps it.
+pub const DEFAULT_MAX_SERIES_KEY_LENGTH: usize = 4096;
+
+/// KeyTooLong is a series or TSM key longer than the max length accepted.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error(
+    "key of measurement {} too long: {len} bytes, max {max}",
+    String::from_utf8_lossy(measurement)
+)]
+pub struct KeyTooLong {
+    pub measurement: Vec<u8>,
+    pub len: usize,
+    pub max: usize,
+}
+
+/// check_key_length returns a `KeyTooLong` if key, a series or TSM key, is longer
+/// than max.
+pub fn check_key_length(key: &[u8], max: usize) -> Result<(), KeyTooLong> {
+    if key.len() <= max {
+        return Ok(());
+    }
+
+    let sep = KEY_FIELD_SEPARATOR.as_bytes();
+    let series_key = match key.windows(sep.len()).position(|x| x == sep) {
+        Some(i) => &key[..i],
+        None => key,
+    };
+    let measurement = match split_unescaped(series_key, b',').first() {
+        Some((_, x)) => unescape(x, MEASUREMENT_ESCAPES),
+        None => vec![],
+    };
+    Err(KeyTooLong {
+        measurement,
+        len: key.len(),
+        max,
+    })
+}
+
+/// series_key returns the key `measurement,k1=v1,k2=v2` of a series, with the tags
+/// sorted by key and the special characters escaped as in the line protocol.
+pub fn series_key(measurement: &[u8], tags: &[Tag]) -> Vec<u8> {
+    let mut tags: Vec<&Tag> = tags.iter().collect();
+    tags.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut key =
+        Vec::with_capacity(measurement.len() + tags.iter().map(|x| x.size() + 2).sum::<usize>());
+    escape(&mut key, measurement, MEASUREMENT_ESCAPES);
+    for tag in tags {
+        key.push(b',');
+        escape(&mut key, tag.key.as_slice(), KEY_ESCAPES);
+        key.push(b'=');
+        escape(&mut key, tag.value.as_slice(), KEY_ESCAPES);
+    }
+    key
+}
+
+/// tsm_key returns the key `series_key#!~#field` of a field in the TSM files. The
+/// field is appended as is, see `parse_tsm_key` for the reverse.
+pub fn tsm_key(series_key: &[u8], field: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(series_key.len() + KEY_FIELD_SEPARATOR.len() + field.len());
+    key.extend_from_slice(series_key);
     key.extend_from_slice(KEY_FIELD_SEPARATOR.as_bytes());
     key.extend_from_slice(field);
     key
@@ -36,8 +100,8 @@ impl Tag {
 
 impl Debug for Tag {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let key = unsafe { from_utf8_unchecked(self.key.as_slice()) };
-        let value = unsafe { from_utf8_unchecked(self.value.as_slice()) };
+        let key = String::from_utf8_lossy(self.key.as_slice());
+        let value = String::from_utf8_lossy(self.value.as_slice());
 
         f.debug_struct("Tag")
             .field("key", &key)
@@ -66,3 +130,799 @@ impl Deref for Tags {
         self.0.as_slice()
     }
 }
+
+/// MEASUREMENT_ESCAPES are the characters escaped by a backslash in a measurement name.
+const MEASUREMENT_ESCAPES: &[u8] = b", ";
+/// KEY_ESCAPES are the characters escaped by a backslash in tag keys and values and
+/// in field keys.
+const KEY_ESCAPES: &[u8] = b",= ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Unsigned(u64),
+    Boolean(bool),
+    String(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub key: Vec<u8>,
+    pub value: FieldValue,
+}
+
+impl Field {
+    pub fn new(key: Vec<u8>, value: FieldValue) -> Self {
+        Self { key, value }
+    }
+}
+
+/// ParseError is a malformed line of line protocol, offset is the byte offset of
+/// the problem in the parsed input.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{reason} at byte {offset}")]
+pub struct ParseError {
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+/// Point is a point parsed from line protocol:
+/// `measurement[,tag=value...] field=value[,field=value...] [unix_nano]`.
+/// Tags are sorted by key.
+#[derive(Clone, Debug)]
+pub struct Point {
+    pub name: Vec<u8>,
+    pub tags: Tags,
+    pub fields: Vec<Field>,
+    pub time: i64,
+}
+
+impl Point {
+    /// parse_line parses a single line of line protocol, a point without timestamp
+    /// gets default_time. The line is taken as bytes, only string field values
+    /// must be valid utf-8.
+    pub fn parse_line(line: &[u8], default_time: i64) -> anyhow::Result<Point> {
+        let mut parser = LineParser::new(line);
+        let point = parser.parse_point(default_time)?;
+
+        parser.skip(b" \t\r\n");
+        if parser.pos < parser.buf.len() {
+            return Err(parser.error(parser.pos, "unexpected data after point"));
+        }
+        Ok(point)
+    }
+
+    /// parse_lines parses newline separated points, skipping empty lines and
+    /// comments. Points without timestamp get the current time.
+    pub fn parse_lines(lines: &str) -> anyhow::Result<Vec<Point>> {
+        Self::parse_lines_with_time(lines, SystemClock.now_nanos())
+    }
+
+    /// parse_lines_with_time is `parse_lines` with the time of the points without
+    /// timestamp.
+    pub fn parse_lines_with_time(lines: &str, default_time: i64) -> anyhow::Result<Vec<Point>> {
+        let mut parser = LineParser::new(lines.as_bytes());
+        let mut points = vec![];
+        loop {
+            parser.skip(b" \t\r\n");
+            if parser.pos >= parser.buf.len() {
+                return Ok(points);
+            }
+            if parser.buf[parser.pos] == b'#' {
+                parser.scan_until(b"\n");
+                continue;
+            }
+            points.push(parser.parse_point(default_time)?);
+        }
+    }
+
+    /// write_line appends the point to b as a line of line protocol, without the
+    /// trailing newline. The special characters are escaped so that `parse_line`
+    /// returns the same point.
+    pub fn write_line(&self, b: &mut Vec<u8>) {
+        b.extend_from_slice(series_key(self.name.as_slice(), &self.tags).as_slice());
+        for (i, field) in self.fields.iter().enumerate() {
+            b.push(if i == 0 { b' ' } else { b',' });
+            escape(b, field.key.as_slice(), KEY_ESCAPES);
+            b.push(b'=');
+            write_field_value(b, &field.value);
+        }
+        b.push(b' ');
+        b.extend_from_slice(self.time.to_string().as_bytes());
+    }
+}
+
+/// write_field_value appends a field value as written in line protocol: integers
+/// end with `i`, unsigned integers with `u` and strings are double quoted with `"`
+/// and `\` escaped.
+fn write_field_value(b: &mut Vec<u8>, value: &FieldValue) {
+    match value {
+        // the shortest representation which parses back to the same float
+        FieldValue::Float(v) => b.extend_from_slice(v.to_string().as_bytes()),
+        FieldValue::Integer(v) => b.extend_from_slice(format!("{}i", v).as_bytes()),
+        FieldValue::Unsigned(v) => b.extend_from_slice(format!("{}u", v).as_bytes()),
+        FieldValue::Boolean(v) => b.extend_from_slice(if *v { b"true" } else { b"false" }),
+        FieldValue::String(v) => {
+            b.push(b'"');
+            escape(b, v.as_bytes(), b"\"\\");
+            b.push(b'"');
+        }
+    }
+}
+
+/// LineParser parses points from line protocol, each point ends at a newline or at
+/// the end of buf.
+struct LineParser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn error(&self, offset: usize, reason: &'static str) -> anyhow::Error {
+        ParseError { offset, reason }.into()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn skip(&mut self, chars: &[u8]) {
+        while self.peek().map(|c| chars.contains(&c)).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    /// scan_until advances to the first unescaped character of stops, or a newline,
+    /// and returns the raw bytes passed over.
+    fn scan_until(&mut self, stops: &[u8]) -> &'a [u8] {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == b'\\' && self.pos + 1 < self.buf.len() && self.buf[self.pos + 1] != b'\n' {
+                self.pos += 2;
+                continue;
+            }
+            if c == b'\n' || stops.contains(&c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        &self.buf[start..self.pos]
+    }
+
+    fn parse_point(&mut self, default_time: i64) -> anyhow::Result<Point> {
+        let start = self.pos;
+        let name = self.scan_until(b", ");
+        if name.is_empty() {
+            return Err(self.error(start, "missing measurement"));
+        }
+        let name = unescape(name, MEASUREMENT_ESCAPES);
+
+        let mut tags = vec![];
+        while self.peek() == Some(b',') {
+            self.pos += 1;
+            let offset = self.pos;
+            let key = self.scan_until(b",= ");
+            if key.is_empty() {
+                return Err(self.error(offset, "missing tag key"));
+            }
+            if self.peek() != Some(b'=') {
+                return Err(self.error(self.pos, "missing tag value"));
+            }
+            self.pos += 1;
+            let value = self.scan_until(b", ");
+            if value.is_empty() {
+                return Err(self.error(self.pos, "missing tag value"));
+            }
+            tags.push((
+                offset,
+                Tag::new(unescape(key, KEY_ESCAPES), unescape(value, KEY_ESCAPES)),
+            ));
+        }
+        tags.sort_by(|(_, a), (_, b)| a.key.cmp(&b.key));
+        for pair in tags.windows(2) {
+            if pair[0].1.key == pair[1].1.key {
+                let offset = pair[0].0.max(pair[1].0);
+                return Err(self.error(offset, "duplicate tag"));
+            }
+        }
+        let tags = Tags::new(tags.into_iter().map(|(_, tag)| tag).collect());
+
+        if self.peek() != Some(b' ') {
+            return Err(self.error(self.pos, "missing fields"));
+        }
+        self.skip(b" ");
+
+        let mut fields = vec![];
+        loop {
+            let offset = self.pos;
+            let key = self.scan_until(b",= ");
+            if key.is_empty() {
+                return Err(self.error(offset, "missing field key"));
+            }
+            if self.peek() != Some(b'=') {
+                return Err(self.error(self.pos, "missing field value"));
+            }
+            self.pos += 1;
+            let value = self.parse_field_value()?;
+            fields.push(Field::new(unescape(key, KEY_ESCAPES), value));
+
+            if self.peek() != Some(b',') {
+                break;
+            }
+            self.pos += 1;
+        }
+
+        let mut time = default_time;
+        self.skip(b" ");
+        match self.peek() {
+            None | Some(b'\n') | Some(b'\r') => {}
+            Some(_) => {
+                let offset = self.pos;
+                let raw = self.scan_until(b" \r");
+                time = std::str::from_utf8(raw)
+                    .ok()
+                    .and_then(|x| x.parse::<i64>().ok())
+                    .ok_or_else(|| self.error(offset, "invalid timestamp"))?;
+                if time == ZERO_TIME {
+                    return Err(self.error(offset, "timestamp out of range"));
+                }
+            }
+        }
+
+        self.skip(b" \r");
+        if !matches!(self.peek(), None | Some(b'\n')) {
+            return Err(self.error(self.pos, "unexpected data after timestamp"));
+        }
+
+        Ok(Point {
+            name,
+            tags,
+            fields,
+            time,
+        })
+    }
+
+    fn parse_field_value(&mut self) -> anyhow::Result<FieldValue> {
+        let start = self.pos;
+        if self.peek() == Some(b'"') {
+            return self.parse_string_value();
+        }
+
+        let raw = self.scan_until(b", ");
+        let value = match raw {
+            b"" => return Err(self.error(start, "missing field value")),
+            b"t" | b"T" | b"true" | b"True" | b"TRUE" => FieldValue::Boolean(true),
+            b"f" | b"F" | b"false" | b"False" | b"FALSE" => FieldValue::Boolean(false),
+            [n @ .., b'i'] => parse_number(n)
+                .map(FieldValue::Integer)
+                .ok_or_else(|| self.error(start, "invalid integer"))?,
+            [n @ .., b'u'] => parse_number(n)
+                .map(FieldValue::Unsigned)
+                .ok_or_else(|| self.error(start, "invalid unsigned integer"))?,
+            n => {
+                // NaN and infinities parse as floats, they can not be stored
+                let v: f64 = parse_number(n).ok_or_else(|| self.error(start, "invalid float"))?;
+                if !v.is_finite() {
+                    return Err(
+                        self.error(start, "invalid float, NaN and infinity are not supported")
+                    );
+                }
+                FieldValue::Float(v)
+            }
+        };
+        Ok(value)
+    }
+
+    /// parse_string_value parses a double quoted string, in which `\"` and `\\` are
+    /// escaped.
+    fn parse_string_value(&mut self) -> anyhow::Result<FieldValue> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let mut value = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error(start, "unterminated string")),
+                Some(b'\\') if matches!(self.buf.get(self.pos + 1), Some(b'"') | Some(b'\\')) => {
+                    value.push(self.buf[self.pos + 1]);
+                    self.pos += 2;
+                }
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if !matches!(
+            self.peek(),
+            None | Some(b',') | Some(b' ') | Some(b'\n') | Some(b'\r')
+        ) {
+            return Err(self.error(self.pos, "invalid string field value"));
+        }
+        // the string only ends at an ascii quote, any utf-8 sequence is whole
+        let value = String::from_utf8(value).map_err(|_| self.error(start, "invalid utf-8"))?;
+        Ok(FieldValue::String(value))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(raw: &[u8]) -> Option<T> {
+    std::str::from_utf8(raw).ok()?.parse().ok()
+}
+
+/// KeyError is a malformed TSM key, see `parse_tsm_key`.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum KeyError {
+    #[error("missing field separator")]
+    MissingFieldSeparator,
+    #[error("missing measurement")]
+    MissingMeasurement,
+    #[error("missing field")]
+    MissingField,
+    #[error("invalid tag at byte {offset}")]
+    InvalidTag { offset: usize },
+}
+
+/// parse_tsm_key splits a TSM key `measurement,k1=v1,k2=v2#!~#field` into its
+/// unescaped measurement, tags and field.
+pub fn parse_tsm_key(key: &[u8]) -> anyhow::Result<(Vec<u8>, Tags, Vec<u8>)> {
+    let sep = KEY_FIELD_SEPARATOR.as_bytes();
+    let i = key
+        .windows(sep.len())
+        .position(|x| x == sep)
+        .ok_or(KeyError::MissingFieldSeparator)?;
+    let (series_key, field) = (&key[..i], &key[i + sep.len()..]);
+    if field.is_empty() {
+        return Err(KeyError::MissingField.into());
+    }
+
+    let (measurement, tags) = parse_series_key(series_key)?;
+    Ok((measurement, tags, field.to_vec()))
+}
+
+/// parse_series_key splits a series key `measurement,k1=v1,k2=v2` into its
+/// unescaped measurement and tags.
+pub fn parse_series_key(series_key: &[u8]) -> anyhow::Result<(Vec<u8>, Tags)> {
+    let mut parts = split_unescaped(series_key, b',').into_iter();
+    let measurement = match parts.next() {
+        Some((_, x)) if !x.is_empty() => unescape(x, MEASUREMENT_ESCAPES),
+        _ => return Err(KeyError::MissingMeasurement.into()),
+    };
+
+    let mut tags = vec![];
+    for (offset, tag) in parts {
+        let (k, v) = match split_unescaped(tag, b'=').as_slice() {
+            [(_, k), (_, v)] if !k.is_empty() && !v.is_empty() => (*k, *v),
+            _ => return Err(KeyError::InvalidTag { offset }.into()),
+        };
+        tags.push(Tag::new(unescape(k, KEY_ESCAPES), unescape(v, KEY_ESCAPES)));
+    }
+
+    Ok((measurement, Tags::new(tags)))
+}
+
+/// split_unescaped splits raw at each unescaped sep, returning the offset of each
+/// part with the part.
+fn split_unescaped(raw: &[u8], sep: u8) -> Vec<(usize, &[u8])> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if raw[i] == sep {
+            parts.push((start, &raw[start..i]));
+            start = i + 1;
+        }
+        i += 1;
+    }
+    parts.push((start, &raw[start..]));
+    parts
+}
+
+/// escape appends raw to b with a backslash before each of the escapes.
+fn escape(b: &mut Vec<u8>, raw: &[u8], escapes: &[u8]) {
+    for c in raw {
+        if escapes.contains(c) {
+            b.push(b'\\');
+        }
+        b.push(*c);
+    }
+}
+
+/// unescape removes the backslash of the escaped characters, other backslashes are
+/// kept as is.
+fn unescape(raw: &[u8], escapes: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\' && i + 1 < raw.len() && escapes.contains(&raw[i + 1]) {
+            b.push(raw[i + 1]);
+            i += 2;
+        } else {
+            b.push(raw[i]);
+            i += 1;
+        }
+    }
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::point::{
+        check_key_length, parse_series_key, parse_tsm_key, series_key, tsm_key, Field, FieldValue,
+        KeyError, KeyTooLong, ParseError, Point, Tag, Tags, MAX_KEY_LENGTH,
+    };
+
+    fn tags(point: &Point) -> Vec<(&str, &str)> {
+        point
+            .tags
+            .iter()
+            .map(|x| {
+                (
+                    std::str::from_utf8(x.key.as_slice()).unwrap(),
+                    std::str::from_utf8(x.value.as_slice()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn fields(point: &Point) -> Vec<(&str, FieldValue)> {
+        point
+            .fields
+            .iter()
+            .map(|x| {
+                (
+                    std::str::from_utf8(x.key.as_slice()).unwrap(),
+                    x.value.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn parse_error(line: &str) -> ParseError {
+        let err = Point::parse_line(line.as_bytes(), 0).unwrap_err();
+        err.downcast_ref::<ParseError>().unwrap().clone()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let p = Point::parse_line(
+            b"cpu,host=a,region=west value=1.5,count=3i,total=4u,up=t,msg=\"ok\" 10",
+            0,
+        )
+        .unwrap();
+        assert_eq!(p.name, b"cpu".to_vec());
+        assert_eq!(tags(&p), vec![("host", "a"), ("region", "west")]);
+        assert_eq!(
+            fields(&p),
+            vec![
+                ("value", FieldValue::Float(1.5)),
+                ("count", FieldValue::Integer(3)),
+                ("total", FieldValue::Unsigned(4)),
+                ("up", FieldValue::Boolean(true)),
+                ("msg", FieldValue::String("ok".to_string())),
+            ]
+        );
+        assert_eq!(p.time, 10);
+
+        // missing timestamp, unsorted tags, negative and exponent numbers
+        let p = Point::parse_line(b"mem,z=1,a=2 free=-3i,used=1e3,ok=FALSE", 42).unwrap();
+        assert_eq!(p.time, 42);
+        assert_eq!(tags(&p), vec![("a", "2"), ("z", "1")]);
+        assert_eq!(
+            fields(&p),
+            vec![
+                ("free", FieldValue::Integer(-3)),
+                ("used", FieldValue::Float(1000.0)),
+                ("ok", FieldValue::Boolean(false)),
+            ]
+        );
+
+        let p = Point::parse_line(b"cpu value=1 -5\n", 0).unwrap();
+        assert_eq!(p.time, -5);
+    }
+
+    #[test]
+    fn test_parse_line_escaped() {
+        let p = Point::parse_line(
+            br#"cpu\,load\ avg,ho\ st=a\,b\=c field\ key\==1i,s="a \"quoted\" \\ string, with=chars" 1"#,
+            0,
+        )
+        .unwrap();
+        assert_eq!(p.name, b"cpu,load avg".to_vec());
+        assert_eq!(tags(&p), vec![("ho st", "a,b=c")]);
+        assert_eq!(
+            fields(&p),
+            vec![
+                ("field key=", FieldValue::Integer(1)),
+                (
+                    "s",
+                    FieldValue::String(r#"a "quoted" \ string, with=chars"#.to_string())
+                ),
+            ]
+        );
+
+        // a backslash escaping nothing is kept
+        let p = Point::parse_line(br#"c\d,t=\x v="\n""#, 0).unwrap();
+        assert_eq!(p.name, br"c\d".to_vec());
+        assert_eq!(tags(&p), vec![("t", r"\x")]);
+        assert_eq!(
+            fields(&p),
+            vec![("v", FieldValue::String(r"\n".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_parse_line_bytes() {
+        // only string field values must be utf-8
+        let p = Point::parse_line(b"cpu,host=\xff\xfe v=1i 3", 0).unwrap();
+        assert_eq!(p.tags[0].value, b"\xff\xfe".to_vec());
+        assert_eq!(fields(&p), vec![("v", FieldValue::Integer(1))]);
+
+        let err = Point::parse_line(b"cpu v=\"\xff\" 3", 0).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError {
+                offset: 6,
+                reason: "invalid utf-8",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        let cases = [
+            ("", 0, "missing measurement"),
+            (",t=1 v=1", 0, "missing measurement"),
+            ("cpu", 3, "missing fields"),
+            ("cpu,=a v=1", 4, "missing tag key"),
+            ("cpu,t v=1", 5, "missing tag value"),
+            ("cpu,t= v=1", 6, "missing tag value"),
+            ("cpu,b=1,a=1,b=2 v=1", 12, "duplicate tag"),
+            ("cpu ", 4, "missing field key"),
+            ("cpu v", 5, "missing field value"),
+            ("cpu v=", 6, "missing field value"),
+            ("cpu v=1,", 8, "missing field key"),
+            ("cpu v=abc", 6, "invalid float"),
+            ("cpu v=1.5i", 6, "invalid integer"),
+            ("cpu v=-1u", 6, "invalid unsigned integer"),
+            (
+                "cpu v=NaN",
+                6,
+                "invalid float, NaN and infinity are not supported",
+            ),
+            (
+                "cpu v=inf",
+                6,
+                "invalid float, NaN and infinity are not supported",
+            ),
+            ("cpu v=\"abc", 6, "unterminated string"),
+            ("cpu v=\"a\"b", 9, "invalid string field value"),
+            ("cpu v=1 abc", 8, "invalid timestamp"),
+            ("cpu v=1 -9223372036854775808", 8, "timestamp out of range"),
+            ("cpu v=1 1 2", 10, "unexpected data after timestamp"),
+            ("cpu v=1 1\nmem v=1", 10, "unexpected data after point"),
+        ];
+        for (line, offset, reason) in cases {
+            assert_eq!(
+                parse_error(line),
+                ParseError { offset, reason },
+                "line {:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let lines = "# comment\n\ncpu,host=a v=1 1\r\n  mem v=\"multi\nline\"\ndisk v=2i 3\n";
+        let points = Point::parse_lines_with_time(lines, 7).unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].name, b"cpu".to_vec());
+        assert_eq!(points[0].time, 1);
+        assert_eq!(
+            fields(&points[1]),
+            vec![("v", FieldValue::String("multi\nline".to_string()))]
+        );
+        assert_eq!(points[1].time, 7);
+        assert_eq!(fields(&points[2]), vec![("v", FieldValue::Integer(2))]);
+
+        // offsets are relative to the whole input
+        let err = Point::parse_lines("cpu v=1 1\nmem v=NaN 2").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError {
+                offset: 16,
+                reason: "invalid float, NaN and infinity are not supported",
+            })
+        );
+
+        assert!(Point::parse_lines("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_tsm_key() {
+        let (name, tags, field) =
+            parse_tsm_key(b"cpu,host=server-09,region=uswest-00#!~#value").unwrap();
+        assert_eq!(name, b"cpu".to_vec());
+        assert_eq!(
+            tags.iter()
+                .map(|x| (x.key.clone(), x.value.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (b"host".to_vec(), b"server-09".to_vec()),
+                (b"region".to_vec(), b"uswest-00".to_vec())
+            ]
+        );
+        assert_eq!(field, b"value".to_vec());
+
+        let (name, tags, field) = parse_tsm_key(b"mem#!~#free").unwrap();
+        assert_eq!(name, b"mem".to_vec());
+        assert!(tags.is_empty());
+        assert_eq!(field, b"free".to_vec());
+
+        let (name, tags, field) =
+            parse_tsm_key(br"disk\,io\ x,path=/a\,b\=c,dc=x\ y#!~#used bytes").unwrap();
+        assert_eq!(name, b"disk,io x".to_vec());
+        assert_eq!(tags[0].value, b"/a,b=c".to_vec());
+        assert_eq!(tags[1].value, b"x y".to_vec());
+        assert_eq!(field, b"used bytes".to_vec());
+
+        let cases: [(&[u8], KeyError); 6] = [
+            (b"cpu,host=a", KeyError::MissingFieldSeparator),
+            (b"cpu,host=a#!~#", KeyError::MissingField),
+            (b",host=a#!~#value", KeyError::MissingMeasurement),
+            (b"cpu,host#!~#value", KeyError::InvalidTag { offset: 4 }),
+            (
+                b"cpu,host=a,=b#!~#value",
+                KeyError::InvalidTag { offset: 11 },
+            ),
+            (b"cpu,host=a=b#!~#value", KeyError::InvalidTag { offset: 4 }),
+        ];
+        for (key, exp) in cases {
+            let err = parse_tsm_key(key).unwrap_err();
+            assert_eq!(err.downcast_ref::<KeyError>(), Some(&exp), "key {:?}", key);
+        }
+    }
+
+    #[test]
+    fn test_tag_debug_invalid_utf8() {
+        let tag = Tag::new(b"host".to_vec(), b"a\xffb".to_vec());
+        assert_eq!(
+            format!("{:?}", tag),
+            "Tag { key: \"host\", value: \"a\u{fffd}b\" }"
+        );
+    }
+
+    #[test]
+    fn test_series_key() {
+        let tags = vec![
+            Tag::new(b"region".to_vec(), b"us,west=1".to_vec()),
+            Tag::new(b"host name".to_vec(), b"a=b".to_vec()),
+        ];
+        let key = series_key(b"cpu load,x", tags.as_slice());
+        assert_eq!(
+            key,
+            br"cpu\ load\,x,host\ name=a\=b,region=us\,west\=1".to_vec()
+        );
+
+        let key = tsm_key(key.as_slice(), b"value");
+        let (name, parsed, field) = parse_tsm_key(key.as_slice()).unwrap();
+        assert_eq!(name, b"cpu load,x".to_vec());
+        assert_eq!(parsed[0].key, b"host name".to_vec());
+        assert_eq!(parsed[0].value, b"a=b".to_vec());
+        assert_eq!(parsed[1].key, b"region".to_vec());
+        assert_eq!(parsed[1].value, b"us,west=1".to_vec());
+        assert_eq!(field, b"value".to_vec());
+
+        let (name, parsed) = parse_series_key(br"cpu\ load\,x,host\ name=a\=b").unwrap();
+        assert_eq!(name, b"cpu load,x".to_vec());
+        assert_eq!(parsed[0].key, b"host name".to_vec());
+        assert_eq!(parsed[0].value, b"a=b".to_vec());
+        let err = parse_series_key(b"cpu,host").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KeyError>(),
+            Some(&KeyError::InvalidTag { offset: 4 })
+        );
+
+        // no tags
+        let key = series_key(b"mem", &[]);
+        assert_eq!(key, b"mem".to_vec());
+        let (name, parsed) = parse_series_key(&key).unwrap();
+        assert_eq!(name, b"mem".to_vec());
+        assert!(parsed.is_empty());
+        assert_eq!(tsm_key(key.as_slice(), b"free"), b"mem#!~#free".to_vec());
+
+        // the longest key a TSM file holds
+        let value = vec![b'v'; MAX_KEY_LENGTH - b"cpu,host=#!~#value".len()];
+        let key = series_key(b"cpu", &[Tag::new(b"host".to_vec(), value.clone())]);
+        let key = tsm_key(key.as_slice(), b"value");
+        assert_eq!(key.len(), MAX_KEY_LENGTH);
+        let (_, parsed, field) = parse_tsm_key(key.as_slice()).unwrap();
+        assert_eq!(parsed[0].value, value);
+        assert_eq!(field, b"value".to_vec());
+    }
+
+    #[test]
+    fn test_check_key_length() {
+        let key = b"c\\,pu,host=a#!~#value";
+        assert!(check_key_length(key, key.len()).is_ok());
+        assert_eq!(
+            check_key_length(key, key.len() - 1),
+            Err(KeyTooLong {
+                measurement: b"c,pu".to_vec(),
+                len: key.len(),
+                max: key.len() - 1,
+            })
+        );
+
+        let err = check_key_length(b"mem#!~#free", 3).unwrap_err();
+        assert_eq!(err.measurement, b"mem".to_vec());
+    }
+
+    #[test]
+    fn test_write_line() {
+        let p = Point {
+            name: b"cpu load,x".to_vec(),
+            tags: Tags::new(vec![
+                Tag::new(b"region".to_vec(), b"us,west=1".to_vec()),
+                Tag::new(b"host".to_vec(), b"a b".to_vec()),
+            ]),
+            fields: vec![
+                Field::new(b"value x".to_vec(), FieldValue::Float(0.1)),
+                Field::new(b"count".to_vec(), FieldValue::Integer(-3)),
+                Field::new(b"total".to_vec(), FieldValue::Unsigned(u64::MAX)),
+                Field::new(b"up".to_vec(), FieldValue::Boolean(false)),
+                Field::new(
+                    b"msg".to_vec(),
+                    FieldValue::String("say \"hi\" \\ bye".to_string()),
+                ),
+            ],
+            time: -10,
+        };
+        let mut line = vec![];
+        p.write_line(&mut line);
+        assert_eq!(
+            String::from_utf8(line.clone()).unwrap(),
+            r#"cpu\ load\,x,host=a\ b,region=us\,west\=1 value\ x=0.1,count=-3i,total=18446744073709551615u,up=false,msg="say \"hi\" \\ bye" -10"#
+        );
+
+        let parsed = Point::parse_line(line.as_slice(), 0).unwrap();
+        assert_eq!(parsed.name, p.name);
+        assert_eq!(
+            tags(&parsed),
+            vec![("host", "a b"), ("region", "us,west=1")]
+        );
+        assert_eq!(parsed.fields, p.fields);
+        assert_eq!(parsed.time, p.time);
+
+        // floats parse back to the same bits
+        for v in [1e-300, 1e300, f64::MAX, -0.0, 1.0 / 3.0] {
+            let p = Point {
+                name: b"m".to_vec(),
+                tags: Tags::new(vec![]),
+                fields: vec![Field::new(b"v".to_vec(), FieldValue::Float(v))],
+                time: 0,
+            };
+            let mut line = vec![];
+            p.write_line(&mut line);
+            let parsed = Point::parse_line(line.as_slice(), 1).unwrap();
+            match parsed.fields[0].value {
+                FieldValue::Float(x) => assert_eq!(x.to_bits(), v.to_bits()),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -1,29 +1,67 @@
+use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
-use std::{io, ptr};
 
+#[cfg(not(miri))]
 use memmap2::{Mmap, MmapOptions};
-use tokio::fs::File;
 
+use crate::file::runtime::{DefaultRuntime, Runtime};
 use crate::RandomAccess;
 
+type File = <DefaultRuntime as Runtime>::File;
+
 pub struct MmapReadableFile {
     f: File,
     len: usize,
+    #[cfg(not(miri))]
     mmap: Mmap,
+    /// mmap holds the content of the file under miri, which can not map files.
+    #[cfg(miri)]
+    mmap: Vec<u8>,
 }
 
 impl MmapReadableFile {
+    /// open maps the file at path, opened with the `DefaultRuntime`.
     pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
-        let f = File::open(path).await?;
-
-        let meta = f.metadata().await?;
-        let len = meta.len() as usize;
+        let f = DefaultRuntime::open(path.as_ref()).await?;
+        let len = DefaultRuntime::len(&f).await? as usize;
 
-        let mmap = unsafe { MmapOptions::new().offset(0).len(len).map(&f)? };
+        let mmap = map(&f, len).await?;
 
         Ok(Self { f, len, mmap })
     }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// as_slice returns the mapped content of the file.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mmap[..self.len]
+    }
+}
+
+/// map maps the first len bytes of f read-only. This is the only unsafe code of
+/// the crate.
+#[cfg(not(miri))]
+async fn map(f: &File, len: usize) -> io::Result<Mmap> {
+    // SAFETY: the map is read-only and only ever read through `&[u8]`. It is
+    // sound as long as the file is not truncated or modified while mapped: the
+    // files read this way are immutable once written, and are only removed when
+    // no reader holds them anymore.
+    unsafe { MmapOptions::new().offset(0).len(len).map(f) }
+}
+
+/// map reads the first len bytes of f, under miri which can not map files.
+#[cfg(miri)]
+async fn map(f: &File, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    DefaultRuntime::read_exact(f, data.as_mut_slice()).await?;
+    Ok(data)
 }
 
 #[async_trait]
@@ -40,10 +78,7 @@ impl RandomAccess for MmapReadableFile {
             return Err(io::Error::new(ErrorKind::UnexpectedEof, ""));
         }
 
-        let data = &self.mmap[offset..offset + size];
-        unsafe {
-            ptr::copy(data.as_ptr(), buf.as_mut_ptr(), size);
-        }
+        buf.copy_from_slice(&self.mmap[offset..upper]);
 
         Ok(size)
     }
@@ -87,4 +122,32 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(feature = "async-std")]
+    #[async_std::test]
+    async fn test_mmap_readable_file_async_std() -> std::io::Result<()> {
+        use async_std::io::WriteExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let tsm_file = dir.as_ref().join("tsm1_test");
+
+        let data = "0123456789".as_bytes();
+        let len = {
+            let mut f = async_std::fs::File::create(&tsm_file).await?;
+            let len = f.write(data).await?;
+            f.sync_all().await?;
+            drop(f);
+            len
+        };
+
+        let accessor = MmapReadableFile::open(&tsm_file).await?;
+
+        let mut buf = Vec::with_capacity(len);
+        buf.resize(len, 0_u8);
+
+        accessor.read(0, &mut buf[..]).await?;
+        assert_eq!(buf.as_slice(), data);
+
+        Ok(())
+    }
 }
@@ -1,2 +1,3 @@
 pub mod mmap_file;
+pub mod runtime;
 pub mod writable_file;
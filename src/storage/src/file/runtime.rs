@@ -0,0 +1,123 @@
+use std::io;
+use std::path::Path;
+
+/// Runtime is the async runtime the local files are opened and written with.
+/// The files are tokio files by default, async-std files with the `async-std`
+/// feature, see `DefaultRuntime`.
+#[async_trait]
+pub trait Runtime {
+    type File: Send + Sync;
+
+    /// open opens the file at path read-only.
+    async fn open(path: &Path) -> io::Result<Self::File>;
+
+    /// create_new creates the file at path for appending, failing if it exists.
+    async fn create_new(path: &Path) -> io::Result<Self::File>;
+
+    /// len returns the size of the file in bytes.
+    async fn len(f: &Self::File) -> io::Result<u64>;
+
+    /// read_exact fills buf from the current position of the file.
+    async fn read_exact(f: &Self::File, buf: &mut [u8]) -> io::Result<()>;
+
+    async fn write(f: &mut Self::File, data: &[u8]) -> io::Result<usize>;
+
+    async fn flush(f: &mut Self::File) -> io::Result<()>;
+
+    async fn sync_all(f: &Self::File) -> io::Result<()>;
+}
+
+/// DefaultRuntime is the runtime of the files of the crate.
+#[cfg(not(feature = "async-std"))]
+pub type DefaultRuntime = TokioRuntime;
+
+/// DefaultRuntime is the runtime of the files of the crate.
+#[cfg(feature = "async-std")]
+pub type DefaultRuntime = AsyncStdRuntime;
+
+/// TokioRuntime opens `tokio::fs::File`s.
+pub struct TokioRuntime;
+
+#[async_trait]
+impl Runtime for TokioRuntime {
+    type File = tokio::fs::File;
+
+    async fn open(path: &Path) -> io::Result<Self::File> {
+        tokio::fs::File::open(path).await
+    }
+
+    async fn create_new(path: &Path) -> io::Result<Self::File> {
+        tokio::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .append(true)
+            .open(path)
+            .await
+    }
+
+    async fn len(f: &Self::File) -> io::Result<u64> {
+        Ok(f.metadata().await?.len())
+    }
+
+    async fn read_exact(f: &Self::File, buf: &mut [u8]) -> io::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        f.try_clone().await?.read_exact(buf).await?;
+        Ok(())
+    }
+
+    async fn write(f: &mut Self::File, data: &[u8]) -> io::Result<usize> {
+        tokio::io::AsyncWriteExt::write(f, data).await
+    }
+
+    async fn flush(f: &mut Self::File) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::flush(f).await
+    }
+
+    async fn sync_all(f: &Self::File) -> io::Result<()> {
+        f.sync_all().await
+    }
+}
+
+/// AsyncStdRuntime opens `async_std::fs::File`s.
+#[cfg(feature = "async-std")]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+#[async_trait]
+impl Runtime for AsyncStdRuntime {
+    type File = async_std::fs::File;
+
+    async fn open(path: &Path) -> io::Result<Self::File> {
+        async_std::fs::File::open(path).await
+    }
+
+    async fn create_new(path: &Path) -> io::Result<Self::File> {
+        async_std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .append(true)
+            .open(path)
+            .await
+    }
+
+    async fn len(f: &Self::File) -> io::Result<u64> {
+        Ok(f.metadata().await?.len())
+    }
+
+    async fn read_exact(mut f: &Self::File, buf: &mut [u8]) -> io::Result<()> {
+        async_std::io::ReadExt::read_exact(&mut f, buf).await
+    }
+
+    async fn write(f: &mut Self::File, data: &[u8]) -> io::Result<usize> {
+        async_std::io::WriteExt::write(f, data).await
+    }
+
+    async fn flush(f: &mut Self::File) -> io::Result<()> {
+        async_std::io::WriteExt::flush(f).await
+    }
+
+    async fn sync_all(f: &Self::File) -> io::Result<()> {
+        f.sync_all().await
+    }
+}
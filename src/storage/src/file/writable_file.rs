@@ -1,22 +1,17 @@
 use std::path::Path;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
 
+use crate::file::runtime::{DefaultRuntime, Runtime};
 use crate::Writable;
 
 pub struct WritableFile {
-    f: File,
+    f: <DefaultRuntime as Runtime>::File,
 }
 
 impl WritableFile {
+    /// create creates the file at path, opened with the `DefaultRuntime`. It
+    /// fails if the file exists.
     pub async fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
-        let f = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .append(true)
-            .open(path)
-            .await?;
-
+        let f = DefaultRuntime::create_new(path.as_ref()).await?;
         Ok(Self { f })
     }
 }
@@ -24,14 +19,14 @@ impl WritableFile {
 #[async_trait]
 impl Writable for WritableFile {
     async fn append(&mut self, data: &[u8]) -> std::io::Result<usize> {
-        self.f.write(data).await
+        DefaultRuntime::write(&mut self.f, data).await
     }
 
     async fn flush(&mut self) -> std::io::Result<()> {
-        self.f.flush().await
+        DefaultRuntime::flush(&mut self.f).await
     }
 
     async fn sync(&self) -> std::io::Result<()> {
-        self.f.sync_all().await
+        DefaultRuntime::sync_all(&self.f).await
     }
 }
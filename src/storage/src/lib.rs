@@ -1,14 +1,18 @@
 #[macro_use]
+extern crate async_trait;
+#[macro_use]
 extern crate serde;
 
+pub mod file;
+
 pub mod opendal {
     pub use opendal::{
-        Builder, Entry, EntryMode, Error, ErrorKind, Lister, Metadata, Operator, Reader, Result,
-        Writer,
+        Appender, Builder, Entry, EntryMode, Error, ErrorKind, Lister, Metadata, Operator, Reader,
+        Result, Scheme, Writer,
     };
 
     pub mod services {
-        pub use opendal::services::Fs;
+        pub use opendal::services::{Fs, Memory};
     }
 
     pub mod layers {
@@ -20,6 +24,26 @@ pub mod opendal {
     }
 }
 
+/// RandomAccess reads a file at any offset.
+#[async_trait]
+pub trait RandomAccess: Send + Sync {
+    /// read fills buf with the bytes at offset and returns the number of bytes
+    /// read.
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    async fn close(self) -> std::io::Result<()>;
+}
+
+/// Writable is a file written by appending to it.
+#[async_trait]
+pub trait Writable: Send {
+    async fn append(&mut self, data: &[u8]) -> std::io::Result<usize>;
+
+    async fn flush(&mut self) -> std::io::Result<()>;
+
+    async fn sync(&self) -> std::io::Result<()>;
+}
+
 pub fn operator() -> std::io::Result<crate::opendal::Operator> {
     let mut builder = opendal::services::Fs::default();
     builder.root("/").enable_path_check();
@@ -64,8 +88,41 @@ impl StorageFsConfig {
     }
 }
 
+/// StorageConfig configures the layers `build_operator` adds to a backend.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// max_retries is the number of times a failed operation is retried.
+    pub max_retries: usize,
+    /// min_delay is the delay before the first retry, doubled for each next one.
+    pub min_delay: std::time::Duration,
+    /// max_delay caps the delay between two retries.
+    pub max_delay: std::time::Duration,
+}
+
+impl Default for StorageConfig {
+    /// default matches the defaults of opendal's `RetryLayer`.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            min_delay: std::time::Duration::from_secs(1),
+            max_delay: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+impl StorageConfig {
+    fn retry_layer(&self) -> crate::opendal::layers::RetryLayer {
+        crate::opendal::layers::RetryLayer::new()
+            .with_max_times(self.max_retries)
+            .with_min_delay(self.min_delay)
+            .with_max_delay(self.max_delay)
+            .with_jitter()
+    }
+}
+
 pub fn build_operator<B: crate::opendal::Builder>(
     builder: B,
+    config: &StorageConfig,
 ) -> std::io::Result<crate::opendal::Operator> {
     let ob = crate::opendal::Operator::new(builder)?;
 
@@ -77,7 +134,7 @@ pub fn build_operator<B: crate::opendal::Builder>(
         // will send to storage runtime.
         // .layer(crate::opendal::layers::RuntimeLayer::new(GlobalIORuntime::instance().inner()))
         // Add retry
-        .layer(crate::opendal::layers::RetryLayer::new().with_jitter())
+        .layer(config.retry_layer())
         // Add metrics
         .layer(crate::opendal::layers::MetricsLayer)
         // Add logging
@@ -115,10 +172,15 @@ pub enum StorageParams {
     // None,
 }
 
+/// ATOMIC_WRITE_TMP_SUFFIX is the suffix of the temporary object used by `write_atomic`.
+const ATOMIC_WRITE_TMP_SUFFIX: &str = "tmp";
+
 #[derive(Clone, Debug)]
 pub struct StorageOperator {
     operator: crate::opendal::Operator,
     path: String,
+    /// overrides the rename capability probe when set.
+    atomic_rename: Option<bool>,
 }
 
 impl StorageOperator {
@@ -126,9 +188,16 @@ impl StorageOperator {
         Self {
             operator,
             path: path.to_string(),
+            atomic_rename: None,
         }
     }
 
+    /// with_rename_is_atomic overrides the result of `rename_is_atomic`.
+    pub fn with_rename_is_atomic(mut self, atomic: bool) -> Self {
+        self.atomic_rename = Some(atomic);
+        self
+    }
+
     pub fn root(path: &str) -> std::io::Result<Self> {
         let op = operator()?;
         Ok(Self::new(op, path))
@@ -138,6 +207,19 @@ impl StorageOperator {
         self.operator.clone()
     }
 
+    /// layer returns the operator of the same path with layer applied to its
+    /// accessor, e.g. to limit or observe its reads.
+    pub fn layer<L>(&self, layer: L) -> Self
+    where
+        L: crate::opendal::raw::Layer<crate::opendal::raw::FusedAccessor>,
+    {
+        Self {
+            operator: self.operator.clone().layer(layer),
+            path: self.path.clone(),
+            atomic_rename: self.atomic_rename,
+        }
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
@@ -150,6 +232,10 @@ impl StorageOperator {
         self.operator.writer(self.path.as_str()).await
     }
 
+    pub async fn appender(&self) -> crate::opendal::Result<crate::opendal::Appender> {
+        self.operator.appender(self.path.as_str()).await
+    }
+
     pub async fn delete(&self) -> crate::opendal::Result<()> {
         self.operator.delete(self.path.as_str()).await
     }
@@ -158,6 +244,34 @@ impl StorageOperator {
         self.operator.rename(self.path.as_str(), to).await
     }
 
+    /// rename_is_atomic returns true if `rename` is an atomic metadata operation on
+    /// the backend. Object stores emulate rename with copy+delete (or not at all),
+    /// so crash-safe commits must not rely on it there.
+    pub fn rename_is_atomic(&self) -> bool {
+        if let Some(atomic) = self.atomic_rename {
+            return atomic;
+        }
+
+        let info = self.operator.info();
+        matches!(info.scheme(), crate::opendal::Scheme::Fs) && info.capability().rename
+    }
+
+    /// write_atomic replaces the content of the path so that readers observe
+    /// either the old or the new content.
+    ///
+    /// On backends with atomic rename the data is written to a temporary object
+    /// which is then renamed over the path. Otherwise the data is written to the
+    /// path directly and the single object PUT is the commit point.
+    pub async fn write_atomic(&self, data: Vec<u8>) -> crate::opendal::Result<()> {
+        if self.rename_is_atomic() {
+            let tmp = self.to_tmp(ATOMIC_WRITE_TMP_SUFFIX);
+            self.operator.write(tmp.path(), data).await?;
+            tmp.rename(self.path()).await
+        } else {
+            self.operator.write(self.path(), data).await
+        }
+    }
+
     pub async fn stat(&self) -> crate::opendal::Result<crate::opendal::Metadata> {
         self.operator.stat(self.path.as_str()).await
     }
@@ -186,14 +300,12 @@ impl StorageOperator {
         Self {
             operator: self.operator.clone(),
             path: new_path.to_string(),
+            atomic_rename: self.atomic_rename,
         }
     }
 
     pub fn to_tmp(&self, suffix: &str) -> Self {
-        Self::new(
-            self.operator(),
-            format!("{}.{}", self.path.as_str(), suffix).as_str(),
-        )
+        self.to_op(format!("{}.{}", self.path.as_str(), suffix).as_str())
     }
 }
 
@@ -236,3 +348,73 @@ pub fn path_join(path1: &str, path2: &str) -> String {
 
     format!("{}/{}", path1, path2)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::opendal::services::{Fs, Memory};
+    use crate::opendal::Operator;
+    use crate::{build_operator, StorageConfig, StorageOperator};
+
+    #[tokio::test]
+    async fn test_rename_is_atomic() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut builder = Fs::default();
+        builder.root(dir.path().to_str().unwrap());
+        let op = StorageOperator::new(Operator::new(builder)?.finish(), "a");
+        assert!(op.rename_is_atomic());
+        assert!(!op
+            .to_op("b")
+            .with_rename_is_atomic(false)
+            .rename_is_atomic());
+
+        let op = StorageOperator::new(Operator::new(Memory::default())?.finish(), "a");
+        assert!(!op.rename_is_atomic());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut builder = Fs::default();
+        builder.root(dir.path().to_str().unwrap());
+        let fs = Operator::new(builder)?.finish();
+        let mem = Operator::new(Memory::default())?.finish();
+
+        for op in [
+            StorageOperator::new(fs.clone(), "manifest"),
+            StorageOperator::new(fs, "manifest.forced").with_rename_is_atomic(false),
+            StorageOperator::new(mem, "manifest"),
+        ] {
+            op.write_atomic(b"v1".to_vec()).await?;
+            op.write_atomic(b"v2".to_vec()).await?;
+
+            let data = op.operator().read(op.path()).await?;
+            assert_eq!(data, b"v2".to_vec());
+            assert!(!op.to_tmp("tmp").exist().await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_operator_retry_config() -> anyhow::Result<()> {
+        let config = StorageConfig {
+            max_retries: 5,
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+        };
+        let op = build_operator(Memory::default(), &config)?;
+
+        op.write("a", b"v1".to_vec()).await?;
+        assert_eq!(op.read("a").await?, b"v1".to_vec());
+
+        let op = build_operator(Memory::default(), &StorageConfig::default())?;
+        op.write("a", b"v2".to_vec()).await?;
+        assert_eq!(op.read("a").await?, b"v2".to_vec());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Compares aggregating float blocks decoded into `TimeValue`s with aggregating
+//! them decoded as flat arrays by `decode_block_soa`, as `Engine::aggregate` does,
+//! on 1M points in 1000-point blocks. Run with `cargo bench --bench aggregate`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use influxdb_tsdb::engine::tsm1::block::decoder::{decode_block, decode_block_soa, FloatOrIntSoA};
+use influxdb_tsdb::engine::tsm1::block::encoder::encode_block;
+use influxdb_tsdb::engine::tsm1::block::BLOCK_FLOAT64;
+use influxdb_tsdb::engine::tsm1::value::{FloatSummary, SumMode, TimeValue, Values};
+
+const POINTS: i64 = 1_000_000;
+const POINTS_PER_BLOCK: i64 = 1000;
+
+fn bench_aggregate(c: &mut Criterion) {
+    let blocks: Vec<Vec<u8>> = (0..POINTS / POINTS_PER_BLOCK)
+        .map(|i| {
+            let values = (i * POINTS_PER_BLOCK..(i + 1) * POINTS_PER_BLOCK)
+                .map(|t| TimeValue::new(1_000_000_000 + t * 10_000, (t as f64).sin()))
+                .collect();
+            let mut block = vec![];
+            encode_block(&mut block, Values::Float(values)).unwrap();
+            block
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("aggregate");
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.sample_size(20);
+    for mode in [SumMode::Fast, SumMode::Compensated] {
+        group.bench_function(format!("{:?}/aos", mode), |b| {
+            b.iter(|| {
+                let mut summary = FloatSummary::new(mode);
+                let mut values = Values::Float(vec![]);
+                for block in blocks.iter() {
+                    values.truncate(0);
+                    decode_block(black_box(block), &mut values).unwrap();
+                    if let Values::Float(values) = &values {
+                        summary.add_values(values);
+                    }
+                }
+                summary
+            })
+        });
+        group.bench_function(format!("{:?}/soa", mode), |b| {
+            b.iter(|| {
+                let mut summary = FloatSummary::new(mode);
+                let mut times = vec![];
+                let mut values = FloatOrIntSoA::Float(vec![]);
+                for block in blocks.iter() {
+                    times.clear();
+                    values.clear();
+                    decode_block_soa(BLOCK_FLOAT64, black_box(block), &mut times, &mut values)
+                        .unwrap();
+                    if let FloatOrIntSoA::Float(values) = &values {
+                        summary.add_slice(values);
+                    }
+                }
+                summary
+            })
+        });
+    }
+    group.finish();
+
+    // the aggregates alone, over values already decoded
+    let aos: Vec<TimeValue<f64>> = (0..POINTS)
+        .map(|t| TimeValue::new(t, (t as f64).sin()))
+        .collect();
+    let soa: Vec<f64> = aos.iter().map(|x| x.value).collect();
+
+    let mut group = c.benchmark_group("summary");
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.sample_size(20);
+    for mode in [SumMode::Fast, SumMode::Compensated] {
+        group.bench_function(format!("{:?}/aos", mode), |b| {
+            b.iter(|| {
+                let mut summary = FloatSummary::new(mode);
+                summary.add_values(black_box(&aos));
+                summary
+            })
+        });
+        group.bench_function(format!("{:?}/soa", mode), |b| {
+            b.iter(|| {
+                let mut summary = FloatSummary::new(mode);
+                summary.add_slice(black_box(&soa));
+                summary
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_aggregate);
+criterion_main!(benches);
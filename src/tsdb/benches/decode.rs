@@ -0,0 +1,88 @@
+//! Compares the batch decoders of `codec::batch` with decoding a block one value per
+//! `next`/`read` call on the timestamp and value decoders, on 1000-point blocks.
+//! Run with `cargo bench --bench decode`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use influxdb_tsdb::engine::tsm1::block::decoder::unpack_block;
+use influxdb_tsdb::engine::tsm1::block::encoder::encode_block;
+use influxdb_tsdb::engine::tsm1::codec::batch;
+use influxdb_tsdb::engine::tsm1::codec::float::FloatDecoder;
+use influxdb_tsdb::engine::tsm1::codec::integer::IntegerDecoder;
+use influxdb_tsdb::engine::tsm1::codec::timestamp::TimeDecoder;
+use influxdb_tsdb::engine::tsm1::codec::Decoder;
+use influxdb_tsdb::engine::tsm1::value::{FieldType, TimeValue, Values};
+
+const POINTS: i64 = 1000;
+
+/// decode_iter decodes the block parts one value per `next`/`read`.
+fn decode_iter<T: FieldType>(
+    mut ts_dec: TimeDecoder,
+    mut v_dec: impl Decoder<T>,
+) -> Vec<TimeValue<T>> {
+    let mut values = vec![];
+    while ts_dec.next() && v_dec.next() {
+        values.push(TimeValue::new(ts_dec.read(), v_dec.read()));
+    }
+    values
+}
+
+fn encode(values: Values) -> Vec<u8> {
+    let mut block = vec![];
+    encode_block(&mut block, values).unwrap();
+    block
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let ts = |t: i64| 1_000_000_000 + t * 10_000 + (t * 7919) % 1000;
+    let integers = encode(Values::Integer(
+        (0..POINTS)
+            .map(|t| TimeValue::new(ts(t), (t * 31) % 1000))
+            .collect(),
+    ));
+    let floats = encode(Values::Float(
+        (0..POINTS)
+            .map(|t| TimeValue::new(ts(t), (t as f64).sin()))
+            .collect(),
+    ));
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(POINTS as u64));
+
+    let (_, tb, vb) = unpack_block(integers.as_slice()).unwrap();
+    group.bench_function("i64/iter", |b| {
+        b.iter(|| {
+            decode_iter(
+                TimeDecoder::new(black_box(tb)).unwrap(),
+                IntegerDecoder::new(black_box(vb)).unwrap(),
+            )
+        })
+    });
+    group.bench_function("i64/batch", |b| {
+        b.iter(|| {
+            let mut out = vec![];
+            batch::decode_all_i64(black_box(tb), black_box(vb), &mut out).unwrap();
+            out
+        })
+    });
+
+    let (_, tb, vb) = unpack_block(floats.as_slice()).unwrap();
+    group.bench_function("f64/iter", |b| {
+        b.iter(|| {
+            decode_iter(
+                TimeDecoder::new(black_box(tb)).unwrap(),
+                FloatDecoder::new(black_box(vb)).unwrap(),
+            )
+        })
+    });
+    group.bench_function("f64/batch", |b| {
+        b.iter(|| {
+            let mut out = vec![];
+            batch::decode_all_f64(black_box(tb), black_box(vb), &mut out).unwrap();
+            out
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
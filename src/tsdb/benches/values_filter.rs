@@ -0,0 +1,49 @@
+//! Compares `Array::exclude` and `Array::include`, which binary search the range,
+//! with a `retain` over every value. Run with
+//! `cargo bench --bench values_filter`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use influxdb_tsdb::engine::tsm1::value::{Array, FloatValues, TimeValue};
+
+const POINTS: i64 = 100_000;
+
+fn bench_values_filter(c: &mut Criterion) {
+    let values: FloatValues = (0..POINTS).map(|t| TimeValue::new(t, t as f64)).collect();
+    // a narrow range in the middle, e.g. a tombstone or a query window
+    let min = POINTS / 2;
+    let max = min + 100;
+
+    let mut group = c.benchmark_group("values_filter");
+    group.bench_function("exclude", |b| {
+        b.iter_batched_ref(
+            || values.clone(),
+            |v| v.exclude(min, max),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("exclude/retain", |b| {
+        b.iter_batched_ref(
+            || values.clone(),
+            |v| v.retain(|x| x.unix_nano < min || x.unix_nano > max),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("include", |b| {
+        b.iter_batched_ref(
+            || values.clone(),
+            |v| v.include(min, max),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("include/retain", |b| {
+        b.iter_batched_ref(
+            || values.clone(),
+            |v| v.retain(|x| x.unix_nano >= min && x.unix_nano <= max),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_values_filter);
+criterion_main!(benches);
@@ -1,5 +1,3 @@
-use std::str::from_utf8_unchecked;
-
 use clap::Parser;
 use common_base::iterator::AsyncIterator;
 use influxdb_storage::StorageOperator;
@@ -32,7 +30,7 @@ async fn main() -> anyhow::Result<()> {
         let mut itr = tsm_reader.key_iterator().await?;
         let mut i = 0;
         while let Some(key) = itr.try_next().await? {
-            let key = unsafe { from_utf8_unchecked(key.as_slice()) };
+            let key = String::from_utf8_lossy(key.as_slice());
             println!("{:010}>{}", i, key);
             i += 1;
         }
@@ -1,5 +0,0 @@
-
-pub mod cache;
-pub mod ring;
-pub mod partition;
-pub mod encoding;
\ No newline at end of file
@@ -1,4 +1,5 @@
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt};
+use crc32fast::Hasher;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Default)]
 pub struct Section {
@@ -21,7 +22,7 @@ impl Section {
         Ok(())
     }
 
-    pub async fn read_from<R: AsyncRead + AsyncSeek + Send + Unpin>(
+    pub async fn read_from<R: AsyncRead + Send + Unpin>(
         r: &mut R,
     ) -> anyhow::Result<(Self, usize)> {
         let mut i = 0;
@@ -36,4 +37,99 @@ impl Section {
 
         Ok((Self { offset, size }, i))
     }
+
+    /// write_to_checked writes the section as `write_to` followed by the CRC32
+    /// of the offset and the size, see `read_from_checked`.
+    pub async fn write_to_checked<W: AsyncWrite + Send + Unpin>(
+        &self,
+        mut w: W,
+    ) -> anyhow::Result<()> {
+        self.write_to(&mut w).await?;
+        w.write_u32(self.checksum()).await?;
+        Ok(())
+    }
+
+    /// read_from_checked reads a section written by `write_to_checked`, failing
+    /// if it does not match its checksum.
+    pub async fn read_from_checked<R: AsyncRead + Send + Unpin>(
+        r: &mut R,
+    ) -> anyhow::Result<(Self, usize)> {
+        let (section, mut i) = Self::read_from(r).await?;
+
+        let crc = r.read_u32().await?;
+        i += 4;
+        if crc != section.checksum() {
+            return Err(anyhow!(
+                "section checksum mismatch: offset {}, size {}",
+                section.offset,
+                section.size
+            ));
+        }
+
+        Ok((section, i))
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut h = Hasher::new();
+        h.update(&self.offset.to_be_bytes());
+        h.update(&self.size.to_be_bytes());
+        h.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::common::Section;
+
+    #[tokio::test]
+    async fn test_section_checked_round_trip() {
+        let section = Section::new(1024, 4096);
+        let mut buf = vec![];
+        section.write_to_checked(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 20);
+
+        let (got, n) = Section::read_from_checked(&mut Cursor::new(buf.as_slice()))
+            .await
+            .unwrap();
+        assert_eq!((got.offset, got.size, n), (1024, 4096, 20));
+
+        // the unchecked read ignores the checksum
+        let (got, n) = Section::read_from(&mut Cursor::new(buf.as_slice()))
+            .await
+            .unwrap();
+        assert_eq!((got.offset, got.size, n), (1024, 4096, 16));
+    }
+
+    #[tokio::test]
+    async fn test_section_checked_corruption() {
+        let mut buf = vec![];
+        Section::new(1024, 4096)
+            .write_to_checked(&mut buf)
+            .await
+            .unwrap();
+
+        for i in 0..buf.len() {
+            let mut corrupt = buf.clone();
+            corrupt[i] ^= 0x01;
+            assert!(
+                Section::read_from_checked(&mut Cursor::new(corrupt.as_slice()))
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_section_read_from_slice() {
+        let mut buf = vec![];
+        Section::new(1024, 4096).write_to(&mut buf).await.unwrap();
+
+        // a slice reads without seeking
+        let mut r = buf.as_slice();
+        let (got, n) = Section::read_from(&mut r).await.unwrap();
+        assert_eq!((got.offset, got.size, n), (1024, 4096, 16));
+        assert!(r.is_empty());
+    }
 }
@@ -0,0 +1,448 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common_base::point::Point;
+use influxdb_storage::{path_join, StorageOperator};
+use tokio::sync::Mutex;
+
+use crate::engine::tsm1::engine::{Engine, ReadIterator, ShardOptions};
+use crate::engine::tsm1::file_store::TimeRange;
+use crate::engine::tsm1::value::Values;
+
+/// MAX_EVICTION_EVENTS is the number of recent evictions kept for diagnostics.
+pub const MAX_EVICTION_EVENTS: usize = 64;
+
+/// DatabaseOptions configures a `Database`.
+#[derive(Clone, Default)]
+pub struct DatabaseOptions {
+    /// max_open_shards is the number of shards kept open, 0 for unlimited. Past
+    /// it the least recently used shards are closed, see `Database`.
+    pub max_open_shards: usize,
+    /// shard configures the engine of each shard.
+    pub shard: ShardOptions,
+}
+
+/// DatabaseStats counts the shards opened and closed by a `Database`.
+#[derive(Debug, Default)]
+pub struct DatabaseStats {
+    open_shards: AtomicU64,
+    evictions: AtomicU64,
+    reopens: AtomicU64,
+    reopen_nanos: AtomicU64,
+}
+
+impl DatabaseStats {
+    /// open_shards returns the number of shards currently open.
+    pub fn open_shards(&self) -> u64 {
+        self.open_shards.load(Ordering::Relaxed)
+    }
+
+    /// evictions returns the number of shards closed to stay within
+    /// `max_open_shards`.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// reopens returns the number of evicted shards opened again.
+    pub fn reopens(&self) -> u64 {
+        self.reopens.load(Ordering::Relaxed)
+    }
+
+    /// reopen_latency returns the time spent opening evicted shards again, in
+    /// total over `reopens`.
+    pub fn reopen_latency(&self) -> Duration {
+        Duration::from_nanos(self.reopen_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// EvictionEvent records a shard closed to stay within `max_open_shards`.
+#[derive(Debug, Clone)]
+pub struct EvictionEvent {
+    pub shard_id: u64,
+    pub at: SystemTime,
+    /// idle is the time since the shard was last accessed.
+    pub idle: Duration,
+}
+
+/// DatabaseDiagnostics is the state of the shards of a `Database`.
+#[derive(Debug, Clone)]
+pub struct DatabaseDiagnostics {
+    pub max_open_shards: usize,
+    pub open_shards: Vec<u64>,
+    /// pinned_shards are the open shards which can not be evicted.
+    pub pinned_shards: Vec<u64>,
+    /// evictions are the most recent evictions, oldest first.
+    pub evictions: Vec<EvictionEvent>,
+}
+
+/// Database holds the shards of a database, each in the directory named after
+/// its id, and opens them on first access.
+///
+/// At most `max_open_shards` are kept open: once the limit is passed, the least
+/// recently accessed shards are closed with `Engine::close_fast`, which syncs the
+/// WAL without flushing the cache, and are opened again transparently by the
+/// next access. A shard is pinned, and never evicted, while a `ShardHandle` to it
+/// is alive, e.g. during a write or a query, or while its cache holds values not
+/// yet flushed to TSM files. The limit is exceeded as long as the shards above it
+/// are pinned.
+pub struct Database {
+    op: StorageOperator,
+    options: DatabaseOptions,
+    state: Mutex<DatabaseState>,
+    stats: DatabaseStats,
+}
+
+struct DatabaseState {
+    shards: BTreeMap<u64, ShardSlot>,
+    evictions: VecDeque<EvictionEvent>,
+}
+
+struct ShardSlot {
+    engine: Option<Arc<Engine>>,
+    last_access: Instant,
+    /// evicted is true once the shard was closed by an eviction.
+    evicted: bool,
+}
+
+impl ShardSlot {
+    /// pinned returns true if the shard is open and can not be evicted.
+    fn pinned(&self) -> bool {
+        match &self.engine {
+            Some(engine) => Arc::strong_count(engine) > 1 || engine.cache().size() > 0,
+            None => false,
+        }
+    }
+}
+
+impl Database {
+    /// new returns the database in the directory `op`, no shard is opened.
+    pub fn new(op: StorageOperator, options: DatabaseOptions) -> Self {
+        Self {
+            op,
+            options,
+            state: Mutex::new(DatabaseState {
+                shards: BTreeMap::new(),
+                evictions: VecDeque::new(),
+            }),
+            stats: DatabaseStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &DatabaseStats {
+        &self.stats
+    }
+
+    /// shard returns the shard `id`, opening it if it is not. Other shards are
+    /// evicted if the limit of open shards is passed. The shard is pinned until
+    /// the handle is dropped.
+    pub async fn shard(&self, id: u64) -> anyhow::Result<ShardHandle> {
+        let clock = self.options.shard.clock.as_ref();
+        let mut state = self.state.lock().await;
+        let slot = state.shards.entry(id).or_insert_with(|| ShardSlot {
+            engine: None,
+            last_access: clock.now_instant(),
+            evicted: false,
+        });
+        slot.last_access = clock.now_instant();
+
+        let engine = match &slot.engine {
+            Some(engine) => engine.clone(),
+            None => {
+                let start = clock.now_instant();
+                let engine = Arc::new(self.open_shard(id).await?);
+                if slot.evicted {
+                    let elapsed = clock.now_instant() - start;
+                    self.stats.reopens.fetch_add(1, Ordering::Relaxed);
+                    self.stats
+                        .reopen_nanos
+                        .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+                }
+                slot.engine = Some(engine.clone());
+                self.stats.open_shards.fetch_add(1, Ordering::Relaxed);
+                engine
+            }
+        };
+
+        self.evict(&mut state, id).await;
+        Ok(ShardHandle { id, engine })
+    }
+
+    /// write_points writes the points into the shard `id`, see
+    /// `Engine::write_points`.
+    pub async fn write_points(&self, id: u64, points: &[Point]) -> anyhow::Result<()> {
+        self.shard(id).await?.write_points(points).await
+    }
+
+    /// write_values writes the values into the shard `id`, see
+    /// `Engine::write_values`.
+    pub async fn write_values(
+        &self,
+        id: u64,
+        values: BTreeMap<Vec<u8>, Values>,
+    ) -> anyhow::Result<()> {
+        self.shard(id).await?.write_values(values).await
+    }
+
+    /// read returns an iterator over the values of key within time_range in the
+    /// shard `id`, see `Engine::read`.
+    pub async fn read(
+        &self,
+        id: u64,
+        key: &[u8],
+        time_range: TimeRange,
+    ) -> anyhow::Result<ReadIterator> {
+        self.shard(id).await?.read(key, time_range).await
+    }
+
+    /// open_shards returns the ids of the open shards.
+    pub async fn open_shards(&self) -> Vec<u64> {
+        let state = self.state.lock().await;
+        state
+            .shards
+            .iter()
+            .filter(|(_, slot)| slot.engine.is_some())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// diagnostics returns the open and pinned shards and the recent evictions.
+    pub async fn diagnostics(&self) -> DatabaseDiagnostics {
+        let state = self.state.lock().await;
+        let open = state.shards.iter().filter(|(_, x)| x.engine.is_some());
+        DatabaseDiagnostics {
+            max_open_shards: self.options.max_open_shards,
+            open_shards: open.clone().map(|(id, _)| *id).collect(),
+            pinned_shards: open
+                .filter(|(_, x)| x.pinned())
+                .map(|(id, _)| *id)
+                .collect(),
+            evictions: state.evictions.iter().cloned().collect(),
+        }
+    }
+
+    /// close closes the open shards, flushing their cache.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        for slot in state.shards.values_mut() {
+            if let Some(engine) = slot.engine.take() {
+                self.stats.open_shards.fetch_sub(1, Ordering::Relaxed);
+                engine.close().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn open_shard(&self, id: u64) -> anyhow::Result<Engine> {
+        let path = path_join(self.op.path(), id.to_string().as_str());
+        self.op
+            .operator()
+            .create_dir(format!("{}/", path).as_str())
+            .await?;
+        Engine::open_with_options(self.op.to_op(path.as_str()), self.options.shard.clone()).await
+    }
+
+    /// evict closes the least recently used shards which are not pinned until at
+    /// most `max_open_shards` are open. The shard `keep` was just accessed and is
+    /// kept open.
+    async fn evict(&self, state: &mut DatabaseState, keep: u64) {
+        let max_open_shards = self.options.max_open_shards;
+        if max_open_shards == 0 {
+            return;
+        }
+
+        loop {
+            let open = state.shards.values().filter(|x| x.engine.is_some()).count();
+            if open <= max_open_shards {
+                return;
+            }
+
+            let victim = state
+                .shards
+                .iter()
+                .filter(|(id, slot)| **id != keep && slot.engine.is_some() && !slot.pinned())
+                .min_by_key(|(_, slot)| slot.last_access)
+                .map(|(id, _)| *id);
+            let id = match victim {
+                Some(id) => id,
+                None => return,
+            };
+
+            let slot = state.shards.get_mut(&id).unwrap();
+            let engine = slot.engine.take().unwrap();
+            if let Err(e) = engine.close_fast().await {
+                tracing::warn!("evicting shard {} failed, kept open: {}", id, e);
+                slot.engine = Some(engine);
+                return;
+            }
+            slot.evicted = true;
+            self.stats.open_shards.fetch_sub(1, Ordering::Relaxed);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+
+            let clock = self.options.shard.clock.as_ref();
+            let event = EvictionEvent {
+                shard_id: id,
+                at: UNIX_EPOCH + Duration::from_nanos(clock.now_nanos() as u64),
+                idle: clock.now_instant() - slot.last_access,
+            };
+            tracing::debug!("evicted shard {} idle for {:?}", id, event.idle);
+            state.evictions.push_back(event);
+            if state.evictions.len() > MAX_EVICTION_EVENTS {
+                state.evictions.pop_front();
+            }
+        }
+    }
+}
+
+/// ShardHandle is an open shard of a `Database`, which pins it: the shard is not
+/// evicted while a handle to it is alive.
+#[derive(Clone)]
+pub struct ShardHandle {
+    id: u64,
+    engine: Arc<Engine>,
+}
+
+impl ShardHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Deref for ShardHandle {
+    type Target = Engine;
+
+    fn deref(&self) -> &Self::Target {
+        self.engine.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use common_base::clock::SimulatedClock;
+    use common_base::iterator::AsyncIterator;
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::database::{Database, DatabaseOptions};
+    use crate::engine::tsm1::engine::ShardOptions;
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    fn points(id: u64) -> BTreeMap<Vec<u8>, Values> {
+        let values = Values::Float(vec![TimeValue::new(id as i64, id as f64)]);
+        BTreeMap::from([(b"cpu#!~#value".to_vec(), values)])
+    }
+
+    async fn assert_readable(db: &Database, id: u64) {
+        let values = db
+            .read(id, b"cpu#!~#value", TimeRange::unbound())
+            .await
+            .unwrap()
+            .collect_values()
+            .await
+            .unwrap();
+        assert_eq!(values, points(id).remove(b"cpu#!~#value".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_database_evicts_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let db = Database::new(
+            StorageOperator::root(path.as_str()).unwrap(),
+            DatabaseOptions {
+                max_open_shards: 2,
+                ..Default::default()
+            },
+        );
+
+        for id in 0..4 {
+            let shard = db.shard(id).await.unwrap();
+            shard.write_values(points(id)).await.unwrap();
+            shard.write_snapshot().await.unwrap();
+        }
+        assert_eq!(db.open_shards().await, vec![2, 3]);
+        assert_eq!(db.stats().evictions(), 2);
+        assert_eq!(db.stats().reopens(), 0);
+
+        // the values written before the eviction are read after the reopen
+        for _ in 0..2 {
+            for id in 0..4 {
+                assert_readable(&db, id).await;
+                assert!(db.open_shards().await.len() <= 2);
+            }
+        }
+        assert_eq!(db.stats().reopens(), 8);
+        assert_eq!(db.stats().evictions(), 10);
+        assert_eq!(db.stats().open_shards(), 2);
+        let diagnostics = db.diagnostics().await;
+        assert_eq!(diagnostics.evictions.len(), 10);
+        assert_eq!(diagnostics.evictions.last().unwrap().shard_id, 1);
+
+        // a shard with an in-flight read is not evicted until the read finishes
+        let reading = db.shard(0).await.unwrap();
+        for id in 1..4 {
+            assert_readable(&db, id).await;
+        }
+        assert_eq!(db.open_shards().await, vec![0, 3]);
+        assert_eq!(db.diagnostics().await.pinned_shards, vec![0]);
+        assert!(reading
+            .read(b"cpu#!~#value", TimeRange::unbound())
+            .await
+            .unwrap()
+            .try_next()
+            .await
+            .unwrap()
+            .is_some());
+        drop(reading);
+        assert_readable(&db, 1).await;
+        assert_eq!(db.open_shards().await, vec![1, 3]);
+
+        // nor is a shard with values not flushed yet
+        db.write_values(1, points(5)).await.unwrap();
+        for id in [0, 2, 3] {
+            assert_readable(&db, id).await;
+        }
+        assert_eq!(db.open_shards().await, vec![1, 3]);
+        db.shard(1).await.unwrap().write_snapshot().await.unwrap();
+        assert_readable(&db, 3).await;
+        assert_readable(&db, 0).await;
+        assert_eq!(db.open_shards().await, vec![0, 3]);
+
+        db.close().await.unwrap();
+        assert!(db.open_shards().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_database_eviction_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let clock = SimulatedClock::new(1_000_000_000);
+        let db = Database::new(
+            StorageOperator::root(path.as_str()).unwrap(),
+            DatabaseOptions {
+                max_open_shards: 1,
+                shard: ShardOptions {
+                    clock: Arc::new(clock.clone()),
+                    ..Default::default()
+                },
+            },
+        );
+
+        drop(db.shard(0).await.unwrap());
+        clock.advance(Duration::from_secs(90));
+        drop(db.shard(1).await.unwrap());
+
+        let evictions = db.diagnostics().await.evictions;
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0].shard_id, 0);
+        assert_eq!(evictions[0].idle, Duration::from_secs(90));
+        assert_eq!(evictions[0].at, UNIX_EPOCH + Duration::from_secs(91));
+        db.close().await.unwrap();
+    }
+}
@@ -1,5 +1,11 @@
+pub mod database;
 pub mod tsm1;
 
+pub use tsm1::diff::{diff, Diff};
+pub use tsm1::repair::repair_index;
+
+/// MAX_TSM_FILE_SIZE is the default size past which the output of a `Compactor`
+/// rolls to a new file.
 pub const MAX_TSM_FILE_SIZE: u32 = 2048 * 1024 * 1024; // 2GB
 
 /// COMPACTION_TEMP_EXTENSION is the extension used for temporary files created during compaction.
@@ -8,5 +14,9 @@ pub(crate) const COMPACTION_TEMP_EXTENSION: &'static str = "tmp";
 /// TSMFILE_EXTENSION is the extension used for TSM files.
 pub const TSM_FILE_EXTENSION: &'static str = "tsm";
 
+/// KEY_STATS_FILE_EXTENSION is the extension of the sidecar file persisting the key
+/// stats of a TSM file, see `KeyStats`.
+pub const KEY_STATS_FILE_EXTENSION: &str = "stats";
+
 /// The extension used to describe corrupt snapshot files.
 pub const BAD_TSM_FILE_EXTENSION: &'static str = "bad";
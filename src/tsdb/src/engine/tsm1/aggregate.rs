@@ -0,0 +1,68 @@
+use crate::engine::tsm1::block::decoder::FloatOrIntSoA;
+use crate::engine::tsm1::block::{BLOCK_FLOAT64, BLOCK_INTEGER};
+use crate::engine::tsm1::value::{FloatSummary, IntegerSummary, SumMode, Values};
+
+/// Aggregate summarizes the values of a float or integer series within a time
+/// range: their count, minimum, maximum, sum and mean, see `Engine::aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Float(FloatSummary),
+    Integer(IntegerSummary),
+}
+
+impl Aggregate {
+    /// new returns the empty aggregate of values of the block type typ, float
+    /// values being summed per mode.
+    pub fn new(typ: u8, mode: SumMode) -> anyhow::Result<Self> {
+        match typ {
+            BLOCK_FLOAT64 => Ok(Self::Float(FloatSummary::new(mode))),
+            BLOCK_INTEGER => Ok(Self::Integer(IntegerSummary::default())),
+            _ => Err(anyhow!("unsupported block type for aggregate: {}", typ)),
+        }
+    }
+
+    /// new_soa returns the flat array to decode the blocks of the aggregate into
+    /// with `decode_block_soa`.
+    pub fn new_soa(&self) -> FloatOrIntSoA {
+        match self {
+            Self::Float(_) => FloatOrIntSoA::Float(vec![]),
+            Self::Integer(_) => FloatOrIntSoA::Integer(vec![]),
+        }
+    }
+
+    /// add_soa adds the values of a block decoded by `decode_block_soa`. An
+    /// integer sum not fitting its accumulator fails with `SumOverflow`.
+    pub fn add_soa(&mut self, values: &FloatOrIntSoA) -> anyhow::Result<()> {
+        match (self, values) {
+            (Self::Float(summary), FloatOrIntSoA::Float(values)) => summary.add_slice(values),
+            (Self::Integer(summary), FloatOrIntSoA::Integer(values)) => {
+                summary.add_slice(values)?
+            }
+            _ => return Err(anyhow!("invalid soa values for aggregate")),
+        }
+        Ok(())
+    }
+
+    /// add_values adds values of the type of the aggregate, see `add_soa`.
+    pub fn add_values(&mut self, values: &Values) -> anyhow::Result<()> {
+        match (self, values) {
+            (Self::Float(summary), Values::Float(values)) => summary.add_values(values),
+            (Self::Integer(summary), Values::Integer(values)) => summary.add_values(values)?,
+            (_, values) => {
+                return Err(anyhow!(
+                    "can not aggregate values of block type {}",
+                    values.block_type()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// count returns the number of values added.
+    pub fn count(&self) -> u64 {
+        match self {
+            Self::Float(summary) => summary.count(),
+            Self::Integer(summary) => summary.count(),
+        }
+    }
+}
@@ -16,7 +16,7 @@ use crate::engine::tsm1::codec::string::StringDecoder;
 use crate::engine::tsm1::codec::timestamp::TimeDecoder;
 use crate::engine::tsm1::codec::unsigned::UnsignedDecoder;
 use crate::engine::tsm1::codec::varint::VarInt;
-use crate::engine::tsm1::codec::{timestamp, Decoder};
+use crate::engine::tsm1::codec::{batch, timestamp, Decoder};
 use crate::engine::tsm1::value::{
     BooleanValues, FieldType, FloatValues, IntegerValues, StringValues, TimeValue, UnsignedValues,
     Value, Values,
@@ -32,12 +32,11 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
     }
 
     let (typ, tb, vb) = unpack_block(block)?;
-    let sz = timestamp::count_timestamps(tb)?;
 
     match typ {
         BLOCK_FLOAT64 => {
             if let Values::Float(values) = values {
-                decode_float_block_values(tb, vb, sz, values)
+                decode_float_block_values(tb, vb, values)
             } else {
                 Err(anyhow!(
                     "invalid block type: exp {}, got {}",
@@ -48,7 +47,7 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
         }
         BLOCK_INTEGER => {
             if let Values::Integer(values) = values {
-                decode_integer_block_values(tb, vb, sz, values)
+                decode_integer_block_values(tb, vb, values)
             } else {
                 Err(anyhow!(
                     "invalid block type: exp {}, got {}",
@@ -59,7 +58,7 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
         }
         BLOCK_BOOLEAN => {
             if let Values::Bool(values) = values {
-                decode_bool_block_values(tb, vb, sz, values)
+                decode_bool_block_values(tb, vb, values)
             } else {
                 Err(anyhow!(
                     "invalid block type: exp {}, got {}",
@@ -70,7 +69,7 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
         }
         BLOCK_STRING => {
             if let Values::String(values) = values {
-                decode_string_block_values(tb, vb, sz, values)
+                decode_string_block_values(tb, vb, values)
             } else {
                 Err(anyhow!(
                     "invalid block type: exp {}, got {}",
@@ -81,7 +80,7 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
         }
         BLOCK_UNSIGNED => {
             if let Values::Unsigned(values) = values {
-                decode_unsigned_block_values(tb, vb, sz, values)
+                decode_unsigned_block_values(tb, vb, values)
             } else {
                 Err(anyhow!(
                     "invalid block type: exp {}, got {}",
@@ -95,31 +94,40 @@ pub fn decode_block(block: &[u8], values: &mut Values) -> anyhow::Result<()> {
 }
 
 pub fn decode_float_block(block: &[u8], values: &mut FloatValues) -> anyhow::Result<()> {
-    let (tb, vb, sz) = pre_decode(block, BLOCK_FLOAT64)?;
-    decode_float_block_values(tb, vb, sz, values)
+    let (tb, vb) = unpack_typed_block(block, BLOCK_FLOAT64)?;
+    decode_float_block_values(tb, vb, values)
 }
 
 pub fn decode_integer_block(block: &[u8], values: &mut IntegerValues) -> anyhow::Result<()> {
-    let (tb, vb, sz) = pre_decode(block, BLOCK_INTEGER)?;
-    decode_integer_block_values(tb, vb, sz, values)
+    let (tb, vb) = unpack_typed_block(block, BLOCK_INTEGER)?;
+    decode_integer_block_values(tb, vb, values)
 }
 
 pub fn decode_bool_block(block: &[u8], values: &mut BooleanValues) -> anyhow::Result<()> {
-    let (tb, vb, sz) = pre_decode(block, BLOCK_BOOLEAN)?;
-    decode_bool_block_values(tb, vb, sz, values)
+    let (tb, vb) = unpack_typed_block(block, BLOCK_BOOLEAN)?;
+    decode_bool_block_values(tb, vb, values)
 }
 
 pub fn decode_string_block(block: &[u8], values: &mut StringValues) -> anyhow::Result<()> {
-    let (tb, vb, sz) = pre_decode(block, BLOCK_STRING)?;
-    decode_string_block_values(tb, vb, sz, values)
+    let (tb, vb) = unpack_typed_block(block, BLOCK_STRING)?;
+    decode_string_block_values(tb, vb, values)
 }
 
 pub fn decode_unsigned_block(block: &[u8], values: &mut UnsignedValues) -> anyhow::Result<()> {
-    let (tb, vb, sz) = pre_decode(block, BLOCK_UNSIGNED)?;
-    decode_unsigned_block_values(tb, vb, sz, values)
+    let (tb, vb) = unpack_typed_block(block, BLOCK_UNSIGNED)?;
+    decode_unsigned_block_values(tb, vb, values)
 }
 
 fn pre_decode(block: &[u8], expect_typ: u8) -> anyhow::Result<(&[u8], &[u8], usize)> {
+    let (tb, vb) = unpack_typed_block(block, expect_typ)?;
+    let sz = timestamp::count_timestamps(tb)?;
+
+    Ok((tb, vb, sz))
+}
+
+/// unpack_typed_block returns the timestamps and values parts of a block of type
+/// `expect_typ`.
+fn unpack_typed_block(block: &[u8], expect_typ: u8) -> anyhow::Result<(&[u8], &[u8])> {
     if block.len() <= ENCODED_BLOCK_HEADER_SIZE {
         return Err(anyhow!(
             "decode of short block: got {}, exp {}",
@@ -136,100 +144,135 @@ fn pre_decode(block: &[u8], expect_typ: u8) -> anyhow::Result<(&[u8], &[u8], usi
             typ
         ));
     }
-    let sz = timestamp::count_timestamps(tb)?;
 
-    Ok((tb, vb, sz))
+    Ok((tb, vb))
 }
 
-fn decode_float_block_values(
-    tb: &[u8],
-    vb: &[u8],
-    sz: usize,
-    values: &mut FloatValues,
-) -> anyhow::Result<()> {
-    let ts_dec = TimeDecoder::new(tb)?;
-    let v_dec = FloatDecoder::new(vb)?;
-    decode_block_using(sz, ts_dec, v_dec, values)?;
-    Ok(())
+fn decode_float_block_values(tb: &[u8], vb: &[u8], values: &mut FloatValues) -> anyhow::Result<()> {
+    batch::decode_all_f64(tb, vb, values)
 }
 
 fn decode_integer_block_values(
     tb: &[u8],
     vb: &[u8],
-    sz: usize,
     values: &mut IntegerValues,
 ) -> anyhow::Result<()> {
-    let ts_dec = TimeDecoder::new(tb)?;
-    let v_dec = IntegerDecoder::new(vb)?;
-    decode_block_using(sz, ts_dec, v_dec, values)?;
-    Ok(())
+    batch::decode_all_i64(tb, vb, values)
 }
 
 fn decode_bool_block_values(
     tb: &[u8],
     vb: &[u8],
-    sz: usize,
     values: &mut BooleanValues,
 ) -> anyhow::Result<()> {
-    let ts_dec = TimeDecoder::new(tb)?;
-    let v_dec = BooleanDecoder::new(vb)?;
-    decode_block_using(sz, ts_dec, v_dec, values)?;
-    Ok(())
+    batch::decode_all_bool(tb, vb, values)
 }
 
 fn decode_string_block_values(
     tb: &[u8],
     vb: &[u8],
-    sz: usize,
     values: &mut StringValues,
 ) -> anyhow::Result<()> {
-    let ts_dec = TimeDecoder::new(tb)?;
-    let v_dec = StringDecoder::new(vb)?;
-    decode_block_using(sz, ts_dec, v_dec, values)?;
-    Ok(())
+    batch::decode_all_string(tb, vb, values)
 }
 
 fn decode_unsigned_block_values(
     tb: &[u8],
     vb: &[u8],
-    sz: usize,
     values: &mut UnsignedValues,
 ) -> anyhow::Result<()> {
+    batch::decode_all_u64(tb, vb, values)
+}
+
+/// FloatOrIntSoA holds the values of a numeric block as a flat array, the
+/// structure-of-arrays counterpart of `Values::Float` and `Values::Integer`.
+/// Timestamps are decoded into a separate `Vec<i64>` so aggregates can run
+/// over contiguous slices.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FloatOrIntSoA {
+    Float(Vec<f64>),
+    Integer(Vec<i64>),
+}
+
+impl FloatOrIntSoA {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Float(values) => values.len(),
+            Self::Integer(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// clear removes the values, keeping the allocation for the next block.
+    pub fn clear(&mut self) {
+        match self {
+            Self::Float(values) => values.clear(),
+            Self::Integer(values) => values.clear(),
+        }
+    }
+}
+
+/// decode_block_soa decodes a float or integer block of type `typ`, appending
+/// the timestamps to `times` and the values to `values`. The variant of
+/// `values` must match `typ`.
+pub fn decode_block_soa(
+    typ: u8,
+    block: &[u8],
+    times: &mut Vec<i64>,
+    values: &mut FloatOrIntSoA,
+) -> anyhow::Result<()> {
+    let (tb, vb, sz) = pre_decode(block, typ)?;
     let ts_dec = TimeDecoder::new(tb)?;
-    let v_dec = UnsignedDecoder::new(vb)?;
-    decode_block_using(sz, ts_dec, v_dec, values)?;
-    Ok(())
+
+    match (typ, values) {
+        (BLOCK_FLOAT64, FloatOrIntSoA::Float(values)) => {
+            let v_dec = FloatDecoder::new(vb)?;
+            decode_block_soa_using(sz, ts_dec, v_dec, times, values)
+        }
+        (BLOCK_INTEGER, FloatOrIntSoA::Integer(values)) => {
+            let v_dec = IntegerDecoder::new(vb)?;
+            decode_block_soa_using(sz, ts_dec, v_dec, times, values)
+        }
+        (BLOCK_FLOAT64, _) | (BLOCK_INTEGER, _) => {
+            Err(anyhow!("invalid soa values for block type {}", typ))
+        }
+        _ => Err(anyhow!("unsupported block type for soa decode: {}", typ)),
+    }
 }
-fn decode_block_using<T>(
+
+fn decode_block_soa_using<T>(
     sz: usize,
     mut ts_dec: impl Decoder<i64>,
     mut v_dec: impl Decoder<T>,
-    values: &mut Vec<TimeValue<T>>,
+    times: &mut Vec<i64>,
+    values: &mut Vec<T>,
 ) -> anyhow::Result<()>
 where
     T: FieldType,
     TimeValue<T>: Value,
 {
-    let remain = values.capacity() - values.len();
-    if remain < sz {
-        values.reserve_exact(sz - remain);
-    }
+    times.reserve(sz);
+    values.reserve(sz);
 
     for _ in 0..sz {
         if !ts_dec.next() {
             return Err(anyhow!("can not read all timestamp block"));
         }
         if let Some(err) = ts_dec.err() {
-            return Err(anyhow!("read timestamp block error: {}", err.to_string()));
+            return Err(anyhow!("read timestamp block error: {}", err));
         }
         if !v_dec.next() {
             return Err(anyhow!("can not read all values block"));
         }
         if let Some(err) = v_dec.err() {
-            return Err(anyhow!("read values block error: {}", err.to_string()));
+            return Err(anyhow!("read values block error: {}", err));
         }
 
-        values.push(TimeValue::new(ts_dec.read(), v_dec.read()));
+        times.push(ts_dec.read());
+        values.push(v_dec.read());
     }
 
     Ok(())
@@ -516,3 +559,143 @@ impl<'a> FloatValueBuilder<'a> {
         self.buf.take().map(|x| x.into_arc())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::block::decoder::{decode_block, decode_block_soa, FloatOrIntSoA};
+    use crate::engine::tsm1::block::encoder::encode_block;
+    use crate::engine::tsm1::block::{
+        BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+    };
+    use crate::engine::tsm1::codec::float::UnsupportedNaN;
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    fn encode(values: Values) -> Vec<u8> {
+        let mut block = vec![];
+        encode_block(&mut block, values).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_decode_block_soa_float() {
+        let values: Vec<TimeValue<f64>> = (0..1000)
+            .map(|i| TimeValue::new(i * 10, i as f64 * 1.5 - 7.0))
+            .collect();
+        let block = encode(Values::Float(values.clone()));
+
+        let mut aos = Values::Float(vec![]);
+        decode_block(&block, &mut aos).unwrap();
+        let aos = match aos {
+            Values::Float(aos) => aos,
+            _ => unreachable!(),
+        };
+
+        let mut times = vec![];
+        let mut soa = FloatOrIntSoA::Float(vec![]);
+        decode_block_soa(BLOCK_FLOAT64, &block, &mut times, &mut soa).unwrap();
+
+        let exp_times: Vec<i64> = aos.iter().map(|v| v.unix_nano).collect();
+        let exp_values: Vec<f64> = aos.iter().map(|v| v.value).collect();
+        assert_eq!(times, exp_times);
+        assert_eq!(soa, FloatOrIntSoA::Float(exp_values));
+    }
+
+    #[test]
+    fn test_decode_block_soa_integer() {
+        let values: Vec<TimeValue<i64>> = (0..1000)
+            .map(|i| TimeValue::new(1_000_000 + i, (i % 17) * 1000 - 5000))
+            .collect();
+        let block = encode(Values::Integer(values.clone()));
+
+        let mut aos = Values::Integer(vec![]);
+        decode_block(&block, &mut aos).unwrap();
+        let aos = match aos {
+            Values::Integer(aos) => aos,
+            _ => unreachable!(),
+        };
+
+        // decoding appends to the existing vectors
+        let mut times = vec![-1];
+        let mut soa = FloatOrIntSoA::Integer(vec![-1]);
+        decode_block_soa(BLOCK_INTEGER, &block, &mut times, &mut soa).unwrap();
+
+        let exp_times: Vec<i64> = std::iter::once(-1)
+            .chain(aos.iter().map(|v| v.unix_nano))
+            .collect();
+        let exp_values: Vec<i64> = std::iter::once(-1)
+            .chain(aos.iter().map(|v| v.value))
+            .collect();
+        assert_eq!(times, exp_times);
+        assert_eq!(soa, FloatOrIntSoA::Integer(exp_values));
+    }
+
+    #[test]
+    fn test_decode_block_soa_unsupported() {
+        let blocks = vec![
+            (
+                BLOCK_BOOLEAN,
+                encode(Values::Bool(vec![TimeValue::new(1, true)])),
+            ),
+            (
+                BLOCK_STRING,
+                encode(Values::String(vec![TimeValue::new(1, b"a".to_vec())])),
+            ),
+            (
+                BLOCK_UNSIGNED,
+                encode(Values::Unsigned(vec![TimeValue::new(1, 1)])),
+            ),
+        ];
+        for (typ, block) in blocks {
+            let mut times = vec![];
+            let mut soa = FloatOrIntSoA::Float(vec![]);
+            assert!(decode_block_soa(typ, &block, &mut times, &mut soa).is_err());
+            assert!(times.is_empty());
+            assert!(soa.is_empty());
+        }
+
+        // mismatched values variant
+        let block = encode(Values::Float(vec![TimeValue::new(1, 1.0)]));
+        let mut times = vec![];
+        let mut soa = FloatOrIntSoA::Integer(vec![]);
+        assert!(decode_block_soa(BLOCK_FLOAT64, &block, &mut times, &mut soa).is_err());
+
+        // block type differs from the requested one
+        let mut soa = FloatOrIntSoA::Integer(vec![]);
+        assert!(decode_block_soa(BLOCK_INTEGER, &block, &mut times, &mut soa).is_err());
+    }
+
+    #[test]
+    fn test_encode_block_float_special() {
+        let values = Values::Float(vec![
+            TimeValue::new(1, 0.0),
+            TimeValue::new(2, -0.0),
+            TimeValue::new(3, f64::INFINITY),
+            TimeValue::new(4, f64::NEG_INFINITY),
+            TimeValue::new(5, f64::from_bits(1)),
+        ]);
+        let block = encode(values.clone());
+
+        let mut got = Values::Float(vec![]);
+        decode_block(block.as_slice(), &mut got).unwrap();
+        match (&got, &values) {
+            (Values::Float(got), Values::Float(values)) => {
+                let got: Vec<_> = got.iter().map(|x| x.value.to_bits()).collect();
+                let want: Vec<_> = values.iter().map(|x| x.value.to_bits()).collect();
+                assert_eq!(got, want);
+            }
+            _ => unreachable!(),
+        }
+
+        let values = Values::Float(vec![
+            TimeValue::new(1, 1.0),
+            TimeValue::new(2, 2.0),
+            TimeValue::new(3, f64::NAN),
+        ]);
+        let mut block = vec![];
+        let err = encode_block(&mut block, values).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedNaN>(),
+            Some(&UnsupportedNaN { index: 2 })
+        );
+    }
+}
@@ -21,6 +21,8 @@ pub fn encode_block(dst: &mut Vec<u8>, values: Values) -> anyhow::Result<()> {
     }
 }
 
+/// encode_float_block fails with `UnsupportedNaN` holding the index of the first NaN
+/// value, see `FloatEncoder`.
 fn encode_float_block(buf: &mut Vec<u8>, values: Vec<TimeValue<f64>>) -> anyhow::Result<()> {
     let v_enc = FloatEncoder::new();
     let ts_enc = TimeEncoder::new(values.len());
@@ -0,0 +1,606 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::engine::tsm1::value::{Array, Values};
+
+/// CacheError are the errors returned when writing to the cache.
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    /// The write would grow the cache beyond its max size, the caller should back
+    /// off until a snapshot is flushed.
+    #[error("cache-max-memory-size exceeded: ({size}/{max_size})")]
+    CacheFull { size: u64, max_size: u64 },
+    #[error("snapshot in progress")]
+    SnapshotInProgress,
+}
+
+/// CacheSnapshot is the frozen content of a cache, keyed and sorted by series key,
+/// with the values of every key sorted and deduplicated.
+pub type CacheSnapshot = BTreeMap<Vec<u8>, Values>;
+
+type Store = HashMap<Vec<u8>, Entry>;
+
+/// Entry holds the values of a key, ordered lazily.
+#[derive(Debug, Clone)]
+struct Entry {
+    values: Values,
+    /// needs_sort is true if values may be unsorted or contain duplicates.
+    needs_sort: bool,
+}
+
+impl Entry {
+    fn new(values: Values) -> Self {
+        let needs_sort = !values.ordered();
+        Self { values, needs_sort }
+    }
+
+    fn add(&mut self, values: Values) -> anyhow::Result<()> {
+        if values.len() == 0 {
+            return Ok(());
+        }
+        if self.values.len() > 0 && self.values.max_time() >= values.min_time() {
+            self.needs_sort = true;
+        }
+        if !values.ordered() {
+            self.needs_sort = true;
+        }
+
+        match (&mut self.values, values) {
+            (Values::Float(dst), Values::Float(src)) => dst.extend(src),
+            (Values::Integer(dst), Values::Integer(src)) => dst.extend(src),
+            (Values::Bool(dst), Values::Bool(src)) => dst.extend(src),
+            (Values::String(dst), Values::String(src)) => dst.extend(src),
+            (Values::Unsigned(dst), Values::Unsigned(src)) => dst.extend(src),
+            _ => return Err(anyhow!("field type conflict")),
+        }
+        Ok(())
+    }
+
+    /// deduplicate sorts the values and removes duplicate timestamps, the value
+    /// written last wins.
+    fn deduplicate(&mut self) {
+        if self.needs_sort {
+            self.values.deduplicate();
+            self.needs_sort = false;
+        }
+    }
+}
+
+/// Cache holds the recent writes of a shard in memory until they are flushed to a
+/// TSM file.
+///
+/// Flushing goes through `snapshot`, which freezes the current entries while new
+/// writes continue into an empty store, and `clear_snapshot` once the snapshot is
+/// persisted. The max size covers both the store and the snapshot.
+///
+/// The store is copied on write while a `CacheView` holds it, so that views are
+/// unaffected by later writes.
+pub struct Cache {
+    store: RwLock<Arc<Store>>,
+    /// size is the size of the store in bytes, see `Array::size`.
+    size: AtomicU64,
+    max_size: u64,
+
+    snapshot: RwLock<Option<Arc<CacheSnapshot>>>,
+    snapshot_size: AtomicU64,
+    snapshotting: RwLock<bool>,
+}
+
+impl Cache {
+    /// new returns an empty cache holding at most `max_size` bytes, 0 for no limit.
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            store: RwLock::new(Arc::new(HashMap::new())),
+            size: AtomicU64::new(0),
+            max_size,
+            snapshot: RwLock::new(None),
+            snapshot_size: AtomicU64::new(0),
+            snapshotting: RwLock::new(false),
+        }
+    }
+
+    /// size returns the size of the cache, including the snapshot, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Acquire) + self.snapshot_size.load(Ordering::Acquire)
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// reserve grows the size by n bytes, or returns `CacheError::CacheFull` if the
+    /// cache would exceed its max size.
+    fn reserve(&self, n: u64) -> anyhow::Result<()> {
+        if self.max_size == 0 {
+            self.size.fetch_add(n, Ordering::AcqRel);
+            return Ok(());
+        }
+
+        self.size
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |size| {
+                let snapshot_size = self.snapshot_size.load(Ordering::Acquire);
+                if size + snapshot_size + n > self.max_size {
+                    None
+                } else {
+                    Some(size + n)
+                }
+            })
+            .map_err(|size| {
+                CacheError::CacheFull {
+                    size: size + self.snapshot_size.load(Ordering::Acquire),
+                    max_size: self.max_size,
+                }
+                .into()
+            })
+            .map(|_| ())
+    }
+
+    /// write writes the values of a key, returning `CacheError::CacheFull` if the
+    /// cache would exceed its max size.
+    pub fn write(&self, key: &[u8], values: Values) -> anyhow::Result<()> {
+        let n = values.size() as u64;
+        self.reserve(n)?;
+
+        let mut store = self.store.write().unwrap();
+        let store = Arc::make_mut(&mut store);
+        let r = match store.get_mut(key) {
+            Some(entry) => entry.add(values),
+            None => {
+                store.insert(key.to_vec(), Entry::new(values));
+                Ok(())
+            }
+        };
+        if r.is_err() {
+            self.size.fetch_sub(n, Ordering::AcqRel);
+        }
+        r
+    }
+
+    /// write_multi writes the values of several keys. Nothing is written if the
+    /// cache would exceed its max size. Keys failing to be written, e.g. because
+    /// of a field type conflict, don't prevent the others to be written, the last
+    /// error is returned.
+    pub fn write_multi(&self, values: BTreeMap<Vec<u8>, Values>) -> anyhow::Result<()> {
+        self.reserve_multi(values)?.write()
+    }
+
+    /// reserve_multi reserves the room of the values of several keys, returning
+    /// `CacheError::CacheFull` if the cache would exceed its max size. The values
+    /// are written by `CacheReservation::write`, e.g. once they are logged.
+    pub fn reserve_multi(
+        &self,
+        values: BTreeMap<Vec<u8>, Values>,
+    ) -> anyhow::Result<CacheReservation<'_>> {
+        let n: u64 = values.values().map(|x| x.size() as u64).sum();
+        self.reserve(n)?;
+        Ok(CacheReservation {
+            cache: self,
+            values: Some(values),
+            n,
+        })
+    }
+
+    /// values returns the sorted and deduplicated values of a key, including the
+    /// values of the snapshot being flushed.
+    pub fn values(&self, key: &[u8]) -> Option<Values> {
+        self.view().values(key)
+    }
+
+    /// view returns the current content of the cache, including the snapshot being
+    /// flushed, unaffected by later writes and snapshots.
+    pub fn view(&self) -> CacheView {
+        let snapshot = self.snapshot.read().unwrap();
+        let store = self.store.read().unwrap();
+        CacheView {
+            store: store.clone(),
+            snapshot: snapshot.clone(),
+        }
+    }
+
+    /// keys returns the sorted keys of the store.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let store = self.store.read().unwrap();
+        let mut keys: Vec<_> = store.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// deduplicate sorts and deduplicates the values of every key of the store.
+    pub fn deduplicate(&self) {
+        let mut store = self.store.write().unwrap();
+        let store = Arc::make_mut(&mut store);
+        for entry in store.values_mut() {
+            let before = entry.values.size() as u64;
+            entry.deduplicate();
+            self.size
+                .fetch_sub(before - entry.values.size() as u64, Ordering::AcqRel);
+        }
+    }
+
+    /// delete_range removes the values of keys within [min, max], from the store
+    /// and the snapshot.
+    pub fn delete_range(&self, keys: &[&[u8]], min: i64, max: i64) {
+        let mut guard = self.store.write().unwrap();
+        let store = Arc::make_mut(&mut guard);
+        for key in keys {
+            let entry = match store.get_mut(*key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let before = entry.values.size() as u64;
+            entry.deduplicate();
+            entry.values.exclude(min, max);
+            self.size
+                .fetch_sub(before - entry.values.size() as u64, Ordering::AcqRel);
+            if entry.values.len() == 0 {
+                store.remove(*key);
+            }
+        }
+        drop(guard);
+
+        let mut snapshot = self.snapshot.write().unwrap();
+        if let Some(snapshot) = snapshot.as_mut() {
+            let snapshot = Arc::make_mut(snapshot);
+            for key in keys {
+                if let Some(values) = snapshot.get_mut(*key) {
+                    let before = values.size() as u64;
+                    values.exclude(min, max);
+                    self.snapshot_size
+                        .fetch_sub(before - values.size() as u64, Ordering::AcqRel);
+                    if values.len() == 0 {
+                        snapshot.remove(*key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// snapshot freezes the current entries for flushing and continues with an
+    /// empty store. If a previous snapshot failed to be flushed, the current entries
+    /// are merged into it. Only one snapshot can be in progress at a time.
+    pub fn snapshot(&self) -> anyhow::Result<Arc<CacheSnapshot>> {
+        let mut snapshotting = self.snapshotting.write().unwrap();
+        if *snapshotting {
+            return Err(CacheError::SnapshotInProgress.into());
+        }
+
+        let mut snapshot = self.snapshot.write().unwrap();
+        let mut entries: HashMap<_, _> = match snapshot.take() {
+            Some(snapshot) => Arc::unwrap_or_clone(snapshot)
+                .into_iter()
+                .map(|(key, values)| (key, Entry::new(values)))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        {
+            let mut store = self.store.write().unwrap();
+            let store = std::mem::take(&mut *store);
+            for (key, entry) in Arc::unwrap_or_clone(store) {
+                match entries.get_mut(key.as_slice()) {
+                    Some(dst) => {
+                        if let Err(e) = dst.add(entry.values) {
+                            tracing::warn!("dropping cached values of {:?}: {}", key, e);
+                        }
+                    }
+                    None => {
+                        entries.insert(key, entry);
+                    }
+                }
+            }
+            let size = self.size.swap(0, Ordering::AcqRel);
+            self.snapshot_size.fetch_add(size, Ordering::AcqRel);
+        }
+
+        let mut size = 0;
+        let frozen: CacheSnapshot = entries
+            .into_iter()
+            .map(|(key, mut entry)| {
+                entry.deduplicate();
+                size += entry.values.size() as u64;
+                (key, entry.values)
+            })
+            .collect();
+        self.snapshot_size.store(size, Ordering::Release);
+
+        let frozen = Arc::new(frozen);
+        *snapshot = Some(frozen.clone());
+        *snapshotting = true;
+
+        Ok(frozen)
+    }
+
+    /// clear_snapshot ends the snapshot in progress. The snapshot is dropped if it
+    /// was flushed successfully, otherwise it is kept and merged into the next one.
+    pub fn clear_snapshot(&self, success: bool) {
+        let mut snapshotting = self.snapshotting.write().unwrap();
+        *snapshotting = false;
+
+        if success {
+            let mut snapshot = self.snapshot.write().unwrap();
+            *snapshot = None;
+            self.snapshot_size.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// CacheReservation holds the values of a write and the room reserved for them in
+/// the cache, see `Cache::reserve_multi`. The room is released if the values are
+/// dropped without being written.
+pub struct CacheReservation<'a> {
+    cache: &'a Cache,
+    values: Option<BTreeMap<Vec<u8>, Values>>,
+    n: u64,
+}
+
+impl<'a> CacheReservation<'a> {
+    /// write writes the values into the cache. Keys failing to be written, e.g.
+    /// because of a field type conflict, don't prevent the others to be written,
+    /// the last error is returned.
+    pub fn write(mut self) -> anyhow::Result<()> {
+        let values = self.values.take().unwrap_or_default();
+
+        let mut r = Ok(());
+        let mut store = self.cache.store.write().unwrap();
+        let store = Arc::make_mut(&mut store);
+        for (key, values) in values {
+            let n = values.size() as u64;
+            let added = match store.get_mut(key.as_slice()) {
+                Some(entry) => entry.add(values),
+                None => {
+                    store.insert(key, Entry::new(values));
+                    Ok(())
+                }
+            };
+            if let Err(e) = added {
+                self.cache.size.fetch_sub(n, Ordering::AcqRel);
+                r = Err(e);
+            }
+        }
+        r
+    }
+}
+
+impl<'a> Drop for CacheReservation<'a> {
+    fn drop(&mut self) {
+        if self.values.is_some() {
+            self.cache.size.fetch_sub(self.n, Ordering::AcqRel);
+        }
+    }
+}
+
+/// CacheView is the content of a cache at the time it was taken.
+pub struct CacheView {
+    store: Arc<Store>,
+    snapshot: Option<Arc<CacheSnapshot>>,
+}
+
+impl CacheView {
+    /// values returns the sorted and deduplicated values of a key.
+    pub fn values(&self, key: &[u8]) -> Option<Values> {
+        let mut entry = self
+            .snapshot
+            .as_ref()
+            .and_then(|x| x.get(key))
+            .map(|x| Entry::new(x.clone()));
+
+        if let Some(values) = self.store.get(key) {
+            match entry.as_mut() {
+                // values conflicting with the snapshot are dropped by `Cache::snapshot`
+                Some(entry) => entry.add(values.values.clone()).unwrap_or_default(),
+                None => entry = Some(values.clone()),
+            }
+        }
+
+        entry.map(|mut x| {
+            x.deduplicate();
+            x.values
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use crate::engine::tsm1::cache::{Cache, CacheError};
+    use crate::engine::tsm1::value::{Array, TimeValue, Values};
+
+    fn float_values(points: &[(i64, f64)]) -> Values {
+        Values::Float(points.iter().map(|(t, v)| TimeValue::new(*t, *v)).collect())
+    }
+
+    #[test]
+    fn test_cache_write_dedup() {
+        let cache = Cache::new(0);
+        cache
+            .write(b"cpu#!~#value", float_values(&[(3, 3.0), (1, 1.0)]))
+            .unwrap();
+        cache
+            .write(b"cpu#!~#value", float_values(&[(2, 2.0), (3, 4.0)]))
+            .unwrap();
+        assert_eq!(cache.size(), 4 * 16);
+
+        let err = cache
+            .write(b"cpu#!~#value", Values::Integer(vec![TimeValue::new(4, 1)]))
+            .unwrap_err();
+        assert!(err.to_string().contains("conflict"));
+        assert_eq!(cache.size(), 4 * 16);
+
+        assert_eq!(
+            cache.values(b"cpu#!~#value"),
+            Some(float_values(&[(1, 1.0), (2, 2.0), (3, 4.0)]))
+        );
+
+        cache.deduplicate();
+        assert_eq!(cache.size(), 3 * 16);
+
+        cache.delete_range(&[b"cpu#!~#value"], 2, 2);
+        assert_eq!(
+            cache.values(b"cpu#!~#value"),
+            Some(float_values(&[(1, 1.0), (3, 4.0)]))
+        );
+        assert_eq!(cache.size(), 2 * 16);
+    }
+
+    #[test]
+    fn test_cache_snapshot() {
+        let cache = Cache::new(0);
+        cache.write(b"a", float_values(&[(1, 1.0)])).unwrap();
+        cache
+            .write(b"b", float_values(&[(2, 1.0), (1, 1.0)]))
+            .unwrap();
+
+        let snapshot = cache.snapshot().unwrap();
+        assert!(cache.snapshot().is_err());
+
+        // writes during the snapshot don't change it
+        cache.write(b"a", float_values(&[(2, 2.0)])).unwrap();
+        cache.write(b"c", float_values(&[(1, 1.0)])).unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(
+            snapshot.get(b"a".as_slice()),
+            Some(&float_values(&[(1, 1.0)]))
+        );
+        assert_eq!(
+            snapshot.get(b"b".as_slice()),
+            Some(&float_values(&[(1, 1.0), (2, 1.0)]))
+        );
+
+        // but reads see both
+        assert_eq!(
+            cache.values(b"a"),
+            Some(float_values(&[(1, 1.0), (2, 2.0)]))
+        );
+        assert_eq!(cache.keys(), vec![b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(cache.size(), 5 * 16);
+
+        // a failed flush keeps the snapshot for the next one
+        cache.clear_snapshot(false);
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(
+            snapshot.get(b"a".as_slice()),
+            Some(&float_values(&[(1, 1.0), (2, 2.0)]))
+        );
+        assert!(cache.keys().is_empty());
+
+        cache.clear_snapshot(true);
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.values(b"a"), None);
+    }
+
+    #[test]
+    fn test_cache_view() {
+        let cache = Cache::new(0);
+        cache.write(b"a", float_values(&[(1, 1.0)])).unwrap();
+        cache.snapshot().unwrap();
+        cache.write(b"a", float_values(&[(2, 2.0)])).unwrap();
+
+        let view = cache.view();
+        cache.write(b"a", float_values(&[(3, 3.0)])).unwrap();
+        cache.delete_range(&[b"a"], 1, 1);
+        cache.clear_snapshot(true);
+
+        assert_eq!(view.values(b"a"), Some(float_values(&[(1, 1.0), (2, 2.0)])));
+        assert_eq!(
+            cache.values(b"a"),
+            Some(float_values(&[(2, 2.0), (3, 3.0)]))
+        );
+    }
+
+    #[test]
+    fn test_cache_max_size() {
+        let cache = Cache::new(10 * 16);
+
+        let mut values = BTreeMap::new();
+        values.insert(b"a".to_vec(), float_values(&[(1, 1.0), (2, 2.0)]));
+        values.insert(b"b".to_vec(), float_values(&[(1, 1.0)]));
+        cache.write_multi(values).unwrap();
+
+        for i in 0..7 {
+            cache.write(b"c", float_values(&[(i, 1.0)])).unwrap();
+        }
+        let err = cache.write(b"c", float_values(&[(8, 1.0)])).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(CacheError::CacheFull {
+                size: 160,
+                max_size: 160
+            })
+        ));
+
+        // the snapshot still counts until it is flushed
+        cache.snapshot().unwrap();
+        assert!(cache.write(b"c", float_values(&[(8, 1.0)])).is_err());
+        cache.clear_snapshot(true);
+        cache.write(b"c", float_values(&[(8, 1.0)])).unwrap();
+    }
+
+    #[test]
+    fn test_cache_reserve_multi() {
+        let cache = Cache::new(4 * 16);
+
+        let mut values = BTreeMap::new();
+        values.insert(b"a".to_vec(), float_values(&[(1, 1.0), (2, 2.0)]));
+        values.insert(b"b".to_vec(), float_values(&[(1, 1.0)]));
+
+        // the room is taken until the reservation is written or dropped
+        let reservation = cache.reserve_multi(values.clone()).unwrap();
+        assert_eq!(cache.size(), 3 * 16);
+        assert!(cache.reserve_multi(values.clone()).is_err());
+        assert_eq!(cache.values(b"a"), None);
+        drop(reservation);
+        assert_eq!(cache.size(), 0);
+
+        cache.reserve_multi(values).unwrap().write().unwrap();
+        assert_eq!(cache.size(), 3 * 16);
+        assert_eq!(
+            cache.values(b"a"),
+            Some(float_values(&[(1, 1.0), (2, 2.0)]))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_cache_concurrent_write() {
+        let max_size = 1000 * 16;
+        let cache = Arc::new(Cache::new(max_size));
+
+        let mut tasks = vec![];
+        for i in 0..8 {
+            let cache = cache.clone();
+            tasks.push(tokio::spawn(async move {
+                let key = format!("cpu,host={}#!~#value", i % 4);
+                let mut full = 0;
+                for t in 0..200 {
+                    let values = float_values(&[(t * 8 + i, t as f64)]);
+                    if cache.write(key.as_bytes(), values).is_err() {
+                        full += 1;
+                    }
+                    tokio::task::yield_now().await;
+                }
+                full
+            }));
+        }
+
+        let mut full = 0;
+        for task in tasks {
+            full += task.await.unwrap();
+        }
+
+        // 1600 values are written, those beyond the limit are rejected
+        assert_eq!(full, 600);
+        assert_eq!(cache.size(), max_size);
+
+        let snapshot = cache.snapshot().unwrap();
+        let count: usize = snapshot.values().map(|x| x.len()).sum();
+        assert_eq!(count, 1000);
+        assert_eq!(snapshot.len(), 4);
+        for values in snapshot.values() {
+            assert!(values.ordered());
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Batch decoders decode the timestamps and values of a block straight into a vector
+//! of values. They replace a `next`/`read` call pair per value on both decoders with
+//! loops over the encoded data, e.g. a simple8b word at a time for integers and
+//! timestamps.
+
+use crate::engine::tsm1::codec::{boolean, float, integer, string, timestamp};
+use crate::engine::tsm1::value::{FieldType, TimeValue};
+
+/// decode_all_f64 appends the values of the encoded float block parts `tb` and `vb`
+/// to `out`. On error `out` is left as it was.
+pub fn decode_all_f64(tb: &[u8], vb: &[u8], out: &mut Vec<TimeValue<f64>>) -> anyhow::Result<()> {
+    decode_all_using(tb, out, |values| {
+        let mut i = 0;
+        float::decode_all_floats(vb, values.len(), |v| {
+            values[i].value = v;
+            i += 1;
+        })
+    })
+}
+
+/// decode_all_i64 appends the values of the encoded integer block parts `tb` and `vb`
+/// to `out`. On error `out` is left as it was.
+pub fn decode_all_i64(tb: &[u8], vb: &[u8], out: &mut Vec<TimeValue<i64>>) -> anyhow::Result<()> {
+    decode_all_using(tb, out, |values| {
+        let mut i = 0;
+        integer::decode_all_integers(vb, values.len(), |v| {
+            values[i].value = v;
+            i += 1;
+        })
+    })
+}
+
+/// decode_all_u64 appends the values of the encoded unsigned block parts `tb` and
+/// `vb` to `out`. On error `out` is left as it was.
+pub fn decode_all_u64(tb: &[u8], vb: &[u8], out: &mut Vec<TimeValue<u64>>) -> anyhow::Result<()> {
+    decode_all_using(tb, out, |values| {
+        let mut i = 0;
+        integer::decode_all_integers(vb, values.len(), |v| {
+            values[i].value = v as u64;
+            i += 1;
+        })
+    })
+}
+
+/// decode_all_bool appends the values of the encoded boolean block parts `tb` and
+/// `vb` to `out`. On error `out` is left as it was.
+pub fn decode_all_bool(tb: &[u8], vb: &[u8], out: &mut Vec<TimeValue<bool>>) -> anyhow::Result<()> {
+    decode_all_using(tb, out, |values| {
+        let mut i = 0;
+        boolean::decode_all_booleans(vb, values.len(), |v| {
+            values[i].value = v;
+            i += 1;
+        })
+    })
+}
+
+/// decode_all_string appends the values of the encoded string block parts `tb` and
+/// `vb` to `out`. On error `out` is left as it was.
+pub fn decode_all_string(
+    tb: &[u8],
+    vb: &[u8],
+    out: &mut Vec<TimeValue<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    decode_all_using(tb, out, |values| {
+        let mut i = 0;
+        string::decode_all_strings(vb, values.len(), |v| {
+            values[i].value = v;
+            i += 1;
+        })
+    })
+}
+
+/// decode_all_using appends a value per timestamp of `tb` to `out`, then fills their
+/// values with `decode_values`, which returns the number of values it decoded.
+fn decode_all_using<T>(
+    tb: &[u8],
+    out: &mut Vec<TimeValue<T>>,
+    decode_values: impl FnOnce(&mut [TimeValue<T>]) -> anyhow::Result<usize>,
+) -> anyhow::Result<()>
+where
+    T: FieldType,
+{
+    let sz = timestamp::count_timestamps(tb)?;
+    out.reserve(sz);
+
+    let start = out.len();
+    let r = timestamp::decode_all_timestamps(tb, sz, |t| out.push(TimeValue::new(t, T::default())))
+        .and_then(|n| match n < sz {
+            true => Err(anyhow!("can not read all timestamp block")),
+            false => decode_values(&mut out[start..]),
+        })
+        .and_then(|n| match n < sz {
+            true => Err(anyhow!("can not read all values block")),
+            false => Ok(()),
+        });
+
+    if r.is_err() {
+        out.truncate(start);
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::engine::tsm1::block::decoder::unpack_block;
+    use crate::engine::tsm1::block::encoder::encode_block;
+    use crate::engine::tsm1::codec::batch::{
+        decode_all_bool, decode_all_f64, decode_all_i64, decode_all_string, decode_all_u64,
+    };
+    use crate::engine::tsm1::codec::boolean::BooleanDecoder;
+    use crate::engine::tsm1::codec::float::FloatDecoder;
+    use crate::engine::tsm1::codec::integer::IntegerDecoder;
+    use crate::engine::tsm1::codec::string::StringDecoder;
+    use crate::engine::tsm1::codec::timestamp::TimeDecoder;
+    use crate::engine::tsm1::codec::unsigned::UnsignedDecoder;
+    use crate::engine::tsm1::codec::Decoder;
+    use crate::engine::tsm1::value::{FieldType, TimeValue, Values};
+
+    /// decode_iter decodes the block parts with the `Decoder` of the type, one value
+    /// per `next`/`read`.
+    fn decode_iter<T: FieldType>(
+        tb: &[u8],
+        mut ts_dec: TimeDecoder,
+        mut v_dec: impl Decoder<T>,
+    ) -> Vec<TimeValue<T>> {
+        assert!(!tb.is_empty());
+        let mut values = vec![];
+        while ts_dec.next() {
+            assert!(v_dec.next());
+            values.push(TimeValue::new(ts_dec.read(), v_dec.read()));
+        }
+        assert!(ts_dec.err().is_none());
+        assert!(v_dec.err().is_none());
+        values
+    }
+
+    /// timestamps returns timestamps for each of the encodings: regular ones are run
+    /// length encoded, small deltas packed, and huge ones stored uncompressed.
+    fn timestamps(rng: &mut StdRng, n: usize) -> Vec<Vec<i64>> {
+        let regular = (0..n as i64).map(|t| 1_000_000_000 + t * 10_000).collect();
+        let mut t = 0;
+        let jitter = (0..n)
+            .map(|_| {
+                t += rng.gen_range(1..1_000);
+                t
+            })
+            .collect();
+        // a single delta beyond the range of simple8b stores the block uncompressed
+        let mut t = 0;
+        let huge = (0..n)
+            .map(|i| {
+                t += match i {
+                    0 => i64::MIN / 2,
+                    1 => rng.gen_range(1 << 61..1 << 62),
+                    _ => rng.gen_range(1..1_000),
+                };
+                t
+            })
+            .collect();
+        vec![regular, jitter, huge]
+    }
+
+    /// unpack encodes values and returns the timestamps and values parts of the block.
+    fn unpack(values: Values) -> (Vec<u8>, Vec<u8>) {
+        let mut block = vec![];
+        encode_block(&mut block, values).unwrap();
+        let (_, tb, vb) = unpack_block(block.as_slice()).unwrap();
+        (tb.to_vec(), vb.to_vec())
+    }
+
+    #[test]
+    fn test_decode_all_matches_decoders() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for n in [1, 2, 239, 240, 241, 1000] {
+            for ts in timestamps(&mut rng, n) {
+                // constant deltas are run length encoded, small ones packed and
+                // large ones uncompressed
+                let integers: Vec<Vec<i64>> = vec![
+                    (0..n as i64).map(|i| i * 3 - 7).collect(),
+                    (0..n).map(|_| rng.gen_range(-1000..1000)).collect(),
+                    (0..n).map(|_| rng.gen()).collect(),
+                ];
+                for ints in integers {
+                    let values = ts.iter().zip(ints.iter());
+
+                    let (tb, vb) = unpack(Values::Integer(
+                        values
+                            .clone()
+                            .map(|(t, v)| TimeValue::new(*t, *v))
+                            .collect(),
+                    ));
+                    let want = decode_iter(
+                        &tb,
+                        TimeDecoder::new(&tb).unwrap(),
+                        IntegerDecoder::new(&vb).unwrap(),
+                    );
+                    let mut got = vec![];
+                    decode_all_i64(&tb, &vb, &mut got).unwrap();
+                    assert_eq!(got.len(), n);
+                    assert_eq!(got, want);
+
+                    let (tb, vb) = unpack(Values::Unsigned(
+                        values.map(|(t, v)| TimeValue::new(*t, *v as u64)).collect(),
+                    ));
+                    let want = decode_iter(
+                        &tb,
+                        TimeDecoder::new(&tb).unwrap(),
+                        UnsignedDecoder::new(&vb).unwrap(),
+                    );
+                    let mut got = vec![];
+                    decode_all_u64(&tb, &vb, &mut got).unwrap();
+                    assert_eq!(got, want);
+                }
+
+                let (tb, vb) = unpack(Values::Float(
+                    ts.iter().map(|t| TimeValue::new(*t, rng.gen())).collect(),
+                ));
+                let want = decode_iter(
+                    &tb,
+                    TimeDecoder::new(&tb).unwrap(),
+                    FloatDecoder::new(&vb).unwrap(),
+                );
+                let mut got = vec![];
+                decode_all_f64(&tb, &vb, &mut got).unwrap();
+                assert_eq!(got, want);
+
+                let (tb, vb) = unpack(Values::Bool(
+                    ts.iter().map(|t| TimeValue::new(*t, rng.gen())).collect(),
+                ));
+                let want = decode_iter(
+                    &tb,
+                    TimeDecoder::new(&tb).unwrap(),
+                    BooleanDecoder::new(&vb).unwrap(),
+                );
+                let mut got = vec![];
+                decode_all_bool(&tb, &vb, &mut got).unwrap();
+                assert_eq!(got, want);
+
+                let (tb, vb) = unpack(Values::String(
+                    ts.iter()
+                        .map(|t| {
+                            let len = rng.gen_range(0..20);
+                            TimeValue::new(*t, (0..len).map(|_| rng.gen()).collect())
+                        })
+                        .collect(),
+                ));
+                let want = decode_iter(
+                    &tb,
+                    TimeDecoder::new(&tb).unwrap(),
+                    StringDecoder::new(&vb).unwrap(),
+                );
+                let mut got = vec![];
+                decode_all_string(&tb, &vb, &mut got).unwrap();
+                assert_eq!(got, want);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_all_short_values() {
+        let (tb, vb) = unpack(Values::Integer(
+            (0..1000).map(|t| TimeValue::new(t, t * t)).collect(),
+        ));
+
+        // appends to what is already there
+        let mut got = vec![TimeValue::new(-1, 0)];
+        decode_all_i64(&tb, &vb, &mut got).unwrap();
+        assert_eq!(got.len(), 1001);
+
+        // a values part missing its last word fails and leaves out untouched
+        let mut got = vec![TimeValue::new(-1, 0)];
+        let err = decode_all_i64(&tb, &vb[..vb.len() - 8], &mut got).unwrap_err();
+        assert!(err.to_string().contains("values"), "{}", err);
+        assert_eq!(got, vec![TimeValue::new(-1, 0)]);
+
+        // and so does a partial word
+        let err = decode_all_i64(&tb, &vb[..vb.len() - 3], &mut got).unwrap_err();
+        assert!(err.to_string().contains("not enough data"), "{}", err);
+        assert_eq!(got.len(), 1);
+    }
+}
@@ -155,9 +155,49 @@ impl<'a> Decoder<bool> for BooleanDecoder<'a> {
     }
 }
 
+/// count_booleans returns the number of booleans encoded in b, read from the varint
+/// count following the header.
+pub fn count_booleans(b: &[u8]) -> anyhow::Result<usize> {
+    if b.is_empty() {
+        return Err(anyhow!("count_booleans: no data found"));
+    }
+
+    let (count, _) =
+        u64::decode_var(&b[1..]).ok_or(anyhow!("count_booleans: can not decode count"))?;
+    Ok(count as usize)
+}
+
+/// decode_all_booleans decodes at most max booleans of b, calling emit with each in
+/// order, and returns the number decoded.
+pub fn decode_all_booleans(
+    b: &[u8],
+    max: usize,
+    mut emit: impl FnMut(bool),
+) -> anyhow::Result<usize> {
+    if b.is_empty() {
+        return Err(anyhow!("no data found"));
+    }
+
+    // First byte stores the encoding type, only have 1 bit-packet format
+    // currently ignore for now.
+    let b = &b[1..];
+    let (count, n) = u64::decode_var(b).ok_or(anyhow!("BooleanDecoder: invalid count"))?;
+    let b = &b[n..];
+
+    let n = count.min(max as u64) as usize;
+    if b.len() < n.div_ceil(8) {
+        return Err(anyhow!("BooleanDecoder: not enough data for {} values", n));
+    }
+    for i in 0..n {
+        // The bits are packed from the most significant bit of each byte
+        emit(b[i >> 3] & (0x80 >> (i & 0x7)) != 0);
+    }
+    Ok(n)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::engine::tsm1::codec::boolean::{BooleanDecoder, BooleanEncoder};
+    use crate::engine::tsm1::codec::boolean::{count_booleans, BooleanDecoder, BooleanEncoder};
     use crate::engine::tsm1::codec::{Decoder, Encoder};
 
     #[test]
@@ -652,4 +692,25 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_count_booleans() {
+        for n in [0, 1, 7, 8, 9, 1000] {
+            let mut enc = BooleanEncoder::new(n);
+            for i in 0..n {
+                enc.write(i % 3 == 0);
+            }
+            let b = enc.bytes().unwrap();
+
+            let mut dec = BooleanDecoder::new(b.as_slice()).unwrap();
+            let mut decoded = 0;
+            while dec.next() {
+                decoded += 1;
+            }
+            assert_eq!(decoded, n);
+            assert_eq!(count_booleans(b.as_slice()).unwrap(), n);
+        }
+
+        assert!(count_booleans(&[]).is_err());
+    }
 }
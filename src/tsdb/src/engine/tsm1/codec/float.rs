@@ -3,7 +3,8 @@
 //!
 //! It implements the float compression as presented in: http://www.vldb.org/pvldb/vol8/p1816-teller.pdf.
 //! This implementation uses a sentinel value of NaN which means that float64 NaN cannot be stored using
-//! this version.
+//! this version: writing NaN fails with `UnsupportedNaN`. All other values, including ±Inf, -0.0 and
+//! subnormals, are encoded by their bits and round-trip exactly.
 
 use crate::engine::tsm1::codec::bit::{Bit, BufferedReader, BufferedWriter, Read, Write};
 use crate::engine::tsm1::codec::{bit, Decoder, Encoder};
@@ -18,10 +19,21 @@ const UVNAN: u64 = 0x7FF8000000000001;
 // same as ^uint64(0) in go
 const BASIC_VALUE: u64 = 18446744073709551615;
 
+/// UnsupportedNaN is returned when encoding NaN, which is the end-of-stream sentinel
+/// of the encoding.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unsupported value {index}: NaN")]
+pub struct UnsupportedNaN {
+    /// index is the position of the value in the encoded stream.
+    pub index: usize,
+}
+
 /// FloatEncoder encodes multiple float64s into a byte slice.
 pub struct FloatEncoder {
     val: f64,
-    err: Option<anyhow::Error>,
+    /// n is the number of values written.
+    n: usize,
+    err: Option<UnsupportedNaN>,
 
     leading: u64,
     trailing: u64,
@@ -39,6 +51,7 @@ impl FloatEncoder {
 
         Self {
             val: 0f64,
+            n: 0,
             err: None,
             leading: 0,
             trailing: BASIC_VALUE,
@@ -47,15 +60,32 @@ impl FloatEncoder {
             finished: false,
         }
     }
+
+    /// write_checked writes v, or returns `UnsupportedNaN` if v is NaN. Unlike
+    /// `Encoder::write`, a rejected value doesn't fail the encoder, so the caller
+    /// may skip it and continue.
+    pub fn write_checked(&mut self, v: f64) -> anyhow::Result<()> {
+        if v.is_nan() {
+            return Err(UnsupportedNaN { index: self.n }.into());
+        }
+        self.write(v);
+        Ok(())
+    }
 }
 
 impl Encoder<f64> for FloatEncoder {
     fn write(&mut self, v: f64) {
         // Only allow NaN as a sentinel value
         if v.is_nan() && !self.finished {
-            self.err = Some(anyhow!("unsupported value: NaN"));
+            if self.err.is_none() {
+                self.err = Some(UnsupportedNaN { index: self.n });
+            }
+            self.n += 1;
             return;
         }
+        if !self.finished {
+            self.n += 1;
+        }
         if self.first {
             // first point
             self.val = v;
@@ -119,7 +149,7 @@ impl Encoder<f64> for FloatEncoder {
 
     fn bytes(&mut self) -> anyhow::Result<Vec<u8>> {
         if let Some(err) = &self.err {
-            Err(anyhow!(err.to_string()))
+            Err(err.clone().into())
         } else {
             Ok(self.bw.as_slice().to_vec())
         }
@@ -242,9 +272,42 @@ impl<'a> Decoder<f64> for FloatDecoder<'a> {
     }
 }
 
+/// count_floats returns the number of floats encoded in b. Gorilla blocks do not
+/// store a count, so this is a scan of the bit stream which skips materializing the
+/// values but costs about as much as a decode.
+pub fn count_floats(b: &[u8]) -> anyhow::Result<usize> {
+    let mut dec = FloatDecoder::new(b)?;
+    let mut count = 0;
+    while dec.next() {
+        count += 1;
+    }
+    if let Some(err) = dec.err() {
+        return Err(anyhow!("count_floats: {}", err));
+    }
+    Ok(count)
+}
+
+/// decode_all_floats decodes at most max floats of b, calling emit with each in order,
+/// and returns the number decoded. Each value is XORed with the previous one, so
+/// unlike the integer codecs the bit stream is read value by value.
+pub fn decode_all_floats(b: &[u8], max: usize, mut emit: impl FnMut(f64)) -> anyhow::Result<usize> {
+    let mut dec = FloatDecoder::new(b)?;
+    let mut n = 0;
+    while n < max && dec.next() {
+        emit(dec.read());
+        n += 1;
+    }
+    if let Some(err) = dec.err() {
+        return Err(anyhow!("read values block error: {}", err));
+    }
+    Ok(n)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::engine::tsm1::codec::float::{FloatDecoder, FloatEncoder};
+    use crate::engine::tsm1::codec::float::{
+        count_floats, FloatDecoder, FloatEncoder, UnsupportedNaN,
+    };
     use crate::engine::tsm1::codec::{Decoder, Encoder};
 
     #[test]
@@ -368,6 +431,68 @@ mod tests {
 
         let r = s.bytes();
         assert_eq!(r.is_err(), true, "expected error. got nil");
+        assert_eq!(
+            r.unwrap_err().downcast_ref::<UnsupportedNaN>(),
+            Some(&UnsupportedNaN { index: 1 })
+        );
+    }
+
+    #[test]
+    fn test_float_encoder_write_checked() {
+        let mut s = FloatEncoder::new();
+        s.write_checked(1.0).unwrap();
+        let err = s.write_checked(f64::NAN).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedNaN>(),
+            Some(&UnsupportedNaN { index: 1 })
+        );
+        // the rejected value is skipped
+        s.write_checked(2.0).unwrap();
+        s.flush();
+
+        let b = s.bytes().unwrap();
+        let mut it = FloatDecoder::new(b.as_slice()).unwrap();
+        let mut got = vec![];
+        while it.next() {
+            got.push(it.read());
+        }
+        assert_eq!(got, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_float_encoder_roundtrip_special() {
+        let values = [
+            0.0,
+            -0.0,
+            0.0,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            -0.0,
+            f64::MIN_POSITIVE,
+            // subnormals
+            f64::from_bits(1),
+            -f64::from_bits(1),
+            f64::MIN_POSITIVE / 3.0,
+            f64::MAX,
+            f64::MIN,
+            f64::INFINITY,
+        ];
+
+        let mut s = FloatEncoder::new();
+        for v in &values {
+            s.write_checked(*v).unwrap();
+        }
+        s.flush();
+
+        let b = s.bytes().unwrap();
+        let mut it = FloatDecoder::new(b.as_slice()).unwrap();
+        for w in &values {
+            assert!(it.next());
+            // compare the bits to tell -0.0 from 0.0
+            assert_eq!(it.read().to_bits(), w.to_bits(), "want {}", w);
+        }
+        assert!(!it.next());
+        assert!(it.err().is_none());
     }
 
     #[test]
@@ -2885,4 +3010,27 @@ mod tests {
             assert_eq!(it.err().is_none(), true, "it.Error()=%v, want nil");
         }
     }
+
+    #[test]
+    fn test_count_floats() {
+        for n in [0, 1, 2, 100, 1000] {
+            let mut enc = FloatEncoder::new();
+            for i in 0..n {
+                enc.write((i % 13) as f64 * 0.25 - 1.0);
+            }
+            enc.flush();
+            let b = enc.bytes().unwrap();
+
+            let mut dec = FloatDecoder::new(b.as_slice()).unwrap();
+            let mut decoded = 0;
+            while dec.next() {
+                decoded += 1;
+            }
+            assert_eq!(decoded, n);
+            assert_eq!(count_floats(b.as_slice()).unwrap(), n);
+        }
+
+        // the decoder treats an empty block as holding no values
+        assert_eq!(count_floats(&[]).unwrap(), 0);
+    }
 }
@@ -23,7 +23,7 @@ use bytes::BufMut;
 use crate::engine::tsm1::codec::varint::VarInt;
 use crate::engine::tsm1::codec::zigzag::zig_zag_decode;
 use crate::engine::tsm1::codec::zigzag::zig_zag_encode;
-use crate::engine::tsm1::codec::{simple8b, Decoder, Encoder};
+use crate::engine::tsm1::codec::{simple8b, Decoder, Encoder, EncodingKind};
 
 /// INT_UNCOMPRESSED is an uncompressed format using 8 bytes per point
 const INT_UNCOMPRESSED: u8 = 0;
@@ -37,6 +37,7 @@ pub struct IntegerEncoder {
     prev: i64,
     rle: bool,
     values: Vec<u64>,
+    encoding: Option<EncodingKind>,
 }
 
 impl IntegerEncoder {
@@ -46,9 +47,16 @@ impl IntegerEncoder {
             prev: 0,
             rle: true,
             values: Vec::with_capacity(sz),
+            encoding: None,
         }
     }
 
+    /// encoding_used returns the encoding selected by the last call to `bytes`,
+    /// None if nothing was encoded.
+    pub fn encoding_used(&self) -> Option<EncodingKind> {
+        self.encoding
+    }
+
     fn encode_rle(&self) -> anyhow::Result<Vec<u8>> {
         // Large varints can take up to 10 bytes.  We're storing 3 + 1
         // type byte.
@@ -134,18 +142,26 @@ impl Encoder<i64> for IntegerEncoder {
     fn flush(&mut self) {}
 
     fn bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.encoding = None;
+        if self.values.is_empty() {
+            return Ok(vec![]);
+        }
+
         // Only run-length encode if it could reduce storage size.
         if self.rle && self.values.len() > 2 {
+            self.encoding = Some(EncodingKind::Rle);
             return self.encode_rle();
         }
 
         for v in self.values.as_slice() {
             // Value is too large to encode using packed format
             if *v > simple8b::MAX_VALUE {
+                self.encoding = Some(EncodingKind::Uncompressed);
                 return self.encode_uncompressed();
             }
         }
 
+        self.encoding = Some(EncodingKind::Packed);
         return self.encode_packed();
     }
 }
@@ -344,7 +360,9 @@ impl<'a> Decoder<i64> for PackedDecoder<'a> {
 
         if self.v_len > 0 && self.v_step < self.v_len - 1 {
             self.v_step += 1;
-            self.first += zig_zag_decode(self.values[self.v_step]);
+            self.first = self
+                .first
+                .wrapping_add(zig_zag_decode(self.values[self.v_step]));
             return true;
         }
 
@@ -377,7 +395,9 @@ impl<'a> Decoder<i64> for PackedDecoder<'a> {
 
         self.v_step = 0;
 
-        self.first += zig_zag_decode(self.values[self.v_step]);
+        self.first = self
+            .first
+            .wrapping_add(zig_zag_decode(self.values[self.v_step]));
         self.b_step += 8;
 
         return true;
@@ -458,13 +478,168 @@ impl<'a> Decoder<i64> for UncompressedDecoder<'a> {
     }
 }
 
+/// count_integers returns the number of integers encoded in b without decoding
+/// them: RLE blocks store the count, packed blocks are counted per simple8b word.
+pub fn count_integers(b: &[u8]) -> anyhow::Result<usize> {
+    if b.is_empty() {
+        return Err(anyhow!("count_integers: no data found"));
+    }
+
+    // Encoding type is stored in the 4 high bits of the first byte
+    let encoding = b[0] >> 4;
+    match encoding {
+        INT_UNCOMPRESSED => {
+            // Uncompressed integers are just 8 bytes each
+            Ok((b.len() - 1) / 8)
+        }
+        INT_COMPRESSED_RLE => {
+            if b.len() < 9 {
+                return Err(anyhow!("count_integers: not enough data for RLE value"));
+            }
+            // First 9 bytes are the header and the starting value, skip over them
+            let mut i = 9;
+            // Next 1-10 bytes is the delta value
+            let (_, n) =
+                u64::decode_var(&b[i..]).ok_or(anyhow!("count_integers: can not decode delta"))?;
+            i += n;
+            // Last 1-10 bytes is how many times the value repeats
+            let (count, _) =
+                u64::decode_var(&b[i..]).ok_or(anyhow!("count_integers: can not decode repeat"))?;
+
+            Ok(count as usize)
+        }
+        INT_COMPRESSED_SIMPLE => {
+            if b.len() < 9 {
+                return Err(anyhow!("count_integers: not enough data for packed value"));
+            }
+            // First 9 bytes are the header and the starting value, skip over them
+            let count = simple8b::count_bytes(&b[9..])?;
+            // +1 is for the first uncompressed value, starting value in b[1:9]
+            Ok(count + 1)
+        }
+        _ => Err(anyhow!("count_integers: unsupported encoding {}", encoding)),
+    }
+}
+
+/// decode_all_integers decodes at most max integers of b, calling emit with each in
+/// order, and returns the number decoded. Unlike `IntegerDecoder`, packed values are
+/// unpacked a simple8b word at a time, without per-value state.
+pub fn decode_all_integers(
+    b: &[u8],
+    max: usize,
+    mut emit: impl FnMut(i64),
+) -> anyhow::Result<usize> {
+    if b.is_empty() || max == 0 {
+        return Ok(0);
+    }
+
+    // Encoding type is stored in the 4 high bits of the first byte
+    let encoding = b[0] >> 4;
+    let b = &b[1..];
+    if b.len() < 8 {
+        return Err(anyhow!(
+            "IntegerDecoder: not enough data to decode starting value"
+        ));
+    }
+
+    // First 8 bytes is the starting value
+    let mut v = zig_zag_decode(u64::from_be_bytes(b[..8].try_into().unwrap()));
+    let b = &b[8..];
+    match encoding {
+        INT_UNCOMPRESSED => {
+            let words = b.chunks_exact(8);
+            let partial = !words.remainder().is_empty();
+
+            emit(v);
+            let mut n = 1;
+            for word in words {
+                if n == max {
+                    return Ok(n);
+                }
+                let delta = u64::from_be_bytes(word.try_into().unwrap());
+                v = v.wrapping_add(zig_zag_decode(delta));
+                emit(v);
+                n += 1;
+            }
+
+            if partial && n < max {
+                return Err(anyhow!(
+                    "IntegerDecoder: not enough data to decode packed value"
+                ));
+            }
+            Ok(n)
+        }
+        INT_COMPRESSED_SIMPLE => {
+            emit(v);
+            let mut n = 1;
+            simple8b::decode_words(b, |deltas| {
+                let take = deltas.len().min(max - n);
+                for delta in &deltas[..take] {
+                    v = v.wrapping_add(zig_zag_decode(*delta));
+                    emit(v);
+                }
+                n += take;
+                n < max
+            })?;
+            Ok(n)
+        }
+        INT_COMPRESSED_RLE => {
+            // Next 1-10 bytes is the delta value
+            let (delta, i) =
+                u64::decode_var(b).ok_or(anyhow!("IntegerDecoder: invalid RLE delta value"))?;
+            // Last 1-10 bytes is how many times the value repeats
+            let (repeat, _) = u64::decode_var(&b[i..])
+                .ok_or(anyhow!("IntegerDecoder: invalid RLE repeat value"))?;
+
+            let delta = zig_zag_decode(delta);
+            let n = repeat.min(max as u64) as usize;
+            for _ in 0..n {
+                emit(v);
+                v = v.wrapping_add(delta);
+            }
+            Ok(n)
+        }
+        _ => Err(anyhow!("unknown encoding {}", encoding)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
     use crate::engine::tsm1::codec::integer::{
-        Decoder, IntegerDecoder, IntegerEncoder, INT_COMPRESSED_RLE, INT_COMPRESSED_SIMPLE,
-        INT_UNCOMPRESSED,
+        count_integers, Decoder, IntegerDecoder, IntegerEncoder, INT_COMPRESSED_RLE,
+        INT_COMPRESSED_SIMPLE, INT_UNCOMPRESSED,
     };
-    use crate::engine::tsm1::codec::Encoder;
+    use crate::engine::tsm1::codec::{Encoder, EncodingKind};
+
+    #[test]
+    fn test_integer_encoder_encoding_used() {
+        let cases: [(&[i64], EncodingKind, u8); 3] = [
+            (&[1, 2, 3, 4], EncodingKind::Rle, INT_COMPRESSED_RLE),
+            (&[1, 5, 2, 7], EncodingKind::Packed, INT_COMPRESSED_SIMPLE),
+            (
+                &[0, i64::MAX, 3],
+                EncodingKind::Uncompressed,
+                INT_UNCOMPRESSED,
+            ),
+        ];
+        for (values, kind, header) in cases {
+            let mut enc = IntegerEncoder::new(values.len());
+            for v in values {
+                enc.write(*v);
+            }
+            assert_eq!(enc.encoding_used(), None);
+            let b = enc.bytes().unwrap();
+            assert_eq!(enc.encoding_used(), Some(kind), "{:?}", values);
+            assert_eq!(b[0] >> 4, header, "{:?}", values);
+        }
+
+        let mut enc = IntegerEncoder::new(0);
+        assert!(enc.bytes().unwrap().is_empty());
+        assert_eq!(enc.encoding_used(), None);
+    }
 
     #[test]
     fn test_integer_encoder_no_values() {
@@ -3477,6 +3652,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_integer_decoder_packed_random_words() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let mut b = vec![0u8; 1 + 8 * rng.gen_range(1..32) + rng.gen_range(0..2)];
+            rng.fill(b.as_mut_slice());
+            b[0] = INT_COMPRESSED_SIMPLE << 4;
+
+            let mut dec = IntegerDecoder::new(b.as_slice()).unwrap();
+            while dec.next() {
+                dec.read();
+            }
+            if (b.len() - 1) % 8 != 0 {
+                assert!(dec.err().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_integers() {
+        let cases: Vec<(Vec<i64>, u8)> = vec![
+            ((0..1000).map(|x| x * 10).collect(), INT_COMPRESSED_RLE),
+            (vec![7, 7, 7], INT_COMPRESSED_RLE),
+            (vec![7], INT_COMPRESSED_SIMPLE),
+            (
+                (0..1000).map(|x| x * x % 977).collect(),
+                INT_COMPRESSED_SIMPLE,
+            ),
+            (vec![1, i64::MIN, i64::MAX, 0], INT_UNCOMPRESSED),
+        ];
+
+        for (values, encoding) in cases {
+            let mut enc = IntegerEncoder::new(values.len());
+            for v in values.iter() {
+                enc.write(*v);
+            }
+            let b = enc.bytes().unwrap();
+            assert_eq!(b[0] >> 4, encoding);
+
+            let mut dec = IntegerDecoder::new(b.as_slice()).unwrap();
+            let mut n = 0;
+            while dec.next() {
+                n += 1;
+            }
+            assert!(dec.err().is_none());
+            assert_eq!(n, values.len());
+            assert_eq!(count_integers(b.as_slice()).unwrap(), n);
+        }
+
+        assert!(count_integers(&[]).is_err());
+        assert!(count_integers(&[INT_COMPRESSED_SIMPLE << 4, 0]).is_err());
+        assert!(count_integers(&[INT_COMPRESSED_RLE << 4, 0]).is_err());
+        assert!(count_integers(&[3 << 4]).is_err());
+    }
+
     // #[test]
     // fn test_integer_decoder_corrupt() {
     //     let cases = [
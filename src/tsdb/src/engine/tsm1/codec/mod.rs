@@ -1,3 +1,4 @@
+pub mod batch;
 pub mod bit;
 pub mod simple8b;
 pub mod varint;
@@ -17,6 +18,18 @@ pub trait Encoder<T> {
     fn bytes(&mut self) -> anyhow::Result<Vec<u8>>;
 }
 
+/// EncodingKind is the encoding selected by an integer or timestamp encoder, stored
+/// in the 4 high bits of the first byte of the encoded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingKind {
+    /// Uncompressed stores 8 bytes per value.
+    Uncompressed,
+    /// Packed bit-packs the values with simple8b.
+    Packed,
+    /// Rle run-length encodes a constant delta.
+    Rle,
+}
+
 pub trait Decoder<T> {
     fn next(&mut self) -> bool;
     fn read(&self) -> T;
@@ -109,6 +109,7 @@ pub struct Decoder<'a> {
     buf: [u64; 240],
     i: usize,
     n: usize,
+    err: Option<anyhow::Error>,
 }
 
 impl<'a> Decoder<'a> {
@@ -118,17 +119,22 @@ impl<'a> Decoder<'a> {
             buf: [0; 240],
             i: 0,
             n: 0,
+            err: None,
         }
     }
 
     pub fn next(&mut self) -> bool {
+        if self.err.is_some() {
+            return false;
+        }
+
         self.i += 1;
 
         if self.i >= self.n {
             self.read0();
         }
 
-        self.bytes.len() >= 8 || self.i < self.n
+        self.err.is_none() && (self.bytes.len() >= 8 || self.i < self.n)
     }
 
     // pub fn set_bytes(&mut self, b: &mut [u8]) {
@@ -138,8 +144,12 @@ impl<'a> Decoder<'a> {
     // }
 
     pub fn read(&self) -> u64 {
-        let v = self.buf[self.i];
-        v
+        self.buf.get(self.i).copied().unwrap_or_default()
+    }
+
+    /// err returns the error hit decoding the bytes, if any.
+    pub fn err(&self) -> Option<&anyhow::Error> {
+        self.err.as_ref()
     }
 
     fn read0(&mut self) {
@@ -151,7 +161,13 @@ impl<'a> Decoder<'a> {
         let v = u64::from_be_bytes(s.try_into().unwrap());
 
         self.bytes = self.bytes[8..].as_ref();
-        self.n = decode(&mut self.buf, v).unwrap();
+        match decode(&mut self.buf, v) {
+            Ok(n) => self.n = n,
+            Err(e) => {
+                self.err = Some(e);
+                self.n = 0;
+            }
+        }
         self.i = 0;
     }
 }
@@ -455,13 +471,72 @@ pub fn encode_all(src: &mut [u64]) -> anyhow::Result<usize> {
     return Ok(j);
 }
 
+/// describe returns the selector of a packed word, how many values it holds and
+/// their bit width.
+pub fn describe(word: u64) -> (u8, usize, u8) {
+    let sel = (word >> 60) as usize;
+    (sel as u8, SELECTOR[sel].n, SELECTOR[sel].bit as u8)
+}
+
+/// pack_ratio returns the average number of bits per value `encode_all` would use
+/// for src, without writing the packed words. A ratio close to 64 means the values
+/// are too large or too irregular to benefit from packing.
+pub fn pack_ratio(src: &[u64]) -> anyhow::Result<f64> {
+    if src.is_empty() {
+        return Ok(0.0);
+    }
+
+    let mut words = 0;
+    let mut i = 0;
+    while i < src.len() {
+        let remaining = &src[i..];
+        let packing = SELECTOR
+            .iter()
+            .find(|x| can_pack(remaining, x.n, x.bit))
+            .ok_or_else(|| anyhow!("value out of bounds"))?;
+        i += packing.n;
+        words += 1;
+    }
+
+    Ok((words * 64) as f64 / src.len() as f64)
+}
+
+/// decode unpacks the values of v into dst.  It returns the number of values
+/// written, or an error if the selector is invalid or dst is too short to hold them.
 pub fn decode(dst: &mut [u64], v: u64) -> anyhow::Result<usize> {
-    let sel = (v >> 60) as usize;
-    if sel >= 16 {
-        return Err(anyhow!("invalid selector value: {}", sel));
+    let packing = selector(v)?;
+    if dst.len() < packing.n {
+        return Err(anyhow!(
+            "destination too small for selector {}: {} < {}",
+            v >> 60,
+            dst.len(),
+            packing.n
+        ));
+    }
+    (packing.unpack)(v, &mut dst[..packing.n]);
+    Ok(packing.n)
+}
+
+/// decode_words unpacks the 8 byte big endian words of b in order, calling f with the
+/// values of each word. Words are unpacked into a single scratch buffer of 240 slots,
+/// the most a word holds. f returns false to stop before the next word.
+pub fn decode_words(b: &[u8], mut f: impl FnMut(&[u64]) -> bool) -> anyhow::Result<()> {
+    let words = b.chunks_exact(8);
+    let partial = !words.remainder().is_empty();
+
+    let mut buf = [0; 240];
+    for word in words {
+        let v = u64::from_be_bytes(word.try_into().unwrap());
+        let n = decode(&mut buf, v)?;
+        if !f(&buf[..n]) {
+            return Ok(());
+        }
+    }
+
+    if partial {
+        return Err(anyhow!("simple8b: not enough data to decode packed value"));
     }
-    (SELECTOR[sel].unpack)(v, dst);
-    return Ok(SELECTOR[sel].n);
+    Ok(())
 }
 
 /// Decode writes the uncompressed values from src to dst.  It returns the number
@@ -469,14 +544,16 @@ pub fn decode(dst: &mut [u64], v: u64) -> anyhow::Result<usize> {
 pub fn decode_all(dst: &mut [u64], src: &[u64]) -> anyhow::Result<usize> {
     let mut j = 0;
     for v in src {
-        let sel = (v >> 60) as usize;
-        if sel >= 16 {
-            return Err(anyhow!("invalid selector value: {}", sel));
-        }
-        (SELECTOR[sel].unpack)(*v, dst);
-        j += SELECTOR[sel].n;
+        j += decode(&mut dst[j..], *v)?;
     }
-    return Ok(j);
+    Ok(j)
+}
+
+fn selector(v: u64) -> anyhow::Result<&'static Packing> {
+    let sel = (v >> 60) as usize;
+    SELECTOR
+        .get(sel)
+        .ok_or_else(|| anyhow!("invalid selector value: {}", sel))
 }
 
 fn can_pack(src: &[u64], n: usize, bits: usize) -> bool {
@@ -1000,8 +1077,12 @@ fn unpack1(v: u64, dst: &mut [u64]) {
 
 #[cfg(test)]
 mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
     use crate::engine::tsm1::codec::simple8b::{
-        count_bytes, count_bytes_between, decode_all, encode_all, Decoder, Encoder,
+        count_bytes, count_bytes_between, decode, decode_all, describe, encode_all, pack_ratio,
+        Decoder, Encoder, MAX_VALUE,
     };
 
     #[test]
@@ -1296,4 +1377,69 @@ mod tests {
         let got = count_bytes_between(encoded, 100000, 100001).expect("Unexpected error in Count");
         assert_eq!(got, 1, "Count mismatch: got {}, exp {}", got, 1);
     }
+
+    #[test]
+    fn test_describe() {
+        // runs of ones use the zero bit selectors
+        let mut src = vec![1u64; 240];
+        assert_eq!(encode_all(&mut src).unwrap(), 1);
+        assert_eq!(describe(src[0]), (0, 240, 0));
+
+        // zeros need a bit each
+        let mut src = vec![0u64; 120];
+        assert_eq!(encode_all(&mut src).unwrap(), 2);
+        assert_eq!(describe(src[0]), (2, 60, 1));
+        assert_eq!(describe(src[1]), (2, 60, 1));
+
+        // a large value forces a wider selector for the following word
+        let mut src = vec![0u64; 30];
+        src.push(1 << 20);
+        src.push(1 << 25);
+        src.push(MAX_VALUE);
+        let n = encode_all(&mut src).unwrap();
+        let got: Vec<_> = src[..n].iter().map(|x| describe(*x)).collect();
+        assert_eq!(got, vec![(3, 30, 2), (14, 2, 30), (15, 1, 60)]);
+    }
+
+    #[test]
+    fn test_pack_ratio() {
+        assert_eq!(pack_ratio(&[]).unwrap(), 0.0);
+        assert_eq!(pack_ratio(&[1; 240]).unwrap(), 64.0 / 240.0);
+        assert_eq!(pack_ratio(&[0; 120]).unwrap(), 64.0 / 60.0);
+        assert_eq!(pack_ratio(&[MAX_VALUE; 4]).unwrap(), 64.0);
+
+        // matches the words written by encode_all
+        let mut src: Vec<u64> = (0..1000).map(|x| x * x % 5000).collect();
+        let ratio = pack_ratio(&src).unwrap();
+        let n = encode_all(&mut src).unwrap();
+        assert_eq!(ratio, (n * 64) as f64 / 1000.0);
+
+        assert!(pack_ratio(&[MAX_VALUE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_random_words() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut dst = [0u64; 240];
+        for _ in 0..100_000 {
+            let v: u64 = rng.gen();
+            let n = decode(&mut dst, v).unwrap();
+            assert!(n > 0 && n <= 240);
+
+            // a destination shorter than the packed values is an error, not a panic
+            assert!(decode(&mut dst[..n - 1], v).is_err());
+        }
+
+        let words: Vec<u64> = (0..64).map(|_| rng.gen()).collect();
+        assert!(decode_all(&mut dst, &words).is_err());
+
+        let mut bytes = vec![0u8; 8 * 64 + 3];
+        rng.fill(bytes.as_mut_slice());
+        let mut dec = Decoder::new(&bytes);
+        while dec.next() {
+            dec.read();
+        }
+        assert!(dec.err().is_none());
+        dec.read();
+    }
 }
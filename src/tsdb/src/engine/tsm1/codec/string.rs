@@ -178,6 +178,199 @@ impl Decoder<Vec<u8>> for StringDecoder {
     }
 }
 
+/// DEFAULT_STRING_DECODE_WINDOW is the default number of decompressed bytes kept by
+/// a `StreamingStringDecoder` besides the string being read. It is also the minimum:
+/// snappy back-references reach at most 64KB behind.
+pub const DEFAULT_STRING_DECODE_WINDOW: usize = 64 * 1024;
+
+/// StreamingStringDecoder decodes a string block like `StringDecoder`, but
+/// decompresses the snappy payload as the strings are read instead of all at
+/// once. Peak memory is bounded by the window plus the largest string, rather than
+/// by the decompressed size of the block.
+pub struct StreamingStringDecoder<'a> {
+    /// src is the snappy payload, following the uncompressed length.
+    src: &'a [u8],
+    s: usize,
+
+    /// buf holds the decompressed bytes from the absolute offset `base`.
+    buf: Vec<u8>,
+    base: usize,
+    /// i is the absolute offset of the next string.
+    i: usize,
+    /// d_len is the decompressed size of the block.
+    d_len: usize,
+
+    window: usize,
+    peak: usize,
+}
+
+impl<'a> StreamingStringDecoder<'a> {
+    /// new initializes the decoder with a string block, keeping `window` bytes of
+    /// decompressed history, at least `DEFAULT_STRING_DECODE_WINDOW`.
+    pub fn new(b: &'a [u8], window: usize) -> anyhow::Result<Self> {
+        if b.is_empty() {
+            return Err(anyhow!("no data found"));
+        }
+        if b[0] >> 4 != STRING_COMPRESSED_SNAPPY {
+            return Err(anyhow!(
+                "StreamingStringDecoder: unknown encoding {}",
+                b[0] >> 4
+            ));
+        }
+
+        let (d_len, n) = u64::decode_var(&b[1..])
+            .ok_or_else(|| anyhow!("StreamingStringDecoder: invalid decompressed length"))?;
+
+        Ok(Self {
+            src: &b[1 + n..],
+            s: 0,
+            buf: vec![],
+            base: 0,
+            i: 0,
+            d_len: d_len as usize,
+            window: window.max(DEFAULT_STRING_DECODE_WINDOW),
+            peak: 0,
+        })
+    }
+
+    /// peak returns the largest number of decompressed bytes held at once.
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// next returns the next string, or None once all strings are read.
+    pub fn next_string(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        if self.i >= self.d_len {
+            if self.s < self.src.len() {
+                return Err(anyhow!("StreamingStringDecoder: trailing data"));
+            }
+            return Ok(None);
+        }
+        self.discard();
+
+        // read the length of the string
+        let (length, n) = loop {
+            let lower = self.i - self.base;
+            if let Some(r) = u64::decode_var(&self.buf[lower..]) {
+                break r;
+            }
+            if self.buf.len() - lower >= 10 || !self.decompress()? {
+                return Err(anyhow!(
+                    "StreamingStringDecoder: invalid encoded string length"
+                ));
+            }
+        };
+
+        let lower = self.i + n;
+        let upper = lower
+            .checked_add(length as usize)
+            .ok_or_else(|| anyhow!("StreamingStringDecoder: length overflow"))?;
+        if upper > self.d_len {
+            return Err(anyhow!(
+                "StreamingStringDecoder: not enough data to represent encoded string"
+            ));
+        }
+        while self.base + self.buf.len() < upper {
+            if !self.decompress()? {
+                return Err(anyhow!(
+                    "StreamingStringDecoder: not enough data to represent encoded string"
+                ));
+            }
+        }
+
+        self.i = upper;
+        Ok(Some(&self.buf[lower - self.base..upper - self.base]))
+    }
+
+    /// discard drops the decompressed bytes before the next string which are out
+    /// of the window.
+    fn discard(&mut self) {
+        let read = self.i - self.base;
+        let n = read.min(self.buf.len().saturating_sub(self.window));
+        if n > 0 {
+            self.buf.drain(..n);
+            self.base += n;
+        }
+    }
+
+    /// decompress decodes the next snappy element into the buffer, returning false
+    /// at the end of the payload.
+    fn decompress(&mut self) -> anyhow::Result<bool> {
+        if self.s >= self.src.len() {
+            return Ok(false);
+        }
+
+        let tag = self.src[self.s];
+        self.s += 1;
+        match tag & 0x03 {
+            // literal
+            0x00 => {
+                let mut length = (tag >> 2) as usize;
+                if length >= 60 {
+                    let n = length - 59;
+                    let bytes = self.take(n)?;
+                    length = bytes
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, b| (acc << 8) | *b as usize);
+                }
+                let literal = self.take(length + 1)?;
+                self.buf.extend_from_slice(literal);
+            }
+            // copy with a 1, 2 or 4 bytes offset
+            typ => {
+                let (length, offset) = match typ {
+                    0x01 => {
+                        let b = self.take(1)?[0] as usize;
+                        (
+                            4 + ((tag >> 2) & 0x07) as usize,
+                            ((tag as usize & 0xe0) << 3) | b,
+                        )
+                    }
+                    0x02 => {
+                        let b = self.take(2)?;
+                        let offset = u16::from_le_bytes([b[0], b[1]]) as usize;
+                        (1 + (tag >> 2) as usize, offset)
+                    }
+                    _ => {
+                        let b = self.take(4)?;
+                        let offset = u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize;
+                        (1 + (tag >> 2) as usize, offset)
+                    }
+                };
+                if offset == 0 || offset > self.buf.len() {
+                    return Err(anyhow!(
+                        "StreamingStringDecoder: copy offset {} out of the window",
+                        offset
+                    ));
+                }
+
+                // the source may overlap the bytes being appended
+                let start = self.buf.len() - offset;
+                for j in 0..length {
+                    let b = self.buf[start + j];
+                    self.buf.push(b);
+                }
+            }
+        }
+
+        if self.base + self.buf.len() > self.d_len {
+            return Err(anyhow!("StreamingStringDecoder: corrupt input"));
+        }
+        self.peak = self.peak.max(self.buf.len());
+        Ok(true)
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.src.len() - self.s < n {
+            return Err(anyhow!("StreamingStringDecoder: corrupt input"));
+        }
+        let b = &self.src[self.s..self.s + n];
+        self.s += n;
+        Ok(b)
+    }
+}
+
 #[derive(Clone)]
 pub struct Ref {
     buf: Arc<Vec<u8>>,
@@ -195,10 +388,80 @@ impl Ref {
     }
 }
 
+/// count_strings returns the number of strings encoded in b. The length prefixes
+/// live inside the snappy payload, so the block is decompressed, but the strings
+/// are skipped over rather than copied.
+pub fn count_strings(b: &[u8]) -> anyhow::Result<usize> {
+    if b.is_empty() {
+        return Err(anyhow!("count_strings: no data found"));
+    }
+
+    let data = snap::raw::Decoder::new()
+        .decompress_vec(&b[1..])
+        .map_err(|e| anyhow!(e))?;
+
+    let mut count = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let (length, n) = u64::decode_var(&data[i..])
+            .ok_or(anyhow!("count_strings: invalid encoded string length"))?;
+        i = (i + n)
+            .checked_add(length as usize)
+            .filter(|x| *x <= data.len())
+            .ok_or(anyhow!(
+                "count_strings: not enough data to represent encoded string"
+            ))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// decode_all_strings decodes at most max strings of b, calling emit with each in
+/// order, and returns the number decoded. The block is decompressed once and split
+/// on the lengths in place, without the per-value state of `StringDecoder`.
+pub fn decode_all_strings(
+    b: &[u8],
+    max: usize,
+    mut emit: impl FnMut(Vec<u8>),
+) -> anyhow::Result<usize> {
+    if b.is_empty() {
+        return Err(anyhow!("no data found"));
+    }
+
+    // First byte stores the encoding type, only have snappy format
+    // currently so ignore for now.
+    let data = snap::raw::Decoder::new()
+        .decompress_vec(&b[1..])
+        .map_err(|e| anyhow!(e))?;
+
+    let mut i = 0;
+    let mut n = 0;
+    while n < max && i < data.len() {
+        // read the length of the string
+        let (length, l) = u64::decode_var(&data[i..])
+            .ok_or(anyhow!("StringDecoder: invalid encoded string length"))?;
+        let lower = i + l;
+        let upper = lower
+            .checked_add(length as usize)
+            .ok_or(anyhow!("StringDecoder: length overflow"))?;
+        if upper > data.len() {
+            return Err(anyhow!(
+                "StringDecoder: not enough data to represent encoded string"
+            ));
+        }
+
+        emit(data[lower..upper].to_vec());
+        i = upper;
+        n += 1;
+    }
+    Ok(n)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::engine::tsm1::codec::string::{
-        StringDecoder, StringEncoder, STRING_COMPRESSED_SNAPPY,
+        count_strings, StreamingStringDecoder, StringDecoder, StringEncoder,
+        DEFAULT_STRING_DECODE_WINDOW, STRING_COMPRESSED_SNAPPY,
     };
     use crate::engine::tsm1::codec::{Decoder, Encoder};
 
@@ -299,4 +562,86 @@ mod tests {
             "unexpected next value: got true, exp false"
         );
     }
+
+    #[test]
+    fn test_streaming_string_decoder() {
+        let mut enc = StringEncoder::new(1024);
+        let mut values = Vec::with_capacity(200);
+        for i in 0..200usize {
+            // large strings, partly repetitive so that snappy emits copies
+            let len = 20_000 + (i * 7919) % 40_000;
+            let v: Vec<u8> = (0..len)
+                .map(|j| {
+                    if j % 100 < 50 {
+                        b'a' + (i % 26) as u8
+                    } else {
+                        (j * 31 + i) as u8
+                    }
+                })
+                .collect();
+            enc.write(v.clone());
+            values.push(v);
+        }
+        enc.write(vec![]);
+        values.push(vec![]);
+        let b = enc.bytes().unwrap();
+
+        let mut dec = StreamingStringDecoder::new(b.as_slice(), 0).unwrap();
+        let mut got = 0;
+        while let Some(v) = dec.next_string().unwrap() {
+            assert_eq!(v, values[got].as_slice(), "unexpected value at pos {}", got);
+            got += 1;
+        }
+        assert_eq!(got, values.len());
+        assert!(dec.next_string().unwrap().is_none());
+
+        // the window and the largest string, plus at most one snappy literal
+        let total: usize = values.iter().map(|x| x.len()).sum();
+        let largest = values.iter().map(|x| x.len()).max().unwrap();
+        let bound = DEFAULT_STRING_DECODE_WINDOW + largest + 64 * 1024;
+        assert!(dec.peak() <= bound, "peak {} over {}", dec.peak(), bound);
+        assert!(dec.peak() < total / 10);
+    }
+
+    #[test]
+    fn test_streaming_string_decoder_corrupt() {
+        let mut enc = StringEncoder::new(1024);
+        for i in 0..10 {
+            enc.write(format!("value {}", i).into_bytes());
+        }
+        let b = enc.bytes().unwrap();
+
+        let mut dec = StreamingStringDecoder::new(&b[..b.len() - 3], 0).unwrap();
+        let r = (0..10).try_for_each(|_| dec.next_string().map(|_| ()));
+        assert!(r.is_err());
+
+        assert!(StreamingStringDecoder::new(&[], 0).is_err());
+    }
+
+    #[test]
+    fn test_count_strings() {
+        for n in [0, 1, 2, 100, 1000] {
+            let mut enc = StringEncoder::new(1024);
+            for i in 0..n {
+                // include empty strings, which are still counted
+                enc.write("x".repeat(i % 5).into_bytes());
+            }
+            let b = enc.bytes().unwrap();
+
+            let mut dec = StringDecoder::new(b.as_slice()).unwrap();
+            let mut decoded = 0;
+            while dec.next() {
+                decoded += 1;
+            }
+            assert_eq!(decoded, n);
+            assert_eq!(count_strings(b.as_slice()).unwrap(), n);
+        }
+
+        assert!(count_strings(&[]).is_err());
+
+        // a length prefix pointing past the end of the data
+        let mut b = vec![STRING_COMPRESSED_SNAPPY << 4];
+        b.extend(snap::raw::Encoder::new().compress_vec(&[5, b'a']).unwrap());
+        assert!(count_strings(b.as_slice()).is_err());
+    }
 }
@@ -33,7 +33,7 @@
 use bytes::BufMut;
 
 use crate::engine::tsm1::codec::varint::VarInt;
-use crate::engine::tsm1::codec::{simple8b, Decoder, Encoder};
+use crate::engine::tsm1::codec::{simple8b, Decoder, Encoder, EncodingKind};
 
 /// TIME_UNCOMPRESSED is an uncompressed format using 8 bytes per timestamp
 const TIME_UNCOMPRESSED: u8 = 0;
@@ -46,6 +46,7 @@ const TIME_COMPRESSED_RLE: u8 = 2;
 pub struct TimeEncoder {
     ts: Vec<u64>,
     enc: simple8b::Encoder,
+    encoding: Option<EncodingKind>,
 }
 
 impl TimeEncoder {
@@ -53,9 +54,16 @@ impl TimeEncoder {
         Self {
             ts: Vec::with_capacity(sz),
             enc: simple8b::Encoder::new(),
+            encoding: None,
         }
     }
 
+    /// encoding_used returns the encoding selected by the last call to `bytes`,
+    /// None if nothing was encoded.
+    pub fn encoding_used(&self) -> Option<EncodingKind> {
+        self.encoding
+    }
+
     fn reduce(&mut self) -> (u64, u64, bool) {
         // Compute the deltas in place to avoid allocating another slice
         let deltas = self.ts.as_mut_slice();
@@ -175,6 +183,7 @@ impl Encoder<i64> for TimeEncoder {
     fn flush(&mut self) {}
 
     fn bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.encoding = None;
         if self.ts.len() == 0 {
             return Ok(vec![]);
         }
@@ -185,14 +194,17 @@ impl Encoder<i64> for TimeEncoder {
 
         // The deltas are all the same, so we can run-length encode them
         if rle && self.ts.len() > 1 {
+            self.encoding = Some(EncodingKind::Rle);
             return self.encode_rle(self.ts[0], self.ts[1], div);
         }
 
         // We can't compress this time-range, the deltas exceed 1 << 60
         if max > simple8b::MAX_VALUE {
+            self.encoding = Some(EncodingKind::Uncompressed);
             return self.encode_raw();
         }
 
+        self.encoding = Some(EncodingKind::Packed);
         return self.encode_packed(div);
     }
 }
@@ -399,7 +411,9 @@ impl<'a> Decoder<i64> for PackedDecoder<'a> {
 
         if self.v_len > 0 && self.v_step < self.v_len - 1 {
             self.v_step += 1;
-            self.first += (self.values[self.v_step] * self.div) as i64;
+            self.first = self
+                .first
+                .wrapping_add(self.values[self.v_step].wrapping_mul(self.div) as i64);
             return true;
         }
 
@@ -432,7 +446,9 @@ impl<'a> Decoder<i64> for PackedDecoder<'a> {
 
         self.v_step = 0;
 
-        self.first += (self.values[self.v_step] * self.div) as i64;
+        self.first = self
+            .first
+            .wrapping_add(self.values[self.v_step].wrapping_mul(self.div) as i64);
         self.b_step += 8;
 
         return true;
@@ -551,18 +567,139 @@ pub fn count_timestamps(b: &[u8]) -> anyhow::Result<usize> {
     }
 }
 
+/// decode_all_timestamps decodes at most max timestamps of b, calling emit with each
+/// in order, and returns the number decoded. Unlike `TimeDecoder`, packed deltas are
+/// unpacked a simple8b word at a time, without per-value state.
+pub fn decode_all_timestamps(
+    b: &[u8],
+    max: usize,
+    mut emit: impl FnMut(i64),
+) -> anyhow::Result<usize> {
+    if b.is_empty() || max == 0 {
+        return Ok(0);
+    }
+
+    // Encoding type is stored in the 4 high bits of the first byte, the lower 4 bits
+    // hold the 10 based exponent, so we can scale the values back up
+    let encoding = b[0] >> 4;
+    let div = 10_u64
+        .checked_pow((b[0] & 0xF) as u32)
+        .ok_or(anyhow!("TimeDecoder: invalid scale {}", b[0] & 0xF))?;
+    let b = &b[1..];
+    if b.len() < 8 {
+        return Err(anyhow!(
+            "TimeDecoder: not enough data to decode starting value"
+        ));
+    }
+
+    // First 8 bytes is the starting timestamp
+    let mut v = u64::from_be_bytes(b[..8].try_into().unwrap()) as i64;
+    let b = &b[8..];
+    match encoding {
+        TIME_UNCOMPRESSED => {
+            let words = b.chunks_exact(8);
+            let partial = !words.remainder().is_empty();
+
+            emit(v);
+            let mut n = 1;
+            for word in words {
+                if n == max {
+                    return Ok(n);
+                }
+                let delta = u64::from_be_bytes(word.try_into().unwrap());
+                v = v.wrapping_add(delta as i64);
+                emit(v);
+                n += 1;
+            }
+
+            if partial && n < max {
+                return Err(anyhow!(
+                    "TimeDecoder: not enough data to decode packed value"
+                ));
+            }
+            Ok(n)
+        }
+        TIME_COMPRESSED_PACKED_SIMPLE => {
+            emit(v);
+            let mut n = 1;
+            simple8b::decode_words(b, |deltas| {
+                let take = deltas.len().min(max - n);
+                for delta in &deltas[..take] {
+                    v = v.wrapping_add(delta.wrapping_mul(div) as i64);
+                    emit(v);
+                }
+                n += take;
+                n < max
+            })?;
+            Ok(n)
+        }
+        TIME_COMPRESSED_RLE => {
+            // Next 1-10 bytes is the delta value
+            let (delta, i) = u64::decode_var(b)
+                .ok_or(anyhow!("TimeDecoder: invalid run length in decodeRLE"))?;
+            // Last 1-10 bytes is how many times the value repeats
+            let (repeat, _) = u64::decode_var(&b[i..])
+                .ok_or(anyhow!("TimeDecoder: invalid repeat value in decodeRLE"))?;
+
+            let delta = delta.wrapping_mul(div) as i64;
+            let n = repeat.min(max as u64) as usize;
+            for _ in 0..n {
+                emit(v);
+                v = v.wrapping_add(delta);
+            }
+            Ok(n)
+        }
+        _ => Err(anyhow!("unknown encoding {}", encoding)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::ops::Add;
     use std::time::Duration;
 
     use influxdb_utils::time;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     use crate::engine::tsm1::codec::timestamp::{
         Decoder, TimeDecoder, TimeEncoder, TIME_COMPRESSED_PACKED_SIMPLE, TIME_COMPRESSED_RLE,
         TIME_UNCOMPRESSED,
     };
-    use crate::engine::tsm1::codec::Encoder;
+    use crate::engine::tsm1::codec::{Encoder, EncodingKind};
+
+    #[test]
+    fn test_time_encoder_encoding_used() {
+        let cases: [(&[i64], EncodingKind, u8); 4] = [
+            (&[10, 20, 30], EncodingKind::Rle, TIME_COMPRESSED_RLE),
+            (
+                &[1, 5, 7, 20],
+                EncodingKind::Packed,
+                TIME_COMPRESSED_PACKED_SIMPLE,
+            ),
+            // a single timestamp is packed
+            (&[42], EncodingKind::Packed, TIME_COMPRESSED_PACKED_SIMPLE),
+            (
+                &[0, i64::MAX, 3],
+                EncodingKind::Uncompressed,
+                TIME_UNCOMPRESSED,
+            ),
+        ];
+        for (values, kind, header) in cases {
+            let mut enc = TimeEncoder::new(values.len());
+            for v in values {
+                enc.write(*v);
+            }
+            assert_eq!(enc.encoding_used(), None);
+            let b = enc.bytes().unwrap();
+            assert_eq!(enc.encoding_used(), Some(kind), "{:?}", values);
+            assert_eq!(b[0] >> 4, header, "{:?}", values);
+        }
+
+        let mut enc = TimeEncoder::new(0);
+        assert!(enc.bytes().unwrap().is_empty());
+        assert_eq!(enc.encoding_used(), None);
+    }
 
     #[test]
     fn test_time_encoder() {
@@ -3584,4 +3721,22 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_time_decoder_packed_random_words() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let mut b = vec![0u8; 1 + 8 * rng.gen_range(1..32) + rng.gen_range(0..2)];
+            rng.fill(b.as_mut_slice());
+            b[0] = (TIME_COMPRESSED_PACKED_SIMPLE << 4) | (b[0] & 0xF);
+
+            let mut dec = TimeDecoder::new(b.as_slice()).unwrap();
+            while dec.next() {
+                dec.read();
+            }
+            if (b.len() - 1) % 8 != 0 {
+                assert!(dec.err().is_some());
+            }
+        }
+    }
 }
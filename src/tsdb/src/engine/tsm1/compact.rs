@@ -0,0 +1,1434 @@
+use std::time::Instant;
+
+use common_base::iterator::AsyncIterator;
+use influxdb_storage::{path_join, StorageOperator};
+use tracing::field::Empty;
+
+use crate::engine::tsm1::block::decoder::{block_type, decode_block};
+use crate::engine::tsm1::block::encoder::encode_block;
+use crate::engine::tsm1::cache::CacheSnapshot;
+use crate::engine::tsm1::file_store::file_store::{
+    append_values, new_values, parse_tsm_file_name, tsm_file_name, FileStore, KeysIterator,
+};
+use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use crate::engine::tsm1::file_store::reader::block_reader::BlockReadError;
+use crate::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+use crate::engine::tsm1::file_store::writer::index_writer::{DirectIndex, MemoryIndexBuffer};
+use crate::engine::tsm1::file_store::writer::tsm_writer::{
+    DefaultTSMWriter, TSMWriter, TSMWriterStats,
+};
+use crate::engine::tsm1::file_store::MAX_INDEX_ENTRIES;
+use crate::engine::tsm1::value::{Array, Values};
+use crate::engine::{COMPACTION_TEMP_EXTENSION, MAX_TSM_FILE_SIZE};
+
+/// DEFAULT_MAX_POINTS_PER_BLOCK is the maximum number of points in an encoded
+/// block of a TSM file.
+pub const DEFAULT_MAX_POINTS_PER_BLOCK: usize = 1000;
+
+/// DEFAULT_MAX_BLOCK_BYTES is the maximum size of an encoded block of a TSM file,
+/// which bounds blocks of long strings.
+pub const DEFAULT_MAX_BLOCK_BYTES: usize = 1024 * 1024;
+
+/// DroppedBlock is a block left out by `Compactor::rewrite_file`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedBlock {
+    pub key: Vec<u8>,
+    pub min_time: i64,
+    pub max_time: i64,
+}
+
+/// RewriteStats reports what `Compactor::rewrite_file` did.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RewriteStats {
+    /// keys is the number of keys written.
+    pub keys: u64,
+    /// blocks is the number of blocks read intact.
+    pub blocks: u64,
+    /// dropped are the blocks failing their checksum or their decoding, or whose
+    /// type differs from the other blocks of their key.
+    pub dropped: Vec<DroppedBlock>,
+    /// retyped is the number of keys whose type in the index differed from the
+    /// type of their blocks.
+    pub retyped: u64,
+}
+
+/// Compactor writes cache snapshots and merges TSM files into new TSM files.
+pub struct Compactor {
+    max_points_per_block: usize,
+    max_block_bytes: usize,
+    max_file_size: u32,
+    /// temp_dir is the local directory the output is written to before it is
+    /// moved next to the files of the store, None to write it there directly.
+    temp_dir: Option<String>,
+    /// bloom_fp_rate adds a bloom filter to the files written, see
+    /// `DefaultTSMWriter::with_bloom_filter`.
+    bloom_fp_rate: Option<f64>,
+}
+
+impl Default for Compactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compactor {
+    pub fn new() -> Self {
+        Self {
+            max_points_per_block: DEFAULT_MAX_POINTS_PER_BLOCK,
+            max_block_bytes: DEFAULT_MAX_BLOCK_BYTES,
+            max_file_size: MAX_TSM_FILE_SIZE,
+            temp_dir: None,
+            bloom_fp_rate: None,
+        }
+    }
+
+    /// set_temp_dir writes the output to the local directory `path`, e.g. on a
+    /// faster scratch disk, instead of next to the files of the store. Complete
+    /// files are moved into the directory of the store before they are installed,
+    /// copied if it is on another file system.
+    pub fn set_temp_dir(&mut self, path: impl Into<String>) {
+        self.temp_dir = Some(path.into());
+    }
+
+    /// with_max_block_bytes sets the size past which an encoded block is split,
+    /// 0 only splits blocks by their number of points.
+    pub fn with_max_block_bytes(mut self, max_block_bytes: usize) -> Self {
+        self.max_block_bytes = max_block_bytes;
+        self
+    }
+
+    /// with_max_file_size sets the size past which the output rolls to a new file.
+    pub fn with_max_file_size(mut self, max_file_size: u32) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// with_bloom_filter writes a bloom filter of the keys with the false
+    /// positive rate `fp_rate` into each file, None writes none.
+    pub fn with_bloom_filter(mut self, fp_rate: Option<f64>) -> Self {
+        self.bloom_fp_rate = fp_rate;
+        self
+    }
+
+    /// write_snapshot writes the snapshot of a cache into TSM files of the next
+    /// generation of `file_store` and adds them to the store, returning their paths.
+    ///
+    /// Keys are written in order, their values split into blocks of at most
+    /// `max_points_per_block` points and `max_block_bytes` bytes. The output rolls to a new file, with the
+    /// next sequence, when a block would grow the current one past the maximum
+    /// file size. Files are written under a `.tmp` name and only renamed by
+    /// `FileStore::replace` once all of them are complete and synced, or under
+    /// their final name if the store has no atomic rename, see
+    /// `FileStore::rename_is_atomic`.
+    pub async fn write_snapshot(
+        &self,
+        snapshot: &CacheSnapshot,
+        file_store: &FileStore,
+    ) -> anyhow::Result<Vec<String>> {
+        if snapshot.values().all(|x| x.len() == 0) {
+            return Ok(vec![]);
+        }
+
+        let generation = file_store.next_generation();
+        let mut w = self.writer(file_store, generation, 1, vec![]);
+        for (key, values) in snapshot.iter() {
+            if let Err(e) = self.write_values(&mut w, key.as_slice(), values).await {
+                w.abort().await;
+                return Err(e);
+            }
+        }
+        let tmp_paths = w.finish().await?;
+
+        self.install(file_store, &[], tmp_paths).await
+    }
+
+    /// compact_full merges the TSM files `files` of `file_store` into as few files
+    /// as the size limits allow, replacing them in the store. Returns the paths of
+    /// the new files, named with the largest generation of the inputs and the
+    /// following sequences.
+    ///
+    /// It runs in a `compact` span recording the number of inputs and, once
+    /// done, the keys and bytes written, the outputs and the duration.
+    ///
+    /// Keys are merged across the files in order. Blocks not overlapping any other
+    /// block of the key nor a tombstone are copied as is; overlapping blocks are
+    /// decoded and merged, the values of the newest file winning for the same
+    /// timestamp, and deleted values are dropped. Keys left without values are
+    /// not written.
+    #[tracing::instrument(
+        name = "compact",
+        skip_all,
+        fields(inputs = files.len(), keys = Empty, bytes = Empty, files = Empty, duration_ms = Empty)
+    )]
+    pub async fn compact_full(
+        &self,
+        file_store: &FileStore,
+        files: &[&str],
+    ) -> anyhow::Result<Vec<String>> {
+        let start = Instant::now();
+        let paths = self.merge_files(file_store, files).await?;
+
+        let span = tracing::Span::current();
+        span.record("files", paths.len());
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        Ok(paths)
+    }
+
+    async fn merge_files(
+        &self,
+        file_store: &FileStore,
+        files: &[&str],
+    ) -> anyhow::Result<Vec<String>> {
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let view = file_store.view().await;
+        let inputs = view.select(files)?;
+
+        let mut generation = 0;
+        let mut sequence = 0;
+        for path in files {
+            let (g, s) = parse_tsm_file_name(path)?;
+            generation = generation.max(g);
+            sequence = sequence.max(s);
+        }
+        let reserved = view
+            .files()
+            .into_iter()
+            .filter(|x| !files.contains(&x.as_str()))
+            .collect();
+
+        let readers = inputs.readers();
+        let mut w = self.writer(file_store, generation, sequence + 1, reserved);
+        if let Err(e) = self.merge(&mut w, readers.as_slice()).await {
+            w.abort().await;
+            return Err(e);
+        }
+        let tmp_paths = w.finish().await?;
+
+        // release the inputs so that replace can remove them
+        drop(inputs);
+        drop(view);
+
+        self.install(file_store, files, tmp_paths).await
+    }
+
+    /// rewrite_file rewrites the TSM file `path` of `file_store`, e.g. after
+    /// `TSMReader::deep_verify` flagged it, replacing it in the store. The new file
+    /// is named with the generation of the input and the following sequence.
+    ///
+    /// Every block is decoded and encoded again, never copied, so the file is
+    /// rebuilt from what its blocks hold: the type of a key is the type of its
+    /// blocks, whatever the index says. Blocks failing their checksum or their
+    /// decoding are dropped and reported in the stats. The tombstones of the file
+    /// are applied.
+    pub async fn rewrite_file(
+        &self,
+        file_store: &FileStore,
+        path: &str,
+    ) -> anyhow::Result<RewriteStats> {
+        let view = file_store.view().await;
+        let input = view.select(&[path])?;
+        let (generation, sequence) = parse_tsm_file_name(path)?;
+        let reserved = view.files().into_iter().filter(|x| x != path).collect();
+
+        let reader = input.readers()[0];
+        let mut stats = RewriteStats::default();
+        let mut w = self.writer(file_store, generation, sequence + 1, reserved);
+        if let Err(e) = self.rewrite(&mut w, reader, &mut stats).await {
+            w.abort().await;
+            return Err(e);
+        }
+        let tmp_paths = w.finish().await?;
+
+        // release the input so that replace can remove it
+        drop(input);
+        drop(view);
+
+        self.install(file_store, &[path], tmp_paths).await?;
+        Ok(stats)
+    }
+
+    /// rewrite writes the decoded blocks of reader, key by key.
+    async fn rewrite(
+        &self,
+        w: &mut CompactionWriter<'_>,
+        reader: &dyn TSMReader,
+        stats: &mut RewriteStats,
+    ) -> anyhow::Result<()> {
+        let mut key = vec![];
+        let mut key_typ = None;
+        let mut values: Option<Values> = None;
+
+        let mut blocks = reader.raw_block_iterator().await?;
+        loop {
+            let block = match blocks.try_next().await {
+                Ok(Some(block)) => block,
+                Ok(None) => break,
+                Err(e) => match e.downcast_ref::<BlockReadError>() {
+                    Some(BlockReadError::ChecksumMismatch { offset, .. }) => {
+                        stats.dropped.push(locate_block(reader, *offset).await?);
+                        continue;
+                    }
+                    _ => return Err(e),
+                },
+            };
+
+            if block.key != key {
+                self.rewrite_key(w, reader, key.as_slice(), values.take(), stats)
+                    .await?;
+                key = block.key.clone();
+                key_typ = Some(block.typ);
+            }
+
+            let dropped = DroppedBlock {
+                key: block.key,
+                min_time: block.min_time,
+                max_time: block.max_time,
+            };
+            let typ = match block_type(block.data.as_slice()) {
+                Ok(typ) if values.as_ref().is_none_or(|x| x.block_type() == typ) => typ,
+                _ => {
+                    stats.dropped.push(dropped);
+                    continue;
+                }
+            };
+            let mut decoded = new_values(typ)?;
+            if decode_block(block.data.as_slice(), &mut decoded).is_err() {
+                stats.dropped.push(dropped);
+                continue;
+            }
+            stats.blocks += 1;
+
+            if key_typ.take().is_some_and(|x| x != typ) {
+                stats.retyped += 1;
+            }
+            match values.as_mut() {
+                Some(values) => append_values(values, decoded)?,
+                None => values = Some(decoded),
+            }
+        }
+
+        self.rewrite_key(w, reader, key.as_slice(), values, stats)
+            .await
+    }
+
+    /// rewrite_key writes the values of key less its tombstones.
+    async fn rewrite_key(
+        &self,
+        w: &mut CompactionWriter<'_>,
+        reader: &dyn TSMReader,
+        key: &[u8],
+        values: Option<Values>,
+        stats: &mut RewriteStats,
+    ) -> anyhow::Result<()> {
+        let mut values = match values {
+            Some(values) => values,
+            None => return Ok(()),
+        };
+
+        values.deduplicate();
+        for tombstone in reader.tombstone_range(key).await {
+            values.exclude(tombstone.min, tombstone.max);
+        }
+        if values.len() == 0 {
+            return Ok(());
+        }
+
+        self.write_values(w, key, &values).await?;
+        stats.keys += 1;
+        Ok(())
+    }
+
+    /// merge writes the keys of readers merged across them.
+    async fn merge(
+        &self,
+        w: &mut CompactionWriter<'_>,
+        readers: &[&dyn TSMReader],
+    ) -> anyhow::Result<()> {
+        let mut itrs = Vec::with_capacity(readers.len());
+        for reader in readers {
+            itrs.push(reader.key_iterator().await?);
+        }
+        let mut keys = KeysIterator::new(itrs).await?;
+        while let Some(key) = keys.try_next().await? {
+            self.merge_key(w, readers, key.as_slice()).await?;
+        }
+        Ok(())
+    }
+
+    /// merge_key writes the blocks of key merged across readers, oldest first.
+    async fn merge_key(
+        &self,
+        w: &mut CompactionWriter<'_>,
+        readers: &[&dyn TSMReader],
+        key: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut typ = None;
+        let mut tombstones = Vec::with_capacity(readers.len());
+        let mut blocks = vec![];
+        for (i, reader) in readers.iter().enumerate() {
+            let mut entries = IndexEntries::default();
+            reader.read_entries(key, &mut entries).await?;
+            if !entries.is_empty() {
+                if typ.is_some_and(|x| x != entries.typ) {
+                    return Err(anyhow!("field type conflict across tsm files"));
+                }
+                typ = Some(entries.typ);
+            }
+
+            blocks.extend(entries.iter().map(|entry| (i, entry)));
+            tombstones.push(reader.tombstone_range(key).await);
+        }
+        let typ = match typ {
+            Some(typ) => typ,
+            None => return Ok(()),
+        };
+
+        // clusters of blocks overlapping in time, in time order
+        blocks.sort_by_key(|(i, entry)| (entry.min_time, *i));
+        let mut clusters: Vec<Vec<(usize, IndexEntry)>> = vec![];
+        let mut cluster_max = i64::MIN;
+        for (i, entry) in blocks {
+            match clusters.last_mut() {
+                Some(cluster) if entry.min_time <= cluster_max => {
+                    cluster_max = cluster_max.max(entry.max_time);
+                    cluster.push((i, entry));
+                }
+                _ => {
+                    cluster_max = entry.max_time;
+                    clusters.push(vec![(i, entry)]);
+                }
+            }
+        }
+
+        let mut raw = vec![];
+        for mut cluster in clusters {
+            if let [(i, entry)] = cluster.as_slice() {
+                let deleted = tombstones[*i]
+                    .iter()
+                    .any(|x| x.min <= entry.max_time && x.max >= entry.min_time);
+                if !deleted {
+                    readers[*i].read_raw_block_at(entry, &mut raw).await?;
+                    w.write_block(key, entry.min_time, entry.max_time, raw.as_slice())
+                        .await?;
+                    continue;
+                }
+            }
+
+            // the values of each file are merged oldest first, so that the newest
+            // file wins on deduplication
+            cluster.sort_by_key(|(i, _)| *i);
+            let mut values = new_values(typ)?;
+            let mut start = 0;
+            while start < cluster.len() {
+                let i = cluster[start].0;
+                let end = start + cluster[start..].iter().take_while(|x| x.0 == i).count();
+
+                let mut file_values = new_values(typ)?;
+                for (_, entry) in cluster[start..end].iter() {
+                    readers[i].read_block_at(entry, &mut file_values).await?;
+                }
+                file_values.deduplicate();
+                for tombstone in tombstones[i].iter() {
+                    file_values.exclude(tombstone.min, tombstone.max);
+                }
+                append_values(&mut values, file_values)?;
+
+                start = end;
+            }
+            values.deduplicate();
+
+            self.write_values(w, key, &values).await?;
+        }
+
+        Ok(())
+    }
+
+    /// write_values encodes values into blocks of at most `max_points_per_block`.
+    /// A block encoded larger than `max_block_bytes` is split in halves until
+    /// each half fits, or holds a single point.
+    async fn write_values(
+        &self,
+        w: &mut CompactionWriter<'_>,
+        key: &[u8],
+        values: &Values,
+    ) -> anyhow::Result<()> {
+        for values in split_values(values, self.max_points_per_block) {
+            let mut pending = vec![values];
+            while let Some(values) = pending.pop() {
+                if values.len() == 0 {
+                    continue;
+                }
+                let min_time = values.min_time();
+                let max_time = values.max_time();
+
+                let mut block = vec![];
+                encode_block(&mut block, values.clone())?;
+                if self.max_block_bytes > 0
+                    && block.len() > self.max_block_bytes
+                    && values.len() > 1
+                {
+                    // the first half is popped first, keeping the blocks in order
+                    let mut halves = split_values(&values, values.len().div_ceil(2));
+                    halves.reverse();
+                    pending.extend(halves);
+                    continue;
+                }
+
+                w.write_block(key, min_time, max_time, block.as_slice())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// writer returns a writer of new TSM files of `file_store`.
+    fn writer<'a>(
+        &'a self,
+        file_store: &'a FileStore,
+        generation: u64,
+        sequence: u64,
+        reserved: Vec<String>,
+    ) -> CompactionWriter<'a> {
+        CompactionWriter {
+            dir: file_store.path(),
+            tmp: file_store.rename_is_atomic(),
+            generation,
+            sequence,
+            max_file_size: self.max_file_size,
+            temp_dir: self.temp_dir.as_deref(),
+            bloom_fp_rate: self.bloom_fp_rate,
+            reserved,
+            w: None,
+            key: vec![],
+            key_blocks: 0,
+            keys: 0,
+            bytes: 0,
+            tmp_paths: vec![],
+        }
+    }
+
+    /// install swaps the `old` files for the files written in the store,
+    /// returning the final paths of the new files. Files written to the temp
+    /// directory are first moved into the directory of the store.
+    async fn install(
+        &self,
+        file_store: &FileStore,
+        old: &[&str],
+        tmp_paths: Vec<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let tmp_paths = match self.temp_dir {
+            Some(_) => move_outputs(file_store, tmp_paths).await?,
+            None => tmp_paths,
+        };
+
+        let new: Vec<&str> = tmp_paths.iter().map(|x| x.as_str()).collect();
+        file_store.replace(old, new.as_slice()).await?;
+
+        let suffix = format!(".{}", COMPACTION_TEMP_EXTENSION);
+        Ok(tmp_paths
+            .iter()
+            .map(|x| x.strip_suffix(suffix.as_str()).unwrap_or(x).to_string())
+            .collect())
+    }
+}
+
+/// CompactionWriter writes blocks into the TSM files of a generation, rolling to
+/// the next sequence when a file reaches the maximum size, or a key the maximum
+/// number of blocks of a file.
+struct CompactionWriter<'a> {
+    /// dir is the directory of the files, e.g. of the store.
+    dir: &'a str,
+    /// tmp names the files with the `.tmp` extension until they are renamed,
+    /// otherwise they are written under their final name.
+    tmp: bool,
+    generation: u64,
+    sequence: u64,
+    max_file_size: u32,
+    temp_dir: Option<&'a str>,
+    bloom_fp_rate: Option<f64>,
+    /// reserved are the paths of files of the store which must not be overwritten.
+    reserved: Vec<String>,
+
+    w: Option<DefaultTSMWriter<DirectIndex<MemoryIndexBuffer>>>,
+    key: Vec<u8>,
+    key_blocks: usize,
+    /// keys and bytes are the number of keys and the size of the files written.
+    keys: u64,
+    bytes: u64,
+    tmp_paths: Vec<String>,
+}
+
+impl<'a> CompactionWriter<'a> {
+    async fn write_block(
+        &mut self,
+        key: &[u8],
+        min_time: i64,
+        max_time: i64,
+        block: &[u8],
+    ) -> anyhow::Result<()> {
+        if key != self.key.as_slice() {
+            self.key = key.to_vec();
+            self.key_blocks = 0;
+            self.keys += 1;
+        }
+
+        let full = match self.w.as_ref() {
+            // the block is prefixed by its checksum
+            Some(w) => {
+                let size = w.size() as u64 + 4 + block.len() as u64;
+                (w.size() > 0 && size > self.max_file_size as u64)
+                    || self.key_blocks + 1 >= MAX_INDEX_ENTRIES
+            }
+            None => true,
+        };
+        if full {
+            self.roll().await?;
+        }
+
+        let w = self.w.as_mut().unwrap();
+        w.write_block(key, min_time, max_time, block).await?;
+        self.key_blocks += 1;
+        Ok(())
+    }
+
+    /// roll completes the current file, if any, and starts the next one.
+    async fn roll(&mut self) -> anyhow::Result<()> {
+        if self.w.is_some() {
+            self.complete().await?;
+            self.sequence += 1;
+        }
+
+        let path = path_join(
+            self.dir,
+            tsm_file_name(self.generation, self.sequence).as_str(),
+        );
+        if self.reserved.contains(&path) {
+            return Err(anyhow!("compaction output {} already exists", path));
+        }
+
+        let path = if self.tmp {
+            format!("{}.{}", path, COMPACTION_TEMP_EXTENSION)
+        } else {
+            path
+        };
+        let tmp_path = match self.temp_dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                path_join(dir, path.rsplit('/').next().unwrap_or(path.as_str()))
+            }
+            None => path,
+        };
+        let w = DefaultTSMWriter::with_mem_buffer(tmp_path.as_str()).await?;
+        self.w = Some(w.with_bloom_filter(self.bloom_fp_rate));
+        self.tmp_paths.push(tmp_path);
+        self.key_blocks = 0;
+        Ok(())
+    }
+
+    /// complete writes the index of the current file and closes it.
+    async fn complete(&mut self) -> anyhow::Result<()> {
+        if let Some(w) = self.w.as_mut() {
+            w.write_index().await?;
+        }
+        if let Some(w) = self.w.take() {
+            self.bytes += w.close().await?.size;
+        }
+        Ok(())
+    }
+
+    /// finish completes the current file and returns the paths of the files written.
+    /// On error the files written are deleted. The keys and bytes written are
+    /// recorded on the current span, e.g. the `flush` or `compact` span.
+    async fn finish(mut self) -> anyhow::Result<Vec<String>> {
+        if let Err(e) = self.complete().await {
+            self.abort().await;
+            return Err(e);
+        }
+
+        let span = tracing::Span::current();
+        span.record("keys", self.keys);
+        span.record("bytes", self.bytes);
+        Ok(self.tmp_paths)
+    }
+
+    /// abort deletes the files written so far, after an error. Failures to delete
+    /// are only logged, the error which caused the abort is the one to report.
+    async fn abort(&mut self) {
+        if let Some(w) = self.w.take() {
+            if let Err(e) = w.abort().await {
+                tracing::warn!("failed to abort compaction output: {}", e);
+            }
+        }
+
+        for tmp_path in self.tmp_paths.drain(..) {
+            let r = match StorageOperator::root(tmp_path.as_str()) {
+                Ok(op) => op.delete().await.map_err(|e| anyhow!(e)),
+                Err(e) => Err(anyhow!(e)),
+            };
+            if let Err(e) = r {
+                tracing::warn!("failed to remove compaction output {}: {}", tmp_path, e);
+            }
+        }
+    }
+}
+
+/// move_outputs moves the files written to the temp directory of a `Compactor`
+/// into the directory of the store, keeping their name, and returns their
+/// new paths. On error the files are removed from both directories.
+async fn move_outputs(
+    file_store: &FileStore,
+    tmp_paths: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut moved = Vec::with_capacity(tmp_paths.len());
+    for tmp_path in tmp_paths.iter() {
+        let name = tmp_path.rsplit('/').next().unwrap_or(tmp_path.as_str());
+        let path = path_join(file_store.path(), name);
+        if let Err(e) = move_file(tmp_path.as_str(), path.as_str()).await {
+            for path in tmp_paths.iter().chain(moved.iter()).chain([&path]) {
+                if let Err(e) = tokio::fs::remove_file(path).await {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        tracing::warn!("failed to remove compaction output {}: {}", path, e);
+                    }
+                }
+            }
+            return Err(e);
+        }
+        moved.push(path);
+    }
+    Ok(moved)
+}
+
+/// move_file renames `from` to `to`, or copies and syncs it then removes `from`
+/// if the rename fails, e.g. across file systems.
+async fn move_file(from: &str, to: &str) -> anyhow::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::File::open(to).await?.sync_all().await?;
+    tokio::fs::remove_file(from).await?;
+    Ok(())
+}
+
+/// write_snapshot_file writes the snapshot of a cache into the single TSM file `op`,
+/// outside of any `FileStore`, e.g. for tools. Keys are written in order, their
+/// values split into blocks of at most `DEFAULT_MAX_POINTS_PER_BLOCK` points.
+///
+/// The file is written under a `.tmp` name and renamed to `op` once it is complete
+/// and synced. On error the temporary file is removed and `op` is left untouched.
+pub async fn write_snapshot_file(
+    snapshot: &CacheSnapshot,
+    op: &StorageOperator,
+) -> anyhow::Result<TSMWriterStats> {
+    let tmp = op.to_tmp(COMPACTION_TEMP_EXTENSION);
+    let mut w = DefaultTSMWriter::with_mem_buffer(tmp.path()).await?;
+
+    let r = async {
+        for (key, values) in snapshot.iter() {
+            for values in split_values(values, DEFAULT_MAX_POINTS_PER_BLOCK) {
+                w.write(key.as_slice(), values).await?;
+            }
+        }
+        w.write_index().await
+    }
+    .await;
+    if let Err(e) = r {
+        if let Err(abort) = w.abort().await {
+            tracing::warn!("failed to remove {}: {}", tmp.path(), abort);
+        }
+        return Err(e);
+    }
+
+    let stats = w.close().await?;
+    tmp.rename(op.path()).await?;
+    Ok(stats)
+}
+
+/// compact merges the TSM files read by `inputs`, oldest first, into new TSM files
+/// in the directory `out`, outside of any `FileStore`, e.g. for tools. It merges
+/// like `Compactor::compact_full` with the default limits: per key in ascending
+/// time, the newest file winning for the same timestamp, in blocks of at most
+/// `DEFAULT_MAX_POINTS_PER_BLOCK` points, rolling to a new file past
+/// `MAX_TSM_FILE_SIZE`. The new files are named with the largest generation of
+/// the inputs and the following sequences.
+///
+/// The new files are added to the `FileStore` of `out`, which commits them as a
+/// whole, see `FileStore::replace`: a crash leaves either none or all of them.
+/// On error the files written are removed.
+pub async fn compact(inputs: Vec<Box<dyn TSMReader>>, out: StorageOperator) -> anyhow::Result<()> {
+    if inputs.is_empty() {
+        return Ok(());
+    }
+
+    let mut generation = 0;
+    let mut sequence = 0;
+    for reader in inputs.iter() {
+        let (g, s) = parse_tsm_file_name(reader.path())?;
+        generation = generation.max(g);
+        sequence = sequence.max(s);
+    }
+    let readers: Vec<&dyn TSMReader> = inputs.iter().map(|x| x.as_ref()).collect();
+
+    let file_store = FileStore::open(out).await?;
+    let mut reserved = file_store.files().await;
+    reserved.extend(readers.iter().map(|x| x.path().to_string()));
+
+    let compactor = Compactor::new();
+    let mut w = compactor.writer(&file_store, generation, sequence + 1, reserved);
+    if let Err(e) = compactor.merge(&mut w, readers.as_slice()).await {
+        w.abort().await;
+        return Err(e);
+    }
+    let tmp_paths = w.finish().await?;
+
+    compactor.install(&file_store, &[], tmp_paths).await?;
+    Ok(())
+}
+
+/// locate_block returns the key and time range of the block at offset in the index
+/// of reader.
+async fn locate_block(reader: &dyn TSMReader, offset: u64) -> anyhow::Result<DroppedBlock> {
+    let mut entries = IndexEntries::default();
+    for i in 0..reader.key_count().await {
+        let key = match reader.key_at(i).await? {
+            Some((key, _)) => key,
+            None => break,
+        };
+        reader.read_entries(key.as_slice(), &mut entries).await?;
+        if let Some(entry) = entries.iter().find(|x| x.offset == offset) {
+            return Ok(DroppedBlock {
+                key,
+                min_time: entry.min_time,
+                max_time: entry.max_time,
+            });
+        }
+    }
+    Err(anyhow!("no block at {} in {}", offset, reader.path()))
+}
+
+/// split_values splits values into chunks of at most `size` points.
+pub(crate) fn split_values(values: &Values, size: usize) -> Vec<Values> {
+    let size = size.max(1);
+    match values {
+        Values::Float(values) => values
+            .chunks(size)
+            .map(|x| Values::Float(x.to_vec()))
+            .collect(),
+        Values::Integer(values) => values
+            .chunks(size)
+            .map(|x| Values::Integer(x.to_vec()))
+            .collect(),
+        Values::Bool(values) => values
+            .chunks(size)
+            .map(|x| Values::Bool(x.to_vec()))
+            .collect(),
+        Values::String(values) => values
+            .chunks(size)
+            .map(|x| Values::String(x.to_vec()))
+            .collect(),
+        Values::Unsigned(values) => values
+            .chunks(size)
+            .map(|x| Values::Unsigned(x.to_vec()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::block::BLOCK_FLOAT64;
+    use crate::engine::tsm1::cache::CacheSnapshot;
+    use crate::engine::tsm1::compact::{
+        compact, split_values, write_snapshot_file, Compactor, DroppedBlock, RewriteStats,
+        DEFAULT_MAX_POINTS_PER_BLOCK,
+    };
+    use crate::engine::tsm1::file_store::file_store::FileStore;
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    const POINTS: i64 = 10_000;
+
+    fn float_values(points: impl Iterator<Item = (i64, f64)>) -> Values {
+        Values::Float(points.map(|(t, v)| TimeValue::new(t, v)).collect())
+    }
+
+    async fn write_tsm_file(path: &str, data: Vec<(&str, Values)>) {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for (key, values) in data {
+            w.write(key.as_bytes(), values).await.unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+    }
+
+    fn new_snapshot() -> CacheSnapshot {
+        let mut snapshot = CacheSnapshot::new();
+        for (i, key) in ["cpu,host=a#!~#value", "cpu,host=b#!~#value", "mem#!~#free"]
+            .iter()
+            .enumerate()
+        {
+            let values = (0..POINTS)
+                .map(|t| TimeValue::new(t, (t * (i as i64 + 1)) as f64))
+                .collect();
+            snapshot.insert(key.as_bytes().to_vec(), Values::Float(values));
+        }
+        snapshot
+    }
+
+    #[tokio::test]
+    async fn test_compactor_write_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let file_store = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let snapshot = new_snapshot();
+        let paths = Compactor::new()
+            .write_snapshot(&snapshot, &file_store)
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![file_store.tsm_path(1, 1)]);
+        assert_eq!(file_store.files().await, paths);
+
+        let op = StorageOperator::root(paths[0].as_str()).unwrap();
+        let reader = new_default_tsm_reader(op).await.unwrap();
+        assert_eq!(reader.key_count().await, 3);
+        for (key, values) in snapshot.iter() {
+            let mut entries = IndexEntries::default();
+            reader.read_entries(key, &mut entries).await.unwrap();
+            assert_eq!(
+                entries.len(),
+                POINTS as usize / DEFAULT_MAX_POINTS_PER_BLOCK
+            );
+
+            let got = file_store.read(key, TimeRange::unbound()).await.unwrap();
+            assert_eq!(got.as_ref(), Some(values));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_snapshot_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000000001-000000001.tsm");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut snapshot = new_snapshot();
+        snapshot.insert(
+            b"disk#!~#used".to_vec(),
+            Values::Integer(vec![TimeValue::new(1, 10), TimeValue::new(2, 20)]),
+        );
+        let stats = write_snapshot_file(&snapshot, &op).await.unwrap();
+        assert_eq!(
+            stats.blocks,
+            3 * POINTS as u64 / DEFAULT_MAX_POINTS_PER_BLOCK as u64 + 1
+        );
+        assert!(!dir.path().join("000000001-000000001.tsm.tmp").exists());
+
+        let reader = new_default_tsm_reader(op).await.unwrap();
+        assert_eq!(reader.key_count().await, snapshot.len());
+        for (i, (key, values)) in snapshot.iter().enumerate() {
+            assert_eq!(reader.key_at(i).await.unwrap().unwrap().0, *key);
+
+            let mut entries = IndexEntries::default();
+            reader.read_entries(key, &mut entries).await.unwrap();
+            let mut got = match values {
+                Values::Integer(_) => Values::Integer(vec![]),
+                _ => Values::Float(vec![]),
+            };
+            for j in 0..entries.len() {
+                reader
+                    .read_block_at(&entries.entry(j), &mut got)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(&got, values);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compactor_write_snapshot_roll() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let file_store = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let snapshot = new_snapshot();
+        let paths = Compactor::new()
+            .with_max_file_size(16 * 1024)
+            .write_snapshot(&snapshot, &file_store)
+            .await
+            .unwrap();
+        assert!(paths.len() > 1);
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(*path, file_store.tsm_path(1, i as u64 + 1));
+            let size = std::fs::metadata(path).unwrap().len();
+            assert!(size <= 16 * 1024, "{} is {} bytes", path, size);
+        }
+        assert_eq!(file_store.files().await, paths);
+
+        for (key, values) in snapshot.iter() {
+            let got = file_store.read(key, TimeRange::unbound()).await.unwrap();
+            assert_eq!(got.as_ref(), Some(values));
+        }
+
+        let empty = CacheSnapshot::new();
+        let paths = Compactor::new()
+            .write_snapshot(&empty, &file_store)
+            .await
+            .unwrap();
+        assert!(paths.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compactor_max_block_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let file_store = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // 100 strings of 1KB which do not compress: far from the point limit, but
+        // 100KB in a single block
+        let mut seed = 1_u64;
+        let values: Vec<TimeValue<Vec<u8>>> = (0..100)
+            .map(|t| {
+                let value = (0..1024)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                        b'a' + (seed >> 59) as u8
+                    })
+                    .collect();
+                TimeValue::new(t, value)
+            })
+            .collect();
+        let key = b"log#!~#line".to_vec();
+        let mut snapshot = CacheSnapshot::new();
+        snapshot.insert(key.clone(), Values::String(values.clone()));
+
+        let max_block_bytes = 16 * 1024;
+        let paths = Compactor::new()
+            .with_max_block_bytes(max_block_bytes)
+            .write_snapshot(&snapshot, &file_store)
+            .await
+            .unwrap();
+
+        let op = StorageOperator::root(paths[0].as_str()).unwrap();
+        let reader = new_default_tsm_reader(op).await.unwrap();
+        let mut entries = IndexEntries::default();
+        reader.read_entries(&key, &mut entries).await.unwrap();
+        assert!(entries.len() > 1);
+        for entry in entries.iter() {
+            // the size of an entry includes the 4 bytes checksum of the block
+            assert!(entry.size as usize - 4 <= max_block_bytes, "{:?}", entry);
+        }
+
+        let got = file_store.read(&key, TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(Values::String(values)));
+    }
+
+    #[tokio::test]
+    async fn test_compactor_rewrite_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let input = format!("{}{}", path, "000000001-000000001.tsm");
+
+        let cpu = float_values((0..20).map(|t| (t, t as f64)));
+        let (cpu_first, cpu_second) = match &cpu {
+            Values::Float(v) => (
+                Values::Float(v[..10].to_vec()),
+                Values::Float(v[10..].to_vec()),
+            ),
+            _ => unreachable!(),
+        };
+        let disk = float_values((0..5).map(|t| (t, -t as f64)));
+        let mem = Values::Integer((0..7).map(|t| TimeValue::new(t, t * 10)).collect());
+        let mut w = DefaultTSMWriter::with_mem_buffer(input.as_str())
+            .await
+            .unwrap();
+        w.write(b"cpu#!~#value", cpu_first.clone()).await.unwrap();
+        w.write(b"cpu#!~#value", cpu_second).await.unwrap();
+        w.write(b"disk#!~#value", disk).await.unwrap();
+        w.write(b"mem#!~#value", mem.clone()).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        // damage the second block of cpu, and the type of mem in the index
+        let reader = new_default_tsm_reader(StorageOperator::root(&input).unwrap())
+            .await
+            .unwrap();
+        let mut entries = IndexEntries::default();
+        reader
+            .read_entries(b"cpu#!~#value", &mut entries)
+            .await
+            .unwrap();
+        let damaged = entries.entry(1);
+        drop(reader);
+
+        let mut data = std::fs::read(&input).unwrap();
+        data[damaged.offset as usize + 6] ^= 0xff;
+        let footer = data.len() - 8;
+        let index_offset = u64::from_be_bytes(data[footer..].try_into().unwrap()) as usize;
+        let key = b"mem#!~#value";
+        let pos = index_offset
+            + data[index_offset..]
+                .windows(key.len())
+                .position(|x| x == key)
+                .unwrap();
+        data[pos + key.len()] = BLOCK_FLOAT64;
+        std::fs::write(&input, data).unwrap();
+
+        let file_store = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        file_store
+            .delete_range(&[b"disk#!~#value"], 2, 3)
+            .await
+            .unwrap();
+
+        let stats = Compactor::new()
+            .rewrite_file(&file_store, input.as_str())
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            RewriteStats {
+                keys: 3,
+                blocks: 3,
+                dropped: vec![DroppedBlock {
+                    key: b"cpu#!~#value".to_vec(),
+                    min_time: 10,
+                    max_time: 19,
+                }],
+                retyped: 1,
+            }
+        );
+
+        let output = file_store.tsm_path(1, 2);
+        assert_eq!(file_store.files().await, vec![output.clone()]);
+        assert!(!std::path::Path::new(&input).exists());
+        let reader = new_default_tsm_reader(StorageOperator::root(&output).unwrap())
+            .await
+            .unwrap();
+        reader.deep_verify().await.unwrap();
+        assert!(!reader.has_tombstones().await.unwrap());
+
+        let got = file_store
+            .read(b"cpu#!~#value", TimeRange::unbound())
+            .await
+            .unwrap();
+        assert_eq!(got, Some(cpu_first));
+        let got = file_store
+            .read(b"disk#!~#value", TimeRange::unbound())
+            .await
+            .unwrap();
+        assert_eq!(
+            got,
+            Some(float_values([(0, 0.0), (1, -1.0), (4, -4.0)].into_iter()))
+        );
+        let got = file_store
+            .read(b"mem#!~#value", TimeRange::unbound())
+            .await
+            .unwrap();
+        assert_eq!(got, Some(mem));
+    }
+
+    #[tokio::test]
+    async fn test_compactor_compact_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let op = StorageOperator::root(&path).unwrap();
+        let file_store = FileStore::open(op.clone()).await.unwrap();
+        write_tsm_file(
+            &file_store.tsm_path(1, 1),
+            vec![
+                ("cpu", float_values((1..=10).map(|t| (t, 1.0)))),
+                ("disk", float_values((1..=5).map(|t| (t, 1.0)))),
+                ("mem", float_values((1..=3).map(|t| (t, 1.0)))),
+            ],
+        )
+        .await;
+        write_tsm_file(
+            &file_store.tsm_path(2, 1),
+            vec![("cpu", float_values((5..=15).map(|t| (t, 2.0))))],
+        )
+        .await;
+
+        let file_store = FileStore::open(op).await.unwrap();
+        file_store
+            .delete_range(&[b"disk"], i64::MIN, i64::MAX)
+            .await
+            .unwrap();
+        file_store.delete_range(&[b"mem"], 2, 2).await.unwrap();
+
+        let inputs = file_store.files().await;
+        let inputs: Vec<&str> = inputs.iter().map(|x| x.as_str()).collect();
+        let paths = Compactor::new()
+            .compact_full(&file_store, inputs.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![file_store.tsm_path(2, 2)]);
+        assert_eq!(file_store.files().await, paths);
+        for input in inputs {
+            assert!(!std::path::Path::new(input).exists());
+        }
+
+        // the later file overrides the overlapping values
+        let expected = float_values((1..=15).map(|t| (t, if t < 5 { 1.0 } else { 2.0 })));
+        let got = file_store.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(expected));
+
+        // deleted values are dropped, as are keys left empty
+        let got = file_store.read(b"mem", TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(float_values([(1, 1.0), (3, 1.0)].into_iter())));
+        let op = StorageOperator::root(paths[0].as_str()).unwrap();
+        let reader = new_default_tsm_reader(op).await.unwrap();
+        assert!(!reader.contains(b"disk").await.unwrap());
+        assert_eq!(reader.key_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+
+        let mut inputs: Vec<Box<dyn TSMReader>> = vec![];
+        for (name, data) in [
+            (
+                "000000001-000000001.tsm",
+                vec![
+                    ("cpu", float_values((1..=10).map(|t| (t, 1.0)))),
+                    ("mem", float_values((1..=3).map(|t| (t, 1.0)))),
+                ],
+            ),
+            (
+                "000000002-000000001.tsm",
+                vec![("cpu", float_values((5..=2500).map(|t| (t, 2.0))))],
+            ),
+        ] {
+            let path = dir.path().join(name);
+            write_tsm_file(path.to_str().unwrap(), data).await;
+            let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+            inputs.push(Box::new(new_default_tsm_reader(op).await.unwrap()));
+        }
+
+        let out_op = StorageOperator::root(&format!("{}/", out.path().to_str().unwrap())).unwrap();
+        compact(inputs, out_op).await.unwrap();
+
+        let names: Vec<_> = std::fs::read_dir(out.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(names, vec!["000000002-000000002.tsm".to_string()]);
+
+        let path = out.path().join("000000002-000000002.tsm");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        let reader = new_default_tsm_reader(op).await.unwrap();
+        assert_eq!(reader.key_count().await, 2);
+
+        // the later file overrides the overlapping values, in full blocks
+        for (key, expected) in [
+            (
+                b"cpu".as_slice(),
+                float_values((1..=2500).map(|t| (t, if t < 5 { 1.0 } else { 2.0 }))),
+            ),
+            (b"mem".as_slice(), float_values((1..=3).map(|t| (t, 1.0)))),
+        ] {
+            let mut entries = IndexEntries::default();
+            reader.read_entries(key, &mut entries).await.unwrap();
+            assert_eq!(
+                entries.len(),
+                expected.len().div_ceil(DEFAULT_MAX_POINTS_PER_BLOCK)
+            );
+
+            let mut got = Values::Float(vec![]);
+            for entry in entries.iter() {
+                reader.read_block_at(&entry, &mut got).await.unwrap();
+            }
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compactor_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let scratch = tempfile::tempdir().unwrap();
+        let temp_dir = scratch.path().join("compaction");
+
+        let op = StorageOperator::root(&path).unwrap();
+        let file_store = FileStore::open(op.clone()).await.unwrap();
+        write_tsm_file(
+            &file_store.tsm_path(1, 1),
+            vec![("cpu", float_values((1..=10).map(|t| (t, 1.0))))],
+        )
+        .await;
+        write_tsm_file(
+            &file_store.tsm_path(2, 1),
+            vec![("cpu", float_values((5..=15).map(|t| (t, 2.0))))],
+        )
+        .await;
+        let file_store = FileStore::open(op).await.unwrap();
+
+        let mut compactor = Compactor::new();
+        compactor.set_temp_dir(temp_dir.to_str().unwrap());
+
+        let inputs = file_store.files().await;
+        let inputs: Vec<&str> = inputs.iter().map(|x| x.as_str()).collect();
+        let paths = compactor
+            .compact_full(&file_store, inputs.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![file_store.tsm_path(2, 2)]);
+        assert!(std::path::Path::new(paths[0].as_str()).exists());
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        let expected = float_values((1..=15).map(|t| (t, if t < 5 { 1.0 } else { 2.0 })));
+        let got = file_store.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(expected));
+
+        // snapshots go through the temp directory too
+        let paths = compactor
+            .write_snapshot(&new_snapshot(), &file_store)
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![file_store.tsm_path(3, 1)]);
+        assert_eq!(file_store.files().await.len(), 2);
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_str().unwrap().to_string())
+            .filter(|x| x.ends_with(".tsm") || x.ends_with(".tmp"))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec!["000000002-000000002.tsm", "000000003-000000001.tsm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compactor_compact_full_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let op = StorageOperator::root(&path).unwrap();
+        let file_store = FileStore::open(op.clone()).await.unwrap();
+
+        // three files with disjoint time ranges of the same key
+        let mut expected = vec![];
+        let mut input_size = 0;
+        for generation in 1..=3 {
+            let min = (generation as i64 - 1) * POINTS;
+            let values: Vec<_> = (min..min + POINTS)
+                .map(|t| TimeValue::new(t, (t as f64 * 1.37).sin()))
+                .collect();
+            expected.extend(values.iter().cloned());
+
+            let tsm_path = file_store.tsm_path(generation, 1);
+            let data = split_values(&Values::Float(values), DEFAULT_MAX_POINTS_PER_BLOCK)
+                .into_iter()
+                .map(|x| ("cpu", x))
+                .collect();
+            write_tsm_file(&tsm_path, data).await;
+            input_size += std::fs::metadata(&tsm_path).unwrap().len();
+        }
+        let file_store = FileStore::open(op).await.unwrap();
+
+        let max_file_size = (input_size * 2 / 3 + 1024) as u32;
+        let inputs = file_store.files().await;
+        let inputs: Vec<&str> = inputs.iter().map(|x| x.as_str()).collect();
+        let paths = Compactor::new()
+            .with_max_file_size(max_file_size)
+            .compact_full(&file_store, inputs.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            paths,
+            vec![file_store.tsm_path(3, 2), file_store.tsm_path(3, 3)]
+        );
+        assert_eq!(file_store.files().await, paths);
+
+        // the blocks are copied as is
+        let mut blocks = 0;
+        for path in paths.iter() {
+            assert!(std::fs::metadata(path).unwrap().len() <= max_file_size as u64);
+
+            let op = StorageOperator::root(path.as_str()).unwrap();
+            let reader = new_default_tsm_reader(op).await.unwrap();
+            let mut entries = IndexEntries::default();
+            reader.read_entries(b"cpu", &mut entries).await.unwrap();
+            blocks += entries.len();
+        }
+        assert_eq!(blocks, 3 * POINTS as usize / DEFAULT_MAX_POINTS_PER_BLOCK);
+
+        let got = file_store.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(Values::Float(expected)));
+    }
+
+    #[tokio::test]
+    async fn test_compactor_compact_full_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let op = StorageOperator::root(&path).unwrap();
+        let file_store = FileStore::open(op.clone()).await.unwrap();
+
+        let mut input_size = 0;
+        for generation in 1..=2 {
+            let min = (generation as i64 - 1) * POINTS;
+            let values = float_values((min..min + POINTS).map(|t| (t, t as f64)));
+            let data = split_values(&values, DEFAULT_MAX_POINTS_PER_BLOCK)
+                .into_iter()
+                .map(|x| ("cpu", x))
+                .collect();
+            let tsm_path = file_store.tsm_path(generation, 1);
+            write_tsm_file(&tsm_path, data).await;
+            input_size += std::fs::metadata(&tsm_path).unwrap().len();
+        }
+        // a file of the store in the way of the second output file
+        write_tsm_file(
+            &file_store.tsm_path(2, 3),
+            vec![("mem", float_values([(0, 1.0)].into_iter()))],
+        )
+        .await;
+        let file_store = FileStore::open(op).await.unwrap();
+        let files = file_store.files().await;
+
+        let inputs = [file_store.tsm_path(1, 1), file_store.tsm_path(2, 1)];
+        let inputs: Vec<&str> = inputs.iter().map(|x| x.as_str()).collect();
+        let err = Compactor::new()
+            .with_max_file_size((input_size * 2 / 3) as u32)
+            .compact_full(&file_store, inputs.as_slice())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+
+        // the first output file, complete when the error happened, is removed
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_str().unwrap().to_string())
+            .filter(|x| !x.ends_with(".stats"))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "000000001-000000001.tsm",
+                "000000002-000000001.tsm",
+                "000000002-000000003.tsm",
+            ]
+        );
+        assert_eq!(file_store.files().await, files);
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::engine::tsm1::file_store::file_store::parse_tsm_file_name;
+use crate::engine::tsm1::file_store::stat::FileStat;
+use crate::engine::MAX_TSM_FILE_SIZE;
+
+/// MAX_LEVEL is the level of generations compacted past level 3, only picked
+/// by the optimize and full plans.
+pub const MAX_LEVEL: u64 = 4;
+
+/// DEFAULT_MIN_GENERATIONS is the number of adjacent generations of a level
+/// grouped by a level plan.
+pub const DEFAULT_MIN_GENERATIONS: usize = 4;
+
+/// CompactionGroup is the paths of the TSM files to compact together.
+pub type CompactionGroup = Vec<String>;
+
+/// DefaultPlanner picks the TSM files of a file store to compact together.
+///
+/// Files are grouped by generation, the level of a generation being the highest
+/// sequence of its files: a cache snapshot writes level 1, compacting level 1
+/// generations writes level 2 and so on up to `MAX_LEVEL`. Files of planned
+/// groups are in progress until released, and are never planned twice.
+pub struct DefaultPlanner {
+    min_generations: usize,
+    max_file_size: u32,
+
+    in_progress: Mutex<HashSet<String>>,
+}
+
+impl Default for DefaultPlanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DefaultPlanner {
+    pub fn new() -> Self {
+        Self {
+            min_generations: DEFAULT_MIN_GENERATIONS,
+            max_file_size: MAX_TSM_FILE_SIZE,
+            in_progress: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// with_min_generations sets the number of generations grouped by a level plan.
+    pub fn with_min_generations(mut self, min_generations: usize) -> Self {
+        self.min_generations = min_generations.max(1);
+        self
+    }
+
+    /// with_max_file_size sets the size from which a generation is left out of
+    /// full plans.
+    pub fn with_max_file_size(mut self, max_file_size: u32) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// plan returns the groups of adjacent generations of `level` to compact,
+    /// `min_generations` at a time. A shorter run is only planned if one of its
+    /// files has tombstones. A generation followed by a higher level one can not
+    /// be compacted with its level anymore and joins the run it follows.
+    pub fn plan(&self, files: &[FileStat], level: u64) -> Vec<CompactionGroup> {
+        let generations = self.generations(files);
+        if generations.len() <= 1 && !generations.iter().any(|x| x.has_tombstone()) {
+            return vec![];
+        }
+
+        let mut runs: Vec<Vec<Generation>> = vec![];
+        let mut current: Vec<Generation> = vec![];
+        let mut iter = generations.into_iter().peekable();
+        while let Some(generation) = iter.next() {
+            let orphan = iter
+                .peek()
+                .map(|next| generation.level() < next.level())
+                .unwrap_or_default();
+            if orphan || current.is_empty() || run_level(&current) == generation.level() {
+                current.push(generation);
+                continue;
+            }
+
+            runs.push(std::mem::take(&mut current));
+            current.push(generation);
+        }
+        if !current.is_empty() {
+            runs.push(current);
+        }
+
+        let mut groups = vec![];
+        for run in runs.iter().filter(|x| run_level(x) == level) {
+            for chunk in run.chunks(self.min_generations) {
+                let has_tombstone = chunk.iter().any(|x| x.has_tombstone());
+                if chunk.len() < self.min_generations && !has_tombstone {
+                    continue;
+                }
+                groups.push(group_of(chunk));
+            }
+        }
+
+        self.acquire(groups)
+    }
+
+    /// plan_optimize returns the runs of adjacent `MAX_LEVEL` generations worth
+    /// merging into fewer files: at least `min_generations` of them, or fewer
+    /// with tombstones.
+    pub fn plan_optimize(&self, files: &[FileStat]) -> Vec<CompactionGroup> {
+        let generations = self.generations(files);
+        if generations.len() <= 1 && !generations.iter().any(|x| x.has_tombstone()) {
+            return vec![];
+        }
+
+        let mut groups = vec![];
+        let mut run: Vec<Generation> = vec![];
+        for generation in generations
+            .into_iter()
+            .chain(std::iter::once(Generation::end()))
+        {
+            if !run.is_empty() && run_level(&run) == generation.level() {
+                run.push(generation);
+                continue;
+            }
+
+            let has_tombstone = run.iter().any(|x| x.has_tombstone());
+            if !run.is_empty()
+                && run_level(&run) == MAX_LEVEL
+                && (run.len() >= self.min_generations || has_tombstone)
+            {
+                groups.push(group_of(&run));
+            }
+            run = vec![generation];
+        }
+
+        self.acquire(groups)
+    }
+
+    /// plan_full returns a single group of all the files, for compacting a shard
+    /// that is no longer written to. Generations at the maximum file size without
+    /// tombstones are already fully compacted and left out.
+    pub fn plan_full(&self, files: &[FileStat]) -> Vec<CompactionGroup> {
+        let generations: Vec<Generation> = self
+            .generations(files)
+            .into_iter()
+            .filter(|x| x.has_tombstone() || x.size() < self.max_file_size as u64)
+            .collect();
+
+        let has_tombstone = generations.iter().any(|x| x.has_tombstone());
+        if generations.len() <= 1 && !has_tombstone {
+            return vec![];
+        }
+
+        self.acquire(vec![group_of(&generations)])
+    }
+
+    /// release marks the files of the groups as no longer in progress, once
+    /// their compaction is done or aborted.
+    pub fn release(&self, groups: &[CompactionGroup]) {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        for path in groups.iter().flatten() {
+            in_progress.remove(path);
+        }
+    }
+
+    /// in_progress returns the number of files of planned groups not yet released.
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.lock().unwrap().len()
+    }
+
+    /// acquire marks the files of the groups as in progress, planning nothing if
+    /// one of them already is.
+    fn acquire(&self, groups: Vec<CompactionGroup>) -> Vec<CompactionGroup> {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        if groups.iter().flatten().any(|x| in_progress.contains(x)) {
+            return vec![];
+        }
+
+        for path in groups.iter().flatten() {
+            in_progress.insert(path.clone());
+        }
+        groups
+    }
+
+    /// generations groups the files by generation, ascending, skipping the files
+    /// in progress and the ones not named like TSM files.
+    fn generations(&self, files: &[FileStat]) -> Vec<Generation> {
+        let in_progress = self.in_progress.lock().unwrap();
+
+        let mut generations: Vec<Generation> = vec![];
+        for file in files.iter() {
+            if in_progress.contains(&file.path) {
+                continue;
+            }
+            let (id, sequence) = match parse_tsm_file_name(file.path.as_str()) {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::warn!("skip file {} of the compaction plan: {}", file.path, e);
+                    continue;
+                }
+            };
+
+            let file = PlannedFile {
+                path: file.path.clone(),
+                sequence,
+                size: file.size as u64,
+                has_tombstone: file.has_tombstone,
+            };
+            match generations.iter_mut().find(|x| x.id == id) {
+                Some(generation) => generation.files.push(file),
+                None => generations.push(Generation {
+                    id,
+                    files: vec![file],
+                }),
+            }
+        }
+
+        generations.sort_by_key(|x| x.id);
+        for generation in generations.iter_mut() {
+            generation.files.sort_by_key(|x| x.sequence);
+        }
+        generations
+    }
+}
+
+struct PlannedFile {
+    path: String,
+    sequence: u64,
+    size: u64,
+    has_tombstone: bool,
+}
+
+/// Generation is the files of a generation, sorted by sequence.
+struct Generation {
+    id: u64,
+    files: Vec<PlannedFile>,
+}
+
+impl Generation {
+    /// end is a sentinel generation closing the last run of a plan.
+    fn end() -> Self {
+        Self {
+            id: u64::MAX,
+            files: vec![],
+        }
+    }
+
+    fn level(&self) -> u64 {
+        self.files
+            .last()
+            .map(|x| x.sequence.min(MAX_LEVEL))
+            .unwrap_or_default()
+    }
+
+    fn size(&self) -> u64 {
+        self.files.iter().map(|x| x.size).sum()
+    }
+
+    fn has_tombstone(&self) -> bool {
+        self.files.iter().any(|x| x.has_tombstone)
+    }
+}
+
+/// run_level is the level of a run of generations, the highest of its generations.
+fn run_level(run: &[Generation]) -> u64 {
+    run.iter().map(|x| x.level()).max().unwrap_or_default()
+}
+
+fn group_of(generations: &[Generation]) -> CompactionGroup {
+    generations
+        .iter()
+        .flat_map(|x| x.files.iter().map(|x| x.path.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::compact_planner::{CompactionGroup, DefaultPlanner};
+    use crate::engine::tsm1::file_store::file_store::tsm_file_name;
+    use crate::engine::tsm1::file_store::stat::{FileStat, KeyStats};
+    use crate::engine::tsm1::file_store::{KeyRange, TimeRange};
+
+    const MB: u32 = 1024 * 1024;
+
+    fn file(generation: u64, sequence: u64, size: u32) -> FileStat {
+        FileStat::new(
+            format!("/db/rp/1/{}", tsm_file_name(generation, sequence)),
+            false,
+            size,
+            0,
+            TimeRange::unbound(),
+            KeyRange {
+                min: vec![],
+                max: vec![],
+            },
+            KeyStats::default(),
+        )
+    }
+
+    fn tombstoned(mut file: FileStat) -> FileStat {
+        file.has_tombstone = true;
+        file
+    }
+
+    fn paths(files: &[FileStat]) -> CompactionGroup {
+        files.iter().map(|x| x.path.clone()).collect()
+    }
+
+    #[test]
+    fn test_plan_level1() {
+        let files = vec![
+            file(1, 1, 2 * MB),
+            file(2, 1, 2 * MB),
+            file(3, 1, 3 * MB),
+            file(4, 1, 2 * MB),
+        ];
+
+        let planner = DefaultPlanner::new();
+        assert_eq!(planner.plan(&files, 1), vec![paths(&files)]);
+        assert!(planner.plan(&files, 2).is_empty());
+    }
+
+    #[test]
+    fn test_plan_level1_too_few() {
+        let planner = DefaultPlanner::new();
+
+        // a lone huge file is not compacted
+        let files = vec![file(1, 1, 1024 * MB)];
+        assert!(planner.plan(&files, 1).is_empty());
+
+        let files = vec![file(1, 1, 2 * MB), file(2, 1, 2 * MB), file(3, 1, 2 * MB)];
+        assert!(planner.plan(&files, 1).is_empty());
+
+        // unless tombstones need to be dropped
+        let files = vec![file(1, 1, 2 * MB), tombstoned(file(2, 1, 2 * MB))];
+        assert_eq!(planner.plan(&files, 1), vec![paths(&files)]);
+    }
+
+    #[test]
+    fn test_plan_levels_split() {
+        let files = vec![
+            file(1, 2, 8 * MB),
+            file(2, 2, 8 * MB),
+            file(3, 2, 8 * MB),
+            file(4, 2, 8 * MB),
+            file(5, 1, 2 * MB),
+            file(6, 1, 2 * MB),
+            file(7, 1, 2 * MB),
+            file(8, 1, 2 * MB),
+            file(9, 1, 2 * MB),
+        ];
+
+        let planner = DefaultPlanner::new();
+        assert_eq!(planner.plan(&files, 2), vec![paths(&files[..4])]);
+        assert_eq!(planner.plan(&files, 1), vec![paths(&files[4..8])]);
+        assert!(planner.plan(&files, 3).is_empty());
+    }
+
+    #[test]
+    fn test_plan_orphan_joins_previous_run() {
+        // generation 3 was left behind at level 1 by a level 2 compaction
+        let files = vec![
+            file(1, 2, 8 * MB),
+            file(2, 2, 8 * MB),
+            file(3, 1, 2 * MB),
+            file(4, 2, 8 * MB),
+        ];
+
+        let planner = DefaultPlanner::new();
+        assert!(planner.plan(&files, 1).is_empty());
+        assert_eq!(planner.plan(&files, 2), vec![paths(&files)]);
+    }
+
+    #[test]
+    fn test_plan_in_progress() {
+        let files: Vec<FileStat> = (1..=8).map(|x| file(x, 1, 2 * MB)).collect();
+
+        let planner = DefaultPlanner::new();
+        let groups = planner.plan(&files, 1);
+        assert_eq!(groups, vec![paths(&files[..4]), paths(&files[4..])]);
+        assert_eq!(planner.in_progress(), 8);
+
+        // the files are not planned again until released
+        assert!(planner.plan(&files, 1).is_empty());
+        assert!(planner.plan_full(&files).is_empty());
+
+        planner.release(&groups[..1]);
+        assert_eq!(planner.plan(&files, 1), vec![paths(&files[..4])]);
+
+        planner.release(&groups);
+        assert_eq!(planner.in_progress(), 0);
+    }
+
+    #[test]
+    fn test_plan_optimize() {
+        let planner = DefaultPlanner::new();
+
+        let files: Vec<FileStat> = (1..=4).map(|x| file(x, 4, 100 * MB)).collect();
+        assert_eq!(planner.plan_optimize(&files), vec![paths(&files)]);
+
+        let planner = DefaultPlanner::new();
+        let files = vec![file(1, 5, 100 * MB), file(2, 4, 100 * MB)];
+        assert!(planner.plan_optimize(&files).is_empty());
+
+        let files = vec![
+            file(1, 5, 100 * MB),
+            tombstoned(file(2, 4, 100 * MB)),
+            file(3, 1, 2 * MB),
+        ];
+        assert_eq!(planner.plan_optimize(&files), vec![paths(&files[..2])]);
+    }
+
+    #[test]
+    fn test_plan_full() {
+        let planner = DefaultPlanner::new().with_max_file_size(64 * MB);
+
+        let files = vec![file(1, 4, 64 * MB), file(2, 2, 8 * MB), file(3, 1, 2 * MB)];
+        assert_eq!(planner.plan_full(&files), vec![paths(&files[1..])]);
+
+        let planner = DefaultPlanner::new().with_max_file_size(64 * MB);
+        let files = vec![file(1, 4, 64 * MB), file(1, 5, 64 * MB)];
+        assert!(planner.plan_full(&files).is_empty());
+    }
+}
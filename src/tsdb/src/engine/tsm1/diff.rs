@@ -0,0 +1,271 @@
+use std::cmp::Ordering;
+
+use common_base::iterator::AsyncIterator;
+
+use crate::engine::tsm1::file_store::file_store::new_values;
+use crate::engine::tsm1::file_store::index::IndexEntries;
+use crate::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+use crate::engine::tsm1::value::{FieldType, TimeValue, Values};
+
+/// Diff is a difference between two TSM files reported by `diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff {
+    /// OnlyInA is a key of the first file only.
+    OnlyInA(Vec<u8>),
+    /// OnlyInB is a key of the second file only.
+    OnlyInB(Vec<u8>),
+    /// ValuesDiffer is a key of both files whose values differ, `unix_nano` is
+    /// the first timestamp at which they do: a value present on one side only, or
+    /// present on both with another value or type.
+    ValuesDiffer { key: Vec<u8>, unix_nano: i64 },
+}
+
+/// diff compares the TSM files `a` and `b` key by key, in key order. Both key
+/// indexes are walked together and the values of a key found in both are decoded
+/// a block at a time, so neither file is loaded fully. Values are compared as
+/// stored: tombstones are not applied and block boundaries do not matter.
+pub async fn diff(a: &dyn TSMReader, b: &dyn TSMReader) -> anyhow::Result<Vec<Diff>> {
+    let mut diffs = vec![];
+
+    let mut a_keys = a.key_iterator().await?;
+    let mut b_keys = b.key_iterator().await?;
+    let mut a_key = a_keys.try_next().await?;
+    let mut b_key = b_keys.try_next().await?;
+    loop {
+        let ordering = match (&a_key, &b_key) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(x), Some(y)) => x.cmp(y),
+        };
+
+        match ordering {
+            Ordering::Less => {
+                diffs.push(Diff::OnlyInA(a_key.take().unwrap()));
+                a_key = a_keys.try_next().await?;
+            }
+            Ordering::Greater => {
+                diffs.push(Diff::OnlyInB(b_key.take().unwrap()));
+                b_key = b_keys.try_next().await?;
+            }
+            Ordering::Equal => {
+                let key = a_key.take().unwrap();
+                if let Some(unix_nano) = first_difference(a, b, key.as_slice()).await? {
+                    diffs.push(Diff::ValuesDiffer { key, unix_nano });
+                }
+                a_key = a_keys.try_next().await?;
+                b_key = b_keys.try_next().await?;
+            }
+        }
+    }
+
+    Ok(diffs)
+}
+
+/// first_difference returns the first timestamp at which the values of key differ
+/// between `a` and `b`, None if they are the same.
+async fn first_difference(
+    a: &dyn TSMReader,
+    b: &dyn TSMReader,
+    key: &[u8],
+) -> anyhow::Result<Option<i64>> {
+    let mut a = BlockCursor::new(a, key).await?;
+    let mut b = BlockCursor::new(b, key).await?;
+    loop {
+        match (a.fill().await?, b.fill().await?) {
+            (false, false) => return Ok(None),
+            (true, false) => return Ok(Some(a.time())),
+            (false, true) => return Ok(Some(b.time())),
+            (true, true) => match compare_values(&a.values, a.pos, &b.values, b.pos) {
+                Ok(n) => {
+                    a.pos += n;
+                    b.pos += n;
+                }
+                Err(unix_nano) => return Ok(Some(unix_nano)),
+            },
+        }
+    }
+}
+
+/// BlockCursor walks the values of a key, decoding its blocks one at a time.
+struct BlockCursor<'a> {
+    reader: &'a dyn TSMReader,
+    entries: IndexEntries,
+    /// i is the next block to decode.
+    i: usize,
+    values: Values,
+    /// pos is the next value of `values`.
+    pos: usize,
+}
+
+impl<'a> BlockCursor<'a> {
+    async fn new(reader: &'a dyn TSMReader, key: &[u8]) -> anyhow::Result<BlockCursor<'a>> {
+        let mut entries = IndexEntries::default();
+        reader.read_entries(key, &mut entries).await?;
+        let values = new_values(entries.typ)?;
+        Ok(Self {
+            reader,
+            entries,
+            i: 0,
+            values,
+            pos: 0,
+        })
+    }
+
+    /// fill decodes the next blocks until a value is left, returning false once
+    /// all blocks are consumed.
+    async fn fill(&mut self) -> anyhow::Result<bool> {
+        while self.pos >= self.values.len() {
+            if self.i >= self.entries.len() {
+                return Ok(false);
+            }
+
+            let entry = self.entries.entry(self.i);
+            self.i += 1;
+            self.values.truncate(0);
+            self.pos = 0;
+            self.reader.read_block_at(&entry, &mut self.values).await?;
+        }
+        Ok(true)
+    }
+
+    /// time returns the timestamp of the next value.
+    fn time(&self) -> i64 {
+        time_at(&self.values, self.pos)
+    }
+}
+
+/// compare_values compares `a` from `a_pos` with `b` from `b_pos`, returning the
+/// number of equal values before either side ends, or the timestamp of the first
+/// difference.
+fn compare_values(a: &Values, a_pos: usize, b: &Values, b_pos: usize) -> Result<usize, i64> {
+    match (a, b) {
+        (Values::Float(a), Values::Float(b)) => compare(&a[a_pos..], &b[b_pos..]),
+        (Values::Integer(a), Values::Integer(b)) => compare(&a[a_pos..], &b[b_pos..]),
+        (Values::Bool(a), Values::Bool(b)) => compare(&a[a_pos..], &b[b_pos..]),
+        (Values::String(a), Values::String(b)) => compare(&a[a_pos..], &b[b_pos..]),
+        (Values::Unsigned(a), Values::Unsigned(b)) => compare(&a[a_pos..], &b[b_pos..]),
+        // the key has another type in each file
+        _ => Err(time_at(a, a_pos).min(time_at(b, b_pos))),
+    }
+}
+
+/// time_at returns the timestamp of the value at pos.
+fn time_at(values: &Values, pos: usize) -> i64 {
+    match values {
+        Values::Float(values) => values[pos].unix_nano,
+        Values::Integer(values) => values[pos].unix_nano,
+        Values::Bool(values) => values[pos].unix_nano,
+        Values::String(values) => values[pos].unix_nano,
+        Values::Unsigned(values) => values[pos].unix_nano,
+    }
+}
+
+fn compare<T: FieldType>(a: &[TimeValue<T>], b: &[TimeValue<T>]) -> Result<usize, i64> {
+    for (x, y) in a.iter().zip(b.iter()) {
+        if x.unix_nano != y.unix_nano {
+            return Err(x.unix_nano.min(y.unix_nano));
+        }
+        if x.value != y.value {
+            return Err(x.unix_nano);
+        }
+    }
+    Ok(a.len().min(b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::diff::{diff, Diff};
+    use crate::engine::tsm1::file_store::reader::tsm_reader::new_default_tsm_reader;
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    fn floats(range: std::ops::Range<i64>) -> Values {
+        Values::Float(range.map(|t| TimeValue::new(t, t as f64)).collect())
+    }
+
+    /// write_tsm writes a TSM file with a block per `Values` of each key.
+    async fn write_tsm(path: &Path, series: Vec<(&[u8], Vec<Values>)>) {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for (key, blocks) in series {
+            for values in blocks {
+                w.write(key, values).await.unwrap();
+            }
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let a_path = dir.as_ref().join("a.tsm");
+        let b_path = dir.as_ref().join("b.tsm");
+
+        write_tsm(
+            &a_path,
+            vec![
+                (b"cpu#!~#a", vec![floats(0..20)]),
+                (b"cpu#!~#b", vec![floats(0..10)]),
+                (b"cpu#!~#c", vec![floats(0..10)]),
+                (b"cpu#!~#e", vec![floats(0..10)]),
+                (b"cpu#!~#f", vec![floats(0..10)]),
+            ],
+        )
+        .await;
+
+        let mut changed = floats(0..10);
+        if let Values::Float(values) = &mut changed {
+            values[5].value = -1.0;
+        }
+        write_tsm(
+            &b_path,
+            vec![
+                // the same values split into other blocks
+                (b"cpu#!~#a", vec![floats(0..7), floats(7..20)]),
+                (b"cpu#!~#b", vec![changed]),
+                (b"cpu#!~#d", vec![floats(0..10)]),
+                (b"cpu#!~#e", vec![floats(0..10), floats(100..101)]),
+                (
+                    b"cpu#!~#f",
+                    vec![Values::Integer(
+                        (3..10).map(|t| TimeValue::new(t, t)).collect(),
+                    )],
+                ),
+            ],
+        )
+        .await;
+
+        let a = new_default_tsm_reader(StorageOperator::root(a_path.to_str().unwrap()).unwrap())
+            .await
+            .unwrap();
+        let b = new_default_tsm_reader(StorageOperator::root(b_path.to_str().unwrap()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(diff(&a, &a).await.unwrap(), vec![]);
+        assert_eq!(
+            diff(&a, &b).await.unwrap(),
+            vec![
+                Diff::ValuesDiffer {
+                    key: b"cpu#!~#b".to_vec(),
+                    unix_nano: 5
+                },
+                Diff::OnlyInA(b"cpu#!~#c".to_vec()),
+                Diff::OnlyInB(b"cpu#!~#d".to_vec()),
+                Diff::ValuesDiffer {
+                    key: b"cpu#!~#e".to_vec(),
+                    unix_nano: 100
+                },
+                Diff::ValuesDiffer {
+                    key: b"cpu#!~#f".to_vec(),
+                    unix_nano: 0
+                },
+            ]
+        );
+    }
+}
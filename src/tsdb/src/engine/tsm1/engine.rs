@@ -0,0 +1,2669 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common_base::clock::{Clock, SystemClock};
+use common_base::iterator::AsyncIterator;
+use common_base::point::{check_key_length, Point, DEFAULT_MAX_SERIES_KEY_LENGTH, MAX_KEY_LENGTH};
+use influxdb_storage::{path_join, StorageOperator};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tracing::field::Empty;
+
+use crate::engine::tsm1::aggregate::Aggregate;
+use crate::engine::tsm1::cache::{Cache, CacheError, CacheView};
+use crate::engine::tsm1::compact::{split_values, Compactor, DEFAULT_MAX_POINTS_PER_BLOCK};
+use crate::engine::tsm1::file_store::file_store::{parse_tsm_file_name, FileStore, FileStoreView};
+use crate::engine::tsm1::file_store::TimeRange;
+use crate::engine::tsm1::negative_cache::{
+    NegativeCache, NegativeCacheStats, DEFAULT_NEGATIVE_CACHE_SIZE, DEFAULT_NEGATIVE_CACHE_TTL,
+};
+use crate::engine::tsm1::read_semaphore::ReadSemaphore;
+use crate::engine::tsm1::schema::{SchemaMode, SchemaRegistry, SchemaViolation};
+use crate::engine::tsm1::series_hook::{
+    NewSeries, SeriesCreationHook, SeriesHookDispatcher, SeriesHookStats,
+    DEFAULT_SERIES_HOOK_BUDGET, DEFAULT_SERIES_HOOK_QUEUE_SIZE,
+};
+use crate::engine::tsm1::shard_lock::{AdvisoryLock, Fence, ShardLockOptions};
+use crate::engine::tsm1::value::{points_to_values_with_max_key_length, Array, SumMode, Values};
+use crate::engine::tsm1::wal::{
+    Progress, Wal, WalEntry, WalOptions, WalReplayIterator, WriteEntry,
+};
+use crate::index::shard_index::{parse_series_key, split_tsm_key, ShardIndex, SHARD_INDEX_FILE};
+
+/// DEFAULT_CACHE_MAX_MEMORY_SIZE is the default maximum size of the cache of a shard.
+pub const DEFAULT_CACHE_MAX_MEMORY_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// DEFAULT_CACHE_SNAPSHOT_MEMORY_SIZE is the default size at which the cache of a
+/// shard is flushed to TSM files.
+pub const DEFAULT_CACHE_SNAPSHOT_MEMORY_SIZE: u64 = 25 * 1024 * 1024;
+
+/// DEFAULT_CACHE_SNAPSHOT_WRITE_COLD_DURATION is the default time without writes
+/// after which the cache of a shard is flushed to TSM files.
+pub const DEFAULT_CACHE_SNAPSHOT_WRITE_COLD_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// WAL_DIR is the directory of the write ahead log within the shard directory.
+pub const WAL_DIR: &str = "wal";
+
+/// ShardOptions configures the engine of a shard.
+#[derive(Clone)]
+pub struct ShardOptions {
+    /// cache_max_memory_size is the size the cache may grow to before writes are
+    /// rejected, 0 for unlimited.
+    pub cache_max_memory_size: u64,
+    /// cache_snapshot_memory_size is the size at which the cache is flushed to TSM
+    /// files by the snapshot flusher, 0 to disable.
+    pub cache_snapshot_memory_size: u64,
+    /// cache_snapshot_write_cold_duration is the time without writes after which
+    /// the cache is flushed by the snapshot flusher whatever its size, zero to
+    /// disable.
+    pub cache_snapshot_write_cold_duration: Duration,
+    /// wal configures the write ahead log.
+    pub wal: WalOptions,
+    /// series_creation_hook is notified of the series created by each flush, see
+    /// `SeriesCreationHook`.
+    pub series_creation_hook: Option<Arc<dyn SeriesCreationHook>>,
+    /// series_hook_queue_size is the number of batches buffered for the hook.
+    pub series_hook_queue_size: usize,
+    /// series_hook_budget is the longest a flush waits for room in the hook
+    /// queue, the batch is dropped past it.
+    pub series_hook_budget: Duration,
+    /// max_replay_memory is the size the cache may grow to while the WAL is
+    /// replayed, the values replayed so far are flushed to a TSM file beyond it.
+    /// 0 uses `cache_max_memory_size`.
+    pub max_replay_memory: u64,
+    /// replay_progress is notified of the progress of the WAL replay.
+    pub replay_progress: Option<Arc<dyn Progress>>,
+    /// open_status is updated as the shard opens, e.g. to be watched by another
+    /// task.
+    pub open_status: Option<Arc<OpenStatus>>,
+    /// lock excludes other owners from the shard directory while it is open,
+    /// see `AdvisoryLock`. None leaves the directory unlocked.
+    pub lock: Option<ShardLockOptions>,
+    /// compaction_temp_dir is the local directory snapshots and compactions are
+    /// written to before they are moved into the shard, see
+    /// `Compactor::set_temp_dir`. None writes them in the shard directory.
+    pub compaction_temp_dir: Option<String>,
+    /// negative_cache_ttl is how long a key found missing by `Engine::read` is
+    /// remembered as missing, see `NegativeCache`. Zero disables the cache.
+    pub negative_cache_ttl: Duration,
+    /// negative_cache_size is the number of missing keys remembered.
+    pub negative_cache_size: usize,
+    /// read_semaphore caps the readers the shard opens at once, it can be shared
+    /// by several shards. None leaves the reads unlimited.
+    pub read_semaphore: Option<ReadSemaphore>,
+    /// max_series_key_length is the longest series key a write accepts, see
+    /// `KeyTooLong`. It can be raised up to `MAX_KEY_LENGTH`, the longest key a
+    /// TSM file holds.
+    pub max_series_key_length: usize,
+    /// bloom_fp_rate adds a bloom filter of the keys with this false positive
+    /// rate to the TSM files written, so reads skip most files lacking a key
+    /// without searching their index. None writes files without one.
+    pub bloom_fp_rate: Option<f64>,
+    /// schema_mode is whether writes may create measurements, fields and tag
+    /// keys, or must stick to those declared in `schema`.
+    pub schema_mode: SchemaMode,
+    /// schema holds the measurements declared for `SchemaMode::Strict`, it can
+    /// be shared by several shards.
+    pub schema: Arc<SchemaRegistry>,
+    /// clock is the time of the policy decisions of the shard, e.g. the age of
+    /// the cache or the TTL of the negative cache. Tests substitute a
+    /// `SimulatedClock`.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ShardOptions {
+    fn default() -> Self {
+        Self {
+            cache_max_memory_size: DEFAULT_CACHE_MAX_MEMORY_SIZE,
+            cache_snapshot_memory_size: DEFAULT_CACHE_SNAPSHOT_MEMORY_SIZE,
+            cache_snapshot_write_cold_duration: DEFAULT_CACHE_SNAPSHOT_WRITE_COLD_DURATION,
+            wal: WalOptions::default(),
+            series_creation_hook: None,
+            series_hook_queue_size: DEFAULT_SERIES_HOOK_QUEUE_SIZE,
+            series_hook_budget: DEFAULT_SERIES_HOOK_BUDGET,
+            max_replay_memory: 0,
+            replay_progress: None,
+            open_status: None,
+            lock: None,
+            compaction_temp_dir: None,
+            negative_cache_ttl: DEFAULT_NEGATIVE_CACHE_TTL,
+            negative_cache_size: DEFAULT_NEGATIVE_CACHE_SIZE,
+            read_semaphore: None,
+            max_series_key_length: DEFAULT_MAX_SERIES_KEY_LENGTH,
+            bloom_fp_rate: None,
+            schema_mode: SchemaMode::Open,
+            schema: Arc::new(SchemaRegistry::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// OpenState is the state of a shard being opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenState {
+    /// Opening loads the TSM files and the index.
+    Opening,
+    /// Replaying replays the WAL into the cache.
+    Replaying {
+        percent: u8,
+    },
+    /// Warming brings the index up to date with the TSM files.
+    Warming,
+    Ready,
+}
+
+/// OpenStatus holds the `OpenState` of a shard, it can be read while the shard
+/// opens.
+#[derive(Debug)]
+pub struct OpenStatus {
+    state: std::sync::Mutex<OpenState>,
+}
+
+impl Default for OpenStatus {
+    fn default() -> Self {
+        Self {
+            state: std::sync::Mutex::new(OpenState::Opening),
+        }
+    }
+}
+
+impl OpenStatus {
+    pub fn get(&self) -> OpenState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set(&self, state: OpenState) {
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+/// ReplayReport counts the entries of the WAL applied by a replay, in the order
+/// they were logged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// writes is the number of write entries replayed, including the ones the
+    /// cache skipped, e.g. because of a field type conflict.
+    pub writes: usize,
+    /// deletes is the number of delete range entries replayed.
+    pub deletes: usize,
+}
+
+impl ReplayReport {
+    /// entries returns the number of entries replayed.
+    pub fn entries(&self) -> usize {
+        self.writes + self.deletes
+    }
+}
+
+/// Engine is the storage engine of a shard: its cache, write ahead log, TSM files
+/// and indexes.
+pub struct Engine {
+    op: StorageOperator,
+
+    cache: Cache,
+    /// wal logs the writes and deletes applied to the cache. The lock is held
+    /// across both, so a closed segment only holds entries already in the cache.
+    wal: Mutex<Wal>,
+    cache_snapshot_memory_size: u64,
+    cache_snapshot_write_cold_duration: Duration,
+    /// last_write_nanos is the time of the last `write_points`, per `clock`.
+    last_write_nanos: AtomicI64,
+    clock: Arc<dyn Clock>,
+    /// snapshot_notify wakes the snapshot flusher.
+    snapshot_notify: Arc<Notify>,
+    /// snapshots counts the snapshots written, see `subscribe_snapshots`.
+    snapshots: watch::Sender<u64>,
+    closed: AtomicBool,
+    max_series_key_length: usize,
+    schema_mode: SchemaMode,
+    schema: Arc<SchemaRegistry>,
+    max_replay_memory: u64,
+    replay_progress: Option<Arc<dyn Progress>>,
+    /// replay_report is the replay of the WAL when the shard was opened.
+    replay_report: ReplayReport,
+    open_status: Arc<OpenStatus>,
+
+    compactor: Compactor,
+    file_store: FileStore,
+    index: RwLock<ShardIndex>,
+    /// negative_cache remembers the keys reads found missing. Writes invalidate
+    /// the keys they write, and new files the whole cache.
+    negative_cache: NegativeCache,
+
+    series_hook: Option<SeriesHookDispatcher>,
+
+    /// lock is held until the engine is closed or dropped.
+    lock: Mutex<Option<AdvisoryLock>>,
+    /// fence is checked before the file store and the index commit, if the lock
+    /// can be taken over.
+    fence: Option<Fence>,
+}
+
+impl Engine {
+    /// open opens the shard in the directory `op`. TSM files which were written
+    /// but not yet reflected by the persisted index, e.g. because of a crash in
+    /// the middle of a flush, are indexed before returning.
+    pub async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        Self::open_with_options(op, ShardOptions::default()).await
+    }
+
+    /// open_with_options opens the shard in the directory `op` with `options`.
+    pub async fn open_with_options(
+        op: StorageOperator,
+        options: ShardOptions,
+    ) -> anyhow::Result<Self> {
+        if options.max_series_key_length > MAX_KEY_LENGTH {
+            return Err(anyhow!(
+                "max series key length {} beyond the max TSM key length {}",
+                options.max_series_key_length,
+                MAX_KEY_LENGTH
+            ));
+        }
+
+        let open_status = options.open_status.clone().unwrap_or_default();
+        open_status.set(OpenState::Opening);
+
+        let lock = match &options.lock {
+            Some(lock_options) => Some(AdvisoryLock::acquire(&op, lock_options).await?),
+            None => None,
+        };
+        let fence = lock.as_ref().and_then(|x| x.fence());
+
+        // all readers of the files, the index and the WAL are opened through op
+        let op = match &options.read_semaphore {
+            Some(read_semaphore) => read_semaphore.apply(&op),
+            None => op,
+        };
+
+        let file_store = FileStore::open(op.clone()).await?.with_fence(fence.clone());
+        let op = op.to_op(file_store.path());
+
+        let index_op = op.to_op(path_join(op.path(), SHARD_INDEX_FILE).as_str());
+        let (index, corrupt) = match ShardIndex::open(index_op.clone()).await {
+            Ok(index) => (index.with_fence(fence.clone()), false),
+            Err(e) => {
+                tracing::warn!(
+                    "shard index {} unreadable, rebuilding: {}",
+                    index_op.path(),
+                    e
+                );
+                let index = ShardIndex::create(index_op).await?;
+                (index.with_fence(fence.clone()), true)
+            }
+        };
+
+        // The segments to replay are listed before the WAL is opened for writes,
+        // which starts a new segment: a torn entry is only expected at the end of
+        // the last segment replayed.
+        let wal_op = op.to_op(path_join(op.path(), WAL_DIR).as_str());
+        let entries = Wal::replay(wal_op.clone()).await?;
+        let wal = Wal::open_with_clock(wal_op, options.wal.clone(), options.clock.clone()).await?;
+
+        let mut compactor = Compactor::new().with_bloom_filter(options.bloom_fp_rate);
+        if let Some(temp_dir) = &options.compaction_temp_dir {
+            compactor.set_temp_dir(temp_dir.as_str());
+        }
+
+        let mut engine = Self {
+            op,
+            cache: Cache::new(options.cache_max_memory_size),
+            wal: Mutex::new(wal),
+            cache_snapshot_memory_size: options.cache_snapshot_memory_size,
+            cache_snapshot_write_cold_duration: options.cache_snapshot_write_cold_duration,
+            last_write_nanos: AtomicI64::new(options.clock.now_nanos()),
+            clock: options.clock.clone(),
+            snapshot_notify: Arc::new(Notify::new()),
+            snapshots: watch::channel(0).0,
+            closed: AtomicBool::new(false),
+            max_series_key_length: options.max_series_key_length,
+            schema_mode: options.schema_mode,
+            schema: options.schema.clone(),
+            max_replay_memory: options.max_replay_memory,
+            replay_progress: options.replay_progress,
+            replay_report: ReplayReport::default(),
+            open_status,
+            compactor,
+            file_store,
+            index: RwLock::new(index),
+            negative_cache: NegativeCache::new(
+                options.negative_cache_ttl,
+                options.negative_cache_size,
+            )
+            .with_clock(options.clock.clone()),
+            series_hook: options.series_creation_hook.map(|hook| {
+                SeriesHookDispatcher::new(
+                    hook,
+                    options.series_hook_queue_size,
+                    options.series_hook_budget,
+                )
+            }),
+            lock: Mutex::new(lock),
+            fence,
+        };
+
+        engine.open_status.set(OpenState::Replaying { percent: 0 });
+        engine.replay_report = engine.replay_wal(entries).await?;
+
+        engine.open_status.set(OpenState::Warming);
+        if corrupt {
+            engine.rebuild_indexes().await?;
+        } else {
+            engine.recover_index().await?;
+        }
+
+        engine.open_status.set(OpenState::Ready);
+        Ok(engine)
+    }
+
+    /// replay_report returns the entries of the WAL replayed when the shard was
+    /// opened.
+    pub fn replay_report(&self) -> ReplayReport {
+        self.replay_report
+    }
+
+    /// open_status returns the state of the shard, `Ready` once opened. See
+    /// `ShardOptions::open_status` to follow it while the shard opens.
+    pub fn open_status(&self) -> OpenState {
+        self.open_status.get()
+    }
+
+    pub fn path(&self) -> &str {
+        self.op.path()
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn file_store(&self) -> &FileStore {
+        &self.file_store
+    }
+
+    pub fn index(&self) -> &RwLock<ShardIndex> {
+        &self.index
+    }
+
+    /// series_hook_stats returns the counters of the series creation hook, if set.
+    pub fn series_hook_stats(&self) -> Option<&SeriesHookStats> {
+        self.series_hook.as_ref().map(|x| x.stats())
+    }
+
+    /// negative_cache_stats returns the counters of the cache of missing keys.
+    pub fn negative_cache_stats(&self) -> &NegativeCacheStats {
+        self.negative_cache.stats()
+    }
+
+    /// invalidate_negative_cache forgets the keys found missing, it must be
+    /// called after files are added to the file store outside of the engine.
+    pub fn invalidate_negative_cache(&self) {
+        self.negative_cache.clear();
+    }
+
+    /// write writes the values into the cache only, they are not logged to the WAL
+    /// and are lost if the process stops before the next `write_snapshot`. See
+    /// `write_points`.
+    pub fn write(&self, values: BTreeMap<Vec<u8>, Values>) -> anyhow::Result<()> {
+        self.check_keys(&values)?;
+        let keys: Vec<Vec<u8>> = values.keys().cloned().collect();
+        let r = self.cache.write_multi(values);
+        self.negative_cache
+            .invalidate(keys.iter().map(|x| x.as_slice()));
+        r
+    }
+
+    /// record_field_order records the order of the fields of the points as they
+    /// were written, see `field_keys_ordered`. It is persisted with the index.
+    pub async fn record_field_order(&self, points: &[Point]) {
+        let mut index = self.index.write().await;
+        for point in points {
+            let fields: Vec<&[u8]> = point.fields.iter().map(|x| x.key.as_slice()).collect();
+            index.record_field_order(point.name.as_slice(), fields.as_slice());
+        }
+    }
+
+    /// field_keys_ordered returns the fields of a measurement in the order they
+    /// were first written, e.g. to assemble multi-field lines as they were
+    /// ingested. Fields of shards which did not record the order are in lexical
+    /// order.
+    pub async fn field_keys_ordered(&self, measurement: &[u8]) -> Vec<Vec<u8>> {
+        self.index.read().await.field_keys_ordered(measurement)
+    }
+
+    /// map_points fans the fields of points out into the values of their TSM keys,
+    /// as written by `write_points`. Points with a series key longer than
+    /// `ShardOptions::max_series_key_length` are rejected with a `KeyTooLong`, see
+    /// `points_to_values_with_max_key_length`. In `SchemaMode::Strict`, points
+    /// violating the declared schema are rejected with their `SchemaViolation`,
+    /// see `check_schema` to sort them out beforehand.
+    pub fn map_points(&self, points: &[Point]) -> anyhow::Result<BTreeMap<Vec<u8>, Values>> {
+        if let Some(violation) = self.check_schema(points).into_iter().find_map(|x| x.err()) {
+            return Err(violation.into());
+        }
+        points_to_values_with_max_key_length(points, self.max_series_key_length)
+    }
+
+    /// check_schema returns the outcome of each point against the schema declared
+    /// in `ShardOptions::schema`. All points pass in `SchemaMode::Open`.
+    pub fn check_schema(&self, points: &[Point]) -> Vec<Result<(), SchemaViolation>> {
+        match self.schema_mode {
+            SchemaMode::Open => points.iter().map(|_| Ok(())).collect(),
+            SchemaMode::Strict => points.iter().map(|x| self.schema.check(x)).collect(),
+        }
+    }
+
+    /// check_keys returns a `KeyTooLong` if the series key of any of the TSM keys
+    /// is longer than `max_series_key_length`, before anything is written.
+    fn check_keys(&self, values: &BTreeMap<Vec<u8>, Values>) -> anyhow::Result<()> {
+        for key in values.keys() {
+            check_key_length(key.as_slice(), MAX_KEY_LENGTH)?;
+            let (series_key, _) = split_tsm_key(key.as_slice());
+            check_key_length(series_key, self.max_series_key_length)?;
+        }
+        Ok(())
+    }
+
+    /// write_points maps the points to the values of their TSM keys, see
+    /// `map_points`, and writes them with `write_values`. Nothing is written if a
+    /// point is rejected.
+    pub async fn write_points(&self, points: &[Point]) -> anyhow::Result<()> {
+        let values = self.map_points(points)?;
+        self.write_values(values).await
+    }
+
+    /// write_values logs the values to the WAL and writes them into the cache, they
+    /// are persisted into TSM files by the next `write_snapshot`.
+    ///
+    /// Nothing is logged nor written if a key is too long, see `check_keys`, or if
+    /// the cache is full: the room of the values is reserved in the cache before
+    /// they are logged, and they are written into the cache once logged. Values
+    /// rejected by the cache for another reason, e.g. a field type conflict, are
+    /// logged all the same and skipped again on replay.
+    pub async fn write_values(&self, values: BTreeMap<Vec<u8>, Values>) -> anyhow::Result<()> {
+        self.check_keys(&values)?;
+        let entries: Vec<WalEntry> = values
+            .iter()
+            .map(|(key, values)| WalEntry::Write(WriteEntry::new(key.clone(), values.clone())))
+            .collect();
+
+        let mut wal = self.wal.lock().await;
+        let reservation = self.cache.reserve_multi(values)?;
+        wal.write_batch(entries.as_slice()).await?;
+        let r = reservation.write();
+        self.last_write_nanos
+            .store(self.clock.now_nanos(), Ordering::Relaxed);
+        // the keys are purged even if the write failed, values may have been
+        // written before the error.
+        self.negative_cache
+            .invalidate(entries.iter().filter_map(|x| match x {
+                WalEntry::Write(entry) => Some(entry.key.as_slice()),
+                _ => None,
+            }));
+        drop(wal);
+
+        if self.should_snapshot() {
+            self.snapshot_notify.notify_one();
+        }
+        r
+    }
+
+    /// delete_series_range logs the delete of the values of keys within [min, max]
+    /// to the WAL, then removes them from the cache and the TSM files.
+    ///
+    /// The delete is logged first so that a replay after a crash re-applies it
+    /// after the writes it supersedes, whatever the step the crash interrupted.
+    pub async fn delete_series_range(
+        &self,
+        keys: &[&[u8]],
+        min: i64,
+        max: i64,
+    ) -> anyhow::Result<()> {
+        let mut wal = self.wal.lock().await;
+        wal.delete_range(keys.iter().map(|x| x.to_vec()).collect(), min, max)
+            .await?;
+        self.cache.delete_range(keys, min, max);
+        self.file_store.delete_range(keys, min, max).await?;
+        Ok(())
+    }
+
+    /// write_snapshot flushes the content of the cache into new TSM files and
+    /// removes the WAL segments it covers. On failure, the values stay in the cache
+    /// and are retried by the next call.
+    pub async fn write_snapshot(&self) -> anyhow::Result<Vec<String>> {
+        if self.cache.size() == 0 {
+            return Ok(vec![]);
+        }
+
+        // Entries logged from here on go to a new segment, the closed ones only
+        // hold values which are in the snapshot. The snapshot is taken under the
+        // lock too, no write has logged values not yet in the cache.
+        let (segments, snapshot) = {
+            let mut wal = self.wal.lock().await;
+            wal.close_segment().await?;
+            (wal.closed_segments(), self.cache.snapshot()?)
+        };
+
+        let r = self.flush(&snapshot).await;
+        self.cache.clear_snapshot(r.is_ok());
+        let paths = r?;
+
+        self.wal.lock().await.remove(segments.as_slice()).await?;
+        self.snapshots.send_modify(|n| *n += 1);
+        Ok(paths)
+    }
+
+    /// subscribe_snapshots returns a receiver of the number of snapshots written
+    /// since the engine was opened, e.g. to wait for the snapshot flusher.
+    pub fn subscribe_snapshots(&self) -> watch::Receiver<u64> {
+        self.snapshots.subscribe()
+    }
+
+    /// should_snapshot returns true if the cache grew beyond its snapshot size, or
+    /// holds values and was not written to for `cache_snapshot_write_cold_duration`.
+    pub fn should_snapshot(&self) -> bool {
+        let size = self.cache.size();
+        if size == 0 {
+            return false;
+        }
+        if self.cache_snapshot_memory_size > 0 && size >= self.cache_snapshot_memory_size {
+            return true;
+        }
+
+        let cold = self.cache_snapshot_write_cold_duration;
+        let idle = self.clock.now_nanos() - self.last_write_nanos.load(Ordering::Relaxed);
+        !cold.is_zero() && idle >= cold.as_nanos() as i64
+    }
+
+    /// spawn_snapshot_flusher starts a task writing a snapshot whenever
+    /// `should_snapshot`, checked after each `write_points` and every `interval`
+    /// of the shard clock. The task ends once the engine is closed or dropped.
+    pub fn spawn_snapshot_flusher(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let engine = Arc::downgrade(self);
+        let notify = self.snapshot_notify.clone();
+        let clock = self.clock.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = notify.notified() => {}
+                    _ = clock.sleep(interval) => {}
+                }
+
+                let engine = match engine.upgrade() {
+                    Some(engine) => engine,
+                    None => return,
+                };
+                if engine.closed.load(Ordering::Acquire) {
+                    return;
+                }
+                if engine.should_snapshot() {
+                    if let Err(e) = engine.write_snapshot().await {
+                        tracing::warn!("snapshot of shard {} failed: {}", engine.path(), e);
+                    }
+                }
+            }
+        })
+    }
+
+    /// close flushes the cache into TSM files, syncs the WAL and releases the
+    /// lock of the shard directory. The snapshot flusher stops.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        self.closed.store(true, Ordering::Release);
+        self.snapshot_notify.notify_one();
+
+        self.write_snapshot().await?;
+        self.wal.lock().await.sync().await?;
+
+        self.release_lock().await
+    }
+
+    /// close_fast syncs the WAL and releases the lock of the shard directory
+    /// without flushing the cache, its values are replayed from the WAL when the
+    /// shard is opened again. The snapshot flusher stops.
+    pub async fn close_fast(&self) -> anyhow::Result<()> {
+        self.closed.store(true, Ordering::Release);
+        self.snapshot_notify.notify_one();
+
+        self.wal.lock().await.sync().await?;
+
+        self.release_lock().await
+    }
+
+    async fn release_lock(&self) -> anyhow::Result<()> {
+        if let Some(lock) = self.lock.lock().await.take() {
+            lock.release().await?;
+        }
+        Ok(())
+    }
+
+    /// snapshot returns a consistent view of the shard for a query: the cache
+    /// and the TSM files at the time of the call. Values flushed or compacted
+    /// afterwards are not visible through the snapshot, and the files it reads
+    /// are kept until it is dropped.
+    pub async fn snapshot(&self) -> EngineSnapshot {
+        // The cache is captured first: values flushed in between are then seen
+        // both in the cache and in a file, rather than in neither.
+        let cache = self.cache.view();
+        let files = self.file_store.view().await;
+        EngineSnapshot { cache, files }
+    }
+
+    /// read returns an iterator over the values of key within time_range, in time
+    /// order, values of the cache overriding the ones of the files for the same
+    /// timestamp. Keys found to have no values at all, in the cache or the files,
+    /// are remembered as missing for `ShardOptions::negative_cache_ttl`.
+    ///
+    /// It runs in a `query` span recording the key and range and, once done, the
+    /// number of values read and the duration.
+    #[tracing::instrument(
+        name = "query",
+        skip_all,
+        fields(
+            key = %String::from_utf8_lossy(key),
+            min = time_range.min,
+            max = time_range.max,
+            points = Empty,
+            duration_ms = Empty,
+        )
+    )]
+    pub async fn read(&self, key: &[u8], time_range: TimeRange) -> anyhow::Result<ReadIterator> {
+        let start = Instant::now();
+        let values = self.read_values(key, time_range).await?;
+
+        let span = tracing::Span::current();
+        span.record(
+            "points",
+            values.as_ref().map(|x| x.len()).unwrap_or_default(),
+        );
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        Ok(ReadIterator::new(values))
+    }
+
+    async fn read_values(
+        &self,
+        key: &[u8],
+        time_range: TimeRange,
+    ) -> anyhow::Result<Option<Values>> {
+        let epoch = match self.negative_cache.probe(key) {
+            Some(epoch) => epoch,
+            None => return Ok(None),
+        };
+
+        let snapshot = self.snapshot().await;
+        let values = snapshot.read(key, time_range).await?;
+        if values.is_none() && !snapshot.contains(key).await? {
+            self.negative_cache.insert(key, epoch);
+        }
+        Ok(values)
+    }
+
+    /// aggregate returns the count, minimum, maximum, sum and mean of the values of
+    /// key within time_range, the values `read` returns, None if there are none.
+    /// Float values are summed per mode, `SumMode::Compensated` by default; an
+    /// integer sum not fitting an i64 is a `SumOverflow` when it is read.
+    pub async fn aggregate(
+        &self,
+        key: &[u8],
+        time_range: TimeRange,
+        mode: SumMode,
+    ) -> anyhow::Result<Option<Aggregate>> {
+        self.snapshot().await.aggregate(key, time_range, mode).await
+    }
+
+    /// contains returns true if the cache or the files hold values of key, see
+    /// `read` for keys found missing.
+    pub async fn contains(&self, key: &[u8]) -> anyhow::Result<bool> {
+        let epoch = match self.negative_cache.probe(key) {
+            Some(epoch) => epoch,
+            None => return Ok(false),
+        };
+
+        let contains = self.snapshot().await.contains(key).await?;
+        if !contains {
+            self.negative_cache.insert(key, epoch);
+        }
+        Ok(contains)
+    }
+
+    /// wal_path returns the directory of the write ahead log.
+    pub fn wal_path(&self) -> String {
+        path_join(self.op.path(), WAL_DIR)
+    }
+
+    /// reload replays the write ahead log into the cache, returning the entries
+    /// applied. `open` replays the log before accepting writes.
+    pub async fn reload(&self) -> anyhow::Result<ReplayReport> {
+        let wal_op = self.op.to_op(self.wal_path().as_str());
+        let entries = Wal::replay(wal_op).await?;
+        self.replay_wal(entries).await
+    }
+
+    /// replay_wal applies the entries of the write ahead log to the cache, in the
+    /// order they were logged, returning the entries applied. Writes are
+    /// added to the cache and deletes remove the range from the cache and from the
+    /// TSM files.
+    ///
+    /// Values replayed beyond `max_replay_memory`, or the max size of the cache,
+    /// would be lost: the values replayed so far are flushed into a TSM file
+    /// instead and the replay continues.
+    async fn replay_wal(&self, mut entries: WalReplayIterator) -> anyhow::Result<ReplayReport> {
+        let max_memory = match self.max_replay_memory {
+            0 => self.cache.max_size(),
+            n => n,
+        };
+
+        let mut report = ReplayReport::default();
+        while let Some(entry) = entries.try_next().await? {
+            match entry {
+                WalEntry::Write(entry) => {
+                    let n = entry.values.size() as u64;
+                    let size = self.cache.size();
+                    if max_memory > 0 && size > 0 && size + n > max_memory {
+                        self.flush_replayed().await?;
+                    }
+
+                    self.negative_cache.invalidate([entry.key.as_slice()]);
+                    if let Err(e) = self.cache.write(entry.key.as_slice(), entry.values) {
+                        // values the cache rejected when they were written are
+                        // skipped again.
+                        if e.is::<CacheError>() {
+                            return Err(e);
+                        }
+                        tracing::warn!("skipping wal entry of {:?}: {}", entry.key, e);
+                    }
+                    report.writes += 1;
+                }
+                WalEntry::DeleteRange(entry) => {
+                    // the files hold values flushed by this replay, or are older
+                    // than the WAL, see `write_snapshot`
+                    let keys: Vec<&[u8]> = entry.keys.iter().map(|x| x.as_slice()).collect();
+                    self.cache
+                        .delete_range(keys.as_slice(), entry.min, entry.max);
+                    self.file_store
+                        .delete_range(keys.as_slice(), entry.min, entry.max)
+                        .await?;
+                    report.deletes += 1;
+                }
+            }
+
+            let progress = entries.progress();
+            self.open_status.set(OpenState::Replaying {
+                percent: progress.percent(),
+            });
+            if let Some(replay_progress) = &self.replay_progress {
+                replay_progress.on_progress(&progress);
+            }
+        }
+
+        let progress = entries.progress();
+        if let Some(replay_progress) = &self.replay_progress {
+            replay_progress.on_progress(&progress);
+        }
+        Ok(report)
+    }
+
+    /// flush_replayed flushes the values replayed so far into a TSM file. The WAL
+    /// is left as is, it is replayed again up to this point if the shard is opened
+    /// before the next snapshot.
+    async fn flush_replayed(&self) -> anyhow::Result<()> {
+        // the index must reflect the files of the shard before a new one is added
+        self.recover_index().await?;
+
+        let snapshot = self.cache.snapshot()?;
+        let r = self.flush(&snapshot).await;
+        self.cache.clear_snapshot(r.is_ok());
+        r.map(|_| ())
+    }
+
+    /// recover_index indexes the TSM files newer than the generation recorded by
+    /// the persisted index.
+    ///
+    /// The series creation hook is only called once the index is persisted, so
+    /// series recovered here were never reported and are reported now.
+    async fn recover_index(&self) -> anyhow::Result<()> {
+        let mut index = self.index.write().await;
+
+        let generation = self.file_store.current_generation();
+        if generation <= index.generation() {
+            return Ok(());
+        }
+
+        index.check_fence().await?;
+        let mut created = vec![];
+        let mut keys = self.file_store.keys_after(index.generation()).await?;
+        while let Some(key) = keys.try_next().await? {
+            let typ = match self.file_store.block_type(key.as_slice()).await? {
+                Some(typ) => typ,
+                None => continue,
+            };
+            index_tsm_key(&mut index, key.as_slice(), typ, &mut created).await?;
+        }
+        index.set_generation(generation);
+        index.persist().await?;
+        drop(index);
+
+        self.notify_series_created(created).await;
+        Ok(())
+    }
+
+    /// rebuild_indexes discards the indexes and rebuilds them from the keys of all
+    /// TSM files, e.g. after the persisted index was lost or corrupted.
+    ///
+    /// The series creation hook is not called: the series were reported when
+    /// they were first written.
+    pub async fn rebuild_indexes(&self) -> anyhow::Result<()> {
+        let mut index = self.index.write().await;
+
+        let index_op = self
+            .op
+            .to_op(path_join(self.op.path(), SHARD_INDEX_FILE).as_str());
+        let mut rebuilt = ShardIndex::create(index_op)
+            .await?
+            .with_fence(self.fence.clone());
+        rebuilt.check_fence().await?;
+
+        let generation = self.file_store.current_generation();
+        let mut created = vec![];
+        let mut keys = self.file_store.keys().await?;
+        while let Some(key) = keys.try_next().await? {
+            let typ = match self.file_store.block_type(key.as_slice()).await? {
+                Some(typ) => typ,
+                None => continue,
+            };
+            index_tsm_key(&mut rebuilt, key.as_slice(), typ, &mut created).await?;
+        }
+        rebuilt.set_generation(generation);
+        rebuilt.persist().await?;
+
+        *index = rebuilt;
+        Ok(())
+    }
+
+    async fn notify_series_created(&self, created: Vec<NewSeries>) {
+        if let Some(series_hook) = &self.series_hook {
+            series_hook.dispatch(created).await;
+        }
+    }
+
+    /// flush writes the values into new TSM files and updates the indexes,
+    /// returning the paths of the files.
+    ///
+    /// The TSM files are written by `Compactor::write_snapshot` under a temporary
+    /// name and renamed into the file store once complete, then the indexes are
+    /// updated and persisted. Persisting
+    /// the index, which records the generation of the new file, completes the
+    /// flush: if the process stops before, the file is re-indexed by `open`.
+    /// The series created by the flush are then passed to the series creation hook.
+    ///
+    /// It runs in a `flush` span recording the number of keys and values and,
+    /// once done, the bytes written, the files and the duration.
+    #[tracing::instrument(
+        name = "flush",
+        skip_all,
+        fields(
+            keys = values.len(),
+            points = values.values().map(|x| x.len()).sum::<usize>(),
+            bytes = Empty,
+            files = Empty,
+            duration_ms = Empty,
+        )
+    )]
+    pub async fn flush(&self, values: &BTreeMap<Vec<u8>, Values>) -> anyhow::Result<Vec<String>> {
+        let start = Instant::now();
+        let paths = self.flush_values(values).await?;
+
+        let span = tracing::Span::current();
+        span.record("files", paths.len());
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        Ok(paths)
+    }
+
+    async fn flush_values(
+        &self,
+        values: &BTreeMap<Vec<u8>, Values>,
+    ) -> anyhow::Result<Vec<String>> {
+        let paths = self
+            .compactor
+            .write_snapshot(values, &self.file_store)
+            .await?;
+        let generation = match paths.first() {
+            Some(path) => parse_tsm_file_name(path)?.0,
+            None => return Ok(paths),
+        };
+        self.negative_cache.clear();
+
+        let mut index = self.index.write().await;
+        index.check_fence().await?;
+        let mut created = vec![];
+        for (key, values) in values.iter() {
+            index_tsm_key(
+                &mut index,
+                key.as_slice(),
+                values.block_type(),
+                &mut created,
+            )
+            .await?;
+        }
+        index.set_generation(generation);
+        index.persist().await?;
+        drop(index);
+
+        self.notify_series_created(created).await;
+        Ok(paths)
+    }
+}
+
+/// EngineSnapshot is the content of a shard at the time it was taken, see
+/// `Engine::snapshot`.
+pub struct EngineSnapshot {
+    cache: CacheView,
+    files: FileStoreView,
+}
+
+impl EngineSnapshot {
+    pub fn files(&self) -> Vec<String> {
+        self.files.files()
+    }
+
+    /// contains returns true if the cache or the files hold values of key.
+    pub async fn contains(&self, key: &[u8]) -> anyhow::Result<bool> {
+        if self.cache.values(key).is_some() {
+            return Ok(true);
+        }
+        self.files.contains(key).await
+    }
+
+    /// read returns the values of key within time_range, values of the cache
+    /// overriding the ones of the files for the same timestamp.
+    pub async fn read(&self, key: &[u8], time_range: TimeRange) -> anyhow::Result<Option<Values>> {
+        let values = self.files.read(key, time_range.clone()).await?;
+        let cached = self.cache.values(key);
+
+        let mut values = match (values, cached) {
+            (values, None) => return Ok(values),
+            (None, Some(cached)) => cached,
+            (Some(values), Some(cached)) => values.merge(cached)?,
+        };
+
+        values.deduplicate();
+        values.include(time_range.min, time_range.max);
+        if values.len() > 0 {
+            Ok(Some(values))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// aggregate returns the aggregate of the values of key within time_range, see
+    /// `Engine::aggregate`. The blocks of the files holding the only values of
+    /// their time range are decoded as flat arrays straight into the aggregate,
+    /// see `FileStoreView::aggregate`, the other values are read and merged with
+    /// the cache as `read` does.
+    pub async fn aggregate(
+        &self,
+        key: &[u8],
+        time_range: TimeRange,
+        mode: SumMode,
+    ) -> anyhow::Result<Option<Aggregate>> {
+        let cached = self.cache.values(key).and_then(|mut cached| {
+            cached.include(time_range.min, time_range.max);
+            (cached.len() > 0).then_some(cached)
+        });
+        let times = cached.as_ref().map(|x| x.timestamps()).unwrap_or_default();
+
+        let (aggregate, values) = match self.files.aggregate(key, time_range, &times, mode).await? {
+            Some((aggregate, values)) => (Some(aggregate), values),
+            None => (None, None),
+        };
+        let values = match (values, cached) {
+            (values, None) => values,
+            (None, Some(cached)) => Some(cached),
+            (Some(values), Some(cached)) => Some(values.merge(cached)?),
+        };
+
+        let mut aggregate = match (aggregate, values.as_ref()) {
+            (Some(aggregate), _) => aggregate,
+            (None, Some(values)) => Aggregate::new(values.block_type(), mode)?,
+            (None, None) => return Ok(None),
+        };
+        if let Some(values) = values.as_ref() {
+            aggregate.add_values(values)?;
+        }
+        Ok((aggregate.count() > 0).then_some(aggregate))
+    }
+}
+
+/// ReadIterator iterates over the values read by `Engine::read`, in blocks of at
+/// most `DEFAULT_MAX_POINTS_PER_BLOCK` points.
+pub struct ReadIterator {
+    blocks: std::vec::IntoIter<Values>,
+}
+
+impl ReadIterator {
+    fn new(values: Option<Values>) -> Self {
+        let blocks = values
+            .map(|x| split_values(&x, DEFAULT_MAX_POINTS_PER_BLOCK))
+            .unwrap_or_default();
+        Self {
+            blocks: blocks.into_iter(),
+        }
+    }
+
+    /// collect_values returns the remaining values as one, None if there are
+    /// none left.
+    pub async fn collect_values(mut self) -> anyhow::Result<Option<Values>> {
+        let mut values: Option<Values> = None;
+        while let Some(block) = self.try_next().await? {
+            values = match values {
+                Some(values) => Some(values.merge(block)?),
+                None => Some(block),
+            };
+        }
+        Ok(values)
+    }
+}
+
+#[async_trait]
+impl AsyncIterator for ReadIterator {
+    type Item = Values;
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        Ok(self.blocks.next())
+    }
+}
+
+/// index_tsm_key indexes the series and the field of a TSM key, appending them to
+/// `created` if either was not indexed yet.
+async fn index_tsm_key(
+    index: &mut ShardIndex,
+    key: &[u8],
+    typ: u8,
+    created: &mut Vec<NewSeries>,
+) -> anyhow::Result<()> {
+    let (series_key, field) = split_tsm_key(key);
+    let (measurement, tags) = parse_series_key(series_key);
+
+    let new_series = index.series_id(series_key).is_none();
+    index.add_series(series_key).await?;
+    let new_field = index.add_field(measurement.as_slice(), field, typ);
+
+    if new_series || new_field {
+        created.push(NewSeries {
+            key: series_key.to_vec(),
+            measurement,
+            tags,
+            field: field.to_vec(),
+            field_type: typ,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use common_base::clock::SimulatedClock;
+    use common_base::iterator::AsyncIterator;
+    use common_base::point::{KeyTooLong, Point, DEFAULT_MAX_SERIES_KEY_LENGTH, MAX_KEY_LENGTH};
+    use influxdb_storage::StorageOperator;
+    use tokio::sync::Notify;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Dispatch, Event, Metadata, Subscriber};
+
+    use crate::engine::tsm1::aggregate::Aggregate;
+    use crate::engine::tsm1::block::{BLOCK_FLOAT64, BLOCK_INTEGER};
+    use crate::engine::tsm1::cache::CacheError;
+    use crate::engine::tsm1::compact::Compactor;
+    use crate::engine::tsm1::engine::{
+        Engine, OpenState, OpenStatus, ReplayReport, ShardOptions, WAL_DIR,
+    };
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::read_semaphore::ReadSemaphore;
+    use crate::engine::tsm1::schema::{SchemaMode, SchemaRegistry, SchemaViolation};
+    use crate::engine::tsm1::series_hook::{NewSeries, SeriesCreationHook};
+    use crate::engine::tsm1::shard_lock::{ShardLockError, ShardLockOptions};
+    use crate::engine::tsm1::value::{SumMode, SumOverflow, TimeValue, Values};
+    use crate::engine::tsm1::wal::{Progress, ReplayProgress, Wal, WalEntry, WalOptions};
+    use crate::index::shard_index::{ShardIndex, SHARD_INDEX_FILE};
+    use crate::index::tag_index::TagPredicate;
+
+    fn float_values(points: &[(i64, f64)]) -> Values {
+        Values::Float(points.iter().map(|(t, v)| TimeValue::new(*t, *v)).collect())
+    }
+
+    /// read returns the values of key read by `Engine::read`, as one.
+    async fn read(engine: &Engine, key: &[u8], time_range: TimeRange) -> Option<Values> {
+        let itr = engine.read(key, time_range).await.unwrap();
+        itr.collect_values().await.unwrap()
+    }
+
+    /// SpanRecorder captures the name and the fields of the spans created.
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<(&'static str, HashMap<String, String>)>>>,
+    }
+
+    impl SpanRecorder {
+        fn spans(&self, name: &str) -> Vec<HashMap<String, String>> {
+            let spans = self.spans.lock().unwrap();
+            spans
+                .iter()
+                .filter(|(x, _)| *x == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    struct FieldRecorder<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldRecorder<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldRecorder(&mut fields));
+
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut FieldRecorder(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[derive(Default)]
+    struct RecordingHook {
+        batches: Mutex<Vec<Vec<NewSeries>>>,
+        recorded: Notify,
+    }
+
+    #[async_trait]
+    impl SeriesCreationHook for RecordingHook {
+        async fn on_series_created(&self, batch: &[NewSeries]) -> anyhow::Result<()> {
+            self.batches.lock().unwrap().push(batch.to_vec());
+            self.recorded.notify_one();
+            Ok(())
+        }
+    }
+
+    impl RecordingHook {
+        /// keys returns the (series key, field) pairs of each recorded batch.
+        fn keys(&self) -> Vec<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.batches
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|batch| {
+                    batch
+                        .iter()
+                        .map(|x| (x.key.clone(), x.field.clone()))
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    async fn open_with_hook(path: &str, hook: Arc<RecordingHook>) -> Engine {
+        let options = ShardOptions {
+            series_creation_hook: Some(hook),
+            ..Default::default()
+        };
+        Engine::open_with_options(StorageOperator::root(path).unwrap(), options)
+            .await
+            .unwrap()
+    }
+
+    /// wait_delivered waits for the hook to record its n-th batch, then lets the
+    /// dispatcher count it.
+    async fn wait_delivered(engine: &Engine, hook: &RecordingHook, n: usize) {
+        while hook.batches.lock().unwrap().len() < n {
+            hook.recorded.notified().await;
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(engine.series_hook_stats().unwrap().delivered(), n as u64);
+    }
+
+    #[tokio::test]
+    async fn test_engine_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+
+            let mut values = BTreeMap::new();
+            values.insert(
+                b"cpu,host=a#!~#value".to_vec(),
+                float_values(&[(1, 1.0), (2, 2.0)]),
+            );
+            values.insert(b"cpu,host=b#!~#value".to_vec(), float_values(&[(1, 3.0)]));
+            engine.flush(&values).await.unwrap();
+        }
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let values = read(&engine, b"cpu,host=a#!~#value", TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+
+        let index = engine.index().read().await;
+        assert_eq!(index.generation(), 1);
+        assert_eq!(index.series_count(), 2);
+        let got = index.query(&[vec![TagPredicate::new(b"host", b"b")]]);
+        assert_eq!(got, vec![b"cpu,host=b".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_engine_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let engine = Arc::new(
+            Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap(),
+        );
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        let mut values = BTreeMap::new();
+        values.insert(key.clone(), float_values(&[(1, 1.0)]));
+        engine.flush(&values).await.unwrap();
+
+        let mut values = BTreeMap::new();
+        values.insert(key.clone(), float_values(&[(1, 10.0), (2, 2.0)]));
+        engine.write(values).unwrap();
+
+        // the query starts, then the cache is written and flushed concurrently
+        let snapshot = engine.snapshot().await;
+        let files = snapshot.files();
+        assert_eq!(files.len(), 1);
+
+        let flusher = engine.clone();
+        let flush_key = key.clone();
+        tokio::spawn(async move {
+            let mut values = BTreeMap::new();
+            values.insert(flush_key, float_values(&[(2, 20.0), (3, 3.0)]));
+            flusher.write(values).unwrap();
+            assert_eq!(flusher.write_snapshot().await.unwrap().len(), 1);
+        })
+        .await
+        .unwrap();
+        assert_eq!(engine.file_store().view().await.files().len(), 2);
+        assert_eq!(engine.cache().size(), 0);
+
+        let got = snapshot.read(&key, TimeRange::unbound()).await.unwrap();
+        assert_eq!(got, Some(float_values(&[(1, 10.0), (2, 2.0)])));
+        assert_eq!(snapshot.files(), files);
+
+        let got = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(got, Some(float_values(&[(1, 10.0), (2, 20.0), (3, 3.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_engine_series_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let hook = Arc::new(RecordingHook::default());
+        let engine = open_with_hook(&path, hook.clone()).await;
+
+        let mut values = BTreeMap::new();
+        values.insert(b"cpu,host=a#!~#value".to_vec(), float_values(&[(1, 1.0)]));
+        values.insert(b"cpu,host=b#!~#value".to_vec(), float_values(&[(1, 1.0)]));
+        engine.flush(&values).await.unwrap();
+        wait_delivered(&engine, &hook, 1).await;
+
+        // only the new series and the new field are reported
+        let mut values = BTreeMap::new();
+        values.insert(b"cpu,host=a#!~#value".to_vec(), float_values(&[(2, 1.0)]));
+        values.insert(
+            b"cpu,host=a#!~#count".to_vec(),
+            Values::Integer(vec![TimeValue::new(2, 1)]),
+        );
+        values.insert(
+            b"cpu,host=c,region=east#!~#value".to_vec(),
+            float_values(&[(2, 1.0)]),
+        );
+        engine.flush(&values).await.unwrap();
+        wait_delivered(&engine, &hook, 2).await;
+
+        // nothing new, no batch
+        let mut values = BTreeMap::new();
+        values.insert(b"cpu,host=b#!~#value".to_vec(), float_values(&[(3, 1.0)]));
+        engine.flush(&values).await.unwrap();
+
+        assert_eq!(
+            hook.keys(),
+            vec![
+                vec![
+                    (b"cpu,host=a".to_vec(), b"value".to_vec()),
+                    (b"cpu,host=b".to_vec(), b"value".to_vec()),
+                ],
+                vec![
+                    (b"cpu,host=a".to_vec(), b"count".to_vec()),
+                    (b"cpu,host=c,region=east".to_vec(), b"value".to_vec()),
+                ],
+            ]
+        );
+
+        let batches = hook.batches.lock().unwrap();
+        let count = &batches[1][0];
+        assert_eq!(count.measurement, b"cpu".to_vec());
+        assert_eq!(count.field_type, BLOCK_INTEGER);
+
+        let series = &batches[1][1];
+        assert_eq!(series.measurement, b"cpu".to_vec());
+        assert_eq!(series.field_type, BLOCK_FLOAT64);
+        let tags: Vec<_> = series
+            .tags
+            .iter()
+            .map(|x| (x.key.clone(), x.value.clone()))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                (b"host".to_vec(), b"c".to_vec()),
+                (b"region".to_vec(), b"east".to_vec()),
+            ]
+        );
+
+        let stats = engine.series_hook_stats().unwrap();
+        assert_eq!(stats.dropped(), 0);
+        assert_eq!(stats.failed(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_engine_series_hook_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        {
+            let hook = Arc::new(RecordingHook::default());
+            let engine = open_with_hook(&path, hook.clone()).await;
+
+            let mut values = BTreeMap::new();
+            values.insert(b"cpu,host=a#!~#value".to_vec(), float_values(&[(1, 1.0)]));
+            engine.flush(&values).await.unwrap();
+            wait_delivered(&engine, &hook, 1).await;
+
+            // crash after writing the TSM file of the next flush, which also
+            // contains the already reported series
+            let generation = engine.file_store().next_generation();
+            let tsm_path = engine.file_store().tsm_path(generation, 1);
+            let mut w = DefaultTSMWriter::with_mem_buffer(&tsm_path).await.unwrap();
+            w.write(b"cpu,host=a#!~#value", float_values(&[(2, 1.0)]))
+                .await
+                .unwrap();
+            w.write(b"mem,host=c#!~#free", float_values(&[(2, 1.0)]))
+                .await
+                .unwrap();
+            w.write_index().await.unwrap();
+            w.close().await.unwrap();
+        }
+
+        // recovery reports the series of the unindexed file once
+        let hook = Arc::new(RecordingHook::default());
+        let engine = open_with_hook(&path, hook.clone()).await;
+        wait_delivered(&engine, &hook, 1).await;
+        assert_eq!(
+            hook.keys(),
+            vec![vec![(b"mem,host=c".to_vec(), b"free".to_vec())]]
+        );
+        drop(engine);
+
+        // and never again
+        let hook = Arc::new(RecordingHook::default());
+        let engine = open_with_hook(&path, hook.clone()).await;
+        // a batch queued by the open would be delivered once the dispatcher runs
+        tokio::task::yield_now().await;
+        assert_eq!(engine.series_hook_stats().unwrap().delivered(), 0);
+        assert!(hook.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_engine_rebuild_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let index_path = format!("{}{}", path, SHARD_INDEX_FILE);
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        for (i, keys) in [
+            vec!["cpu,host=a,region=east", "cpu,host=b,region=west"],
+            vec!["cpu,host=a,region=east", "mem,host=c,region=east"],
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut values = BTreeMap::new();
+            for key in keys {
+                values.insert(
+                    format!("{}#!~#value", key).into_bytes(),
+                    float_values(&[(i as i64, 1.0)]),
+                );
+            }
+            engine.flush(&values).await.unwrap();
+        }
+
+        let queries = [
+            vec![vec![TagPredicate::new(b"region", b"east")]],
+            vec![vec![TagPredicate::new(b"host", b"b")]],
+            vec![
+                vec![TagPredicate::new(b"host", b"a")],
+                vec![TagPredicate::new(b"host", b"c")],
+            ],
+        ];
+        let query_all = |index: &ShardIndex| {
+            queries
+                .iter()
+                .map(|groups| {
+                    let mut keys = index.query(groups);
+                    keys.sort();
+                    keys
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (expected, cardinality) = {
+            let mut index = engine.index().write().await;
+            (query_all(&index), index.series_cardinality())
+        };
+        assert_eq!(expected[0].len(), 2);
+
+        tokio::fs::remove_file(&index_path).await.unwrap();
+        engine.rebuild_indexes().await.unwrap();
+        {
+            let mut index = engine.index().write().await;
+            assert_eq!(query_all(&index), expected);
+            assert_eq!(index.series_count(), 3);
+            assert_eq!(index.series_cardinality(), cardinality);
+            assert_eq!(index.generation(), 2);
+            assert_eq!(index.field_type(b"mem", b"value"), Some(BLOCK_FLOAT64));
+        }
+        drop(engine);
+
+        // the rebuilt index was persisted
+        let index = ShardIndex::open(StorageOperator::root(&index_path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(query_all(&index), expected);
+
+        // a corrupt index is rebuilt on open
+        tokio::fs::write(&index_path, b"garbage").await.unwrap();
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let index = engine.index().read().await;
+        assert_eq!(query_all(&index), expected);
+        assert_eq!(index.generation(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_engine_flush_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+
+            let mut values = BTreeMap::new();
+            values.insert(b"cpu,host=a#!~#value".to_vec(), float_values(&[(1, 1.0)]));
+            engine.flush(&values).await.unwrap();
+
+            // Simulate a crash after the TSM file of the next flush is written but
+            // before the index is updated.
+            let generation = engine.file_store().next_generation();
+            let tsm_path = engine.file_store().tsm_path(generation, 1);
+            let mut w = DefaultTSMWriter::with_mem_buffer(&tsm_path).await.unwrap();
+            w.write(b"mem,host=c#!~#free", float_values(&[(1, 1.0)]))
+                .await
+                .unwrap();
+            w.write_index().await.unwrap();
+            w.close().await.unwrap();
+        }
+
+        // The persisted index only reflects the first flush.
+        let index_op = StorageOperator::root(&format!("{}{}", path, SHARD_INDEX_FILE)).unwrap();
+        let index = ShardIndex::open(index_op.clone()).await.unwrap();
+        assert_eq!(index.generation(), 1);
+        assert_eq!(index.series_id(b"mem,host=c"), None);
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        {
+            let index = engine.index().read().await;
+            assert_eq!(index.generation(), 2);
+            assert_eq!(index.series_count(), 2);
+            let got = index.query(&[vec![TagPredicate::new(b"host", b"c")]]);
+            assert_eq!(got, vec![b"mem,host=c".to_vec()]);
+        }
+
+        // and the recovered index was persisted
+        let index = ShardIndex::open(index_op).await.unwrap();
+        assert_eq!(index.generation(), 2);
+        assert!(index.series_id(b"mem,host=c").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_engine_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key_a = b"cpu,host=a#!~#value".to_vec();
+        let key_b = b"cpu,host=b#!~#value".to_vec();
+
+        let wal_op = StorageOperator::root(&format!("{}{}", path, WAL_DIR)).unwrap();
+        let mut wal = Wal::open(wal_op, WalOptions::default()).await.unwrap();
+        wal.write_values(key_a.clone(), float_values(&[(1, 1.0), (2, 2.0), (3, 3.0)]))
+            .await
+            .unwrap();
+        wal.write_values(key_b.clone(), float_values(&[(1, 10.0)]))
+            .await
+            .unwrap();
+        wal.delete_range(vec![key_a.clone()], 2, 2).await.unwrap();
+        wal.write_values(key_a.clone(), float_values(&[(4, 4.0)]))
+            .await
+            .unwrap();
+        // the entry torn by the crash
+        wal.write_values(key_b.clone(), float_values(&[(2, 20.0)]))
+            .await
+            .unwrap();
+        let segment = wal.segments().pop().unwrap();
+        wal.close().await.unwrap();
+
+        let segment = std::path::Path::new(segment.as_str());
+        let len = std::fs::metadata(segment).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(segment)
+            .unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let values = read(&engine, &key_a, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (3, 3.0), (4, 4.0)])));
+        let values = read(&engine, &key_b, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 10.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_engine_write_points_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key_a = b"cpu,host=a#!~#value".to_vec();
+        let key_b = b"cpu,host=b#!~#value".to_vec();
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+            let lines = "cpu,host=a value=1 1
+                         cpu,host=a value=2 2
+                         cpu,host=b value=10 1";
+            let points = Point::parse_lines_with_time(lines, 0).unwrap();
+            engine.write_points(&points).await.unwrap();
+
+            let points = Point::parse_lines_with_time("cpu,host=a value=3 3", 0).unwrap();
+            engine.write_points(&points).await.unwrap();
+            engine
+                .delete_series_range(&[key_a.as_slice()], 2, 2)
+                .await
+                .unwrap();
+            // dropped without close, the cache is replayed from the WAL
+        }
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(engine.file_store().view().await.files().len(), 0);
+        let values = read(&engine, &key_a, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (3, 3.0)])));
+
+        let points = Point::parse_lines_with_time("cpu,host=b value=20 2", 0).unwrap();
+        engine.write_points(&points).await.unwrap();
+        engine.close().await.unwrap();
+        drop(engine);
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(engine.file_store().view().await.files().len(), 1);
+        assert_eq!(engine.cache().size(), 0);
+        let values = read(&engine, &key_a, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (3, 3.0)])));
+        let values = read(&engine, &key_b, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 10.0), (2, 20.0)])));
+
+        // a rejected point fails the whole batch
+        let lines = "cpu,host=c value=1 1
+                     cpu,host=d value=1 1";
+        let mut points = Point::parse_lines_with_time(lines, 0).unwrap();
+        points[1].name = vec![b'x'; DEFAULT_MAX_SERIES_KEY_LENGTH + 1];
+        assert!(engine.write_points(&points).await.is_err());
+        assert!(!engine.contains(b"cpu,host=c#!~#value").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_engine_read_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // half of the values in a file, the other half in the cache overriding
+        // some of them
+        let points: Vec<(i64, f64)> = (0..2500).map(|t| (t, t as f64)).collect();
+        engine
+            .write_values(write_values(&key, &points[..1500]))
+            .await
+            .unwrap();
+        engine.write_snapshot().await.unwrap();
+        let cached: Vec<(i64, f64)> = (1000..2500).map(|t| (t, -(t as f64))).collect();
+        engine
+            .write_values(write_values(&key, &cached))
+            .await
+            .unwrap();
+
+        let mut itr = engine.read(&key, TimeRange::new(100, 2199)).await.unwrap();
+        let mut blocks = vec![];
+        while let Some(block) = itr.try_next().await.unwrap() {
+            blocks.push(block);
+        }
+        assert_eq!(
+            blocks.iter().map(|x| x.len()).collect::<Vec<_>>(),
+            vec![1000, 1000, 100]
+        );
+        let expected: Vec<(i64, f64)> = (100..2200)
+            .map(|t| (t, if t < 1000 { t as f64 } else { -(t as f64) }))
+            .collect();
+        let mut values = blocks.remove(0);
+        for block in blocks {
+            values = values.merge(block).unwrap();
+        }
+        assert_eq!(values, float_values(&expected));
+
+        let mut itr = engine
+            .read(b"mem#!~#free", TimeRange::unbound())
+            .await
+            .unwrap();
+        assert!(itr.try_next().await.unwrap().is_none());
+        engine.close().await.unwrap();
+    }
+
+    /// write_values returns the values of a single key, to be written by
+    /// `write_values`.
+    fn write_values(key: &[u8], points: &[(i64, f64)]) -> BTreeMap<Vec<u8>, Values> {
+        let mut values = BTreeMap::new();
+        values.insert(key.to_vec(), float_values(points));
+        values
+    }
+
+    #[tokio::test]
+    async fn test_engine_aggregate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // files, oldest first: [1, 10] deleted in [5, 6], [21, 30] overwritten in
+        // [25, 26] by the next file, [41, 50] overwritten at 45 by the cache and
+        // [61, 70] holding the only values of its range
+        let files = [
+            (1..=10, 1.0),
+            (21..=30, 1.0),
+            (25..=26, 100.0),
+            (41..=50, 1.0),
+            (61..=70, 1.0),
+        ];
+        for (range, factor) in files {
+            let points: Vec<(i64, f64)> = range.map(|t| (t, t as f64 * factor)).collect();
+            let mut values = BTreeMap::new();
+            values.insert(key.clone(), float_values(&points));
+            engine.flush(&values).await.unwrap();
+        }
+        engine
+            .delete_series_range(&[key.as_slice()], 5, 6)
+            .await
+            .unwrap();
+        engine
+            .write_values(write_values(&key, &[(45, -45.0)]))
+            .await
+            .unwrap();
+
+        // only the block of [61, 70] is decoded into the aggregate as is
+        let snapshot = engine.snapshot().await;
+        let (aggregate, values) = snapshot
+            .files
+            .aggregate(&key, TimeRange::unbound(), &[45], SumMode::Compensated)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(aggregate.count(), 10);
+        assert_eq!(values.unwrap().len(), 8 + 10 + 10);
+
+        match engine
+            .aggregate(&key, TimeRange::unbound(), SumMode::Compensated)
+            .await
+            .unwrap()
+        {
+            Some(Aggregate::Float(summary)) => {
+                assert_eq!(summary.count(), 38);
+                assert_eq!(summary.sum(), Some(44.0 + 5304.0 + 365.0 + 655.0));
+                assert_eq!(summary.min(), Some(-45.0));
+                assert_eq!(summary.max(), Some(2600.0));
+            }
+            other => panic!("unexpected aggregate {:?}", other),
+        }
+
+        // the aggregate of the values read, whatever the range
+        let ranges = [
+            TimeRange::unbound(),
+            TimeRange::new(3, 28),
+            TimeRange::new(41, 70),
+            TimeRange::new(61, 70),
+            TimeRange::new(11, 20),
+        ];
+        for range in ranges {
+            for mode in [SumMode::Fast, SumMode::Compensated] {
+                let want = read(&engine, &key, range.clone()).await.map(|values| {
+                    let mut aggregate = Aggregate::new(BLOCK_FLOAT64, mode).unwrap();
+                    aggregate.add_values(&values).unwrap();
+                    aggregate
+                });
+                let aggregate = engine.aggregate(&key, range.clone(), mode).await.unwrap();
+                assert_eq!(aggregate, want, "{:?} {:?}", range, mode);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_aggregate_sum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // the small values added to a large one are lost by a plain f64 sum
+        let key = b"cpu,host=a#!~#value".to_vec();
+        let points: Vec<(i64, f64)> = (2..1002).map(|t| (t, 1.0)).collect();
+        engine
+            .flush(&write_values(&key, &[(1, 1e16)]))
+            .await
+            .unwrap();
+        engine.flush(&write_values(&key, &points)).await.unwrap();
+
+        let mut sums = vec![];
+        for mode in [SumMode::Fast, SumMode::Compensated] {
+            match engine.aggregate(&key, TimeRange::unbound(), mode).await {
+                Ok(Some(Aggregate::Float(summary))) => sums.push(summary.sum()),
+                other => panic!("unexpected aggregate {:?}", other),
+            }
+        }
+        assert_eq!(sums, vec![Some(1e16), Some(1e16 + 1000.0)]);
+
+        // an integer sum not fitting an i64 is an error, not a wrapped sum
+        let key = b"cpu,host=a#!~#count".to_vec();
+        let mut values = BTreeMap::new();
+        values.insert(
+            key.clone(),
+            Values::Integer(vec![TimeValue::new(1, i64::MAX)]),
+        );
+        engine.flush(&values).await.unwrap();
+        values.insert(key.clone(), Values::Integer(vec![TimeValue::new(2, 1)]));
+        engine.write_values(values).await.unwrap();
+
+        match engine
+            .aggregate(&key, TimeRange::unbound(), SumMode::default())
+            .await
+        {
+            Ok(Some(Aggregate::Integer(summary))) => {
+                assert_eq!(summary.count(), 2);
+                assert_eq!(summary.max(), Some(i64::MAX));
+                assert_eq!(summary.sum(), Err(SumOverflow { count: 2 }));
+            }
+            other => panic!("unexpected aggregate {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_write_points_cache_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+        let options = ShardOptions {
+            cache_max_memory_size: 3 * 16,
+            cache_snapshot_memory_size: 0,
+            ..Default::default()
+        };
+
+        {
+            let engine =
+                Engine::open_with_options(StorageOperator::root(&path).unwrap(), options.clone())
+                    .await
+                    .unwrap();
+            engine
+                .write_values(write_values(&key, &[(1, 1.0), (2, 2.0)]))
+                .await
+                .unwrap();
+            // rejected before it is logged
+            let err = engine
+                .write_values(write_values(&key, &[(3, 3.0), (4, 4.0)]))
+                .await
+                .unwrap_err();
+            assert!(err.is::<CacheError>());
+        }
+
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+        assert_eq!(engine.replay_report().writes, 1);
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_engine_replay_write_then_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+            // the older values are in a TSM file, the newer ones in the WAL only
+            engine
+                .write_values(write_values(&key, &[(1, 1.0), (2, 2.0), (3, 3.0)]))
+                .await
+                .unwrap();
+            engine.write_snapshot().await.unwrap();
+            engine
+                .write_values(write_values(&key, &[(4, 4.0), (5, 5.0)]))
+                .await
+                .unwrap();
+            engine
+                .delete_series_range(&[key.as_slice()], 2, 4)
+                .await
+                .unwrap();
+            // dropped without close, the cache is replayed from the WAL
+        }
+
+        // the delete is replayed after the write it supersedes
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            engine.replay_report(),
+            ReplayReport {
+                writes: 1,
+                deletes: 1
+            }
+        );
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (5, 5.0)])));
+        drop(engine);
+
+        // a crash right after a delete is logged, before the cache and the TSM
+        // files are changed
+        let wal_op = StorageOperator::root(&format!("{}{}", path, WAL_DIR)).unwrap();
+        let mut wal = Wal::open(wal_op, WalOptions::default()).await.unwrap();
+        wal.delete_range(vec![key.clone()], 1, 1).await.unwrap();
+        wal.delete_range(vec![key.clone()], 5, 5).await.unwrap();
+        wal.close().await.unwrap();
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            engine.replay_report(),
+            ReplayReport {
+                writes: 1,
+                deletes: 3
+            }
+        );
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, None);
+    }
+
+    #[tokio::test]
+    async fn test_engine_replay_delete_then_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+            engine
+                .write_values(write_values(&key, &[(1, 1.0), (2, 2.0), (3, 3.0)]))
+                .await
+                .unwrap();
+            engine.write_snapshot().await.unwrap();
+            engine
+                .delete_series_range(&[key.as_slice()], 1, 3)
+                .await
+                .unwrap();
+            // newer values in the deleted range
+            engine
+                .write_values(write_values(&key, &[(2, 20.0), (3, 30.0)]))
+                .await
+                .unwrap();
+            // dropped without close, the cache is replayed from the WAL
+        }
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            engine.replay_report(),
+            ReplayReport {
+                writes: 1,
+                deletes: 1
+            }
+        );
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(2, 20.0), (3, 30.0)])));
+
+        // replaying again changes nothing
+        let report = engine.reload().await.unwrap();
+        assert_eq!(report.entries(), 2);
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(2, 20.0), (3, 30.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_engine_snapshot_flusher() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        let options = ShardOptions {
+            cache_snapshot_memory_size: 1,
+            ..Default::default()
+        };
+        let engine = Arc::new(
+            Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+                .await
+                .unwrap(),
+        );
+        let flusher = engine.spawn_snapshot_flusher(Duration::from_secs(60));
+        let mut snapshots = engine.subscribe_snapshots();
+
+        let mut values = BTreeMap::new();
+        values.insert(key.clone(), float_values(&[(1, 1.0), (2, 2.0)]));
+        engine.write_values(values).await.unwrap();
+
+        snapshots.changed().await.unwrap();
+        assert_eq!(engine.cache().size(), 0);
+        assert_eq!(engine.file_store().view().await.files().len(), 1);
+        // only the segment opened by the snapshot is left
+        assert_eq!(engine.wal.lock().await.segments().len(), 1);
+
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+
+        engine.close().await.unwrap();
+        flusher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_engine_snapshot_write_cold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let key = b"cpu,host=a#!~#value".to_vec();
+        let clock = SimulatedClock::new(0);
+
+        let options = ShardOptions {
+            cache_snapshot_memory_size: 0,
+            cache_snapshot_write_cold_duration: Duration::from_secs(600),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let engine = Arc::new(
+            Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+                .await
+                .unwrap(),
+        );
+        let flusher = engine.spawn_snapshot_flusher(Duration::from_secs(60));
+        let mut snapshots = engine.subscribe_snapshots();
+        tokio::task::yield_now().await;
+
+        // an empty cache is never flushed
+        clock.advance(Duration::from_secs(600));
+        assert!(!engine.should_snapshot());
+
+        engine
+            .write_values(write_values(&key, &[(1, 1.0)]))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(300));
+        assert!(!engine.should_snapshot());
+
+        // a write restarts the age of the cache
+        engine
+            .write_values(write_values(&key, &[(2, 2.0)]))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(599));
+        assert!(!engine.should_snapshot());
+        assert_eq!(engine.file_store().view().await.files().len(), 0);
+
+        // flushed by the next tick of the flusher once cold
+        clock.advance(Duration::from_secs(1));
+        assert!(engine.should_snapshot());
+        clock.advance(Duration::from_secs(60));
+        snapshots.changed().await.unwrap();
+        assert_eq!(*snapshots.borrow(), 1);
+        assert_eq!(engine.cache().size(), 0);
+        assert_eq!(engine.file_store().view().await.files().len(), 1);
+
+        let values = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+
+        engine.close().await.unwrap();
+        flusher.await.unwrap();
+    }
+
+    #[derive(Default)]
+    struct RecordingProgress {
+        progress: Mutex<Vec<ReplayProgress>>,
+    }
+
+    impl Progress for RecordingProgress {
+        fn on_progress(&self, progress: &ReplayProgress) {
+            self.progress.lock().unwrap().push(progress.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_replay_max_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let keys: Vec<Vec<u8>> = (0..4)
+            .map(|i| format!("cpu,host={}#!~#value", i).into_bytes())
+            .collect();
+
+        {
+            let options = ShardOptions {
+                wal: WalOptions {
+                    segment_size: 256,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+                .await
+                .unwrap();
+            for t in 0..50 {
+                let mut values = BTreeMap::new();
+                for key in keys.iter() {
+                    values.insert(key.clone(), float_values(&[(t, t as f64)]));
+                }
+                engine.write_values(values).await.unwrap();
+            }
+            engine
+                .delete_series_range(&[keys[0].as_slice()], 10, 19)
+                .await
+                .unwrap();
+            // dropped without close, the cache is replayed from the WAL
+        }
+
+        let progress = Arc::new(RecordingProgress::default());
+        let status = Arc::new(OpenStatus::default());
+        let options = ShardOptions {
+            max_replay_memory: 1024,
+            replay_progress: Some(progress.clone()),
+            open_status: Some(status.clone()),
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+        assert!(!engine.file_store().view().await.files().is_empty());
+        assert!(engine.cache().size() <= 1024);
+        assert_eq!(engine.open_status(), OpenState::Ready);
+        assert_eq!(status.get(), OpenState::Ready);
+
+        for (i, key) in keys.iter().enumerate() {
+            let expected: Vec<(i64, f64)> = (0..50)
+                .filter(|t| i != 0 || !(10..20).contains(t))
+                .map(|t| (t, t as f64))
+                .collect();
+            let values = read(&engine, key, TimeRange::unbound()).await;
+            assert_eq!(values, Some(float_values(expected.as_slice())));
+        }
+
+        let progress = progress.progress.lock().unwrap();
+        assert!(progress.len() > 1);
+        assert!(progress
+            .windows(2)
+            .all(|w| w[0].bytes_replayed <= w[1].bytes_replayed && w[0].entries <= w[1].entries));
+        let last = progress.last().unwrap();
+        assert_eq!(last.percent(), 100);
+        // one entry per key written and the delete
+        assert_eq!(last.entries, 201);
+        assert!(last.segment.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_engine_field_keys_ordered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        {
+            let engine = Engine::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+            let points = Point::parse_lines_with_time(
+                "weather,city=a temp=1,humidity=2,pressure=3 1\nweather,city=b wind=4,temp=5 1",
+                0,
+            )
+            .unwrap();
+            engine.record_field_order(points.as_slice()).await;
+
+            let mut values = BTreeMap::new();
+            for field in ["temp", "humidity", "pressure", "wind"] {
+                let key = format!("weather,city=a#!~#{}", field);
+                values.insert(key.into_bytes(), float_values(&[(1, 1.0)]));
+            }
+            // mem is written without recording its field order, as by older shards
+            values.insert(b"mem#!~#used".to_vec(), float_values(&[(1, 1.0)]));
+            values.insert(b"mem#!~#free".to_vec(), float_values(&[(1, 1.0)]));
+            engine.flush(&values).await.unwrap();
+        }
+
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            engine.field_keys_ordered(b"weather").await,
+            vec![
+                b"temp".to_vec(),
+                b"humidity".to_vec(),
+                b"pressure".to_vec(),
+                b"wind".to_vec()
+            ]
+        );
+        assert_eq!(
+            engine.field_keys_ordered(b"mem").await,
+            vec![b"free".to_vec(), b"used".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_engine_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        let options = ShardOptions {
+            lock: Some(ShardLockOptions::default()),
+            ..Default::default()
+        };
+
+        let engine =
+            Engine::open_with_options(StorageOperator::root(&path).unwrap(), options.clone())
+                .await
+                .unwrap();
+        let err = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ShardLockError>(),
+            Some(ShardLockError::Locked { .. })
+        ));
+
+        engine.close().await.unwrap();
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+        engine.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_engine_negative_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        for _ in 0..3 {
+            let got = read(&engine, &key, TimeRange::unbound()).await;
+            assert_eq!(got, None);
+        }
+        let stats = engine.negative_cache_stats();
+        assert_eq!(stats.misses(), 1);
+        assert_eq!(stats.hits(), 2);
+        assert_eq!(stats.insertions(), 1);
+        assert!(!engine.contains(&key).await.unwrap());
+        assert_eq!(stats.misses(), 1);
+
+        // the write purges the key, it is visible right away
+        let mut values = BTreeMap::new();
+        values.insert(key.clone(), float_values(&[(1, 1.0)]));
+        engine.write_values(values).await.unwrap();
+        assert_eq!(stats.invalidations(), 1);
+        let got = read(&engine, &key, TimeRange::unbound()).await;
+        assert_eq!(got, Some(float_values(&[(1, 1.0)])));
+
+        // values outside of the range do not make the key missing
+        let got = read(&engine, &key, TimeRange::new(5, 10)).await;
+        assert_eq!(got, None);
+        assert!(engine.contains(&key).await.unwrap());
+        assert_eq!(stats.insertions(), 1);
+
+        // flushed files clear the whole cache
+        let other = b"cpu,host=b#!~#value".to_vec();
+        assert_eq!(read(&engine, &other, TimeRange::unbound()).await, None);
+        let mut values = BTreeMap::new();
+        values.insert(other.clone(), float_values(&[(2, 2.0)]));
+        engine.flush(&values).await.unwrap();
+        let got = read(&engine, &other, TimeRange::unbound()).await;
+        assert_eq!(got, Some(float_values(&[(2, 2.0)])));
+        assert_eq!(stats.invalidations(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_engine_tracing_spans() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::dispatcher::set_default(&Dispatch::new(recorder.clone()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let mut values = BTreeMap::new();
+        values.insert(
+            b"cpu#!~#value".to_vec(),
+            float_values(&[(1, 1.0), (2, 2.0)]),
+        );
+        values.insert(b"mem#!~#free".to_vec(), float_values(&[(1, 1.0)]));
+        engine.flush(&values).await.unwrap();
+        let mut values = BTreeMap::new();
+        values.insert(b"cpu#!~#value".to_vec(), float_values(&[(3, 3.0)]));
+        engine.flush(&values).await.unwrap();
+
+        let flushes = recorder.spans("flush");
+        assert_eq!(flushes.len(), 2);
+        let flush = &flushes[0];
+        assert_eq!(flush["keys"], "2");
+        assert_eq!(flush["points"], "3");
+        assert_eq!(flush["files"], "1");
+        assert!(flush["bytes"].parse::<u64>().unwrap() > 0);
+        assert!(flush.contains_key("duration_ms"));
+
+        let files = engine.file_store().files().await;
+        let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+        Compactor::new()
+            .compact_full(engine.file_store(), files.as_slice())
+            .await
+            .unwrap();
+
+        let compactions = recorder.spans("compact");
+        assert_eq!(compactions.len(), 1);
+        let compact = &compactions[0];
+        assert_eq!(compact["inputs"], "2");
+        assert_eq!(compact["keys"], "2");
+        assert_eq!(compact["files"], "1");
+        assert!(compact["bytes"].parse::<u64>().unwrap() > 0);
+        assert!(compact.contains_key("duration_ms"));
+
+        let got = read(&engine, b"cpu#!~#value", TimeRange::new(2, 3)).await;
+        assert_eq!(got, Some(float_values(&[(2, 2.0), (3, 3.0)])));
+
+        let queries = recorder.spans("query");
+        assert_eq!(queries.len(), 1);
+        let query = &queries[0];
+        assert_eq!(query["key"], "cpu#!~#value");
+        assert_eq!(query["min"], "2");
+        assert_eq!(query["max"], "3");
+        assert_eq!(query["points"], "2");
+        assert!(query.contains_key("duration_ms"));
+    }
+
+    #[tokio::test]
+    async fn test_engine_bloom_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let options = ShardOptions {
+            bloom_fp_rate: Some(0.01),
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+
+        let mut values = BTreeMap::new();
+        for i in 0..100 {
+            let key = format!("cpu,host={}#!~#value", i).into_bytes();
+            values.insert(key, float_values(&[(i, i as f64)]));
+        }
+        engine.flush(&values).await.unwrap();
+
+        let view = engine.file_store().view().await;
+        let readers = view.readers();
+        assert_eq!(readers.len(), 1);
+        let absent: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("mem,host={}#!~#free", i).into_bytes())
+            .collect();
+        assert!(absent.iter().any(|x| !readers[0].maybe_contains(x)));
+
+        for (key, expected) in values.iter() {
+            assert!(readers[0].maybe_contains(key));
+            let got = read(&engine, key, TimeRange::unbound()).await;
+            assert_eq!(got.as_ref(), Some(expected));
+        }
+        for key in absent.iter() {
+            assert_eq!(read(&engine, key, TimeRange::unbound()).await, None);
+            assert!(engine
+                .file_store()
+                .reader_for(key)
+                .await
+                .unwrap()
+                .readers()
+                .is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_negative_cache_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let clock = SimulatedClock::new(0);
+        let options = ShardOptions {
+            negative_cache_ttl: Duration::from_secs(1),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+        let key = b"cpu,host=a#!~#value".to_vec();
+
+        assert_eq!(read(&engine, &key, TimeRange::unbound()).await, None);
+        assert_eq!(read(&engine, &key, TimeRange::unbound()).await, None);
+        let stats = engine.negative_cache_stats();
+        assert_eq!((stats.misses(), stats.hits()), (1, 1));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(read(&engine, &key, TimeRange::unbound()).await, None);
+        assert_eq!((stats.misses(), stats.hits()), (2, 1));
+        assert_eq!(stats.insertions(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_engine_read_semaphore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let read_semaphore = ReadSemaphore::new(2);
+        let options = ShardOptions {
+            negative_cache_ttl: Duration::ZERO,
+            read_semaphore: Some(read_semaphore.clone()),
+            ..Default::default()
+        };
+        let engine = Arc::new(
+            Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+                .await
+                .unwrap(),
+        );
+
+        let keys: Vec<Vec<u8>> = (0..8)
+            .map(|i| format!("cpu,host={}#!~#value", i).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            let mut values = BTreeMap::new();
+            values.insert(key.clone(), float_values(&[(i as i64, i as f64)]));
+            engine.flush(&values).await.unwrap();
+        }
+
+        let mut tasks = vec![];
+        for _ in 0..4 {
+            for (i, key) in keys.iter().enumerate() {
+                let engine = engine.clone();
+                let key = key.clone();
+                tasks.push(tokio::spawn(async move {
+                    let got = read(&engine, &key, TimeRange::unbound()).await;
+                    assert_eq!(got, Some(float_values(&[(i as i64, i as f64)])));
+                }));
+            }
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let stats = read_semaphore.stats();
+        assert!(stats.acquired() > 0);
+        assert!(stats.max_in_flight() <= 2);
+        assert_eq!(stats.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_engine_max_series_key_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let options = ShardOptions {
+            max_series_key_length: 16,
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+
+        // `cpu,host=abcdefg` is 16 bytes
+        let points = Point::parse_lines_with_time("cpu,host=abcdefg value=1 10", 0).unwrap();
+        let values = engine.map_points(points.as_slice()).unwrap();
+        engine.write_values(values).await.unwrap();
+
+        let points = Point::parse_lines_with_time(
+            "cpu,host=abcdefg value=2 20\ncpu,host=abcdefgh value=1 10",
+            0,
+        )
+        .unwrap();
+        let err = engine.map_points(points.as_slice()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KeyTooLong>(),
+            Some(&KeyTooLong {
+                measurement: b"cpu".to_vec(),
+                len: 17,
+                max: 16,
+            })
+        );
+
+        // the write path checks the keys before the WAL and the cache
+        let mut values = BTreeMap::new();
+        values.insert(
+            b"cpu,host=abcdefg#!~#value".to_vec(),
+            float_values(&[(20, 2.0)]),
+        );
+        values.insert(
+            b"cpu,host=abcdefgh#!~#value".to_vec(),
+            float_values(&[(10, 1.0)]),
+        );
+        let err = engine.write_values(values.clone()).await.unwrap_err();
+        assert!(err.is::<KeyTooLong>());
+        assert!(engine.write(values).unwrap_err().is::<KeyTooLong>());
+
+        assert_eq!(
+            engine.cache().keys(),
+            vec![b"cpu,host=abcdefg#!~#value".to_vec()]
+        );
+        engine.close_fast().await.unwrap();
+        let wal_op = StorageOperator::root(engine.wal_path().as_str()).unwrap();
+        let mut entries = Wal::replay(wal_op).await.unwrap();
+        let mut keys = vec![];
+        while let Some(entry) = entries.try_next().await.unwrap() {
+            if let WalEntry::Write(entry) = entry {
+                keys.push(entry.key);
+            }
+        }
+        assert_eq!(keys, vec![b"cpu,host=abcdefg#!~#value".to_vec()]);
+
+        engine.write_snapshot().await.unwrap();
+        let index = engine.index().read().await;
+        assert_eq!(index.series_count(), 1);
+        assert!(index.series_id(b"cpu,host=abcdefgh").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_engine_max_series_key_length_ceiling() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let options = ShardOptions {
+            max_series_key_length: MAX_KEY_LENGTH + 1,
+            ..Default::default()
+        };
+        assert!(
+            Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+                .await
+                .is_err()
+        );
+
+        let options = ShardOptions {
+            max_series_key_length: MAX_KEY_LENGTH,
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+
+        // beyond the default, within the raised limit
+        let line = format!(
+            "cpu,host={} value=1 10",
+            "a".repeat(DEFAULT_MAX_SERIES_KEY_LENGTH)
+        );
+        let points = Point::parse_lines_with_time(line.as_str(), 0).unwrap();
+        let values = engine.map_points(points.as_slice()).unwrap();
+        engine.write_values(values).await.unwrap();
+
+        // the TSM key must still fit a TSM file
+        let line = format!("cpu,host={} value=1 10", "a".repeat(MAX_KEY_LENGTH - 9));
+        let points = Point::parse_lines_with_time(line.as_str(), 0).unwrap();
+        let err = engine.map_points(points.as_slice()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KeyTooLong>().unwrap().max,
+            MAX_KEY_LENGTH
+        );
+        engine.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_engine_strict_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let lines = "cpu,host=a usage=0.5 10
+                     cpu,host=a usage=0.7,idle=0.3 20
+                     mem,host=a free=1i 10
+                     cpu,host=b,region=west usage=0.1 10
+                     cpu,host=b usage=2i 20";
+        let points = Point::parse_lines_with_time(lines, 0).unwrap();
+
+        // open mode writes everything, e.g. before the schema is declared
+        let engine = Engine::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert!(engine.check_schema(&points).iter().all(|x| x.is_ok()));
+        engine.write_points(&points[2..3]).await.unwrap();
+        engine.close().await.unwrap();
+
+        let schema = Arc::new(SchemaRegistry::new());
+        schema.declare_measurement(b"cpu", &[(b"usage", BLOCK_FLOAT64)], &[b"host"]);
+        let options = ShardOptions {
+            schema_mode: SchemaMode::Strict,
+            schema,
+            ..Default::default()
+        };
+        let engine = Engine::open_with_options(StorageOperator::root(&path).unwrap(), options)
+            .await
+            .unwrap();
+
+        let outcomes = engine.check_schema(&points);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(()),
+                Err(SchemaViolation::UnknownField {
+                    measurement: b"cpu".to_vec(),
+                    field: b"idle".to_vec(),
+                }),
+                Err(SchemaViolation::UnknownMeasurement {
+                    measurement: b"mem".to_vec(),
+                }),
+                Err(SchemaViolation::UnknownTagKey {
+                    measurement: b"cpu".to_vec(),
+                    tag_key: b"region".to_vec(),
+                }),
+                Err(SchemaViolation::FieldType {
+                    measurement: b"cpu".to_vec(),
+                    field: b"usage".to_vec(),
+                    expected: BLOCK_FLOAT64,
+                    actual: BLOCK_INTEGER,
+                }),
+            ]
+        );
+
+        // the batch is rejected as a whole, the conforming points write normally
+        let err = engine.map_points(&points).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SchemaViolation>(),
+            outcomes[1].as_ref().err()
+        );
+        let accepted: Vec<Point> = points
+            .iter()
+            .zip(outcomes.iter())
+            .filter(|(_, outcome)| outcome.is_ok())
+            .map(|(point, _)| point.clone())
+            .collect();
+        engine.write_points(&accepted).await.unwrap();
+
+        let values = read(&engine, b"cpu,host=a#!~#usage", TimeRange::unbound()).await;
+        assert_eq!(values, Some(float_values(&[(10, 0.5)])));
+        // the data written in open mode stays readable
+        let values = read(&engine, b"mem,host=a#!~#free", TimeRange::unbound()).await;
+        assert_eq!(values, Some(Values::Integer(vec![TimeValue::new(10, 1)])));
+        engine.close().await.unwrap();
+    }
+}
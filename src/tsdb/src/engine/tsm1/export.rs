@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+
+use common_arrow::arrow::array::{Array, Utf8Array};
+use common_arrow::arrow::chunk::Chunk;
+use common_arrow::arrow::compute::concatenate::concatenate;
+use common_arrow::arrow::datatypes::{DataType, Field, Schema};
+use common_arrow::arrow::io::parquet::write::{
+    transverse, CompressionOptions, Encoding, FileSink, Version, WriteOptions,
+};
+use common_arrow::{ArrayRef, Timestamps};
+use common_base::iterator::AsyncIterator;
+use futures::SinkExt;
+use influxdb_storage::opendal::Writer;
+use influxdb_storage::{path_join, StorageOperator};
+
+use crate::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::field_reader::TIMESTAMP_DATA_TYPE;
+use crate::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+
+/// EXPORT_ROW_GROUP_MAX_ROWS bounds the rows buffered for a row group. A key with
+/// more values is written as several row groups, so the memory used by an export
+/// does not depend on the size of the file.
+pub const EXPORT_ROW_GROUP_MAX_ROWS: usize = 1 << 20;
+
+/// ExportStats reports what `export_parquet` wrote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    /// files are the Parquet files written, one per block type of the TSM file.
+    pub files: Vec<String>,
+    pub keys: usize,
+    pub rows: usize,
+}
+
+/// export_parquet writes the values of the TSM file `reader` as Parquet files in
+/// the directory `op`, named after the block type, e.g. `float.parquet`. Keys are
+/// partitioned by block type since each file has a single value type; the columns
+/// are `series_key`, `time` and `value`.
+///
+/// Keys are walked in order and each key is written as its own row group, split
+/// at the first block boundary past `EXPORT_ROW_GROUP_MAX_ROWS` rows. Tombstones
+/// are not applied.
+pub async fn export_parquet(
+    reader: &dyn TSMReader,
+    op: &StorageOperator,
+) -> anyhow::Result<ExportStats> {
+    export_parquet_with_max_rows(reader, op, EXPORT_ROW_GROUP_MAX_ROWS).await
+}
+
+async fn export_parquet_with_max_rows(
+    reader: &dyn TSMReader,
+    op: &StorageOperator,
+    max_rows: usize,
+) -> anyhow::Result<ExportStats> {
+    let mut stats = ExportStats::default();
+    let mut sinks: BTreeMap<u8, ParquetSink> = BTreeMap::new();
+
+    let fr = reader.block_iterator_builder().await?;
+    let mut keys = reader.key_iterator().await?;
+    while let Some(key) = keys.try_next().await? {
+        let series_key = String::from_utf8(key.clone())
+            .map_err(|e| anyhow!("key {:?} is not valid utf8: {}", key, e))?;
+        let typ = reader.block_type(key.as_slice()).await?;
+        let sink = match sinks.entry(typ) {
+            std::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::btree_map::Entry::Vacant(e) => {
+                e.insert(ParquetSink::create(op, typ).await?)
+            }
+        };
+
+        let mut blocks = fr.arrow_blocks(key.as_slice()).await?;
+        let mut pending = vec![];
+        let mut pending_rows = 0;
+        while let Some((timestamps, values)) = blocks.try_next().await? {
+            pending_rows += timestamps.len();
+            pending.push((timestamps, values));
+            if pending_rows >= max_rows {
+                sink.write(series_key.as_str(), &pending).await?;
+                pending.clear();
+                pending_rows = 0;
+            }
+        }
+        if !pending.is_empty() {
+            sink.write(series_key.as_str(), &pending).await?;
+        }
+        stats.keys += 1;
+    }
+
+    for (_, mut sink) in sinks {
+        sink.sink.close().await?;
+        stats.rows += sink.rows;
+        stats.files.push(sink.path);
+    }
+    Ok(stats)
+}
+
+/// ParquetSink is the Parquet file of a block type.
+struct ParquetSink {
+    path: String,
+    sink: FileSink<'static, Writer>,
+    rows: usize,
+}
+
+impl ParquetSink {
+    async fn create(op: &StorageOperator, typ: u8) -> anyhow::Result<Self> {
+        let (name, data_type) = match typ {
+            BLOCK_FLOAT64 => ("float", DataType::Float64),
+            BLOCK_INTEGER => ("integer", DataType::Int64),
+            BLOCK_BOOLEAN => ("boolean", DataType::Boolean),
+            BLOCK_STRING => ("string", DataType::Utf8),
+            BLOCK_UNSIGNED => ("unsigned", DataType::UInt64),
+            _ => return Err(anyhow!("unknown block type: {}", typ)),
+        };
+
+        let schema = Schema::from(vec![
+            Field::new("series_key", DataType::Utf8, false),
+            Field::new("time", TIMESTAMP_DATA_TYPE, false),
+            Field::new("value", data_type, false),
+        ]);
+        let encodings = schema
+            .fields
+            .iter()
+            .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
+            .collect();
+        let options = WriteOptions {
+            write_statistics: true,
+            compression: CompressionOptions::Uncompressed,
+            version: Version::V2,
+            data_pagesize_limit: None,
+        };
+
+        let path = path_join(op.path(), format!("{}.parquet", name).as_str());
+        let writer = op.to_op(path.as_str()).writer().await?;
+        let sink = FileSink::try_new(writer, schema, encodings, options)?;
+        Ok(Self {
+            path,
+            sink,
+            rows: 0,
+        })
+    }
+
+    /// write writes the blocks of a key as a row group.
+    async fn write(
+        &mut self,
+        series_key: &str,
+        blocks: &[(Timestamps, ArrayRef)],
+    ) -> anyhow::Result<()> {
+        let timestamps: Vec<&dyn Array> = blocks.iter().map(|(x, _)| x as &dyn Array).collect();
+        let values: Vec<&dyn Array> = blocks.iter().map(|(_, x)| x.as_ref()).collect();
+        let timestamps = concatenate(timestamps.as_slice())?;
+        let values = concatenate(values.as_slice())?;
+
+        let rows = timestamps.len();
+        let keys = Utf8Array::<i32>::from_iter_values(std::iter::repeat_n(series_key, rows));
+        self.sink
+            .send(Chunk::new(vec![keys.boxed(), timestamps, values]))
+            .await?;
+        self.rows += rows;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use common_arrow::arrow::array::{Array, Utf8Array};
+    use common_arrow::arrow::io::parquet::read::{infer_schema, read_metadata, FileReader};
+    use common_arrow::{FloatValues, IntegerValues, StringValues, Timestamps};
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::export::{export_parquet, export_parquet_with_max_rows};
+    use crate::engine::tsm1::file_store::reader::tsm_reader::new_default_tsm_reader;
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    /// write_tsm writes a TSM file with a block per `Values` of each key.
+    async fn write_tsm(path: &Path, series: Vec<(&[u8], Vec<Values>)>) {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for (key, blocks) in series {
+            for values in blocks {
+                w.write(key, values).await.unwrap();
+            }
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+    }
+
+    /// read_parquet returns the row counts of the row groups of a Parquet file and
+    /// its rows as (series_key, time, value column) chunks.
+    fn read_parquet(path: &str) -> (Vec<usize>, Vec<Vec<Box<dyn Array>>>) {
+        let mut file = std::fs::File::open(path).unwrap();
+        let metadata = read_metadata(&mut file).unwrap();
+        let schema = infer_schema(&metadata).unwrap();
+        let row_groups: Vec<usize> = metadata.row_groups.iter().map(|x| x.num_rows()).collect();
+
+        let reader = FileReader::new(file, metadata.row_groups, schema, None, None, None);
+        let chunks = reader.map(|x| x.unwrap().into_arrays()).collect();
+        (row_groups, chunks)
+    }
+
+    #[tokio::test]
+    async fn test_export_parquet() {
+        let dir = tempfile::tempdir().unwrap();
+        let tsm_path = dir.as_ref().join("export.tsm");
+        let out = dir.as_ref().join("out");
+        std::fs::create_dir_all(&out).unwrap();
+
+        let floats = |range: std::ops::Range<i64>| {
+            Values::Float(range.map(|t| TimeValue::new(t, t as f64 / 2.0)).collect())
+        };
+        write_tsm(
+            &tsm_path,
+            vec![
+                (
+                    b"cpu,host=a#!~#idle",
+                    vec![floats(0..10), floats(10..20), floats(20..30)],
+                ),
+                (
+                    b"cpu,host=a#!~#state",
+                    vec![Values::String(
+                        (0..5)
+                            .map(|t| TimeValue::new(t, format!("s{}", t).into_bytes()))
+                            .collect(),
+                    )],
+                ),
+                (
+                    b"cpu,host=b#!~#count",
+                    vec![Values::Integer(
+                        (0..7).map(|t| TimeValue::new(t, -t)).collect(),
+                    )],
+                ),
+                (b"cpu,host=b#!~#idle", vec![floats(100..104)]),
+            ],
+        )
+        .await;
+
+        let reader =
+            new_default_tsm_reader(StorageOperator::root(tsm_path.to_str().unwrap()).unwrap())
+                .await
+                .unwrap();
+        let op = StorageOperator::root(format!("{}/", out.to_str().unwrap()).as_str()).unwrap();
+        let stats = export_parquet_with_max_rows(&reader, &op, 16)
+            .await
+            .unwrap();
+        assert_eq!(stats.keys, 4);
+        assert_eq!(stats.rows, 30 + 5 + 7 + 4);
+        let file = |name: &str| out.join(name).to_str().unwrap().to_string();
+        assert_eq!(
+            stats.files,
+            vec![
+                file("float.parquet"),
+                file("integer.parquet"),
+                file("string.parquet")
+            ]
+        );
+
+        // the 30 values of the first key are split at the first block boundary
+        // past 16 rows
+        let (row_groups, chunks) = read_parquet(&stats.files[0]);
+        assert_eq!(row_groups, vec![20, 10, 4]);
+        let mut rows = vec![];
+        for arrays in chunks.iter() {
+            let keys = arrays[0].as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
+            let times = arrays[1].as_any().downcast_ref::<Timestamps>().unwrap();
+            let values = arrays[2].as_any().downcast_ref::<FloatValues>().unwrap();
+            for i in 0..keys.len() {
+                rows.push((keys.value(i).to_string(), times.value(i), values.value(i)));
+            }
+        }
+        assert_eq!(rows.len(), 34);
+        assert_eq!(rows[0], ("cpu,host=a#!~#idle".to_string(), 0, 0.0));
+        assert_eq!(rows[29], ("cpu,host=a#!~#idle".to_string(), 29, 14.5));
+        assert_eq!(rows[33], ("cpu,host=b#!~#idle".to_string(), 103, 51.5));
+
+        let (row_groups, chunks) = read_parquet(&stats.files[1]);
+        assert_eq!(row_groups, vec![7]);
+        let values = chunks[0][2]
+            .as_any()
+            .downcast_ref::<IntegerValues>()
+            .unwrap();
+        assert_eq!(values.value(6), -6);
+
+        let (row_groups, chunks) = read_parquet(&stats.files[2]);
+        assert_eq!(row_groups, vec![5]);
+        let values = chunks[0][2]
+            .as_any()
+            .downcast_ref::<StringValues>()
+            .unwrap();
+        assert_eq!(values.value(3), "s3");
+
+        // a key is a single row group below the limit
+        let out = dir.as_ref().join("out2");
+        std::fs::create_dir_all(&out).unwrap();
+        let op = StorageOperator::root(format!("{}/", out.to_str().unwrap()).as_str()).unwrap();
+        let stats = export_parquet(&reader, &op).await.unwrap();
+        let (row_groups, _) = read_parquet(&stats.files[0]);
+        assert_eq!(row_groups, vec![30, 4]);
+    }
+}
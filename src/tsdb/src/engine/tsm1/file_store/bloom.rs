@@ -0,0 +1,190 @@
+use bytes::{Buf, BufMut};
+use influxdb_utils::hash::hash_key;
+
+/// DEFAULT_BLOOM_FP_RATE is the default false positive rate of the bloom filter
+/// of a TSM file.
+pub const DEFAULT_BLOOM_FP_RATE: f64 = 0.01;
+
+/// BLOOM_MAGIC follows the bloom filter section of a TSM file, just before the
+/// footer. Files without the section end their index with the size of a block
+/// instead, which is far below it.
+pub(crate) const BLOOM_MAGIC: u32 = 0xB10F_B10F;
+
+/// BLOOM_TRAILER_SIZE is the size of the length and magic following the section.
+pub(crate) const BLOOM_TRAILER_SIZE: usize = 4 + 4;
+
+/// MAX_HASHES bounds the number of hashes of a filter, reached for tiny rates.
+const MAX_HASHES: u32 = 30;
+
+/// BloomFilter tells whether a key may be in a set: a key inserted is always
+/// reported, a key which was not is reported with the false positive rate the
+/// filter was sized for.
+///
+/// Keys are hashed with xxhash64, the bit positions are derived from the two
+/// halves of the hash, so the filter is stable across processes and can be
+/// persisted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// m is the number of bits.
+    m: u64,
+    /// k is the number of bits set for each key.
+    k: u32,
+}
+
+impl BloomFilter {
+    /// new returns a filter sized for n keys with a false positive rate of fp_rate.
+    pub fn new(n: usize, fp_rate: f64) -> Self {
+        let n = n.max(1) as f64;
+        let fp_rate = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let m = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let k = ((m as f64 / n) * ln2).round().clamp(1.0, MAX_HASHES as f64) as u32;
+
+        Self {
+            bits: vec![0; m.div_ceil(64) as usize],
+            m,
+            k,
+        }
+    }
+
+    /// bits returns the number of bits of the filter.
+    pub fn bits(&self) -> u64 {
+        self.m
+    }
+
+    /// hashes returns the number of bits set for each key.
+    pub fn hashes(&self) -> u32 {
+        self.k
+    }
+
+    /// insert adds key to the filter.
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash_key(key))
+    }
+
+    /// maybe_contains returns false if key was definitely not inserted.
+    pub fn maybe_contains(&self, key: &[u8]) -> bool {
+        let h = hash_key(key);
+        self.positions(h)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub(crate) fn insert_hash(&mut self, h: u64) {
+        let positions: Vec<u64> = self.positions(h).collect();
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// positions returns the bits of a key hashed to h, by double hashing.
+    fn positions(&self, h: u64) -> impl Iterator<Item = u64> + '_ {
+        let h1 = h & 0xFFFF_FFFF;
+        let h2 = (h >> 32) | 1;
+        (0..self.k as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.m)
+    }
+
+    /// encode returns the filter as `| k(4B) | m(8B) | bits | crc32(4B) |`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 8 + self.bits.len() * 8 + 4);
+        buf.put_u32(self.k);
+        buf.put_u64(self.m);
+        for word in self.bits.iter() {
+            buf.put_u64(*word);
+        }
+        let checksum = crc32fast::hash(buf.as_slice());
+        buf.put_u32(checksum);
+        buf
+    }
+
+    /// decode returns the filter encoded by `encode`.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 4 + 8 + 4 {
+            return Err(anyhow!("bloom filter of {} bytes is too short", data.len()));
+        }
+
+        let (body, mut checksum) = data.split_at(data.len() - 4);
+        if crc32fast::hash(body) != checksum.get_u32() {
+            return Err(anyhow!("bloom filter checksum mismatch"));
+        }
+
+        let mut body = body;
+        let k = body.get_u32();
+        let m = body.get_u64();
+        if k == 0 || k > MAX_HASHES || m == 0 || body.len() as u64 != m.div_ceil(64) * 8 {
+            return Err(anyhow!(
+                "invalid bloom filter of {} bits and {} hashes",
+                m,
+                k
+            ));
+        }
+
+        let mut bits = Vec::with_capacity(body.len() / 8);
+        while body.has_remaining() {
+            bits.push(body.get_u64());
+        }
+        Ok(Self { bits, m, k })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::file_store::bloom::BloomFilter;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("cpu,host=server-{}#!~#value", i).into_bytes()
+    }
+
+    #[test]
+    fn test_bloom_filter_no_false_negatives() {
+        let n = 10_000;
+        let mut filter = BloomFilter::new(n, 0.01);
+        for i in 0..n {
+            filter.insert(key(i).as_slice());
+        }
+
+        for i in 0..n {
+            assert!(filter.maybe_contains(key(i).as_slice()), "key {}", i);
+        }
+    }
+
+    #[test]
+    fn test_bloom_filter_false_positive_rate() {
+        for fp_rate in [0.1, 0.01, 0.001] {
+            let n = 10_000;
+            let mut filter = BloomFilter::new(n, fp_rate);
+            for i in 0..n {
+                filter.insert(key(i).as_slice());
+            }
+
+            let probes = 100_000;
+            let false_positives = (n..n + probes)
+                .filter(|i| filter.maybe_contains(key(*i).as_slice()))
+                .count();
+            let rate = false_positives as f64 / probes as f64;
+            assert!(
+                rate < fp_rate * 2.0,
+                "false positive rate {} for a target of {}",
+                rate,
+                fp_rate
+            );
+        }
+    }
+
+    #[test]
+    fn test_bloom_filter_encode() {
+        let mut filter = BloomFilter::new(100, 0.01);
+        for i in 0..100 {
+            filter.insert(key(i).as_slice());
+        }
+
+        let mut data = filter.encode();
+        let decoded = BloomFilter::decode(data.as_slice()).unwrap();
+        assert_eq!(decoded, filter);
+
+        data[20] ^= 0xFF;
+        assert!(BloomFilter::decode(data.as_slice()).is_err());
+        assert!(BloomFilter::decode(&data[..10]).is_err());
+    }
+}
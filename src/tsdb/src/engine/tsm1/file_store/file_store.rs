@@ -0,0 +1,1610 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use common_base::iterator::AsyncIterator;
+use futures::TryStreamExt;
+use influxdb_storage::{path_join, StorageOperator};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::engine::tsm1::aggregate::Aggregate;
+use crate::engine::tsm1::block::decoder::decode_block_soa;
+use crate::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use crate::engine::tsm1::file_store::reader::index_reader::KeyIterator;
+use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
+use crate::engine::tsm1::file_store::stat::FileStat;
+use crate::engine::tsm1::file_store::TimeRange;
+use crate::engine::tsm1::shard_lock::Fence;
+use crate::engine::tsm1::value::{Array, SumMode, Values};
+use crate::engine::{BAD_TSM_FILE_EXTENSION, COMPACTION_TEMP_EXTENSION, TSM_FILE_EXTENSION};
+
+/// MANIFEST_FILE_NAME is the object listing the live TSM files of a store on a
+/// backend without atomic rename, see `FileStore::replace`.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// COMMIT_FILE_EXTENSION is the extension of the record listing the new files
+/// a `replace` renames, see `FileStore::rename_all`.
+const COMMIT_FILE_EXTENSION: &str = "commit";
+
+/// tsm_file_name returns the file name of a TSM file for the generation and sequence.
+pub fn tsm_file_name(generation: u64, sequence: u64) -> String {
+    format!("{:09}-{:09}.{}", generation, sequence, TSM_FILE_EXTENSION)
+}
+
+/// parse_tsm_file_name returns the generation and sequence of a TSM file name
+/// formatted by `tsm_file_name`.
+pub fn parse_tsm_file_name(name: &str) -> anyhow::Result<(u64, u64)> {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    let stem = base
+        .strip_suffix(TSM_FILE_EXTENSION)
+        .and_then(|x| x.strip_suffix('.'))
+        .ok_or_else(|| anyhow!("file {} is named incorrectly", name))?;
+
+    let (generation, sequence) = stem
+        .split_once('-')
+        .ok_or_else(|| anyhow!("file {} is named incorrectly", name))?;
+    let generation = generation
+        .parse::<u64>()
+        .map_err(|e| anyhow!("file {} is named incorrectly: {}", name, e))?;
+    let sequence = sequence
+        .parse::<u64>()
+        .map_err(|e| anyhow!("file {} is named incorrectly: {}", name, e))?;
+
+    Ok((generation, sequence))
+}
+
+struct TSMFile {
+    generation: u64,
+    sequence: u64,
+    reader: Box<dyn TSMReader>,
+}
+
+impl TSMFile {
+    async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        let (generation, sequence) = parse_tsm_file_name(op.path())?;
+        let reader = new_default_tsm_reader(op).await?;
+        Ok(Self {
+            generation,
+            sequence,
+            reader: Box::new(reader),
+        })
+    }
+}
+
+/// FileStore is the set of TSM files of a shard, ordered from the oldest to the
+/// newest generation.
+pub struct FileStore {
+    op: StorageOperator,
+
+    files: RwLock<Vec<Arc<TSMFile>>>,
+    /// pending_removal are the files replaced while still held by a `FileStoreView`.
+    pending_removal: Mutex<Vec<Arc<TSMFile>>>,
+
+    /// current_generation is the largest generation in use.
+    current_generation: AtomicU64,
+
+    /// fence is checked before files are replaced, see `AdvisoryLock`.
+    fence: Option<Fence>,
+
+    /// manifest lists the live files on a backend without atomic rename, see
+    /// `replace`.
+    manifest: Option<StorageOperator>,
+}
+
+impl FileStore {
+    /// open loads the TSM files of the directory. Files failing to open are
+    /// renamed with the `.bad` extension and ignored, orphaned `.tmp` files are
+    /// deleted.
+    ///
+    /// On a backend without atomic rename only the files listed in the manifest
+    /// are loaded, the others are left by an interrupted `replace` and deleted
+    /// with their sidecars. A store without manifest adopts its TSM files.
+    pub async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        let op = if op.path().ends_with('/') {
+            op
+        } else {
+            op.to_op(format!("{}/", op.path()).as_str())
+        };
+        op.create_dir().await?;
+
+        let manifest_op = op.to_op(path_join(op.path(), MANIFEST_FILE_NAME).as_str());
+        let manifest = if op.rename_is_atomic() {
+            // a manifest left by an earlier open without atomic rename is stale
+            if manifest_op.exist().await? {
+                manifest_op.delete().await?;
+            }
+            None
+        } else {
+            Some(manifest_op)
+        };
+
+        let store = Self {
+            op,
+            files: RwLock::new(vec![]),
+            pending_removal: Mutex::new(vec![]),
+            current_generation: AtomicU64::new(0),
+            fence: None,
+            manifest,
+        };
+        store.load().await?;
+
+        Ok(store)
+    }
+
+    /// load opens the live TSM files of the directory, see `open`.
+    async fn load(&self) -> anyhow::Result<()> {
+        self.roll_forward().await?;
+
+        let live = match &self.manifest {
+            Some(manifest) => read_manifest(manifest).await?,
+            None => None,
+        };
+
+        let tmp_suffix = format!(".{}", COMPACTION_TEMP_EXTENSION);
+        let bad_suffix = format!(".{}", BAD_TSM_FILE_EXTENSION);
+        let tsm_suffix = format!(".{}", TSM_FILE_EXTENSION);
+
+        let mut names = vec![];
+        let mut lister = self.op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            let file_op = self.op.to_op(path_join(self.op.path(), de.name()).as_str());
+
+            // Remove temporary files left by an interrupted compaction or replace.
+            if de.name().ends_with(tmp_suffix.as_str()) {
+                file_op.delete().await?;
+                continue;
+            }
+
+            // Remove the files not committed to the manifest, or left by a purge.
+            if let (Some(live), Some(tsm_name)) = (&live, tsm_file_of(de.name())) {
+                if !live.contains(&tsm_name) && !de.name().ends_with(bad_suffix.as_str()) {
+                    file_op.delete().await?;
+                    continue;
+                }
+            }
+
+            if de.name().ends_with(tsm_suffix.as_str()) {
+                names.push(de.name().to_string());
+            }
+        }
+
+        let mut files = Vec::with_capacity(names.len());
+        for name in names {
+            let file_op = self
+                .op
+                .to_op(path_join(self.op.path(), name.as_str()).as_str());
+            match TSMFile::open(file_op.clone()).await {
+                Ok(file) => files.push(Arc::new(file)),
+                Err(_) => {
+                    let bad_op = file_op.to_tmp(BAD_TSM_FILE_EXTENSION);
+                    self.move_object(&file_op, bad_op.path()).await?;
+                }
+            }
+        }
+        files.sort_by_key(|x| (x.generation, x.sequence));
+
+        // commit the loaded files if the manifest is missing or lists bad ones
+        if live.map(|x| x.len()) != Some(files.len()) {
+            self.write_manifest(&files).await?;
+        }
+
+        let current_generation = files.last().map(|x| x.generation).unwrap_or_default();
+        self.current_generation
+            .store(current_generation, Ordering::Relaxed);
+        *self.files.write().await = files;
+
+        Ok(())
+    }
+
+    /// roll_forward finishes the renames listed in the commit records left by an
+    /// interrupted `replace`, see `rename_all`.
+    async fn roll_forward(&self) -> anyhow::Result<()> {
+        let commit_suffix = format!(".{}", COMMIT_FILE_EXTENSION);
+
+        let mut records = vec![];
+        let mut lister = self.op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            if de.name().ends_with(commit_suffix.as_str()) {
+                records.push(de.name().to_string());
+            }
+        }
+
+        for record in records {
+            let record_op = self
+                .op
+                .to_op(path_join(self.op.path(), record.as_str()).as_str());
+            let data = record_op.operator().read(record_op.path()).await?;
+            let data = String::from_utf8(data)
+                .map_err(|e| anyhow!("commit record {} is corrupt: {}", record_op.path(), e))?;
+            for name in data.lines() {
+                let path = path_join(self.op.path(), name);
+                let tmp_op = self
+                    .op
+                    .to_op(path.as_str())
+                    .to_tmp(COMPACTION_TEMP_EXTENSION);
+                if tmp_op.exist().await? {
+                    tmp_op.rename(path.as_str()).await?;
+                }
+            }
+            record_op.delete().await?;
+        }
+
+        Ok(())
+    }
+
+    /// with_fence rejects `replace` once the shard lock identified by `fence` is
+    /// taken over by another owner.
+    pub fn with_fence(mut self, fence: Option<Fence>) -> Self {
+        self.fence = fence;
+        self
+    }
+
+    /// path returns the directory of the store.
+    pub fn path(&self) -> &str {
+        self.op.path()
+    }
+
+    /// count returns the number of TSM files in the store.
+    pub async fn count(&self) -> usize {
+        self.files.read().await.len()
+    }
+
+    /// files returns the paths of the TSM files, oldest first.
+    pub async fn files(&self) -> Vec<String> {
+        let files = self.files.read().await;
+        files.iter().map(|x| x.reader.path().to_string()).collect()
+    }
+
+    /// current_generation returns the largest generation in use.
+    pub fn current_generation(&self) -> u64 {
+        self.current_generation.load(Ordering::Relaxed)
+    }
+
+    /// next_generation reserves and returns the generation for a new TSM file.
+    pub fn next_generation(&self) -> u64 {
+        self.current_generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// rename_is_atomic returns true if new files can be written under a `.tmp`
+    /// name and renamed by `replace`, false if they must be written under their
+    /// final name, see `StorageOperator::rename_is_atomic`.
+    pub fn rename_is_atomic(&self) -> bool {
+        self.op.rename_is_atomic()
+    }
+
+    /// tsm_path returns the path of a TSM file of the store.
+    pub fn tsm_path(&self, generation: u64, sequence: u64) -> String {
+        path_join(self.op.path(), tsm_file_name(generation, sequence).as_str())
+    }
+
+    /// read returns the values of key within time_range merged across all files,
+    /// see `FileStoreView::read`.
+    pub async fn read(&self, key: &[u8], time_range: TimeRange) -> anyhow::Result<Option<Values>> {
+        self.view().await.read(key, time_range).await
+    }
+
+    /// reader_for returns the view of the files containing key, oldest first, see
+    /// `FileStoreView::reader_for`.
+    pub async fn reader_for(&self, key: &[u8]) -> anyhow::Result<FileStoreView> {
+        self.view().await.reader_for(key).await
+    }
+
+    /// time_range returns the time range covered by the files, None if there are
+    /// none.
+    pub async fn time_range(&self) -> Option<TimeRange> {
+        self.view().await.time_range().await
+    }
+
+    /// view returns the current set of files. The files of a view stay readable
+    /// until it is dropped, even if they are replaced in the store meanwhile.
+    pub async fn view(&self) -> FileStoreView {
+        let files = self.files.read().await;
+        FileStoreView {
+            files: files.clone(),
+        }
+    }
+
+    /// stats returns the stats of the TSM files, oldest first.
+    pub async fn stats(&self) -> anyhow::Result<Vec<FileStat>> {
+        let files = self.files.read().await;
+        let mut stats = Vec::with_capacity(files.len());
+        for file in files.iter() {
+            stats.push(file.reader.stats().await?);
+        }
+        Ok(stats)
+    }
+
+    /// delete_range removes the values of keys between min and max from all files,
+    /// see `TSMReader::delete_range`.
+    pub async fn delete_range(&self, keys: &[&[u8]], min: i64, max: i64) -> anyhow::Result<()> {
+        let files = self.files.read().await;
+        for file in files.iter() {
+            let mut keys = keys.to_vec();
+            file.reader
+                .delete_range(keys.as_mut_slice(), min, max)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// block_type returns the block type of key in the newest file containing it.
+    pub async fn block_type(&self, key: &[u8]) -> anyhow::Result<Option<u8>> {
+        let files = self.files.read().await;
+        for file in files.iter().rev() {
+            if file.reader.contains(key).await? {
+                return file.reader.block_type(key).await.map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// keys returns an iterator over the distinct keys of all files in ascending order.
+    pub async fn keys(&self) -> anyhow::Result<KeysIterator> {
+        self.keys_after(0).await
+    }
+
+    /// keys_after returns an iterator over the distinct keys of the files whose
+    /// generation is greater than `generation`, in ascending order.
+    pub async fn keys_after(&self, generation: u64) -> anyhow::Result<KeysIterator> {
+        let files = self.files.read().await;
+
+        let mut itrs = Vec::with_capacity(files.len());
+        for file in files.iter().filter(|x| x.generation > generation) {
+            itrs.push(file.reader.key_iterator().await?);
+        }
+
+        KeysIterator::new(itrs).await
+    }
+
+    /// replace swaps the `old` TSM files for the `new` ones, e.g. after a compaction.
+    ///
+    /// New files named with the `.tmp` extension are renamed to their final name
+    /// first, all of them or none, see `rename_all`. All new files are opened before the store is changed so that a failure
+    /// leaves the store untouched. The file list is swapped under the write lock,
+    /// so readers never observe a removed file; iterators created before the swap
+    /// keep their own handles and can finish. Old files still held by a view are
+    /// removed by a later `purge`.
+    ///
+    /// On a backend without atomic rename, see `rename_is_atomic`, new files are
+    /// written under their final name, which is unique as its generation is
+    /// reserved, and `.tmp` files are rejected. They only become live when the
+    /// manifest listing the new set of files is written. That single write is
+    /// the commit point: a crash before it reopens the old files and `open`
+    /// deletes the unlisted new ones, a crash after it reopens the new files.
+    pub async fn replace(&self, old: &[&str], new: &[&str]) -> anyhow::Result<()> {
+        if let Some(fence) = &self.fence {
+            fence.check().await?;
+        }
+
+        let tmp_suffix = format!(".{}", COMPACTION_TEMP_EXTENSION);
+        if !self.rename_is_atomic() {
+            if let Some(path) = new.iter().find(|x| x.ends_with(tmp_suffix.as_str())) {
+                return Err(anyhow!(
+                    "cannot install {}: rename is not atomic on the backend of {}",
+                    path,
+                    self.op.path()
+                ));
+            }
+        }
+
+        let mut renames = vec![];
+        let mut paths = Vec::with_capacity(new.len());
+        for path in new {
+            match path.strip_suffix(tmp_suffix.as_str()) {
+                Some(final_path) => {
+                    renames.push((*path, final_path));
+                    paths.push(final_path);
+                }
+                None => paths.push(*path),
+            }
+        }
+        self.rename_all(renames.as_slice()).await?;
+
+        let mut new_files = Vec::with_capacity(new.len());
+        for path in paths {
+            new_files.push(Arc::new(TSMFile::open(self.op.to_op(path)).await?));
+        }
+
+        let mut files = self.files.write().await;
+
+        let (mut removed, mut retained): (Vec<_>, Vec<_>) = files
+            .iter()
+            .cloned()
+            .partition(|x| old.iter().any(|path| *path == x.reader.path()));
+        retained.extend(new_files);
+        retained.sort_by_key(|x| (x.generation, x.sequence));
+
+        self.write_manifest(&retained).await?;
+
+        if let Some(file) = retained.last() {
+            self.current_generation
+                .fetch_max(file.generation, Ordering::Relaxed);
+        }
+        *files = retained;
+        drop(files);
+
+        self.pending_removal.lock().await.append(&mut removed);
+        self.purge().await
+    }
+
+    /// rename_all renames the `.tmp` files of renames to their final names as a
+    /// whole. Several files are first listed in a commit record, so that a crash
+    /// between the renames is rolled forward by the next `open` instead of leaving
+    /// only some of the files renamed.
+    async fn rename_all(&self, renames: &[(&str, &str)]) -> anyhow::Result<()> {
+        let record_op = match renames {
+            [] => return Ok(()),
+            [_] => None,
+            [(_, first), ..] => {
+                let stem = first.strip_suffix(TSM_FILE_EXTENSION).unwrap_or(first);
+                let record_op = self
+                    .op
+                    .to_op(format!("{}{}", stem, COMMIT_FILE_EXTENSION).as_str());
+
+                let mut data = String::new();
+                for (_, path) in renames {
+                    data.push_str(path.rsplit('/').next().unwrap_or(path));
+                    data.push('\n');
+                }
+                record_op.write_atomic(data.into_bytes()).await?;
+                Some(record_op)
+            }
+        };
+
+        for (tmp_path, path) in renames {
+            self.op.to_op(tmp_path).rename(path).await?;
+        }
+
+        if let Some(record_op) = record_op {
+            record_op.delete().await?;
+        }
+        Ok(())
+    }
+
+    /// purge removes the replaced files which are no longer held by a view.
+    pub async fn purge(&self) -> anyhow::Result<()> {
+        let mut pending = self.pending_removal.lock().await;
+
+        let mut retained = vec![];
+        for file in pending.drain(..) {
+            match Arc::try_unwrap(file) {
+                Ok(mut file) => {
+                    file.reader.close().await?;
+                    file.reader.remove().await?;
+                }
+                Err(file) => retained.push(file),
+            }
+        }
+        *pending = retained;
+
+        Ok(())
+    }
+
+    /// write_manifest commits files as the live files of the store, it does
+    /// nothing on a backend with atomic rename.
+    async fn write_manifest(&self, files: &[Arc<TSMFile>]) -> anyhow::Result<()> {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return Ok(()),
+        };
+
+        let mut data = String::new();
+        for file in files {
+            let path = file.reader.path();
+            data.push_str(path.rsplit('/').next().unwrap_or(path));
+            data.push('\n');
+        }
+
+        manifest.write_atomic(data.into_bytes()).await?;
+        Ok(())
+    }
+
+    /// move_object moves the object of from to the path to. Without atomic rename
+    /// it is copied then deleted, which is not atomic, so only objects which are
+    /// not live are moved, e.g. a corrupt file to its `.bad` name.
+    async fn move_object(&self, from: &StorageOperator, to: &str) -> anyhow::Result<()> {
+        if from.rename_is_atomic() {
+            from.rename(to).await?;
+            return Ok(());
+        }
+
+        let operator = from.operator();
+        let data = operator.read(from.path()).await?;
+        operator.write(to, data).await?;
+        from.delete().await?;
+        Ok(())
+    }
+}
+
+/// read_manifest returns the names of the TSM files listed in the manifest, None
+/// if it was never written.
+async fn read_manifest(op: &StorageOperator) -> anyhow::Result<Option<HashSet<String>>> {
+    if !op.exist().await? {
+        return Ok(None);
+    }
+
+    let data = op.operator().read(op.path()).await?;
+    let data =
+        String::from_utf8(data).map_err(|e| anyhow!("manifest {} is corrupt: {}", op.path(), e))?;
+    Ok(Some(data.lines().map(|x| x.to_string()).collect()))
+}
+
+/// tsm_file_of returns the name of the TSM file a file of the store belongs to,
+/// e.g. the TSM file itself, its sidecars or its tombstone, None for other files.
+fn tsm_file_of(name: &str) -> Option<String> {
+    let (stem, _) = name.split_once('.')?;
+    let tsm_name = format!("{}.{}", stem, TSM_FILE_EXTENSION);
+    parse_tsm_file_name(tsm_name.as_str())
+        .ok()
+        .map(|_| tsm_name)
+}
+
+/// FileStoreView is a consistent set of TSM files of a `FileStore`, unaffected by
+/// files added or replaced after it was taken.
+pub struct FileStoreView {
+    files: Vec<Arc<TSMFile>>,
+}
+
+impl FileStoreView {
+    /// files returns the paths of the TSM files of the view, oldest first.
+    pub fn files(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|x| x.reader.path().to_string())
+            .collect()
+    }
+
+    /// select returns the view of the files `paths`, which must all be part of
+    /// this view.
+    pub fn select(&self, paths: &[&str]) -> anyhow::Result<FileStoreView> {
+        let mut files = Vec::with_capacity(paths.len());
+        for file in self.files.iter() {
+            if paths.contains(&file.reader.path()) {
+                files.push(file.clone());
+            }
+        }
+        if files.len() != paths.len() {
+            return Err(anyhow!("tsm files {:?} not in the file store", paths));
+        }
+        Ok(FileStoreView { files })
+    }
+
+    /// readers returns the readers of the files, oldest first.
+    pub fn readers(&self) -> Vec<&dyn TSMReader> {
+        self.files.iter().map(|x| x.reader.as_ref()).collect()
+    }
+
+    /// reader_for returns the view of the files containing key, oldest first. Its
+    /// readers stay open while it is held, even if the files are replaced.
+    pub async fn reader_for(&self, key: &[u8]) -> anyhow::Result<FileStoreView> {
+        let mut files = vec![];
+        for file in self.files.iter() {
+            if file.reader.contains(key).await? {
+                files.push(file.clone());
+            }
+        }
+        Ok(FileStoreView { files })
+    }
+
+    /// contains returns true if any file of the view holds key.
+    pub async fn contains(&self, key: &[u8]) -> anyhow::Result<bool> {
+        for file in self.files.iter() {
+            if file.reader.contains(key).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// time_range returns the time range covered by the files, None if there are
+    /// none.
+    pub async fn time_range(&self) -> Option<TimeRange> {
+        let mut time_range: Option<TimeRange> = None;
+        for file in self.files.iter() {
+            let TimeRange { min, max } = file.reader.time_range().await;
+            time_range = Some(match time_range {
+                Some(x) => TimeRange::new(x.min.min(min), x.max.max(max)),
+                None => TimeRange::new(min, max),
+            });
+        }
+        time_range
+    }
+
+    /// keys returns an iterator over the distinct keys of the files in ascending order.
+    pub async fn keys(&self) -> anyhow::Result<KeysIterator> {
+        let mut itrs = Vec::with_capacity(self.files.len());
+        for file in self.files.iter() {
+            itrs.push(file.reader.key_iterator().await?);
+        }
+
+        KeysIterator::new(itrs).await
+    }
+
+    /// read returns the values of key within time_range merged across all files.
+    /// Values are sorted by timestamp and, if several files hold a value for the
+    /// same timestamp, the one of the newest file wins. Returns None if no file
+    /// holds values for the key in the range.
+    ///
+    /// The newest file wins because the values of the files are appended oldest
+    /// first and `Values::deduplicate` keeps the last value of a timestamp, which
+    /// relies on its sort being stable.
+    pub async fn read(&self, key: &[u8], time_range: TimeRange) -> anyhow::Result<Option<Values>> {
+        let TimeRange { min, max } = time_range;
+
+        let mut values: Option<Values> = None;
+        for file in self.files.iter() {
+            if !file.reader.overlaps_time_range(min, max).await {
+                continue;
+            }
+
+            let mut entries = IndexEntries::default();
+            file.reader.read_entries(key, &mut entries).await?;
+            if entries.is_empty() {
+                continue;
+            }
+
+            let blocks: Vec<_> = entries
+                .iter()
+                .filter(|x| x.min_time <= max && x.max_time >= min)
+                .collect();
+            let tombstones = file.reader.tombstone_range(key).await;
+            let file_values = read_blocks(file, entries.typ, &blocks, &tombstones).await?;
+            append_file_values(&mut values, file_values)?;
+        }
+
+        Ok(values.and_then(|values| include_values(values, min, max)))
+    }
+
+    /// aggregate returns the aggregate of the values of key within time_range, the
+    /// values `read` returns, and the values it did not add. Returns None if no
+    /// file holds blocks of the key in the range.
+    ///
+    /// Blocks within the range which no other block, tombstone nor timestamp of
+    /// `others` overlaps hold the only values of their time range: they are
+    /// decoded as flat arrays straight into the aggregate. The values of the other
+    /// blocks are merged as `read` does and returned, for the caller to add once
+    /// combined with its own, e.g. the values of the cache at `others`, which must
+    /// be sorted.
+    pub async fn aggregate(
+        &self,
+        key: &[u8],
+        time_range: TimeRange,
+        others: &[i64],
+        mode: SumMode,
+    ) -> anyhow::Result<Option<(Aggregate, Option<Values>)>> {
+        let TimeRange { min, max } = time_range;
+
+        // the blocks in the range, and the tombstones, of each file
+        let mut typ = None;
+        let mut files = Vec::with_capacity(self.files.len());
+        for file in self.files.iter() {
+            if !file.reader.overlaps_time_range(min, max).await {
+                continue;
+            }
+
+            let mut entries = IndexEntries::default();
+            file.reader.read_entries(key, &mut entries).await?;
+            if entries.is_empty() {
+                continue;
+            }
+            if typ.is_some_and(|typ| typ != entries.typ) {
+                return Err(anyhow!("field type conflict across tsm files"));
+            }
+            typ = Some(entries.typ);
+
+            let blocks: Vec<_> = entries
+                .iter()
+                .filter(|x| x.min_time <= max && x.max_time >= min)
+                .collect();
+            let tombstones = file.reader.tombstone_range(key).await;
+            files.push((file, blocks, tombstones));
+        }
+        let typ = match typ {
+            Some(typ) => typ,
+            None => return Ok(None),
+        };
+
+        // A block overlaps another one if it starts before the end of the blocks
+        // starting before it, or ends after the start of the next one.
+        let mut starts: Vec<_> = files
+            .iter()
+            .flat_map(|(_, blocks, _)| blocks.iter().map(|x| (x.min_time, x.max_time)))
+            .collect();
+        starts.sort_unstable();
+        let mut overlapped = HashSet::new();
+        let mut end = None;
+        for (i, (block_min, block_max)) in starts.iter().enumerate() {
+            if end.is_some_and(|end| end >= *block_min)
+                || starts.get(i + 1).is_some_and(|x| x.0 <= *block_max)
+            {
+                overlapped.insert((*block_min, *block_max));
+            }
+            end = Some(end.map_or(*block_max, |end: i64| end.max(*block_max)));
+        }
+
+        let mut aggregate = Aggregate::new(typ, mode)?;
+        let mut soa = aggregate.new_soa();
+        let mut times = vec![];
+        let mut block = vec![];
+        let mut values: Option<Values> = None;
+        for (file, blocks, tombstones) in files {
+            let (exclusive, rest): (Vec<_>, Vec<_>) = blocks.into_iter().partition(|x| {
+                x.min_time >= min
+                    && x.max_time <= max
+                    && !overlapped.contains(&(x.min_time, x.max_time))
+                    && !tombstones
+                        .iter()
+                        .any(|t| t.min <= x.max_time && t.max >= x.min_time)
+                    && !contains_time(others, x.min_time, x.max_time)
+            });
+
+            for entry in exclusive.iter() {
+                block.clear();
+                times.clear();
+                soa.clear();
+                file.reader.read_raw_block_at(entry, &mut block).await?;
+                decode_block_soa(typ, block.as_slice(), &mut times, &mut soa)?;
+                aggregate.add_soa(&soa)?;
+            }
+
+            let file_values = read_blocks(file, typ, &rest, &tombstones).await?;
+            append_file_values(&mut values, file_values)?;
+        }
+
+        let values = values.and_then(|values| include_values(values, min, max));
+        Ok(Some((aggregate, values)))
+    }
+}
+
+/// read_blocks returns the values of the blocks of file, deduplicated and without
+/// the values deleted by the tombstones of the file.
+async fn read_blocks(
+    file: &TSMFile,
+    typ: u8,
+    blocks: &[IndexEntry],
+    tombstones: &[TimeRange],
+) -> anyhow::Result<Values> {
+    let mut values = new_values(typ)?;
+    for entry in blocks.iter() {
+        file.reader.read_block_at(entry, &mut values).await?;
+    }
+
+    // Tombstones only apply to the values of the file they belong to.
+    values.deduplicate();
+    for tombstone in tombstones.iter() {
+        values.exclude(tombstone.min, tombstone.max);
+    }
+    Ok(values)
+}
+
+/// append_file_values appends the values read from a file to the values of the
+/// older files.
+fn append_file_values(values: &mut Option<Values>, file_values: Values) -> anyhow::Result<()> {
+    if file_values.len() == 0 {
+        return Ok(());
+    }
+    match values.as_mut() {
+        Some(values) => append_values(values, file_values)?,
+        None => *values = Some(file_values),
+    }
+    Ok(())
+}
+
+/// include_values merges the values appended across files, the ones of the newest
+/// file winning, and keeps the ones within [min, max], None if there are none.
+fn include_values(mut values: Values, min: i64, max: i64) -> Option<Values> {
+    values.deduplicate();
+    values.include(min, max);
+    if values.len() > 0 {
+        Some(values)
+    } else {
+        None
+    }
+}
+
+/// contains_time returns true if the sorted timestamps hold one within [min, max].
+fn contains_time(timestamps: &[i64], min: i64, max: i64) -> bool {
+    let i = timestamps.partition_point(|x| *x < min);
+    timestamps.get(i).is_some_and(|x| *x <= max)
+}
+
+/// new_values returns empty values of the block type typ.
+pub fn new_values(typ: u8) -> anyhow::Result<Values> {
+    match typ {
+        BLOCK_FLOAT64 => Ok(Values::Float(vec![])),
+        BLOCK_INTEGER => Ok(Values::Integer(vec![])),
+        BLOCK_BOOLEAN => Ok(Values::Bool(vec![])),
+        BLOCK_STRING => Ok(Values::String(vec![])),
+        BLOCK_UNSIGNED => Ok(Values::Unsigned(vec![])),
+        _ => Err(anyhow!("unknown block type: {}", typ)),
+    }
+}
+
+pub(crate) fn append_values(dst: &mut Values, src: Values) -> anyhow::Result<()> {
+    match (dst, src) {
+        (Values::Float(dst), Values::Float(src)) => dst.extend(src),
+        (Values::Integer(dst), Values::Integer(src)) => dst.extend(src),
+        (Values::Bool(dst), Values::Bool(src)) => dst.extend(src),
+        (Values::String(dst), Values::String(src)) => dst.extend(src),
+        (Values::Unsigned(dst), Values::Unsigned(src)) => dst.extend(src),
+        _ => return Err(anyhow!("field type conflict across tsm files")),
+    }
+    Ok(())
+}
+
+/// KeysIterator merges the sorted key iterators of several TSM files, yielding
+/// every distinct key once.
+pub struct KeysIterator {
+    itrs: Vec<KeyIterator>,
+    heads: Vec<Option<Vec<u8>>>,
+}
+
+impl KeysIterator {
+    /// new merges the ascending keys of itrs, dropping duplicates.
+    pub(crate) async fn new(mut itrs: Vec<KeyIterator>) -> anyhow::Result<Self> {
+        let mut heads = Vec::with_capacity(itrs.len());
+        for itr in itrs.iter_mut() {
+            heads.push(itr.try_next().await?);
+        }
+        Ok(Self { itrs, heads })
+    }
+}
+
+#[async_trait]
+impl AsyncIterator for KeysIterator {
+    type Item = Vec<u8>;
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        let key = match self.heads.iter().flatten().min() {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+
+        for (itr, head) in self.itrs.iter_mut().zip(self.heads.iter_mut()) {
+            if head.as_ref() == Some(&key) {
+                *head = itr.try_next().await?;
+            }
+        }
+
+        Ok(Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use common_base::iterator::AsyncIterator;
+    use futures::TryStreamExt;
+    use influxdb_storage::opendal::raw::*;
+    use influxdb_storage::opendal::services::Memory;
+    use influxdb_storage::opendal::{Error, ErrorKind, Operator, Result};
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::file_store::{
+        parse_tsm_file_name, tsm_file_of, FileStore, MANIFEST_FILE_NAME,
+    };
+    use crate::engine::tsm1::file_store::stat::{KeyStats, DEFAULT_KEY_SAMPLE_SIZE};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::value::{TimeValue, Values};
+    use crate::engine::KEY_STATS_FILE_EXTENSION;
+
+    async fn write_tsm_file(path: &str, data: Vec<(&str, Values)>) {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for (key, values) in data {
+            w.write(key.as_bytes(), values).await.unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+    }
+
+    fn float_values(points: &[(i64, f64)]) -> Values {
+        Values::Float(points.iter().map(|(t, v)| TimeValue::new(*t, *v)).collect())
+    }
+
+    /// upload copies the local TSM file of dir named like path to path.
+    async fn upload(dir: &Path, op: &StorageOperator, path: &str) {
+        let name = path.rsplit('/').next().unwrap();
+        let data = std::fs::read(dir.join(name)).unwrap();
+        op.operator().write(path, data).await.unwrap();
+    }
+
+    /// Probe records the paths read from an operator and fails the storage
+    /// mutation with index `at`, leaving the storage as a crash at that point
+    /// would.
+    #[derive(Clone, Debug)]
+    struct Probe {
+        reads: Arc<Mutex<Vec<String>>>,
+        /// mutations counts the writes, deletes and renames.
+        mutations: Arc<AtomicUsize>,
+        at: Arc<AtomicUsize>,
+    }
+
+    impl Probe {
+        fn new() -> Self {
+            Self {
+                reads: Arc::new(Mutex::new(vec![])),
+                mutations: Arc::new(AtomicUsize::new(0)),
+                at: Arc::new(AtomicUsize::new(usize::MAX)),
+            }
+        }
+
+        /// take_reads returns the paths read since the last call.
+        fn take_reads(&self) -> Vec<String> {
+            std::mem::take(&mut *self.reads.lock().unwrap())
+        }
+
+        /// crash_after fails the mutation n after the ones done so far.
+        fn crash_after(&self, n: usize) {
+            let mutations = self.mutations.load(Ordering::Relaxed);
+            self.at.store(mutations + n, Ordering::Relaxed);
+        }
+
+        fn mutate(&self, path: &str) -> Result<()> {
+            let mutation = self.mutations.fetch_add(1, Ordering::Relaxed);
+            if mutation == self.at.load(Ordering::Relaxed) {
+                return Err(Error::new(
+                    ErrorKind::Unexpected,
+                    format!("injected crash at mutation {} of {}", mutation, path).as_str(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /// ProbeLayer injects a `Probe` into the operator.
+    #[derive(Clone, Debug)]
+    struct ProbeLayer {
+        probe: Probe,
+    }
+
+    impl<A: Accessor> Layer<A> for ProbeLayer {
+        type LayeredAccessor = ProbeAccessor<A>;
+
+        fn layer(&self, inner: A) -> Self::LayeredAccessor {
+            ProbeAccessor {
+                inner,
+                probe: self.probe.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct ProbeAccessor<A: Accessor> {
+        inner: A,
+        probe: Probe,
+    }
+
+    #[async_trait]
+    impl<A: Accessor> LayeredAccessor for ProbeAccessor<A> {
+        type Inner = A;
+        type Reader = A::Reader;
+        type BlockingReader = A::BlockingReader;
+        type Writer = A::Writer;
+        type BlockingWriter = A::BlockingWriter;
+        type Appender = A::Appender;
+        type Pager = A::Pager;
+        type BlockingPager = A::BlockingPager;
+
+        fn inner(&self) -> &Self::Inner {
+            &self.inner
+        }
+
+        async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+            self.probe.reads.lock().unwrap().push(path.to_string());
+            self.inner.read(path, args).await
+        }
+
+        async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+            self.probe.mutate(path)?;
+            self.inner.write(path, args).await
+        }
+
+        async fn append(&self, path: &str, args: OpAppend) -> Result<(RpAppend, Self::Appender)> {
+            self.probe.mutate(path)?;
+            self.inner.append(path, args).await
+        }
+
+        async fn delete(&self, path: &str, args: OpDelete) -> Result<RpDelete> {
+            self.probe.mutate(path)?;
+            self.inner.delete(path, args).await
+        }
+
+        async fn rename(&self, from: &str, to: &str, args: OpRename) -> Result<RpRename> {
+            self.probe.mutate(from)?;
+            self.inner.rename(from, to, args).await
+        }
+
+        async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Pager)> {
+            self.inner.list(path, args).await
+        }
+
+        fn blocking_read(
+            &self,
+            path: &str,
+            args: OpRead,
+        ) -> Result<(RpRead, Self::BlockingReader)> {
+            self.inner.blocking_read(path, args)
+        }
+
+        fn blocking_write(
+            &self,
+            path: &str,
+            args: OpWrite,
+        ) -> Result<(RpWrite, Self::BlockingWriter)> {
+            self.inner.blocking_write(path, args)
+        }
+
+        fn blocking_list(&self, path: &str, args: OpList) -> Result<(RpList, Self::BlockingPager)> {
+            self.inner.blocking_list(path, args)
+        }
+    }
+
+    #[test]
+    fn test_parse_tsm_file_name() {
+        assert_eq!(
+            parse_tsm_file_name("/data/000000012-000000003.tsm").unwrap(),
+            (12, 3)
+        );
+        assert!(parse_tsm_file_name("000000012-000000003.tsm.bad").is_err());
+        assert!(parse_tsm_file_name("000000012.tsm").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(fs.count().await, 0);
+        assert_eq!(fs.next_generation(), 1);
+
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert!(values.is_none());
+
+        let mut keys = fs.keys().await.unwrap();
+        assert!(keys.try_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_read_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // two generations overlapping on [3, 4] for the same key
+        write_tsm_file(
+            &fs.tsm_path(1, 1),
+            vec![
+                ("cpu", float_values(&[(1, 1.0), (3, 3.0), (4, 4.0)])),
+                ("mem", float_values(&[(1, 1.0)])),
+            ],
+        )
+        .await;
+        write_tsm_file(
+            &fs.tsm_path(2, 1),
+            vec![
+                ("cpu", float_values(&[(2, 20.0), (4, 40.0), (5, 50.0)])),
+                ("disk", float_values(&[(1, 1.0)])),
+            ],
+        )
+        .await;
+
+        // a corrupt file is renamed to `.bad`, an existing `.bad` file is skipped
+        tokio::fs::write(dir.path().join("000000003-000000001.tsm"), b"garbage")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("000000004-000000001.tsm.bad"), b"garbage")
+            .await
+            .unwrap();
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(fs.count().await, 2);
+        assert_eq!(fs.current_generation(), 2);
+        assert!(dir.path().join("000000003-000000001.tsm.bad").exists());
+
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(
+            values,
+            Some(float_values(&[
+                (1, 1.0),
+                (2, 20.0),
+                (3, 3.0),
+                (4, 40.0),
+                (5, 50.0)
+            ]))
+        );
+
+        let values = fs.read(b"cpu", TimeRange::new(2, 3)).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(2, 20.0), (3, 3.0)])));
+
+        let values = fs.read(b"cpu", TimeRange::new(10, 20)).await.unwrap();
+        assert!(values.is_none());
+
+        let mut keys = fs.keys().await.unwrap();
+        let mut got = vec![];
+        while let Some(key) = keys.try_next().await.unwrap() {
+            got.push(String::from_utf8(key).unwrap());
+        }
+        assert_eq!(got, vec!["cpu", "disk", "mem"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_store_read_equal_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        // both files hold every timestamp, enough of them for the sort of
+        // `Values::deduplicate` not to be a plain insertion sort
+        let old: Vec<_> = (0..1000).map(|i| (i, i as f64)).collect();
+        let new: Vec<_> = (0..1000).map(|i| (i, -i as f64)).collect();
+        write_tsm_file(&fs.tsm_path(1, 1), vec![("cpu", float_values(&old))]).await;
+        write_tsm_file(&fs.tsm_path(2, 1), vec![("cpu", float_values(&new))]).await;
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&new)));
+
+        let values = fs.read(b"cpu", TimeRange::new(100, 199)).await.unwrap();
+        assert_eq!(values, Some(float_values(&new[100..200])));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_read_time_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let probe = Probe::new();
+        let operator = Operator::new(Memory::default())
+            .unwrap()
+            .layer(ProbeLayer {
+                probe: probe.clone(),
+            })
+            .finish();
+        let op = StorageOperator::new(operator, "shard/");
+        let fs = FileStore::open(op.clone()).await.unwrap();
+
+        // three files covering disjoint time windows: [0,9], [10,19], [20,29]
+        let mut paths = vec![];
+        for i in 0..3_i64 {
+            let path = fs.tsm_path(i as u64 + 1, 1);
+            let name = path.rsplit('/').next().unwrap();
+            let points: Vec<(i64, f64)> = (i * 10..i * 10 + 10).map(|t| (t, t as f64)).collect();
+            write_tsm_file(
+                dir.path().join(name).to_str().unwrap(),
+                vec![("cpu", float_values(&points))],
+            )
+            .await;
+            upload(dir.path(), &op, &path).await;
+            paths.push(path);
+        }
+        let new: Vec<&str> = paths.iter().map(|x| x.as_str()).collect();
+        fs.replace(&[], &new).await.unwrap();
+
+        // only the file overlapping the range is read
+        probe.take_reads();
+        let values = fs.read(b"cpu", TimeRange::new(12, 15)).await.unwrap();
+        assert_eq!(
+            values,
+            Some(float_values(&[
+                (12, 12.0),
+                (13, 13.0),
+                (14, 14.0),
+                (15, 15.0)
+            ]))
+        );
+        let reads = probe.take_reads();
+        assert!(!reads.is_empty());
+        assert!(
+            reads.iter().all(|x| x.ends_with(paths[1].as_str())),
+            "{:?}",
+            reads
+        );
+
+        // and none outside of the windows of the files
+        let values = fs.read(b"cpu", TimeRange::new(30, 40)).await.unwrap();
+        assert!(values.is_none());
+        assert!(probe.take_reads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let old = vec![fs.tsm_path(1, 1), fs.tsm_path(2, 1)];
+        write_tsm_file(&old[0], vec![("cpu", float_values(&[(1, 1.0)]))]).await;
+        write_tsm_file(&old[1], vec![("cpu", float_values(&[(2, 2.0)]))]).await;
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(fs.files().await, old);
+
+        let generation = fs.next_generation();
+        assert_eq!(generation, 3);
+
+        let new = vec![fs.tsm_path(generation, 2)];
+        write_tsm_file(&new[0], vec![("cpu", float_values(&[(1, 1.0), (2, 2.0)]))]).await;
+
+        let old: Vec<&str> = old.iter().map(|x| x.as_str()).collect();
+        fs.replace(&old, &[new[0].as_str()]).await.unwrap();
+        assert_eq!(fs.files().await, new);
+        assert!(!dir.path().join("000000001-000000001.tsm").exists());
+
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_reader_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert!(fs.time_range().await.is_none());
+
+        let old = vec![fs.tsm_path(1, 1), fs.tsm_path(2, 1)];
+        write_tsm_file(
+            &old[0],
+            vec![
+                ("cpu", float_values(&[(1, 1.0), (4, 4.0)])),
+                ("mem", float_values(&[(2, 2.0)])),
+            ],
+        )
+        .await;
+        write_tsm_file(
+            &old[1],
+            vec![
+                ("cpu", float_values(&[(3, 3.0), (8, 8.0)])),
+                ("disk", float_values(&[(-1, 1.0)])),
+            ],
+        )
+        .await;
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let mut keys = fs.keys().await.unwrap();
+        let mut got = vec![];
+        while let Some(key) = keys.try_next().await.unwrap() {
+            got.push(String::from_utf8(key).unwrap());
+        }
+        assert_eq!(got, vec!["cpu", "disk", "mem"]);
+
+        let time_range = fs.time_range().await.unwrap();
+        assert_eq!((time_range.min, time_range.max), (-1, 8));
+
+        assert_eq!(fs.reader_for(b"cpu").await.unwrap().files(), old);
+        assert_eq!(
+            fs.reader_for(b"mem").await.unwrap().files(),
+            vec![old[0].clone()]
+        );
+        assert_eq!(
+            fs.reader_for(b"disk").await.unwrap().files(),
+            vec![old[1].clone()]
+        );
+        assert!(fs.reader_for(b"net").await.unwrap().files().is_empty());
+
+        // a held view keeps its reader open across replace
+        let held = fs.reader_for(b"mem").await.unwrap();
+
+        let new = fs.tsm_path(fs.next_generation(), 2);
+        write_tsm_file(&new, vec![("mem", float_values(&[(2, 2.0), (5, 5.0)]))]).await;
+        fs.replace(&[old[0].as_str()], &[new.as_str()])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs.reader_for(b"mem").await.unwrap().files(),
+            vec![new.clone()]
+        );
+        assert_eq!(
+            fs.reader_for(b"cpu").await.unwrap().files(),
+            vec![old[1].clone()]
+        );
+        let time_range = fs.time_range().await.unwrap();
+        assert_eq!((time_range.min, time_range.max), (-1, 8));
+
+        let values = held.read(b"mem", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(2, 2.0)])));
+        drop(held);
+        fs.purge().await.unwrap();
+        assert!(!std::path::Path::new(&old[0]).exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_replace_tmp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let old = fs.tsm_path(1, 1);
+        write_tsm_file(&old, vec![("cpu", float_values(&[(1, 1.0)]))]).await;
+        write_tsm_file(&fs.tsm_path(2, 1), vec![("mem", float_values(&[(1, 1.0)]))]).await;
+
+        // an orphaned output of an interrupted compaction
+        let orphan = format!("{}.tmp", fs.tsm_path(3, 2));
+        write_tsm_file(&orphan, vec![("cpu", float_values(&[(1, 1.0)]))]).await;
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert!(!std::path::Path::new(&orphan).exists());
+        assert_eq!(fs.count().await, 2);
+
+        // a reader obtained before the replace
+        let mut keys = fs.keys().await.unwrap();
+
+        let new = format!("{}.tmp", fs.tsm_path(fs.next_generation(), 2));
+        write_tsm_file(&new, vec![("cpu", float_values(&[(1, 10.0), (2, 20.0)]))]).await;
+        fs.replace(&[old.as_str()], &[new.as_str()]).await.unwrap();
+
+        assert!(!std::path::Path::new(&old).exists());
+        assert!(!std::path::Path::new(&new).exists());
+        assert_eq!(fs.files().await, vec![fs.tsm_path(2, 1), fs.tsm_path(3, 2)]);
+
+        let mut got = vec![];
+        while let Some(key) = keys.try_next().await.unwrap() {
+            got.push(String::from_utf8(key).unwrap());
+        }
+        assert_eq!(got, vec!["cpu", "mem"]);
+
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(1, 10.0), (2, 20.0)])));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_replace_roll_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let new = vec![fs.tsm_path(1, 2), fs.tsm_path(1, 3)];
+        write_tsm_file(&new[0], vec![("cpu", float_values(&[(1, 1.0)]))]).await;
+        write_tsm_file(&new[1], vec![("mem", float_values(&[(1, 1.0)]))]).await;
+
+        // a crash between the renames of a replace, after the commit record
+        let record = format!(
+            "{}/000000001-000000002.commit",
+            dir.path().to_str().unwrap()
+        );
+        std::fs::write(
+            &record,
+            "000000001-000000002.tsm\n000000001-000000003.tsm\n",
+        )
+        .unwrap();
+        std::fs::rename(&new[1], format!("{}.tmp", new[1])).unwrap();
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(fs.files().await, new);
+        assert!(!std::path::Path::new(&record).exists());
+        assert!(!std::path::Path::new(&format!("{}.tmp", new[1])).exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_view() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let old = fs.tsm_path(fs.next_generation(), 1);
+        write_tsm_file(&old, vec![("cpu", float_values(&[(1, 1.0)]))]).await;
+        fs.replace(&[], &[old.as_str()]).await.unwrap();
+
+        let view = fs.view().await;
+
+        // a flush and a compaction of the old file after the view was taken
+        let flushed = fs.tsm_path(fs.next_generation(), 1);
+        write_tsm_file(&flushed, vec![("cpu", float_values(&[(2, 2.0)]))]).await;
+        fs.replace(&[], &[flushed.as_str()]).await.unwrap();
+        let compacted = fs.tsm_path(fs.next_generation(), 2);
+        write_tsm_file(&compacted, vec![("cpu", float_values(&[(1, 10.0)]))]).await;
+        fs.replace(&[old.as_str()], &[compacted.as_str()])
+            .await
+            .unwrap();
+
+        // the view still reads the old file, which is kept until the view is dropped
+        assert_eq!(view.files(), vec![old.clone()]);
+        let values = view.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(1, 1.0)])));
+        assert!(std::path::Path::new(&old).exists());
+
+        let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+        assert_eq!(values, Some(float_values(&[(1, 10.0), (2, 2.0)])));
+
+        drop(view);
+        fs.purge().await.unwrap();
+        assert!(!std::path::Path::new(&old).exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_key_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+
+        let mut keys = vec![];
+        for measurement in ["cpu", "disk", "mem"] {
+            for host in 0..10 {
+                for field in ["free", "used"] {
+                    keys.push(format!("{},host={}#!~#{}", measurement, host, field));
+                }
+            }
+        }
+        keys.push("disk#!~#count".to_string());
+        // escaped commas are part of the measurement
+        keys.push(r"net\,eth0#!~#rx".to_string());
+        keys.push(r"net\,eth1#!~#rx".to_string());
+        keys.sort();
+
+        {
+            let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+                .await
+                .unwrap();
+            let data = keys
+                .iter()
+                .map(|x| (x.as_str(), float_values(&[(1, 1.0)])))
+                .collect();
+            write_tsm_file(&fs.tsm_path(1, 1), data).await;
+        }
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let stats = fs.stats().await.unwrap();
+        assert_eq!(stats.len(), 1);
+
+        let key_stats = &stats[0].key_stats;
+        assert_eq!(key_stats.key_count, keys.len());
+        assert_eq!(key_stats.measurement_count, 5);
+        assert_eq!(key_stats.series_count, 33);
+        assert_eq!(key_stats.sample.len(), DEFAULT_KEY_SAMPLE_SIZE);
+        for key in key_stats.sample.iter() {
+            assert!(keys.contains(&String::from_utf8(key.clone()).unwrap()));
+        }
+
+        // reopening restores the stats from the sidecar
+        let sidecar = format!("{}.{}", fs.tsm_path(1, 1), KEY_STATS_FILE_EXTENSION);
+        let data = std::fs::read(sidecar.as_str()).unwrap();
+        assert_eq!(KeyStats::decode(data.as_slice()).unwrap(), *key_stats);
+        drop(fs);
+
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let restored = fs.stats().await.unwrap();
+        assert_eq!(restored[0].key_stats, *key_stats);
+
+        // a corrupt sidecar is recomputed
+        std::fs::write(sidecar.as_str(), b"corrupt").unwrap();
+        let fs = FileStore::open(StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+        let recomputed = fs.stats().await.unwrap();
+        assert_eq!(recomputed[0].key_stats, *key_stats);
+
+        fs.replace(&[fs.tsm_path(1, 1).as_str()], &[])
+            .await
+            .unwrap();
+        assert!(!std::path::Path::new(sidecar.as_str()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_replace_crash() {
+        // the TSM files are written locally, then uploaded to the memory backend
+        let dir = tempfile::tempdir().unwrap();
+        let local = |name: &str| format!("{}/{}", dir.path().to_str().unwrap(), name);
+        write_tsm_file(
+            &local("000000001-000000001.tsm"),
+            vec![("cpu", float_values(&[(1, 1.0)]))],
+        )
+        .await;
+        write_tsm_file(
+            &local("000000002-000000001.tsm"),
+            vec![("cpu", float_values(&[(2, 2.0)]))],
+        )
+        .await;
+        write_tsm_file(
+            &local("000000003-000000002.tsm"),
+            vec![("cpu", float_values(&[(1, 10.0), (2, 20.0)]))],
+        )
+        .await;
+
+        let mut committed = false;
+        for crash_at in 0.. {
+            let probe = Probe::new();
+            let operator = Operator::new(Memory::default())
+                .unwrap()
+                .layer(ProbeLayer {
+                    probe: probe.clone(),
+                })
+                .finish();
+            let op = StorageOperator::new(operator, "shard/").with_rename_is_atomic(false);
+
+            let fs = FileStore::open(op.clone()).await.unwrap();
+            let old = vec![fs.tsm_path(1, 1), fs.tsm_path(2, 1)];
+            for path in old.iter() {
+                upload(dir.path(), &op, path).await;
+            }
+            let old_paths: Vec<&str> = old.iter().map(|x| x.as_str()).collect();
+            fs.replace(&[], &old_paths).await.unwrap();
+
+            // the new file is written under its final name, not renamed
+            let new = fs.tsm_path(3, 2);
+            let tmp = format!("{}.tmp", new);
+            let err = fs.replace(&old_paths, &[tmp.as_str()]).await.unwrap_err();
+            assert!(err.to_string().contains("not atomic"), "{}", err);
+            upload(dir.path(), &op, &new).await;
+
+            probe.crash_after(crash_at);
+            let result = fs.replace(&old_paths, &[new.as_str()]).await;
+            drop(fs);
+
+            // the reopened store holds either the old or the new files
+            let fs = FileStore::open(op.clone()).await.unwrap();
+            let files = fs.files().await;
+            let values = fs.read(b"cpu", TimeRange::unbound()).await.unwrap();
+            if files == old {
+                assert!(!committed, "crash at step {} lost the commit", crash_at);
+                assert!(result.is_err());
+                assert_eq!(values, Some(float_values(&[(1, 1.0), (2, 2.0)])));
+            } else {
+                assert_eq!(files, vec![fs.tsm_path(3, 2)], "crash at step {}", crash_at);
+                assert_eq!(values, Some(float_values(&[(1, 10.0), (2, 20.0)])));
+                committed = true;
+            }
+
+            // and nothing left by the interrupted replace
+            let mut lister = op.list().await.unwrap();
+            while let Some(de) = lister.try_next().await.unwrap() {
+                if de.name() == MANIFEST_FILE_NAME || de.name().ends_with('/') {
+                    continue;
+                }
+                let tsm_name = tsm_file_of(de.name()).unwrap();
+                assert!(
+                    files.iter().any(|x| x.ends_with(tsm_name.as_str())),
+                    "crash at step {} left {}",
+                    crash_at,
+                    de.name()
+                );
+            }
+
+            if result.is_ok() {
+                break;
+            }
+        }
+        assert!(committed);
+    }
+}
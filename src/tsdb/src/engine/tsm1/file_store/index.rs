@@ -91,17 +91,21 @@ impl Display for IndexEntry {
     }
 }
 
+/// IndexEntries holds the index entries of a key. Entries are kept in their
+/// 28-byte on-disk encoding, in a boxed slice sized to them, and decoded on
+/// access. `entries` is a view of them with the methods of the slice of decoded
+/// entries they used to be held in.
 #[derive(Default)]
 pub struct IndexEntries {
     pub typ: u8,
-    pub entries: Vec<IndexEntry>,
+    raw: Box<[u8]>,
 }
 
 impl IndexEntries {
     pub fn new(typ: u8) -> Self {
         Self {
             typ,
-            entries: vec![],
+            raw: Box::default(),
         }
     }
 
@@ -109,53 +113,293 @@ impl IndexEntries {
         self.typ = typ;
     }
 
-    pub fn clear_with_cap(&mut self, cap: usize) {
-        if self.entries.capacity() < cap {
-            self.entries.reserve_exact(cap - self.entries.len());
+    /// clear_with_cap removes the entries. The boxed slice is sized when the
+    /// entries are set, cap is only kept for compatibility.
+    pub fn clear_with_cap(&mut self, _cap: usize) {
+        self.raw = Box::default();
+    }
+
+    /// set_raw replaces the entries by the ones of raw, in their on-disk encoding.
+    pub fn set_raw(&mut self, raw: Vec<u8>) -> anyhow::Result<()> {
+        if raw.len() % INDEX_ENTRY_SIZE != 0 {
+            return Err(anyhow!(
+                "invalid index entries size: {} is not a multiple of {}",
+                raw.len(),
+                INDEX_ENTRY_SIZE
+            ));
         }
-        self.entries.clear();
+        self.raw = raw.into_boxed_slice();
+        Ok(())
     }
 
+    /// push appends an entry, reallocating the entries. Prefer `set_raw` or
+    /// `collect` to build many of them.
     pub fn push(&mut self, entry: IndexEntry) {
-        self.entries.push(entry);
+        let mut raw = std::mem::take(&mut self.raw).into_vec();
+        entry.write_to(&mut raw);
+        self.raw = raw.into_boxed_slice();
+    }
+
+    /// push_raw appends an entry in its on-disk encoding, see `push`.
+    pub fn push_raw(&mut self, b: &[u8]) -> anyhow::Result<()> {
+        if b.len() != INDEX_ENTRY_SIZE {
+            return Err(anyhow!(
+                "invalid index entry size: {} != {}",
+                b.len(),
+                INDEX_ENTRY_SIZE
+            ));
+        }
+        let mut raw = std::mem::take(&mut self.raw).into_vec();
+        raw.extend_from_slice(b);
+        self.raw = raw.into_boxed_slice();
+        Ok(())
+    }
+
+    /// entries returns a view of the entries.
+    pub fn entries(&self) -> EntriesView<'_> {
+        EntriesView { raw: &self.raw }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    fn raw_entry(&self, i: usize) -> &[u8] {
+        &self.raw[i * INDEX_ENTRY_SIZE..(i + 1) * INDEX_ENTRY_SIZE]
+    }
+
+    /// get returns the entry at position i.
+    pub fn get(&self, i: usize) -> Option<IndexEntry> {
+        self.entries().get(i)
+    }
+
+    /// entry returns the entry at position i, panics if out of bounds.
+    pub fn entry(&self, i: usize) -> IndexEntry {
+        IndexEntry::read_from(self.raw_entry(i)).unwrap()
+    }
+
+    /// min_time returns the min time of the entry at position i without decoding it.
+    pub fn min_time(&self, i: usize) -> i64 {
+        u64::from_be_bytes(self.raw_entry(i)[..8].try_into().unwrap()) as i64
+    }
+
+    /// max_time returns the max time of the entry at position i without decoding it.
+    pub fn max_time(&self, i: usize) -> i64 {
+        u64::from_be_bytes(self.raw_entry(i)[8..16].try_into().unwrap()) as i64
+    }
+
+    pub fn iter(&self) -> EntriesIter<'_> {
+        self.entries().iter()
+    }
+
+    pub fn to_vec(&self) -> Vec<IndexEntry> {
+        self.iter().collect()
     }
 
     pub fn time_range(&self) -> TimeRange {
-        if self.entries.len() > 0 {
-            TimeRange::new(
-                self.entries[0].min_time,
-                self.entries[self.entries.len() - 1].max_time,
-            )
+        if self.len() > 0 {
+            TimeRange::new(self.min_time(0), self.max_time(self.len() - 1))
         } else {
             TimeRange::new(i64::MIN, i64::MIN)
         }
     }
 
     pub fn marshal_binary(&self) -> anyhow::Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(self.entries.len() * INDEX_ENTRY_SIZE);
+        Ok(self.raw.to_vec())
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, mut w: W) -> anyhow::Result<u64> {
+        w.write_all(&self.raw).await.map_err(|e| anyhow!(e))?;
+        Ok(self.raw.len() as u64)
+    }
 
-        for entry in &self.entries {
-            entry.write_to(&mut buf);
+    pub fn sort(&mut self) {
+        let sorted = (1..self.len()).all(|i| self.min_time(i - 1) <= self.min_time(i));
+        if sorted {
+            return;
         }
 
-        Ok(buf)
+        let mut entries = self.to_vec();
+        entries.sort_by_key(|x| x.min_time);
+
+        let typ = self.typ;
+        *self = entries.into_iter().collect();
+        self.typ = typ;
     }
+}
 
-    pub async fn write_to<W: AsyncWrite + Unpin>(&self, mut w: W) -> anyhow::Result<u64> {
-        let mut buf = Vec::with_capacity(INDEX_ENTRY_SIZE);
-        let mut total = 0;
-
-        for entry in &self.entries {
-            buf.clear();
-            entry.write_to(&mut buf);
-            let n = w.write(buf.as_slice()).await.map_err(|e| anyhow!(e))?;
-            total += n as u64;
+impl FromIterator<IndexEntry> for IndexEntries {
+    fn from_iter<T: IntoIterator<Item = IndexEntry>>(iter: T) -> Self {
+        let mut raw = vec![];
+        for entry in iter {
+            entry.write_to(&mut raw);
         }
+        Self {
+            typ: 0,
+            raw: raw.into_boxed_slice(),
+        }
+    }
+}
 
-        Ok(total)
+impl<'a> IntoIterator for &'a IndexEntries {
+    type Item = IndexEntry;
+    type IntoIter = EntriesIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
+}
 
-    pub fn sort(&mut self) {
-        self.entries.sort_by_key(|x| x.min_time)
+/// EntriesView is a read-only view of the entries of an `IndexEntries`, with the
+/// methods of a slice of entries. Entries are returned by value, decoded from
+/// their on-disk encoding.
+#[derive(Clone, Copy)]
+pub struct EntriesView<'a> {
+    raw: &'a [u8],
+}
+
+impl<'a> EntriesView<'a> {
+    pub fn len(&self) -> usize {
+        self.raw.len() / INDEX_ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<IndexEntry> {
+        let b = self
+            .raw
+            .get(i * INDEX_ENTRY_SIZE..(i + 1) * INDEX_ENTRY_SIZE)?;
+        Some(IndexEntry::read_from(b).unwrap())
+    }
+
+    pub fn first(&self) -> Option<IndexEntry> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<IndexEntry> {
+        self.len().checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    pub fn iter(&self) -> EntriesIter<'a> {
+        EntriesIter {
+            chunks: self.raw.chunks_exact(INDEX_ENTRY_SIZE),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<IndexEntry> {
+        self.iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for EntriesView<'a> {
+    type Item = IndexEntry;
+    type IntoIter = EntriesIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// EntriesIter iterates over the entries of an `IndexEntries`, decoding them.
+#[derive(Clone)]
+pub struct EntriesIter<'a> {
+    chunks: std::slice::ChunksExact<'a, u8>,
+}
+
+impl Iterator for EntriesIter<'_> {
+    type Item = IndexEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks
+            .next()
+            .map(|b| IndexEntry::read_from(b).unwrap())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for EntriesIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.chunks
+            .next_back()
+            .map(|b| IndexEntry::read_from(b).unwrap())
+    }
+}
+
+impl ExactSizeIterator for EntriesIter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+    use crate::engine::tsm1::file_store::INDEX_ENTRY_SIZE;
+
+    #[test]
+    fn test_index_entries_accessors() {
+        let mut entries = IndexEntries::new(0);
+        entries.push(IndexEntry::new(10, 19, 100, 32));
+        entries.push(IndexEntry::new(0, 9, 5, 95));
+        entries.sort();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.min_time(0), 0);
+        assert_eq!(entries.max_time(1), 19);
+
+        let entry = entries.entry(1);
+        assert_eq!(
+            (entry.min_time, entry.max_time, entry.offset, entry.size),
+            (10, 19, 100, 32)
+        );
+        assert!(entries.get(2).is_none());
+
+        let tr = entries.time_range();
+        assert_eq!((tr.min, tr.max), (0, 19));
+
+        // the view of the entries behaves as the slice they used to be held in
+        let view = entries.entries();
+        assert_eq!(view.len(), 2);
+        assert!(!view.is_empty());
+        assert_eq!(view.first().unwrap().min_time, 0);
+        assert_eq!(view.last().unwrap().offset, 100);
+        assert!(view.get(2).is_none());
+        let offsets: Vec<u64> = view.iter().rev().map(|x| x.offset).collect();
+        assert_eq!(offsets, vec![100, 5]);
+        assert_eq!(view.iter().len(), 2);
+        let mut sizes = vec![];
+        for entry in &entries {
+            sizes.push(entry.size);
+        }
+        assert_eq!(sizes, vec![95, 32]);
+
+        let mut raw = entries.marshal_binary().unwrap();
+        raw.truncate(INDEX_ENTRY_SIZE);
+        let mut copy = IndexEntries::new(entries.typ);
+        copy.set_raw(raw).unwrap();
+        assert_eq!(copy.entries().to_vec().len(), 1);
+        assert!(copy.set_raw(vec![0; 27]).is_err());
+        assert_eq!(
+            entries.marshal_binary().unwrap().len(),
+            2 * INDEX_ENTRY_SIZE
+        );
+    }
+
+    #[test]
+    fn test_index_entries_memory_size() {
+        let n = 10_000;
+
+        let entries: IndexEntries = (0..n as i64)
+            .map(|i| IndexEntry::new(i * 10, i * 10 + 9, i as u64 * 64, 64))
+            .collect();
+
+        assert_eq!(entries.len(), n);
+        assert_eq!(entries.raw.len(), n * INDEX_ENTRY_SIZE);
+        assert!(std::mem::size_of::<IndexEntries>() <= 24);
     }
 }
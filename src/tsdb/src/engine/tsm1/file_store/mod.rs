@@ -1,3 +1,6 @@
+pub mod bloom;
+#[allow(clippy::module_inception)]
+pub mod file_store;
 pub mod index;
 pub mod reader;
 pub mod stat;
@@ -12,7 +15,7 @@ const MAGIC_NUMBER: u32 = 0x16D116D1;
 const VERSION: u8 = 1;
 
 /// Block's header: | magic number(4B) | VERSION(1B) |
-const HEADER: [u8; 5] = [22, 209, 22, 209, 1];
+pub(crate) const HEADER: [u8; 5] = [22, 209, 22, 209, 1];
 
 /// size in bytes of an index entry
 const INDEX_ENTRY_SIZE: usize = 28;
@@ -24,10 +27,10 @@ const INDEX_COUNT_SIZE: usize = 2;
 const INDEX_TYPE_SIZE: usize = 1;
 
 /// Max number of blocks for a given key that can exist in a single file
-const MAX_INDEX_ENTRIES: usize = (1 << (INDEX_COUNT_SIZE * 8)) - 1;
+pub(crate) const MAX_INDEX_ENTRIES: usize = (1 << (INDEX_COUNT_SIZE * 8)) - 1;
 
 /// max length of a key in an index entry (measurement + tags)
-const MAX_KEY_LENGTH: usize = (1 << (2 * 8)) - 1;
+const MAX_KEY_LENGTH: usize = common_base::point::MAX_KEY_LENGTH;
 
 /// The threshold amount data written before we periodically fsync a TSM file.  This helps avoid
 /// long pauses due to very large fsyncs at the end of writing a TSM file.
@@ -49,21 +52,57 @@ impl TimeRange {
         Self::new(i64::MIN, i64::MAX)
     }
 
+    pub fn min(&self) -> i64 {
+        self.min
+    }
+
+    pub fn max(&self) -> i64 {
+        self.max
+    }
+
     pub fn overlaps(&self, other: &TimeRange) -> bool {
         self.min <= other.max && self.max >= other.min
     }
 }
 
-/// TimeRange holds a min and max timestamp.
+/// KeyRange holds a min and max key, compared lexicographically.
 #[derive(Debug, Clone)]
 pub struct KeyRange {
     pub(crate) min: Vec<u8>,
     pub(crate) max: Vec<u8>,
 }
 
+impl KeyRange {
+    pub fn new(min: Vec<u8>, max: Vec<u8>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn min(&self) -> &[u8] {
+        self.min.as_slice()
+    }
+
+    pub fn max(&self) -> &[u8] {
+        self.max.as_slice()
+    }
+
+    /// contains returns true if key is between the min and the max, inclusive.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.min.as_slice() <= key && key <= self.max.as_slice()
+    }
+
+    /// overlaps returns true if the two ranges share at least one key.
+    pub fn overlaps(&self, other: &KeyRange) -> bool {
+        self.min <= other.max && self.max >= other.min
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
     use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::file_store::{KeyRange, HEADER};
     use crate::engine::tsm1::value::{TimeValue, Values};
 
     #[tokio::test]
@@ -113,4 +152,101 @@ mod tests {
         //     r.close().await.unwrap();
         // }
     }
+
+    #[tokio::test]
+    async fn test_tsm_reader_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // three files covering disjoint time windows: [0,9], [10,19], [20,29]
+        let mut readers = vec![];
+        for i in 0..3_i64 {
+            let tsm_file = dir.as_ref().join(format!("{:09}-000000001.tsm", i + 1));
+            let mut w = DefaultTSMWriter::with_mem_buffer(&tsm_file).await.unwrap();
+
+            let values = (i * 10..i * 10 + 10)
+                .map(|t| TimeValue::new(t, t as f64))
+                .collect();
+            let key = format!("cpu,host=server-{}#!~#value", i);
+            w.write(key.as_bytes(), Values::Float(values))
+                .await
+                .unwrap();
+            w.write_index().await.unwrap();
+            w.close().await.unwrap();
+
+            let op = StorageOperator::root(tsm_file.to_str().unwrap()).unwrap();
+            readers.push(new_default_tsm_reader(op).await.unwrap());
+        }
+
+        let mut matched = vec![];
+        for r in &readers {
+            if r.overlaps_time_range(12, 15).await {
+                matched.push(r.path().to_string());
+            }
+        }
+        assert_eq!(matched, vec![readers[1].path().to_string()]);
+
+        assert!(readers[0].overlaps_time_range(i64::MIN, 0).await);
+        assert!(!readers[0].overlaps_time_range(10, i64::MAX).await);
+
+        let mut matched = 0;
+        for r in &readers {
+            if r.overlaps_key_range(b"cpu,host=server-1", b"cpu,host=server-3")
+                .await
+            {
+                matched += 1;
+            }
+        }
+        assert_eq!(matched, 2);
+    }
+
+    #[tokio::test]
+    async fn test_tsm_reader_overlaps_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let tsm_file = dir.as_ref().join("000000001-000000001.tsm");
+
+        // header followed by an empty index
+        let mut data = HEADER.to_vec();
+        data.extend_from_slice(&(HEADER.len() as u64).to_be_bytes());
+        tokio::fs::write(&tsm_file, data).await.unwrap();
+
+        let op = StorageOperator::root(tsm_file.to_str().unwrap()).unwrap();
+        let r = new_default_tsm_reader(op).await.unwrap();
+        assert!(!r.overlaps_time_range(i64::MIN, i64::MAX).await);
+        assert!(!r.overlaps_key_range(b"", b"\xff").await);
+        assert_eq!(r.key_count().await, 0);
+    }
+
+    #[test]
+    fn test_key_range_contains() {
+        let r = KeyRange::new(b"cpu".to_vec(), b"mem".to_vec());
+        assert!(r.contains(b"cpu"));
+        assert!(r.contains(b"mem"));
+        assert!(r.contains(b"disk"));
+        assert!(r.contains(b"cpu,host=a"));
+        assert!(!r.contains(b"cp"));
+        assert!(!r.contains(b"aaa"));
+        assert!(!r.contains(b"mem,host=a"));
+        assert!(!r.contains(b"net"));
+    }
+
+    #[test]
+    fn test_key_range_overlaps() {
+        let r = KeyRange::new(b"cpu".to_vec(), b"mem".to_vec());
+        let overlaps = |min: &[u8], max: &[u8]| {
+            let other = KeyRange::new(min.to_vec(), max.to_vec());
+            assert_eq!(r.overlaps(&other), other.overlaps(&r));
+            r.overlaps(&other)
+        };
+
+        // sharing a boundary key
+        assert!(overlaps(b"aaa", b"cpu"));
+        assert!(overlaps(b"mem", b"net"));
+        // inside, around and partially
+        assert!(overlaps(b"disk", b"disk"));
+        assert!(overlaps(b"aaa", b"zzz"));
+        assert!(overlaps(b"disk", b"net"));
+        // outside
+        assert!(!overlaps(b"aaa", b"cp"));
+        assert!(!overlaps(b"mem,host=a", b"net"));
+    }
 }
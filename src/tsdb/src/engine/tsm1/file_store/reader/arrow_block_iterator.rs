@@ -0,0 +1,277 @@
+use common_arrow::arrow::chunk::Chunk;
+use common_arrow::{
+    ArrayRef, BoolValues, FloatValues, IntegerValues, StringValues, Timestamps, Unsigned,
+};
+use common_base::iterator::{AsyncIterator, RefAsyncIterator};
+
+use crate::engine::tsm1::block::decoder::decode_block;
+use crate::engine::tsm1::file_store::file_store::new_values;
+use crate::engine::tsm1::file_store::reader::block_reader::TSMBlock;
+use crate::engine::tsm1::file_store::reader::index_reader::TSMIndex;
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::block_iterator::BlockIterator;
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::field_reader::TIMESTAMP_DATA_TYPE;
+use crate::engine::tsm1::value::{FieldType, TimeValue, Values};
+
+/// ArrowBlockIterator decodes the blocks of a key into arrow arrays, one
+/// `(Timestamps, ArrayRef)` pair per block: the timestamps and the values at the
+/// same positions, typed after the block type. See `FieldReader::arrow_blocks`.
+pub struct ArrowBlockIterator<B, I>
+where
+    B: TSMBlock,
+    I: TSMIndex,
+{
+    blocks: BlockIterator<B, I>,
+    typ: u8,
+}
+
+impl<B, I> ArrowBlockIterator<B, I>
+where
+    B: TSMBlock,
+    I: TSMIndex,
+{
+    pub(crate) fn new(blocks: BlockIterator<B, I>, typ: u8) -> Self {
+        Self { blocks, typ }
+    }
+}
+
+#[async_trait]
+impl<B, I> AsyncIterator for ArrowBlockIterator<B, I>
+where
+    B: TSMBlock,
+    I: TSMIndex,
+{
+    type Item = (Timestamps, ArrayRef);
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        let typ = self.typ;
+        match self.blocks.try_next().await? {
+            Some(block) => Ok(Some(decode_arrow_block(typ, block)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// decode_arrow_block decodes a block of type `typ` into arrow arrays. An empty
+/// block gives zero-length arrays.
+pub fn decode_arrow_block(typ: u8, block: &[u8]) -> anyhow::Result<(Timestamps, ArrayRef)> {
+    let mut values = new_values(typ)?;
+    if !block.is_empty() {
+        decode_block(block, &mut values)?;
+    }
+
+    let arrays = match values {
+        Values::Float(values) => {
+            let (timestamps, values) = split(values);
+            (timestamps, FloatValues::from_vec(values).boxed())
+        }
+        Values::Integer(values) => {
+            let (timestamps, values) = split(values);
+            (timestamps, IntegerValues::from_vec(values).boxed())
+        }
+        Values::Bool(values) => {
+            let (timestamps, values) = split(values);
+            (timestamps, BoolValues::from_slice(values).boxed())
+        }
+        Values::String(values) => {
+            let (timestamps, values) = split(values);
+            let values = values
+                .iter()
+                .map(|v| std::str::from_utf8(v.as_slice()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow!("invalid string value: {}", e))?;
+            (timestamps, StringValues::from_slice(values).boxed())
+        }
+        Values::Unsigned(values) => {
+            let (timestamps, values) = split(values);
+            (timestamps, Unsigned::from_vec(values).boxed())
+        }
+    };
+    Ok(arrays)
+}
+
+/// to_chunk returns the arrays of a block as a two-column chunk, the timestamps
+/// then the values.
+pub fn to_chunk(timestamps: Timestamps, values: ArrayRef) -> Chunk<ArrayRef> {
+    Chunk::new(vec![timestamps.boxed(), values])
+}
+
+/// split returns the timestamps array and the values of a block.
+fn split<T>(values: Vec<TimeValue<T>>) -> (Timestamps, Vec<T>)
+where
+    T: FieldType,
+{
+    let mut timestamps = Vec::with_capacity(values.len());
+    let mut fields = Vec::with_capacity(values.len());
+    for TimeValue { unix_nano, value } in values {
+        timestamps.push(unix_nano);
+        fields.push(value);
+    }
+    let timestamps = Timestamps::from_vec(timestamps).to(TIMESTAMP_DATA_TYPE);
+    (timestamps, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use common_arrow::arrow::array::Array;
+    use common_arrow::{BoolValues, FloatValues, IntegerValues, StringValues, Unsigned};
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::block::{
+        BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+    };
+    use crate::engine::tsm1::file_store::file_store::new_values;
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::arrow_block_iterator::{
+        decode_arrow_block, to_chunk,
+    };
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    /// blocks returns the values of a key of each type, in two blocks.
+    fn blocks() -> Vec<(Vec<u8>, Vec<Values>)> {
+        let block = |f: &dyn Fn(i64) -> Values| vec![f(0), f(10)];
+        vec![
+            (
+                b"cpu#!~#bool".to_vec(),
+                block(&|t| {
+                    Values::Bool((t..t + 10).map(|t| TimeValue::new(t, t % 3 == 0)).collect())
+                }),
+            ),
+            (
+                b"cpu#!~#float".to_vec(),
+                block(&|t| {
+                    Values::Float(
+                        (t..t + 10)
+                            .map(|t| TimeValue::new(t, t as f64 / 2.0))
+                            .collect(),
+                    )
+                }),
+            ),
+            (
+                b"cpu#!~#integer".to_vec(),
+                block(&|t| Values::Integer((t..t + 10).map(|t| TimeValue::new(t, -t)).collect())),
+            ),
+            (
+                b"cpu#!~#string".to_vec(),
+                block(&|t| {
+                    Values::String(
+                        (t..t + 10)
+                            .map(|t| TimeValue::new(t, format!("v{}", t).into_bytes()))
+                            .collect(),
+                    )
+                }),
+            ),
+            (
+                b"cpu#!~#unsigned".to_vec(),
+                block(&|t| {
+                    Values::Unsigned((t..t + 10).map(|t| TimeValue::new(t, t as u64)).collect())
+                }),
+            ),
+        ]
+    }
+
+    /// assert_block asserts that the arrays hold the values of the block.
+    fn assert_block(timestamps: &[i64], array: &dyn Array, values: &Values) {
+        match values {
+            Values::Float(values) => {
+                let array = array.as_any().downcast_ref::<FloatValues>().unwrap();
+                let want: Vec<_> = values.iter().map(|v| v.value).collect();
+                assert_eq!(array.values().as_slice(), want.as_slice());
+            }
+            Values::Integer(values) => {
+                let array = array.as_any().downcast_ref::<IntegerValues>().unwrap();
+                let want: Vec<_> = values.iter().map(|v| v.value).collect();
+                assert_eq!(array.values().as_slice(), want.as_slice());
+            }
+            Values::Bool(values) => {
+                let array = array.as_any().downcast_ref::<BoolValues>().unwrap();
+                let want: Vec<_> = values.iter().map(|v| v.value).collect();
+                assert_eq!(array.values_iter().collect::<Vec<_>>(), want);
+            }
+            Values::String(values) => {
+                let array = array.as_any().downcast_ref::<StringValues>().unwrap();
+                let want: Vec<_> = values.iter().map(|v| v.value.as_slice()).collect();
+                let got: Vec<_> = array.values_iter().map(|v| v.as_bytes()).collect();
+                assert_eq!(got, want);
+            }
+            Values::Unsigned(values) => {
+                let array = array.as_any().downcast_ref::<Unsigned>().unwrap();
+                let want: Vec<_> = values.iter().map(|v| v.value).collect();
+                assert_eq!(array.values().as_slice(), want.as_slice());
+            }
+        }
+
+        let want: Vec<i64> = match values {
+            Values::Float(values) => values.iter().map(|v| v.unix_nano).collect(),
+            Values::Integer(values) => values.iter().map(|v| v.unix_nano).collect(),
+            Values::Bool(values) => values.iter().map(|v| v.unix_nano).collect(),
+            Values::String(values) => values.iter().map(|v| v.unix_nano).collect(),
+            Values::Unsigned(values) => values.iter().map(|v| v.unix_nano).collect(),
+        };
+        assert_eq!(timestamps, want.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_arrow_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("arrow.tsm");
+
+        let series = blocks();
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        for (key, blocks) in series.iter() {
+            for values in blocks {
+                w.write(key.as_slice(), values.clone()).await.unwrap();
+            }
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let r = new_default_tsm_reader(StorageOperator::root(path.to_str().unwrap()).unwrap())
+            .await
+            .unwrap();
+        let fr = r.block_iterator_builder().await.unwrap();
+        for (key, blocks) in series.iter() {
+            let mut entries = IndexEntries::default();
+            r.read_entries(key.as_slice(), &mut entries).await.unwrap();
+
+            let mut itr = fr.arrow_blocks(key.as_slice()).await.unwrap();
+            for i in 0..entries.len() {
+                // the arrays match the values read from the block
+                let mut values = new_values(entries.typ).unwrap();
+                r.read_block_at(&entries.entry(i), &mut values)
+                    .await
+                    .unwrap();
+                assert_eq!(&values, &blocks[i]);
+
+                let (timestamps, array) = itr.try_next().await.unwrap().unwrap();
+                assert_block(timestamps.values().as_slice(), array.as_ref(), &values);
+                assert_eq!(to_chunk(timestamps, array).len(), values.len());
+            }
+            assert!(itr.try_next().await.unwrap().is_none());
+        }
+
+        let mut itr = fr.arrow_blocks(b"cpu#!~#missing").await.unwrap();
+        assert!(itr.try_next().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_arrow_block_empty() {
+        for typ in [
+            BLOCK_FLOAT64,
+            BLOCK_INTEGER,
+            BLOCK_BOOLEAN,
+            BLOCK_STRING,
+            BLOCK_UNSIGNED,
+        ] {
+            let (timestamps, values) = decode_arrow_block(typ, &[]).unwrap();
+            assert_eq!(timestamps.len(), 0);
+            assert_eq!(values.len(), 0);
+            assert_block(&[], values.as_ref(), &new_values(typ).unwrap());
+
+            let chunk = to_chunk(timestamps, values);
+            assert_eq!(chunk.arrays().len(), 2);
+            assert_eq!(chunk.len(), 0);
+        }
+    }
+}
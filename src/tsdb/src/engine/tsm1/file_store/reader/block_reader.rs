@@ -6,16 +6,31 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use crate::engine::tsm1::file_store::index::IndexEntry;
 
+/// BlockReadError are the errors of data which did not arrive intact, a retry
+/// may read the block fine.
+#[derive(Debug, thiserror::Error)]
+pub enum BlockReadError {
+    #[error("short read of block at {offset}: {read} of {size} bytes")]
+    ShortRead {
+        offset: u64,
+        read: usize,
+        size: usize,
+    },
+    #[error("block at {offset} does not match its checksum: {cause}")]
+    ChecksumMismatch { offset: u64, cause: String },
+}
+
 /// BlockAccessor abstracts a method of accessing blocks from a
 /// TSM file.
 #[async_trait]
 pub trait TSMBlock: Send + Sync {
+    /// read_block reads the block of entry into buf and returns its checksum.
     async fn read_block(
         &self,
         reader: &mut Reader,
         entry: &IndexEntry,
         buf: &mut Vec<u8>,
-    ) -> anyhow::Result<()>;
+    ) -> anyhow::Result<u32>;
     async fn free(&self) -> anyhow::Result<()>;
 }
 
@@ -40,6 +55,11 @@ impl DefaultBlockAccessor {
         })
     }
 
+    /// max_offset returns the end of the blocks section, where the index starts.
+    pub fn max_offset(&self) -> u64 {
+        self.max_offset
+    }
+
     fn inc_access(&self) {
         self.access_count.fetch_add(1, Ordering::Relaxed);
     }
@@ -53,7 +73,7 @@ impl TSMBlock for DefaultBlockAccessor {
         reader: &mut Reader,
         entry: &IndexEntry,
         buf: &mut Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<u32> {
         self.inc_access();
 
         if entry.offset + entry.size as u64 > self.max_offset {
@@ -62,16 +82,26 @@ impl TSMBlock for DefaultBlockAccessor {
 
         reader.seek(SeekFrom::Start(entry.offset)).await?;
 
-        let _checksum = reader.read_u32().await?;
+        let checksum = reader.read_u32().await?;
 
         let block_size = entry.size as usize - 4;
         buf.resize(block_size, 0);
-        let n = reader.read(buf.as_mut_slice()).await?;
+        let mut n = 0;
+        while n < block_size {
+            match reader.read(&mut buf[n..]).await? {
+                0 => break,
+                read => n += read,
+            }
+        }
         if n != block_size {
-            return Err(anyhow!("not enough entry were read"));
+            return Err(anyhow!(BlockReadError::ShortRead {
+                offset: entry.offset,
+                read: n,
+                size: block_size,
+            }));
         }
 
-        Ok(())
+        Ok(checksum)
     }
 
     async fn free(&self) -> anyhow::Result<()> {
@@ -5,17 +5,24 @@ use std::io::{ErrorKind, SeekFrom};
 use std::sync::Arc;
 
 use common_base::iterator::AsyncIterator;
+use common_base::point::KEY_FIELD_SEPARATOR;
+use influxdb_storage::file::mmap_file::MmapReadableFile;
 use influxdb_storage::opendal::Reader;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedRwLockReadGuard, RwLock};
 
 use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use crate::engine::tsm1::file_store::stat::{IndexLoadStats, MeasurementLoadStats};
 use crate::engine::tsm1::file_store::{
     KeyRange, TimeRange, INDEX_COUNT_SIZE, INDEX_ENTRY_SIZE, INDEX_TYPE_SIZE,
 };
 
 const NIL_OFFSET: u64 = u64::MAX;
 
+/// LAZY_INDEX_STRIDE is the number of keys between two offsets kept by a lazy index
+/// before any measurement is loaded.
+pub(crate) const LAZY_INDEX_STRIDE: usize = 128;
+
 // pub struct IndexHeader {
 //     index_of_offset: usize,
 //
@@ -47,6 +54,15 @@ pub trait TSMIndex: Send + Sync {
     /// contains return true if the given key exists in the index.
     async fn contains(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<bool>;
 
+    /// overlaps returns true if a block of key may hold values within time_range,
+    /// going by the min and max time of the blocks. Tombstones are not applied.
+    async fn overlaps(
+        &self,
+        reader: &mut Reader,
+        key: &[u8],
+        time_range: &TimeRange,
+    ) -> anyhow::Result<bool>;
+
     /// entries reads the index entries for key into entries.
     async fn entries(
         &self,
@@ -84,6 +100,14 @@ pub trait TSMIndex: Send + Sync {
 
     async fn key_iterator(&self, reader: Reader) -> anyhow::Result<KeyIterator>;
 
+    /// key_iterator_prefix returns an iterator over the keys starting with prefix,
+    /// seeking to the first of them instead of scanning the index from its start.
+    async fn key_iterator_prefix(
+        &self,
+        reader: Reader,
+        prefix: &[u8],
+    ) -> anyhow::Result<KeyIterator>;
+
     /// seek returns the position in the index where key <= value in the index.
     async fn seek(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<u64>;
 
@@ -109,12 +133,27 @@ pub trait TSMIndex: Send + Sync {
     /// BlockFloat64, BlockInt64, BlockBool, BlockString.  If key does not exist,
     /// an error is returned.
     async fn block_type(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<u8>;
+
+    /// ensure_measurement_loaded loads the offsets of the keys of measurement into a
+    /// lazy index, lookups do it on demand. It does nothing on an eager index.
+    async fn ensure_measurement_loaded(
+        &self,
+        reader: &mut Reader,
+        measurement: &[u8],
+    ) -> anyhow::Result<()>;
+
+    /// load_stats returns how much of the index is loaded.
+    async fn load_stats(&self) -> IndexLoadStats;
 }
 
 pub struct KeyIterator {
     reader: Reader,
     index_offset: u64,
     max_offset: u64,
+
+    /// prefix ends the iteration at the first key not starting with it, keys are
+    /// sorted.
+    prefix: Vec<u8>,
 }
 
 impl KeyIterator {
@@ -123,8 +162,20 @@ impl KeyIterator {
             reader,
             index_offset,
             max_offset: index_offset + (index_len as u64),
+            prefix: vec![],
         })
     }
+
+    /// with_prefix iterates from the key at offset, up to max_offset, for as long as
+    /// the keys start with prefix.
+    pub(crate) fn with_prefix(reader: Reader, offset: u64, max_offset: u64, prefix: &[u8]) -> Self {
+        Self {
+            reader,
+            index_offset: offset,
+            max_offset,
+            prefix: prefix.to_vec(),
+        }
+    }
 }
 
 #[async_trait]
@@ -149,6 +200,11 @@ impl AsyncIterator for KeyIterator {
         let count = self.reader.read_u16().await?;
         self.index_offset += (key_len as u64) + 5 + (count as u64) * (INDEX_ENTRY_SIZE as u64);
 
+        if !key.starts_with(self.prefix.as_slice()) {
+            self.index_offset = self.max_offset;
+            return Ok(None);
+        }
+
         Ok(Some(key))
     }
 }
@@ -159,10 +215,19 @@ pub(crate) struct IndirectIndex {
     index_offset: u64,
     index_len: u32,
 
+    /// mmap is the mapped TSM file the keys and entries are read from, see
+    /// `IndirectIndex::with_mmap`. Without it they are read with the reader passed
+    /// to each call.
+    mmap: Option<MmapReadableFile>,
+
     /// offsets contains the positions in b for each key.  It points to the 2 byte length of
-    /// key.
+    /// key. It is empty while the index is lazy.
     offsets: Arc<RwLock<Vec<u64>>>,
 
+    /// lazy holds the offsets loaded so far of an index opened lazily, None once
+    /// every offset is in `offsets`.
+    lazy: RwLock<Option<LazyOffsets>>,
+
     /// min_key, max_key are the minimum and maximum (lexicographically sorted) contained in the
     /// file
     min_key: Vec<u8>,
@@ -182,91 +247,86 @@ pub(crate) struct IndirectIndex {
 }
 
 impl IndirectIndex {
+    /// new builds the index of a TSM file read with reader. A lazy index only keeps
+    /// the offset of every `LAZY_INDEX_STRIDE`th key when built, the offsets of the
+    /// keys of a measurement are loaded the first time one of them is looked up.
     pub async fn new(
         reader: &mut Reader,
         index_offset: u64,
         index_len: u32,
+        lazy: bool,
     ) -> anyhow::Result<Self> {
-        if index_len == 0 {
-            return Err(anyhow!("no index found"));
-        }
-
-        let mut min_time: i64 = i64::MAX;
-        let mut max_time = 0_i64;
-
-        // To create our "indirect" index, we need to find the location of all the keys in
-        // the raw byte slice.  The keys are listed once each (in sorted order).  Following
-        // each key is a time ordered list of index entry blocks for that key.  The loop below
-        // basically skips across the slice keeping track of the counter when we are at a key
-        // field.
-        let mut i = index_offset;
-        let mut offsets = Vec::new();
-        let i_max = index_offset + index_len as u64;
-        while i < i_max {
-            offsets.push(i);
-
-            // Skip to the start of the values
-            // key length value (2) + type (1) + length of key
-            if i + 2 >= i_max {
-                return Err(anyhow!(
-                    "indirectIndex: not enough data for key length value"
-                ));
-            }
-            reader.seek(SeekFrom::Start(i)).await?;
-            let key_len = reader.read_u16().await?;
-            i += 3 + key_len as u64;
-
-            // count of index entries
-            if i + INDEX_COUNT_SIZE as u64 >= i_max {
-                return Err(anyhow!(
-                    "indirectIndex: not enough data for index entries count"
-                ));
-            }
-            reader.seek(SeekFrom::Start(i)).await?;
-            let count = reader.read_u16().await?;
-            i += INDEX_COUNT_SIZE as u64;
-
-            // Find the min time for the block
-            // first entry's min_time
-            if i + 8 >= i_max {
-                return Err(anyhow!("indirectIndex: not enough data for min time"));
-            }
-            reader.seek(SeekFrom::Start(i)).await?;
-            let min_t = reader.read_u64().await? as i64;
-            if min_t < min_time {
-                min_time = min_t;
-            }
-
-            i += (count as u64 - 1) * (INDEX_ENTRY_SIZE as u64);
-
-            // Find the max time for the block
-            // latest entry's max_time
-            if i + 16 >= i_max {
-                return Err(anyhow!("indirectIndex: not enough data for max time"));
-            }
-            reader.seek(SeekFrom::Start(i + 8)).await?;
-            let max_t = reader.read_u64().await? as i64;
-            if max_t > max_time {
-                max_time = max_t
-            }
+        Self::load(IndexSource::Reader(reader), index_offset, index_len, lazy).await
+    }
 
-            i += INDEX_ENTRY_SIZE as u64;
+    /// with_mmap builds the index of a memory mapped TSM file. Only the offsets of
+    /// the keys are held, the keys and their entries are read from the mapping
+    /// when asked for, so the memory used does not grow with the entries.
+    pub async fn with_mmap(
+        mmap: MmapReadableFile,
+        index_offset: u64,
+        index_len: u32,
+        lazy: bool,
+    ) -> anyhow::Result<Self> {
+        if index_offset + index_len as u64 > mmap.len() as u64 {
+            return Err(anyhow!(
+                "indirectIndex: index [{}, {}) past the end of a file of {} bytes",
+                index_offset,
+                index_offset + index_len as u64,
+                mmap.len()
+            ));
         }
 
-        let first_ofs = offsets[0];
-        let (_, min_key) = read_key(reader, first_ofs).await?;
+        let mut index = Self::load(
+            IndexSource::Mmap(mmap.as_slice()),
+            index_offset,
+            index_len,
+            lazy,
+        )
+        .await?;
+        index.mmap = Some(mmap);
+        Ok(index)
+    }
+
+    async fn load(
+        mut source: IndexSource<'_>,
+        index_offset: u64,
+        index_len: u32,
+        lazy: bool,
+    ) -> anyhow::Result<Self> {
+        let stride = if lazy { LAZY_INDEX_STRIDE } else { 1 };
+        let scan = scan_index(&mut source, index_offset, index_len, stride).await?;
+
+        // An empty index has no keys, min_time > max_time marks it as such.
+        let (min_key, max_key) = if scan.key_count == 0 {
+            (vec![], vec![])
+        } else {
+            let (_, min_key) = read_key(&mut source, scan.offsets[0]).await?;
+            let (_, max_key) = read_key(&mut source, scan.last_offset).await?;
+            (min_key, max_key)
+        };
 
-        let last_ofs = offsets[offsets.len() - 1];
-        let (_, max_key) = read_key(reader, last_ofs).await?;
+        let (offsets, lazy) = if lazy {
+            let lazy = LazyOffsets {
+                sparse: scan.offsets,
+                key_count: scan.key_count,
+                measurements: HashMap::new(),
+            };
+            (vec![], Some(lazy))
+        } else {
+            (scan.offsets, None)
+        };
 
         Ok(Self {
             index_offset,
             index_len,
+            mmap: None,
             offsets: Arc::new(RwLock::new(offsets)),
+            lazy: RwLock::new(lazy),
             min_key,
             max_key,
-            min_time,
-            max_time,
+            min_time: scan.min_time,
+            max_time: scan.max_time,
             tombstones: Default::default(),
         })
     }
@@ -281,6 +341,7 @@ impl IndirectIndex {
         let mut left = 0;
         let mut right = size;
 
+        let mut source = self.source(reader);
         let mut key_buf: Vec<u8> = vec![];
         while left < right {
             let mid = (left + right) / 2;
@@ -288,22 +349,9 @@ impl IndirectIndex {
             let cmp = {
                 let offset = offsets[mid];
 
-                reader.seek(SeekFrom::Start(offset)).await?;
-
-                let key_len = reader.read_u16().await? as usize;
-                if key_buf.len() < key_len {
-                    key_buf.resize(key_len, 0_u8);
-                } else if key_buf.len() > key_len {
-                    key_buf.truncate(key_len);
-                }
-
-                let n = reader.read(&mut key_buf).await?;
-                if n != key_len {
-                    return Err(io::Error::new(
-                        ErrorKind::InvalidData,
-                        "not enough keys were read",
-                    ));
-                }
+                let key_len = source.read_u16(offset).await? as usize;
+                key_buf.resize(key_len, 0_u8);
+                source.read_exact(offset + 2, &mut key_buf).await?;
 
                 key_buf.as_slice().cmp(key)
             };
@@ -320,6 +368,19 @@ impl IndirectIndex {
         Ok(left as isize * -1)
     }
 
+    /// source returns where to read the index from, the mapping if there is one.
+    fn source<'a>(&'a self, reader: &'a mut Reader) -> IndexSource<'a> {
+        match &self.mmap {
+            Some(mmap) => IndexSource::Mmap(mmap.as_slice()),
+            None => IndexSource::Reader(reader),
+        }
+    }
+
+    /// is_empty returns true if the index was loaded without any key.
+    fn is_empty(&self) -> bool {
+        self.min_time > self.max_time
+    }
+
     /// search_offset searches the offsets slice for key and returns the position in
     /// offsets where key would exist.
     async fn search_offset(
@@ -339,6 +400,184 @@ impl IndirectIndex {
 
         Ok(Some(i as usize))
     }
+
+    /// offsets_for returns the offsets to search key in: all of them, or those of
+    /// the measurement of key when the index is lazy.
+    async fn offsets_for(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<Offsets> {
+        match self
+            .measurement_offsets(reader, measurement_of(key))
+            .await?
+        {
+            Some(offsets) => Ok(Offsets::Measurement(offsets)),
+            None => Ok(Offsets::All(self.offsets.clone().read_owned().await)),
+        }
+    }
+
+    /// measurement_offsets returns the offsets of the keys of measurement in a lazy
+    /// index, loading them on first use. It returns None once the index is eager.
+    ///
+    /// The keys of a measurement are the measurement followed by `,` or by the field
+    /// separator, so they all sort in `measurement_bounds`. The index is walked from
+    /// the sparse offset before the lower bound up to the upper bound, keys of other
+    /// measurements met on the way (`cpu$x` sorts between `cpu#!~#..` and `cpu,..`)
+    /// are skipped.
+    async fn measurement_offsets(
+        &self,
+        reader: &mut Reader,
+        measurement: &[u8],
+    ) -> anyhow::Result<Option<Arc<Vec<u64>>>> {
+        match self.lazy.read().await.as_ref() {
+            None => return Ok(None),
+            Some(lazy) => {
+                if let Some(offsets) = lazy.measurements.get(measurement) {
+                    return Ok(Some(offsets.clone()));
+                }
+            }
+        }
+
+        let mut state = self.lazy.write().await;
+        let lazy = match state.as_mut() {
+            None => return Ok(None),
+            Some(lazy) => lazy,
+        };
+        if let Some(offsets) = lazy.measurements.get(measurement) {
+            return Ok(Some(offsets.clone()));
+        }
+
+        let (lower, upper) = measurement_bounds(measurement);
+        let max_offset = self.index_offset + self.index_len as u64;
+        let mut offset = self.sparse_seek(reader, &lazy.sparse, &lower).await?;
+
+        let mut source = self.source(reader);
+        let mut offsets = vec![];
+        while offset < max_offset {
+            let (key, next) = read_key_record(&mut source, offset).await?;
+            if key.as_slice() >= upper.as_slice() {
+                break;
+            }
+            if measurement_of(&key) == measurement {
+                offsets.push(offset);
+            }
+            offset = next;
+        }
+
+        let offsets = Arc::new(offsets);
+        lazy.measurements
+            .insert(measurement.to_vec(), offsets.clone());
+        Ok(Some(offsets))
+    }
+
+    /// ensure_all_loaded makes a lazy index eager, loading the offset of every key.
+    /// Lookups by position and deletes need them all.
+    async fn ensure_all_loaded(&self, reader: &mut Reader) -> anyhow::Result<()> {
+        let mut lazy = self.lazy.write().await;
+        if lazy.is_none() {
+            return Ok(());
+        }
+
+        let mut source = self.source(reader);
+        let scan = scan_index(&mut source, self.index_offset, self.index_len, 1).await?;
+        *self.offsets.write().await = scan.offsets;
+        *lazy = None;
+        Ok(())
+    }
+
+    /// seek_offset returns the offset of the first key >= key, or the end of the
+    /// index if there is none.
+    async fn seek_offset(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<u64> {
+        if let Some(lazy) = self.lazy.read().await.as_ref() {
+            return self.sparse_seek(reader, &lazy.sparse, key).await;
+        }
+
+        let offsets = self.offsets.read().await;
+        // binary_search returns the negated insert position of a missing key, the
+        // position of the first key >= key either way.
+        let i = self
+            .binary_search(reader, offsets.as_slice(), key)
+            .await?
+            .unsigned_abs();
+        Ok(offsets
+            .get(i)
+            .copied()
+            .unwrap_or(self.index_offset + self.index_len as u64))
+    }
+
+    /// sparse_seek is `seek_offset` over the sparse offsets of a lazy index, it walks
+    /// the keys following the sparse offset before key.
+    async fn sparse_seek(
+        &self,
+        reader: &mut Reader,
+        sparse: &[u64],
+        key: &[u8],
+    ) -> anyhow::Result<u64> {
+        let max_offset = self.index_offset + self.index_len as u64;
+
+        let i = self.binary_search(reader, sparse, key).await?;
+        let anchor = if i > 0 {
+            i as usize
+        } else {
+            i.unsigned_abs().saturating_sub(1)
+        };
+        let mut offset = match sparse.get(anchor) {
+            Some(offset) => *offset,
+            None => return Ok(max_offset),
+        };
+
+        let mut source = self.source(reader);
+        while offset < max_offset {
+            let (k, next) = read_key_record(&mut source, offset).await?;
+            if k.as_slice() >= key {
+                return Ok(offset);
+            }
+            offset = next;
+        }
+        Ok(max_offset)
+    }
+
+    /// read_key_entries reads the key at offset, and its entries into entries.
+    async fn read_key_entries(
+        &self,
+        reader: &mut Reader,
+        offset: u64,
+        entries: &mut IndexEntries,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut source = self.source(reader);
+        let (n, key) = read_key(&mut source, offset).await?;
+        read_entries(
+            &mut source,
+            offset + n as u64,
+            self.index_offset + self.index_len as u64,
+            entries,
+        )
+        .await?;
+        Ok(key)
+    }
+}
+
+/// LazyOffsets are the offsets of a lazy index loaded so far.
+struct LazyOffsets {
+    /// sparse holds the offset of every `LAZY_INDEX_STRIDE`th key.
+    sparse: Vec<u64>,
+    key_count: usize,
+    /// measurements holds the offsets of the keys of the measurements loaded.
+    measurements: HashMap<Vec<u8>, Arc<Vec<u64>>>,
+}
+
+/// Offsets are the sorted key offsets a key is searched in.
+enum Offsets {
+    All(OwnedRwLockReadGuard<Vec<u64>>),
+    Measurement(Arc<Vec<u64>>),
+}
+
+impl std::ops::Deref for Offsets {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        match self {
+            Self::All(offsets) => offsets.as_slice(),
+            Self::Measurement(offsets) => offsets.as_slice(),
+        }
+    }
 }
 
 #[async_trait]
@@ -349,6 +588,7 @@ impl TSMIndex for IndirectIndex {
         }
 
         keys.sort();
+        self.ensure_all_loaded(reader).await?;
 
         // Both keys and offsets are sorted.  Walk both in order and skip
         // any keys that exist in both.
@@ -370,7 +610,7 @@ impl TSMIndex for IndirectIndex {
             let offset = offsets[i];
             let del_key = keys[key_index];
 
-            let (_, key) = read_key(reader, offset).await?;
+            let (_, key) = read_key(&mut self.source(reader), offset).await?;
 
             while key_index < keys.len() && del_key.cmp(key.as_slice()).is_lt() {
                 key_index += 1;
@@ -423,6 +663,8 @@ impl TSMIndex for IndirectIndex {
             return Ok(());
         }
 
+        self.ensure_all_loaded(reader).await?;
+
         let mut full_keys = Vec::with_capacity(keys.len());
         let mut entries = IndexEntries::default();
         let mut key_index = 0;
@@ -451,7 +693,7 @@ impl TSMIndex for IndirectIndex {
             }
 
             // If multiple tombstones are saved for the same key
-            if entries.entries.len() == 0 {
+            if entries.is_empty() {
                 continue;
             }
 
@@ -539,7 +781,9 @@ impl TSMIndex for IndirectIndex {
     }
 
     fn contains_key(&self, key: &[u8]) -> bool {
-        key.cmp(self.min_key.as_slice()).is_ge() && key.cmp(self.max_key.as_slice()).is_le()
+        !self.is_empty()
+            && key.cmp(self.min_key.as_slice()).is_ge()
+            && key.cmp(self.max_key.as_slice()).is_le()
     }
 
     async fn contains(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<bool> {
@@ -548,23 +792,47 @@ impl TSMIndex for IndirectIndex {
         // Ok(entries.entries.len() > 0)
 
         // optimization
-        let offsets = self.offsets.clone();
-        let offsets = offsets.read().await;
-        let offset_index = self.search_offset(reader, offsets.as_slice(), key).await?;
+        if !self.contains_key(key) {
+            return Ok(false);
+        }
+        let offsets = self.offsets_for(reader, key).await?;
+        let offset_index = self.search_offset(reader, &offsets, key).await?;
         Ok(offset_index.is_some())
     }
 
+    async fn overlaps(
+        &self,
+        reader: &mut Reader,
+        key: &[u8],
+        time_range: &TimeRange,
+    ) -> anyhow::Result<bool> {
+        if !self.time_range().overlaps(time_range) {
+            return Ok(false);
+        }
+
+        let mut entries = IndexEntries::default();
+        self.entries(reader, key, &mut entries).await?;
+        let overlaps = entries
+            .iter()
+            .any(|x| TimeRange::new(x.min_time, x.max_time).overlaps(time_range));
+        Ok(overlaps)
+    }
+
     async fn entries(
         &self,
         reader: &mut Reader,
         key: &[u8],
         entries: &mut IndexEntries,
     ) -> anyhow::Result<()> {
-        let offsets = self.offsets.clone();
-        let offsets = offsets.read().await;
-        let offset_index = self.search_offset(reader, offsets.as_slice(), key).await?;
+        if !self.contains_key(key) {
+            return Ok(());
+        }
+        let offsets = self.offsets_for(reader, key).await?;
+        let offset_index = self.search_offset(reader, &offsets, key).await?;
         if let Some(index) = offset_index {
-            let k = self.key(reader, index, entries).await?;
+            let k = self
+                .read_key_entries(reader, offsets[index], entries)
+                .await?;
             if !k.as_slice().cmp(key).is_eq() {
                 return Err(anyhow!(
                     "key is inconsistency, expect: {:?}, found: {:?}",
@@ -587,7 +855,7 @@ impl TSMIndex for IndirectIndex {
         let mut entries = IndexEntries::default();
         self.entries(reader, key, &mut entries).await?;
 
-        for entry in entries.entries {
+        for entry in entries.iter() {
             if entry.contains(timestamp) {
                 return Ok(Some(entry));
             }
@@ -601,24 +869,14 @@ impl TSMIndex for IndirectIndex {
         index: usize,
         entries: &mut IndexEntries,
     ) -> anyhow::Result<Vec<u8>> {
+        self.ensure_all_loaded(reader).await?;
+
         let offsets = self.offsets.read().await;
         if index >= offsets.len() {
             return Err(anyhow!("offset's index out of bounds"));
         }
 
-        let mut offset = offsets[index];
-        let (n, key) = read_key(reader, offset).await?;
-        offset += n as u64;
-
-        let _ = read_entries(
-            reader,
-            offset,
-            self.index_offset + self.index_len as u64,
-            entries,
-        )
-        .await?;
-
-        Ok(key)
+        self.read_key_entries(reader, offsets[index], entries).await
     }
 
     async fn key_at(
@@ -626,22 +884,27 @@ impl TSMIndex for IndirectIndex {
         reader: &mut Reader,
         index: usize,
     ) -> anyhow::Result<Option<(Vec<u8>, u8)>> {
+        self.ensure_all_loaded(reader).await?;
+
         let offsets = self.offsets.read().await;
         if index >= offsets.len() {
             return Ok(None);
         }
 
         let mut offset = offsets[index];
-        let (n, key) = read_key(reader, offset).await?;
+        let mut source = self.source(reader);
+        let (n, key) = read_key(&mut source, offset).await?;
         offset += n as u64;
 
-        reader.seek(SeekFrom::Start(offset)).await?;
-        let typ = reader.read_u8().await?;
+        let typ = source.read_u8(offset).await?;
 
         Ok(Some((key, typ)))
     }
 
     async fn key_count(&self) -> usize {
+        if let Some(lazy) = self.lazy.read().await.as_ref() {
+            return lazy.key_count;
+        }
         let offsets = self.offsets.read().await;
         offsets.len()
     }
@@ -650,22 +913,52 @@ impl TSMIndex for IndirectIndex {
         KeyIterator::new(reader, self.index_offset, self.index_len).await
     }
 
+    async fn key_iterator_prefix(
+        &self,
+        mut reader: Reader,
+        prefix: &[u8],
+    ) -> anyhow::Result<KeyIterator> {
+        let max_offset = self.index_offset + self.index_len as u64;
+
+        // The keys starting with a prefix longer than its measurement are all keys
+        // of that measurement, a lazy index only needs to load it.
+        let measurement = measurement_of(prefix);
+        let offsets = if measurement.len() < prefix.len() {
+            self.measurement_offsets(&mut reader, measurement).await?
+        } else {
+            None
+        };
+        let offset = match offsets {
+            Some(offsets) => {
+                let i = self
+                    .binary_search(&mut reader, offsets.as_slice(), prefix)
+                    .await?
+                    .unsigned_abs();
+                offsets.get(i).copied().unwrap_or(max_offset)
+            }
+            None => self.seek_offset(&mut reader, prefix).await?,
+        };
+
+        Ok(KeyIterator::with_prefix(reader, offset, max_offset, prefix))
+    }
+
     async fn seek(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<u64> {
-        let offsets = self.offsets.clone();
-        let offsets = offsets.read().await;
+        let offsets = self.offsets_for(reader, key).await?;
         let offset_index = self
-            .search_offset(reader, offsets.as_slice(), key)
+            .search_offset(reader, &offsets, key)
             .await?
             .ok_or(anyhow!("key not found"))?;
         Ok(offsets[offset_index])
     }
 
     fn overlaps_time_range(&self, min: i64, max: i64) -> bool {
-        self.min_time <= max && self.max_time >= min
+        !self.is_empty() && self.min_time <= max && self.max_time >= min
     }
 
     fn overlaps_key_range(&self, min: &[u8], max: &[u8]) -> bool {
-        self.min_key.as_slice().cmp(max).is_le() && self.max_key.as_slice().cmp(min).is_ge()
+        !self.is_empty()
+            && self.min_key.as_slice().cmp(max).is_le()
+            && self.max_key.as_slice().cmp(min).is_ge()
     }
 
     fn size(&self) -> u32 {
@@ -689,43 +982,252 @@ impl TSMIndex for IndirectIndex {
     }
 
     async fn block_type(&self, reader: &mut Reader, key: &[u8]) -> anyhow::Result<u8> {
-        let offsets = self.offsets.clone();
-        let offsets = offsets.read().await;
+        let offsets = self.offsets_for(reader, key).await?;
 
         let offset_index = self
-            .search_offset(reader, offsets.as_slice(), key)
+            .search_offset(reader, &offsets, key)
             .await?
             .ok_or(anyhow!("key not found"))?;
         let offset = offsets[offset_index];
 
-        let (n, _key) = read_key(reader, offset).await?;
+        let mut source = self.source(reader);
+        let (n, _key) = read_key(&mut source, offset).await?;
 
-        reader.seek(SeekFrom::Start(offset + n as u64)).await?;
-        let typ = reader.read_u8().await?;
+        let typ = source.read_u8(offset + n as u64).await?;
         Ok(typ)
     }
+
+    async fn ensure_measurement_loaded(
+        &self,
+        reader: &mut Reader,
+        measurement: &[u8],
+    ) -> anyhow::Result<()> {
+        self.measurement_offsets(reader, measurement).await?;
+        Ok(())
+    }
+
+    async fn load_stats(&self) -> IndexLoadStats {
+        let lazy = self.lazy.read().await;
+        let lazy = match lazy.as_ref() {
+            Some(lazy) => lazy,
+            None => {
+                let offsets = self.offsets.read().await;
+                return IndexLoadStats {
+                    lazy: false,
+                    offsets_bytes: offsets.len() * std::mem::size_of::<u64>(),
+                    measurements: vec![],
+                };
+            }
+        };
+
+        let mut measurements: Vec<MeasurementLoadStats> = lazy
+            .measurements
+            .iter()
+            .map(|(measurement, offsets)| MeasurementLoadStats {
+                measurement: measurement.clone(),
+                key_count: offsets.len(),
+                bytes: offsets.len() * std::mem::size_of::<u64>(),
+            })
+            .collect();
+        measurements.sort_by(|a, b| a.measurement.cmp(&b.measurement));
+
+        let offsets_bytes = lazy.sparse.len() * std::mem::size_of::<u64>()
+            + measurements.iter().map(|x| x.bytes).sum::<usize>();
+        IndexLoadStats {
+            lazy: true,
+            offsets_bytes,
+            measurements,
+        }
+    }
+}
+
+/// IndexSource is where the bytes of an index are read from, all reads are at an
+/// offset of the TSM file.
+enum IndexSource<'a> {
+    Reader(&'a mut Reader),
+    Mmap(&'a [u8]),
 }
 
-async fn read_key(reader: &mut Reader, index_offset: u64) -> io::Result<(u16, Vec<u8>)> {
-    reader.seek(SeekFrom::Start(index_offset)).await?;
-    let key_len = reader.read_u16().await?;
+impl IndexSource<'_> {
+    async fn read_exact(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Self::Reader(reader) => {
+                reader.seek(SeekFrom::Start(offset)).await?;
+                reader.read_exact(buf).await?;
+            }
+            Self::Mmap(b) => {
+                let start = offset as usize;
+                let data = start
+                    .checked_add(buf.len())
+                    .and_then(|end| b.get(start..end))
+                    .ok_or_else(|| {
+                        io::Error::new(ErrorKind::UnexpectedEof, "read past the end of the index")
+                    })?;
+                buf.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_u8(&mut self, offset: u64) -> io::Result<u8> {
+        let mut b = [0_u8; 1];
+        self.read_exact(offset, &mut b).await?;
+        Ok(b[0])
+    }
 
-    let mut key = Vec::with_capacity(key_len as usize);
-    key.resize(key_len as usize, 0);
+    async fn read_u16(&mut self, offset: u64) -> io::Result<u16> {
+        let mut b = [0_u8; 2];
+        self.read_exact(offset, &mut b).await?;
+        Ok(u16::from_be_bytes(b))
+    }
 
-    let n = reader.read(&mut key).await?;
-    if n != key_len as usize {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "not enough keys were read",
-        ));
+    async fn read_u64(&mut self, offset: u64) -> io::Result<u64> {
+        let mut b = [0_u8; 8];
+        self.read_exact(offset, &mut b).await?;
+        Ok(u64::from_be_bytes(b))
     }
+}
+
+async fn read_key(source: &mut IndexSource<'_>, index_offset: u64) -> io::Result<(u16, Vec<u8>)> {
+    let key_len = source.read_u16(index_offset).await?;
+
+    let mut key = vec![0; key_len as usize];
+    source.read_exact(index_offset + 2, &mut key).await?;
 
     Ok((key_len + 2, key))
 }
 
+/// read_key_record reads the key at offset and returns it with the offset of the
+/// next key.
+async fn read_key_record(source: &mut IndexSource<'_>, offset: u64) -> io::Result<(Vec<u8>, u64)> {
+    let (n, key) = read_key(source, offset).await?;
+    let count = source
+        .read_u16(offset + n as u64 + INDEX_TYPE_SIZE as u64)
+        .await?;
+    let next = offset
+        + (n as usize + INDEX_TYPE_SIZE + INDEX_COUNT_SIZE) as u64
+        + count as u64 * INDEX_ENTRY_SIZE as u64;
+    Ok((key, next))
+}
+
+/// IndexScan is what `scan_index` finds in an index.
+struct IndexScan {
+    /// offsets holds the offset of every stride-th key, from the first one.
+    offsets: Vec<u64>,
+    last_offset: u64,
+    key_count: usize,
+    min_time: i64,
+    max_time: i64,
+}
+
+/// scan_index walks every key of the index, keeping the offset of one key out of
+/// stride.
+async fn scan_index(
+    source: &mut IndexSource<'_>,
+    index_offset: u64,
+    index_len: u32,
+    stride: usize,
+) -> anyhow::Result<IndexScan> {
+    let mut min_time: i64 = i64::MAX;
+    let mut max_time = i64::MIN;
+
+    // To create our "indirect" index, we need to find the location of all the keys in
+    // the raw byte slice.  The keys are listed once each (in sorted order).  Following
+    // each key is a time ordered list of index entry blocks for that key.  The loop below
+    // basically skips across the slice keeping track of the counter when we are at a key
+    // field.
+    let mut i = index_offset;
+    let mut offsets = Vec::new();
+    let mut last_offset = index_offset;
+    let mut key_count = 0;
+    let i_max = index_offset + index_len as u64;
+    while i < i_max {
+        if key_count % stride == 0 {
+            offsets.push(i);
+        }
+        last_offset = i;
+        key_count += 1;
+
+        // Skip to the start of the values
+        // key length value (2) + type (1) + length of key
+        if i + 2 >= i_max {
+            return Err(anyhow!(
+                "indirectIndex: not enough data for key length value"
+            ));
+        }
+        let key_len = source.read_u16(i).await?;
+        i += 3 + key_len as u64;
+
+        // count of index entries
+        if i + INDEX_COUNT_SIZE as u64 >= i_max {
+            return Err(anyhow!(
+                "indirectIndex: not enough data for index entries count"
+            ));
+        }
+        let count = source.read_u16(i).await?;
+        i += INDEX_COUNT_SIZE as u64;
+
+        // Find the min time for the block
+        // first entry's min_time
+        if i + 8 >= i_max {
+            return Err(anyhow!("indirectIndex: not enough data for min time"));
+        }
+        let min_t = source.read_u64(i).await? as i64;
+        if min_t < min_time {
+            min_time = min_t;
+        }
+
+        i += (count as u64 - 1) * (INDEX_ENTRY_SIZE as u64);
+
+        // Find the max time for the block
+        // latest entry's max_time
+        if i + 16 >= i_max {
+            return Err(anyhow!("indirectIndex: not enough data for max time"));
+        }
+        let max_t = source.read_u64(i + 8).await? as i64;
+        if max_t > max_time {
+            max_time = max_t
+        }
+
+        i += INDEX_ENTRY_SIZE as u64;
+    }
+
+    Ok(IndexScan {
+        offsets,
+        last_offset,
+        key_count,
+        min_time,
+        max_time,
+    })
+}
+
+/// measurement_of returns the measurement of a TSM key as stored, escaped: the
+/// bytes before its first unescaped `,` or field separator.
+pub(crate) fn measurement_of(key: &[u8]) -> &[u8] {
+    let separator = KEY_FIELD_SEPARATOR.as_bytes();
+    let mut i = 0;
+    while i < key.len() {
+        match key[i] {
+            b'\\' => i += 2,
+            b',' => return &key[..i],
+            _ if key[i..].starts_with(separator) => return &key[..i],
+            _ => i += 1,
+        }
+    }
+    key
+}
+
+/// measurement_bounds returns the range [lower, upper) holding the keys of
+/// measurement. They are the measurement followed by `,` or `#!~#`, `,` being the
+/// greater of both, the keys of other measurements may sort in between.
+fn measurement_bounds(measurement: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut upper = measurement.to_vec();
+    upper.push(b',' + 1);
+    (measurement.to_vec(), upper)
+}
+
 async fn read_entries(
-    reader: &mut Reader,
+    source: &mut IndexSource<'_>,
     mut offset: u64,
     max_offset: u64,
     entries: &mut IndexEntries,
@@ -736,25 +1238,18 @@ async fn read_entries(
     }
 
     // 1 byte block type
-    reader.seek(SeekFrom::Start(offset)).await?;
-    let typ = reader.read_u8().await?;
+    let typ = source.read_u8(offset).await?;
     entries.set_block_type(typ);
     offset += 1;
 
     // 2 byte count of index entries
-    let count = reader.read_u16().await? as usize;
+    let count = source.read_u16(offset).await? as usize;
     offset += 2;
 
-    entries.clear_with_cap(count);
-
-    let mut entry_buf = [0_u8; INDEX_ENTRY_SIZE];
-    for _ in 0..count {
-        reader.read(&mut entry_buf).await?;
-        offset += INDEX_ENTRY_SIZE as u64;
-
-        let entry = IndexEntry::read_from(&entry_buf)?;
-        entries.push(entry);
-    }
+    let mut raw = vec![0_u8; count * INDEX_ENTRY_SIZE];
+    source.read_exact(offset, &mut raw).await?;
+    offset += raw.len() as u64;
+    entries.set_raw(raw)?;
 
     Ok(offset)
 }
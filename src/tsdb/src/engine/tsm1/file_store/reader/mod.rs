@@ -1,3 +1,4 @@
+pub mod arrow_block_iterator;
 pub mod batch_deleter;
 pub mod block_reader;
 pub mod file_store_reader;
@@ -52,11 +52,11 @@ where
     type Item<'b> = &'b [u8] where Self: 'b;
 
     async fn try_next<'c>(&'c mut self) -> anyhow::Result<Option<Self::Item<'c>>> {
-        if self.entries.entries.len() == 0 || self.i >= self.entries.entries.len() {
+        if self.entries.is_empty() || self.i >= self.entries.len() {
             return Ok(None);
         }
 
-        let ie = self.entries.entries[self.i].clone();
+        let ie = self.entries.entry(self.i);
         self.i += 1;
 
         let mut reader = self.reader.lock().await;
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use common_arrow::arrow::array::Array;
 use common_arrow::arrow::chunk::Chunk;
 use common_base::iterator::AsyncIterator;
-use common_base::point::series_field_key;
+use common_base::point::tsm_key;
 use influxdb_storage::opendal::Reader;
 use tokio::sync::Mutex;
 
@@ -73,7 +73,7 @@ where
         let mut builders = Vec::with_capacity(fields.len());
 
         for field in fields {
-            let key = series_field_key(key, field);
+            let key = tsm_key(key, field);
 
             let entries = self.entries(key.as_slice()).await?;
             let typ = entries.typ;
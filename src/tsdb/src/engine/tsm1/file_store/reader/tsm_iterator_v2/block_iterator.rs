@@ -1,11 +1,11 @@
 use std::sync::Arc;
 
-use common_base::iterator::RefAsyncIterator;
+use common_base::iterator::{AsyncIterator, RefAsyncIterator};
 use influxdb_storage::opendal::Reader;
 use tokio::sync::Mutex;
 
 use crate::engine::tsm1::file_store::index::IndexEntries;
-use crate::engine::tsm1::file_store::reader::block_reader::TSMBlock;
+use crate::engine::tsm1::file_store::reader::block_reader::{BlockReadError, TSMBlock};
 use crate::engine::tsm1::file_store::reader::index_reader::TSMIndex;
 use crate::engine::tsm1::file_store::reader::tsm_reader::ShareTSMReaderInner;
 
@@ -51,14 +51,17 @@ where
     B: TSMBlock,
     I: TSMIndex,
 {
-    type Item<'b> = &'b [u8] where Self: 'b;
+    type Item<'b>
+        = &'b [u8]
+    where
+        Self: 'b;
 
     async fn try_next<'c>(&'c mut self) -> anyhow::Result<Option<Self::Item<'c>>> {
-        if self.entries.entries.len() == 0 || self.i >= self.entries.entries.len() {
+        if self.entries.is_empty() || self.i >= self.entries.len() {
             return Ok(None);
         }
 
-        let ie = self.entries.entries[self.i].clone();
+        let ie = self.entries.entry(self.i);
         self.i += 1;
 
         let mut reader = self.reader.lock().await;
@@ -70,3 +73,105 @@ where
         Ok(Some(self.block.as_slice()))
     }
 }
+
+/// RawBlock is an encoded block of a TSM file with the index information needed to
+/// write it into another file, see `TSMWriter::write_raw_block`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawBlock {
+    pub key: Vec<u8>,
+    pub typ: u8,
+    pub min_time: i64,
+    pub max_time: i64,
+    /// data is the encoded block, without its checksum.
+    pub data: Vec<u8>,
+}
+
+/// RawBlockIterator iterates over every block of a TSM file in file order, i.e. by
+/// key then time, without decoding them. The checksum of each block is verified.
+pub struct RawBlockIterator<B, I>
+where
+    B: TSMBlock,
+    I: TSMIndex,
+{
+    reader: Reader,
+    inner: ShareTSMReaderInner<I, B>,
+
+    key_count: usize,
+    /// key_index is the position in the index of the next key.
+    key_index: usize,
+
+    key: Vec<u8>,
+    entries: IndexEntries,
+    i: usize,
+}
+
+impl<B, I> RawBlockIterator<B, I>
+where
+    B: TSMBlock,
+    I: TSMIndex,
+{
+    pub(crate) async fn new(
+        reader: Reader,
+        inner: ShareTSMReaderInner<I, B>,
+    ) -> anyhow::Result<RawBlockIterator<B, I>> {
+        let key_count = inner.index().key_count().await;
+        Ok(Self {
+            reader,
+            inner,
+            key_count,
+            key_index: 0,
+            key: vec![],
+            entries: IndexEntries::default(),
+            i: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl<B, I> AsyncIterator for RawBlockIterator<B, I>
+where
+    B: TSMBlock + 'static,
+    I: TSMIndex + 'static,
+{
+    type Item = RawBlock;
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        while self.i >= self.entries.len() {
+            if self.key_index >= self.key_count {
+                return Ok(None);
+            }
+
+            self.key = self
+                .inner
+                .index()
+                .key(&mut self.reader, self.key_index, &mut self.entries)
+                .await?;
+            self.key_index += 1;
+            self.i = 0;
+        }
+
+        let ie = self.entries.entry(self.i);
+        self.i += 1;
+
+        let mut data = vec![];
+        let checksum = self
+            .inner
+            .block()
+            .read_block(&mut self.reader, &ie, &mut data)
+            .await?;
+        if crc32fast::hash(data.as_slice()) != checksum {
+            return Err(anyhow!(BlockReadError::ChecksumMismatch {
+                offset: ie.offset,
+                cause: "raw block read".to_string(),
+            }));
+        }
+
+        Ok(Some(RawBlock {
+            key: self.key.clone(),
+            typ: self.entries.typ,
+            min_time: ie.min_time,
+            max_time: ie.max_time,
+            data,
+        }))
+    }
+}
@@ -1,11 +1,16 @@
 use std::sync::Arc;
 
-use common_base::iterator::RefAsyncIterator;
+use common_arrow::arrow::datatypes::{DataType, TimeUnit};
+use common_arrow::{ArrayRef, FloatValues, FloatValuesVec, Timestamps, TimestampsVec};
+use common_base::iterator::{AsyncIterator, RefAsyncIterator, TryIterator};
 use influxdb_storage::opendal::Reader;
 use influxdb_storage::StorageOperator;
 use tokio::sync::Mutex;
 
+use crate::engine::tsm1::block::decoder::{block_count, FloatValueIterator};
+use crate::engine::tsm1::block::BLOCK_FLOAT64;
 use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use crate::engine::tsm1::file_store::reader::arrow_block_iterator::ArrowBlockIterator;
 use crate::engine::tsm1::file_store::reader::block_reader::TSMBlock;
 use crate::engine::tsm1::file_store::reader::index_reader::TSMIndex;
 use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::block_iterator::BlockIterator;
@@ -15,6 +20,25 @@ use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::values_iterator::{
 use crate::engine::tsm1::file_store::reader::tsm_reader::ShareTSMReaderInner;
 use crate::engine::tsm1::value::Array;
 
+pub(crate) const TIMESTAMP_DATA_TYPE: DataType = DataType::Timestamp(TimeUnit::Nanosecond, None);
+
+/// ArrowBlock holds the values of a key as arrow arrays, the timestamps and the
+/// values at the same positions.
+pub struct ArrowBlock {
+    pub timestamps: Timestamps,
+    pub values: FloatValues,
+}
+
+impl ArrowBlock {
+    pub fn len(&self) -> usize {
+        self.timestamps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 #[async_trait]
 pub trait FieldReader: Send + Sync {
     fn path(&self) -> &str;
@@ -22,6 +46,16 @@ pub trait FieldReader: Send + Sync {
     async fn read<'a, 'b>(&'a self, key: &[u8]) -> anyhow::Result<Box<dyn EntriesValuesReader>>;
 
     async fn read_at(&self, entry: &IndexEntry, values: &mut Box<dyn Array>) -> anyhow::Result<()>;
+
+    /// build_arrow_f64 decodes all blocks of the float key into arrow arrays.
+    async fn build_arrow_f64(&self, key: &[u8]) -> anyhow::Result<ArrowBlock>;
+
+    /// arrow_blocks returns an iterator decoding the blocks of key into arrow
+    /// arrays, see `ArrowBlockIterator`. A missing key has no blocks.
+    async fn arrow_blocks(
+        &self,
+        key: &[u8],
+    ) -> anyhow::Result<Box<dyn AsyncIterator<Item = (Timestamps, ArrayRef)>>>;
 }
 
 pub struct DefaultFieldReader<B, I>
@@ -88,10 +122,8 @@ where
     }
 
     async fn read_at(&self, entry: &IndexEntry, values: &mut Box<dyn Array>) -> anyhow::Result<()> {
-        let entries = IndexEntries {
-            typ: 0, // ignore this
-            entries: vec![entry.clone()],
-        };
+        let mut entries = IndexEntries::new(0); // type is ignored
+        entries.push(entry.clone());
         let mut itr: BlockIterator<B, I> =
             BlockIterator::new(entries, self.reader.clone(), self.inner.clone()).await?;
         if let Some(v) = itr.try_next().await? {
@@ -100,4 +132,51 @@ where
 
         Ok(())
     }
+
+    async fn build_arrow_f64(&self, key: &[u8]) -> anyhow::Result<ArrowBlock> {
+        let entries = self.entries(key).await?;
+        if entries.is_empty() {
+            return Err(anyhow!("key not found"));
+        }
+        if entries.typ != BLOCK_FLOAT64 {
+            return Err(anyhow!(
+                "invalid block type: exp {}, got {}",
+                BLOCK_FLOAT64,
+                entries.typ
+            ));
+        }
+
+        let mut timestamps = TimestampsVec::from(TIMESTAMP_DATA_TYPE.clone());
+        let mut values = FloatValuesVec::new();
+
+        let mut itr: BlockIterator<B, I> =
+            BlockIterator::new(entries, self.reader.clone(), self.inner.clone()).await?;
+        while let Some(block) = itr.try_next().await? {
+            let sz = block_count(block)?;
+            timestamps.reserve(sz);
+            values.reserve(sz);
+
+            let mut values_itr = FloatValueIterator::new(block)?;
+            while let Some(v) = values_itr.try_next()? {
+                timestamps.push(Some(v.unix_nano));
+                values.push(Some(v.value));
+            }
+        }
+
+        Ok(ArrowBlock {
+            timestamps: timestamps.into(),
+            values: values.into(),
+        })
+    }
+
+    async fn arrow_blocks(
+        &self,
+        key: &[u8],
+    ) -> anyhow::Result<Box<dyn AsyncIterator<Item = (Timestamps, ArrayRef)>>> {
+        let entries = self.entries(key).await?;
+        let typ = entries.typ;
+        let itr: BlockIterator<B, I> =
+            BlockIterator::new(entries, self.reader.clone(), self.inner.clone()).await?;
+        Ok(Box::new(ArrowBlockIterator::new(itr, typ)))
+    }
 }
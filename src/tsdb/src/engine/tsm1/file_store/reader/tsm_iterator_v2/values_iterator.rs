@@ -1,9 +1,14 @@
-use common_base::iterator::RefAsyncIterator;
+use std::sync::Arc;
 
+use common_base::iterator::{AsyncIterator, RefAsyncIterator};
+
+use crate::engine::tsm1::file_store::file_store::new_values;
+use crate::engine::tsm1::file_store::index::IndexEntries;
 use crate::engine::tsm1::file_store::reader::block_reader::TSMBlock;
 use crate::engine::tsm1::file_store::reader::index_reader::TSMIndex;
 use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::block_iterator::BlockIterator;
-use crate::engine::tsm1::value::Array;
+use crate::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+use crate::engine::tsm1::value::{Array, Values};
 
 #[async_trait]
 pub trait EntriesValuesReader {
@@ -43,3 +48,44 @@ where
         }
     }
 }
+
+/// ValuesIterator iterates over the decoded blocks of a key, one `Values` per block,
+/// as stored in the file: tombstones are not applied. It owns its reader, so it can
+/// be returned and consumed after the scope which opened the reader, see
+/// `TSMReader::into_value_iterator`.
+pub struct ValuesIterator {
+    reader: Arc<dyn TSMReader>,
+    entries: IndexEntries,
+    i: usize,
+}
+
+impl ValuesIterator {
+    /// new returns an iterator over the blocks of key, sharing the reader.
+    pub async fn new(reader: Arc<dyn TSMReader>, key: &[u8]) -> anyhow::Result<Self> {
+        let mut entries = IndexEntries::default();
+        reader.read_entries(key, &mut entries).await?;
+        Ok(Self {
+            reader,
+            entries,
+            i: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl AsyncIterator for ValuesIterator {
+    type Item = Values;
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        if self.i >= self.entries.len() {
+            return Ok(None);
+        }
+
+        let entry = self.entries.entry(self.i);
+        self.i += 1;
+
+        let mut values = new_values(self.entries.typ)?;
+        self.reader.read_block_at(&entry, &mut values).await?;
+        Ok(Some(values))
+    }
+}
@@ -1,24 +1,41 @@
+use std::future::Future;
 use std::io::SeekFrom;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use influxdb_storage::opendal::Reader;
+use common_base::iterator::AsyncIterator;
+use influxdb_storage::file::mmap_file::MmapReadableFile;
+use influxdb_storage::opendal::{Reader, Scheme};
 use influxdb_storage::StorageOperator;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::RwLock;
 
-use crate::engine::tsm1::file_store::index::IndexEntries;
+use crate::engine::tsm1::block::decoder::decode_block;
+use crate::engine::tsm1::file_store::bloom::{BloomFilter, BLOOM_MAGIC, BLOOM_TRAILER_SIZE};
+use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
 use crate::engine::tsm1::file_store::reader::batch_deleter::BatchDeleter;
-use crate::engine::tsm1::file_store::reader::block_reader::{DefaultBlockAccessor, TSMBlock};
+use crate::engine::tsm1::file_store::reader::block_reader::{
+    BlockReadError, DefaultBlockAccessor, TSMBlock,
+};
 use crate::engine::tsm1::file_store::reader::index_reader::{IndirectIndex, KeyIterator, TSMIndex};
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::block_iterator::{
+    RawBlock, RawBlockIterator,
+};
 use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::field_reader::{
     DefaultFieldReader, FieldReader,
 };
-use crate::engine::tsm1::file_store::stat::FileStat;
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::values_iterator::ValuesIterator;
+use crate::engine::tsm1::file_store::stat::{
+    FileStat, KeyStats, KeyStatsBuilder, DEFAULT_KEY_SAMPLE_SIZE,
+};
 use crate::engine::tsm1::file_store::tombstone::{
     IndexTombstonerFilter, TombstoneStat, Tombstoner,
 };
-use crate::engine::tsm1::file_store::{KeyRange, TimeRange, MAGIC_NUMBER, VERSION};
+use crate::engine::tsm1::file_store::{KeyRange, TimeRange, HEADER, MAGIC_NUMBER, VERSION};
+use crate::engine::tsm1::value::Values;
+use crate::engine::KEY_STATS_FILE_EXTENSION;
 
 /// TSMFile represents an on-disk TSM file.
 #[async_trait]
@@ -29,20 +46,53 @@ pub trait TSMReader: Sync + Send {
 
     async fn block_iterator_builder(&self) -> anyhow::Result<Box<dyn FieldReader>>;
 
-    // async fn read_block_at<T>(&self, entry: &IndexEntry, values: &mut T) -> anyhow::Result<()>
-    // where
-    //     T: BlockDecoder;
+    /// read_block_at decodes the block referenced by entry and appends its values.
+    async fn read_block_at(&self, entry: &IndexEntry, values: &mut Values) -> anyhow::Result<()>;
+
+    /// read_raw_block_at reads the encoded block referenced by entry, without its
+    /// checksum, e.g. to copy it into another file as is.
+    async fn read_raw_block_at(
+        &self,
+        entry: &IndexEntry,
+        block: &mut Vec<u8>,
+    ) -> anyhow::Result<()>;
+
+    /// raw_block_iterator returns an iterator over every block of the file in file
+    /// order, without decoding them, e.g. to stream-copy the file with
+    /// `TSMWriter::write_raw_block`.
+    async fn raw_block_iterator(&self) -> anyhow::Result<Box<dyn AsyncIterator<Item = RawBlock>>>;
 
     /// Entries returns the index entries for all blocks for the given key.
     async fn read_entries(&self, key: &[u8], entries: &mut IndexEntries) -> anyhow::Result<()>;
 
+    /// into_value_iterator consumes the reader and returns an iterator over the
+    /// decoded blocks of key which owns it, so it is not bound to the scope of the
+    /// reader. Use `ValuesIterator::new` to share a reader held in an `Arc` instead.
+    async fn into_value_iterator(self, key: &[u8]) -> anyhow::Result<ValuesIterator>
+    where
+        Self: Sized + 'static,
+    {
+        ValuesIterator::new(Arc::new(self), key).await
+    }
+
     /// contains returns true if the file contains any values for the given
     /// key.
     async fn contains(&self, key: &[u8]) -> anyhow::Result<bool>;
 
+    /// maybe_contains returns false if the bloom filter of the file rules key
+    /// out, without reading the index. It returns true for a file without one.
+    fn maybe_contains(&self, key: &[u8]) -> bool;
+
+    /// overlaps returns true if the file may hold values of key within time_range,
+    /// e.g. to skip the file when planning a query. See `TSMIndex::overlaps`.
+    async fn overlaps(&self, key: &[u8], time_range: &TimeRange) -> anyhow::Result<bool>;
+
     /// overlaps_time_range returns true if the time range of the file intersect min and max.
     async fn overlaps_time_range(&self, min: i64, max: i64) -> bool;
 
+    /// overlaps_key_range returns true if the key range of the file intersect min and max.
+    async fn overlaps_key_range(&self, min: &[u8], max: &[u8]) -> bool;
+
     /// time_range returns the min and max time across all keys in the file.
     async fn time_range(&self) -> TimeRange;
 
@@ -57,6 +107,10 @@ pub trait TSMReader: Sync + Send {
 
     async fn key_iterator(&self) -> anyhow::Result<KeyIterator>;
 
+    /// key_iterator_prefix returns an iterator over the keys starting with prefix,
+    /// e.g. the keys of a measurement. An empty prefix iterates over all keys.
+    async fn key_iterator_prefix(&self, prefix: &[u8]) -> anyhow::Result<KeyIterator>;
+
     /// seek returns the position in the index with the key <= key.
     async fn seek(&self, key: &[u8]) -> anyhow::Result<u64>;
 
@@ -68,6 +122,11 @@ pub trait TSMReader: Sync + Send {
     /// an error is returned.
     async fn block_type(&self, key: &[u8]) -> anyhow::Result<u8>;
 
+    /// ensure_measurement_loaded loads the index of the keys of measurement of a file
+    /// opened with `TSMReaderOptions::lazy_index`, e.g. to warm it up before a query.
+    /// Lookups load it on demand, it does nothing on a file indexed eagerly.
+    async fn ensure_measurement_loaded(&self, measurement: &[u8]) -> anyhow::Result<()>;
+
     /// batch_delete return a BatchDeleter that allows for multiple deletes in batches
     /// and group commit or rollback.
     async fn batch_delete(&mut self) -> Box<dyn BatchDeleter>;
@@ -78,6 +137,11 @@ pub trait TSMReader: Sync + Send {
     /// delete_range removes the values for keys between timestamps min and max.
     async fn delete_range(&self, keys: &mut [&[u8]], min: i64, max: i64) -> anyhow::Result<()>;
 
+    /// tombstone_epoch is incremented every time keys or ranges are deleted from the
+    /// file. Anything derived from the blocks of the file, e.g. decoded and filtered
+    /// values, should be keyed on it to be invalidated by new tombstones.
+    fn tombstone_epoch(&self) -> u64;
+
     /// has_tombstones returns true if file contains values that have been deleted.
     async fn has_tombstones(&self) -> anyhow::Result<bool>;
 
@@ -106,14 +170,73 @@ pub trait TSMReader: Sync + Send {
     /// stats returns summary information about the TSM file.
     async fn stats(&self) -> anyhow::Result<FileStat>;
 
+    /// key_stats returns the measurement and series counts of the file. They are
+    /// computed on first use and persisted in a sidecar file, so that reopening
+    /// the file does not iterate its keys again.
+    async fn key_stats(&self) -> anyhow::Result<KeyStats>;
+
+    /// deep_verify reads every block of the file and checks its checksum, and that
+    /// the bytes outside of blocks, e.g. alignment padding, are all zero.
+    async fn deep_verify(&self) -> anyhow::Result<()>;
+
     /// free releases any resources held by the FileStore to free up system resources.
     async fn free(&mut self) -> anyhow::Result<()>;
 }
 
+/// DEFAULT_DECODE_RETRIES is the number of times a block which did not arrive
+/// intact is read again, see `DefaultTSMReader::set_decode_retries`.
+pub const DEFAULT_DECODE_RETRIES: usize = 2;
+
+/// DECODE_RETRY_BACKOFF is the wait before the first retry of a block read,
+/// doubled on every retry.
+const DECODE_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// TSMFileError are the errors of a file which can not be opened as a TSM file,
+/// either because it is damaged or because it is not a format this reader supports.
+#[derive(Debug, thiserror::Error)]
+pub enum TSMFileError {
+    #[error("file of {0} bytes too small for a tsm file")]
+    TooSmall(u64),
+    #[error("invalid index offset {index_start} in a file of {file_size} bytes")]
+    InvalidIndexOffset { index_start: u64, file_size: u64 },
+    #[error("can only read from tsm file, magic number {0:#x}")]
+    InvalidMagicNumber(u32),
+    #[error("file is version {0}, expected {VERSION}")]
+    UnsupportedVersion(u8),
+}
+
+impl TSMFileError {
+    /// is_corruption returns true if the file looks like a TSM file of a supported
+    /// version which is damaged.
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Self::TooSmall(_) | Self::InvalidIndexOffset { .. })
+    }
+}
+
+/// TSMReaderOptions configures how `new_default_tsm_reader_with_options` opens a
+/// TSM file.
+#[derive(Debug, Clone, Default)]
+pub struct TSMReaderOptions {
+    /// mmap_index memory-maps the file and reads the keys and index entries from
+    /// the mapping instead of through the storage operator. Only files of a local
+    /// file system can be mapped.
+    pub mmap_index: bool,
+    /// lazy_index only loads the offsets of the keys of a measurement when one of
+    /// them is first looked up, instead of every offset when the file is opened.
+    pub lazy_index: bool,
+}
+
 pub async fn new_default_tsm_reader(op: StorageOperator) -> anyhow::Result<impl TSMReader> {
     DefaultTSMReader::new(op).await
 }
 
+pub async fn new_default_tsm_reader_with_options(
+    op: StorageOperator,
+    options: &TSMReaderOptions,
+) -> anyhow::Result<impl TSMReader> {
+    DefaultTSMReader::with_options(op, options).await
+}
+
 pub(crate) struct TSMReaderInner<I, B>
 where
     I: TSMIndex,
@@ -167,6 +290,22 @@ where
 
     /// last_modified is the last time this file was modified on disk
     last_modified: i64,
+
+    /// key_stats caches the stats of the keys, see `TSMReader::key_stats`.
+    key_stats: RwLock<Option<KeyStats>>,
+
+    /// bloom is the bloom filter of the keys, if the file has one.
+    bloom: Option<BloomFilter>,
+
+    /// tombstone_epoch is bumped by every delete, see `TSMReader::tombstone_epoch`.
+    tombstone_epoch: AtomicU64,
+
+    /// read_timeout bounds each read of the underlying operator, `None` waits forever.
+    read_timeout: Option<Duration>,
+
+    /// decode_retries is the number of times a block which did not arrive intact
+    /// is read again.
+    decode_retries: usize,
     // /// Counter incremented everytime the mmapAccessor is accessed
     // access_count: AtomicU64,
     // /// Counter to determine whether the accessor can free its resources
@@ -175,19 +314,24 @@ where
 
 impl DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
     pub async fn new(op: StorageOperator) -> anyhow::Result<Self> {
-        let mut reader = op.reader().await?;
-        Self::verify_version(&mut reader).await?;
-
-        reader.seek(SeekFrom::Start(0)).await?;
+        Self::with_options(op, &TSMReaderOptions::default()).await
+    }
 
+    pub async fn with_options(
+        op: StorageOperator,
+        options: &TSMReaderOptions,
+    ) -> anyhow::Result<Self> {
         let stat = op.stat().await?;
         let file_size = stat.content_length();
-        if file_size < 8 {
-            return Err(anyhow!(
-                "BlockAccessor: byte slice too small for IndirectIndex"
-            ));
+        if file_size < HEADER.len() as u64 + 8 {
+            return Err(TSMFileError::TooSmall(file_size).into());
         }
 
+        let mut reader = op.reader().await?;
+        Self::verify_version(&mut reader).await?;
+
+        reader.seek(SeekFrom::Start(0)).await?;
+
         let last_modified = stat
             .last_modified()
             .map(|x| x.timestamp_millis())
@@ -196,13 +340,28 @@ impl DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
         let index_ofs_pos = file_size - 8;
         reader.seek(SeekFrom::Start(index_ofs_pos)).await?;
         let index_start = reader.read_u64().await?;
+        // a footer which was lost or damaged points anywhere
+        if index_start < HEADER.len() as u64 || index_start > index_ofs_pos {
+            return Err(TSMFileError::InvalidIndexOffset {
+                index_start,
+                file_size,
+            }
+            .into());
+        }
 
-        let index = IndirectIndex::new(
-            &mut reader,
-            index_start,
-            (index_ofs_pos - index_start) as u32,
-        )
-        .await?;
+        let (index_end, bloom) =
+            Self::read_bloom_filter(&mut reader, index_start, index_ofs_pos).await?;
+        if bloom.is_none() && index_end != index_ofs_pos {
+            tracing::warn!("ignoring the damaged bloom filter of {}", op.path());
+        }
+
+        let index_len = (index_end - index_start) as u32;
+        let index = if options.mmap_index {
+            let mmap = MmapReadableFile::open(local_path(&op)?).await?;
+            IndirectIndex::with_mmap(mmap, index_start, index_len, options.lazy_index).await?
+        } else {
+            IndirectIndex::new(&mut reader, index_start, index_len, options.lazy_index).await?
+        };
         let block = DefaultBlockAccessor::new(index_start).await?;
         let inner = Arc::new(TSMReaderInner::new(index, block));
 
@@ -214,13 +373,135 @@ impl DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
             op,
             inner,
             tombstoner: RwLock::new(tombstoner),
-            size: 0,
+            size: file_size as u32,
             last_modified,
+            key_stats: RwLock::new(None),
+            bloom,
+            tombstone_epoch: AtomicU64::new(0),
+            read_timeout: None,
+            decode_retries: DEFAULT_DECODE_RETRIES,
             // access_count: AtomicU64::new(0),
             // free_count: AtomicU64::new(0),
         })
     }
 
+    /// set_read_timeout bounds the time of each block and index read, a read
+    /// taking longer fails with a timeout error instead of hanging.
+    #[allow(dead_code)]
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+    }
+
+    /// set_decode_retries sets the number of times a block read is retried when
+    /// its data did not arrive intact: a short read, or a block failing to decode
+    /// which does not match its checksum. Retries back off exponentially.
+    #[allow(dead_code)]
+    pub fn set_decode_retries(&mut self, retries: usize) {
+        self.decode_retries = retries;
+    }
+
+    /// read_block reads the block of entry into block and returns its checksum.
+    async fn read_block(&self, entry: &IndexEntry, block: &mut Vec<u8>) -> anyhow::Result<u32> {
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner
+                .block()
+                .read_block(&mut reader, entry, block)
+                .await
+        })
+        .await
+    }
+
+    /// try_read_block_at reads and decodes the block of entry once. A decode
+    /// failure of a block not matching its checksum is a `BlockReadError`, the
+    /// data may have been damaged on its way and reading it again may help.
+    async fn try_read_block_at(
+        &self,
+        entry: &IndexEntry,
+        values: &mut Values,
+    ) -> anyhow::Result<()> {
+        let mut block = vec![];
+        let checksum = self.read_block(entry, &mut block).await?;
+
+        decode_block(block.as_slice(), values).map_err(|e| {
+            if crc32fast::hash(block.as_slice()) != checksum {
+                anyhow!(BlockReadError::ChecksumMismatch {
+                    offset: entry.offset,
+                    cause: e.to_string(),
+                })
+            } else {
+                e
+            }
+        })
+    }
+
+    /// with_read_timeout runs a read of the underlying operator under the read timeout.
+    async fn with_read_timeout<T, F>(&self, read: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| {
+                anyhow!(
+                    "read of tsm file {} timed out after {:?}",
+                    self.op.path(),
+                    timeout
+                )
+            })?,
+            None => read.await,
+        }
+    }
+
+    /// load_key_stats reads the key stats sidecar, a missing or unreadable sidecar
+    /// is ignored.
+    async fn load_key_stats(&self, sidecar: &StorageOperator) -> Option<KeyStats> {
+        if !sidecar.exist().await.unwrap_or_default() {
+            return None;
+        }
+
+        let r = match sidecar.operator().read(sidecar.path()).await {
+            Ok(data) => KeyStats::decode(data.as_slice()),
+            Err(e) => Err(anyhow!(e)),
+        };
+        match r {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                tracing::warn!("ignoring key stats {}: {}", sidecar.path(), e);
+                None
+            }
+        }
+    }
+
+    /// read_bloom_filter returns where the index ends, before the bloom filter
+    /// section if the file has one, and the filter. A section whose filter does
+    /// not decode still ends the index, but no filter is returned.
+    async fn read_bloom_filter(
+        reader: &mut Reader,
+        index_start: u64,
+        index_end: u64,
+    ) -> anyhow::Result<(u64, Option<BloomFilter>)> {
+        if index_end - index_start < BLOOM_TRAILER_SIZE as u64 {
+            return Ok((index_end, None));
+        }
+
+        reader
+            .seek(SeekFrom::Start(index_end - BLOOM_TRAILER_SIZE as u64))
+            .await?;
+        let len = reader.read_u32().await? as u64;
+        if reader.read_u32().await? != BLOOM_MAGIC
+            || len + BLOOM_TRAILER_SIZE as u64 > index_end - index_start
+        {
+            return Ok((index_end, None));
+        }
+
+        let bloom_start = index_end - BLOOM_TRAILER_SIZE as u64 - len;
+        reader.seek(SeekFrom::Start(bloom_start)).await?;
+        let mut data = vec![0; len as usize];
+        reader.read_exact(data.as_mut_slice()).await?;
+
+        Ok((bloom_start, BloomFilter::decode(data.as_slice()).ok()))
+    }
+
     async fn verify_version(reader: &mut Reader) -> anyhow::Result<()> {
         reader
             .seek(SeekFrom::Start(0))
@@ -232,7 +513,7 @@ impl DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
             .await
             .map_err(|e| anyhow!("init: error reading magic number of file: {}", e))?;
         if magic_number != MAGIC_NUMBER {
-            return Err(anyhow!("can only read from tsm file"));
+            return Err(TSMFileError::InvalidMagicNumber(magic_number).into());
         }
 
         let version = reader
@@ -240,17 +521,26 @@ impl DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
             .await
             .map_err(|e| anyhow!("init: error reading version: {}", e))?;
         if version != VERSION {
-            return Err(anyhow!(
-                "init: file is version {}. expected {}",
-                version,
-                VERSION
-            ));
+            return Err(TSMFileError::UnsupportedVersion(version).into());
         }
 
         Ok(())
     }
 }
 
+/// local_path returns the path of the file of op on the local file system.
+fn local_path(op: &StorageOperator) -> anyhow::Result<PathBuf> {
+    let info = op.operator().info();
+    if !matches!(info.scheme(), Scheme::Fs) {
+        return Err(anyhow!(
+            "can not map {} of a {} storage, only local files can be mapped",
+            op.path(),
+            info.scheme()
+        ));
+    }
+    Ok(PathBuf::from(info.root()).join(op.path().trim_start_matches('/')))
+}
+
 #[async_trait]
 impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
     fn path(&self) -> &str {
@@ -263,36 +553,98 @@ impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
         Ok(builder)
     }
 
-    // async fn read_block_at<T>(&self, entry: &IndexEntry, values: &mut T) -> anyhow::Result<()>
-    // where
-    //     T: BlockDecoder,
-    // {
-    //     let mut reader = self.op.reader().await?;
-    //
-    //     let mut block = vec![];
-    //     self.inner
-    //         .block()
-    //         .read_block(&mut reader, entry, &mut block)
-    //         .await?;
-    //     values.decode(block.as_slice())?;
-    //
-    //     Ok(())
-    // }
+    async fn read_block_at(&self, entry: &IndexEntry, values: &mut Values) -> anyhow::Result<()> {
+        let len = values.len();
+        let mut backoff = DECODE_RETRY_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let e = match self.try_read_block_at(entry, values).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            // drop what was decoded of the block before failing
+            values.truncate(len);
+            if retries >= self.decode_retries || e.downcast_ref::<BlockReadError>().is_none() {
+                return Err(e);
+            }
+
+            retries += 1;
+            tracing::warn!("retry {} of read of {}: {}", retries, self.path(), e);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn read_raw_block_at(
+        &self,
+        entry: &IndexEntry,
+        block: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.read_block(entry, block).await?;
+        Ok(())
+    }
+
+    async fn raw_block_iterator(&self) -> anyhow::Result<Box<dyn AsyncIterator<Item = RawBlock>>> {
+        let reader = self.op.reader().await?;
+        let itr = RawBlockIterator::new(reader, self.inner.clone()).await?;
+        Ok(Box::new(itr))
+    }
 
     async fn read_entries(&self, key: &[u8], entries: &mut IndexEntries) -> anyhow::Result<()> {
-        let mut reader = self.op.reader().await?;
-        self.inner.index().entries(&mut reader, key, entries).await
+        if !self.maybe_contains(key) {
+            return Ok(());
+        }
+
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner.index().entries(&mut reader, key, entries).await
+        })
+        .await
     }
 
     async fn contains(&self, key: &[u8]) -> anyhow::Result<bool> {
-        let mut reader = self.op.reader().await?;
-        self.inner.index().contains(&mut reader, key).await
+        if !self.maybe_contains(key) {
+            return Ok(false);
+        }
+
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner.index().contains(&mut reader, key).await
+        })
+        .await
+    }
+
+    fn maybe_contains(&self, key: &[u8]) -> bool {
+        self.bloom
+            .as_ref()
+            .map(|x| x.maybe_contains(key))
+            .unwrap_or(true)
+    }
+
+    async fn overlaps(&self, key: &[u8], time_range: &TimeRange) -> anyhow::Result<bool> {
+        if !self.maybe_contains(key) {
+            return Ok(false);
+        }
+
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner
+                .index()
+                .overlaps(&mut reader, key, time_range)
+                .await
+        })
+        .await
     }
 
     async fn overlaps_time_range(&self, min: i64, max: i64) -> bool {
         self.inner.index().overlaps_time_range(min, max)
     }
 
+    async fn overlaps_key_range(&self, min: &[u8], max: &[u8]) -> bool {
+        self.inner.index().overlaps_key_range(min, max)
+    }
+
     async fn time_range(&self) -> TimeRange {
         self.inner.index().time_range()
     }
@@ -314,19 +666,44 @@ impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
         self.inner.index().key_iterator(reader).await
     }
 
+    async fn key_iterator_prefix(&self, prefix: &[u8]) -> anyhow::Result<KeyIterator> {
+        let reader = self.op.reader().await?;
+        self.inner.index().key_iterator_prefix(reader, prefix).await
+    }
+
     async fn seek(&self, key: &[u8]) -> anyhow::Result<u64> {
-        let mut reader = self.op.reader().await?;
-        self.inner.index().seek(&mut reader, key).await
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner.index().seek(&mut reader, key).await
+        })
+        .await
     }
 
     async fn key_at(&self, idx: usize) -> anyhow::Result<Option<(Vec<u8>, u8)>> {
-        let mut reader = self.op.reader().await?;
-        self.inner.index().key_at(&mut reader, idx).await
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner.index().key_at(&mut reader, idx).await
+        })
+        .await
     }
 
     async fn block_type(&self, key: &[u8]) -> anyhow::Result<u8> {
-        let mut reader = self.op.reader().await?;
-        self.inner.index().block_type(&mut reader, key).await
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner.index().block_type(&mut reader, key).await
+        })
+        .await
+    }
+
+    async fn ensure_measurement_loaded(&self, measurement: &[u8]) -> anyhow::Result<()> {
+        self.with_read_timeout(async {
+            let mut reader = self.op.reader().await?;
+            self.inner
+                .index()
+                .ensure_measurement_loaded(&mut reader, measurement)
+                .await
+        })
+        .await
     }
 
     async fn batch_delete(&mut self) -> Box<dyn BatchDeleter> {
@@ -335,15 +712,25 @@ impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
 
     async fn delete(&self, keys: &mut [&[u8]]) -> anyhow::Result<()> {
         let mut reader = self.op.reader().await?;
-        self.inner.index().delete(&mut reader, keys).await
+        // bumped even on failure, the index may be partially updated
+        let r = self.inner.index().delete(&mut reader, keys).await;
+        self.tombstone_epoch.fetch_add(1, Ordering::AcqRel);
+        r
     }
 
     async fn delete_range(&self, keys: &mut [&[u8]], min: i64, max: i64) -> anyhow::Result<()> {
         let mut reader = self.op.reader().await?;
-        self.inner
+        let r = self
+            .inner
             .index()
             .delete_range(&mut reader, keys, min, max)
-            .await
+            .await;
+        self.tombstone_epoch.fetch_add(1, Ordering::AcqRel);
+        r
+    }
+
+    fn tombstone_epoch(&self) -> u64 {
+        self.tombstone_epoch.load(Ordering::Acquire)
     }
 
     async fn has_tombstones(&self) -> anyhow::Result<bool> {
@@ -366,6 +753,7 @@ impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
 
     async fn remove(&mut self) -> anyhow::Result<()> {
         self.op.delete().await?;
+        self.op.to_tmp(KEY_STATS_FILE_EXTENSION).delete().await?;
 
         {
             let tombstoner = self.tombstoner.write().await;
@@ -393,18 +781,1028 @@ impl TSMReader for DefaultTSMReader<IndirectIndex, DefaultBlockAccessor> {
         let key_range = i.key_range();
 
         let has_tombstone = self.has_tombstones().await?;
+        let key_stats = self.key_stats().await?;
 
-        Ok(FileStat::new(
+        let mut stat = FileStat::new(
             self.path().to_string(),
             has_tombstone,
             self.size().await,
             self.last_modified,
             time_range,
             key_range,
-        ))
+            key_stats,
+        );
+        stat.index_load = i.load_stats().await;
+        Ok(stat)
+    }
+
+    async fn key_stats(&self) -> anyhow::Result<KeyStats> {
+        // deleting keys invalidates the stats
+        let key_count = self.key_count().await;
+        {
+            let cached = self.key_stats.read().await;
+            if let Some(stats) = cached.as_ref().filter(|x| x.key_count == key_count) {
+                return Ok(stats.clone());
+            }
+        }
+
+        let mut cached = self.key_stats.write().await;
+        let sidecar = self.op.to_tmp(KEY_STATS_FILE_EXTENSION);
+        let stats = match self.load_key_stats(&sidecar).await {
+            Some(stats) if stats.key_count == key_count => stats,
+            _ => {
+                // walks the offsets rather than the raw index, skipping deleted keys
+                let mut reader = self.op.reader().await?;
+                let mut builder = KeyStatsBuilder::new(DEFAULT_KEY_SAMPLE_SIZE);
+                for i in 0..key_count {
+                    if let Some((key, _)) = self.inner.index().key_at(&mut reader, i).await? {
+                        builder.add(key.as_slice());
+                    }
+                }
+                let stats = builder.build();
+
+                if let Err(e) = sidecar.write_atomic(stats.encode()).await {
+                    tracing::warn!("failed to persist key stats {}: {}", sidecar.path(), e);
+                }
+                stats
+            }
+        };
+
+        *cached = Some(stats.clone());
+        Ok(stats)
+    }
+
+    async fn deep_verify(&self) -> anyhow::Result<()> {
+        let mut reader = self.op.reader().await?;
+
+        let mut blocks = vec![];
+        let mut entries = IndexEntries::default();
+        for i in 0..self.inner.index().key_count().await {
+            self.inner.index().key(&mut reader, i, &mut entries).await?;
+            blocks.extend(entries.iter().map(|x| (x.offset, x.size)));
+        }
+        blocks.sort();
+
+        let max_offset = self.inner.block().max_offset();
+        let mut pos = HEADER.len() as u64;
+        let mut buf = vec![];
+        reader.seek(SeekFrom::Start(pos)).await?;
+        for (offset, size) in blocks.into_iter().chain([(max_offset, 0)]) {
+            if offset < pos || offset + size as u64 > max_offset {
+                return Err(anyhow!("block at {} overlaps the previous block", offset));
+            }
+
+            buf.resize((offset - pos) as usize, 0);
+            reader.read_exact(buf.as_mut_slice()).await?;
+            if let Some(i) = buf.iter().position(|x| *x != 0) {
+                return Err(anyhow!(
+                    "non-zero byte at {} outside of blocks",
+                    pos + i as u64
+                ));
+            }
+            if size == 0 {
+                break;
+            }
+            if size < 4 {
+                return Err(anyhow!("block at {} too small: {}", offset, size));
+            }
+
+            let checksum = reader.read_u32().await?;
+            buf.resize(size as usize - 4, 0);
+            reader.read_exact(buf.as_mut_slice()).await?;
+            if crc32fast::hash(buf.as_slice()) != checksum {
+                return Err(anyhow!("block at {} checksum mismatch", offset));
+            }
+            pos = offset + size as u64;
+        }
+
+        Ok(())
     }
 
     async fn free(&mut self) -> anyhow::Result<()> {
         self.inner.block().free().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::SeekFrom;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use common_base::iterator::AsyncIterator;
+
+    use influxdb_storage::opendal::layers::LoggingLayer;
+    use influxdb_storage::opendal::raw::oio;
+    use influxdb_storage::opendal::raw::*;
+    use influxdb_storage::opendal::services::{Fs, Memory};
+    use influxdb_storage::opendal::{Operator, Result};
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::block::{BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING};
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::block_reader::BlockReadError;
+    use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::values_iterator::ValuesIterator;
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{
+        new_default_tsm_reader, new_default_tsm_reader_with_options, DefaultTSMReader,
+        TSMFileError, TSMReader, TSMReaderOptions,
+    };
+    use crate::engine::tsm1::file_store::stat::MeasurementLoadStats;
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    /// Faults are the faults injected into the reads of an operator.
+    #[derive(Clone, Debug, Default)]
+    struct Faults {
+        /// delay stalls every read while set.
+        delay: Arc<AtomicBool>,
+        /// short_reads is the number of next reads cut short, ending at half of
+        /// the first read of more than 8 bytes.
+        short_reads: Arc<AtomicUsize>,
+        /// reads counts the readers opened.
+        reads: Arc<AtomicUsize>,
+    }
+
+    /// FaultLayer injects `Faults` into the reads of the operator.
+    #[derive(Clone, Debug)]
+    struct FaultLayer {
+        faults: Faults,
+    }
+
+    impl<A: Accessor> Layer<A> for FaultLayer {
+        type LayeredAccessor = FaultAccessor<A>;
+
+        fn layer(&self, inner: A) -> Self::LayeredAccessor {
+            FaultAccessor {
+                inner,
+                faults: self.faults.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct FaultAccessor<A: Accessor> {
+        inner: A,
+        faults: Faults,
+    }
+
+    struct FaultReader<R> {
+        inner: R,
+        short: bool,
+        eof: bool,
+    }
+
+    impl<R: oio::Read> oio::Read for FaultReader<R> {
+        fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            if self.short && buf.len() > 8 {
+                self.eof = true;
+                let n = buf.len() / 2;
+                return self.inner.poll_read(cx, &mut buf[..n]);
+            }
+            self.inner.poll_read(cx, buf)
+        }
+
+        fn poll_seek(&mut self, cx: &mut Context<'_>, pos: SeekFrom) -> Poll<Result<u64>> {
+            self.inner.poll_seek(cx, pos)
+        }
+
+        fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+            self.inner.poll_next(cx)
+        }
+    }
+
+    #[async_trait]
+    impl<A: Accessor> LayeredAccessor for FaultAccessor<A> {
+        type Inner = A;
+        type Reader = FaultReader<A::Reader>;
+        type BlockingReader = A::BlockingReader;
+        type Writer = A::Writer;
+        type BlockingWriter = A::BlockingWriter;
+        type Appender = A::Appender;
+        type Pager = A::Pager;
+        type BlockingPager = A::BlockingPager;
+
+        fn inner(&self) -> &Self::Inner {
+            &self.inner
+        }
+
+        async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+            self.faults.reads.fetch_add(1, Ordering::Relaxed);
+            if self.faults.delay.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            }
+            let short = self
+                .faults
+                .short_reads
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+                .is_ok();
+
+            let (rp, inner) = self.inner.read(path, args).await?;
+            let r = FaultReader {
+                inner,
+                short,
+                eof: false,
+            };
+            Ok((rp, r))
+        }
+
+        async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+            self.inner.write(path, args).await
+        }
+
+        async fn append(&self, path: &str, args: OpAppend) -> Result<(RpAppend, Self::Appender)> {
+            self.inner.append(path, args).await
+        }
+
+        async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Pager)> {
+            self.inner.list(path, args).await
+        }
+
+        fn blocking_read(
+            &self,
+            path: &str,
+            args: OpRead,
+        ) -> Result<(RpRead, Self::BlockingReader)> {
+            self.inner.blocking_read(path, args)
+        }
+
+        fn blocking_write(
+            &self,
+            path: &str,
+            args: OpWrite,
+        ) -> Result<(RpWrite, Self::BlockingWriter)> {
+            self.inner.blocking_write(path, args)
+        }
+
+        fn blocking_list(&self, path: &str, args: OpList) -> Result<(RpList, Self::BlockingPager)> {
+            self.inner.blocking_list(path, args)
+        }
+    }
+
+    fn fault_operator(faults: Faults) -> Operator {
+        let mut builder = Fs::default();
+        builder.root("/");
+        Operator::new(builder)
+            .unwrap()
+            .layer(LoggingLayer::default())
+            .layer(FaultLayer { faults })
+            .finish()
+    }
+
+    async fn write_test_file(path: &str) {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        let values = Values::Float((0..10).map(|i| TimeValue::new(i, i as f64)).collect());
+        w.write("cpu#!~#value".as_bytes(), values).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reader_read_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+        write_test_file(path).await;
+
+        let faults = Faults::default();
+        let delay = faults.delay.clone();
+        let op = StorageOperator::new(fault_operator(faults), path);
+        let mut r = DefaultTSMReader::new(op).await.unwrap();
+        r.set_read_timeout(Duration::from_millis(50));
+
+        let mut entries = IndexEntries::default();
+        r.read_entries("cpu#!~#value".as_bytes(), &mut entries)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let mut values = Values::Float(vec![]);
+        r.read_block_at(&entries.entry(0), &mut values)
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 10);
+
+        delay.store(true, Ordering::Relaxed);
+        let start = Instant::now();
+        let err = r
+            .read_block_at(&entries.entry(0), &mut values)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let err = r
+            .read_entries("cpu#!~#value".as_bytes(), &mut entries)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_reader_read_retry_short_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+        write_test_file(path).await;
+
+        let faults = Faults::default();
+        let op = StorageOperator::new(fault_operator(faults.clone()), path);
+        let mut r = DefaultTSMReader::new(op).await.unwrap();
+
+        let mut entries = IndexEntries::default();
+        r.read_entries("cpu#!~#value".as_bytes(), &mut entries)
+            .await
+            .unwrap();
+        let entry = entries.entry(0);
+
+        // the first read is cut short, the second one gets the full block
+        faults.short_reads.store(1, Ordering::Relaxed);
+        let reads = faults.reads.load(Ordering::Relaxed);
+        let mut values = Values::Float(vec![]);
+        r.read_block_at(&entry, &mut values).await.unwrap();
+        assert_eq!(values.len(), 10);
+        assert_eq!(faults.reads.load(Ordering::Relaxed), reads + 2);
+
+        // without retries the short read fails the read
+        r.set_decode_retries(0);
+        faults.short_reads.store(1, Ordering::Relaxed);
+        let mut values = Values::Float(vec![]);
+        let err = r.read_block_at(&entry, &mut values).await.unwrap_err();
+        assert!(err.is::<BlockReadError>(), "{}", err);
+        assert_eq!(values.len(), 0);
+
+        // a block corrupted on disk fails once the retries are exhausted
+        r.set_decode_retries(2);
+        let mut data = std::fs::read(path).unwrap();
+        let offset = entry.offset as usize + 4;
+        data[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+        std::fs::write(path, &data).unwrap();
+
+        let reads = faults.reads.load(Ordering::Relaxed);
+        let err = r.read_block_at(&entry, &mut values).await.unwrap_err();
+        assert!(err.is::<BlockReadError>(), "{}", err);
+        assert_eq!(faults.reads.load(Ordering::Relaxed), reads + 3);
+        assert_eq!(values.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reader_build_arrow_f64() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for block in 0..3 {
+            let values = Values::Float(
+                (block * 100..(block + 1) * 100)
+                    .map(|i| TimeValue::new(i, i as f64 / 2.0))
+                    .collect(),
+            );
+            w.write("cpu#!~#value".as_bytes(), values).await.unwrap();
+        }
+        let values = Values::Integer(vec![TimeValue::new(1, 1)]);
+        w.write("mem#!~#value".as_bytes(), values).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let r = DefaultTSMReader::new(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let field_reader = r.block_iterator_builder().await.unwrap();
+
+        let block = field_reader
+            .build_arrow_f64("cpu#!~#value".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(block.len(), 300);
+        assert_eq!(block.values.len(), 300);
+        let exp_times: Vec<i64> = (0..300).collect();
+        let exp_values: Vec<f64> = (0..300).map(|i| i as f64 / 2.0).collect();
+        assert_eq!(block.timestamps.values().as_slice(), exp_times.as_slice());
+        assert_eq!(block.values.values().as_slice(), exp_values.as_slice());
+        assert!(block.values.validity().is_none());
+
+        assert!(field_reader
+            .build_arrow_f64("mem#!~#value".as_bytes())
+            .await
+            .is_err());
+        assert!(field_reader
+            .build_arrow_f64("disk#!~#value".as_bytes())
+            .await
+            .is_err());
+    }
+
+    fn empty_values(typ: u8) -> Values {
+        match typ {
+            BLOCK_FLOAT64 => Values::Float(vec![]),
+            BLOCK_INTEGER => Values::Integer(vec![]),
+            BLOCK_STRING => Values::String(vec![]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_raw_block_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.as_ref().join("000000001-000000001.tsm");
+        let src = src.to_str().unwrap();
+        let dst = dir.as_ref().join("000000002-000000001.tsm");
+        let dst = dst.to_str().unwrap();
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(src).await.unwrap();
+        for block in 0..3 {
+            let values = Values::Float(
+                (block * 10..(block + 1) * 10)
+                    .map(|i| TimeValue::new(i, i as f64))
+                    .collect(),
+            );
+            w.write("cpu#!~#value".as_bytes(), values).await.unwrap();
+        }
+        let values = Values::String(vec![TimeValue::new(1, b"a".to_vec())]);
+        w.write("host#!~#name".as_bytes(), values).await.unwrap();
+        let values = Values::Integer(vec![TimeValue::new(1, 1), TimeValue::new(2, 2)]);
+        w.write("mem#!~#value".as_bytes(), values).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let r = DefaultTSMReader::new(StorageOperator::root(src).unwrap())
+            .await
+            .unwrap();
+        let mut w = DefaultTSMWriter::with_mem_buffer(dst).await.unwrap();
+        let mut itr = r.raw_block_iterator().await.unwrap();
+        let mut blocks = 0;
+        while let Some(block) = itr.try_next().await.unwrap() {
+            w.write_raw_block(&block).await.unwrap();
+            blocks += 1;
+        }
+        assert_eq!(blocks, 5);
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let copy = DefaultTSMReader::new(StorageOperator::root(dst).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(copy.key_count().await, 3);
+        let (exp, got) = (r.time_range().await, copy.time_range().await);
+        assert_eq!((got.min, got.max), (exp.min, exp.max));
+        for key in ["cpu#!~#value", "host#!~#name", "mem#!~#value"] {
+            let mut entries = IndexEntries::default();
+            r.read_entries(key.as_bytes(), &mut entries).await.unwrap();
+            let mut copy_entries = IndexEntries::default();
+            copy.read_entries(key.as_bytes(), &mut copy_entries)
+                .await
+                .unwrap();
+            assert_eq!(copy_entries.len(), entries.len());
+            assert_eq!(copy_entries.typ, entries.typ);
+
+            for (entry, copy_entry) in entries.iter().zip(copy_entries.iter()) {
+                let mut exp = empty_values(entries.typ);
+                r.read_block_at(&entry, &mut exp).await.unwrap();
+                let mut got = empty_values(entries.typ);
+                copy.read_block_at(&copy_entry, &mut got).await.unwrap();
+                assert_eq!(got, exp);
+            }
+        }
+
+        // a block of another type than its key is refused
+        let other = dir.as_ref().join("000000003-000000001.tsm");
+        let mut w = DefaultTSMWriter::with_mem_buffer(other.to_str().unwrap())
+            .await
+            .unwrap();
+        let mut itr = r.raw_block_iterator().await.unwrap();
+        let mut block = itr.try_next().await.unwrap().unwrap();
+        block.typ = BLOCK_INTEGER;
+        assert!(w.write_raw_block(&block).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reader_key_iterator_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let keys = [
+            "cpu,host=a#!~#idle",
+            "cpu,host=a#!~#value",
+            "cpu,host=b#!~#value",
+            "cpux,host=a#!~#value",
+            "mem,host=a#!~#value",
+        ];
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for key in keys {
+            let values = Values::Float(vec![TimeValue::new(1, 1.0)]);
+            w.write(key.as_bytes(), values).await.unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let r = DefaultTSMReader::new(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let collect = |prefix: &'static str| {
+            let r = &r;
+            async move {
+                let mut itr = r.key_iterator_prefix(prefix.as_bytes()).await.unwrap();
+                let mut got = vec![];
+                while let Some(key) = itr.try_next().await.unwrap() {
+                    got.push(String::from_utf8(key).unwrap());
+                }
+                got
+            }
+        };
+
+        assert_eq!(collect("").await, keys.to_vec());
+        assert_eq!(collect("cpu,").await, keys[..3].to_vec());
+        assert_eq!(collect("cpu").await, keys[..4].to_vec());
+        assert_eq!(collect("cpu,host=b").await, keys[2..3].to_vec());
+        assert_eq!(collect("mem,host=a#!~#value").await, keys[4..].to_vec());
+        assert_eq!(collect("cpu,host=a#!~#value").await, keys[1..2].to_vec());
+        assert!(collect("disk").await.is_empty());
+        assert!(collect("a").await.is_empty());
+        assert!(collect("zzz").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reader_tombstone_epoch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+        write_test_file(path).await;
+        let other_path = dir.as_ref().join("000000002-000000001.tsm");
+        let other_path = other_path.to_str().unwrap();
+        write_test_file(other_path).await;
+
+        let r = DefaultTSMReader::new(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let other = DefaultTSMReader::new(StorageOperator::root(other_path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(r.tombstone_epoch(), 0);
+
+        r.delete_range(&mut ["cpu#!~#value".as_bytes()], 2, 4)
+            .await
+            .unwrap();
+        assert_eq!(r.tombstone_epoch(), 1);
+        let ranges = r.tombstone_range("cpu#!~#value".as_bytes()).await;
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].min, ranges[0].max), (2, 4));
+
+        r.delete(&mut ["cpu#!~#value".as_bytes()]).await.unwrap();
+        assert_eq!(r.tombstone_epoch(), 2);
+
+        // deletes of a file don't affect the epoch of the others
+        assert_eq!(other.tombstone_epoch(), 0);
+        assert!(other.contains("cpu#!~#value".as_bytes()).await.unwrap());
+    }
+
+    /// open_values opens the file and returns an iterator over the values of key,
+    /// dropping every other handle on the reader.
+    async fn open_values(path: &str, key: &str) -> ValuesIterator {
+        let r = new_default_tsm_reader(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        r.into_value_iterator(key.as_bytes()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reader_into_value_iterator() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let blocks: Vec<Values> = (0..3_i64)
+            .map(|j| Values::Integer((0..10).map(|t| TimeValue::new(j * 10 + t, t)).collect()))
+            .collect();
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for values in &blocks {
+            w.write("cpu#!~#value".as_bytes(), values.clone())
+                .await
+                .unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let mut itr = open_values(path, "cpu#!~#value").await;
+        let mut got = vec![];
+        while let Some(values) = itr.try_next().await.unwrap() {
+            got.push(values);
+        }
+        assert_eq!(got, blocks);
+
+        let mut itr = open_values(path, "mem#!~#free").await;
+        assert!(itr.try_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reader_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for range in [0..10_i64, 20..30] {
+            let values = Values::Integer(range.map(|t| TimeValue::new(t, t)).collect());
+            w.write("cpu#!~#value".as_bytes(), values).await.unwrap();
+        }
+        let values = Values::Integer((100..110).map(|t| TimeValue::new(t, t)).collect());
+        w.write("mem#!~#free".as_bytes(), values).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        for mmap_index in [false, true] {
+            let op = StorageOperator::root(path).unwrap();
+            let options = TSMReaderOptions {
+                mmap_index,
+                ..Default::default()
+            };
+            let r = new_default_tsm_reader_with_options(op, &options)
+                .await
+                .unwrap();
+
+            let cpu = "cpu#!~#value".as_bytes();
+            assert!(r.contains(cpu).await.unwrap());
+            assert!(r.overlaps(cpu, &TimeRange::new(5, 6)).await.unwrap());
+            assert!(r.overlaps(cpu, &TimeRange::new(15, 20)).await.unwrap());
+            // between the blocks of the key, and past the end of the file
+            assert!(!r.overlaps(cpu, &TimeRange::new(10, 19)).await.unwrap());
+            assert!(!r.overlaps(cpu, &TimeRange::new(200, 300)).await.unwrap());
+            // within the range of another key
+            assert!(!r.overlaps(cpu, &TimeRange::new(100, 109)).await.unwrap());
+
+            for absent in ["a#!~#value", "disk#!~#used", "zz#!~#value"] {
+                assert!(!r.contains(absent.as_bytes()).await.unwrap());
+                assert!(!r
+                    .overlaps(absent.as_bytes(), &TimeRange::unbound())
+                    .await
+                    .unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_bloom_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let keys: Vec<String> = (0..1000)
+            .map(|i| format!("cpu,host={:04}#!~#value", i))
+            .collect();
+        let mut w = DefaultTSMWriter::with_mem_buffer(path)
+            .await
+            .unwrap()
+            .with_bloom_filter(Some(0.01));
+        for key in keys.iter() {
+            // two blocks per key
+            for range in [0..10_i64, 10..20] {
+                let values = Values::Integer(range.map(|t| TimeValue::new(t, t)).collect());
+                w.write(key.as_bytes(), values).await.unwrap();
+            }
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        for mmap_index in [false, true] {
+            let op = StorageOperator::root(path).unwrap();
+            let options = TSMReaderOptions {
+                mmap_index,
+                ..Default::default()
+            };
+            let r = new_default_tsm_reader_with_options(op, &options)
+                .await
+                .unwrap();
+            assert_eq!(r.key_count().await, keys.len());
+
+            for key in keys.iter() {
+                assert!(r.maybe_contains(key.as_bytes()));
+                assert!(r.contains(key.as_bytes()).await.unwrap());
+                let mut entries = IndexEntries::default();
+                r.read_entries(key.as_bytes(), &mut entries).await.unwrap();
+                assert_eq!(entries.len(), 2);
+            }
+
+            let absent: Vec<String> = (0..1000)
+                .map(|i| format!("mem,host={:04}#!~#free", i))
+                .collect();
+            let ruled_out = absent
+                .iter()
+                .filter(|x| !r.maybe_contains(x.as_bytes()))
+                .count();
+            assert!(
+                ruled_out > 950,
+                "{} of 1000 absent keys ruled out",
+                ruled_out
+            );
+            for key in absent.iter() {
+                assert!(!r.contains(key.as_bytes()).await.unwrap());
+            }
+        }
+
+        // a damaged filter is ignored, the index is still read up to it
+        let mut data = std::fs::read(path).unwrap();
+        let footer = data.len() - 8;
+        let len = u32::from_be_bytes(data[footer - 8..footer - 4].try_into().unwrap()) as usize;
+        data[footer - 8 - len / 2] ^= 0xFF;
+        std::fs::write(path, data).unwrap();
+
+        let r = new_default_tsm_reader(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(r.key_count().await, keys.len());
+        assert!(r.maybe_contains(b"mem,host=0000#!~#free"));
+        assert!(r.contains(keys[500].as_bytes()).await.unwrap());
+        assert!(!r.contains(b"mem,host=0000#!~#free").await.unwrap());
+    }
+
+    /// index_of returns the keys of a file in order with their block type and
+    /// index entries.
+    async fn index_of(r: &dyn TSMReader) -> Vec<(Vec<u8>, u8, Vec<(i64, i64, u64, u32)>)> {
+        let mut index = vec![];
+        let mut keys = r.key_iterator().await.unwrap();
+        while let Some(key) = keys.try_next().await.unwrap() {
+            let mut entries = IndexEntries::default();
+            r.read_entries(key.as_slice(), &mut entries).await.unwrap();
+            let entries = entries
+                .iter()
+                .map(|e| (e.min_time, e.max_time, e.offset, e.size))
+                .collect();
+            let typ = r.block_type(key.as_slice()).await.unwrap();
+            index.push((key, typ, entries));
+        }
+        index
+    }
+
+    #[tokio::test]
+    async fn test_reader_mmap_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(path)
+            .await
+            .unwrap()
+            .with_max_points_per_block(7);
+        for i in 0..50_i64 {
+            let key = format!("cpu,host=h{:02}#!~#value", i);
+            let values = Values::Integer((0..i).map(|t| TimeValue::new(t * 10, i)).collect());
+            if values.len() > 0 {
+                w.write(key.as_bytes(), values).await.unwrap();
+            }
+        }
+        w.write(
+            b"mem#!~#state",
+            Values::String(vec![TimeValue::new(-5, b"ok".to_vec())]),
+        )
+        .await
+        .unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let eager = new_default_tsm_reader(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let options = TSMReaderOptions {
+            mmap_index: true,
+            ..Default::default()
+        };
+        let mapped =
+            new_default_tsm_reader_with_options(StorageOperator::root(path).unwrap(), &options)
+                .await
+                .unwrap();
+
+        let index = index_of(&eager).await;
+        assert_eq!(index.len(), 50);
+        assert_eq!(index[49].1, BLOCK_STRING);
+        assert_eq!(index[48].2.len(), 7);
+        assert_eq!(index_of(&mapped).await, index);
+
+        assert_eq!(mapped.key_count().await, eager.key_count().await);
+        let (a, b) = (mapped.time_range().await, eager.time_range().await);
+        assert_eq!((a.min, a.max), (b.min, b.max));
+        assert_eq!((a.min, a.max), (-5, 480));
+        let (a, b) = (mapped.key_range().await, eager.key_range().await);
+        assert_eq!((a.min, a.max), (b.min, b.max));
+        for idx in [0, 17, 49, 50] {
+            assert_eq!(
+                mapped.key_at(idx).await.unwrap(),
+                eager.key_at(idx).await.unwrap()
+            );
+        }
+        for key in [
+            b"cpu,host=h01#!~#value".as_slice(),
+            b"cpu,host=h00#!~#value",
+            b"cpu,host=h25#!~#value",
+            b"disk#!~#value",
+            b"zzz",
+        ] {
+            assert_eq!(
+                mapped.contains(key).await.unwrap(),
+                eager.contains(key).await.unwrap()
+            );
+        }
+
+        // the blocks read through the mapped index
+        let mut entries = IndexEntries::default();
+        mapped
+            .read_entries(b"cpu,host=h10#!~#value", &mut entries)
+            .await
+            .unwrap();
+        let mut values = Values::Integer(vec![]);
+        for entry in entries.iter() {
+            mapped.read_block_at(&entry, &mut values).await.unwrap();
+        }
+        assert_eq!(
+            values,
+            Values::Integer((0..10).map(|t| TimeValue::new(t * 10, 10)).collect())
+        );
+
+        // only local files can be mapped
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        op.write("a.tsm", std::fs::read(path).unwrap())
+            .await
+            .unwrap();
+        let err = new_default_tsm_reader_with_options(StorageOperator::new(op, "a.tsm"), &options)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("only local files"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_reader_lazy_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        // 10 measurements of 300 series, `m1$x` sorting within the keys of `m1`
+        let mut keys: Vec<String> = (0..10)
+            .flat_map(|m| (0..300).map(move |h| format!("m{},host=h{:03}#!~#value", m, h)))
+            .collect();
+        keys.push("m1#!~#count".to_string());
+        keys.push("m1$x#!~#value".to_string());
+        keys.sort();
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let values = Values::Integer(vec![TimeValue::new(i as i64, i as i64)]);
+            w.write(key.as_bytes(), values).await.unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let eager = new_default_tsm_reader(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let options = TSMReaderOptions {
+            lazy_index: true,
+            ..Default::default()
+        };
+        let open_lazy = || async {
+            new_default_tsm_reader_with_options(StorageOperator::root(path).unwrap(), &options)
+                .await
+                .unwrap()
+        };
+        let lazy = open_lazy().await;
+
+        let eager_load = eager.stats().await.unwrap().index_load;
+        assert!(!eager_load.lazy);
+        assert_eq!(eager_load.offsets_bytes, keys.len() * 8);
+        let load = lazy.stats().await.unwrap().index_load;
+        assert!(load.lazy);
+        assert!(load.measurements.is_empty());
+        assert!(load.offsets_bytes * 100 < eager_load.offsets_bytes);
+        assert_eq!(lazy.key_count().await, keys.len());
+
+        // a lookup loads the measurement of the key only
+        let key = b"m3,host=h007#!~#value".as_slice();
+        let (mut a, mut b) = (IndexEntries::default(), IndexEntries::default());
+        lazy.read_entries(key, &mut a).await.unwrap();
+        eager.read_entries(key, &mut b).await.unwrap();
+        let times = |x: &IndexEntries| -> Vec<(i64, i64, u64)> {
+            x.iter()
+                .map(|e| (e.min_time, e.max_time, e.offset))
+                .collect()
+        };
+        assert_eq!(times(&a), times(&b));
+        assert_eq!(a.len(), 1);
+        let m3 = MeasurementLoadStats {
+            measurement: b"m3".to_vec(),
+            key_count: 300,
+            bytes: 300 * 8,
+        };
+        let load = lazy.stats().await.unwrap().index_load;
+        assert_eq!(load.measurements, vec![m3.clone()]);
+        assert!(load.offsets_bytes * 5 < eager_load.offsets_bytes);
+
+        // another measurement is added, the loaded one is kept
+        let mut itr = lazy.key_iterator_prefix(b"m1,host=h29").await.unwrap();
+        let mut got = vec![];
+        while let Some(key) = itr.try_next().await.unwrap() {
+            got.push(String::from_utf8(key).unwrap());
+        }
+        let expected: Vec<String> = (290..300)
+            .map(|h| format!("m1,host=h{:03}#!~#value", h))
+            .collect();
+        assert_eq!(got, expected);
+        lazy.ensure_measurement_loaded(b"m1").await.unwrap();
+        assert!(lazy.contains(b"m1$x#!~#value").await.unwrap());
+        assert!(!lazy.contains(b"m1,host=h300#!~#value").await.unwrap());
+        let load = lazy.stats().await.unwrap().index_load;
+        let loaded: Vec<(Vec<u8>, usize)> = load
+            .measurements
+            .iter()
+            .map(|x| (x.measurement.clone(), x.key_count))
+            .collect();
+        assert_eq!(
+            loaded,
+            vec![
+                (b"m1".to_vec(), 301),
+                (b"m1$x".to_vec(), 1),
+                (b"m3".to_vec(), 300)
+            ]
+        );
+        assert_eq!(load.measurements[2], m3);
+
+        // the results of a lazy index are the ones of an eager one
+        let lazy = open_lazy().await;
+        assert_eq!(index_of(&lazy).await, index_of(&eager).await);
+        let load = lazy.stats().await.unwrap().index_load;
+        assert!(load.lazy);
+        assert_eq!(load.measurements.len(), 11);
+        for key in [b"a".as_slice(), b"m0", b"m10,host=h000#!~#value", b"zzz"] {
+            assert!(!lazy.contains(key).await.unwrap());
+        }
+        assert_eq!(
+            lazy.seek(key).await.unwrap(),
+            eager.seek(key).await.unwrap()
+        );
+
+        // lookups by position need every offset
+        for idx in [0, 1000, keys.len()] {
+            assert_eq!(
+                lazy.key_at(idx).await.unwrap(),
+                eager.key_at(idx).await.unwrap()
+            );
+        }
+        assert_eq!(lazy.stats().await.unwrap().index_load, eager_load);
+
+        let lazy = open_lazy().await;
+        lazy.delete(&mut [key]).await.unwrap();
+        assert!(!lazy.contains(key).await.unwrap());
+        assert_eq!(lazy.key_count().await, keys.len() - 1);
+        assert!(!lazy.stats().await.unwrap().index_load.lazy);
+    }
+
+    #[tokio::test]
+    async fn test_reader_file_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(path).await.unwrap();
+        let values = Values::Integer((0..10).map(|t| TimeValue::new(t, t)).collect());
+        w.write("cpu#!~#value".as_bytes(), values).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+        let data = std::fs::read(path).unwrap();
+
+        let open = |data: Vec<u8>| async move {
+            std::fs::write(path, data).unwrap();
+            let op = StorageOperator::root(path).unwrap();
+            match new_default_tsm_reader(op).await {
+                Ok(_) => panic!("opened a bad tsm file"),
+                Err(e) => e.downcast::<TSMFileError>().unwrap(),
+            }
+        };
+
+        let e = open(data[..10].to_vec()).await;
+        assert!(matches!(e, TSMFileError::TooSmall(10)));
+        assert!(e.is_corruption());
+
+        let mut footer = data.clone();
+        let len = footer.len();
+        footer[len - 8..].copy_from_slice(&(len as u64).to_be_bytes());
+        let e = open(footer).await;
+        assert!(matches!(e, TSMFileError::InvalidIndexOffset { .. }));
+        assert!(e.is_corruption());
+
+        let mut magic = data.clone();
+        magic[0] = 0;
+        let e = open(magic).await;
+        assert!(matches!(e, TSMFileError::InvalidMagicNumber(_)));
+        assert!(!e.is_corruption());
+
+        let mut version = data;
+        version[4] = 9;
+        let e = open(version).await;
+        assert!(matches!(e, TSMFileError::UnsupportedVersion(9)));
+        assert!(!e.is_corruption());
+    }
+}
@@ -1,4 +1,12 @@
+use bytes::{Buf, BufMut};
+
 use crate::engine::tsm1::file_store::{KeyRange, TimeRange};
+use crate::index::shard_index::{parse_series_key, split_tsm_key};
+
+/// DEFAULT_KEY_SAMPLE_SIZE is the number of keys sampled by `KeyStats`.
+pub const DEFAULT_KEY_SAMPLE_SIZE: usize = 8;
+
+const KEY_STATS_VERSION: u8 = 1;
 
 /// FileStat holds information about a TSM file on disk.
 pub struct FileStat {
@@ -9,6 +17,11 @@ pub struct FileStat {
 
     pub time_range: TimeRange,
     pub key_range: KeyRange,
+
+    pub key_stats: KeyStats,
+
+    /// index_load tells how much of the index of the file is loaded.
+    pub index_load: IndexLoadStats,
 }
 
 impl FileStat {
@@ -19,6 +32,7 @@ impl FileStat {
         last_modified: i64,
         time_range: TimeRange,
         key_range: KeyRange,
+        key_stats: KeyStats,
     ) -> Self {
         Self {
             path,
@@ -27,6 +41,8 @@ impl FileStat {
             last_modified,
             time_range,
             key_range,
+            key_stats,
+            index_load: IndexLoadStats::default(),
         }
     }
 
@@ -50,6 +66,160 @@ impl FileStat {
     }
 }
 
+/// IndexLoadStats tells how much of the index of a TSM file is held in memory, see
+/// `TSMReaderOptions::lazy_index`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IndexLoadStats {
+    /// lazy is true while the offsets of the keys are loaded per measurement.
+    pub lazy: bool,
+    /// offsets_bytes is the memory used by the key offsets loaded.
+    pub offsets_bytes: usize,
+    /// measurements are the measurements loaded by a lazy index, sorted.
+    pub measurements: Vec<MeasurementLoadStats>,
+}
+
+/// MeasurementLoadStats is the part of a lazy index loaded for a measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementLoadStats {
+    pub measurement: Vec<u8>,
+    pub key_count: usize,
+    pub bytes: usize,
+}
+
+/// KeyStats summarizes the keys of a TSM file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KeyStats {
+    /// key_count is the number of keys the stats were computed from.
+    pub key_count: usize,
+    /// measurement_count is the number of distinct measurements.
+    pub measurement_count: usize,
+    /// series_count is the number of distinct series keys.
+    pub series_count: usize,
+    /// sample is a uniform sample of the keys, for diagnostics.
+    pub sample: Vec<Vec<u8>>,
+}
+
+impl KeyStats {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u8(KEY_STATS_VERSION);
+        buf.put_u64(self.key_count as u64);
+        buf.put_u64(self.measurement_count as u64);
+        buf.put_u64(self.series_count as u64);
+        buf.put_u32(self.sample.len() as u32);
+        for key in self.sample.iter() {
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key.as_slice());
+        }
+        buf.put_u32(crc32fast::hash(buf.as_slice()));
+        buf
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 4 {
+            return Err(anyhow!("key stats truncated"));
+        }
+        let (mut b, checksum) = data.split_at(data.len() - 4);
+        if crc32fast::hash(b) != u32::from_be_bytes(checksum.try_into()?) {
+            return Err(anyhow!("key stats checksum mismatch"));
+        }
+
+        if b.remaining() < 29 {
+            return Err(anyhow!("key stats truncated"));
+        }
+        let version = b.get_u8();
+        if version != KEY_STATS_VERSION {
+            return Err(anyhow!("key stats version {} not supported", version));
+        }
+        let key_count = b.get_u64() as usize;
+        let measurement_count = b.get_u64() as usize;
+        let series_count = b.get_u64() as usize;
+
+        let n = b.get_u32() as usize;
+        let mut sample = Vec::with_capacity(n.min(DEFAULT_KEY_SAMPLE_SIZE));
+        for _ in 0..n {
+            if b.remaining() < 2 {
+                return Err(anyhow!("key stats truncated"));
+            }
+            let len = b.get_u16() as usize;
+            if b.remaining() < len {
+                return Err(anyhow!("key stats truncated"));
+            }
+            sample.push(b[..len].to_vec());
+            b.advance(len);
+        }
+
+        Ok(Self {
+            key_count,
+            measurement_count,
+            series_count,
+            sample,
+        })
+    }
+}
+
+/// KeyStatsBuilder computes the `KeyStats` of keys added in ascending order.
+///
+/// Measurements and series are counted on boundary changes, the keys of a series
+/// and the series of a measurement being adjacent in sorted order. The sample
+/// uses reservoir sampling with a fixed seed, so the same keys give the same sample.
+pub(crate) struct KeyStatsBuilder {
+    stats: KeyStats,
+    sample_size: usize,
+    last_series: Vec<u8>,
+    last_measurement: Vec<u8>,
+    rng: u64,
+}
+
+impl KeyStatsBuilder {
+    pub fn new(sample_size: usize) -> Self {
+        Self {
+            stats: KeyStats::default(),
+            sample_size,
+            last_series: vec![],
+            last_measurement: vec![],
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        let (series_key, _) = split_tsm_key(key);
+        let (measurement, _) = parse_series_key(series_key);
+
+        if self.stats.key_count == 0 || series_key != self.last_series.as_slice() {
+            self.stats.series_count += 1;
+            self.last_series = series_key.to_vec();
+        }
+        if self.stats.key_count == 0 || measurement != self.last_measurement {
+            self.stats.measurement_count += 1;
+            self.last_measurement = measurement;
+        }
+
+        let n = self.stats.key_count;
+        self.stats.key_count += 1;
+        if self.stats.sample.len() < self.sample_size {
+            self.stats.sample.push(key.to_vec());
+        } else if self.sample_size > 0 {
+            let i = (self.next_random() % (n as u64 + 1)) as usize;
+            if i < self.sample_size {
+                self.stats.sample[i] = key.to_vec();
+            }
+        }
+    }
+
+    pub fn build(self) -> KeyStats {
+        self.stats
+    }
+
+    /// next_random is xorshift64*, good enough for sampling.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
 /// FileStoreStatistics keeps statistics about the file store.
 pub struct FileStoreStatistics {
     pub disk_bytes: i64,
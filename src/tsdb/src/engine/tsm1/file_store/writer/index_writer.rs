@@ -9,9 +9,9 @@ use filepath::FilePath;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
-use crate::engine::tsm1::file_store::index::{IndexEntries, IndexEntry};
+use crate::engine::tsm1::file_store::index::IndexEntry;
 use crate::engine::tsm1::file_store::{
-    FSYNC_EVERY, INDEX_COUNT_SIZE, INDEX_ENTRY_SIZE, MAX_INDEX_ENTRIES,
+    FSYNC_EVERY, INDEX_COUNT_SIZE, INDEX_ENTRY_SIZE, MAX_INDEX_ENTRIES, MAX_KEY_LENGTH,
 };
 
 /// IndexWriter writes a TSMIndex.
@@ -182,7 +182,11 @@ where
     f: Box<dyn Syncer>,
 
     key: Vec<u8>,
-    index_entries: Option<IndexEntries>,
+    /// block_type and index_entries are the entries of the current key. They
+    /// are kept decoded, unlike `IndexEntries`, as there is only one key at a
+    /// time and `entries` lends them as a slice.
+    block_type: u8,
+    index_entries: Vec<IndexEntry>,
 }
 
 impl DirectIndex<MemoryIndexBuffer> {
@@ -194,7 +198,8 @@ impl DirectIndex<MemoryIndexBuffer> {
             buf: MemoryIndexBuffer::new(sz),
             f: Box::new(DefaultSyncer {}),
             key: vec![],
-            index_entries: None,
+            block_type: 0,
+            index_entries: vec![],
         }
     }
 }
@@ -214,7 +219,8 @@ impl DirectIndex<FileIndexBuffer> {
             buf: FileIndexBuffer::new(idx_fd),
             f: Box::new(DefaultSyncer {}),
             key: vec![],
-            index_entries: None,
+            block_type: 0,
+            index_entries: vec![],
         })
     }
 }
@@ -231,7 +237,8 @@ where
             buf,
             f: Box::new(DefaultSyncer {}),
             key: vec![],
-            index_entries: None,
+            block_type: 0,
+            index_entries: vec![],
         }
     }
 
@@ -254,23 +261,23 @@ where
         }
 
         // For each key, individual entries are sorted by time
-        let mut index_entries = self.index_entries.take().unwrap();
+        let index_entries = &mut self.index_entries;
 
-        if index_entries.entries.len() > MAX_INDEX_ENTRIES {
+        if index_entries.len() > MAX_INDEX_ENTRIES {
             return Err(anyhow!(
                 "key '{:?}' exceeds max index entries: {} > {}",
                 self.key.as_slice(),
-                index_entries.entries.len(),
+                index_entries.len(),
                 MAX_INDEX_ENTRIES
             ));
         }
 
-        index_entries.sort();
+        index_entries.sort_by_key(|x| x.min_time);
 
         let mut buf = Vec::with_capacity(5);
         buf.put_u16(self.key.len() as u16);
-        buf.push(index_entries.typ);
-        buf.put_u16(index_entries.entries.len() as u16);
+        buf.push(self.block_type);
+        buf.put_u16(index_entries.len() as u16);
 
         let mut total = 0_u64;
 
@@ -298,14 +305,18 @@ where
         total += 3;
 
         // Append each index entry for all blocks for this key
-        let n = index_entries
-            .write_to(&mut self.buf)
+        buf.clear();
+        for entry in self.index_entries.iter() {
+            entry.write_to(&mut buf);
+        }
+        self.buf
+            .write_all(buf.as_slice())
             .await
             .map_err(|e| anyhow!("write: writer entries error: {}", e.to_string()))?;
-        total += n;
+        total += buf.len() as u64;
 
         self.key.clear();
-        self.index_entries = None;
+        self.index_entries.clear();
 
         // If this is a disk based index and we've written more than the fsync threshold,
         // fsync the data to avoid long pauses later on.
@@ -329,6 +340,13 @@ where
         block_type: u8,
         index_entry: IndexEntry,
     ) -> anyhow::Result<()> {
+        // `TSMWriter` rejects longer keys, the length is stored on 2 bytes
+        debug_assert!(
+            key.len() <= MAX_KEY_LENGTH,
+            "index entry of a key of {} bytes",
+            key.len()
+        );
+
         // Is this the first block being added?
         if self.key.len() == 0 {
             // size of the key stored in the index
@@ -337,13 +355,8 @@ where
             self.size += INDEX_COUNT_SIZE as u32;
 
             self.key.extend_from_slice(key);
-            if self.index_entries.is_none() {
-                self.index_entries = Some(IndexEntries::new(block_type));
-            }
-
-            let index_entries = self.index_entries.as_mut().unwrap();
-            index_entries.typ = block_type;
-            index_entries.entries.push(index_entry);
+            self.block_type = block_type;
+            self.index_entries.push(index_entry);
 
             // size of the encoded index entry
             self.size += INDEX_ENTRY_SIZE as u32;
@@ -354,10 +367,8 @@ where
 
         match self.key.as_slice().cmp(key) {
             Ordering::Equal => {
-                let index_entries = self.index_entries.as_mut().unwrap();
-
                 // The last block is still this key
-                index_entries.entries.push(index_entry);
+                self.index_entries.push(index_entry);
 
                 // size of the encoded index entry
                 self.size += INDEX_ENTRY_SIZE as u32;
@@ -375,9 +386,8 @@ where
                 self.key.clear();
                 self.key.extend_from_slice(key);
 
-                let index_entries = self.index_entries.as_mut().unwrap();
-                index_entries.typ = block_type;
-                index_entries.entries.push(index_entry);
+                self.block_type = block_type;
+                self.index_entries.push(index_entry);
 
                 // size of the encoded index entry
                 self.size += INDEX_ENTRY_SIZE as u32;
@@ -402,7 +412,7 @@ where
         }
 
         if let Ordering::Equal = self.key.as_slice().cmp(key) {
-            return self.index_entries.as_ref().map(|ie| ie.entries.as_slice());
+            return Some(self.index_entries.as_slice());
         }
 
         return None;
@@ -441,3 +451,39 @@ where
             .map_err(|e| anyhow!("clear buf error: {}", e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::file_store::index::IndexEntry;
+    use crate::engine::tsm1::file_store::writer::index_writer::{DirectIndex, IndexWriter};
+
+    #[tokio::test]
+    async fn test_direct_index_entries() {
+        let mut index = DirectIndex::with_mem_buffer(1024);
+        index
+            .add(b"cpu", 0, IndexEntry::new(0, 9, 5, 95))
+            .await
+            .unwrap();
+        index
+            .add(b"cpu", 0, IndexEntry::new(10, 19, 100, 32))
+            .await
+            .unwrap();
+
+        let entries: &[IndexEntry] = index.entries(b"cpu").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(index.entries(b"mem").is_none());
+
+        let entry: &IndexEntry = index.entry(b"cpu", 15).unwrap();
+        assert_eq!((entry.offset, entry.size), (100, 32));
+        assert!(index.entry(b"cpu", 20).is_none());
+
+        // the entries of the next key replace those of the flushed one
+        index
+            .add(b"mem", 1, IndexEntry::new(0, 9, 132, 16))
+            .await
+            .unwrap();
+        assert!(index.entries(b"cpu").is_none());
+        assert_eq!(index.entries(b"mem").unwrap().len(), 1);
+        assert_eq!(index.key_count(), 2);
+    }
+}
@@ -1,29 +1,37 @@
-use std::path::Path;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use bytes::BytesMut;
+use common_base::iterator::AsyncIterator;
+use common_base::point::check_key_length;
 use filepath::FilePath;
+use influxdb_storage::StorageOperator;
+use influxdb_utils::hash::hash_key;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 
 use crate::engine::tsm1::block::decoder::block_type;
 use crate::engine::tsm1::block::encoder::encode_block;
+use crate::engine::tsm1::compact::{split_values, DEFAULT_MAX_POINTS_PER_BLOCK};
+use crate::engine::tsm1::file_store::bloom::{BloomFilter, BLOOM_MAGIC};
 use crate::engine::tsm1::file_store::index::IndexEntry;
+use crate::engine::tsm1::file_store::reader::tsm_iterator_v2::block_iterator::RawBlock;
 use crate::engine::tsm1::file_store::writer::index_writer::{
     DirectIndex, FileIndexBuffer, IndexWriter, MemoryIndexBuffer,
 };
 use crate::engine::tsm1::file_store::{FSYNC_EVERY, HEADER, MAX_INDEX_ENTRIES, MAX_KEY_LENGTH};
-use crate::engine::tsm1::value::{Array, Values};
+use crate::engine::tsm1::value::{Array, ValidateOptions, ValueError, Values};
 
 /// TSMWriter writes TSM formatted key and values.
 #[async_trait]
 pub trait TSMWriter {
-    /// write writes a new block for key containing and values.  Writes append
+    /// write writes new blocks for key containing values.  Writes append
     /// blocks in the order that the Write function is called.  The caller is
-    /// responsible for ensuring keys and blocks are sorted appropriately.
-    /// Values are encoded as a full block.  The caller is responsible for
-    /// ensuring a fixed number of values are encoded in each block as well as
-    /// ensuring the Values are sorted. The first and last timestamp values are
-    /// used as the minimum and maximum values for the index entry.
+    /// responsible for ensuring keys and blocks are sorted appropriately, as well
+    /// as ensuring the Values are sorted. Values are split into blocks of at most
+    /// the maximum points per block of the writer, each with its own index entry
+    /// whose minimum and maximum are the first and last timestamps of the block.
     async fn write(&mut self, key: &[u8], values: Values) -> anyhow::Result<()>;
 
     /// write_block writes a new block for key containing the bytes in block.  WriteBlock appends
@@ -39,25 +47,85 @@ pub trait TSMWriter {
         block: &[u8],
     ) -> anyhow::Result<()>;
 
+    /// write_raw_block writes a block read by `TSMReader::raw_block_iterator` as is.
+    /// Blocks copied in the order of the iterator keep the file sorted.
+    async fn write_raw_block(&mut self, block: &RawBlock) -> anyhow::Result<()>;
+
     /// write_index finishes the TSM write streams and writes the index.
     async fn write_index(&mut self) -> anyhow::Result<()>;
 
     /// Flushes flushes all pending changes to the underlying file resources.
     async fn flush(&mut self) -> anyhow::Result<()>;
 
-    /// close closes any underlying file resources.
-    async fn close(self) -> anyhow::Result<()>;
+    /// close closes any underlying file resources and returns the stats of the file.
+    async fn close(self) -> anyhow::Result<TSMWriterStats>;
 
     /// size returns the current size in bytes of the file.
     fn size(&self) -> u32;
 
     async fn remove(mut self) -> anyhow::Result<()>;
+
+    /// abort discards a file which can not be completed, e.g. after the writer is
+    /// poisoned, deleting whatever was written of it.
+    async fn abort(self) -> anyhow::Result<()>;
+}
+
+/// WriterPoisoned is returned by a TSM writer once a write failed part way: the
+/// file may end with a partial block, so nothing more is written to it and it
+/// can only be aborted, closing it aborts it too. The error which poisoned the
+/// writer is the source.
+#[derive(Debug, Clone)]
+pub struct WriterPoisoned {
+    cause: Arc<anyhow::Error>,
+}
+
+impl WriterPoisoned {
+    /// cause returns the error which poisoned the writer.
+    pub fn cause(&self) -> &anyhow::Error {
+        &self.cause
+    }
+}
+
+impl Display for WriterPoisoned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tsm writer poisoned: {}", self.cause)
+    }
+}
+
+impl std::error::Error for WriterPoisoned {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.cause.as_ref().as_ref())
+    }
+}
+
+/// TSMWriterStats summarizes a written TSM file.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TSMWriterStats {
+    /// size is the size of the file in bytes.
+    pub size: u64,
+    /// blocks is the number of blocks written.
+    pub blocks: u64,
+    /// padding is the number of zero bytes written to align blocks, see
+    /// `DefaultTSMWriter::with_align_blocks`.
+    pub padding: u64,
+}
+
+/// WriteSummary summarizes the series written by `DefaultTSMWriter::write_all`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WriteSummary {
+    /// keys is the number of series keys written.
+    pub keys: u64,
+    /// blocks is the number of blocks written.
+    pub blocks: u64,
+    /// points is the number of values written.
+    pub points: u64,
 }
 
 pub struct DefaultTSMWriter<I>
 where
     I: IndexWriter + Send + 'static,
 {
+    path: PathBuf,
     fd: File,
     buf: BytesMut,
 
@@ -66,6 +134,27 @@ where
 
     // The bytes written count of when we last fsync'd
     last_sync: u64,
+
+    align_blocks: Option<u32>,
+    /// max_points_per_block splits the values of a `write` into blocks of at
+    /// most this many points, 0 for a single block.
+    max_points_per_block: usize,
+    /// allow_reserved_timestamps accepts values at `i64::MIN`.
+    allow_reserved_timestamps: bool,
+    /// bloom_fp_rate adds a bloom filter of the keys to the file, see
+    /// `with_bloom_filter`.
+    bloom_fp_rate: Option<f64>,
+    /// key_hashes are the hashes of the keys written, for the bloom filter.
+    key_hashes: Vec<u64>,
+    last_key: Vec<u8>,
+    stats: TSMWriterStats,
+
+    /// poisoned is set by the first failed write, see `WriterPoisoned`.
+    poisoned: Option<WriterPoisoned>,
+
+    /// fail_block makes the write of the block with this index fail part way.
+    #[cfg(test)]
+    fail_block: Option<u64>,
 }
 
 impl DefaultTSMWriter<DirectIndex<MemoryIndexBuffer>> {
@@ -88,94 +177,200 @@ where
     I: IndexWriter + Send + 'static,
 {
     pub async fn new(tsm_path: impl AsRef<Path>, index: I) -> anyhow::Result<Self> {
+        let path = tsm_path.as_ref().to_path_buf();
         let fd = OpenOptions::new()
             .create_new(true)
             .write(true)
-            .open(tsm_path)
+            .open(&path)
             .await
             .map_err(|e| anyhow!(e))?;
 
         Ok(Self {
+            path,
             fd,
             buf: BytesMut::with_capacity(1024 * 1024),
             index,
             n: 0,
             last_sync: 0,
+            align_blocks: None,
+            max_points_per_block: DEFAULT_MAX_POINTS_PER_BLOCK,
+            allow_reserved_timestamps: false,
+            bloom_fp_rate: None,
+            key_hashes: vec![],
+            last_key: vec![],
+            stats: TSMWriterStats::default(),
+            poisoned: None,
+            #[cfg(test)]
+            fail_block: None,
         })
     }
 
-    async fn write_header(&mut self) -> anyhow::Result<()> {
-        // let mut buf = Vec::with_capacity(5);
-        // buf.put_u32(MAGIC_NUMBER);
-        // buf.put_u8(VERSION);
+    /// is_poisoned returns true if a write failed, see `WriterPoisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
 
-        let n = self.fd.write(&HEADER).await.map_err(|e| anyhow!(e))?;
-        self.n = n as u64;
+    fn check_poisoned(&self) -> anyhow::Result<()> {
+        match &self.poisoned {
+            Some(poisoned) => Err(anyhow!(poisoned.clone())),
+            None => Ok(()),
+        }
+    }
 
-        Ok(())
+    /// poison_on_err poisons the writer with the error of a write, if any.
+    fn poison_on_err<T>(&mut self, r: anyhow::Result<T>) -> anyhow::Result<T> {
+        r.map_err(|e| {
+            let poisoned = WriterPoisoned { cause: Arc::new(e) };
+            self.poisoned = Some(poisoned.clone());
+            anyhow!(poisoned)
+        })
     }
 
-    async fn sync(&mut self) -> anyhow::Result<()> {
-        self.fd.flush().await.map_err(|e| anyhow!(e))?;
-        self.fd.sync_all().await.map_err(|e| anyhow!(e))
+    /// with_align_blocks pads the file with zeros before each block so that the
+    /// block, starting with its checksum, is at a multiple of `align` bytes, e.g.
+    /// 4096 for direct IO. The index records the padded offsets, so readers are
+    /// unaffected.
+    pub fn with_align_blocks(mut self, align: Option<u32>) -> Self {
+        self.align_blocks = align.filter(|x| *x > 1);
+        self
     }
-}
 
-#[async_trait]
-impl<I> TSMWriter for DefaultTSMWriter<I>
-where
-    I: IndexWriter + Send + 'static,
-{
-    async fn write(&mut self, key: &[u8], values: Values) -> anyhow::Result<()> {
-        if key.len() > MAX_KEY_LENGTH {
-            // TODO return ErrMaxKeyLengthExceeded
-            return Err(anyhow!("ErrMaxKeyLengthExceeded"));
+    /// with_max_points_per_block sets the number of points past which `write`
+    /// splits values into several blocks, `DEFAULT_MAX_POINTS_PER_BLOCK` by
+    /// default. 0 writes the values of each call as a single block.
+    pub fn with_max_points_per_block(mut self, max_points_per_block: usize) -> Self {
+        self.max_points_per_block = max_points_per_block;
+        self
+    }
+
+    /// with_allow_reserved_timestamps accepts writes of values at `i64::MIN`, which
+    /// are otherwise rejected with `ValueError::ReservedTimestamp`: decoders and
+    /// tombstones use it as a sentinel, so only enable this for data known not to
+    /// be read through them.
+    pub fn with_allow_reserved_timestamps(mut self, allow: bool) -> Self {
+        self.allow_reserved_timestamps = allow;
+        self
+    }
+
+    /// with_bloom_filter writes a bloom filter of the keys, sized for the false
+    /// positive rate `fp_rate`, between the index and the footer. Readers use it
+    /// to skip the index lookup of most keys the file lacks, files without it
+    /// read as before. None writes no filter.
+    pub fn with_bloom_filter(mut self, fp_rate: Option<f64>) -> Self {
+        self.bloom_fp_rate = fp_rate;
+        self
+    }
+
+    /// write_all writes a stream of series sorted by key for bulk loads. The values of
+    /// each key must be sorted by time and are split into blocks of at most the
+    /// maximum points per block, see `with_max_points_per_block`. The order is checked as the stream is
+    /// consumed: a key not strictly greater than the previous one or unsorted values
+    /// fail the write before anything is written for that key.
+    pub async fn write_all<S>(&mut self, mut series: S) -> anyhow::Result<WriteSummary>
+    where
+        S: AsyncIterator<Item = (Vec<u8>, Values)>,
+    {
+        let mut summary = WriteSummary::default();
+        let mut last_key: Option<Vec<u8>> = None;
+
+        while let Some((key, values)) = series.try_next().await? {
+            if let Some(last_key) = &last_key {
+                if key.as_slice() <= last_key.as_slice() {
+                    return Err(anyhow!(
+                        "series key {} is not sorted after {}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(last_key)
+                    ));
+                }
+            }
+            if !values.ordered() {
+                return Err(anyhow!(
+                    "values of series key {} are not sorted by time",
+                    String::from_utf8_lossy(&key)
+                ));
+            }
+
+            if values.len() > 0 {
+                summary.points += values.len() as u64;
+                summary.blocks += self.block_count(values.len()) as u64;
+                self.write(key.as_slice(), values).await?;
+                summary.keys += 1;
+            }
+            last_key = Some(key);
         }
 
-        // Nothing to write
-        if values.len() == 0 {
-            return Ok(());
+        Ok(summary)
+    }
+
+    /// block_count returns the number of blocks `write` splits `points` values into.
+    fn block_count(&self, points: usize) -> usize {
+        match self.max_points_per_block {
+            0 => 1,
+            max => points.div_ceil(max),
         }
+    }
 
-        let min_time = values.min_time();
-        let max_time = values.max_time();
+    async fn write_padding(&mut self) -> anyhow::Result<()> {
+        let align = match self.align_blocks {
+            Some(align) => align as u64,
+            None => return Ok(()),
+        };
 
-        let mut block = vec![];
-        encode_block(&mut block, values)?;
+        let padding = (align - self.n % align) % align;
+        if padding > 0 {
+            let zeros = vec![0_u8; padding as usize];
+            self.fd.write_all(zeros.as_slice()).await?;
+            self.n += padding;
+            self.stats.padding += padding;
+        }
 
-        self.write_block(key, min_time, max_time, block.as_slice())
-            .await
+        Ok(())
     }
 
-    async fn write_block(
+    async fn write_header(&mut self) -> anyhow::Result<()> {
+        // let mut buf = Vec::with_capacity(5);
+        // buf.put_u32(MAGIC_NUMBER);
+        // buf.put_u8(VERSION);
+
+        let n = self.fd.write(&HEADER).await.map_err(|e| anyhow!(e))?;
+        self.n = n as u64;
+
+        Ok(())
+    }
+
+    /// write_block_at_end appends the block, prefixed by its checksum, and adds it
+    /// to the index.
+    async fn write_block_at_end(
         &mut self,
         key: &[u8],
         min_time: i64,
         max_time: i64,
         block: &[u8],
     ) -> anyhow::Result<()> {
-        if key.len() > MAX_KEY_LENGTH {
-            // TODO return ErrMaxKeyLengthExceeded
-            return Err(anyhow!("ErrMaxKeyLengthExceeded"));
-        }
-
-        // Nothing to write
-        if block.len() == 0 {
-            return Ok(());
-        }
-
         let block_type = block_type(block)?;
 
         // Write header only after we have some data to write.
         if self.n == 0 {
             self.write_header().await?;
         }
+        self.write_padding().await?;
 
         let mut n = 0;
         let checksum = crc32fast::hash(block);
         self.fd.write_u32(checksum).await.map_err(|e| anyhow!(e))?;
         n += 4;
-        n += self.fd.write(block).await.map_err(|e| anyhow!(e))?;
+
+        #[cfg(test)]
+        if self.fail_block == Some(self.stats.blocks) {
+            self.fd.write_all(&block[..block.len() / 2]).await?;
+            return Err(anyhow!(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "injected storage error"
+            )));
+        }
+
+        self.fd.write_all(block).await.map_err(|e| anyhow!(e))?;
+        n += block.len();
 
         // Record this block in index
         let index_entry = IndexEntry {
@@ -185,9 +380,15 @@ where
             size: n as u32,
         };
         self.index.add(key, block_type, index_entry).await?;
+        // keys are written in order, the blocks of a key one after the other
+        if self.bloom_fp_rate.is_some() && (self.key_hashes.is_empty() || self.last_key != key) {
+            self.key_hashes.push(hash_key(key));
+            self.last_key = key.to_vec();
+        }
 
         // Increment file position pointer
         self.n += n as u64;
+        self.stats.blocks += 1;
 
         // fsync the file periodically to avoid long pauses with very big files.
         if self.n - self.last_sync > FSYNC_EVERY {
@@ -195,6 +396,109 @@ where
             self.last_sync = self.n
         }
 
+        Ok(())
+    }
+
+    /// write_values encodes the values as a single block and writes it.
+    async fn write_values(&mut self, key: &[u8], values: Values) -> anyhow::Result<()> {
+        let min_time = values.min_time();
+        let max_time = values.max_time();
+
+        let mut block = vec![];
+        let r = encode_block(&mut block, values);
+        self.poison_on_err(r)?;
+
+        self.write_block(key, min_time, max_time, block.as_slice())
+            .await
+    }
+
+    /// write_bloom_filter writes the bloom filter section, if enabled:
+    /// `| filter | length(4B) | BLOOM_MAGIC(4B) |`.
+    async fn write_bloom_filter(&mut self) -> anyhow::Result<()> {
+        let fp_rate = match self.bloom_fp_rate {
+            Some(fp_rate) => fp_rate,
+            None => return Ok(()),
+        };
+
+        let mut filter = BloomFilter::new(self.key_hashes.len(), fp_rate);
+        for h in self.key_hashes.iter() {
+            filter.insert_hash(*h);
+        }
+        let data = filter.encode();
+        self.fd.write_all(data.as_slice()).await?;
+        self.fd.write_u32(data.len() as u32).await?;
+        self.fd.write_u32(BLOOM_MAGIC).await?;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        self.fd.flush().await.map_err(|e| anyhow!(e))?;
+        self.fd.sync_all().await.map_err(|e| anyhow!(e))
+    }
+}
+
+#[async_trait]
+impl<I> TSMWriter for DefaultTSMWriter<I>
+where
+    I: IndexWriter + Send + 'static,
+{
+    async fn write(&mut self, key: &[u8], values: Values) -> anyhow::Result<()> {
+        check_key_length(key, MAX_KEY_LENGTH)?;
+
+        // Nothing to write
+        if values.len() == 0 {
+            return Ok(());
+        }
+
+        self.check_poisoned()?;
+
+        if !self.allow_reserved_timestamps {
+            let options = ValidateOptions {
+                allow_non_finite: true,
+                ..Default::default()
+            };
+            values.validate_with(&options)?;
+        }
+
+        if self.block_count(values.len()) > 1 {
+            for chunk in split_values(&values, self.max_points_per_block) {
+                self.write_values(key, chunk).await?;
+            }
+            return Ok(());
+        }
+        self.write_values(key, values).await
+    }
+
+    async fn write_block(
+        &mut self,
+        key: &[u8],
+        min_time: i64,
+        max_time: i64,
+        block: &[u8],
+    ) -> anyhow::Result<()> {
+        check_key_length(key, MAX_KEY_LENGTH)?;
+
+        // Nothing to write
+        if block.len() == 0 {
+            return Ok(());
+        }
+
+        self.check_poisoned()?;
+
+        // blocks are sorted, a reserved timestamp can only be the first one
+        if !self.allow_reserved_timestamps && min_time == i64::MIN {
+            return Err(ValueError::ReservedTimestamp {
+                index: 0,
+                unix_nano: min_time,
+            }
+            .into());
+        }
+
+        let r = self
+            .write_block_at_end(key, min_time, max_time, block)
+            .await;
+        self.poison_on_err(r)?;
+
         if self.index.entries(key).map(|x| x.len()).unwrap_or_default() >= MAX_INDEX_ENTRIES {
             // TODO return ErrMaxBlocksExceeded
             return Err(anyhow!("ErrMaxBlocksExceeded"));
@@ -203,9 +507,30 @@ where
         Ok(())
     }
 
+    async fn write_raw_block(&mut self, block: &RawBlock) -> anyhow::Result<()> {
+        let typ = block_type(block.data.as_slice())?;
+        if typ != block.typ {
+            return Err(anyhow!(
+                "block of key {:?} is of type {}, expected {}",
+                String::from_utf8_lossy(block.key.as_slice()),
+                typ,
+                block.typ
+            ));
+        }
+
+        self.write_block(
+            block.key.as_slice(),
+            block.min_time,
+            block.max_time,
+            block.data.as_slice(),
+        )
+        .await
+    }
+
     /// WriteIndex writes the index section of the file.  If there are no index entries to write,
     /// this returns ErrNoValues.
     async fn write_index(&mut self) -> anyhow::Result<()> {
+        self.check_poisoned()?;
         let index_pos = self.n;
 
         if self.index.key_count() == 0 {
@@ -220,17 +545,30 @@ where
         // }
 
         // Write the index
-        self.index.write_to(&mut self.fd).await?;
+        let r = self.index.write_to(&mut self.fd).await;
+        self.poison_on_err(r)?;
+
+        let r = self.write_bloom_filter().await;
+        self.poison_on_err(r)?;
 
         // Write the index index position
-        self.fd.write_u64(index_pos).await.map_err(|e| anyhow!(e))
+        let r = self.fd.write_u64(index_pos).await.map_err(|e| anyhow!(e));
+        self.poison_on_err(r)
     }
 
     async fn flush(&mut self) -> anyhow::Result<()> {
-        self.sync().await
+        self.check_poisoned()?;
+        let r = self.sync().await;
+        self.poison_on_err(r)
     }
 
-    async fn close(mut self) -> anyhow::Result<()> {
+    async fn close(mut self) -> anyhow::Result<TSMWriterStats> {
+        // the partial file must not be left behind as if it was complete
+        if let Err(e) = self.check_poisoned() {
+            self.abort().await?;
+            return Err(e);
+        }
+
         self.flush().await?;
         self.index.close(true).await?;
 
@@ -238,7 +576,9 @@ where
         //     return c.Close()
         // }
         // return nil
-        Ok(())
+        let mut stats = self.stats;
+        stats.size = self.fd.metadata().await?.len();
+        Ok(stats)
     }
 
     fn size(&self) -> u32 {
@@ -257,12 +597,37 @@ where
 
         tokio::fs::remove_file(path).await.map_err(|e| anyhow!(e))
     }
+
+    async fn abort(self) -> anyhow::Result<()> {
+        let Self {
+            path, fd, index, ..
+        } = self;
+
+        index.close(false).await?;
+        drop(fd);
+
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("invalid tsm path {:?}", path))?;
+        StorageOperator::root(path)?.delete().await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
-    use crate::engine::tsm1::value::{TimeValue, Values};
+    use std::path::Path;
+
+    use common_base::iterator::AsyncIterator;
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{
+        DefaultTSMWriter, TSMWriter, TSMWriterStats, WriteSummary, WriterPoisoned,
+    };
+    use crate::engine::tsm1::file_store::HEADER;
+    use crate::engine::tsm1::value::{TimeValue, ValueError, Values};
 
     #[test]
     fn test_crc() {
@@ -297,4 +662,357 @@ mod tests {
             ]
         );
     }
+
+    const ALIGN_KEYS: [&str; 3] = ["cpu,host=a#!~#value", "cpu,host=b#!~#value", "mem#!~#free"];
+
+    async fn write_align_test_file(path: &Path, align: Option<u32>) -> TSMWriterStats {
+        let mut w = DefaultTSMWriter::with_mem_buffer(path)
+            .await
+            .unwrap()
+            .with_align_blocks(align);
+        for (i, key) in ALIGN_KEYS.iter().enumerate() {
+            // two blocks per key
+            for j in 0..2_i64 {
+                let values = (0..10_i64)
+                    .map(|t| TimeValue::new(j * 10 + t, (i as i64 * t) as f64))
+                    .collect();
+                w.write(key.as_bytes(), Values::Float(values))
+                    .await
+                    .unwrap();
+            }
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_align_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let aligned_file = dir.as_ref().join("aligned.tsm");
+        let unaligned_file = dir.as_ref().join("unaligned.tsm");
+
+        let aligned_stats = write_align_test_file(&aligned_file, Some(4096)).await;
+        let unaligned_stats = write_align_test_file(&unaligned_file, None).await;
+        assert_eq!(aligned_stats.blocks, 6);
+        assert_eq!(unaligned_stats.padding, 0);
+        assert!(aligned_stats.padding > 0);
+        assert_eq!(
+            aligned_stats.size,
+            unaligned_stats.size + aligned_stats.padding
+        );
+
+        let aligned =
+            new_default_tsm_reader(StorageOperator::root(aligned_file.to_str().unwrap()).unwrap())
+                .await
+                .unwrap();
+        let unaligned = new_default_tsm_reader(
+            StorageOperator::root(unaligned_file.to_str().unwrap()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        for key in ALIGN_KEYS {
+            let mut entries = IndexEntries::default();
+            aligned
+                .read_entries(key.as_bytes(), &mut entries)
+                .await
+                .unwrap();
+            assert_eq!(entries.len(), 2);
+
+            let mut aligned_values = Values::Float(vec![]);
+            for entry in entries.iter() {
+                assert_eq!(entry.offset % 4096, 0);
+                aligned
+                    .read_block_at(&entry, &mut aligned_values)
+                    .await
+                    .unwrap();
+            }
+
+            let mut entries = IndexEntries::default();
+            unaligned
+                .read_entries(key.as_bytes(), &mut entries)
+                .await
+                .unwrap();
+            let mut unaligned_values = Values::Float(vec![]);
+            for entry in entries.iter() {
+                unaligned
+                    .read_block_at(&entry, &mut unaligned_values)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(aligned_values, unaligned_values);
+        }
+
+        aligned.deep_verify().await.unwrap();
+        unaligned.deep_verify().await.unwrap();
+
+        // a non-zero byte in the padding after the header
+        let mut data = tokio::fs::read(&aligned_file).await.unwrap();
+        data[HEADER.len() + 1] = 1;
+        tokio::fs::write(&aligned_file, data).await.unwrap();
+        let aligned =
+            new_default_tsm_reader(StorageOperator::root(aligned_file.to_str().unwrap()).unwrap())
+                .await
+                .unwrap();
+        assert!(aligned.deep_verify().await.is_err());
+    }
+
+    fn poisoned_test_values(i: i64) -> Values {
+        Values::Float(
+            (0..10)
+                .map(|t| TimeValue::new(i * 10 + t, t as f64))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_poisoned() {
+        let dir = tempfile::tempdir().unwrap();
+        let tsm_file = dir.as_ref().join("poisoned.tsm");
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&tsm_file).await.unwrap();
+        w.fail_block = Some(2);
+        for i in 0..2 {
+            w.write(b"cpu", poisoned_test_values(i)).await.unwrap();
+        }
+        assert!(!w.is_poisoned());
+
+        let err = w.write(b"cpu", poisoned_test_values(2)).await.unwrap_err();
+        assert!(err.downcast_ref::<WriterPoisoned>().is_some());
+        assert!(w.is_poisoned());
+
+        // later calls fail with the original error as source, whatever their input
+        let err = w.write(b"mem", poisoned_test_values(3)).await.unwrap_err();
+        let poisoned = err.downcast_ref::<WriterPoisoned>().unwrap();
+        let source = std::error::Error::source(poisoned).unwrap();
+        let source = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.to_string(), "injected storage error");
+        assert!(w.write_index().await.unwrap_err().is::<WriterPoisoned>());
+        assert!(w.flush().await.unwrap_err().is::<WriterPoisoned>());
+
+        assert!(tsm_file.exists());
+        w.abort().await.unwrap();
+        assert!(!tsm_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_close_poisoned() {
+        let dir = tempfile::tempdir().unwrap();
+        let tsm_file = dir.as_ref().join("poisoned.tsm");
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&tsm_file).await.unwrap();
+        w.fail_block = Some(2);
+        for i in 0..3 {
+            let _ = w.write(b"cpu", poisoned_test_values(i)).await;
+        }
+        assert!(w.write_index().await.is_err());
+        let err = w.close().await.unwrap_err();
+        assert!(err.is::<WriterPoisoned>());
+
+        assert!(!tsm_file.exists());
+        let op = StorageOperator::root(tsm_file.to_str().unwrap()).unwrap();
+        assert!(new_default_tsm_reader(op).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_reserved_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("tsm1_test");
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let values = Values::Float(vec![TimeValue::new(i64::MIN, 1.0), TimeValue::new(0, 2.0)]);
+        let err = w.write("cpu".as_bytes(), values.clone()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ValueError>(),
+            Some(&ValueError::ReservedTimestamp {
+                index: 0,
+                unix_nano: i64::MIN
+            })
+        );
+        let err = w
+            .write_block("cpu".as_bytes(), i64::MIN, 0, &[1, 2, 3])
+            .await
+            .unwrap_err();
+        assert!(err.is::<ValueError>());
+
+        // the rejection does not poison the writer
+        assert!(!w.is_poisoned());
+        w.write(
+            "cpu".as_bytes(),
+            Values::Float(vec![TimeValue::new(1, 1.0)]),
+        )
+        .await
+        .unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        // unless allowed
+        let path = dir.as_ref().join("tsm1_test_allowed");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path)
+            .await
+            .unwrap()
+            .with_allow_reserved_timestamps(true);
+        w.write("cpu".as_bytes(), values.clone()).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        let r = new_default_tsm_reader(op).await.unwrap();
+        let mut entries = IndexEntries::default();
+        r.read_entries("cpu".as_bytes(), &mut entries)
+            .await
+            .unwrap();
+        let mut got = Values::Float(vec![]);
+        r.read_block_at(&entries.entry(0), &mut got).await.unwrap();
+        assert_eq!(got, values);
+    }
+
+    struct SeriesIterator(std::vec::IntoIter<(Vec<u8>, Values)>);
+
+    #[async_trait]
+    impl AsyncIterator for SeriesIterator {
+        type Item = (Vec<u8>, Values);
+
+        async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+            Ok(self.0.next())
+        }
+    }
+
+    fn float_series(points: i64) -> Values {
+        Values::Float((0..points).map(|t| TimeValue::new(t, t as f64)).collect())
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_max_points_per_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("tsm1_test");
+        let values = float_series(2500);
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path)
+            .await
+            .unwrap()
+            .with_max_points_per_block(1000);
+        w.write(b"cpu", values.clone()).await.unwrap();
+        w.write_index().await.unwrap();
+        assert_eq!(w.close().await.unwrap().blocks, 3);
+
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        let r = new_default_tsm_reader(op).await.unwrap();
+        let mut entries = IndexEntries::default();
+        r.read_entries(b"cpu", &mut entries).await.unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let mut all = Values::Float(vec![]);
+        for (i, (min_time, max_time)) in [(0, 999), (1000, 1999), (2000, 2499)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = entries.entry(i);
+            assert_eq!((entry.min_time, entry.max_time), (min_time, max_time));
+            if i > 0 {
+                let prev = entries.entry(i - 1);
+                assert_eq!(entry.offset, prev.offset + prev.size as u64);
+            }
+
+            let mut block = Values::Float(vec![]);
+            r.read_block_at(&entry, &mut block).await.unwrap();
+            assert_eq!(block.len(), (max_time - min_time + 1) as usize);
+            all = all.merge(block).unwrap();
+        }
+        assert_eq!(all, values);
+
+        // 0 writes a single block
+        let path = dir.as_ref().join("tsm1_test_unlimited");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path)
+            .await
+            .unwrap()
+            .with_max_points_per_block(0);
+        w.write(b"cpu", values).await.unwrap();
+        w.write_index().await.unwrap();
+        assert_eq!(w.close().await.unwrap().blocks, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_write_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("tsm1_test");
+
+        let series = vec![
+            (b"cpu,host=a#!~#value".to_vec(), float_series(2500)),
+            (b"cpu,host=b#!~#value".to_vec(), float_series(10)),
+            (
+                b"mem#!~#free".to_vec(),
+                Values::Integer(vec![TimeValue::new(1, 1), TimeValue::new(2, 2)]),
+            ),
+        ];
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let summary = w
+            .write_all(SeriesIterator(series.clone().into_iter()))
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            WriteSummary {
+                keys: 3,
+                blocks: 5,
+                points: 2512,
+            }
+        );
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        let r = new_default_tsm_reader(op).await.unwrap();
+        for (key, values) in series {
+            let mut entries = IndexEntries::default();
+            r.read_entries(key.as_slice(), &mut entries).await.unwrap();
+
+            let mut got = vec![];
+            for i in 0..entries.len() {
+                let mut block = match &values {
+                    Values::Integer(_) => Values::Integer(vec![]),
+                    _ => Values::Float(vec![]),
+                };
+                r.read_block_at(&entries.entry(i), &mut block)
+                    .await
+                    .unwrap();
+                assert!(block.len() <= 1000);
+                got.push(block);
+            }
+            let mut iter = got.into_iter();
+            let mut all = iter.next().unwrap();
+            for block in iter {
+                all = all.merge(block).unwrap();
+            }
+            assert_eq!(all, values);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tsm_writer_write_all_unsorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("tsm1_test");
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let series = vec![
+            (b"mem#!~#free".to_vec(), float_series(1)),
+            (b"cpu#!~#value".to_vec(), float_series(1)),
+        ];
+        let err = w
+            .write_all(SeriesIterator(series.into_iter()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not sorted"));
+
+        let path = dir.as_ref().join("tsm1_test_values");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let values = Values::Float(vec![TimeValue::new(2, 1.0), TimeValue::new(1, 2.0)]);
+        let series = vec![(b"cpu#!~#value".to_vec(), values)];
+        let err = w
+            .write_all(SeriesIterator(series.into_iter()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not sorted by time"));
+    }
 }
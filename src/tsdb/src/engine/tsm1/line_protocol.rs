@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use common_base::iterator::AsyncIterator;
+use common_base::point::{
+    check_key_length, parse_tsm_key, series_key, tsm_key, Field, FieldValue, Point, MAX_KEY_LENGTH,
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::engine::tsm1::file_store::file_store::new_values;
+use crate::engine::tsm1::file_store::index::IndexEntries;
+use crate::engine::tsm1::file_store::reader::tsm_reader::TSMReader;
+use crate::engine::tsm1::file_store::writer::index_writer::IndexWriter;
+use crate::engine::tsm1::file_store::writer::tsm_writer::{
+    DefaultTSMWriter, TSMWriter, WriteSummary,
+};
+use crate::engine::tsm1::value::{Array, FieldType, TimeValue, Values};
+
+/// LineExportStats reports what `export_lp` wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineExportStats {
+    pub keys: u64,
+    /// points is the number of lines written, one per value.
+    pub points: u64,
+}
+
+/// export_lp writes the values of the TSM file `reader` to w as line protocol,
+/// one line per value with its nanosecond timestamp. The key of each field is
+/// parsed back into its measurement, tags and field with `parse_tsm_key`, the
+/// lines are escaped so that `import_lp` rebuilds the same keys.
+///
+/// Keys are walked in order and the blocks of a key in file order. Tombstones
+/// are not applied.
+pub async fn export_lp<W>(reader: &dyn TSMReader, w: &mut W) -> anyhow::Result<LineExportStats>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut stats = LineExportStats::default();
+    let mut entries = IndexEntries::default();
+    let mut buf = vec![];
+
+    let mut keys = reader.key_iterator().await?;
+    while let Some(key) = keys.try_next().await? {
+        let (name, tags, field) = parse_tsm_key(key.as_slice()).map_err(|e| {
+            anyhow!(
+                "invalid key {}: {}",
+                String::from_utf8_lossy(key.as_slice()),
+                e
+            )
+        })?;
+        let mut point = Point {
+            name,
+            tags,
+            fields: vec![Field::new(field, FieldValue::Boolean(false))],
+            time: 0,
+        };
+
+        reader.read_entries(key.as_slice(), &mut entries).await?;
+        for entry in entries.iter() {
+            let mut values = new_values(entries.typ)?;
+            reader.read_block_at(&entry, &mut values).await?;
+
+            buf.clear();
+            for (time, value) in field_values(values)? {
+                point.fields[0].value = value;
+                point.time = time;
+                point.write_line(&mut buf);
+                buf.push(b'\n');
+                stats.points += 1;
+            }
+            w.write_all(buf.as_slice()).await?;
+        }
+        stats.keys += 1;
+    }
+
+    w.flush().await?;
+    Ok(stats)
+}
+
+/// field_values returns the values of a block as line protocol field values.
+fn field_values(values: Values) -> anyhow::Result<Vec<(i64, FieldValue)>> {
+    fn map<T, F>(values: Vec<TimeValue<T>>, f: F) -> Vec<(i64, FieldValue)>
+    where
+        T: FieldType,
+        F: Fn(T) -> FieldValue,
+    {
+        values
+            .into_iter()
+            .map(|v| (v.unix_nano, f(v.value)))
+            .collect()
+    }
+
+    let values = match values {
+        Values::Float(values) => map(values, FieldValue::Float),
+        Values::Integer(values) => map(values, FieldValue::Integer),
+        Values::Unsigned(values) => map(values, FieldValue::Unsigned),
+        Values::Bool(values) => map(values, FieldValue::Boolean),
+        Values::String(values) => {
+            let mut fields = Vec::with_capacity(values.len());
+            for v in values {
+                let s = String::from_utf8(v.value).map_err(|e| {
+                    anyhow!("string value at {} is not valid utf8: {}", v.unix_nano, e)
+                })?;
+                fields.push((v.unix_nano, FieldValue::String(s)));
+            }
+            fields
+        }
+    };
+    Ok(values)
+}
+
+/// import_lp parses lines of line protocol and writes their values to w, which
+/// is left open for the caller to write the index. Points without timestamp get
+/// the current time.
+///
+/// The values are grouped by TSM key, sorted by time and written in key order;
+/// of values of a key at the same time the last one is kept. A field written
+/// with different types, or a series key longer than max_series_key_length, see
+/// `KeyTooLong`, fails the import before anything is written.
+pub async fn import_lp<I>(
+    lines: &str,
+    max_series_key_length: usize,
+    w: &mut DefaultTSMWriter<I>,
+) -> anyhow::Result<WriteSummary>
+where
+    I: IndexWriter + Send + 'static,
+{
+    let points = Point::parse_lines(lines)?;
+
+    let mut series: BTreeMap<Vec<u8>, Values> = BTreeMap::new();
+    for point in points {
+        let sk = series_key(point.name.as_slice(), &point.tags);
+        check_key_length(sk.as_slice(), max_series_key_length)?;
+        for field in point.fields {
+            let key = tsm_key(sk.as_slice(), field.key.as_slice());
+            check_key_length(key.as_slice(), MAX_KEY_LENGTH)?;
+            let values = series
+                .entry(key.clone())
+                .or_insert_with(|| new_field_values(&field.value));
+            push_value(values, point.time, field.value)
+                .map_err(|e| anyhow!("field {}: {}", String::from_utf8_lossy(key.as_slice()), e))?;
+        }
+    }
+
+    for values in series.values_mut() {
+        values.deduplicate();
+    }
+    w.write_all(SeriesIterator(series.into_iter())).await
+}
+
+/// import_lp_file is `import_lp` into a new TSM file at path, with its index
+/// written.
+pub async fn import_lp_file(
+    lines: &str,
+    max_series_key_length: usize,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<WriteSummary> {
+    let mut w = DefaultTSMWriter::with_mem_buffer(path).await?;
+    let summary = import_lp(lines, max_series_key_length, &mut w).await?;
+    w.write_index().await?;
+    w.close().await?;
+    Ok(summary)
+}
+
+fn new_field_values(value: &FieldValue) -> Values {
+    match value {
+        FieldValue::Float(_) => Values::Float(vec![]),
+        FieldValue::Integer(_) => Values::Integer(vec![]),
+        FieldValue::Unsigned(_) => Values::Unsigned(vec![]),
+        FieldValue::Boolean(_) => Values::Bool(vec![]),
+        FieldValue::String(_) => Values::String(vec![]),
+    }
+}
+
+fn push_value(values: &mut Values, time: i64, value: FieldValue) -> anyhow::Result<()> {
+    match (values, value) {
+        (Values::Float(values), FieldValue::Float(v)) => values.push(TimeValue::new(time, v)),
+        (Values::Integer(values), FieldValue::Integer(v)) => values.push(TimeValue::new(time, v)),
+        (Values::Unsigned(values), FieldValue::Unsigned(v)) => values.push(TimeValue::new(time, v)),
+        (Values::Bool(values), FieldValue::Boolean(v)) => values.push(TimeValue::new(time, v)),
+        (Values::String(values), FieldValue::String(v)) => {
+            values.push(TimeValue::new(time, v.into_bytes()))
+        }
+        (_, v) => return Err(anyhow!("field type conflict, value {:?}", v)),
+    }
+    Ok(())
+}
+
+/// SeriesIterator yields the grouped values of `import_lp` in key order.
+struct SeriesIterator(std::collections::btree_map::IntoIter<Vec<u8>, Values>);
+
+#[async_trait]
+impl AsyncIterator for SeriesIterator {
+    type Item = (Vec<u8>, Values);
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        Ok(self.0.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common_base::iterator::AsyncIterator;
+    use common_base::point::{KeyTooLong, DEFAULT_MAX_SERIES_KEY_LENGTH};
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::file_store::new_values;
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{new_default_tsm_reader, TSMReader};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::line_protocol::{export_lp, field_values, import_lp, import_lp_file};
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    /// read_all returns the keys of a TSM file with all their values, as debug
+    /// strings so that floats compare by their representation, -0.0 included.
+    async fn read_all(reader: &dyn TSMReader) -> Vec<(Vec<u8>, Vec<(i64, String)>)> {
+        let mut all = vec![];
+        let mut keys = reader.key_iterator().await.unwrap();
+        while let Some(key) = keys.try_next().await.unwrap() {
+            let mut entries = IndexEntries::default();
+            reader
+                .read_entries(key.as_slice(), &mut entries)
+                .await
+                .unwrap();
+            let mut values = new_values(entries.typ).unwrap();
+            for entry in entries.iter() {
+                reader.read_block_at(&entry, &mut values).await.unwrap();
+            }
+            let values = field_values(values)
+                .unwrap()
+                .into_iter()
+                .map(|(t, v)| (t, format!("{:?}", v)))
+                .collect();
+            all.push((key, values));
+        }
+        all
+    }
+
+    #[tokio::test]
+    async fn test_line_protocol_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+
+        let series: Vec<(&[u8], Values)> = vec![
+            (
+                b"cpu,host=a#!~#count",
+                Values::Integer(vec![
+                    TimeValue::new(1, i64::MIN),
+                    TimeValue::new(2, i64::MAX),
+                ]),
+            ),
+            (
+                b"cpu,host=a#!~#msg",
+                Values::String(vec![
+                    TimeValue::new(1, b"say \"hi\"".to_vec()),
+                    TimeValue::new(2, br"c:\dir, a=b".to_vec()),
+                ]),
+            ),
+            (
+                b"cpu,host=a#!~#total",
+                Values::Unsigned(vec![TimeValue::new(3, u64::MAX)]),
+            ),
+            (
+                br"cpu\ load,host=a\,b,region=us\=west#!~#value x",
+                Values::Float(vec![
+                    TimeValue::new(-5, 0.1),
+                    TimeValue::new(0, -0.0),
+                    TimeValue::new(7, 1e300),
+                ]),
+            ),
+            (
+                b"mem#!~#up",
+                Values::Bool(vec![TimeValue::new(4, true), TimeValue::new(5, false)]),
+            ),
+        ];
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path)
+            .await
+            .unwrap()
+            .with_max_points_per_block(2);
+        for (key, values) in series {
+            w.write(key, values).await.unwrap();
+        }
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        let reader = new_default_tsm_reader(StorageOperator::root(path.to_str().unwrap()).unwrap())
+            .await
+            .unwrap();
+        let mut lines = vec![];
+        let stats = export_lp(&reader, &mut lines).await.unwrap();
+        assert_eq!(stats.keys, 5);
+        assert_eq!(stats.points, 10);
+        let lines = String::from_utf8(lines).unwrap();
+        assert!(lines.contains("cpu,host=a msg=\"c:\\\\dir, a=b\" 2\n",));
+
+        let imported = dir.as_ref().join("000000002-000000001.tsm");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&imported).await.unwrap();
+        let summary = import_lp(lines.as_str(), DEFAULT_MAX_SERIES_KEY_LENGTH, &mut w)
+            .await
+            .unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+        assert_eq!(summary.keys, 5);
+        assert_eq!(summary.points, 10);
+
+        let imported =
+            new_default_tsm_reader(StorageOperator::root(imported.to_str().unwrap()).unwrap())
+                .await
+                .unwrap();
+        assert_eq!(read_all(&reader).await, read_all(&imported).await);
+    }
+
+    #[tokio::test]
+    async fn test_import_lp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+
+        // unsorted, with a duplicate time of which the last value is kept
+        let lines = "cpu,host=a value=3 30\n\
+                     cpu,host=a value=1 10\n\
+                     # comment\n\
+                     cpu,host=a value=2,count=1i 10\n";
+        let summary = import_lp_file(lines, DEFAULT_MAX_SERIES_KEY_LENGTH, &path)
+            .await
+            .unwrap();
+        assert_eq!((summary.keys, summary.points), (2, 3));
+
+        let reader = new_default_tsm_reader(StorageOperator::root(path.to_str().unwrap()).unwrap())
+            .await
+            .unwrap();
+        let all = read_all(&reader).await;
+        assert_eq!(all[0].0, b"cpu,host=a#!~#count".to_vec());
+        assert_eq!(all[1].0, b"cpu,host=a#!~#value".to_vec());
+        assert_eq!(
+            all[1].1,
+            vec![
+                (10, "Float(2.0)".to_string()),
+                (30, "Float(3.0)".to_string())
+            ]
+        );
+
+        // a field written with two types
+        let path = dir.as_ref().join("000000002-000000001.tsm");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let err = import_lp(
+            "cpu value=1 10\ncpu value=1i 20",
+            DEFAULT_MAX_SERIES_KEY_LENGTH,
+            &mut w,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("field type conflict"), "{}", err);
+
+        // a series key one byte over the limit, `cpu,host=aa` is 11 bytes
+        let path = dir.as_ref().join("000000003-000000001.tsm");
+        let mut w = DefaultTSMWriter::with_mem_buffer(&path).await.unwrap();
+        let err = import_lp("cpu,host=a value=1 10\ncpu,host=aa value=1 20", 10, &mut w)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KeyTooLong>(),
+            Some(&KeyTooLong {
+                measurement: b"cpu".to_vec(),
+                len: 11,
+                max: 10,
+            })
+        );
+        assert_eq!(w.size(), 0);
+    }
+}
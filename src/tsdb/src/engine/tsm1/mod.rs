@@ -1,4 +1,19 @@
+pub mod aggregate;
 pub mod block;
+pub mod cache;
 pub mod codec;
+pub mod compact;
+pub mod compact_planner;
+pub mod diff;
+pub mod engine;
+pub mod export;
 pub mod file_store;
+pub mod line_protocol;
+pub mod negative_cache;
+pub mod read_semaphore;
+pub mod repair;
+pub mod schema;
+pub mod series_hook;
+pub mod shard_lock;
 pub mod value;
+pub mod wal;
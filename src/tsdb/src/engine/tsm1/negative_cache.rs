@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common_base::clock::{Clock, SystemClock};
+use influxdb_utils::hash::hash_key;
+
+/// DEFAULT_NEGATIVE_CACHE_TTL is the default time a key found missing is
+/// remembered for.
+pub const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// DEFAULT_NEGATIVE_CACHE_SIZE is the default number of missing keys remembered.
+pub const DEFAULT_NEGATIVE_CACHE_SIZE: usize = 10_000;
+
+/// NegativeCacheStats counts the lookups of a `NegativeCache`.
+#[derive(Debug, Default)]
+pub struct NegativeCacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl NegativeCacheStats {
+    /// hits returns the number of lookups answered as missing by the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// misses returns the number of lookups which had to probe the cache and
+    /// the files of the shard.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// insertions returns the number of keys remembered as missing.
+    pub fn insertions(&self) -> u64 {
+        self.insertions.load(Ordering::Relaxed)
+    }
+
+    /// invalidations returns the number of keys forgotten before they expired,
+    /// because they were written or the files of the shard changed.
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+}
+
+struct Entries {
+    /// expires maps the hash of a missing key to the time it is forgotten.
+    expires: HashMap<u64, Instant>,
+    /// epoch changes on every invalidation, a lookup which started before must
+    /// not remember its key: it may have missed the write invalidating it.
+    epoch: u64,
+}
+
+/// NegativeCache remembers, for a short time, the keys a read found no data
+/// for, so repeated reads of a missing key skip the cache and the files.
+///
+/// Keys are remembered by their hash: a key colliding with a missing one reads
+/// as missing until the entry expires or is invalidated.
+pub struct NegativeCache {
+    ttl: Duration,
+    max_size: usize,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<Entries>,
+    stats: NegativeCacheStats,
+}
+
+impl NegativeCache {
+    /// new returns a cache remembering up to max_size keys for ttl. A zero ttl or
+    /// max_size disables it.
+    pub fn new(ttl: Duration, max_size: usize) -> Self {
+        Self {
+            ttl,
+            max_size,
+            clock: Arc::new(SystemClock),
+            entries: Mutex::new(Entries {
+                expires: HashMap::new(),
+                epoch: 0,
+            }),
+            stats: NegativeCacheStats::default(),
+        }
+    }
+
+    /// with_clock returns the cache with its entries expiring per clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stats(&self) -> &NegativeCacheStats {
+        &self.stats
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_size > 0
+    }
+
+    /// probe returns None if key was found missing less than ttl ago. Otherwise
+    /// it returns the epoch to pass to `insert` if the lookup finds the key
+    /// missing.
+    pub fn probe(&self, key: &[u8]) -> Option<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        if self.enabled() {
+            let hash = hash_key(key);
+            match entries.expires.get(&hash) {
+                Some(expires) if *expires > self.clock.now_instant() => {
+                    self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                Some(_) => {
+                    entries.expires.remove(&hash);
+                }
+                None => {}
+            }
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        Some(entries.epoch)
+    }
+
+    /// insert remembers key as missing, unless the cache was invalidated since
+    /// `epoch` was returned by `probe`. The entry expiring first is evicted
+    /// if the cache is full.
+    pub fn insert(&self, key: &[u8], epoch: u64) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.epoch != epoch {
+            return;
+        }
+
+        let now = self.clock.now_instant();
+        if entries.expires.len() >= self.max_size {
+            entries.expires.retain(|_, expires| *expires > now);
+        }
+        if entries.expires.len() >= self.max_size {
+            let oldest = entries
+                .expires
+                .iter()
+                .min_by_key(|(_, expires)| **expires)
+                .map(|(hash, _)| *hash);
+            if let Some(hash) = oldest {
+                entries.expires.remove(&hash);
+            }
+        }
+
+        entries.expires.insert(hash_key(key), now + self.ttl);
+        self.stats.insertions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// invalidate forgets keys, e.g. once they are written.
+    pub fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.epoch += 1;
+        if entries.expires.is_empty() {
+            return;
+        }
+
+        let mut removed = 0;
+        for key in keys {
+            if entries.expires.remove(&hash_key(key)).is_some() {
+                removed += 1;
+            }
+        }
+        self.stats
+            .invalidations
+            .fetch_add(removed, Ordering::Relaxed);
+    }
+
+    /// clear forgets all keys, e.g. once files are added to the shard.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.epoch += 1;
+        let removed = entries.expires.len() as u64;
+        entries.expires.clear();
+        self.stats
+            .invalidations
+            .fetch_add(removed, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use common_base::clock::SimulatedClock;
+
+    use crate::engine::tsm1::negative_cache::NegativeCache;
+
+    #[test]
+    fn test_negative_cache_evicts_oldest() {
+        let clock = SimulatedClock::new(0);
+        let cache =
+            NegativeCache::new(Duration::from_secs(60), 2).with_clock(Arc::new(clock.clone()));
+        for key in [b"a", b"b", b"c"] {
+            let epoch = cache.probe(key).unwrap();
+            cache.insert(key, epoch);
+            clock.advance(Duration::from_millis(1));
+        }
+
+        assert!(cache.probe(b"a").is_some());
+        assert!(cache.probe(b"b").is_none());
+        assert!(cache.probe(b"c").is_none());
+    }
+
+    #[test]
+    fn test_negative_cache_stale_epoch() {
+        let cache = NegativeCache::new(Duration::from_secs(60), 10);
+        let epoch = cache.probe(b"a").unwrap();
+        cache.invalidate([b"a".as_slice()]);
+        cache.insert(b"a", epoch);
+
+        assert!(cache.probe(b"a").is_some());
+        assert_eq!(cache.stats().insertions(), 0);
+    }
+
+    #[test]
+    fn test_negative_cache_ttl() {
+        let clock = SimulatedClock::new(0);
+        let cache =
+            NegativeCache::new(Duration::from_secs(60), 10).with_clock(Arc::new(clock.clone()));
+        let epoch = cache.probe(b"a").unwrap();
+        cache.insert(b"a", epoch);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(cache.probe(b"a").is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.probe(b"a").is_some());
+        assert_eq!(cache.stats().hits(), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use influxdb_storage::opendal::raw::oio;
+use influxdb_storage::opendal::raw::*;
+use influxdb_storage::opendal::Result;
+use influxdb_storage::StorageOperator;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// ReadSemaphoreStats gauges the reads going through a `ReadSemaphore`.
+#[derive(Debug, Default)]
+pub struct ReadSemaphoreStats {
+    in_flight: AtomicU64,
+    max_in_flight: AtomicU64,
+    acquired: AtomicU64,
+    waited: AtomicU64,
+}
+
+impl ReadSemaphoreStats {
+    /// in_flight returns the number of readers currently open.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// max_in_flight returns the highest number of readers open at once.
+    pub fn max_in_flight(&self) -> u64 {
+        self.max_in_flight.load(Ordering::Relaxed)
+    }
+
+    /// acquired returns the number of permits handed out.
+    pub fn acquired(&self) -> u64 {
+        self.acquired.load(Ordering::Relaxed)
+    }
+
+    /// waited returns the number of reads which had to wait for a permit.
+    pub fn waited(&self) -> u64 {
+        self.waited.load(Ordering::Relaxed)
+    }
+}
+
+/// ReadSemaphore caps the number of readers open at once on the operators it is
+/// applied to, e.g. so a scan over all files of a shard does not open thousands
+/// of connections to an object store. A single semaphore can be shared by the
+/// engines of several shards for a global cap.
+///
+/// A read takes a permit before the reader is opened and holds it until the
+/// reader is dropped, so the permit count must be at least the number of
+/// readers a single read holds at once.
+#[derive(Debug, Clone)]
+pub struct ReadSemaphore {
+    permits: usize,
+    semaphore: Arc<Semaphore>,
+    stats: Arc<ReadSemaphoreStats>,
+}
+
+impl ReadSemaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            semaphore: Arc::new(Semaphore::new(permits)),
+            stats: Arc::new(ReadSemaphoreStats::default()),
+        }
+    }
+
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    pub fn stats(&self) -> &ReadSemaphoreStats {
+        &self.stats
+    }
+
+    /// apply returns op with its reads limited by the semaphore.
+    pub fn apply(&self, op: &StorageOperator) -> StorageOperator {
+        op.layer(self.clone())
+    }
+
+    async fn acquire(&self) -> Permit {
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.stats.waited.fetch_add(1, Ordering::Relaxed);
+                self.semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("read semaphore is never closed")
+            }
+        };
+
+        self.stats.acquired.fetch_add(1, Ordering::Relaxed);
+        let n = self.stats.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.stats.max_in_flight.fetch_max(n, Ordering::Relaxed);
+        Permit {
+            _permit: permit,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<A: Accessor> Layer<A> for ReadSemaphore {
+    type LayeredAccessor = ReadSemaphoreAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccessor {
+        ReadSemaphoreAccessor {
+            inner,
+            semaphore: self.clone(),
+        }
+    }
+}
+
+/// Permit is held by an open reader, it updates the gauges once released.
+struct Permit {
+    _permit: OwnedSemaphorePermit,
+    stats: Arc<ReadSemaphoreStats>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadSemaphoreAccessor<A: Accessor> {
+    inner: A,
+    semaphore: ReadSemaphore,
+}
+
+pub struct ReadSemaphoreReader<R> {
+    inner: R,
+    _permit: Permit,
+}
+
+impl<R: oio::Read> oio::Read for ReadSemaphoreReader<R> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        self.inner.poll_read(cx, buf)
+    }
+
+    fn poll_seek(&mut self, cx: &mut Context<'_>, pos: SeekFrom) -> Poll<Result<u64>> {
+        self.inner.poll_seek(cx, pos)
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        self.inner.poll_next(cx)
+    }
+}
+
+#[async_trait]
+impl<A: Accessor> LayeredAccessor for ReadSemaphoreAccessor<A> {
+    type Inner = A;
+    type Reader = ReadSemaphoreReader<A::Reader>;
+    type BlockingReader = A::BlockingReader;
+    type Writer = A::Writer;
+    type BlockingWriter = A::BlockingWriter;
+    type Appender = A::Appender;
+    type Pager = A::Pager;
+    type BlockingPager = A::BlockingPager;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        let permit = self.semaphore.acquire().await;
+        let (rp, inner) = self.inner.read(path, args).await?;
+        let r = ReadSemaphoreReader {
+            inner,
+            _permit: permit,
+        };
+        Ok((rp, r))
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        self.inner.write(path, args).await
+    }
+
+    async fn append(&self, path: &str, args: OpAppend) -> Result<(RpAppend, Self::Appender)> {
+        self.inner.append(path, args).await
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Pager)> {
+        self.inner.list(path, args).await
+    }
+
+    /// blocking_read is not limited, the engine only reads asynchronously.
+    fn blocking_read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::BlockingReader)> {
+        self.inner.blocking_read(path, args)
+    }
+
+    fn blocking_write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::BlockingWriter)> {
+        self.inner.blocking_write(path, args)
+    }
+
+    fn blocking_list(&self, path: &str, args: OpList) -> Result<(RpList, Self::BlockingPager)> {
+        self.inner.blocking_list(path, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common_base::clock::SimulatedClock;
+    use influxdb_storage::StorageOperator;
+    use tokio::io::AsyncReadExt;
+
+    use crate::engine::tsm1::read_semaphore::ReadSemaphore;
+
+    #[tokio::test]
+    async fn test_read_semaphore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let semaphore = ReadSemaphore::new(2);
+        let op = semaphore.apply(&StorageOperator::root(path.to_str().unwrap()).unwrap());
+        let clock = SimulatedClock::new(0);
+
+        // each reader is held open until the clock advances
+        let mut tasks = vec![];
+        for _ in 0..16 {
+            let op = op.clone();
+            let clock = clock.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut reader = op.reader().await.unwrap();
+                clock.sleep(Duration::from_secs(1)).await;
+                let mut buf = vec![];
+                reader.read_to_end(&mut buf).await.unwrap();
+                assert_eq!(buf, b"0123456789");
+            }));
+        }
+        while !tasks.iter().all(|x| x.is_finished()) {
+            tokio::task::yield_now().await;
+            clock.advance(Duration::from_secs(1));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let stats = semaphore.stats();
+        assert_eq!(stats.acquired(), 16);
+        assert_eq!(stats.max_in_flight(), 2);
+        assert_eq!(stats.in_flight(), 0);
+        assert!(stats.waited() > 0);
+    }
+}
@@ -0,0 +1,230 @@
+use std::path::Path;
+
+use common_base::point::tsm_key;
+use influxdb_storage::StorageOperator;
+
+use crate::engine::tsm1::block::decoder::decode_block;
+use crate::engine::tsm1::file_store::file_store::new_values;
+use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+use crate::engine::tsm1::file_store::HEADER;
+use crate::engine::tsm1::value::Array;
+
+/// REPAIRED_MEASUREMENT is the measurement of the keys given to the blocks salvaged
+/// by `repair_index`.
+pub const REPAIRED_MEASUREMENT: &str = "_repaired";
+
+/// RepairStats reports what `repair_index` salvaged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RepairStats {
+    /// blocks is the number of blocks salvaged.
+    pub blocks: u64,
+    /// keys is the number of keys the blocks were written under.
+    pub keys: u64,
+    /// skipped is the number of bytes after the last block, e.g. what is left of
+    /// the index.
+    pub skipped: u64,
+}
+
+/// SalvagedBlock is a block found by scanning the blocks region of a TSM file.
+struct SalvagedBlock {
+    typ: u8,
+    min_time: i64,
+    max_time: i64,
+    start: usize,
+    end: usize,
+}
+
+/// repair_index salvages the blocks of a TSM file whose index or footer is lost
+/// and writes them with a new index into a TSM file at `output`.
+///
+/// Blocks are framed by their checksum only: the end of each block is found as the
+/// first position where the checksum of the data matches and the data decodes.
+/// The keys are only stored in the index and can not be recovered. The blocks of a
+/// key are contiguous and in time order, so consecutive blocks of the same type
+/// with increasing times are written under the same key
+/// `_repaired#!~#<run number>`. Adjacent keys of the same type whose times follow
+/// each other end up under the same key.
+pub async fn repair_index(
+    input: StorageOperator,
+    output: impl AsRef<Path>,
+) -> anyhow::Result<RepairStats> {
+    let data = input.operator().read(input.path()).await?;
+    if data.len() < HEADER.len() || data[..HEADER.len()] != HEADER {
+        return Err(anyhow!("{} is not a TSM file", input.path()));
+    }
+
+    let mut stats = RepairStats::default();
+    let mut blocks = vec![];
+    let mut pos = HEADER.len();
+    while pos < data.len() {
+        match salvage_block(data.as_slice(), pos) {
+            Some(block) => {
+                pos = block.end;
+                blocks.push(block);
+            }
+            // blocks may be aligned with zeros
+            None if data[pos] == 0 => pos += 1,
+            None => break,
+        }
+    }
+    let end = match blocks.last() {
+        Some(block) => block.end,
+        None => return Err(anyhow!("no block found in {}", input.path())),
+    };
+    stats.skipped = (data.len() - end) as u64;
+
+    let mut w = DefaultTSMWriter::with_mem_buffer(output).await?;
+    let mut key = vec![];
+    let mut prev: Option<&SalvagedBlock> = None;
+    for block in blocks.iter() {
+        let same_run = prev
+            .map(|prev| prev.typ == block.typ && prev.max_time < block.min_time)
+            .unwrap_or(false);
+        if !same_run {
+            let field = format!("{:06}", stats.keys);
+            key = tsm_key(REPAIRED_MEASUREMENT.as_bytes(), field.as_bytes());
+            stats.keys += 1;
+        }
+
+        let r = w
+            .write_block(
+                key.as_slice(),
+                block.min_time,
+                block.max_time,
+                &data[block.start..block.end],
+            )
+            .await;
+        if let Err(e) = r {
+            w.abort().await?;
+            return Err(e);
+        }
+        stats.blocks += 1;
+        prev = Some(block);
+    }
+
+    w.write_index().await?;
+    w.close().await?;
+
+    Ok(stats)
+}
+
+/// salvage_block returns the block whose checksum starts at `pos`, if any.
+fn salvage_block(data: &[u8], pos: usize) -> Option<SalvagedBlock> {
+    let start = pos + 4;
+    if start >= data.len() {
+        return None;
+    }
+    let checksum = u32::from_be_bytes(data[pos..start].try_into().ok()?);
+    let mut values = new_values(data[start]).ok()?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    for end in start + 1..=data.len() {
+        hasher.update(&data[end - 1..end]);
+        if hasher.clone().finalize() != checksum {
+            continue;
+        }
+
+        if decode_block(&data[start..end], &mut values).is_ok() && values.len() > 0 {
+            return Some(SalvagedBlock {
+                typ: data[start],
+                min_time: values.min_time(),
+                max_time: values.max_time(),
+                start,
+                end,
+            });
+        }
+        values = new_values(data[start]).ok()?;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::file_store::new_values;
+    use crate::engine::tsm1::file_store::index::IndexEntries;
+    use crate::engine::tsm1::file_store::reader::tsm_reader::{DefaultTSMReader, TSMReader};
+    use crate::engine::tsm1::file_store::writer::tsm_writer::{DefaultTSMWriter, TSMWriter};
+    use crate::engine::tsm1::repair::{repair_index, RepairStats};
+    use crate::engine::tsm1::value::{TimeValue, Values};
+
+    async fn read_all(path: &str, key: &[u8]) -> Values {
+        let r = DefaultTSMReader::new(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        let mut entries = IndexEntries::default();
+        r.read_entries(key, &mut entries).await.unwrap();
+
+        let mut values = new_values(entries.typ).unwrap();
+        for entry in entries.iter() {
+            let mut block = new_values(entries.typ).unwrap();
+            r.read_block_at(&entry, &mut block).await.unwrap();
+            match (&mut values, block) {
+                (Values::Float(dst), Values::Float(src)) => dst.extend(src),
+                (Values::Integer(dst), Values::Integer(src)) => dst.extend(src),
+                _ => unreachable!(),
+            }
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn test_repair_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.as_ref().join("000000001-000000001.tsm");
+        let path = path.to_str().unwrap();
+        let repaired = dir.as_ref().join("000000002-000000001.tsm");
+        let repaired = repaired.to_str().unwrap();
+
+        let cpu = Values::Float((0..20).map(|i| TimeValue::new(i, i as f64)).collect());
+        let disk = Values::Float((0..5).map(|i| TimeValue::new(i, -i as f64)).collect());
+        let mem = Values::Integer((0..7).map(|i| TimeValue::new(i * 10, i)).collect());
+
+        let mut w = DefaultTSMWriter::with_mem_buffer(path)
+            .await
+            .unwrap()
+            .with_align_blocks(Some(64));
+        let (cpu_first, cpu_second) = match &cpu {
+            Values::Float(v) => (v[..10].to_vec(), v[10..].to_vec()),
+            _ => unreachable!(),
+        };
+        w.write(b"cpu#!~#value", Values::Float(cpu_first))
+            .await
+            .unwrap();
+        w.write(b"cpu#!~#value", Values::Float(cpu_second))
+            .await
+            .unwrap();
+        w.write(b"disk#!~#value", disk.clone()).await.unwrap();
+        w.write(b"mem#!~#value", mem.clone()).await.unwrap();
+        w.write_index().await.unwrap();
+        w.close().await.unwrap();
+
+        // lose the index and the footer, which holds the offset of the index
+        let mut data = std::fs::read(path).unwrap();
+        let footer = data.len() - 8;
+        let index_offset = u64::from_be_bytes(data[footer..].try_into().unwrap()) as usize;
+        data.truncate(index_offset + 10);
+        std::fs::write(path, data).unwrap();
+        assert!(DefaultTSMReader::new(StorageOperator::root(path).unwrap())
+            .await
+            .is_err());
+
+        let stats = repair_index(StorageOperator::root(path).unwrap(), repaired)
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            RepairStats {
+                blocks: 4,
+                keys: 3,
+                skipped: 10,
+            }
+        );
+
+        assert_eq!(read_all(repaired, b"_repaired#!~#000000").await, cpu);
+        assert_eq!(read_all(repaired, b"_repaired#!~#000001").await, disk);
+        assert_eq!(read_all(repaired, b"_repaired#!~#000002").await, mem);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use common_base::point::{FieldValue, Point};
+
+use crate::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+
+/// SchemaMode is whether writes to a shard may create measurements, fields and
+/// tag keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SchemaMode {
+    /// Open creates them on first write.
+    #[default]
+    Open,
+    /// Strict rejects the points writing a measurement, field or tag key not
+    /// declared in the `SchemaRegistry`, see `SchemaViolation`. The data already
+    /// in the shard is not checked.
+    Strict,
+}
+
+/// SchemaViolation is a point rejected in `SchemaMode::Strict`.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SchemaViolation {
+    #[error("measurement {} is not declared", String::from_utf8_lossy(measurement))]
+    UnknownMeasurement { measurement: Vec<u8> },
+    #[error(
+        "field {} is not declared for measurement {}",
+        String::from_utf8_lossy(field),
+        String::from_utf8_lossy(measurement)
+    )]
+    UnknownField {
+        measurement: Vec<u8>,
+        field: Vec<u8>,
+    },
+    #[error(
+        "tag key {} is not declared for measurement {}",
+        String::from_utf8_lossy(tag_key),
+        String::from_utf8_lossy(measurement)
+    )]
+    UnknownTagKey {
+        measurement: Vec<u8>,
+        tag_key: Vec<u8>,
+    },
+    #[error(
+        "field {} of measurement {} is declared with block type {expected}, written with block type {actual}",
+        String::from_utf8_lossy(field),
+        String::from_utf8_lossy(measurement)
+    )]
+    FieldType {
+        measurement: Vec<u8>,
+        field: Vec<u8>,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+struct MeasurementSchema {
+    /// fields maps the declared fields to their block type.
+    fields: HashMap<Vec<u8>, u8>,
+    tag_keys: HashSet<Vec<u8>>,
+}
+
+/// SchemaRegistry holds the measurements declared for `SchemaMode::Strict`, it
+/// can be shared by several shards.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    measurements: RwLock<HashMap<Vec<u8>, MeasurementSchema>>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// declare_measurement declares measurement with its fields, by block type,
+    /// and tag keys, replacing a previous declaration.
+    pub fn declare_measurement(
+        &self,
+        measurement: &[u8],
+        fields: &[(&[u8], u8)],
+        tag_keys: &[&[u8]],
+    ) {
+        let schema = MeasurementSchema {
+            fields: fields.iter().map(|(k, typ)| (k.to_vec(), *typ)).collect(),
+            tag_keys: tag_keys.iter().map(|k| k.to_vec()).collect(),
+        };
+        self.measurements
+            .write()
+            .unwrap()
+            .insert(measurement.to_vec(), schema);
+    }
+
+    /// check returns the first violation of the declared schema by point.
+    pub fn check(&self, point: &Point) -> Result<(), SchemaViolation> {
+        let measurements = self.measurements.read().unwrap();
+        let schema = match measurements.get(&point.name) {
+            Some(schema) => schema,
+            None => {
+                return Err(SchemaViolation::UnknownMeasurement {
+                    measurement: point.name.clone(),
+                })
+            }
+        };
+
+        for tag in point.tags.iter() {
+            if !schema.tag_keys.contains(&tag.key) {
+                return Err(SchemaViolation::UnknownTagKey {
+                    measurement: point.name.clone(),
+                    tag_key: tag.key.clone(),
+                });
+            }
+        }
+
+        for field in point.fields.iter() {
+            let actual = block_type(&field.value);
+            match schema.fields.get(&field.key) {
+                Some(expected) if *expected == actual => {}
+                Some(expected) => {
+                    return Err(SchemaViolation::FieldType {
+                        measurement: point.name.clone(),
+                        field: field.key.clone(),
+                        expected: *expected,
+                        actual,
+                    })
+                }
+                None => {
+                    return Err(SchemaViolation::UnknownField {
+                        measurement: point.name.clone(),
+                        field: field.key.clone(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// block_type returns the TSM block type the value is written to.
+fn block_type(value: &FieldValue) -> u8 {
+    match value {
+        FieldValue::Float(_) => BLOCK_FLOAT64,
+        FieldValue::Integer(_) => BLOCK_INTEGER,
+        FieldValue::Unsigned(_) => BLOCK_UNSIGNED,
+        FieldValue::Boolean(_) => BLOCK_BOOLEAN,
+        FieldValue::String(_) => BLOCK_STRING,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common_base::point::Point;
+
+    use crate::engine::tsm1::block::{BLOCK_FLOAT64, BLOCK_INTEGER};
+    use crate::engine::tsm1::schema::{SchemaRegistry, SchemaViolation};
+
+    #[test]
+    fn test_schema_registry_check() {
+        let registry = SchemaRegistry::new();
+        registry.declare_measurement(
+            b"cpu",
+            &[(b"usage", BLOCK_FLOAT64), (b"count", BLOCK_INTEGER)],
+            &[b"host"],
+        );
+
+        let points = Point::parse_lines_with_time(
+            "cpu,host=a usage=0.5,count=1i 10\n\
+             cpu usage=0.5 10\n\
+             mem,host=a free=1i 10\n\
+             cpu,host=a,region=west usage=0.5 10\n\
+             cpu,host=a idle=0.5 10\n\
+             cpu,host=a count=0.5 10",
+            0,
+        )
+        .unwrap();
+        let results: Vec<_> = points.iter().map(|x| registry.check(x)).collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err(SchemaViolation::UnknownMeasurement {
+                    measurement: b"mem".to_vec()
+                }),
+                Err(SchemaViolation::UnknownTagKey {
+                    measurement: b"cpu".to_vec(),
+                    tag_key: b"region".to_vec(),
+                }),
+                Err(SchemaViolation::UnknownField {
+                    measurement: b"cpu".to_vec(),
+                    field: b"idle".to_vec(),
+                }),
+                Err(SchemaViolation::FieldType {
+                    measurement: b"cpu".to_vec(),
+                    field: b"count".to_vec(),
+                    expected: BLOCK_INTEGER,
+                    actual: BLOCK_FLOAT64,
+                }),
+            ]
+        );
+    }
+}
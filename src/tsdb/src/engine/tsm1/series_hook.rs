@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common_base::point::Tags;
+use tokio::sync::mpsc;
+
+/// DEFAULT_SERIES_HOOK_QUEUE_SIZE is the default number of batches buffered for
+/// a series creation hook.
+pub const DEFAULT_SERIES_HOOK_QUEUE_SIZE: usize = 1024;
+
+/// DEFAULT_SERIES_HOOK_BUDGET is the default time a flush waits for room in the
+/// hook queue before the batch is dropped.
+pub const DEFAULT_SERIES_HOOK_BUDGET: Duration = Duration::from_millis(10);
+
+/// NewSeries describes a series, or a new field of a series, created in a shard.
+#[derive(Clone, Debug)]
+pub struct NewSeries {
+    /// key is the series key, e.g. `cpu,host=a`.
+    pub key: Vec<u8>,
+    pub measurement: Vec<u8>,
+    pub tags: Tags,
+    pub field: Vec<u8>,
+    /// field_type is the TSM block type of the field.
+    pub field_type: u8,
+}
+
+/// SeriesCreationHook is notified of the series created in a shard, e.g. to keep
+/// an external schema registry up to date.
+///
+/// The hook is called from a background task once the series are durably
+/// indexed, with one batch per update of the shard index: each flush of the
+/// cache into TSM files, and the recovery of files left unindexed by a crash.
+/// The batch is not sent per `write_points`: a write is only logged to the WAL
+/// and the cache, the series are registered in the index by the next flush, and
+/// reporting them then is what makes the hook fire once per series even when
+/// the WAL is replayed after a crash.
+///
+/// Each series is reported at most once: a batch is lost if the hook is too slow
+/// to keep up, fails, or the process stops before it is delivered.
+#[async_trait]
+pub trait SeriesCreationHook: Send + Sync {
+    async fn on_series_created(&self, batch: &[NewSeries]) -> anyhow::Result<()>;
+}
+
+/// SeriesHookStats counts the batches handed to a series creation hook.
+#[derive(Debug, Default)]
+pub struct SeriesHookStats {
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl SeriesHookStats {
+    /// delivered returns the number of batches the hook accepted.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    /// dropped returns the number of batches dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// failed returns the number of batches the hook returned an error for.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// SeriesHookDispatcher queues batches for a hook, running it off the write path.
+pub(crate) struct SeriesHookDispatcher {
+    tx: mpsc::Sender<Vec<NewSeries>>,
+    budget: Duration,
+    stats: Arc<SeriesHookStats>,
+}
+
+impl SeriesHookDispatcher {
+    /// new spawns the task calling `hook`, it stops once the dispatcher is dropped.
+    pub fn new(hook: Arc<dyn SeriesCreationHook>, queue_size: usize, budget: Duration) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Vec<NewSeries>>(queue_size.max(1));
+        let stats = Arc::new(SeriesHookStats::default());
+
+        let task_stats = stats.clone();
+        tokio::spawn(async move {
+            while let Some(batch) = rx.recv().await {
+                match hook.on_series_created(batch.as_slice()).await {
+                    Ok(_) => {
+                        task_stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        task_stats.failed.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(
+                            "series creation hook failed, {} series lost: {}",
+                            batch.len(),
+                            e
+                        );
+                    }
+                }
+            }
+        });
+
+        Self { tx, budget, stats }
+    }
+
+    pub fn stats(&self) -> &SeriesHookStats {
+        &self.stats
+    }
+
+    /// dispatch queues a batch, waiting at most the budget for room in the queue.
+    /// The batch is dropped if the queue stays full.
+    pub async fn dispatch(&self, batch: Vec<NewSeries>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let len = batch.len();
+        let queued = if self.budget.is_zero() {
+            self.tx.try_send(batch).is_ok()
+        } else {
+            self.tx.send_timeout(batch, self.budget).await.is_ok()
+        };
+        if !queued {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("series creation hook queue full, {} series dropped", len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use common_base::point::Tags;
+    use tokio::sync::Notify;
+    use tokio::time::Instant;
+
+    use crate::engine::tsm1::series_hook::{NewSeries, SeriesCreationHook, SeriesHookDispatcher};
+
+    fn new_series(key: &[u8]) -> NewSeries {
+        NewSeries {
+            key: key.to_vec(),
+            measurement: b"cpu".to_vec(),
+            tags: Tags::new(vec![]),
+            field: b"value".to_vec(),
+            field_type: 0,
+        }
+    }
+
+    /// BlockedHook never returns until released.
+    struct BlockedHook {
+        release: Notify,
+    }
+
+    #[async_trait]
+    impl SeriesCreationHook for BlockedHook {
+        async fn on_series_created(&self, _batch: &[NewSeries]) -> anyhow::Result<()> {
+            self.release.notified().await;
+            Ok(())
+        }
+    }
+
+    /// FailingHook fails every batch, signaling `called` each time.
+    #[derive(Default)]
+    struct FailingHook {
+        called: Notify,
+    }
+
+    #[async_trait]
+    impl SeriesCreationHook for FailingHook {
+        async fn on_series_created(&self, _batch: &[NewSeries]) -> anyhow::Result<()> {
+            self.called.notify_one();
+            Err(anyhow!("registry unavailable"))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_series_hook_slow() {
+        let hook = Arc::new(BlockedHook {
+            release: Notify::new(),
+        });
+        let budget = Duration::from_millis(20);
+        let dispatcher = SeriesHookDispatcher::new(hook.clone(), 1, budget);
+
+        // the first batch blocks the hook, the second fills the queue
+        for i in 0..5u8 {
+            let start = Instant::now();
+            dispatcher.dispatch(vec![new_series(&[i])]).await;
+            assert!(start.elapsed() <= budget);
+        }
+        assert_eq!(dispatcher.stats().dropped(), 3);
+        assert_eq!(dispatcher.stats().delivered(), 0);
+
+        hook.release.notify_waiters();
+    }
+
+    #[tokio::test]
+    async fn test_series_hook_failed() {
+        let hook = Arc::new(FailingHook::default());
+        let dispatcher = SeriesHookDispatcher::new(hook.clone(), 4, Duration::from_millis(20));
+        dispatcher.dispatch(vec![new_series(b"cpu,host=a")]).await;
+        dispatcher.dispatch(vec![]).await;
+
+        // the failure is counted before the hook task waits for the next batch
+        hook.called.notified().await;
+        tokio::task::yield_now().await;
+        assert_eq!(dispatcher.stats().failed(), 1);
+        assert_eq!(dispatcher.stats().dropped(), 0);
+    }
+}
@@ -0,0 +1,664 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use influxdb_storage::opendal::{ErrorKind, Scheme};
+use influxdb_storage::{path_join, StorageOperator};
+use tokio::task::JoinHandle;
+
+/// LOCK_FILE is the lock file within the shard directory.
+pub const LOCK_FILE: &str = "LOCK";
+
+/// WRITER_LOCK_FILE is the lock file of a `ShardLock` within the shard directory.
+pub const WRITER_LOCK_FILE: &str = "WRITER";
+
+/// DEFAULT_LEASE_TTL is the time after which a lease which was not refreshed is
+/// considered stale.
+pub const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// DEFAULT_LEASE_HEARTBEAT is the interval at which the owner refreshes its lease.
+pub const DEFAULT_LEASE_HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// ShardLockError are the errors of the advisory lock of a shard.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ShardLockError {
+    /// The shard is locked by another owner: a process, or another `AdvisoryLock`
+    /// of this process.
+    #[error("shard {path} is locked by {owner}")]
+    Locked { path: String, owner: String },
+    /// The lease was taken over by another owner, a commit by the former owner
+    /// must be rejected.
+    #[error("shard lock token {token} was superseded by {current}")]
+    Fenced { token: u64, current: u64 },
+}
+
+/// ShardLockOptions configures the lease of a shard stored on a backend without
+/// file locks. Shards on the local file system use `flock`.
+#[derive(Clone, Debug)]
+pub struct ShardLockOptions {
+    /// lease_ttl is the time after which a lease which was not refreshed can be
+    /// taken over by another owner.
+    pub lease_ttl: Duration,
+    /// heartbeat is the interval at which the lease is refreshed, it must be
+    /// shorter than `lease_ttl`.
+    pub heartbeat: Duration,
+}
+
+impl Default for ShardLockOptions {
+    fn default() -> Self {
+        Self {
+            lease_ttl: DEFAULT_LEASE_TTL,
+            heartbeat: DEFAULT_LEASE_HEARTBEAT,
+        }
+    }
+}
+
+/// AdvisoryLock excludes other owners from a shard directory until it is released
+/// or dropped.
+///
+/// On the local file system the lock file is locked with `flock`, which the
+/// kernel releases with the process, so a second opener fails immediately. Other
+/// backends hold a lease: the lock file records an owner, a heartbeat timestamp
+/// and a fencing token incremented by each owner. A lease which was not refreshed
+/// within its ttl is taken over, and the former owner, e.g. a node stalled past
+/// its ttl on a network file system, is deposed: its commits are rejected by the
+/// `Fence` check.
+pub enum AdvisoryLock {
+    Flock(FlockFile),
+    Lease(Lease),
+}
+
+impl AdvisoryLock {
+    /// acquire locks the shard directory `dir`, failing with
+    /// `ShardLockError::Locked` if it is held by another owner.
+    pub async fn acquire(
+        dir: &StorageOperator,
+        options: &ShardLockOptions,
+    ) -> anyhow::Result<Self> {
+        let op = dir.to_op(path_join(dir.path(), LOCK_FILE).as_str());
+        let info = op.operator().info();
+        if matches!(info.scheme(), Scheme::Fs) {
+            let path = PathBuf::from(info.root()).join(op.path().trim_start_matches('/'));
+            Ok(Self::Flock(FlockFile::acquire(path)?))
+        } else {
+            Ok(Self::Lease(Lease::acquire(op, options).await?))
+        }
+    }
+
+    /// fence returns the fence to check before a commit, None if the lock can not
+    /// be taken over while held.
+    pub fn fence(&self) -> Option<Fence> {
+        match self {
+            Self::Flock(_) => None,
+            Self::Lease(lease) => Some(lease.fence.clone()),
+        }
+    }
+
+    /// release unlocks the shard directory.
+    pub async fn release(self) -> anyhow::Result<()> {
+        match self {
+            Self::Flock(file) => {
+                drop(file);
+                Ok(())
+            }
+            Self::Lease(lease) => lease.release().await,
+        }
+    }
+}
+
+/// FlockFile is an open lock file holding an exclusive `flock`, released when it
+/// is closed.
+pub struct FlockFile {
+    _file: std::fs::File,
+}
+
+impl FlockFile {
+    fn acquire(path: PathBuf) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_path())?;
+
+        if !sys::try_lock_exclusive(&file)? {
+            return Err(ShardLockError::Locked {
+                path: path.to_string_lossy().to_string(),
+                owner: "flock".to_string(),
+            }
+            .into());
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::unix::io::AsRawFd;
+
+    /// try_lock_exclusive returns false if the file is locked by another open file
+    /// description, which may belong to this process.
+    pub fn try_lock_exclusive(file: &std::fs::File) -> std::io::Result<bool> {
+        let r = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if r == 0 {
+            return Ok(true);
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(false),
+            _ => Err(err),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub fn try_lock_exclusive(_file: &std::fs::File) -> std::io::Result<bool> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "flock is not supported on this platform",
+        ))
+    }
+}
+
+/// LeaseRecord is the content of the lock file of a lease.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LeaseRecord {
+    token: u64,
+    owner: String,
+    heartbeat_ms: u64,
+}
+
+impl LeaseRecord {
+    fn encode(&self) -> Vec<u8> {
+        format!("{} {} {}\n", self.token, self.owner, self.heartbeat_ms).into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let s = std::str::from_utf8(data)?;
+        let mut fields = s.split_whitespace();
+        let mut next = || {
+            fields
+                .next()
+                .ok_or_else(|| anyhow!("lock file truncated: {:?}", s))
+        };
+        Ok(Self {
+            token: next()?.parse()?,
+            owner: next()?.to_string(),
+            heartbeat_ms: next()?.parse()?,
+        })
+    }
+
+    /// read returns the record of the lock file, None if there is none.
+    async fn read(op: &StorageOperator) -> anyhow::Result<Option<Self>> {
+        match op.operator().read(op.path()).await {
+            Ok(data) => Ok(Some(Self::decode(data.as_slice())?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Fence identifies an owner of a lease by its fencing token. Commits check it
+/// right before they are made, so the writes of a deposed owner are rejected.
+#[derive(Debug, Clone)]
+pub struct Fence {
+    op: StorageOperator,
+    token: u64,
+    owner: String,
+    deposed: Arc<AtomicBool>,
+}
+
+impl Fence {
+    /// token returns the fencing token of the owner.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// check fails with `ShardLockError::Fenced` if the lease is no longer held
+    /// by the owner.
+    pub async fn check(&self) -> anyhow::Result<()> {
+        let current = match LeaseRecord::read(&self.op).await? {
+            Some(record) if record.token == self.token && record.owner == self.owner => {
+                if !self.deposed.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                record.token
+            }
+            Some(record) => record.token,
+            None => 0,
+        };
+
+        self.deposed.store(true, Ordering::Release);
+        Err(ShardLockError::Fenced {
+            token: self.token,
+            current,
+        }
+        .into())
+    }
+}
+
+/// Lease is a lock held by refreshing the lock file, see `AdvisoryLock`.
+pub struct Lease {
+    fence: Fence,
+    heartbeat: JoinHandle<()>,
+}
+
+impl Lease {
+    async fn acquire(op: StorageOperator, options: &ShardLockOptions) -> anyhow::Result<Self> {
+        if options.heartbeat >= options.lease_ttl {
+            return Err(anyhow!(
+                "lease heartbeat {:?} must be shorter than its ttl {:?}",
+                options.heartbeat,
+                options.lease_ttl
+            ));
+        }
+
+        let now = now_ms();
+        let token = match LeaseRecord::read(&op).await? {
+            Some(record)
+                if now.saturating_sub(record.heartbeat_ms)
+                    < options.lease_ttl.as_millis() as u64 =>
+            {
+                return Err(ShardLockError::Locked {
+                    path: op.path().to_string(),
+                    owner: record.owner,
+                }
+                .into());
+            }
+            Some(record) => record.token + 1,
+            None => 1,
+        };
+
+        let owner = format!("{}-{}", std::process::id(), now_nanos());
+        let record = LeaseRecord {
+            token,
+            owner: owner.clone(),
+            heartbeat_ms: now,
+        };
+        op.write_atomic(record.encode()).await?;
+
+        // Two owners taking over the same stale lease both write it, the last one
+        // wins.
+        match LeaseRecord::read(&op).await? {
+            Some(current) if current == record => {}
+            current => {
+                return Err(ShardLockError::Locked {
+                    path: op.path().to_string(),
+                    owner: current.map(|x| x.owner).unwrap_or_default(),
+                }
+                .into());
+            }
+        }
+
+        let fence = Fence {
+            op,
+            token,
+            owner,
+            deposed: Arc::new(AtomicBool::new(false)),
+        };
+        let heartbeat = tokio::spawn(Self::heartbeat(fence.clone(), options.heartbeat));
+        Ok(Self { fence, heartbeat })
+    }
+
+    /// heartbeat refreshes the lease every `interval` until it is superseded.
+    async fn heartbeat(fence: Fence, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            if let Err(e) = fence.check().await {
+                tracing::warn!("shard lock {} lost: {}", fence.op.path(), e);
+                return;
+            }
+
+            let record = LeaseRecord {
+                token: fence.token,
+                owner: fence.owner.clone(),
+                heartbeat_ms: now_ms(),
+            };
+            if let Err(e) = fence.op.write_atomic(record.encode()).await {
+                tracing::warn!("shard lock {} heartbeat failed: {}", fence.op.path(), e);
+            }
+        }
+    }
+
+    /// release stops the heartbeat and expires the lease if it is still held. The
+    /// lock file is kept, so the next owner continues the token sequence.
+    async fn release(self) -> anyhow::Result<()> {
+        self.heartbeat.abort();
+        if self.fence.check().await.is_ok() {
+            let record = LeaseRecord {
+                token: self.fence.token,
+                owner: self.fence.owner.clone(),
+                heartbeat_ms: 0,
+            };
+            self.fence.op.write_atomic(record.encode()).await?;
+        }
+        self.fence.deposed.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+/// ShardLock is a lock file created through the operator of a shard directory,
+/// so it works on any backend. It records its owner and the time it was taken or
+/// last refreshed: a lock which was not refreshed within `stale_after` is stale,
+/// e.g. left behind by a crashed process, and is taken over. The lock file is
+/// removed when the lock is released or dropped.
+///
+/// Unlike `AdvisoryLock` it holds no `flock` and no fencing token, an owner
+/// holding the lock longer than `stale_after` must call `refresh`.
+pub struct ShardLock {
+    op: StorageOperator,
+    owner: String,
+    released: bool,
+}
+
+impl ShardLock {
+    /// acquire creates the lock file of the shard directory `dir`, failing with
+    /// `ShardLockError::Locked` if it exists and is not stale.
+    pub async fn acquire(dir: &StorageOperator, stale_after: Duration) -> anyhow::Result<Self> {
+        let op = dir.to_op(path_join(dir.path(), WRITER_LOCK_FILE).as_str());
+
+        let now = now_ms();
+        let token = match LeaseRecord::read(&op).await? {
+            Some(record)
+                if now.saturating_sub(record.heartbeat_ms) < stale_after.as_millis() as u64 =>
+            {
+                return Err(ShardLockError::Locked {
+                    path: op.path().to_string(),
+                    owner: record.owner,
+                }
+                .into());
+            }
+            Some(record) => {
+                tracing::warn!(
+                    "taking over stale shard lock {} of {}",
+                    op.path(),
+                    record.owner
+                );
+                record.token + 1
+            }
+            None => 1,
+        };
+
+        let owner = format!("{}-{}", std::process::id(), now_nanos());
+        let record = LeaseRecord {
+            token,
+            owner: owner.clone(),
+            heartbeat_ms: now,
+        };
+        op.write_atomic(record.encode()).await?;
+
+        // The operator has no create-if-absent, of two owners creating the lock
+        // file at once the last write wins.
+        match LeaseRecord::read(&op).await? {
+            Some(current) if current == record => Ok(Self {
+                op,
+                owner,
+                released: false,
+            }),
+            current => Err(ShardLockError::Locked {
+                path: op.path().to_string(),
+                owner: current.map(|x| x.owner).unwrap_or_default(),
+            }
+            .into()),
+        }
+    }
+
+    /// owner returns the owner recorded in the lock file.
+    pub fn owner(&self) -> &str {
+        self.owner.as_str()
+    }
+
+    /// refresh updates the timestamp of the lock file, failing with
+    /// `ShardLockError::Locked` if the lock was taken over as stale.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let record = self.check().await?;
+        let record = LeaseRecord {
+            heartbeat_ms: now_ms(),
+            ..record
+        };
+        self.op.write_atomic(record.encode()).await?;
+        Ok(())
+    }
+
+    /// release removes the lock file if it is still held by the owner.
+    pub async fn release(mut self) -> anyhow::Result<()> {
+        self.released = true;
+        match self.check().await {
+            Ok(_) => {
+                self.op.delete().await?;
+                Ok(())
+            }
+            Err(e) if e.downcast_ref::<ShardLockError>().is_some() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// check returns the record of the lock file if it is held by the owner.
+    async fn check(&self) -> anyhow::Result<LeaseRecord> {
+        match LeaseRecord::read(&self.op).await? {
+            Some(record) if record.owner == self.owner => Ok(record),
+            record => Err(ShardLockError::Locked {
+                path: self.op.path().to_string(),
+                owner: record.map(|x| x.owner).unwrap_or_default(),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Drop for ShardLock {
+    /// drop removes the lock file, synchronously if the backend supports it so
+    /// the shard can be locked again right away, otherwise from a task.
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        let operator = self.op.operator();
+        let path = self.op.path().to_string();
+        let owner = self.owner.clone();
+        if operator.info().capability().blocking {
+            let operator = operator.blocking();
+            let held = match operator.read(path.as_str()) {
+                Ok(data) => LeaseRecord::decode(data.as_slice())
+                    .map(|x| x.owner == owner)
+                    .unwrap_or(false),
+                Err(_) => false,
+            };
+            if held {
+                if let Err(e) = operator.delete(path.as_str()) {
+                    tracing::warn!("release shard lock {} failed: {}", path, e);
+                }
+            }
+        } else if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let lock = ShardLock {
+                op: self.op.clone(),
+                owner,
+                released: false,
+            };
+            handle.spawn(async move {
+                if let Err(e) = lock.release().await {
+                    tracing::warn!("release shard lock {} failed: {}", path, e);
+                }
+            });
+        } else {
+            tracing::warn!("shard lock {} not released, no runtime", path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use influxdb_storage::opendal::services::Memory;
+    use influxdb_storage::opendal::Operator;
+    use influxdb_storage::StorageOperator;
+
+    use crate::engine::tsm1::file_store::file_store::FileStore;
+    use crate::engine::tsm1::shard_lock::{
+        AdvisoryLock, ShardLock, ShardLockError, ShardLockOptions, WRITER_LOCK_FILE,
+    };
+
+    fn locked(r: &anyhow::Result<AdvisoryLock>) -> bool {
+        matches!(
+            r.as_ref()
+                .err()
+                .and_then(|e| e.downcast_ref::<ShardLockError>()),
+            Some(ShardLockError::Locked { .. })
+        )
+    }
+
+    #[tokio::test]
+    async fn test_flock_excludes_second_opener() {
+        let dir = tempfile::tempdir().unwrap();
+        let op = StorageOperator::root(dir.path().to_str().unwrap()).unwrap();
+        let options = ShardLockOptions::default();
+
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let op = op.clone();
+                let options = options.clone();
+                tokio::spawn(async move { AdvisoryLock::acquire(&op, &options).await })
+            })
+            .collect();
+        let mut results = vec![];
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+
+        assert_eq!(results.iter().filter(|x| x.is_ok()).count(), 1);
+        assert_eq!(results.iter().filter(|x| locked(x)).count(), 1);
+
+        let lock = results.into_iter().find_map(|x| x.ok()).unwrap();
+        assert!(matches!(lock, AdvisoryLock::Flock(_)));
+        assert!(lock.fence().is_none());
+
+        // released on close
+        lock.release().await.unwrap();
+        let lock = AdvisoryLock::acquire(&op, &options).await.unwrap();
+        assert!(locked(&AdvisoryLock::acquire(&op, &options).await));
+        drop(lock);
+        AdvisoryLock::acquire(&op, &options).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lease_fences_deposed_owner() {
+        let op = StorageOperator::new(Operator::new(Memory::default()).unwrap().finish(), "shard/");
+        let options = ShardLockOptions {
+            lease_ttl: Duration::from_millis(200),
+            heartbeat: Duration::from_millis(20),
+        };
+
+        // a refreshed lease is not taken over
+        let a = AdvisoryLock::acquire(&op, &options).await.unwrap();
+        let fence_a = a.fence().unwrap();
+        assert_eq!(fence_a.token(), 1);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(locked(&AdvisoryLock::acquire(&op, &options).await));
+
+        let store_a = FileStore::open(op.clone())
+            .await
+            .unwrap()
+            .with_fence(Some(fence_a.clone()));
+        store_a.replace(&[], &[]).await.unwrap();
+
+        // the owner stalls past its ttl, without a heartbeat, and is deposed
+        drop(a);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let b = AdvisoryLock::acquire(&op, &options).await.unwrap();
+        let fence_b = b.fence().unwrap();
+        assert_eq!(fence_b.token(), 2);
+
+        let err = store_a.replace(&[], &[]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ShardLockError>(),
+            Some(&ShardLockError::Fenced {
+                token: 1,
+                current: 2
+            })
+        );
+        fence_b.check().await.unwrap();
+
+        // released on close
+        b.release().await.unwrap();
+        let c = AdvisoryLock::acquire(&op, &options).await.unwrap();
+        assert_eq!(c.fence().unwrap().token(), 3);
+        c.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shard_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/", dir.path().to_str().unwrap());
+        let op = StorageOperator::root(path.as_str()).unwrap();
+        let stale_after = Duration::from_secs(30);
+        let lock_file = dir.path().join(WRITER_LOCK_FILE);
+
+        let lock = ShardLock::acquire(&op, stale_after).await.unwrap();
+        assert!(lock_file.exists());
+        let err = ShardLock::acquire(&op, stale_after).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<ShardLockError>(),
+            Some(&ShardLockError::Locked {
+                path: lock_file.to_str().unwrap().to_string(),
+                owner: lock.owner().to_string(),
+            })
+        );
+
+        // released on drop
+        drop(lock);
+        assert!(!lock_file.exists());
+        let lock = ShardLock::acquire(&op, stale_after).await.unwrap();
+        lock.refresh().await.unwrap();
+        lock.release().await.unwrap();
+        assert!(!lock_file.exists());
+
+        // a lock which was not refreshed is stale and taken over, its former owner
+        // does not remove the lock of the new one
+        let stale = ShardLock::acquire(&op, stale_after).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let lock = ShardLock::acquire(&op, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(stale.refresh().await.is_err());
+        drop(stale);
+        assert!(lock_file.exists());
+        lock.refresh().await.unwrap();
+        drop(lock);
+        assert!(!lock_file.exists());
+
+        let op = StorageOperator::new(Operator::new(Memory::default()).unwrap().finish(), "shard/");
+        let lock = ShardLock::acquire(&op, stale_after).await.unwrap();
+        assert!(ShardLock::acquire(&op, stale_after).await.is_err());
+        drop(lock);
+        ShardLock::acquire(&op, stale_after).await.unwrap();
+    }
+}
@@ -1,5 +1,11 @@
+pub mod point_values;
+pub mod sum;
+pub mod summary;
 pub mod value;
 pub mod values;
 
+pub use point_values::*;
+pub use sum::*;
+pub use summary::*;
 pub use value::*;
 pub use values::*;
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+
+use common_base::point::{
+    check_key_length, series_key, tsm_key, FieldValue, Point, DEFAULT_MAX_SERIES_KEY_LENGTH,
+    MAX_KEY_LENGTH,
+};
+
+use crate::engine::tsm1::value::{Array, TimeValue, Values};
+
+/// FieldTypeConflict is a field written with values of different types, see
+/// `points_to_values`.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error(
+    "field type conflict on {}: block type {actual} written to block type {expected}",
+    String::from_utf8_lossy(key)
+)]
+pub struct FieldTypeConflict {
+    /// key is the TSM key of the field.
+    pub key: Vec<u8>,
+    /// expected is the block type of the first value of the field.
+    pub expected: u8,
+    /// actual is the block type of the conflicting value.
+    pub actual: u8,
+}
+
+/// points_to_values fans the fields of points out into the values of their TSM
+/// key `measurement,tags#!~#field`, as taken by `Engine::write_values`. The values
+/// of each key are sorted, the last point winning for the same timestamp. A field
+/// written with values of different types is a `FieldTypeConflict`.
+///
+/// Series keys longer than `DEFAULT_MAX_SERIES_KEY_LENGTH` are rejected, see
+/// `points_to_values_with_max_key_length`.
+pub fn points_to_values(points: &[Point]) -> anyhow::Result<BTreeMap<Vec<u8>, Values>> {
+    points_to_values_with_max_key_length(points, DEFAULT_MAX_SERIES_KEY_LENGTH)
+}
+
+/// points_to_values_with_max_key_length is `points_to_values` rejecting the
+/// points with a series key longer than max_series_key_length, or a TSM key
+/// longer than `MAX_KEY_LENGTH`, with a `KeyTooLong`. Nothing is returned if any
+/// point is rejected.
+pub fn points_to_values_with_max_key_length(
+    points: &[Point],
+    max_series_key_length: usize,
+) -> anyhow::Result<BTreeMap<Vec<u8>, Values>> {
+    let mut values: BTreeMap<Vec<u8>, Values> = BTreeMap::new();
+    for point in points {
+        let series_key = series_key(point.name.as_slice(), &point.tags);
+        check_key_length(series_key.as_slice(), max_series_key_length)?;
+        for field in point.fields.iter() {
+            let key = tsm_key(series_key.as_slice(), field.key.as_slice());
+            check_key_length(key.as_slice(), MAX_KEY_LENGTH)?;
+            let value = field_values(point.time, &field.value);
+            match values.get_mut(&key) {
+                Some(existing) => push_value(key, existing, value)?,
+                None => {
+                    values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    for values in values.values_mut() {
+        values.deduplicate();
+    }
+    Ok(values)
+}
+
+/// field_values returns the single value of a field at time.
+fn field_values(time: i64, value: &FieldValue) -> Values {
+    match value {
+        FieldValue::Float(v) => Values::Float(vec![TimeValue::new(time, *v)]),
+        FieldValue::Integer(v) => Values::Integer(vec![TimeValue::new(time, *v)]),
+        FieldValue::Unsigned(v) => Values::Unsigned(vec![TimeValue::new(time, *v)]),
+        FieldValue::Boolean(v) => Values::Bool(vec![TimeValue::new(time, *v)]),
+        FieldValue::String(v) => Values::String(vec![TimeValue::new(time, v.as_bytes().to_vec())]),
+    }
+}
+
+/// push_value appends the single value of value to the values of key.
+fn push_value(key: Vec<u8>, values: &mut Values, value: Values) -> anyhow::Result<()> {
+    match (values, value) {
+        (Values::Float(dst), Values::Float(src)) => dst.extend(src),
+        (Values::Integer(dst), Values::Integer(src)) => dst.extend(src),
+        (Values::Unsigned(dst), Values::Unsigned(src)) => dst.extend(src),
+        (Values::Bool(dst), Values::Bool(src)) => dst.extend(src),
+        (Values::String(dst), Values::String(src)) => dst.extend(src),
+        (values, value) => {
+            return Err(FieldTypeConflict {
+                key,
+                expected: values.block_type(),
+                actual: value.block_type(),
+            }
+            .into())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common_base::point::Point;
+
+    use crate::engine::tsm1::block::{BLOCK_FLOAT64, BLOCK_INTEGER};
+    use crate::engine::tsm1::value::{points_to_values, FieldTypeConflict, TimeValue, Values};
+
+    #[test]
+    fn test_points_to_values() {
+        let points = Point::parse_lines_with_time(
+            "cpu,region=west,host=a usage=0.5,count=3i,up=true 20\n\
+             cpu,host=a,region=west usage=0.7,count=4i 10\n\
+             mem,host=a free=10u,msg=\"low\" 10\n\
+             cpu,host=b usage=0.1 10\n\
+             cpu,host=a,region=west usage=0.9 20",
+            0,
+        )
+        .unwrap();
+        let values = points_to_values(points.as_slice()).unwrap();
+
+        let keys: Vec<&[u8]> = values.keys().map(|x| x.as_slice()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"cpu,host=a,region=west#!~#count".as_slice(),
+                b"cpu,host=a,region=west#!~#up",
+                b"cpu,host=a,region=west#!~#usage",
+                b"cpu,host=b#!~#usage",
+                b"mem,host=a#!~#free",
+                b"mem,host=a#!~#msg",
+            ]
+        );
+        assert_eq!(
+            values[b"cpu,host=a,region=west#!~#usage".as_slice()],
+            Values::Float(vec![TimeValue::new(10, 0.7), TimeValue::new(20, 0.9)])
+        );
+        assert_eq!(
+            values[b"cpu,host=a,region=west#!~#count".as_slice()],
+            Values::Integer(vec![TimeValue::new(10, 4), TimeValue::new(20, 3)])
+        );
+        assert_eq!(
+            values[b"cpu,host=a,region=west#!~#up".as_slice()],
+            Values::Bool(vec![TimeValue::new(20, true)])
+        );
+        assert_eq!(
+            values[b"mem,host=a#!~#free".as_slice()],
+            Values::Unsigned(vec![TimeValue::new(10, 10)])
+        );
+        assert_eq!(
+            values[b"mem,host=a#!~#msg".as_slice()],
+            Values::String(vec![TimeValue::new(10, b"low".to_vec())])
+        );
+
+        let points =
+            Point::parse_lines_with_time("cpu,host=a usage=1i 10\ncpu,host=a usage=0.5 20", 0)
+                .unwrap();
+        let err = points_to_values(points.as_slice()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FieldTypeConflict>(),
+            Some(&FieldTypeConflict {
+                key: b"cpu,host=a#!~#usage".to_vec(),
+                expected: BLOCK_INTEGER,
+                actual: BLOCK_FLOAT64,
+            })
+        );
+    }
+}
@@ -0,0 +1,279 @@
+/// SumMode selects how float values are summed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SumMode {
+    /// Fast adds the values to a plain f64, the rounding error grows with the
+    /// number of values.
+    Fast,
+    /// Compensated carries the rounding error of each addition along
+    /// (Kahan-Babuska-Neumaier summation), so small values added to a large sum
+    /// are not lost.
+    #[default]
+    Compensated,
+}
+
+/// SumOverflow is returned when an integer or unsigned sum does not fit its type.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("sum of {count} values overflows")]
+pub struct SumOverflow {
+    pub count: u64,
+}
+
+/// FloatSum accumulates the sum and the count of float values. Partial sums, e.g.
+/// of the blocks of a series, are combined with `merge` without losing their
+/// compensation.
+///
+/// NaN values are counted, see `count` and `nan_count`, but left out of the sum
+/// and the mean, as InfluxQL does.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FloatSum {
+    mode: SumMode,
+    sum: f64,
+    /// compensation is the rounding error lost by the additions to `sum`.
+    compensation: f64,
+    count: u64,
+    nan_count: u64,
+}
+
+impl FloatSum {
+    pub fn new(mode: SumMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, v: f64) {
+        self.count += 1;
+        if v.is_nan() {
+            self.nan_count += 1;
+            return;
+        }
+        self.add_sum(v);
+    }
+
+    pub fn add_slice(&mut self, values: &[f64]) {
+        for v in values {
+            self.add(*v);
+        }
+    }
+
+    fn add_sum(&mut self, v: f64) {
+        match self.mode {
+            SumMode::Fast => self.sum += v,
+            SumMode::Compensated => {
+                let t = self.sum + v;
+                if self.sum.abs() >= v.abs() {
+                    self.compensation += (self.sum - t) + v;
+                } else {
+                    self.compensation += (v - t) + self.sum;
+                }
+                self.sum = t;
+            }
+        }
+    }
+
+    /// merge adds the values of another partial sum.
+    pub fn merge(&mut self, other: &FloatSum) {
+        self.count += other.count;
+        self.nan_count += other.nan_count;
+        self.add_sum(other.sum);
+        match self.mode {
+            SumMode::Fast => self.sum += other.compensation,
+            SumMode::Compensated => self.compensation += other.compensation,
+        }
+    }
+
+    /// count returns the number of values added, NaN included.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// nan_count returns the number of NaN values added.
+    pub fn nan_count(&self) -> u64 {
+        self.nan_count
+    }
+
+    /// sum returns the sum of the values which are not NaN, 0 if there are none.
+    pub fn sum(&self) -> f64 {
+        self.sum + self.compensation
+    }
+
+    /// mean returns the mean of the values which are not NaN, None if there are
+    /// none.
+    pub fn mean(&self) -> Option<f64> {
+        match self.count - self.nan_count {
+            0 => None,
+            n => Some(self.sum() / n as f64),
+        }
+    }
+}
+
+/// IntegerSum accumulates the sum and the count of integer values in an i128, the
+/// sum is checked to fit an i64 when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IntegerSum {
+    sum: i128,
+    count: u64,
+}
+
+impl IntegerSum {
+    pub fn add(&mut self, v: i64) -> Result<(), SumOverflow> {
+        self.count += 1;
+        self.sum = self
+            .sum
+            .checked_add(v as i128)
+            .ok_or(SumOverflow { count: self.count })?;
+        Ok(())
+    }
+
+    pub fn add_slice(&mut self, values: &[i64]) -> Result<(), SumOverflow> {
+        values.iter().try_for_each(|v| self.add(*v))
+    }
+
+    /// merge adds the values of another partial sum.
+    pub fn merge(&mut self, other: &IntegerSum) -> Result<(), SumOverflow> {
+        self.count += other.count;
+        self.sum = self
+            .sum
+            .checked_add(other.sum)
+            .ok_or(SumOverflow { count: self.count })?;
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// sum returns the sum, or `SumOverflow` if it does not fit an i64.
+    pub fn sum(&self) -> Result<i64, SumOverflow> {
+        i64::try_from(self.sum).map_err(|_| SumOverflow { count: self.count })
+    }
+
+    /// mean returns the mean of the values, None if there are none. It is
+    /// computed from the wide sum, so it is defined even if `sum` overflows.
+    pub fn mean(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            n => Some(self.sum as f64 / n as f64),
+        }
+    }
+}
+
+/// UnsignedSum accumulates the sum and the count of unsigned values in a u128,
+/// the sum is checked to fit a u64 when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UnsignedSum {
+    sum: u128,
+    count: u64,
+}
+
+impl UnsignedSum {
+    pub fn add(&mut self, v: u64) -> Result<(), SumOverflow> {
+        self.count += 1;
+        self.sum = self
+            .sum
+            .checked_add(v as u128)
+            .ok_or(SumOverflow { count: self.count })?;
+        Ok(())
+    }
+
+    pub fn add_slice(&mut self, values: &[u64]) -> Result<(), SumOverflow> {
+        values.iter().try_for_each(|v| self.add(*v))
+    }
+
+    /// merge adds the values of another partial sum.
+    pub fn merge(&mut self, other: &UnsignedSum) -> Result<(), SumOverflow> {
+        self.count += other.count;
+        self.sum = self
+            .sum
+            .checked_add(other.sum)
+            .ok_or(SumOverflow { count: self.count })?;
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// sum returns the sum, or `SumOverflow` if it does not fit a u64.
+    pub fn sum(&self) -> Result<u64, SumOverflow> {
+        u64::try_from(self.sum).map_err(|_| SumOverflow { count: self.count })
+    }
+
+    /// mean returns the mean of the values, None if there are none.
+    pub fn mean(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            n => Some(self.sum as f64 / n as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::value::sum::{
+        FloatSum, IntegerSum, SumMode, SumOverflow, UnsignedSum,
+    };
+
+    #[test]
+    fn test_float_sum_compensated() {
+        // 1.0 is below the precision of 1e16, a plain f64 sum drops every one
+        let n = 100_000;
+        let mut values = vec![1e16];
+        values.extend(std::iter::repeat(1.0).take(n));
+
+        let mut fast = FloatSum::new(SumMode::Fast);
+        fast.add_slice(&values);
+        assert_eq!(fast.sum(), 1e16);
+
+        let mut sum = FloatSum::default();
+        sum.add_slice(&values);
+        assert_eq!(sum.sum(), 1e16 + n as f64);
+        assert_eq!(sum.count(), n as u64 + 1);
+
+        // partial sums keep their compensation when merged
+        let mut merged = FloatSum::default();
+        for chunk in values.chunks(1000) {
+            let mut partial = FloatSum::default();
+            partial.add_slice(chunk);
+            merged.merge(&partial);
+        }
+        assert_eq!(merged.sum(), 1e16 + n as f64);
+        assert_eq!(merged.mean(), Some((1e16 + n as f64) / (n as f64 + 1.0)));
+
+        // cancellation of large values
+        let mut sum = FloatSum::default();
+        sum.add_slice(&[1.0, 1e100, 1.0, -1e100]);
+        assert_eq!(sum.sum(), 2.0);
+
+        assert_eq!(FloatSum::default().mean(), None);
+    }
+
+    #[test]
+    fn test_integer_sum_overflow() {
+        let mut sum = IntegerSum::default();
+        sum.add_slice(&[i64::MAX, i64::MAX]).unwrap();
+        assert_eq!(sum.sum(), Err(SumOverflow { count: 2 }));
+        assert_eq!(sum.mean(), Some(i64::MAX as f64));
+
+        // back in range
+        sum.add_slice(&[i64::MIN, i64::MIN, 5]).unwrap();
+        assert_eq!(sum.sum(), Ok(3));
+
+        let mut sum = IntegerSum::default();
+        sum.add_slice(&[i64::MIN, -1]).unwrap();
+        assert_eq!(sum.sum(), Err(SumOverflow { count: 2 }));
+
+        let mut sum = UnsignedSum::default();
+        sum.add_slice(&[u64::MAX, 1]).unwrap();
+        assert_eq!(sum.sum(), Err(SumOverflow { count: 2 }));
+
+        let mut partial = UnsignedSum::default();
+        partial.add_slice(&[1, 2, 3]).unwrap();
+        let mut sum = UnsignedSum::default();
+        sum.merge(&partial).unwrap();
+        sum.merge(&partial).unwrap();
+        assert_eq!(sum.sum(), Ok(12));
+        assert_eq!(sum.mean(), Some(2.0));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::engine::tsm1::value::{FloatSum, IntegerSum, SumMode, SumOverflow, TimeValue};
+
+/// FloatSummary summarizes float values, e.g. of a block: their count, minimum,
+/// maximum and sum. Summaries of blocks are combined with `merge`, giving the
+/// summary of all their values.
+///
+/// NaN values are ignored by the minimum, the maximum, the sum and the mean, as
+/// InfluxQL does; `count` still counts them and `nan_count` reports how many
+/// there were. Values are compared with `f64::total_cmp`, so the minimum and the
+/// maximum do not depend on the order of the values nor of the merges, -0.0
+/// being lower than 0.0.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FloatSummary {
+    min: Option<f64>,
+    max: Option<f64>,
+    sum: FloatSum,
+}
+
+impl FloatSummary {
+    pub fn new(mode: SumMode) -> Self {
+        Self {
+            sum: FloatSum::new(mode),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, v: f64) {
+        self.sum.add(v);
+        if v.is_nan() {
+            return;
+        }
+        self.add_min_max(v, v);
+    }
+
+    /// add_values adds the values of a block.
+    pub fn add_values(&mut self, values: &[TimeValue<f64>]) {
+        for v in values {
+            self.add(v.value);
+        }
+    }
+
+    /// add_slice adds the values of a flat array, e.g. of a block decoded by
+    /// `decode_block_soa`.
+    pub fn add_slice(&mut self, values: &[f64]) {
+        self.sum.add_slice(values);
+        let min_max = values
+            .iter()
+            .filter(|v| !v.is_nan())
+            .fold(None, |acc, v| match acc {
+                None => Some((*v, *v)),
+                Some((min, max)) => Some((
+                    std::cmp::min_by(min, *v, f64::total_cmp),
+                    std::cmp::max_by(max, *v, f64::total_cmp),
+                )),
+            });
+        if let Some((min, max)) = min_max {
+            self.add_min_max(min, max);
+        }
+    }
+
+    fn add_min_max(&mut self, min: f64, max: f64) {
+        self.min = match self.min {
+            Some(x) if x.total_cmp(&min).is_le() => Some(x),
+            _ => Some(min),
+        };
+        self.max = match self.max {
+            Some(x) if x.total_cmp(&max).is_ge() => Some(x),
+            _ => Some(max),
+        };
+    }
+
+    /// merge adds the values of another summary.
+    pub fn merge(&mut self, other: &FloatSummary) {
+        self.sum.merge(&other.sum);
+        if let (Some(min), Some(max)) = (other.min, other.max) {
+            self.add_min_max(min, max);
+        }
+    }
+
+    /// count returns the number of values, NaN included.
+    pub fn count(&self) -> u64 {
+        self.sum.count()
+    }
+
+    /// nan_count returns the number of NaN values.
+    pub fn nan_count(&self) -> u64 {
+        self.sum.nan_count()
+    }
+
+    /// min returns the lowest value which is not NaN, None if there is none.
+    pub fn min(&self) -> Option<f64> {
+        self.min
+    }
+
+    /// max returns the highest value which is not NaN, None if there is none.
+    pub fn max(&self) -> Option<f64> {
+        self.max
+    }
+
+    /// sum returns the sum of the values which are not NaN, None if there are
+    /// none.
+    pub fn sum(&self) -> Option<f64> {
+        self.min.map(|_| self.sum.sum())
+    }
+
+    /// mean returns the mean of the values which are not NaN, None if there are
+    /// none.
+    pub fn mean(&self) -> Option<f64> {
+        self.sum.mean()
+    }
+}
+
+/// IntegerSummary summarizes integer values, e.g. of a block, like
+/// `FloatSummary`. The sum is accumulated by an `IntegerSum`, adding values whose
+/// sum does not fit its wide accumulator fails with `SumOverflow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IntegerSummary {
+    min: Option<i64>,
+    max: Option<i64>,
+    sum: IntegerSum,
+}
+
+impl IntegerSummary {
+    pub fn add(&mut self, v: i64) -> Result<(), SumOverflow> {
+        self.sum.add(v)?;
+        self.add_min_max(v, v);
+        Ok(())
+    }
+
+    /// add_values adds the values of a block.
+    pub fn add_values(&mut self, values: &[TimeValue<i64>]) -> Result<(), SumOverflow> {
+        values.iter().try_for_each(|v| self.add(v.value))
+    }
+
+    /// add_slice adds the values of a flat array, e.g. of a block decoded by
+    /// `decode_block_soa`.
+    pub fn add_slice(&mut self, values: &[i64]) -> Result<(), SumOverflow> {
+        self.sum.add_slice(values)?;
+        if let (Some(min), Some(max)) = (values.iter().min(), values.iter().max()) {
+            self.add_min_max(*min, *max);
+        }
+        Ok(())
+    }
+
+    fn add_min_max(&mut self, min: i64, max: i64) {
+        self.min = Some(self.min.map_or(min, |x| x.min(min)));
+        self.max = Some(self.max.map_or(max, |x| x.max(max)));
+    }
+
+    /// merge adds the values of another summary.
+    pub fn merge(&mut self, other: &IntegerSummary) -> Result<(), SumOverflow> {
+        self.sum.merge(&other.sum)?;
+        if let (Some(min), Some(max)) = (other.min, other.max) {
+            self.add_min_max(min, max);
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.sum.count()
+    }
+
+    /// min returns the lowest value, None if there is none.
+    pub fn min(&self) -> Option<i64> {
+        self.min
+    }
+
+    /// max returns the highest value, None if there is none.
+    pub fn max(&self) -> Option<i64> {
+        self.max
+    }
+
+    /// sum returns the sum of the values, None if there are none, or
+    /// `SumOverflow` if it does not fit an i64.
+    pub fn sum(&self) -> Result<Option<i64>, SumOverflow> {
+        match self.count() {
+            0 => Ok(None),
+            _ => self.sum.sum().map(Some),
+        }
+    }
+
+    /// mean returns the mean of the values, None if there are none.
+    pub fn mean(&self) -> Option<f64> {
+        self.sum.mean()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tsm1::value::summary::{FloatSummary, IntegerSummary};
+    use crate::engine::tsm1::value::{SumOverflow, TimeValue};
+
+    type Summary = (u64, u64, Option<f64>, Option<f64>, Option<f64>, Option<f64>);
+
+    fn results(s: &FloatSummary) -> Summary {
+        (
+            s.count(),
+            s.nan_count(),
+            s.min(),
+            s.max(),
+            s.sum(),
+            s.mean(),
+        )
+    }
+
+    fn block(values: &[f64]) -> Vec<TimeValue<f64>> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| TimeValue::new(i as i64, *v))
+            .collect()
+    }
+
+    #[test]
+    fn test_float_summary_nan() {
+        let nan = f64::NAN;
+        let blocks = vec![
+            // leading
+            block(&[nan, 2.0, -1.5]),
+            // trailing
+            block(&[4.0, 0.5, nan]),
+            // all NaN
+            block(&[nan, nan, nan]),
+        ];
+
+        let want: Vec<Summary> = vec![
+            (3, 1, Some(-1.5), Some(2.0), Some(0.5), Some(0.25)),
+            (3, 1, Some(0.5), Some(4.0), Some(4.5), Some(2.25)),
+            (3, 3, None, None, None, None),
+        ];
+        for (values, want) in blocks.iter().zip(want.iter()) {
+            let mut summary = FloatSummary::default();
+            summary.add_values(values);
+            assert_eq!(&results(&summary), want);
+        }
+
+        // the summaries of the blocks merged, in any order, agree exactly with
+        // the values aggregated one by one
+        let mut all = FloatSummary::default();
+        for values in blocks.iter() {
+            for v in values {
+                all.add(v.value);
+            }
+        }
+        let want = (9, 5, Some(-1.5), Some(4.0), Some(5.0), Some(1.25));
+        assert_eq!(results(&all), want);
+
+        for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0]] {
+            let mut merged = FloatSummary::default();
+            for i in order {
+                let mut summary = FloatSummary::default();
+                summary.add_values(&blocks[i]);
+                merged.merge(&summary);
+            }
+            assert_eq!(results(&merged), want);
+        }
+
+        // -0.0 is lower than 0.0 whatever the order
+        let mut a = FloatSummary::default();
+        a.add_values(&block(&[0.0, -0.0]));
+        let mut b = FloatSummary::default();
+        b.add_values(&block(&[-0.0, 0.0]));
+        assert_eq!(a.min().unwrap().to_bits(), (-0.0_f64).to_bits());
+        assert_eq!(b.min().unwrap().to_bits(), (-0.0_f64).to_bits());
+        assert_eq!(a.max().unwrap().to_bits(), 0.0_f64.to_bits());
+    }
+
+    #[test]
+    fn test_summary_add_slice() {
+        let nan = f64::NAN;
+        let floats = [nan, 2.0, -0.0, 0.0, -1.5, nan, 4.0];
+
+        // a flat array adds up to its values added one by one
+        let mut one_by_one = FloatSummary::default();
+        one_by_one.add_values(&block(&floats));
+        let mut slice = FloatSummary::default();
+        slice.add_slice(&floats);
+        assert_eq!(results(&slice), results(&one_by_one));
+        assert_eq!(
+            results(&slice),
+            (7, 2, Some(-1.5), Some(4.0), Some(4.5), Some(0.9))
+        );
+
+        let mut slice = FloatSummary::default();
+        slice.add_slice(&[nan, nan]);
+        assert_eq!(results(&slice), (2, 2, None, None, None, None));
+
+        let mut summary = IntegerSummary::default();
+        summary.add_slice(&[3, -7, 12]).unwrap();
+        summary.add_slice(&[]).unwrap();
+        assert_eq!(summary.count(), 3);
+        assert_eq!(summary.min(), Some(-7));
+        assert_eq!(summary.max(), Some(12));
+        assert_eq!(summary.sum(), Ok(Some(8)));
+        assert_eq!(IntegerSummary::default().sum(), Ok(None));
+
+        // a sum which does not fit an i64 is reported, not wrapped
+        let mut summary = IntegerSummary::default();
+        summary.add_slice(&[i64::MAX, 1]).unwrap();
+        assert_eq!(summary.sum(), Err(SumOverflow { count: 2 }));
+        assert_eq!(summary.max(), Some(i64::MAX));
+        assert_eq!(summary.mean(), Some((i64::MAX as f64 + 1.0) / 2.0));
+    }
+}
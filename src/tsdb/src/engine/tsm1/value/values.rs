@@ -2,6 +2,10 @@ use std::any::Any;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
 
+use crate::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+use crate::engine::tsm1::file_store::TimeRange;
 use crate::engine::tsm1::value::value::{TimeValue, Value};
 use crate::engine::tsm1::value::FieldType;
 
@@ -18,7 +22,6 @@ pub trait Array: Send + Sync + Debug + 'static {
     fn exclude(&mut self, min: i64, max: i64);
     fn include(&mut self, min: i64, max: i64);
     fn find_range(&self, min: i64, max: i64) -> (isize, isize);
-    // fn merge(self, b: Box<dyn Array>) -> Box<dyn Array>;
     fn decode(&mut self, block: &[u8]) -> anyhow::Result<()>;
     fn decode_v1(block: &[u8]) -> anyhow::Result<Box<dyn Array>>
     where
@@ -135,6 +138,9 @@ where
         return true;
     }
 
+    /// deduplicate sorts the values by timestamp and keeps the last value of each
+    /// timestamp. The sort must stay stable: readers append newer values after
+    /// older ones for them to win, see `FileStoreView::read`.
     fn deduplicate(&mut self) {
         if self.len() <= 1 {
             return;
@@ -162,8 +168,9 @@ where
         self.truncate(i);
     }
 
-    /// Exclude returns the subset of values not in [min, max].  The values must
-    /// be deduplicated and sorted before calling Exclude or the results are undefined.
+    /// Exclude removes the values in [min, max], both ends included, in place. The
+    /// values must be deduplicated and sorted before calling Exclude or the results
+    /// are undefined.
     fn exclude(&mut self, min: i64, max: i64) {
         let (rmin, mut rmax) = self.find_range(min, max);
         if rmin == -1 && rmax == -1 {
@@ -172,47 +179,31 @@ where
 
         // a[rmin].UnixNano() ≥ min
         // a[rmax].UnixNano() ≥ max
-
-        if rmax < self.len() as isize {
-            if self[rmax as usize].unix_nano == max {
-                rmax += 1;
-            }
-            let rest = self.len() as isize - rmax;
-            if rest > 0 {
-                let right = self[rmax as usize..].to_vec();
-                self.truncate((rmin + rest) as usize);
-                self.extend_from_slice(right.as_slice());
-
-                return;
-            }
+        if rmax < self.len() as isize && self[rmax as usize].unix_nano == max {
+            rmax += 1;
         }
 
-        self.truncate(rmin as usize);
+        self.drain(rmin as usize..rmax as usize);
     }
 
-    /// Include returns the subset values between min and max inclusive. The values must
-    /// be deduplicated and sorted before calling Exclude or the results are undefined.
+    /// Include keeps the values in [min, max], both ends included, in place. The
+    /// values must be deduplicated and sorted before calling Include or the results
+    /// are undefined.
     fn include(&mut self, min: i64, max: i64) {
         let (rmin, mut rmax) = self.find_range(min, max);
         if rmin == -1 && rmax == -1 {
+            self.clear();
             return;
         }
 
         // a[rmin].UnixNano() ≥ min
         // a[rmax].UnixNano() ≥ max
-
         if rmax < self.len() as isize && self[rmax as usize].unix_nano == max {
             rmax += 1;
         }
 
-        if rmin > -1 {
-            let b = self[rmin as usize..rmax as usize].to_vec();
-            self.clear();
-            self.extend_from_slice(b.as_slice());
-            return;
-        }
-
         self.truncate(rmax as usize);
+        self.drain(..rmin as usize);
     }
 
     /// FindRange returns the positions where min and max would be
@@ -236,59 +227,6 @@ where
         (search(self, min) as isize, search(self, max) as isize)
     }
 
-    // /// Merge overlays b to top of a.  If two values conflict with
-    // /// the same timestamp, b is used.  Both a and b must be sorted
-    // /// in ascending order.
-    // fn merge(mut self, mut b: Box<dyn Array>) -> Box<dyn Array> {
-    //     if self.len() == 0 {
-    //         return b;
-    //     }
-    //     if b.len() == 0 {
-    //         return self;
-    //     }
-    //
-    //     // Normally, both a and b should not contain duplicates.  Due to a bug in older versions, it's
-    //     // possible stored blocks might contain duplicate values.  Remove them if they exists before
-    //     // merging.
-    //     self.deduplicate();
-    //     b.deduplicate();
-    //
-    //     if self[self.len() - 1].unix_nano < b[0].unix_nano {
-    //         self.extend_from_slice(b.as_slice());
-    //         return self;
-    //     }
-    //
-    //     if b[b.len() - 1].unix_nano < self[0].unix_nano {
-    //         b.extend_from_slice(self.as_slice());
-    //         return b;
-    //     }
-    //
-    //     let mut out = Vec::with_capacity(self.len() + b.len());
-    //     let mut a = self.as_slice();
-    //     let mut b = b.as_slice();
-    //
-    //     while a.len() > 0 && b.len() > 0 {
-    //         if a[0].unix_nano < b[0].unix_nano {
-    //             out.push(a[0].clone());
-    //             a = &a[1..];
-    //         } else if b.len() > 0 && a[0].unix_nano == b[0].unix_nano {
-    //             a = &a[1..];
-    //         } else {
-    //             out.push(b[0].clone());
-    //             b = &b[1..];
-    //         }
-    //     }
-    //
-    //     if a.len() > 0 {
-    //         out.extend_from_slice(a);
-    //     }
-    //     if b.len() > 0 {
-    //         out.extend_from_slice(b);
-    //     }
-    //
-    //     out
-    // }
-
     fn decode(&mut self, block: &[u8]) -> anyhow::Result<()> {
         Value::decode(self, block)
     }
@@ -309,6 +247,26 @@ pub type BooleanValues = TypeValues<bool>;
 pub type StringValues = TypeValues<Vec<u8>>;
 pub type UnsignedValues = TypeValues<u64>;
 
+/// ValueError describes a value rejected by `Values::validate`.
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ValueError {
+    #[error("timestamp of value {index} is reserved: {unix_nano}")]
+    ReservedTimestamp { index: usize, unix_nano: i64 },
+    #[error("value {index} at {unix_nano} is not finite")]
+    NonFinite { index: usize, unix_nano: i64 },
+    #[error("value {index} at {unix_nano} is an empty string")]
+    EmptyString { index: usize, unix_nano: i64 },
+}
+
+/// ValidateOptions is the policy applied by `Values::validate_with`.
+#[derive(Debug, Clone, Default)]
+pub struct ValidateOptions {
+    /// allow_non_finite accepts NaN and infinite floats.
+    pub allow_non_finite: bool,
+    /// require_non_empty_strings rejects empty string values.
+    pub require_non_empty_strings: bool,
+}
+
 /// Values describes the various types of block data that can be held within a TSM file.
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Values {
@@ -378,6 +336,17 @@ impl Into<IntegerValues> for Values {
 // }
 
 impl Values {
+    /// block_type returns the TSM block type of the values.
+    pub fn block_type(&self) -> u8 {
+        match self {
+            Self::Float(_) => BLOCK_FLOAT64,
+            Self::Integer(_) => BLOCK_INTEGER,
+            Self::Bool(_) => BLOCK_BOOLEAN,
+            Self::String(_) => BLOCK_STRING,
+            Self::Unsigned(_) => BLOCK_UNSIGNED,
+        }
+    }
+
     pub fn len(&self) -> usize {
         match self {
             Self::Float(values) => values.len(),
@@ -387,6 +356,108 @@ impl Values {
             Self::Unsigned(values) => values.len(),
         }
     }
+
+    /// validate checks the values with the default policy: floats must be finite
+    /// and no timestamp may be `i64::MIN`, which is reserved.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.validate_with(&ValidateOptions::default())
+    }
+
+    /// validate_with checks the values against the policy, returning a `ValueError`
+    /// for the first value rejected.
+    pub fn validate_with(&self, options: &ValidateOptions) -> anyhow::Result<()> {
+        let reserved = match self {
+            Self::Float(values) => reserved_timestamp(values),
+            Self::Integer(values) => reserved_timestamp(values),
+            Self::Bool(values) => reserved_timestamp(values),
+            Self::String(values) => reserved_timestamp(values),
+            Self::Unsigned(values) => reserved_timestamp(values),
+        };
+        if let Some(index) = reserved {
+            return Err(ValueError::ReservedTimestamp {
+                index,
+                unix_nano: i64::MIN,
+            }
+            .into());
+        }
+
+        match self {
+            Self::Float(values) if !options.allow_non_finite => {
+                if let Some(index) = values.iter().position(|v| !v.value.is_finite()) {
+                    return Err(ValueError::NonFinite {
+                        index,
+                        unix_nano: values[index].unix_nano,
+                    }
+                    .into());
+                }
+            }
+            Self::String(values) if options.require_non_empty_strings => {
+                if let Some(index) = values.iter().position(|v| v.value.is_empty()) {
+                    return Err(ValueError::EmptyString {
+                        index,
+                        unix_nano: values[index].unix_nano,
+                    }
+                    .into());
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// merge overlays other on top of self, the values of other winning for the
+    /// same timestamp. Both sides are deduplicated first, the result is sorted and
+    /// holds a single value per timestamp. Values of different types can not be
+    /// merged.
+    pub fn merge(self, other: Values) -> anyhow::Result<Values> {
+        match (self, other) {
+            (Self::Float(a), Self::Float(b)) => Ok(Self::Float(merge_values(a, b))),
+            (Self::Integer(a), Self::Integer(b)) => Ok(Self::Integer(merge_values(a, b))),
+            (Self::Bool(a), Self::Bool(b)) => Ok(Self::Bool(merge_values(a, b))),
+            (Self::String(a), Self::String(b)) => Ok(Self::String(merge_values(a, b))),
+            (Self::Unsigned(a), Self::Unsigned(b)) => Ok(Self::Unsigned(merge_values(a, b))),
+            (a, b) => Err(anyhow!(
+                "can not merge values of block type {} into block type {}",
+                b.block_type(),
+                a.block_type()
+            )),
+        }
+    }
+
+    /// clamp_time returns the values within range, both ends included. The values
+    /// must be sorted by time, the range is found by binary search.
+    pub fn clamp_time(&self, range: TimeRange) -> Values {
+        match self {
+            Self::Float(values) => Self::Float(clamp_values(values, &range)),
+            Self::Integer(values) => Self::Integer(clamp_values(values, &range)),
+            Self::Bool(values) => Self::Bool(clamp_values(values, &range)),
+            Self::String(values) => Self::String(clamp_values(values, &range)),
+            Self::Unsigned(values) => Self::Unsigned(clamp_values(values, &range)),
+        }
+    }
+
+    /// timestamps returns the timestamps of the values, in their order.
+    pub fn timestamps(&self) -> Vec<i64> {
+        match self {
+            Self::Float(values) => values.iter().map(|x| x.unix_nano).collect(),
+            Self::Integer(values) => values.iter().map(|x| x.unix_nano).collect(),
+            Self::Bool(values) => values.iter().map(|x| x.unix_nano).collect(),
+            Self::String(values) => values.iter().map(|x| x.unix_nano).collect(),
+            Self::Unsigned(values) => values.iter().map(|x| x.unix_nano).collect(),
+        }
+    }
+
+    /// truncate keeps the first len values.
+    pub fn truncate(&mut self, len: usize) {
+        match self {
+            Self::Float(values) => values.truncate(len),
+            Self::Integer(values) => values.truncate(len),
+            Self::Bool(values) => values.truncate(len),
+            Self::String(values) => values.truncate(len),
+            Self::Unsigned(values) => values.truncate(len),
+        }
+    }
 }
 
 impl Array for Values {
@@ -486,46 +557,6 @@ impl Array for Values {
         }
     }
 
-    // fn merge(self, b: Self) -> Self {
-    //     match self {
-    //         Self::Float(values) => {
-    //             if let Self::Float(values_b) = b {
-    //                 Self::Float(values.merge(values_b))
-    //             } else {
-    //                 panic!("expect Float values")
-    //             }
-    //         }
-    //         Self::Integer(values) => {
-    //             if let Self::Integer(values_b) = b {
-    //                 Self::Integer(values.merge(values_b))
-    //             } else {
-    //                 panic!("expect Float values")
-    //             }
-    //         }
-    //         Self::Bool(values) => {
-    //             if let Self::Bool(values_b) = b {
-    //                 Self::Bool(values.merge(values_b))
-    //             } else {
-    //                 panic!("expect Float values")
-    //             }
-    //         }
-    //         Self::String(values) => {
-    //             if let Self::String(values_b) = b {
-    //                 Self::String(values.merge(values_b))
-    //             } else {
-    //                 panic!("expect Float values")
-    //             }
-    //         }
-    //         Self::Unsigned(values) => {
-    //             if let Self::Unsigned(values_b) = b {
-    //                 Self::Unsigned(values.merge(values_b))
-    //             } else {
-    //                 panic!("expect Float values")
-    //             }
-    //         }
-    //     }
-    // }
-
     fn decode(&mut self, block: &[u8]) -> anyhow::Result<()> {
         match self {
             Self::Float(values) => values.decode(block),
@@ -541,6 +572,74 @@ impl Array for Values {
     }
 }
 
+/// reserved_timestamp returns the position of the first value at `i64::MIN`.
+fn reserved_timestamp<T>(values: &[TimeValue<T>]) -> Option<usize>
+where
+    T: FieldType,
+{
+    values.iter().position(|v| v.unix_nano == i64::MIN)
+}
+
+/// merge_values overlays b on top of a, see `Values::merge`. A side is returned
+/// as is when the other one is empty, or appended to when they do not overlap.
+fn merge_values<T>(mut a: TypeValues<T>, mut b: TypeValues<T>) -> TypeValues<T>
+where
+    T: FieldType + 'static,
+    TimeValue<T>: Value,
+{
+    // stored blocks may hold duplicates, written by older versions
+    a.deduplicate();
+    b.deduplicate();
+
+    if a.is_empty() {
+        return b;
+    }
+    if b.is_empty() {
+        return a;
+    }
+    if a[a.len() - 1].unix_nano < b[0].unix_nano {
+        a.extend(b);
+        return a;
+    }
+    if b[b.len() - 1].unix_nano < a[0].unix_nano {
+        b.extend(a);
+        return b;
+    }
+
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+    while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+        if x.unix_nano < y.unix_nano {
+            out.extend(a.next());
+        } else {
+            if x.unix_nano == y.unix_nano {
+                a.next();
+            }
+            out.extend(b.next());
+        }
+    }
+    out.extend(a);
+    out.extend(b);
+    out
+}
+
+/// clamp_values returns a copy of the sorted values within range.
+fn clamp_values<T>(values: &[TimeValue<T>], range: &TimeRange) -> TypeValues<T>
+where
+    T: FieldType + Clone,
+{
+    if range.min > range.max {
+        return vec![];
+    }
+    let start = search(values, range.min);
+    let end = match range.max.checked_add(1) {
+        Some(max) => search(values, max),
+        None => values.len(),
+    };
+    values[start..end.max(start)].to_vec()
+}
+
 /// search performs a binary search for UnixNano() v in a
 /// and returns the position, i, where v would be inserted.
 /// An additional check of a[i].UnixNano() == v is necessary
@@ -566,3 +665,201 @@ where
     // lo == hi
     lo
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::engine::tsm1::file_store::TimeRange;
+    use crate::engine::tsm1::value::{Array, TimeValue, ValidateOptions, ValueError, Values};
+
+    /// random_values returns up to 50 values in random order with duplicate
+    /// timestamps, the value of each one being its tag plus its position.
+    fn random_values(rng: &mut StdRng, tag: i64) -> Vec<TimeValue<i64>> {
+        let n = rng.gen_range(0..50);
+        (0..n)
+            .map(|i| TimeValue::new(rng.gen_range(0..40), tag + i))
+            .collect()
+    }
+
+    #[test]
+    fn test_values_validate() {
+        let values = Values::Float(vec![TimeValue::new(1, 1.0), TimeValue::new(2, -1.0)]);
+        values.validate().unwrap();
+        Values::Integer(vec![TimeValue::new(i64::MIN + 1, 0)])
+            .validate()
+            .unwrap();
+        Values::String(vec![TimeValue::new(1, vec![])])
+            .validate()
+            .unwrap();
+
+        let values = Values::Float(vec![TimeValue::new(1, 1.0), TimeValue::new(2, f64::NAN)]);
+        let err = values.validate().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ValueError>(),
+            Some(&ValueError::NonFinite {
+                index: 1,
+                unix_nano: 2
+            })
+        );
+        let options = ValidateOptions {
+            allow_non_finite: true,
+            ..Default::default()
+        };
+        values.validate_with(&options).unwrap();
+        Values::Float(vec![TimeValue::new(1, f64::INFINITY)])
+            .validate()
+            .unwrap_err();
+
+        let values = Values::Integer(vec![TimeValue::new(i64::MIN, 1)]);
+        let err = values.validate().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ValueError>(),
+            Some(&ValueError::ReservedTimestamp {
+                index: 0,
+                unix_nano: i64::MIN
+            })
+        );
+
+        let values = Values::String(vec![
+            TimeValue::new(1, b"a".to_vec()),
+            TimeValue::new(3, vec![]),
+        ]);
+        let options = ValidateOptions {
+            require_non_empty_strings: true,
+            ..Default::default()
+        };
+        let err = values.validate_with(&options).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ValueError>(),
+            Some(&ValueError::EmptyString {
+                index: 1,
+                unix_nano: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_values_merge() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..500 {
+            let a = random_values(&mut rng, 0);
+            let b = random_values(&mut rng, 1000);
+
+            // the last value of a timestamp wins, b over a
+            let mut expected = BTreeMap::new();
+            for v in a.iter().chain(b.iter()) {
+                expected.insert(v.unix_nano, v.value);
+            }
+            let expected: Vec<TimeValue<i64>> = expected
+                .into_iter()
+                .map(|(t, v)| TimeValue::new(t, v))
+                .collect();
+
+            let merged = Values::Integer(a.clone())
+                .merge(Values::Integer(b.clone()))
+                .unwrap();
+            assert!(merged.ordered());
+            assert_eq!(merged, Values::Integer(expected.clone()));
+
+            let mut deduplicated = Values::Integer(a.into_iter().chain(b).collect());
+            deduplicated.deduplicate();
+            assert_eq!(deduplicated, Values::Integer(expected));
+        }
+
+        // an empty side returns the other one as is
+        let b = vec![TimeValue::new(1, 1.0), TimeValue::new(2, 2.0)];
+        let ptr = b.as_ptr();
+        match Values::Float(vec![]).merge(Values::Float(b)).unwrap() {
+            Values::Float(merged) => assert_eq!(merged.as_ptr(), ptr),
+            _ => unreachable!(),
+        }
+
+        let err = Values::Float(vec![])
+            .merge(Values::Integer(vec![TimeValue::new(1, 1)]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can not merge values of block type 1 into block type 0"
+        );
+    }
+
+    #[test]
+    fn test_values_exclude_include() {
+        let values = || Values::Integer((1..=5).map(|t| TimeValue::new(t * 10, t)).collect());
+        let times = |values: Values| match values {
+            Values::Integer(values) => values.iter().map(|x| x.unix_nano).collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+
+        let cases: [(i64, i64, &[i64], &[i64]); 8] = [
+            // bounds matching timestamps are included
+            (20, 40, &[10, 50], &[20, 30, 40]),
+            (15, 45, &[10, 50], &[20, 30, 40]),
+            (10, 50, &[], &[10, 20, 30, 40, 50]),
+            (i64::MIN, 10, &[20, 30, 40, 50], &[10]),
+            (50, i64::MAX, &[10, 20, 30, 40], &[50]),
+            // outside of the values
+            (0, 5, &[10, 20, 30, 40, 50], &[]),
+            (60, 70, &[10, 20, 30, 40, 50], &[]),
+            (21, 29, &[10, 20, 30, 40, 50], &[]),
+        ];
+        for (min, max, excluded, included) in cases {
+            let mut v = values();
+            v.exclude(min, max);
+            assert_eq!(times(v), excluded, "exclude [{}, {}]", min, max);
+
+            let mut v = values();
+            v.include(min, max);
+            assert_eq!(times(v), included, "include [{}, {}]", min, max);
+        }
+
+        let mut v = Values::Float(vec![]);
+        v.exclude(0, 10);
+        assert_eq!(v.len(), 0);
+        v.include(0, 10);
+        assert_eq!(v.len(), 0);
+    }
+
+    #[test]
+    fn test_values_clamp_time() {
+        let values = Values::Float((1..=5).map(|t| TimeValue::new(t * 10, t as f64)).collect());
+        let times = |values: Values| match values {
+            Values::Float(values) => values.iter().map(|x| x.unix_nano).collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+
+        let cases: [(i64, i64, &[i64]); 8] = [
+            // fully inside, bounds matching timestamps are included
+            (20, 40, &[20, 30, 40]),
+            (15, 45, &[20, 30, 40]),
+            // overlapping the start
+            (i64::MIN, 25, &[10, 20]),
+            // overlapping the end
+            (30, i64::MAX, &[30, 40, 50]),
+            (i64::MIN, i64::MAX, &[10, 20, 30, 40, 50]),
+            // disjoint
+            (0, 5, &[]),
+            (21, 29, &[]),
+            (40, 30, &[]),
+        ];
+        for (min, max, want) in cases {
+            let clamped = values.clamp_time(TimeRange::new(min, max));
+            assert_eq!(times(clamped), want, "clamp [{}, {}]", min, max);
+        }
+
+        let clamped =
+            Values::String(vec![TimeValue::new(1, b"a".to_vec())]).clamp_time(TimeRange::new(1, 1));
+        assert_eq!(
+            clamped,
+            Values::String(vec![TimeValue::new(1, b"a".to_vec())])
+        );
+        assert_eq!(
+            Values::Bool(vec![]).clamp_time(TimeRange::unbound()),
+            Values::Bool(vec![])
+        );
+    }
+}
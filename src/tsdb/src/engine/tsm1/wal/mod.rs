@@ -0,0 +1,986 @@
+//! The write ahead log records every write and delete before it is applied to the cache,
+//! so the cache can be rebuilt after a crash.
+//!
+//! The log is a sequence of segment files, `_00001.wal`, `_00002.wal`, ..., in a directory.
+//! Each segment holds a sequence of entries framed as:
+//!
+//! ```text
+//! ┌──────────────────────────────────────────────────────────┐
+//! │                           Entry                          │
+//! ├──────────┬───────────┬────────────────────┬──────────────┤
+//! │  Type 1B │ Length 4B │ Snappy Payload N B │  CRC32 4B    │
+//! └──────────┴───────────┴────────────────────┴──────────────┘
+//! ```
+//!
+//! The checksum covers the type, the length and the compressed payload.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut};
+use common_base::clock::{Clock, SystemClock};
+use common_base::iterator::AsyncIterator;
+use common_base::point::MAX_KEY_LENGTH;
+use futures::TryStreamExt;
+use influxdb_storage::opendal::Appender;
+use influxdb_storage::{path_join, StorageOperator};
+
+use crate::engine::tsm1::block::{
+    BLOCK_BOOLEAN, BLOCK_FLOAT64, BLOCK_INTEGER, BLOCK_STRING, BLOCK_UNSIGNED,
+};
+use crate::engine::tsm1::value::{TimeValue, Values};
+
+/// DEFAULT_SEGMENT_SIZE is the size at which a segment is closed and a new one started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 10 * 1024 * 1024;
+
+/// WAL_FILE_EXTENSION is the file extension of a segment file.
+pub const WAL_FILE_EXTENSION: &str = "wal";
+
+/// WAL_FILE_PREFIX is the prefix of a segment file name.
+pub const WAL_FILE_PREFIX: &str = "_";
+
+/// WRITE_WAL_ENTRY_TYPE indicates a write entry.
+pub const WRITE_WAL_ENTRY_TYPE: u8 = 0x01;
+
+/// DELETE_RANGE_WAL_ENTRY_TYPE indicates a delete range entry.
+pub const DELETE_RANGE_WAL_ENTRY_TYPE: u8 = 0x03;
+
+/// the size of the type and length fields preceding the payload.
+const ENTRY_HEADER_SIZE: usize = 5;
+
+/// the size of the checksum following the payload.
+const ENTRY_CHECKSUM_SIZE: usize = 4;
+
+/// WriteEntry represents a write of points of a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteEntry {
+    pub key: Vec<u8>,
+    pub values: Values,
+}
+
+impl WriteEntry {
+    pub fn new(key: Vec<u8>, values: Values) -> Self {
+        Self { key, values }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // writes are checked against the max series key length before they are logged
+        debug_assert!(
+            self.key.len() <= MAX_KEY_LENGTH,
+            "wal write of a key of {} bytes",
+            self.key.len()
+        );
+        buf.put_u32(self.key.len() as u32);
+        buf.put_slice(self.key.as_slice());
+        buf.put_u8(self.values.block_type());
+        buf.put_u32(self.values.len() as u32);
+
+        match &self.values {
+            Values::Float(values) => {
+                for v in values.iter() {
+                    buf.put_i64(v.unix_nano);
+                    buf.put_u64(v.value.to_bits());
+                }
+            }
+            Values::Integer(values) => {
+                for v in values.iter() {
+                    buf.put_i64(v.unix_nano);
+                    buf.put_i64(v.value);
+                }
+            }
+            Values::Bool(values) => {
+                for v in values.iter() {
+                    buf.put_i64(v.unix_nano);
+                    buf.put_u8(v.value as u8);
+                }
+            }
+            Values::String(values) => {
+                for v in values.iter() {
+                    buf.put_i64(v.unix_nano);
+                    buf.put_u32(v.value.len() as u32);
+                    buf.put_slice(v.value.as_slice());
+                }
+            }
+            Values::Unsigned(values) => {
+                for v in values.iter() {
+                    buf.put_i64(v.unix_nano);
+                    buf.put_u64(v.value);
+                }
+            }
+        }
+    }
+
+    fn decode(mut b: &[u8]) -> anyhow::Result<Self> {
+        check_remaining(b, 4)?;
+        let key_len = b.get_u32() as usize;
+        check_remaining(b, key_len + 5)?;
+        let key = b[..key_len].to_vec();
+        b.advance(key_len);
+
+        let typ = b.get_u8();
+        let n = b.get_u32() as usize;
+
+        // every value holds at least a timestamp and a 1 byte value
+        check_remaining(b, n.saturating_mul(9))?;
+        let values = match typ {
+            BLOCK_FLOAT64 => {
+                check_remaining(b, n * 16)?;
+                Values::Float(
+                    (0..n)
+                        .map(|_| TimeValue::new(b.get_i64(), f64::from_bits(b.get_u64())))
+                        .collect(),
+                )
+            }
+            BLOCK_INTEGER => {
+                check_remaining(b, n * 16)?;
+                Values::Integer(
+                    (0..n)
+                        .map(|_| TimeValue::new(b.get_i64(), b.get_i64()))
+                        .collect(),
+                )
+            }
+            BLOCK_BOOLEAN => Values::Bool(
+                (0..n)
+                    .map(|_| TimeValue::new(b.get_i64(), b.get_u8() == 1))
+                    .collect(),
+            ),
+            BLOCK_STRING => {
+                let mut values = Vec::with_capacity(n);
+                for _ in 0..n {
+                    check_remaining(b, 12)?;
+                    let unix_nano = b.get_i64();
+                    let len = b.get_u32() as usize;
+                    check_remaining(b, len)?;
+                    values.push(TimeValue::new(unix_nano, b[..len].to_vec()));
+                    b.advance(len);
+                }
+                Values::String(values)
+            }
+            BLOCK_UNSIGNED => {
+                check_remaining(b, n * 16)?;
+                Values::Unsigned(
+                    (0..n)
+                        .map(|_| TimeValue::new(b.get_i64(), b.get_u64()))
+                        .collect(),
+                )
+            }
+            _ => return Err(anyhow!("unsupported value type: {}", typ)),
+        };
+
+        if b.has_remaining() {
+            return Err(anyhow!("write entry has {} trailing bytes", b.remaining()));
+        }
+
+        Ok(Self::new(key, values))
+    }
+}
+
+/// DeleteRangeEntry represents the deletion of keys within a time range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteRangeEntry {
+    pub keys: Vec<Vec<u8>>,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl DeleteRangeEntry {
+    pub fn new(keys: Vec<Vec<u8>>, min: i64, max: i64) -> Self {
+        Self { keys, min, max }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_i64(self.min);
+        buf.put_i64(self.max);
+        for key in self.keys.iter() {
+            buf.put_u32(key.len() as u32);
+            buf.put_slice(key.as_slice());
+        }
+    }
+
+    fn decode(mut b: &[u8]) -> anyhow::Result<Self> {
+        check_remaining(b, 16)?;
+        let min = b.get_i64();
+        let max = b.get_i64();
+
+        let mut keys = Vec::new();
+        while b.has_remaining() {
+            check_remaining(b, 4)?;
+            let len = b.get_u32() as usize;
+            check_remaining(b, len)?;
+            keys.push(b[..len].to_vec());
+            b.advance(len);
+        }
+
+        Ok(Self::new(keys, min, max))
+    }
+}
+
+/// WalEntry is an entry of the write ahead log.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalEntry {
+    Write(WriteEntry),
+    DeleteRange(DeleteRangeEntry),
+}
+
+impl WalEntry {
+    pub fn entry_type(&self) -> u8 {
+        match self {
+            Self::Write(_) => WRITE_WAL_ENTRY_TYPE,
+            Self::DeleteRange(_) => DELETE_RANGE_WAL_ENTRY_TYPE,
+        }
+    }
+
+    /// encode appends the framed entry to `dst`.
+    pub fn encode(&self, dst: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut payload = Vec::new();
+        match self {
+            Self::Write(entry) => entry.encode(&mut payload),
+            Self::DeleteRange(entry) => entry.encode(&mut payload),
+        }
+        let compressed = snap::raw::Encoder::new().compress_vec(payload.as_slice())?;
+
+        let start = dst.len();
+        dst.put_u8(self.entry_type());
+        dst.put_u32(compressed.len() as u32);
+        dst.put_slice(compressed.as_slice());
+        let checksum = crc32fast::hash(&dst[start..]);
+        dst.put_u32(checksum);
+
+        Ok(())
+    }
+
+    /// decode decodes the framed entry at the beginning of `b`, returning the entry
+    /// and the number of bytes read.
+    pub fn decode(b: &[u8]) -> anyhow::Result<(Self, usize)> {
+        if b.len() < ENTRY_HEADER_SIZE {
+            return Err(anyhow!("wal entry header truncated"));
+        }
+        let typ = b[0];
+        let len = u32::from_be_bytes(b[1..ENTRY_HEADER_SIZE].try_into()?) as usize;
+
+        let end = ENTRY_HEADER_SIZE + len;
+        if b.len() < end + ENTRY_CHECKSUM_SIZE {
+            return Err(anyhow!("wal entry truncated"));
+        }
+        let checksum = u32::from_be_bytes(b[end..end + ENTRY_CHECKSUM_SIZE].try_into()?);
+        if crc32fast::hash(&b[..end]) != checksum {
+            return Err(anyhow!("wal entry checksum mismatch"));
+        }
+
+        let payload = snap::raw::Decoder::new().decompress_vec(&b[ENTRY_HEADER_SIZE..end])?;
+        let entry = match typ {
+            WRITE_WAL_ENTRY_TYPE => Self::Write(WriteEntry::decode(payload.as_slice())?),
+            DELETE_RANGE_WAL_ENTRY_TYPE => {
+                Self::DeleteRange(DeleteRangeEntry::decode(payload.as_slice())?)
+            }
+            _ => return Err(anyhow!("unknown wal entry type: {}", typ)),
+        };
+
+        Ok((entry, end + ENTRY_CHECKSUM_SIZE))
+    }
+}
+
+fn check_remaining(b: &[u8], n: usize) -> anyhow::Result<()> {
+    if b.len() < n {
+        return Err(anyhow!(
+            "wal entry payload truncated: got {}, exp {}",
+            b.len(),
+            n
+        ));
+    }
+    Ok(())
+}
+
+/// WalOptions configures a `Wal`.
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    /// segment_size is the size at which the current segment is closed and a new one
+    /// is started.
+    pub segment_size: u64,
+    /// sync_delay is the minimum time between two fsyncs of the current segment. Writes
+    /// within the delay are fsynced by a later write, `sync` or `close`. A zero delay
+    /// fsyncs every write.
+    pub sync_delay: Duration,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Wal appends entries to the segment files of a directory.
+pub struct Wal {
+    op: StorageOperator,
+    options: WalOptions,
+
+    segment_ids: Vec<u64>,
+    current_segment_id: u64,
+    current_segment_size: u64,
+
+    appender: Option<Appender>,
+    /// clock times the `sync_delay`.
+    clock: Arc<dyn Clock>,
+    last_sync: Instant,
+}
+
+impl Wal {
+    /// open opens the write ahead log in the directory `op`. Writes go to a new segment
+    /// following the existing ones.
+    pub async fn open(op: StorageOperator, options: WalOptions) -> anyhow::Result<Self> {
+        Self::open_with_clock(op, options, Arc::new(SystemClock)).await
+    }
+
+    /// open_with_clock opens the write ahead log like `open`, the `sync_delay` is
+    /// timed by `clock`.
+    pub async fn open_with_clock(
+        op: StorageOperator,
+        options: WalOptions,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        let op = dir_op(op);
+        op.create_dir().await?;
+
+        let segment_ids = list_segments(&op).await?;
+        let current_segment_id = segment_ids.last().copied().unwrap_or_default();
+        let mut wal = Self {
+            op,
+            options,
+            segment_ids,
+            current_segment_id,
+            current_segment_size: 0,
+            appender: None,
+            last_sync: clock.now_instant(),
+            clock,
+        };
+        wal.new_segment().await?;
+
+        Ok(wal)
+    }
+
+    /// replay returns an iterator over the entries of all segments in the directory
+    /// `op`, in the order they were written.
+    ///
+    /// A torn or corrupt entry in the last segment is what a crash in the middle of
+    /// a write leaves behind: the segment is truncated before it and the replay ends
+    /// there. The same in an earlier segment is an error.
+    pub async fn replay(op: StorageOperator) -> anyhow::Result<WalReplayIterator> {
+        let op = dir_op(op);
+        let segments: VecDeque<String> = if op.exist().await? {
+            list_segments(&op)
+                .await?
+                .into_iter()
+                .map(|id| path_join(op.path(), segment_filename(id).as_str()))
+                .collect()
+        } else {
+            VecDeque::new()
+        };
+
+        // the sizes are only used to report progress
+        let mut total_bytes = 0;
+        for path in segments.iter() {
+            total_bytes += op.to_op(path.as_str()).stat().await?.content_length();
+        }
+
+        Ok(WalReplayIterator {
+            op,
+            segments,
+            current: None,
+            data: vec![],
+            pos: 0,
+            total_bytes,
+            read_bytes: 0,
+            entries: 0,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        self.op.path()
+    }
+
+    /// current_segment_id returns the id of the segment being written.
+    pub fn current_segment_id(&self) -> u64 {
+        self.current_segment_id
+    }
+
+    /// segments returns the paths of all segments ordered by id, the current one last.
+    pub fn segments(&self) -> Vec<String> {
+        self.segment_ids
+            .iter()
+            .map(|id| self.segment_path(*id))
+            .collect()
+    }
+
+    /// closed_segments returns the paths of the segments which are no longer written.
+    pub fn closed_segments(&self) -> Vec<String> {
+        self.segment_ids
+            .iter()
+            .filter(|id| **id != self.current_segment_id)
+            .map(|id| self.segment_path(*id))
+            .collect()
+    }
+
+    /// write_values appends a write entry for the values of `key`, returning the id of the
+    /// segment it was written to.
+    pub async fn write_values(&mut self, key: Vec<u8>, values: Values) -> anyhow::Result<u64> {
+        self.write(&WalEntry::Write(WriteEntry::new(key, values)))
+            .await
+    }
+
+    /// delete_range appends a delete range entry, returning the id of the segment it was
+    /// written to.
+    pub async fn delete_range(
+        &mut self,
+        keys: Vec<Vec<u8>>,
+        min: i64,
+        max: i64,
+    ) -> anyhow::Result<u64> {
+        self.write(&WalEntry::DeleteRange(DeleteRangeEntry::new(
+            keys, min, max,
+        )))
+        .await
+    }
+
+    /// write appends the entry to the current segment, rolling over to a new segment
+    /// first if the current one is full.
+    pub async fn write(&mut self, entry: &WalEntry) -> anyhow::Result<u64> {
+        self.write_batch(std::slice::from_ref(entry)).await
+    }
+
+    /// write_batch appends the entries to the same segment with a single fsync,
+    /// rolling over to a new segment first if the current one is full.
+    pub async fn write_batch(&mut self, entries: &[WalEntry]) -> anyhow::Result<u64> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            entry.encode(&mut buf)?;
+        }
+        if buf.is_empty() {
+            return Ok(self.current_segment_id);
+        }
+
+        if self.current_segment_size > 0
+            && self.current_segment_size + buf.len() as u64 > self.options.segment_size
+        {
+            self.close_segment().await?;
+        }
+
+        if self.appender.is_none() {
+            let op = self
+                .op
+                .to_op(self.segment_path(self.current_segment_id).as_str());
+            self.appender = Some(op.appender().await?);
+        }
+
+        let len = buf.len() as u64;
+        let appender = self.appender.as_mut().unwrap();
+        appender.append(buf).await?;
+        self.current_segment_size += len;
+
+        if self.clock.now_instant() - self.last_sync >= self.options.sync_delay {
+            self.sync().await?;
+        }
+
+        Ok(self.current_segment_id)
+    }
+
+    /// sync fsyncs the writes of the current segment.
+    pub async fn sync(&mut self) -> anyhow::Result<()> {
+        // closing the appender fsyncs the file, it is reopened by the next write.
+        if let Some(mut appender) = self.appender.take() {
+            appender.close().await?;
+        }
+        self.last_sync = self.clock.now_instant();
+        Ok(())
+    }
+
+    /// close_segment syncs and closes the current segment, subsequent writes go to a new
+    /// segment.
+    pub async fn close_segment(&mut self) -> anyhow::Result<()> {
+        self.sync().await?;
+        self.new_segment().await
+    }
+
+    /// remove deletes the given segments, usually after their entries have been
+    /// snapshotted to TSM files. The current segment can not be removed.
+    pub async fn remove(&mut self, segments: &[String]) -> anyhow::Result<()> {
+        let current = self.segment_path(self.current_segment_id);
+        for segment in segments {
+            if *segment == current {
+                return Err(anyhow!("can not remove current wal segment: {}", segment));
+            }
+        }
+
+        for segment in segments {
+            self.op.to_op(segment.as_str()).delete().await?;
+
+            let filename = segment.rsplit('/').next().unwrap_or_default();
+            if let Ok(id) = parse_segment_filename(filename) {
+                self.segment_ids.retain(|x| *x != id);
+            }
+        }
+        Ok(())
+    }
+
+    /// close syncs and closes the current segment.
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        self.sync().await
+    }
+
+    async fn new_segment(&mut self) -> anyhow::Result<()> {
+        self.current_segment_id += 1;
+        self.current_segment_size = 0;
+
+        let op = self
+            .op
+            .to_op(self.segment_path(self.current_segment_id).as_str());
+        op.operator().write(op.path(), vec![]).await?;
+        self.segment_ids.push(self.current_segment_id);
+
+        Ok(())
+    }
+
+    fn segment_path(&self, id: u64) -> String {
+        path_join(self.op.path(), segment_filename(id).as_str())
+    }
+}
+
+/// dir_op returns the operator of the directory `op`, with a trailing slash.
+fn dir_op(op: StorageOperator) -> StorageOperator {
+    if op.path().ends_with('/') {
+        op
+    } else {
+        op.to_op(format!("{}/", op.path()).as_str())
+    }
+}
+
+/// list_segments returns the ids of the segments in the directory `op`, sorted.
+async fn list_segments(op: &StorageOperator) -> anyhow::Result<Vec<u64>> {
+    let mut segment_ids = Vec::new();
+    let mut lister = op.list().await?;
+    while let Some(de) = lister.try_next().await? {
+        if let Ok(id) = parse_segment_filename(de.name()) {
+            segment_ids.push(id);
+        }
+    }
+    segment_ids.sort_unstable();
+    Ok(segment_ids)
+}
+
+/// segment_filename returns the file name of the segment with the given id, e.g. `_00001.wal`.
+pub fn segment_filename(id: u64) -> String {
+    format!("{}{:05}.{}", WAL_FILE_PREFIX, id, WAL_FILE_EXTENSION)
+}
+
+/// parse_segment_filename returns the id of the segment file name.
+pub fn parse_segment_filename(filename: &str) -> anyhow::Result<u64> {
+    let id = filename
+        .strip_prefix(WAL_FILE_PREFIX)
+        .and_then(|x| x.strip_suffix(WAL_FILE_EXTENSION))
+        .and_then(|x| x.strip_suffix('.'))
+        .ok_or_else(|| anyhow!("invalid wal segment file name: {}", filename))?;
+    id.parse::<u64>().map_err(|e| anyhow!(e))
+}
+
+/// WalReplayIterator iterates the entries of the segments of a directory, see
+/// `Wal::replay`.
+pub struct WalReplayIterator {
+    op: StorageOperator,
+    /// the paths of the segments not read yet.
+    segments: VecDeque<String>,
+
+    /// the segment being read, its content and the position of the next entry.
+    current: Option<String>,
+    data: Vec<u8>,
+    pos: usize,
+
+    /// total_bytes is the size of all segments when the replay started.
+    total_bytes: u64,
+    /// read_bytes is the size of the segments read before the current one.
+    read_bytes: u64,
+    /// entries is the number of entries returned.
+    entries: u64,
+}
+
+impl WalReplayIterator {
+    /// progress returns how far the replay went.
+    pub fn progress(&self) -> ReplayProgress {
+        let bytes_replayed =
+            if self.current.is_some() && self.segments.is_empty() && self.pos >= self.data.len() {
+                // done, the torn tail of the last segment may have been dropped
+                self.total_bytes
+            } else {
+                (self.read_bytes + self.pos as u64).min(self.total_bytes)
+            };
+
+        ReplayProgress {
+            bytes_replayed,
+            total_bytes: self.total_bytes,
+            entries: self.entries,
+            segment: self.current.clone(),
+        }
+    }
+
+    /// truncate drops the torn tail of the last segment, so the segment is whole
+    /// once new segments follow it.
+    async fn truncate(&mut self, path: &str, cause: anyhow::Error) -> anyhow::Result<()> {
+        tracing::warn!(
+            "wal segment {} torn at {}, truncating: {}",
+            path,
+            self.pos,
+            cause
+        );
+        self.data.truncate(self.pos);
+        self.op
+            .to_op(path)
+            .write_atomic(std::mem::take(&mut self.data))
+            .await?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsyncIterator for WalReplayIterator {
+    type Item = WalEntry;
+
+    async fn try_next(&mut self) -> anyhow::Result<Option<Self::Item>> {
+        loop {
+            if self.pos < self.data.len() {
+                let path = self.current.clone().unwrap_or_default();
+                match WalEntry::decode(&self.data[self.pos..]) {
+                    Ok((entry, n)) => {
+                        self.pos += n;
+                        self.entries += 1;
+                        return Ok(Some(entry));
+                    }
+                    Err(e) if self.segments.is_empty() => {
+                        self.truncate(path.as_str(), e).await?;
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        return Err(anyhow!(
+                            "wal segment {} corrupt at {}: {}",
+                            path,
+                            self.pos,
+                            e
+                        ));
+                    }
+                }
+            }
+
+            let path = match self.segments.pop_front() {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            self.read_bytes += self.data.len() as u64;
+            self.data = self.op.operator().read(path.as_str()).await?;
+            self.pos = 0;
+            self.current = Some(path);
+        }
+    }
+}
+
+/// ReplayProgress is how far a WAL replay went.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayProgress {
+    /// bytes_replayed is the size of the entries replayed so far.
+    pub bytes_replayed: u64,
+    /// total_bytes is the size of the segments to replay.
+    pub total_bytes: u64,
+    /// entries is the number of entries replayed so far.
+    pub entries: u64,
+    /// segment is the path of the segment being replayed.
+    pub segment: Option<String>,
+}
+
+impl ReplayProgress {
+    /// percent returns the share of the bytes replayed, 100 if there is nothing to
+    /// replay.
+    pub fn percent(&self) -> u8 {
+        if self.total_bytes == 0 {
+            return 100;
+        }
+        (self.bytes_replayed * 100 / self.total_bytes) as u8
+    }
+}
+
+/// Progress receives the progress of long running operations, e.g. a WAL replay.
+pub trait Progress: Send + Sync {
+    fn on_progress(&self, progress: &ReplayProgress);
+}
+
+/// read_segment reads all entries of the segment file.
+pub async fn read_segment(op: StorageOperator) -> anyhow::Result<Vec<WalEntry>> {
+    let data = op.operator().read(op.path()).await?;
+
+    let mut entries = Vec::new();
+    let mut b = data.as_slice();
+    while !b.is_empty() {
+        let (entry, n) = WalEntry::decode(b)?;
+        entries.push(entry);
+        b = &b[n..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use common_base::clock::SimulatedClock;
+    use common_base::iterator::AsyncIterator;
+    use influxdb_storage::{path_join, StorageOperator};
+
+    use crate::engine::tsm1::value::{TimeValue, Values};
+    use crate::engine::tsm1::wal::{
+        read_segment, segment_filename, DeleteRangeEntry, Wal, WalEntry, WalOptions, WriteEntry,
+    };
+
+    fn entries() -> Vec<WalEntry> {
+        vec![
+            WalEntry::Write(WriteEntry::new(
+                b"cpu,host=A#!~#value".to_vec(),
+                Values::Float(vec![TimeValue::new(1, 1.5), TimeValue::new(2, -2.5)]),
+            )),
+            WalEntry::Write(WriteEntry::new(
+                b"cpu,host=A#!~#count".to_vec(),
+                Values::Integer(vec![TimeValue::new(1, -7), TimeValue::new(3, i64::MAX)]),
+            )),
+            WalEntry::Write(WriteEntry::new(
+                b"cpu,host=A#!~#up".to_vec(),
+                Values::Bool(vec![TimeValue::new(1, true), TimeValue::new(2, false)]),
+            )),
+            WalEntry::Write(WriteEntry::new(
+                b"cpu,host=A#!~#msg".to_vec(),
+                Values::String(vec![
+                    TimeValue::new(1, b"".to_vec()),
+                    TimeValue::new(2, b"hello".to_vec()),
+                ]),
+            )),
+            WalEntry::Write(WriteEntry::new(
+                b"cpu,host=A#!~#bytes".to_vec(),
+                Values::Unsigned(vec![TimeValue::new(4, u64::MAX)]),
+            )),
+            WalEntry::DeleteRange(DeleteRangeEntry::new(
+                vec![
+                    b"cpu,host=A#!~#value".to_vec(),
+                    b"cpu,host=B#!~#value".to_vec(),
+                ],
+                i64::MIN,
+                100,
+            )),
+        ]
+    }
+
+    #[test]
+    fn test_wal_entry_encode_decode() {
+        for entry in entries() {
+            let mut buf = Vec::new();
+            entry.encode(&mut buf).unwrap();
+
+            let (got, n) = WalEntry::decode(buf.as_slice()).unwrap();
+            assert_eq!(got, entry);
+            assert_eq!(n, buf.len());
+
+            // a corrupted payload fails the checksum
+            let last = buf.len() - 5;
+            buf[last] ^= 0xff;
+            assert!(WalEntry::decode(buf.as_slice()).is_err());
+
+            // a torn entry is rejected
+            assert!(WalEntry::decode(&buf[..buf.len() - 1]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_write_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let op = StorageOperator::root(dir.path().to_str().unwrap()).unwrap();
+
+        let mut wal = Wal::open(op.clone(), WalOptions::default()).await.unwrap();
+        assert_eq!(wal.current_segment_id(), 1);
+
+        for entry in entries() {
+            assert_eq!(wal.write(&entry).await.unwrap(), 1);
+        }
+        let segments = wal.segments();
+        wal.close().await.unwrap();
+
+        assert_eq!(segments.len(), 1);
+        let got = read_segment(op.to_op(segments[0].as_str())).await.unwrap();
+        assert_eq!(got, entries());
+
+        // writes after reopening go to the next segment
+        let mut wal = Wal::open(op.clone(), WalOptions::default()).await.unwrap();
+        assert_eq!(wal.current_segment_id(), 2);
+        assert_eq!(wal.closed_segments(), segments);
+        wal.delete_range(vec![b"a".to_vec()], 0, 1).await.unwrap();
+        wal.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wal_rotate_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let op = StorageOperator::root(path).unwrap();
+
+        let options = WalOptions {
+            segment_size: 256,
+            sync_delay: Duration::from_secs(60),
+        };
+        let mut wal = Wal::open(op.clone(), options).await.unwrap();
+
+        let mut written = Vec::new();
+        for i in 0..20 {
+            let values = Values::Integer((0..16).map(|j| TimeValue::new(i * 100 + j, j)).collect());
+            let segment_id = wal
+                .write_values(
+                    format!("cpu,host={}#!~#value", i).into_bytes(),
+                    values.clone(),
+                )
+                .await
+                .unwrap();
+            written.push((segment_id, values));
+        }
+        assert!(wal.current_segment_id() > 1);
+
+        // an explicit close starts a new segment
+        let last_id = wal.current_segment_id();
+        wal.close_segment().await.unwrap();
+        assert_eq!(wal.current_segment_id(), last_id + 1);
+
+        let segments = wal.segments();
+        let exp: Vec<String> = (1..=last_id + 1)
+            .map(|id| path_join(wal.path(), segment_filename(id).as_str()))
+            .collect();
+        assert_eq!(segments, exp);
+        assert!(dir.path().join("_00001.wal").exists());
+        assert!(dir.path().join(segment_filename(last_id + 1)).exists());
+
+        // every entry is found in the segment it was reported in
+        for id in 1..=last_id {
+            let got = read_segment(op.to_op(segments[id as usize - 1].as_str()))
+                .await
+                .unwrap();
+            let exp: Vec<Values> = written
+                .iter()
+                .filter(|(segment_id, _)| *segment_id == id)
+                .map(|(_, values)| values.clone())
+                .collect();
+            assert!(!exp.is_empty());
+            let got: Vec<Values> = got
+                .into_iter()
+                .map(|entry| match entry {
+                    WalEntry::Write(entry) => entry.values,
+                    _ => unreachable!(),
+                })
+                .collect();
+            assert_eq!(got, exp);
+        }
+
+        // closed segments can be removed, the current one can not
+        let closed = wal.closed_segments();
+        assert_eq!(closed.len(), last_id as usize);
+        assert!(wal.remove(&segments[last_id as usize..]).await.is_err());
+        wal.remove(&closed).await.unwrap();
+        assert_eq!(wal.segments(), segments[last_id as usize..].to_vec());
+        assert!(!dir.path().join("_00001.wal").exists());
+        wal.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wal_sync_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let op = StorageOperator::root(dir.path().to_str().unwrap()).unwrap();
+        let clock = SimulatedClock::new(0);
+
+        let options = WalOptions {
+            sync_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut wal = Wal::open_with_clock(op, options, Arc::new(clock.clone()))
+            .await
+            .unwrap();
+
+        // synced once the delay passed since the last sync
+        wal.write_batch(&entries()).await.unwrap();
+        assert!(wal.appender.is_some());
+        clock.advance(Duration::from_secs(59));
+        wal.write_batch(&entries()).await.unwrap();
+        assert!(wal.appender.is_some());
+        clock.advance(Duration::from_secs(1));
+        wal.write_batch(&entries()).await.unwrap();
+        assert!(wal.appender.is_none());
+
+        wal.write_batch(&entries()).await.unwrap();
+        assert!(wal.appender.is_some());
+        wal.close().await.unwrap();
+    }
+
+    async fn replay_all(op: StorageOperator) -> anyhow::Result<Vec<WalEntry>> {
+        let mut entries = vec![];
+        let mut itr = Wal::replay(op).await?;
+        while let Some(entry) = itr.try_next().await? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_torn_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let op = StorageOperator::root(dir.path().to_str().unwrap()).unwrap();
+
+        // nothing to replay in a missing directory
+        let missing = op.to_op(path_join(op.path(), "missing").as_str());
+        assert!(replay_all(missing).await.unwrap().is_empty());
+
+        let mut wal = Wal::open(op.clone(), WalOptions::default()).await.unwrap();
+        let entries = entries();
+        for entry in entries[..3].iter() {
+            wal.write(entry).await.unwrap();
+        }
+        wal.close_segment().await.unwrap();
+        for entry in entries[3..].iter() {
+            wal.write(entry).await.unwrap();
+        }
+        let segments = wal.segments();
+        wal.close().await.unwrap();
+        assert_eq!(replay_all(op.clone()).await.unwrap(), entries);
+
+        // cut the last entry of the last segment in half
+        let last = dir.path().join(segment_filename(2));
+        let mut buf = vec![];
+        entries.last().unwrap().encode(&mut buf).unwrap();
+        let len = std::fs::metadata(&last).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&last).unwrap();
+        file.set_len(len - buf.len() as u64 / 2).unwrap();
+        drop(file);
+
+        let got = replay_all(op.clone()).await.unwrap();
+        assert_eq!(got, entries[..entries.len() - 1].to_vec());
+        // the torn tail was truncated
+        assert_eq!(
+            std::fs::metadata(&last).unwrap().len(),
+            len - buf.len() as u64
+        );
+        assert_eq!(replay_all(op.clone()).await.unwrap(), got);
+
+        // the same in a segment followed by others is corruption
+        let first = dir.path().join(segment_filename(1));
+        let len = std::fs::metadata(&first).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&first)
+            .unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+        assert!(replay_all(op.clone()).await.is_err());
+        assert_eq!(segments.len(), 2);
+    }
+}
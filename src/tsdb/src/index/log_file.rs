@@ -0,0 +1,748 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::{Buf, BufMut};
+use common_base::point::{parse_series_key, Tags};
+use influxdb_storage::opendal::Appender;
+use influxdb_storage::StorageOperator;
+use roaring::RoaringTreemap;
+
+use crate::index::sketches::{Sketches, SKETCHES_FILE_EXTENSION};
+
+/// Bitmap is a compressed set of series ids.
+pub type Bitmap = RoaringTreemap;
+
+/// LOG_FILE_EXTENSION is the extension of the log files of an index.
+pub const LOG_FILE_EXTENSION: &str = "tsl";
+
+const LOG_ENTRY_SERIES_ADD: u8 = 0x01;
+const LOG_ENTRY_SERIES_DELETE: u8 = 0x02;
+
+/// flag + id + key length
+const LOG_ENTRY_HEADER_SIZE: usize = 1 + 8 + 4;
+const LOG_ENTRY_CHECKSUM_SIZE: usize = 4;
+
+/// LogEntry is a change of the index appended to the log file.
+///
+/// ┌────────┬─────────┬────────────┬────────────┬─────────┐
+/// │  flag  │   id    │  key len   │    key     │  crc32  │
+/// │ 1 byte │ 8 bytes │  4 bytes   │  N bytes   │ 4 bytes │
+/// └────────┴─────────┴────────────┴────────────┴─────────┘
+///
+/// The key of a delete entry is empty.
+#[derive(Debug, PartialEq)]
+enum LogEntry {
+    AddSeries(u64, Vec<u8>),
+    DeleteSeries(u64),
+}
+
+impl LogEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        match self {
+            Self::AddSeries(id, key) => {
+                buf.put_u8(LOG_ENTRY_SERIES_ADD);
+                buf.put_u64(*id);
+                buf.put_u32(key.len() as u32);
+                buf.put_slice(key.as_slice());
+            }
+            Self::DeleteSeries(id) => {
+                buf.put_u8(LOG_ENTRY_SERIES_DELETE);
+                buf.put_u64(*id);
+                buf.put_u32(0);
+            }
+        }
+
+        let crc = crc32fast::hash(&buf[start..]);
+        buf.put_u32(crc);
+    }
+
+    /// decode returns the entry at the beginning of b and its size, None if b
+    /// ends before the entry does. A complete entry which is not valid is an
+    /// error.
+    fn decode(b: &[u8]) -> Result<Option<(Self, usize)>, String> {
+        if b.len() < LOG_ENTRY_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut h = &b[..LOG_ENTRY_HEADER_SIZE];
+        let flag = h.get_u8();
+        let id = h.get_u64();
+        let key_len = h.get_u32() as usize;
+
+        let size = LOG_ENTRY_HEADER_SIZE + key_len + LOG_ENTRY_CHECKSUM_SIZE;
+        if b.len() < size {
+            return Ok(None);
+        }
+        let (data, mut crc) = b[..size].split_at(size - LOG_ENTRY_CHECKSUM_SIZE);
+        if crc32fast::hash(data) != crc.get_u32() {
+            return Err("checksum mismatch".to_string());
+        }
+
+        let entry = match flag {
+            LOG_ENTRY_SERIES_ADD => Self::AddSeries(id, data[LOG_ENTRY_HEADER_SIZE..].to_vec()),
+            LOG_ENTRY_SERIES_DELETE => Self::DeleteSeries(id),
+            _ => return Err(format!("unknown entry flag {:#x}", flag)),
+        };
+        Ok(Some((entry, size)))
+    }
+}
+
+/// LogFileError is the error of a log file which can not be replayed.
+#[derive(Debug, thiserror::Error)]
+pub enum LogFileError {
+    /// An entry before the end of the file is damaged, or holds a malformed
+    /// series key. The file is left as is.
+    #[error("log file {path} is corrupt at offset {offset}: {reason}")]
+    Corrupt {
+        path: String,
+        offset: usize,
+        reason: String,
+    },
+}
+
+/// MeasurementEntry holds the series of a measurement by tag.
+#[derive(Default)]
+struct MeasurementEntry {
+    /// map: tag key -> tag value -> series ids
+    tags: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Bitmap>>,
+    /// all the series ids of the measurement
+    series: Bitmap,
+}
+
+/// SeriesEntry is an indexed series key and its unescaped measurement and tags.
+struct SeriesEntry {
+    key: Vec<u8>,
+    measurement: Vec<u8>,
+    tags: Tags,
+}
+
+/// LogFile is an inverted index from measurement, tag key and tag value to
+/// the ids of the series, e.g. `cpu,host=a,region=eu`, held in memory.
+///
+/// The changes are appended to the log file at `op` and replayed when it is
+/// opened. An incomplete entry at the end of the file, left by a crash while
+/// appending it, is dropped. Any other damaged entry fails the open with
+/// `LogFileError::Corrupt`, rather than dropping the valid entries after it.
+///
+/// The series and measurements are counted by `Sketches`, persisted next to
+/// the log file, with the `.sketches` extension, when it is closed. They are
+/// rebuilt from the replayed index when they are missing, damaged or miss
+/// entries appended since.
+pub struct LogFile {
+    op: StorageOperator,
+    appender: Option<Appender>,
+    /// size is the size of the valid entries of the log file.
+    size: u64,
+    sketches: Sketches,
+
+    /// map: measurement -> series by tag, ordered for the iterators
+    measurements: BTreeMap<Vec<u8>, MeasurementEntry>,
+    /// map: series id -> series
+    series: HashMap<u64, SeriesEntry>,
+    /// map: series key -> series id
+    ids: HashMap<Vec<u8>, u64>,
+    /// max_series_id is the highest series id logged, deleted series included
+    max_series_id: u64,
+}
+
+impl LogFile {
+    /// open replays the log file at `op`, an empty index is returned if there
+    /// is none.
+    pub async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        let mut log_file = Self {
+            op,
+            appender: None,
+            size: 0,
+            sketches: Sketches::new()?,
+            measurements: BTreeMap::new(),
+            series: HashMap::new(),
+            ids: HashMap::new(),
+            max_series_id: 0,
+        };
+
+        if log_file.op.exist().await? {
+            let data = log_file.op.operator().read(log_file.op.path()).await?;
+
+            let path = log_file.op.path().to_string();
+            let corrupt = |offset: usize, reason: String| LogFileError::Corrupt {
+                path: path.clone(),
+                offset,
+                reason,
+            };
+
+            let mut n = 0;
+            while n < data.len() {
+                let (entry, size) = match LogEntry::decode(&data[n..]) {
+                    Ok(Some(x)) => x,
+                    // the rest of the file is an incomplete entry
+                    Ok(None) => break,
+                    Err(reason) => return Err(corrupt(n, reason).into()),
+                };
+                if let Err(e) = log_file.apply(entry) {
+                    return Err(corrupt(n, e.to_string()).into());
+                }
+                n += size;
+            }
+
+            // drop the incomplete entry, the next ones are appended after the
+            // valid ones.
+            if n < data.len() {
+                log_file.op.write_atomic(data[..n].to_vec()).await?;
+            }
+            log_file.size = n as u64;
+        }
+
+        match Sketches::load(&log_file.sketches_op(), log_file.size).await? {
+            Some(sketches) => log_file.sketches = sketches,
+            None => log_file.build_sketches(),
+        }
+
+        Ok(log_file)
+    }
+
+    /// remove deletes the log file at `op` and its sketches.
+    pub async fn remove(op: &StorageOperator) -> anyhow::Result<()> {
+        op.delete().await?;
+        op.to_tmp(SKETCHES_FILE_EXTENSION).delete().await?;
+        Ok(())
+    }
+
+    fn sketches_op(&self) -> StorageOperator {
+        self.op.to_tmp(SKETCHES_FILE_EXTENSION)
+    }
+
+    /// build_sketches adds the indexed series and their measurements to the
+    /// sketches.
+    fn build_sketches(&mut self) {
+        for (id, series) in self.series.iter() {
+            self.sketches.add_series(*id, series.measurement.as_slice());
+        }
+    }
+
+    /// add_series indexes the series key, e.g. `cpu,host=a`, with id. The key is
+    /// indexed by its unescaped measurement and tags, a malformed key is a
+    /// `KeyError`. Adding an indexed id again is a no-op.
+    pub async fn add_series(&mut self, id: u64, key: &[u8]) -> anyhow::Result<()> {
+        if self.series.contains_key(&id) {
+            return Ok(());
+        }
+
+        let (measurement, tags) = parse_series_key(key)?;
+        self.append(&LogEntry::AddSeries(id, key.to_vec())).await?;
+        self.sketches.add_series(id, measurement.as_slice());
+        self.insert_series(id, key.to_vec(), measurement, tags);
+        Ok(())
+    }
+
+    /// delete_series drops the series id from the index. Deleting an id which
+    /// is not indexed is a no-op.
+    pub async fn delete_series(&mut self, id: u64) -> anyhow::Result<()> {
+        let measurement = match self.series.get(&id) {
+            Some(series) => series.measurement.clone(),
+            None => return Ok(()),
+        };
+
+        self.append(&LogEntry::DeleteSeries(id)).await?;
+        self.remove_series(id);
+        self.sketches.delete_series(id);
+        if !self.measurements.contains_key(&measurement) {
+            self.sketches.delete_measurement(measurement.as_slice());
+        }
+        Ok(())
+    }
+
+    /// series_count returns the number of indexed series.
+    pub fn series_count(&self) -> usize {
+        self.series.len()
+    }
+
+    /// series_id returns the id of an indexed series key.
+    pub fn series_id(&self, key: &[u8]) -> Option<u64> {
+        self.ids.get(key).copied()
+    }
+
+    /// series_key returns the key of an indexed series id.
+    pub fn series_key(&self, id: u64) -> Option<&[u8]> {
+        self.series.get(&id).map(|x| x.key.as_slice())
+    }
+
+    /// max_series_id returns the highest series id logged, including the ids of
+    /// deleted series, 0 if there is none.
+    pub fn max_series_id(&self) -> u64 {
+        self.max_series_id
+    }
+
+    /// series_n returns the estimated number of series, see `Sketches`.
+    pub fn series_n(&mut self) -> u64 {
+        self.sketches.series_n()
+    }
+
+    /// measurement_n returns the estimated number of measurements, see `Sketches`.
+    pub fn measurement_n(&mut self) -> u64 {
+        self.sketches.measurement_n()
+    }
+
+    /// measurement_iterator returns the measurements having series, in order.
+    pub fn measurement_iterator(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.measurements.keys().map(|x| x.as_slice())
+    }
+
+    /// tag_key_iterator returns the tag keys of the series of a measurement, in
+    /// order.
+    pub fn tag_key_iterator<'a>(
+        &'a self,
+        measurement: &[u8],
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.measurements
+            .get(measurement)
+            .into_iter()
+            .flat_map(|x| x.tags.keys().map(|x| x.as_slice()))
+    }
+
+    /// tag_value_iterator returns the values of a tag key of the series of a
+    /// measurement, in order.
+    pub fn tag_value_iterator<'a>(
+        &'a self,
+        measurement: &[u8],
+        tag_key: &[u8],
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.measurements
+            .get(measurement)
+            .and_then(|x| x.tags.get(tag_key))
+            .into_iter()
+            .flat_map(|x| x.keys().map(|x| x.as_slice()))
+    }
+
+    /// measurement_series_ids returns the ids of the series of a measurement.
+    pub fn measurement_series_ids(&self, measurement: &[u8]) -> Bitmap {
+        self.measurements
+            .get(measurement)
+            .map(|x| x.series.clone())
+            .unwrap_or_default()
+    }
+
+    /// series_ids returns the ids of the series of a measurement having the tag
+    /// `tag_key=tag_value`. The bitmaps of several tags compose with `&` and `|`.
+    pub fn series_ids(&self, measurement: &[u8], tag_key: &[u8], tag_value: &[u8]) -> Bitmap {
+        self.measurements
+            .get(measurement)
+            .and_then(|x| x.tags.get(tag_key))
+            .and_then(|x| x.get(tag_value))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// close flushes and releases the write handle of the log file and
+    /// persists the sketches.
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(mut appender) = self.appender.take() {
+            appender.close().await?;
+        }
+        self.sketches.persist(&self.sketches_op(), self.size).await
+    }
+
+    async fn append(&mut self, entry: &LogEntry) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        entry.encode(&mut buf);
+
+        if self.appender.is_none() {
+            self.appender = Some(self.op.appender().await?);
+        }
+        let size = buf.len() as u64;
+        self.appender.as_mut().unwrap().append(buf).await?;
+        self.size += size;
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: LogEntry) -> anyhow::Result<()> {
+        match entry {
+            LogEntry::AddSeries(id, key) => {
+                let (measurement, tags) = parse_series_key(key.as_slice())?;
+                self.insert_series(id, key, measurement, tags);
+            }
+            LogEntry::DeleteSeries(id) => self.remove_series(id),
+        }
+        Ok(())
+    }
+
+    fn insert_series(&mut self, id: u64, key: Vec<u8>, measurement: Vec<u8>, tags: Tags) {
+        let m = self.measurements.entry(measurement.clone()).or_default();
+        for tag in tags.iter() {
+            m.tags
+                .entry(tag.key.clone())
+                .or_default()
+                .entry(tag.value.clone())
+                .or_default()
+                .insert(id);
+        }
+        m.series.insert(id);
+        self.ids.insert(key.clone(), id);
+        self.max_series_id = self.max_series_id.max(id);
+        self.series.insert(
+            id,
+            SeriesEntry {
+                key,
+                measurement,
+                tags,
+            },
+        );
+    }
+
+    fn remove_series(&mut self, id: u64) {
+        let SeriesEntry {
+            key,
+            measurement,
+            tags,
+        } = match self.series.remove(&id) {
+            Some(x) => x,
+            None => return,
+        };
+        self.ids.remove(&key);
+
+        let m = match self.measurements.get_mut(&measurement) {
+            Some(m) => m,
+            None => return,
+        };
+        for tag in tags.iter() {
+            if let Some(values) = m.tags.get_mut(&tag.key) {
+                if let Some(ids) = values.get_mut(&tag.value) {
+                    ids.remove(id);
+                    if ids.is_empty() {
+                        values.remove(&tag.value);
+                    }
+                }
+                if values.is_empty() {
+                    m.tags.remove(&tag.key);
+                }
+            }
+        }
+        m.series.remove(id);
+        if m.series.is_empty() {
+            self.measurements.remove(&measurement);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use common_base::point::{series_key, KeyError, Tag};
+    use influxdb_storage::StorageOperator;
+
+    use crate::index::log_file::{Bitmap, LogEntry, LogFile, LogFileError};
+
+    /// TestSeries is a series with the measurement and tags its key is built of.
+    struct TestSeries {
+        id: u64,
+        measurement: Vec<u8>,
+        tags: Vec<Tag>,
+    }
+
+    impl TestSeries {
+        fn key(&self) -> Vec<u8> {
+            series_key(self.measurement.as_slice(), self.tags.as_slice())
+        }
+
+        fn has_tag(&self, key: &[u8], value: &[u8]) -> bool {
+            self.tags.iter().any(|x| x.key == key && x.value == value)
+        }
+    }
+
+    /// series returns the series 1..=200, some without region and some with
+    /// characters which are escaped in their key.
+    fn series() -> Vec<TestSeries> {
+        (1..=200_u64)
+            .map(|id| {
+                let measurement = ["cpu", "mem", "disk io,x"][id as usize % 3];
+                let host = if id % 7 == 6 {
+                    "h,6=x".to_string()
+                } else {
+                    format!("h{}", id % 7)
+                };
+                let mut tags = vec![Tag::new(b"host".to_vec(), host.into_bytes())];
+                if id % 5 != 0 {
+                    let region = ["r0", "r=1", "r,2"][id as usize % 3];
+                    tags.push(Tag::new(b"region".to_vec(), region.as_bytes().to_vec()));
+                }
+                TestSeries {
+                    id,
+                    measurement: measurement.as_bytes().to_vec(),
+                    tags,
+                }
+            })
+            .collect()
+    }
+
+    /// brute_force returns the ids of the live series of the measurement having
+    /// the tag.
+    fn brute_force(live: &[&TestSeries], measurement: &[u8], key: &[u8], value: &[u8]) -> Bitmap {
+        live.iter()
+            .filter(|x| x.measurement == measurement && x.has_tag(key, value))
+            .map(|x| x.id)
+            .collect()
+    }
+
+    fn assert_index(log_file: &LogFile, live: &[&TestSeries]) {
+        let tags: [(&[u8], &[u8]); 7] = [
+            (b"host", b"h1"),
+            (b"host", b"h3"),
+            (b"host", b"h,6=x"),
+            (b"region", b"r0"),
+            (b"region", b"r=1"),
+            (b"region", b"r,2"),
+            (b"zone", b"z"),
+        ];
+        let measurements: [&[u8]; 5] = [b"cpu", b"mem", b"disk io,x", b"disk io", b"net"];
+        for measurement in measurements {
+            for (k1, v1) in tags.iter() {
+                let a = log_file.series_ids(measurement, k1, v1);
+                let expected_a = brute_force(live, measurement, k1, v1);
+                assert_eq!(a, expected_a);
+
+                for (k2, v2) in tags.iter() {
+                    let b = log_file.series_ids(measurement, k2, v2);
+                    let expected_b = brute_force(live, measurement, k2, v2);
+                    assert_eq!(&a & &b, &expected_a & &expected_b);
+                    assert_eq!(&a | &b, &expected_a | &expected_b);
+                }
+            }
+        }
+
+        let measurements: BTreeSet<&[u8]> = live.iter().map(|x| x.measurement.as_slice()).collect();
+        assert_eq!(
+            log_file.measurement_iterator().collect::<Vec<_>>(),
+            measurements.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(log_file.series_count(), live.len());
+        for x in live {
+            assert_eq!(log_file.series_id(&x.key()), Some(x.id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_file_series_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("L0-00000001.tsl");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        let series = series();
+        for x in series.iter() {
+            log_file.add_series(x.id, &x.key()).await.unwrap();
+        }
+        assert_index(&log_file, &series.iter().collect::<Vec<_>>());
+
+        assert_eq!(
+            log_file.tag_key_iterator(b"cpu").collect::<Vec<_>>(),
+            vec![b"host".as_slice(), b"region".as_slice()]
+        );
+        assert_eq!(
+            log_file
+                .tag_value_iterator(b"cpu", b"region")
+                .collect::<Vec<_>>(),
+            vec![b"r0".as_slice()]
+        );
+        assert_eq!(
+            log_file
+                .tag_value_iterator(b"disk io,x", b"region")
+                .collect::<Vec<_>>(),
+            vec![b"r,2".as_slice()]
+        );
+        assert_eq!(log_file.tag_key_iterator(b"net").count(), 0);
+        assert_eq!(log_file.tag_value_iterator(b"cpu", b"zone").count(), 0);
+
+        // delete every series of host h1 and the disk measurement
+        for x in series.iter() {
+            if x.has_tag(b"host", b"h1") || x.measurement == b"disk io,x" {
+                log_file.delete_series(x.id).await.unwrap();
+            }
+        }
+        let live: Vec<&TestSeries> = series
+            .iter()
+            .filter(|x| !x.has_tag(b"host", b"h1") && x.measurement != b"disk io,x")
+            .collect();
+        assert_index(&log_file, live.as_slice());
+        assert!(log_file.series_ids(b"cpu", b"host", b"h1").is_empty());
+        assert!(log_file.measurement_series_ids(b"disk io,x").is_empty());
+        log_file.close().await.unwrap();
+
+        // the log is replayed on open
+        let log_file = LogFile::open(op).await.unwrap();
+        assert_index(&log_file, live.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_log_file_escaped_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("L0-00000001.tsl");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut log_file = LogFile::open(op).await.unwrap();
+        log_file
+            .add_series(1, br"cpu\,x,host=a\,b,region=us\=west")
+            .await
+            .unwrap();
+        log_file.add_series(2, b"cpu,host=a").await.unwrap();
+
+        assert_eq!(
+            log_file.measurement_iterator().collect::<Vec<_>>(),
+            vec![b"cpu".as_slice(), b"cpu,x".as_slice()]
+        );
+        assert_eq!(
+            log_file.series_ids(b"cpu,x", b"host", b"a,b"),
+            Bitmap::from_iter([1])
+        );
+        assert_eq!(
+            log_file.series_ids(b"cpu,x", b"region", b"us=west"),
+            Bitmap::from_iter([1])
+        );
+        assert_eq!(
+            log_file.series_ids(b"cpu", b"host", b"a"),
+            Bitmap::from_iter([2])
+        );
+        assert_eq!(log_file.series_id(b"cpu,host=a"), Some(2));
+        assert_eq!(
+            log_file.series_key(1),
+            Some(br"cpu\,x,host=a\,b,region=us\=west".as_slice())
+        );
+        assert_eq!(log_file.max_series_id(), 2);
+
+        // a malformed key is not logged
+        let err = log_file.add_series(3, b"cpu,host").await.unwrap_err();
+        assert!(err.downcast_ref::<KeyError>().is_some());
+        assert_eq!(log_file.series_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_log_file_torn_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("L0-00000001.tsl");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        log_file.add_series(1, b"cpu,host=a").await.unwrap();
+        log_file.add_series(2, b"cpu,host=b").await.unwrap();
+        log_file.close().await.unwrap();
+
+        // a crash in the middle of appending the third entry
+        let mut data = std::fs::read(&path).unwrap();
+        let size = data.len();
+        data.extend_from_slice(&[0x01, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0]);
+        std::fs::write(&path, data).unwrap();
+
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        assert_eq!(log_file.series_count(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+
+        log_file.add_series(3, b"cpu,host=c").await.unwrap();
+        log_file.close().await.unwrap();
+
+        let log_file = LogFile::open(op).await.unwrap();
+        assert_eq!(
+            log_file.series_ids(b"cpu", b"host", b"c"),
+            Bitmap::from_iter([3])
+        );
+        assert_eq!(log_file.series_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_log_file_corrupt_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("L0-00000001.tsl");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        for (id, key) in [(1, b"cpu,host=a"), (2, b"cpu,host=b"), (3, b"cpu,host=c")] {
+            log_file.add_series(id, key).await.unwrap();
+        }
+        log_file.close().await.unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let size = data.len() / 3;
+
+        let assert_corrupt = |data: Vec<u8>, reason: &'static str| {
+            let op = op.clone();
+            let path = path.clone();
+            async move {
+                std::fs::write(&path, &data).unwrap();
+                let err = match LogFile::open(op).await {
+                    Ok(_) => panic!("opened a corrupt log file"),
+                    Err(e) => e,
+                };
+                match err.downcast_ref::<LogFileError>() {
+                    Some(LogFileError::Corrupt {
+                        offset, reason: r, ..
+                    }) => {
+                        assert_eq!(*offset, size);
+                        assert!(r.contains(reason), "{}", r);
+                    }
+                    None => panic!("{}", err),
+                }
+                // the entries after the damaged one are kept
+                assert_eq!(std::fs::read(&path).unwrap(), data);
+            }
+        };
+
+        // a bit flip in the key of the second entry
+        let mut flipped = data.clone();
+        flipped[size + 15] ^= 0x01;
+        assert_corrupt(flipped, "checksum").await;
+
+        // a malformed key is as corrupt as a bad checksum
+        let mut malformed = data[..size].to_vec();
+        LogEntry::AddSeries(4, b"cpu,host".to_vec()).encode(&mut malformed);
+        malformed.extend_from_slice(&data[size..]);
+        assert_corrupt(malformed, "").await;
+
+        // an incomplete entry at the end is still dropped
+        std::fs::write(&path, &data[..size * 3 - 1]).unwrap();
+        let log_file = LogFile::open(op).await.unwrap();
+        assert_eq!(log_file.series_count(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64 * 2);
+    }
+
+    #[tokio::test]
+    async fn test_log_file_sketches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("L0-00000001.tsl");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        let within = |estimate: u64, n: u64| {
+            let err = (estimate as f64 - n as f64).abs();
+            assert!(err <= (n as f64 * 0.02).max(1.0), "{} != {}", estimate, n);
+        };
+
+        // 10000 series of 20 measurements, the ones of m0 and m1 are deleted
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        for id in 0..10_000_u64 {
+            let key = format!("m{},host=server{}", id % 20, id);
+            log_file.add_series(id, key.as_bytes()).await.unwrap();
+        }
+        for id in (0..10_000_u64).filter(|x| x % 20 < 2) {
+            log_file.delete_series(id).await.unwrap();
+        }
+        within(log_file.series_n(), 9_000);
+        within(log_file.measurement_n(), 18);
+        log_file.close().await.unwrap();
+
+        // the persisted sketches are loaded
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        within(log_file.series_n(), 9_000);
+        within(log_file.measurement_n(), 18);
+
+        // the sketches miss the series added since, they are rebuilt
+        log_file.add_series(10_000, b"m0,host=a").await.unwrap();
+        drop(log_file);
+        let mut log_file = LogFile::open(op.clone()).await.unwrap();
+        within(log_file.series_n(), 9_001);
+        within(log_file.measurement_n(), 19);
+        log_file.close().await.unwrap();
+
+        // damaged sketches are rebuilt
+        let sketches_path = dir.path().join("L0-00000001.tsl.sketches");
+        let data = std::fs::read(&sketches_path).unwrap();
+        std::fs::write(&sketches_path, &data[..data.len() / 2]).unwrap();
+        let mut log_file = LogFile::open(op).await.unwrap();
+        within(log_file.series_n(), 9_001);
+        within(log_file.measurement_n(), 19);
+    }
+}
@@ -1 +1,5 @@
-pub mod tsi1;
\ No newline at end of file
+pub mod log_file;
+pub mod shard_index;
+pub mod sketches;
+pub mod tag_index;
+pub mod tsi1;
@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+
+use bytes::{Buf, BufMut};
+use common_base::point::{self, Tags, KEY_FIELD_SEPARATOR};
+use futures::TryStreamExt;
+use influxdb_storage::StorageOperator;
+
+use crate::engine::tsm1::shard_lock::Fence;
+use crate::index::log_file::{Bitmap, LogFile, LOG_FILE_EXTENSION};
+use crate::index::tag_index::TagPredicate;
+
+/// SHARD_INDEX_FILE is the name of the persisted index in a shard directory.
+pub const SHARD_INDEX_FILE: &str = "index";
+
+const SHARD_INDEX_MAGIC: &str = "SHIX";
+const SHARD_INDEX_VERSION: u8 = 4;
+
+/// ShardIndex holds the series and tag indexes, the field types and field order
+/// per measurement and the series cardinality sketch of a shard.
+///
+/// The series, their tags and the sketches are held by a `LogFile`, next to the
+/// index file, e.g. `index.1.tsl`, which logs the series as they are added. The
+/// index file records the log file in use, the fields and the highest TSM
+/// generation the index reflects, so that TSM files written after the last
+/// persist can be re-indexed on open.
+///
+/// A rebuilt index, see `create`, logs its series to a new log file, which the
+/// index file only refers to once persisted. The log files it does not refer to
+/// are removed when the index is opened or persisted.
+pub struct ShardIndex {
+    op: StorageOperator,
+
+    /// generation is the highest TSM file generation reflected by the index.
+    generation: u64,
+
+    /// log_sequence is the sequence of the log file of the series.
+    log_sequence: u64,
+    series: LogFile,
+    /// removes_logs is set until the log files of other sequences are removed.
+    removes_logs: bool,
+
+    /// map: measurement -> field -> block type
+    fields: HashMap<Vec<u8>, HashMap<Vec<u8>, u8>>,
+    /// map: measurement -> fields in the order they were first written. The order
+    /// is advisory, it is absent for the measurements of older shards.
+    field_order: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+
+    /// fence is checked before the index is persisted, see `AdvisoryLock`.
+    fence: Option<Fence>,
+}
+
+impl ShardIndex {
+    /// create returns an empty index persisted at `op`, e.g. to rebuild an index
+    /// which is lost or damaged. Its series are logged to a new log file, the
+    /// index persisted at `op` is replaced once it is persisted.
+    pub async fn create(op: StorageOperator) -> anyhow::Result<Self> {
+        let log_sequence = log_sequences(&op)
+            .await?
+            .into_iter()
+            .max()
+            .map_or(0, |x| x + 1);
+        LogFile::remove(&log_op(&op, log_sequence)).await?;
+        Self::open_log(op, log_sequence).await
+    }
+
+    /// open loads the persisted index at `op`, an empty index is returned if
+    /// there is none.
+    pub async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        if !op.exist().await? {
+            return Self::open_log(op, 0).await;
+        }
+
+        let data = op.operator().read(op.path()).await?;
+        let persisted = PersistedIndex::decode(data.as_slice())?;
+
+        let mut index = Self::open_log(op, persisted.log_sequence).await?;
+        index.generation = persisted.generation;
+        // the series of versions before 4 are in the index file, ids by position
+        for (i, key) in persisted.series.iter().enumerate() {
+            if index.series.series_id(key.as_slice()).is_none() {
+                index
+                    .series
+                    .add_series(i as u64 + 1, key.as_slice())
+                    .await?;
+            }
+        }
+        for (measurement, field, typ) in persisted.fields {
+            index.add_field(measurement.as_slice(), field.as_slice(), typ);
+        }
+        index.field_order = persisted.field_order;
+        index.remove_logs().await?;
+
+        Ok(index)
+    }
+
+    async fn open_log(op: StorageOperator, log_sequence: u64) -> anyhow::Result<Self> {
+        let series = LogFile::open(log_op(&op, log_sequence)).await?;
+        Ok(Self {
+            op,
+            generation: 0,
+            log_sequence,
+            series,
+            removes_logs: true,
+            fields: HashMap::new(),
+            field_order: HashMap::new(),
+            fence: None,
+        })
+    }
+
+    /// with_fence rejects `persist` once the shard lock identified by `fence` is
+    /// taken over by another owner.
+    pub fn with_fence(mut self, fence: Option<Fence>) -> Self {
+        self.fence = fence;
+        self
+    }
+
+    /// generation returns the highest TSM file generation reflected by the index.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// set_generation records that the TSM files up to `generation` are indexed.
+    /// The generation never goes backwards.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = self.generation.max(generation);
+    }
+
+    /// series_count returns the exact number of indexed series.
+    pub fn series_count(&self) -> usize {
+        self.series.series_count()
+    }
+
+    /// series_cardinality returns the estimated number of series.
+    pub fn series_cardinality(&mut self) -> u64 {
+        self.series.series_n()
+    }
+
+    /// series_id returns the id of a series key, if indexed.
+    pub fn series_id(&self, series_key: &[u8]) -> Option<u64> {
+        self.series.series_id(series_key)
+    }
+
+    /// series_key returns the series key of an id, if indexed.
+    pub fn series_key(&self, id: u64) -> Option<&[u8]> {
+        self.series.series_key(id)
+    }
+
+    /// add_series indexes a series key, e.g. `cpu,host=a`, and returns its id.
+    /// A malformed key is a `KeyError`.
+    pub async fn add_series(&mut self, series_key: &[u8]) -> anyhow::Result<u64> {
+        if let Some(id) = self.series.series_id(series_key) {
+            return Ok(id);
+        }
+
+        let id = self.series.max_series_id() + 1;
+        self.series.add_series(id, series_key).await?;
+        Ok(id)
+    }
+
+    /// add_tsm_key indexes the series of a TSM key, e.g. `cpu,host=a#!~#value`.
+    pub async fn add_tsm_key(&mut self, key: &[u8]) -> anyhow::Result<u64> {
+        self.add_series(series_key_of(key)).await
+    }
+
+    /// field_type returns the block type of a field of a measurement, if registered.
+    pub fn field_type(&self, measurement: &[u8], field: &[u8]) -> Option<u8> {
+        self.fields
+            .get(measurement)
+            .and_then(|fields| fields.get(field))
+            .copied()
+    }
+
+    /// add_field registers the block type of a field of a measurement. It returns
+    /// false if the field was already registered, the first type registered wins.
+    pub fn add_field(&mut self, measurement: &[u8], field: &[u8], typ: u8) -> bool {
+        let fields = self.fields.entry(measurement.to_vec()).or_default();
+        if fields.contains_key(field) {
+            return false;
+        }
+        fields.insert(field.to_vec(), typ);
+        true
+    }
+
+    /// record_field_order appends the fields of a measurement which were not seen
+    /// before to its field order, in the order given.
+    pub fn record_field_order(&mut self, measurement: &[u8], fields: &[&[u8]]) {
+        let order = self.field_order.entry(measurement.to_vec()).or_default();
+        for field in fields {
+            if !order.iter().any(|x| x.as_slice() == *field) {
+                order.push(field.to_vec());
+            }
+        }
+    }
+
+    /// field_keys_ordered returns the registered fields of a measurement in the
+    /// order they were first written. Fields without recorded order follow in
+    /// lexical order, which is the order of all fields of older shards.
+    pub fn field_keys_ordered(&self, measurement: &[u8]) -> Vec<Vec<u8>> {
+        let fields = match self.fields.get(measurement) {
+            Some(fields) => fields,
+            None => return vec![],
+        };
+        let order: HashMap<&[u8], usize> = self
+            .field_order
+            .get(measurement)
+            .map(|order| {
+                order
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| (field.as_slice(), i))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut keys: Vec<Vec<u8>> = fields.keys().cloned().collect();
+        keys.sort_by(|a, b| {
+            let a_order = order.get(a.as_slice()).unwrap_or(&usize::MAX);
+            let b_order = order.get(b.as_slice()).unwrap_or(&usize::MAX);
+            a_order.cmp(b_order).then_with(|| a.cmp(b))
+        });
+        keys
+    }
+
+    /// query returns the series keys matching a disjunction of conjunctions, i.e.
+    /// `groups[0] OR groups[1] OR ...` where each group is `p0 AND p1 AND ...`, in
+    /// the order of their ids. An empty conjunction matches every series.
+    pub fn query(&self, groups: &[Vec<TagPredicate>]) -> Vec<Vec<u8>> {
+        let mut ids = Bitmap::new();
+        for measurement in self.series.measurement_iterator() {
+            for group in groups {
+                ids |= self.query_measurement(measurement, group.as_slice());
+            }
+        }
+
+        ids.iter()
+            .filter_map(|id| self.series_key(id).map(|x| x.to_vec()))
+            .collect()
+    }
+
+    /// query_measurement returns the series of a measurement matching all
+    /// predicates.
+    fn query_measurement(&self, measurement: &[u8], predicates: &[TagPredicate]) -> Bitmap {
+        let mut ids = self.series.measurement_series_ids(measurement);
+        for predicate in predicates {
+            if ids.is_empty() {
+                break;
+            }
+            ids &= self
+                .series
+                .series_ids(measurement, &predicate.key, &predicate.value);
+        }
+        ids
+    }
+
+    /// check_fence fails with `ShardLockError::Fenced` once the shard lock is
+    /// taken over, e.g. before series are logged.
+    pub async fn check_fence(&self) -> anyhow::Result<()> {
+        match &self.fence {
+            Some(fence) => fence.check().await,
+            None => Ok(()),
+        }
+    }
+
+    /// persist flushes the log file and atomically writes the index.
+    pub async fn persist(&mut self) -> anyhow::Result<()> {
+        self.check_fence().await?;
+
+        self.series.close().await?;
+        let persisted = PersistedIndex {
+            generation: self.generation,
+            log_sequence: self.log_sequence,
+            series: vec![],
+            fields: self
+                .fields
+                .iter()
+                .flat_map(|(measurement, fields)| {
+                    fields
+                        .iter()
+                        .map(|(field, typ)| (measurement.clone(), field.clone(), *typ))
+                })
+                .collect(),
+            field_order: self.field_order.clone(),
+        };
+        self.op.write_atomic(persisted.encode()).await?;
+
+        self.remove_logs().await
+    }
+
+    /// remove_logs removes the log files of other sequences, left by the index
+    /// this one replaces or by an interrupted rebuild.
+    async fn remove_logs(&mut self) -> anyhow::Result<()> {
+        if !self.removes_logs {
+            return Ok(());
+        }
+        for sequence in log_sequences(&self.op).await? {
+            if sequence != self.log_sequence {
+                LogFile::remove(&log_op(&self.op, sequence)).await?;
+            }
+        }
+        self.removes_logs = false;
+        Ok(())
+    }
+}
+
+/// PersistedIndex is the content of the index file.
+struct PersistedIndex {
+    generation: u64,
+    log_sequence: u64,
+    /// series are the series keys of the versions before 4, by id
+    series: Vec<Vec<u8>>,
+    /// (measurement, field, block type)
+    fields: Vec<(Vec<u8>, Vec<u8>, u8)>,
+    field_order: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+}
+
+impl PersistedIndex {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_slice(SHARD_INDEX_MAGIC.as_bytes());
+        buf.put_u8(SHARD_INDEX_VERSION);
+        buf.put_u64(self.generation);
+        buf.put_u64(self.log_sequence);
+
+        let mut fields: HashMap<&[u8], Vec<(&[u8], u8)>> = HashMap::new();
+        for (measurement, field, typ) in self.fields.iter() {
+            fields
+                .entry(measurement.as_slice())
+                .or_default()
+                .push((field.as_slice(), *typ));
+        }
+        buf.put_u64(fields.len() as u64);
+        for (measurement, fields) in fields.iter() {
+            buf.put_u16(measurement.len() as u16);
+            buf.put_slice(measurement);
+            buf.put_u32(fields.len() as u32);
+            for (field, typ) in fields.iter() {
+                buf.put_u16(field.len() as u16);
+                buf.put_slice(field);
+                buf.put_u8(*typ);
+            }
+        }
+
+        buf.put_u64(self.field_order.len() as u64);
+        for (measurement, fields) in self.field_order.iter() {
+            buf.put_u16(measurement.len() as u16);
+            buf.put_slice(measurement.as_slice());
+            buf.put_u32(fields.len() as u32);
+            for field in fields.iter() {
+                buf.put_u16(field.len() as u16);
+                buf.put_slice(field.as_slice());
+            }
+        }
+
+        let crc = crc32fast::hash(buf.as_slice());
+        buf.put_u32(crc);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let header_size = SHARD_INDEX_MAGIC.len() + 1 + 8;
+        if data.len() < header_size + 4 {
+            return Err(anyhow!("shard index too short: {}", data.len()));
+        }
+
+        let (data, mut crc) = data.split_at(data.len() - 4);
+        if crc32fast::hash(data) != crc.get_u32() {
+            return Err(anyhow!("shard index checksum mismatch"));
+        }
+
+        let mut b = data;
+        if &b[..SHARD_INDEX_MAGIC.len()] != SHARD_INDEX_MAGIC.as_bytes() {
+            return Err(anyhow!("invalid shard index"));
+        }
+        b.advance(SHARD_INDEX_MAGIC.len());
+
+        let version = b.get_u8();
+        if version == 0 || version > SHARD_INDEX_VERSION {
+            return Err(anyhow!("unknown shard index version {}", version));
+        }
+
+        let mut index = Self {
+            generation: b.get_u64(),
+            log_sequence: 0,
+            series: vec![],
+            fields: vec![],
+            field_order: HashMap::new(),
+        };
+
+        // versions before 4 hold the series keys rather than a log file
+        if version >= 4 {
+            index.log_sequence = get_u64(&mut b)?;
+        } else {
+            let count = get_u64(&mut b)?;
+            for _ in 0..count {
+                index.series.push(get_bytes(&mut b)?);
+            }
+        }
+
+        // version 1 has no field types
+        if version >= 2 {
+            let count = get_u64(&mut b)?;
+            for _ in 0..count {
+                let measurement = get_bytes(&mut b)?;
+                if b.remaining() < 4 {
+                    return Err(anyhow!("shard index truncated"));
+                }
+                let n = b.get_u32();
+                for _ in 0..n {
+                    let field = get_bytes(&mut b)?;
+                    if b.remaining() < 1 {
+                        return Err(anyhow!("shard index truncated"));
+                    }
+                    index.fields.push((measurement.clone(), field, b.get_u8()));
+                }
+            }
+        }
+
+        // versions before 3 have no field order
+        if version >= 3 {
+            let count = get_u64(&mut b)?;
+            for _ in 0..count {
+                let measurement = get_bytes(&mut b)?;
+                if b.remaining() < 4 {
+                    return Err(anyhow!("shard index truncated"));
+                }
+                let n = b.get_u32();
+                let mut fields = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    fields.push(get_bytes(&mut b)?);
+                }
+                index.field_order.insert(measurement, fields);
+            }
+        }
+
+        // versions before 4 end with the series sketch, which the log file
+        // rebuilds
+        if version < 4 {
+            if b.remaining() < 4 {
+                return Err(anyhow!("shard index truncated"));
+            }
+            let len = b.get_u32() as usize;
+            b.advance(len.min(b.remaining()));
+        }
+        if b.has_remaining() {
+            return Err(anyhow!("shard index truncated"));
+        }
+
+        Ok(index)
+    }
+}
+
+/// log_op returns the log file of the series of the index at `op` with sequence.
+fn log_op(op: &StorageOperator, sequence: u64) -> StorageOperator {
+    op.to_tmp(format!("{}.{}", sequence, LOG_FILE_EXTENSION).as_str())
+}
+
+/// log_sequences returns the sequences of the log files of the index at `op`.
+async fn log_sequences(op: &StorageOperator) -> anyhow::Result<Vec<u64>> {
+    let (dir, name) = match op.path().rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), op.path()),
+    };
+    let prefix = format!("{}.", name);
+    let suffix = format!(".{}", LOG_FILE_EXTENSION);
+
+    let mut sequences = vec![];
+    let mut lister = op.to_op(dir.as_str()).list().await?;
+    while let Some(de) = lister.try_next().await? {
+        let sequence = de
+            .name()
+            .strip_prefix(prefix.as_str())
+            .and_then(|x| x.strip_suffix(suffix.as_str()))
+            .and_then(|x| x.parse::<u64>().ok());
+        sequences.extend(sequence);
+    }
+    Ok(sequences)
+}
+
+/// get_u64 reads a u64.
+fn get_u64(b: &mut &[u8]) -> anyhow::Result<u64> {
+    if b.remaining() < 8 {
+        return Err(anyhow!("shard index truncated"));
+    }
+    Ok(b.get_u64())
+}
+
+/// get_bytes reads a u16 length prefixed byte string.
+fn get_bytes(b: &mut &[u8]) -> anyhow::Result<Vec<u8>> {
+    if b.remaining() < 2 {
+        return Err(anyhow!("shard index truncated"));
+    }
+    let len = b.get_u16() as usize;
+    if b.remaining() < len {
+        return Err(anyhow!("shard index truncated"));
+    }
+    let data = b[..len].to_vec();
+    b.advance(len);
+    Ok(data)
+}
+
+/// split_tsm_key splits a TSM key into its series key and field.
+pub(crate) fn split_tsm_key(key: &[u8]) -> (&[u8], &[u8]) {
+    let sep = KEY_FIELD_SEPARATOR.as_bytes();
+    key.windows(sep.len())
+        .position(|x| x == sep)
+        .map(|i| (&key[..i], &key[i + sep.len()..]))
+        .unwrap_or((key, &[]))
+}
+
+/// series_key_of strips the field from a TSM key.
+fn series_key_of(key: &[u8]) -> &[u8] {
+    split_tsm_key(key).0
+}
+
+/// parse_series_key returns the unescaped measurement and tags of a series key
+/// `measurement,k1=v1,k2=v2`, see `point::parse_series_key`. A malformed key,
+/// which no point maps to, is a measurement without tags.
+pub(crate) fn parse_series_key(series_key: &[u8]) -> (Vec<u8>, Tags) {
+    point::parse_series_key(series_key).unwrap_or_else(|_| (series_key.to_vec(), Tags::new(vec![])))
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::index::shard_index::ShardIndex;
+    use crate::index::tag_index::TagPredicate;
+
+    #[tokio::test]
+    async fn test_shard_index_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut index = ShardIndex::open(op.clone()).await.unwrap();
+        assert_eq!(index.series_count(), 0);
+
+        assert_eq!(
+            index
+                .add_tsm_key(b"cpu,host=a,region=east#!~#value")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            index
+                .add_tsm_key(b"cpu,host=a,region=east#!~#idle")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            index
+                .add_tsm_key(b"cpu,host=b,region=west#!~#value")
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(index.add_field(b"cpu", b"value", 0));
+        assert!(!index.add_field(b"cpu", b"value", 1));
+        index.set_generation(3);
+        index.persist().await.unwrap();
+
+        let mut index = ShardIndex::open(op).await.unwrap();
+        assert_eq!(index.generation(), 3);
+        assert_eq!(index.series_count(), 2);
+        assert_eq!(index.series_cardinality(), 2);
+        assert_eq!(index.series_id(b"cpu,host=b,region=west"), Some(2));
+        assert_eq!(index.field_type(b"cpu", b"value"), Some(0));
+        assert_eq!(index.field_type(b"cpu", b"idle"), None);
+
+        let got = index.query(&[vec![TagPredicate::new(b"region", b"west")]]);
+        assert_eq!(got, vec![b"cpu,host=b,region=west".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_shard_index_escaped_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut index = ShardIndex::open(op).await.unwrap();
+        let key: &[u8] = br"cpu\,x,host=a\,b,region=west\=1";
+        index.add_series(key).await.unwrap();
+        index.add_series(b"cpu,host=a").await.unwrap();
+
+        let got = index.query(&[vec![TagPredicate::new(b"host", b"a,b")]]);
+        assert_eq!(got, vec![key.to_vec()]);
+        let got = index.query(&[vec![TagPredicate::new(b"region", b"west=1")]]);
+        assert_eq!(got, vec![key.to_vec()]);
+        let got = index.query(&[vec![TagPredicate::new(b"host", b"a")]]);
+        assert_eq!(got, vec![b"cpu,host=a".to_vec()]);
+
+        // a malformed key is rejected
+        assert!(index.add_series(b"cpu,host").await.is_err());
+        assert_eq!(index.series_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shard_index_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut index = ShardIndex::open(op.clone()).await.unwrap();
+        index.add_series(b"cpu,host=a").await.unwrap();
+        index.persist().await.unwrap();
+
+        let mut data = tokio::fs::read(&path).await.unwrap();
+        data[10] ^= 0xFF;
+        tokio::fs::write(&path, data).await.unwrap();
+
+        assert!(ShardIndex::open(op).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shard_index_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut index = ShardIndex::open(op.clone()).await.unwrap();
+        index.add_series(b"cpu,host=a").await.unwrap();
+        index.add_series(b"cpu,host=b").await.unwrap();
+        index.persist().await.unwrap();
+        assert!(dir.path().join("index.0.tsl").exists());
+
+        // the persisted index refers to its log until the rebuilt one is persisted
+        let mut rebuilt = ShardIndex::create(op.clone()).await.unwrap();
+        assert_eq!(rebuilt.add_series(b"cpu,host=b").await.unwrap(), 1);
+        assert!(dir.path().join("index.0.tsl").exists());
+        assert!(dir.path().join("index.1.tsl").exists());
+
+        rebuilt.persist().await.unwrap();
+        assert!(!dir.path().join("index.0.tsl").exists());
+
+        let index = ShardIndex::open(op).await.unwrap();
+        assert_eq!(index.series_count(), 1);
+        assert_eq!(index.series_id(b"cpu,host=b"), Some(1));
+        assert_eq!(index.series_key(1), Some(b"cpu,host=b".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_shard_index_field_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut index = ShardIndex::open(op.clone()).await.unwrap();
+        index.record_field_order(b"cpu", &[b"usage", b"idle"]);
+        index.record_field_order(b"cpu", &[b"system", b"idle", b"usage"]);
+        for field in [b"usage".as_slice(), b"idle", b"system", b"nice"] {
+            index.add_field(b"cpu", field, 0);
+        }
+        // a measurement of an older shard, without order
+        for field in [b"used".as_slice(), b"free"] {
+            index.add_field(b"mem", field, 0);
+        }
+        index.persist().await.unwrap();
+
+        let index = ShardIndex::open(op).await.unwrap();
+        // the fields which are not registered yet are left out, unordered ones follow
+        assert_eq!(
+            index.field_keys_ordered(b"cpu"),
+            vec![
+                b"usage".to_vec(),
+                b"idle".to_vec(),
+                b"system".to_vec(),
+                b"nice".to_vec()
+            ]
+        );
+        assert_eq!(
+            index.field_keys_ordered(b"mem"),
+            vec![b"free".to_vec(), b"used".to_vec()]
+        );
+        assert!(index.field_keys_ordered(b"disk").is_empty());
+    }
+}
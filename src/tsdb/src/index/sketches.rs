@@ -0,0 +1,225 @@
+use bytes::{Buf, BufMut};
+use influxdb_storage::StorageOperator;
+use influxdb_utils::estimator::hll::Plus;
+use influxdb_utils::estimator::Sketch;
+
+/// SKETCHES_FILE_EXTENSION is the extension of the sketches persisted next to
+/// the file of an index, see `LogFile`.
+pub const SKETCHES_FILE_EXTENSION: &str = "sketches";
+
+const SKETCHES_MAGIC: &str = "SKCH";
+const SKETCHES_VERSION: u8 = 2;
+
+/// SKETCHES_N is the number of sketches persisted.
+const SKETCHES_N: usize = 4;
+
+/// Sketches estimates the number of series and measurements of an index.
+///
+/// The series sketch holds the id of every created series and the tombstone
+/// sketch the id of every deleted one, the number of series is the difference
+/// of their counts. A series created again gets a new id, so it is counted
+/// again. Measurements are counted the same way, by name: a measurement is
+/// deleted once its last series is, and is not counted again if it is created
+/// again, until the sketches are rebuilt.
+pub struct Sketches {
+    series_sketch: Plus,
+    series_tsketch: Plus,
+    measurement_sketch: Plus,
+    measurement_tsketch: Plus,
+}
+
+impl Sketches {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            series_sketch: Plus::new()?,
+            series_tsketch: Plus::new()?,
+            measurement_sketch: Plus::new()?,
+            measurement_tsketch: Plus::new()?,
+        })
+    }
+
+    /// add_series records the series id of the measurement as created. Adding
+    /// an id again does not change the estimates.
+    pub fn add_series(&mut self, id: u64, measurement: &[u8]) {
+        self.series_sketch.add(&id.to_be_bytes());
+        self.measurement_sketch.add(measurement);
+    }
+
+    /// delete_series records the series id as deleted.
+    pub fn delete_series(&mut self, id: u64) {
+        self.series_tsketch.add(&id.to_be_bytes());
+    }
+
+    /// delete_measurement records the measurement as deleted, e.g. once it has
+    /// no series left.
+    pub fn delete_measurement(&mut self, measurement: &[u8]) {
+        self.measurement_tsketch.add(measurement);
+    }
+
+    /// series_n returns the estimated number of series.
+    pub fn series_n(&mut self) -> u64 {
+        let n = self.series_sketch.count();
+        n.saturating_sub(self.series_tsketch.count())
+    }
+
+    /// measurement_n returns the estimated number of measurements.
+    pub fn measurement_n(&mut self) -> u64 {
+        let n = self.measurement_sketch.count();
+        n.saturating_sub(self.measurement_tsketch.count())
+    }
+
+    /// load returns the sketches persisted at `op` for an index file of `size`
+    /// bytes, None if there are none or they were persisted at another size,
+    /// i.e. they miss the series created or deleted since. Sketches which are
+    /// corrupt, truncated or of another version are None as well, the caller
+    /// rebuilds them.
+    pub async fn load(op: &StorageOperator, size: u64) -> anyhow::Result<Option<Self>> {
+        if !op.exist().await? {
+            return Ok(None);
+        }
+
+        let data = op.operator().read(op.path()).await?;
+        let (sketches, persisted_size) = match Self::decode(data.as_slice()) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!("ignoring the sketches {}: {}", op.path(), e);
+                return Ok(None);
+            }
+        };
+        Ok(if persisted_size == size {
+            Some(sketches)
+        } else {
+            None
+        })
+    }
+
+    /// persist atomically writes the sketches of an index file of `size` bytes.
+    pub async fn persist(&self, op: &StorageOperator, size: u64) -> anyhow::Result<()> {
+        let data = self.encode(size)?;
+        op.write_atomic(data).await?;
+        Ok(())
+    }
+
+    fn encode(&self, size: u64) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.put_slice(SKETCHES_MAGIC.as_bytes());
+        buf.put_u8(SKETCHES_VERSION);
+        buf.put_u64(size);
+
+        for sketch in [
+            &self.series_sketch,
+            &self.series_tsketch,
+            &self.measurement_sketch,
+            &self.measurement_tsketch,
+        ] {
+            let data = sketch.encode()?;
+            buf.put_u32(data.len() as u32);
+            buf.put_slice(data.as_slice());
+        }
+
+        let crc = crc32fast::hash(buf.as_slice());
+        buf.put_u32(crc);
+
+        Ok(buf)
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<(Self, u64)> {
+        let header_size = SKETCHES_MAGIC.len() + 1 + 8;
+        if data.len() < header_size + 4 {
+            return Err(anyhow!("sketches too short: {}", data.len()));
+        }
+
+        let (data, mut crc) = data.split_at(data.len() - 4);
+        if crc32fast::hash(data) != crc.get_u32() {
+            return Err(anyhow!("sketches checksum mismatch"));
+        }
+
+        let mut b = data;
+        if &b[..SKETCHES_MAGIC.len()] != SKETCHES_MAGIC.as_bytes() {
+            return Err(anyhow!("invalid sketches"));
+        }
+        b.advance(SKETCHES_MAGIC.len());
+
+        let version = b.get_u8();
+        if version != SKETCHES_VERSION {
+            return Err(anyhow!("unknown sketches version {}", version));
+        }
+        let size = b.get_u64();
+
+        let mut sketches = Vec::with_capacity(SKETCHES_N);
+        for _ in 0..SKETCHES_N {
+            if b.remaining() < 4 {
+                return Err(anyhow!("sketches truncated"));
+            }
+            let len = b.get_u32() as usize;
+            if b.remaining() < len {
+                return Err(anyhow!("sketches truncated"));
+            }
+            sketches.push(Plus::decode(&b[..len])?);
+            b.advance(len);
+        }
+        if b.has_remaining() {
+            return Err(anyhow!("sketches has {} trailing bytes", b.remaining()));
+        }
+
+        let measurement_tsketch = sketches.pop().unwrap();
+        let measurement_sketch = sketches.pop().unwrap();
+        let series_tsketch = sketches.pop().unwrap();
+        let series_sketch = sketches.pop().unwrap();
+        Ok((
+            Self {
+                series_sketch,
+                series_tsketch,
+                measurement_sketch,
+                measurement_tsketch,
+            },
+            size,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::index::sketches::{Sketches, SKETCHES_FILE_EXTENSION};
+
+    #[tokio::test]
+    async fn test_sketches_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SKETCHES_FILE_EXTENSION);
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+        assert!(Sketches::load(&op, 0).await.unwrap().is_none());
+
+        let mut sketches = Sketches::new().unwrap();
+        for id in 0..100 {
+            sketches.add_series(id, format!("m{}", id % 4).as_bytes());
+            if id % 4 == 0 {
+                sketches.delete_series(id);
+            }
+        }
+        // the series of m0 are all deleted
+        sketches.delete_measurement(b"m0");
+        let series_n = sketches.series_n();
+        assert!((73..=77).contains(&series_n), "series_n {}", series_n);
+        assert_eq!(sketches.measurement_n(), 3);
+        sketches.persist(&op, 1024).await.unwrap();
+
+        let mut loaded = Sketches::load(&op, 1024).await.unwrap().unwrap();
+        assert_eq!(loaded.series_n(), series_n);
+        assert_eq!(loaded.measurement_n(), 3);
+
+        // persisted before the index file grew
+        assert!(Sketches::load(&op, 2048).await.unwrap().is_none());
+
+        // corrupt or truncated sketches are rebuilt by the caller
+        let data = std::fs::read(&path).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[8] ^= 0x01;
+        std::fs::write(&path, corrupt).unwrap();
+        assert!(Sketches::load(&op, 1024).await.unwrap().is_none());
+
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert!(Sketches::load(&op, 1024).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{BTreeSet, HashMap};
+
+use common_base::point::Tags;
+
+use crate::series::series_partition::SeriesPartition;
+
+/// SeriesIDSet is a sorted set of series ids.
+pub type SeriesIDSet = BTreeSet<u64>;
+
+/// TagPredicate matches series having the tag `key=value`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagPredicate {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl TagPredicate {
+    pub fn new(key: &[u8], value: &[u8]) -> Self {
+        Self {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+}
+
+/// TagIndex is an in-memory inverted index from tag key/value pairs to series ids.
+#[derive(Default)]
+pub struct TagIndex {
+    /// map: tag key -> tag value -> series ids
+    postings: HashMap<Vec<u8>, HashMap<Vec<u8>, SeriesIDSet>>,
+    /// all indexed series ids
+    series: SeriesIDSet,
+}
+
+impl TagIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// insert_series indexes the tags of a series.
+    pub fn insert_series(&mut self, id: u64, tags: &Tags) {
+        for tag in tags.iter() {
+            self.postings
+                .entry(tag.key.clone())
+                .or_default()
+                .entry(tag.value.clone())
+                .or_default()
+                .insert(id);
+        }
+        self.series.insert(id);
+    }
+
+    /// remove_series drops a series from the index.
+    pub fn remove_series(&mut self, id: u64, tags: &Tags) {
+        for tag in tags.iter() {
+            if let Some(values) = self.postings.get_mut(&tag.key) {
+                if let Some(ids) = values.get_mut(&tag.value) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        values.remove(&tag.value);
+                    }
+                }
+                if values.is_empty() {
+                    self.postings.remove(&tag.key);
+                }
+            }
+        }
+        self.series.remove(&id);
+    }
+
+    /// series_count returns the number of indexed series.
+    pub fn series_count(&self) -> usize {
+        self.series.len()
+    }
+
+    fn matches(&self, predicate: &TagPredicate) -> Option<&SeriesIDSet> {
+        self.postings
+            .get(&predicate.key)
+            .and_then(|values| values.get(&predicate.value))
+    }
+
+    /// query_and returns the series matching all predicates. An empty
+    /// conjunction matches every series.
+    fn query_and(&self, predicates: &[TagPredicate]) -> SeriesIDSet {
+        let mut sets = Vec::with_capacity(predicates.len());
+        for predicate in predicates {
+            match self.matches(predicate) {
+                Some(ids) => sets.push(ids),
+                None => return SeriesIDSet::new(),
+            }
+        }
+
+        // Intersect starting from the smallest set.
+        sets.sort_by_key(|ids| ids.len());
+        let mut itr = sets.into_iter();
+        let mut ids = match itr.next() {
+            Some(ids) => ids.clone(),
+            None => return self.series.clone(),
+        };
+        for other in itr {
+            ids.retain(|id| other.contains(id));
+            if ids.is_empty() {
+                break;
+            }
+        }
+        ids
+    }
+
+    /// query returns the series matching a disjunction of conjunctions, i.e.
+    /// `groups[0] OR groups[1] OR ...` where each group is `p0 AND p1 AND ...`.
+    pub fn query(&self, groups: &[Vec<TagPredicate>]) -> SeriesIDSet {
+        let mut ids = SeriesIDSet::new();
+        for group in groups {
+            ids.extend(self.query_and(group.as_slice()));
+        }
+        ids
+    }
+
+    /// query_keys returns the series keys of the series matching `groups`,
+    /// resolving ids through the series partition. Ids without a key, e.g.
+    /// deleted series, are skipped.
+    pub async fn query_keys(
+        &self,
+        groups: &[Vec<TagPredicate>],
+        series: &SeriesPartition,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let ids = self.query(groups);
+
+        let mut keys = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(key) = series.series_key(id).await? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common_base::point::{Tag, Tags};
+    use influxdb_storage::StorageOperator;
+
+    use crate::index::tag_index::{SeriesIDSet, TagIndex, TagPredicate};
+    use crate::series::series_key::encode_series_key;
+    use crate::series::series_partition::SeriesPartition;
+
+    fn tags(kvs: &[(&str, &str)]) -> Tags {
+        Tags::new(
+            kvs.iter()
+                .map(|(k, v)| Tag::new(k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    fn new_index() -> TagIndex {
+        let mut index = TagIndex::new();
+        index.insert_series(1, &tags(&[("host", "a"), ("region", "east")]));
+        index.insert_series(2, &tags(&[("host", "a"), ("region", "west")]));
+        index.insert_series(3, &tags(&[("host", "b"), ("region", "west")]));
+        index.insert_series(4, &tags(&[("host", "c"), ("region", "east")]));
+        index
+    }
+
+    #[test]
+    fn test_query_or() {
+        let index = new_index();
+
+        let host_a = vec![TagPredicate::new(b"host", b"a")];
+        let region_west = vec![TagPredicate::new(b"region", b"west")];
+
+        let got = index.query(&[host_a.clone()]);
+        assert_eq!(got, SeriesIDSet::from([1, 2]));
+
+        let got = index.query(&[region_west.clone()]);
+        assert_eq!(got, SeriesIDSet::from([2, 3]));
+
+        let got = index.query(&[host_a, region_west]);
+        assert_eq!(got, SeriesIDSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_query_and_or() {
+        let index = new_index();
+
+        // (host=a AND region=east) OR (host=c)
+        let got = index.query(&[
+            vec![
+                TagPredicate::new(b"host", b"a"),
+                TagPredicate::new(b"region", b"east"),
+            ],
+            vec![TagPredicate::new(b"host", b"c")],
+        ]);
+        assert_eq!(got, SeriesIDSet::from([1, 4]));
+
+        // unknown tag value matches nothing
+        let got = index.query(&[vec![TagPredicate::new(b"host", b"z")]]);
+        assert!(got.is_empty());
+
+        // empty conjunction matches everything
+        let got = index.query(&[vec![]]);
+        assert_eq!(got, SeriesIDSet::from([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_remove_series() {
+        let mut index = new_index();
+        index.remove_series(2, &tags(&[("host", "a"), ("region", "west")]));
+
+        let got = index.query(&[vec![TagPredicate::new(b"host", b"a")]]);
+        assert_eq!(got, SeriesIDSet::from([1]));
+        assert_eq!(index.series_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_query_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/00/", dir.path().to_str().unwrap());
+        let partition = SeriesPartition::new(0, StorageOperator::root(&path).unwrap())
+            .await
+            .unwrap();
+
+        let series = vec![
+            tags(&[("host", "a"), ("region", "east")]),
+            tags(&[("host", "a"), ("region", "west")]),
+            tags(&[("host", "b"), ("region", "west")]),
+        ];
+        let keys: Vec<Vec<u8>> = series
+            .iter()
+            .map(|t| encode_series_key(b"cpu", t))
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+
+        let mut ids = vec![0; keys.len()];
+        partition
+            .create_series_list_if_not_exists(&key_refs, &vec![0; keys.len()], &mut ids)
+            .await
+            .unwrap();
+
+        let mut index = TagIndex::new();
+        for (id, tags) in ids.iter().zip(series.iter()) {
+            index.insert_series(*id, tags);
+        }
+
+        let got = index
+            .query_keys(&[vec![TagPredicate::new(b"host", b"a")]], &partition)
+            .await
+            .unwrap();
+        assert_eq!(got, vec![keys[0].clone(), keys[1].clone()]);
+
+        let got = index
+            .query_keys(&[vec![TagPredicate::new(b"region", b"north")]], &partition)
+            .await
+            .unwrap();
+        assert!(got.is_empty());
+    }
+}
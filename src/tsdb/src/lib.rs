@@ -5,7 +5,6 @@ extern crate async_trait;
 #[macro_use]
 extern crate lazy_static;
 
-// pub mod cache;
 pub mod common;
 pub mod engine;
 pub mod field;
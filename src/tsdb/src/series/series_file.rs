@@ -1,2 +1,574 @@
+use common_base::iterator::{AsyncIterator, AsyncIterators};
+use futures::TryStreamExt;
+use influxdb_storage::{path_join, StorageOperator};
+use influxdb_utils::hash::hash_key;
+
+use crate::series::series_partition::SeriesPartition;
+use crate::series::series_segment::{SeriesEntry, SeriesEntryIterator, SeriesSegment};
+
 /// SERIES_FILE_PARTITION_N is the number of partitions a series file is split into.
 pub(crate) const SERIES_FILE_PARTITION_N: usize = 8;
+
+/// PARTITION_N_FILE_NAME is the object recording the number of partitions of a
+/// series file, see `SeriesFile::check_partition_n`.
+const PARTITION_N_FILE_NAME: &str = "PARTITIONS";
+
+/// SeriesFileIterator iterates the entries of all segments of a series file in order,
+/// yielding `(entry, offset, size)`. The offset packs the segment id and the position
+/// in the segment, see `SeriesOffset`.
+pub type SeriesFileIterator = AsyncIterators<(SeriesEntry, u64, usize), SeriesEntryIterator>;
+
+/// SeriesFileOptions configures a series file.
+#[derive(Clone)]
+pub struct SeriesFileOptions {
+    /// partition_n is the number of partitions the series are split into. It
+    /// must not change once series were created, as it is part of their ids:
+    /// the series file records it and can not be opened with another one.
+    pub partition_n: usize,
+    /// max_segment_size is the size a segment may grow to before the partition
+    /// rolls to a new one. None grows the segments with their id, see
+    /// `series_segment_size`.
+    pub max_segment_size: Option<u32>,
+}
+
+impl Default for SeriesFileOptions {
+    fn default() -> Self {
+        Self {
+            partition_n: SERIES_FILE_PARTITION_N,
+            max_segment_size: None,
+        }
+    }
+}
+
+impl SeriesFileOptions {
+    pub(crate) fn apply_max_segment_size(&self, segment: SeriesSegment) -> SeriesSegment {
+        match self.max_segment_size {
+            Some(max_segment_size) => segment.with_max_file_size(max_segment_size),
+            None => segment,
+        }
+    }
+}
+
+/// SeriesFile is the set of partitions, `00`, `01`, ..., in a directory, each
+/// a set of segments `0000`, `0001`, ...
+///
+/// A series key belongs to the partition of its hash modulo the number of
+/// partitions, which assigns its id, see `SeriesPartition`. The partition of an
+/// id is encoded in its low bits, see `series_id_partition`.
+pub struct SeriesFile {
+    op: StorageOperator,
+    partitions: Vec<SeriesPartition>,
+}
+
+impl SeriesFile {
+    /// open opens the partitions in the directory `op`.
+    pub async fn open(op: StorageOperator) -> anyhow::Result<Self> {
+        Self::open_with_options(op, SeriesFileOptions::default()).await
+    }
+
+    /// open_with_options opens the partitions in the directory `op` with
+    /// `options`, creating the missing ones.
+    pub async fn open_with_options(
+        op: StorageOperator,
+        options: SeriesFileOptions,
+    ) -> anyhow::Result<Self> {
+        if options.partition_n == 0 || options.partition_n > u16::MAX as usize {
+            return Err(anyhow!(
+                "invalid number of series file partitions: {}",
+                options.partition_n
+            ));
+        }
+
+        let op = if op.path().ends_with('/') {
+            op
+        } else {
+            op.to_op(format!("{}/", op.path()).as_str())
+        };
+        op.create_dir().await?;
+        Self::check_partition_n(&op, options.partition_n).await?;
+
+        let mut partitions = Vec::with_capacity(options.partition_n);
+        for i in 0..options.partition_n {
+            let partition_op = op.to_op(format!("{}{:02}/", op.path(), i).as_str());
+            partitions.push(SeriesPartition::with_options(i as u16, partition_op, &options).await?);
+        }
+
+        Ok(Self { op, partitions })
+    }
+
+    /// check_partition_n records the number of partitions of the series file
+    /// `op` when it is created and rejects opening it with another number, which
+    /// would resolve the ids to other partitions. A series file created before
+    /// the number was recorded is rejected if it has a partition directory
+    /// beyond `partition_n`.
+    async fn check_partition_n(op: &StorageOperator, partition_n: usize) -> anyhow::Result<()> {
+        let partition_n_op = op.to_op(path_join(op.path(), PARTITION_N_FILE_NAME).as_str());
+        if partition_n_op.exist().await? {
+            let data = op.operator().read(partition_n_op.path()).await?;
+            let recorded = std::str::from_utf8(data.as_slice())?
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow!("{} is corrupt: {}", partition_n_op.path(), e))?;
+            if recorded != partition_n {
+                return Err(anyhow!(
+                    "series file {} has {} partitions, can not open it with {}",
+                    op.path(),
+                    recorded,
+                    partition_n
+                ));
+            }
+            return Ok(());
+        }
+
+        let mut lister = op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            let partition = de
+                .name()
+                .strip_suffix('/')
+                .and_then(|x| x.parse::<u16>().ok());
+            if let Some(partition) = partition.filter(|x| *x as usize >= partition_n) {
+                return Err(anyhow!(
+                    "series file {} has the partition {:02}, can not open it with {} partitions",
+                    op.path(),
+                    partition,
+                    partition_n
+                ));
+            }
+        }
+
+        partition_n_op
+            .write_atomic(format!("{}\n", partition_n).into_bytes())
+            .await?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        self.op.path()
+    }
+
+    pub fn partitions(&self) -> &[SeriesPartition] {
+        self.partitions.as_slice()
+    }
+
+    /// series_key_partition returns the partition of a series key.
+    pub fn series_key_partition(&self, key: &[u8]) -> &SeriesPartition {
+        let i = hash_key(key) % self.partitions.len() as u64;
+        &self.partitions[i as usize]
+    }
+
+    /// series_id_partition returns the partition which assigned a series id,
+    /// None for the id 0, which is never assigned. A partition assigns the ids
+    /// `i + 1`, `i + 1 + n`, `i + 1 + 2n`, ... where `i` is its index and `n`
+    /// the number of partitions.
+    pub fn series_id_partition(&self, id: u64) -> Option<&SeriesPartition> {
+        if id == 0 {
+            return None;
+        }
+        let i = (id - 1) % self.partitions.len() as u64;
+        Some(&self.partitions[i as usize])
+    }
+
+    /// series_id returns the id of the series key, None if the series does not
+    /// exist or was deleted.
+    pub async fn series_id(&self, key: &[u8]) -> anyhow::Result<Option<u64>> {
+        self.series_key_partition(key).series_id(key).await
+    }
+
+    /// series_key returns the key of the series id, None if the series does not
+    /// exist or was deleted.
+    pub async fn series_key(&self, id: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.series_id_partition(id) {
+            Some(partition) => partition.series_key(id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// is_deleted returns true if the id is not the id of a live series.
+    pub async fn is_deleted(&self, id: u64) -> anyhow::Result<bool> {
+        match self.series_id_partition(id) {
+            Some(partition) => partition.is_deleted(id).await,
+            None => Ok(true),
+        }
+    }
+
+    /// create_series_if_not_exists returns the id of the series key, appending an
+    /// insert entry to its partition with a new id if the series does not exist.
+    /// A deleted series is created again with a new id.
+    pub async fn create_series_if_not_exists(&self, key: &[u8]) -> anyhow::Result<u64> {
+        self.series_key_partition(key)
+            .create_series_if_not_exists(key)
+            .await
+    }
+
+    /// create_series_list_if_not_exists returns the ids of the series keys,
+    /// creating the series which do not exist.
+    pub async fn create_series_list_if_not_exists(
+        &self,
+        keys: &[&[u8]],
+    ) -> anyhow::Result<Vec<u64>> {
+        let key_partition_ids: Vec<u16> = keys
+            .iter()
+            .map(|key| self.series_key_partition(key).id())
+            .collect();
+
+        let mut ids = vec![0; keys.len()];
+        for partition in self.partitions.iter() {
+            if !key_partition_ids.contains(&partition.id()) {
+                continue;
+            }
+            partition
+                .create_series_list_if_not_exists(keys, key_partition_ids.as_slice(), &mut ids)
+                .await?;
+        }
+
+        Ok(ids)
+    }
+
+    /// delete_series_id appends a tombstone entry for the series id to its
+    /// partition. The series must be created again, with a new id, to be written
+    /// to. Deleting an id which is not the id of a live series is a no-op.
+    pub async fn delete_series_id(&self, id: u64) -> anyhow::Result<()> {
+        match self.series_id_partition(id) {
+            Some(partition) => partition.delete_series_id(id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// series_count returns the number of series of all partitions.
+    pub async fn series_count(&self) -> u64 {
+        let mut n = 0;
+        for partition in self.partitions.iter() {
+            n += partition.series_count().await;
+        }
+        n
+    }
+
+    /// close flushes and releases the write handles of the partitions.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        for partition in self.partitions.iter() {
+            partition.close().await?;
+        }
+        Ok(())
+    }
+
+    /// series_iterator returns an iterator over the entries of all segments,
+    /// partition by partition.
+    pub async fn series_iterator(&self) -> anyhow::Result<SeriesFileIterator> {
+        let mut itrs = vec![];
+        for partition in self.partitions.iter() {
+            for segment in partition.segments().await.iter() {
+                itrs.push(segment.series_iterator(0).await?);
+            }
+        }
+
+        Ok(AsyncIterators::new(itrs))
+    }
+
+    /// find_series returns the last entry of the series id, with its offset, or
+    /// None if the id is in no segment of its partition. The entry is a tombstone
+    /// if the series was deleted.
+    ///
+    /// Only insert entries are indexed by id, so this scans the segments of the
+    /// partition.
+    pub async fn find_series(&self, id: u64) -> anyhow::Result<Option<(SeriesEntry, u64)>> {
+        let partition = match self.series_id_partition(id) {
+            Some(partition) => partition,
+            None => return Ok(None),
+        };
+
+        let mut found = None;
+        let mut itr = partition.series_iterator().await?;
+        while let Some((entry, offset, _size)) = itr.try_next().await? {
+            if entry.id() == id {
+                found = Some((entry, offset));
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use common_base::iterator::AsyncIterator;
+    use influxdb_storage::StorageOperator;
+
+    use crate::series::series_file::{SeriesFile, SeriesFileOptions, SERIES_FILE_PARTITION_N};
+    use crate::series::series_segment::{
+        split_series_offset, SeriesEntry, SeriesEntryFlag, SeriesOffset, SeriesSegment,
+        SERIES_SEGMENT_HEADER_SIZE,
+    };
+
+    #[tokio::test]
+    async fn test_series_file_iterator() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        // the ids of partition 0
+        let segments = [
+            vec![b"cpu,host=a".to_vec(), b"cpu,host=b".to_vec()],
+            vec![b"mem,host=a".to_vec()],
+        ];
+        let mut expected = vec![];
+        let mut id = 1;
+        for (segment_id, keys) in segments.iter().enumerate() {
+            let op = StorageOperator::root(&format!("{}/00/{:04}", path, segment_id)).unwrap();
+            let mut segment = SeriesSegment::create(segment_id as u16, op).await.unwrap();
+            for key in keys {
+                let entry = SeriesEntry::new(SeriesEntryFlag::InsertFlag(key.clone()), id);
+                let offset = segment.append(&entry).await.unwrap();
+                expected.push((entry, offset));
+                id += SERIES_FILE_PARTITION_N as u64;
+            }
+            segment.close().await.unwrap();
+        }
+
+        let file = SeriesFile::open(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(file.partitions().len(), SERIES_FILE_PARTITION_N);
+        assert_eq!(file.partitions()[0].segments().await.len(), 2);
+
+        let mut itr = file.series_iterator().await.unwrap();
+        let mut got = vec![];
+        while let Some((entry, offset, _size)) = itr.try_next().await.unwrap() {
+            got.push((entry, offset));
+        }
+        assert_eq!(got, expected);
+        assert!(itr.try_next().await.unwrap().is_none());
+
+        // the first entry of the second segment
+        let (_, offset) = &got[2];
+        assert_eq!(
+            split_series_offset(*offset),
+            (1, SERIES_SEGMENT_HEADER_SIZE as u32)
+        );
+        assert_eq!(
+            *offset,
+            SeriesOffset::join(1, SERIES_SEGMENT_HEADER_SIZE as u32).0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_series_file_find_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        // the ids of partition 0
+        let entries = [
+            vec![
+                SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=a".to_vec()), 1),
+                SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=b".to_vec()), 9),
+            ],
+            vec![SeriesEntry::new(SeriesEntryFlag::TombstoneFlag, 1)],
+        ];
+        let mut offsets = vec![];
+        for (segment_id, entries) in entries.iter().enumerate() {
+            let op = StorageOperator::root(&format!("{}/00/{:04}", path, segment_id)).unwrap();
+            let mut segment = SeriesSegment::create(segment_id as u16, op).await.unwrap();
+            for entry in entries {
+                offsets.push(segment.append(entry).await.unwrap());
+            }
+            segment.close().await.unwrap();
+        }
+
+        let file = SeriesFile::open(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+
+        let (entry, offset) = file.find_series(9).await.unwrap().unwrap();
+        assert_eq!(entry, entries[0][1]);
+        assert_eq!(offset, offsets[1]);
+        assert_eq!(
+            file.series_key(9).await.unwrap(),
+            Some(b"cpu,host=b".to_vec())
+        );
+
+        // the tombstone in the second segment is the last entry of series 1
+        let (entry, offset) = file.find_series(1).await.unwrap().unwrap();
+        assert!(entry.flag().is_tombstone());
+        assert_eq!(
+            split_series_offset(offset),
+            (1, SERIES_SEGMENT_HEADER_SIZE as u32)
+        );
+        assert!(file.is_deleted(1).await.unwrap());
+        assert!(file.series_key(1).await.unwrap().is_none());
+
+        assert!(file.find_series(3).await.unwrap().is_none());
+        assert!(file.find_series(0).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_series_file_create_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let options = SeriesFileOptions {
+            max_segment_size: Some(16 * 1024),
+            ..Default::default()
+        };
+
+        let keys: Vec<Vec<u8>> = (0..10_000)
+            .map(|i| format!("cpu,host=server{:05}", i).into_bytes())
+            .collect();
+
+        let file =
+            SeriesFile::open_with_options(StorageOperator::root(path).unwrap(), options.clone())
+                .await
+                .unwrap();
+        let mut ids = vec![];
+        for key in keys.iter() {
+            let id = file.create_series_if_not_exists(key).await.unwrap();
+            // the id encodes the partition of the key
+            assert_eq!(
+                file.series_id_partition(id).unwrap().id(),
+                file.series_key_partition(key).id()
+            );
+            ids.push(id);
+        }
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), keys.len());
+        assert_eq!(file.series_count().await, keys.len() as u64);
+        for partition in file.partitions() {
+            assert!(partition.segments().await.len() > 1);
+        }
+
+        // existing series keep their id
+        let id = file.create_series_if_not_exists(&keys[42]).await.unwrap();
+        assert_eq!(id, ids[42]);
+        assert_eq!(
+            file.series_key(ids[42]).await.unwrap(),
+            Some(keys[42].clone())
+        );
+
+        file.delete_series_id(ids[0]).await.unwrap();
+        assert!(file.series_id(&keys[0]).await.unwrap().is_none());
+        assert!(file.is_deleted(ids[0]).await.unwrap());
+        file.close().await.unwrap();
+
+        let file = SeriesFile::open_with_options(StorageOperator::root(path).unwrap(), options)
+            .await
+            .unwrap();
+        for (key, id) in keys.iter().zip(ids.iter()).skip(1) {
+            assert_eq!(file.series_id(key).await.unwrap(), Some(*id));
+            assert_eq!(file.series_key(*id).await.unwrap().as_ref(), Some(key));
+        }
+        assert!(file.series_id(&keys[0]).await.unwrap().is_none());
+        assert!(file.series_key(ids[0]).await.unwrap().is_none());
+        assert!(file.series_id(b"mem,host=a").await.unwrap().is_none());
+
+        // a deleted series is created again with a new id of its partition
+        let id = file.create_series_if_not_exists(&keys[0]).await.unwrap();
+        assert!(!ids.contains(&id));
+        assert_eq!(
+            file.series_id_partition(id).unwrap().id(),
+            file.series_id_partition(ids[0]).unwrap().id()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_series_file_partition_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let options = |partition_n| SeriesFileOptions {
+            partition_n,
+            ..Default::default()
+        };
+
+        let file = SeriesFile::open_with_options(StorageOperator::root(path).unwrap(), options(4))
+            .await
+            .unwrap();
+        let id = file
+            .create_series_if_not_exists(b"cpu,host=a")
+            .await
+            .unwrap();
+        file.close().await.unwrap();
+
+        for partition_n in [2, 8] {
+            let op = StorageOperator::root(path).unwrap();
+            assert!(SeriesFile::open_with_options(op, options(partition_n))
+                .await
+                .is_err());
+        }
+
+        let file = SeriesFile::open_with_options(StorageOperator::root(path).unwrap(), options(4))
+            .await
+            .unwrap();
+        assert_eq!(file.series_id(b"cpu,host=a").await.unwrap(), Some(id));
+        file.close().await.unwrap();
+
+        // a series file without the recorded number can not shrink
+        std::fs::remove_file(dir.path().join("PARTITIONS")).unwrap();
+        let op = StorageOperator::root(path).unwrap();
+        assert!(SeriesFile::open_with_options(op, options(2)).await.is_err());
+        let op = StorageOperator::root(path).unwrap();
+        SeriesFile::open_with_options(op, options(4)).await.unwrap();
+        assert!(dir.path().join("PARTITIONS").exists());
+    }
+
+    #[tokio::test]
+    async fn test_series_file_create_series_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let file = SeriesFile::open(StorageOperator::root(path).unwrap())
+            .await
+            .unwrap();
+
+        let id = file
+            .create_series_if_not_exists(b"cpu,host=a")
+            .await
+            .unwrap();
+        let keys: Vec<&[u8]> = vec![b"cpu,host=a", b"cpu,host=b", b"mem,host=a", b"cpu,host=b"];
+        let ids = file.create_series_list_if_not_exists(&keys).await.unwrap();
+        assert_eq!(ids[0], id);
+        assert_eq!(ids[1], ids[3]);
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 3);
+        for (key, id) in keys.iter().zip(ids.iter()) {
+            assert_eq!(file.series_id(key).await.unwrap(), Some(*id));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_series_file_concurrent_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let file = Arc::new(
+            SeriesFile::open(StorageOperator::root(path).unwrap())
+                .await
+                .unwrap(),
+        );
+
+        // the tasks create overlapping ranges of keys, spread over all partitions
+        let mut handles = vec![];
+        for t in 0..16 {
+            let file = file.clone();
+            handles.push(tokio::spawn(async move {
+                let mut created = vec![];
+                for i in (t * 50)..(t * 50 + 200) {
+                    let key = format!("cpu,host=server{}", i).into_bytes();
+                    let id = file.create_series_if_not_exists(&key).await.unwrap();
+                    created.push((key, id));
+                }
+                created
+            }));
+        }
+
+        let mut key_ids = vec![];
+        for handle in handles {
+            key_ids.extend(handle.await.unwrap());
+        }
+
+        let keys: HashSet<&Vec<u8>> = key_ids.iter().map(|(key, _)| key).collect();
+        let ids: HashSet<u64> = key_ids.iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids.len(), keys.len());
+        assert_eq!(file.series_count().await, keys.len() as u64);
+
+        // every task saw the same id for a key
+        for (key, id) in key_ids.iter() {
+            assert_eq!(file.series_id(key).await.unwrap(), Some(*id));
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::io::SeekFrom;
 
+use common_base::iterator::AsyncIterator;
 use influxdb_storage::StorageOperator;
 use influxdb_utils::hash::{distance, hash_key};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
@@ -8,7 +9,7 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, As
 use crate::common::Section;
 use crate::series::series_segment::{
     read_series_key_from_segments, SeriesEntry, SeriesEntryFlag, SeriesOffset, SeriesSegment,
-    SERIES_ENTRY_HEADER_SIZE,
+    SERIES_ENTRY_HEADER_SIZE, SERIES_SEGMENT_HEADER_SIZE,
 };
 
 const SERIES_INDEX_VERSION: u8 = 1;
@@ -16,16 +17,14 @@ const SERIES_INDEX_MAGIC: &'static str = "SIDX";
 
 /// offset + id
 const SERIES_INDEX_ELEM_SIZE: u32 = 16;
-// /// rhh load factor
-// const SERIES_INDEX_LOAD_FACTOR: u32 = 90;
+/// rhh load factor
+const SERIES_INDEX_LOAD_FACTOR: u64 = 90;
 
-// const SERIES_INDEX_HEADER_SIZE: u32 = 0 +
-//     4 + 1 + // magic + version
-//     8 + 8 + // max series + max offset
-//     8 + 8 + // count + capacity
-//     8 + 8 + // key/id map offset & size
-//     8 + 8 + // id/offset map offset & size
-//     0;
+const SERIES_INDEX_HEADER_SIZE: u32 = 4 + 1 // magic + version
+    + 8 + 8 // max series + max offset
+    + 8 + 8 // count + capacity
+    + 8 + 8 // key/id map offset & size
+    + 8 + 8; // id/offset map offset & size
 
 ///SeriesIndexHeader represents the header of a series index.
 #[derive(Default)]
@@ -196,6 +195,39 @@ impl SeriesIndex {
         }
     }
 
+    /// max_series_id returns the highest series id ever assigned by the index.
+    pub fn max_series_id(&self) -> u64 {
+        self.hdr.max_series_id
+    }
+
+    /// recover indexes the entries of the segments written after the on-disk
+    /// index, i.e. past its max offset.
+    pub async fn recover(&mut self, segments: &[SeriesSegment]) -> anyhow::Result<()> {
+        let max_offset = self.hdr.max_offset;
+        let (min_segment_id, max_pos) = max_offset.split();
+        for segment in segments {
+            if segment.id() < min_segment_id {
+                continue;
+            }
+
+            // start at the last indexed entry of its segment, which is skipped below.
+            let series_pos = if max_offset.0 != 0 && segment.id() == min_segment_id {
+                max_pos - SERIES_SEGMENT_HEADER_SIZE as u32
+            } else {
+                0
+            };
+            let mut itr = segment.series_iterator(series_pos).await?;
+            while let Some((entry, offset, _size)) = itr.try_next().await? {
+                if offset <= max_offset.0 {
+                    continue;
+                }
+                self.exec_entry(entry, SeriesOffset(offset));
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn find_id_by_series_key(
         &self,
         segments: &[SeriesSegment],
@@ -208,6 +240,11 @@ impl SeriesIndex {
             }
         }
 
+        // No on-disk index has been written yet.
+        if self.hdr.capacity == 0 {
+            return Ok(0);
+        }
+
         let mask = self.hdr.capacity - 1;
         let hash = hash_key(key);
 
@@ -257,6 +294,10 @@ impl SeriesIndex {
             return Ok(Some(*series_offset));
         }
 
+        if self.hdr.capacity == 0 {
+            return Ok(None);
+        }
+
         let mask = self.hdr.capacity - 1;
         let hash = hash_key(series_id.to_be_bytes().as_slice());
 
@@ -288,3 +329,96 @@ impl SeriesIndex {
         }
     }
 }
+
+/// SeriesIndexElem is a series of an on-disk index: its key, id and the offset
+/// of its insert entry.
+pub(crate) struct SeriesIndexElem {
+    pub key: Vec<u8>,
+    pub id: u64,
+    pub offset: SeriesOffset,
+}
+
+/// encode_series_index encodes the on-disk index of the series: the header
+/// followed by the key/id and the id/offset robin hood hash maps, each a power
+/// of 2 of slots of `SERIES_INDEX_ELEM_SIZE` bytes.
+///
+/// max_series_id is the highest series id ever assigned, which may be the id of
+/// a series which was deleted since.
+pub(crate) async fn encode_series_index(
+    elems: &[SeriesIndexElem],
+    max_series_id: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let count = elems.len() as u64;
+    let capacity = (count * 100 / SERIES_INDEX_LOAD_FACTOR)
+        .next_power_of_two()
+        .max(2);
+    let map_size = capacity * SERIES_INDEX_ELEM_SIZE as u64;
+
+    let mut key_id_map = vec![None; capacity as usize];
+    let mut id_offset_map = vec![None; capacity as usize];
+    let mut max_offset = SeriesOffset::default();
+    for elem in elems {
+        let id = elem.id;
+        let offset = elem.offset.0;
+        rhh_insert(&mut key_id_map, hash_key(elem.key.as_slice()), offset, id);
+        rhh_insert(
+            &mut id_offset_map,
+            hash_key(id.to_be_bytes().as_slice()),
+            id,
+            offset,
+        );
+
+        if elem.offset > max_offset {
+            max_offset = elem.offset;
+        }
+    }
+
+    let mut hdr = SeriesIndexHeader::new();
+    hdr.max_series_id = max_series_id;
+    hdr.max_offset = max_offset;
+    hdr.count = count;
+    hdr.capacity = capacity;
+    hdr.key_id_map = Section::new(SERIES_INDEX_HEADER_SIZE as u64, map_size);
+    hdr.id_offset_map = Section::new(hdr.key_id_map.max_offset(), map_size);
+
+    let mut buf = Vec::with_capacity(hdr.id_offset_map.max_offset() as usize);
+    hdr.write_to(&mut buf).await?;
+    for slot in key_id_map.iter().chain(id_offset_map.iter()) {
+        let (_, v1, v2) = slot.unwrap_or_default();
+        buf.write_u64(v1).await?;
+        buf.write_u64(v2).await?;
+    }
+
+    Ok(buf)
+}
+
+/// rhh_insert inserts the element `(v1, v2)` of hash into the slots, displacing
+/// the elements closer to their home slot.
+fn rhh_insert(slots: &mut [Option<(u64, u64, u64)>], hash: u64, v1: u64, v2: u64) {
+    let capacity = slots.len() as u64;
+    let mask = capacity - 1;
+
+    let mut elem = (hash, v1, v2);
+    let mut d = 0_u64;
+    let mut pos = hash & mask;
+    loop {
+        match slots[pos as usize] {
+            None => {
+                slots[pos as usize] = Some(elem);
+                return;
+            }
+            Some(existing) => {
+                // swap with the existing element if it is closer to its home slot.
+                let existing_d = distance(existing.0, pos as usize, capacity);
+                if existing_d < d {
+                    slots[pos as usize] = Some(elem);
+                    elem = existing;
+                    d = existing_d;
+                }
+            }
+        }
+
+        d += 1;
+        pos = (pos + 1) & mask;
+    }
+}
@@ -1,8 +1,8 @@
 use std::fmt::{Debug, Formatter};
 use std::io::{Cursor, SeekFrom};
-use std::str::from_utf8_unchecked;
 
 use bytes::Buf;
+use common_base::point::Tag;
 use crc32fast::Hasher;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
@@ -34,6 +34,32 @@ pub async fn read_series_key<R: AsyncRead + AsyncSeek + Send + Unpin>(
     Ok((key, v_len + k_len))
 }
 
+/// parse_series_key_name returns the measurement of an encoded series key, or
+/// None if the key is too short to hold it.
+pub fn parse_series_key_name(series_key: &[u8]) -> Option<&[u8]> {
+    if series_key.len() < 2 {
+        return None;
+    }
+    let name_len = u16::from_be_bytes([series_key[0], series_key[1]]) as usize;
+    series_key.get(2..2 + name_len)
+}
+
+/// encode_series_key returns the series key of the measurement name and tags
+/// in the layout `SeriesKeyDecoder` reads.
+pub fn encode_series_key(name: &[u8], tags: &[Tag]) -> Vec<u8> {
+    let mut key = vec![];
+    key.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    key.extend_from_slice(name);
+    tags.len().encode_var_vec(&mut key);
+    for tag in tags {
+        key.extend_from_slice(&(tag.key.len() as u16).to_be_bytes());
+        key.extend_from_slice(tag.key.as_slice());
+        key.extend_from_slice(&(tag.value.len() as u16).to_be_bytes());
+        key.extend_from_slice(tag.value.as_slice());
+    }
+    key
+}
+
 #[derive(Clone)]
 pub struct SeriesKeyDecoder<'a> {
     name: &'a [u8],
@@ -76,11 +102,11 @@ impl<'a> Debug for SeriesKeyDecoder<'a> {
         let mut itr = self.tags_iterator();
         let mut n = f.debug_list();
 
-        let name = unsafe { from_utf8_unchecked(&self.name) };
+        let name = String::from_utf8_lossy(self.name);
         n.entry(&format!("__name__: {}", name));
         while let Some((k, v)) = itr.next().unwrap() {
-            let k = unsafe { from_utf8_unchecked(k) };
-            let v = unsafe { from_utf8_unchecked(v) };
+            let k = String::from_utf8_lossy(k);
+            let v = String::from_utf8_lossy(v);
             if k.len() == 0 || v.len() == 0 {
                 println!("for debug: k or v is empty");
                 panic!("k or v is empty");
@@ -236,3 +262,28 @@ impl<'a> TagsIterator<'a> {
 //         ))
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use common_base::point::Tag;
+
+    use crate::series::series_key::{encode_series_key, parse_series_key_name, SeriesKeyDecoder};
+
+    #[test]
+    fn test_encode_series_key() {
+        let tags = vec![
+            Tag::new(b"host".to_vec(), b"a".to_vec()),
+            Tag::new(b"region".to_vec(), b"east".to_vec()),
+        ];
+        let key = encode_series_key(b"cpu", &tags);
+        assert_eq!(parse_series_key_name(&key), Some(b"cpu".as_slice()));
+
+        let decoder = SeriesKeyDecoder::new(&key);
+        let mut itr = decoder.tags_iterator();
+        for tag in tags.iter() {
+            let (k, v) = itr.next().unwrap().unwrap();
+            assert_eq!((k, v), (tag.key.as_slice(), tag.value.as_slice()));
+        }
+        assert!(itr.next().unwrap().is_none());
+    }
+}
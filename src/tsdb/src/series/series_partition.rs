@@ -1,18 +1,114 @@
+use std::collections::BTreeMap;
+
 use common_base::iterator::{AsyncIterator, AsyncIterators};
 use futures::TryStreamExt;
 use influxdb_storage::{path_join, StorageOperator};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard};
 
-use crate::series::series_file::SERIES_FILE_PARTITION_N;
-use crate::series::series_index::SeriesIndex;
+use crate::series::series_file::{SeriesFileIterator, SeriesFileOptions};
+use crate::series::series_index::{encode_series_index, SeriesIndex, SeriesIndexElem};
 use crate::series::series_segment::{
     parse_series_segment_filename, SeriesEntry, SeriesEntryFlag, SeriesOffset, SeriesSegment,
+    SeriesSegmentError, SERIES_SEGMENT_HEADER_SIZE,
 };
 
+/// COMPACTION_FILE_SUFFIX is the suffix of the segments and the index written by
+/// a compaction until it is committed, see `SeriesPartition::compact`.
+const COMPACTION_FILE_SUFFIX: &str = "compacting";
+
+/// INDEX_FILE_NAME is the name of the on-disk index of a partition.
+const INDEX_FILE_NAME: &str = "index";
+
+/// MANIFEST_FILE_NAME is the object recording the compactions of a partition on
+/// a backend without atomic rename, see `PartitionManifest`.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
 /// DEFAULT_SERIES_PARTITION_COMPACT_THRESHOLD is the number of series IDs to hold in the in-memory
 /// series map before compacting and rebuilding the on-disk representation.
 const DEFAULT_SERIES_PARTITION_COMPACT_THRESHOLD: usize = 1 << 17; // 128K
 
+/// PartitionManifest records the compactions of a partition on a backend without
+/// atomic rename, see `StorageOperator::rename_is_atomic`. There a compaction
+/// writes its segments and index under their final names, which are unique, and
+/// each write of the manifest is a commit point: first of the intent to compact,
+/// so that a crash rolls back the new files, then of the compaction, so that a
+/// crash finishes it.
+#[derive(Clone, Debug, PartialEq)]
+struct PartitionManifest {
+    /// first_segment is the id of the first live segment, the segments before it
+    /// were replaced by a compaction.
+    first_segment: u16,
+    /// index is the name of the live on-disk index.
+    index: String,
+    /// pending is the id of the first segment of a compaction which is written
+    /// but not committed.
+    pending: Option<u16>,
+}
+
+impl Default for PartitionManifest {
+    fn default() -> Self {
+        Self {
+            first_segment: 0,
+            index: INDEX_FILE_NAME.to_string(),
+            pending: None,
+        }
+    }
+}
+
+impl PartitionManifest {
+    /// read returns the manifest of the partition `op`, the default one if it was
+    /// never written.
+    async fn read(op: &StorageOperator) -> anyhow::Result<Self> {
+        let manifest_op = op.to_op(path_join(op.path(), MANIFEST_FILE_NAME).as_str());
+        if !manifest_op.exist().await? {
+            return Ok(Self::default());
+        }
+
+        let data = op.operator().read(manifest_op.path()).await?;
+        Self::decode(data.as_slice())
+            .map_err(|e| anyhow!("manifest {} is corrupt: {}", manifest_op.path(), e))
+    }
+
+    /// write replaces the manifest of the partition `op`.
+    async fn write(&self, op: &StorageOperator) -> anyhow::Result<()> {
+        let manifest_op = op.to_op(path_join(op.path(), MANIFEST_FILE_NAME).as_str());
+        manifest_op.write_atomic(self.encode()).await?;
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = format!("first {}\nindex {}\n", self.first_segment, self.index);
+        if let Some(pending) = self.pending {
+            data.push_str(format!("pending {}\n", pending).as_str());
+        }
+        data.into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let data = std::str::from_utf8(data)?;
+
+        let mut manifest = Self::default();
+        for line in data.lines() {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("invalid line {:?}", line))?;
+            match name {
+                "first" => manifest.first_segment = value.parse()?,
+                "index" => manifest.index = value.to_string(),
+                "pending" => manifest.pending = Some(value.parse()?),
+                _ => return Err(anyhow!("invalid line {:?}", line)),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// compacted_index_name returns the name of the index written by a compaction
+/// starting at the segment first_id on a backend without atomic rename.
+fn compacted_index_name(first_id: u16) -> String {
+    format!("{}.{:04}", INDEX_FILE_NAME, first_id)
+}
+
 struct KeyRange {
     entry: SeriesEntry,
     offset: SeriesOffset,
@@ -27,25 +123,32 @@ impl KeyRange {
 struct SeriesPartitionInner {
     id: u16,
     op: StorageOperator,
+    options: SeriesFileOptions,
     segments: Vec<SeriesSegment>,
     index: SeriesIndex,
     seq: u64, // series id sequence
+    /// manifest records the compactions on a backend without atomic rename.
+    manifest: Option<PartitionManifest>,
 }
 
 impl SeriesPartitionInner {
     pub fn new(
         id: u16,
         op: StorageOperator,
+        options: SeriesFileOptions,
         segments: Vec<SeriesSegment>,
         index: SeriesIndex,
         seq: u64,
+        manifest: Option<PartitionManifest>,
     ) -> Self {
         Self {
             id,
             op,
+            options,
             segments,
             index,
             seq,
+            manifest,
         }
     }
 
@@ -143,20 +246,20 @@ impl SeriesPartitionInner {
         let entry = SeriesEntry::new(SeriesEntryFlag::InsertFlag(key.to_vec()), id);
         let offset = self.write_log_entry(&entry).await?;
 
-        self.seq += SERIES_FILE_PARTITION_N as u64;
+        self.seq += self.options.partition_n as u64;
         Ok(KeyRange::new(entry, offset))
     }
 
     /// writeLogEntry appends an entry to the end of the active segment.
     /// If there is no more room in the segment then a new segment is added.
     async fn write_log_entry(&mut self, entry: &SeriesEntry) -> anyhow::Result<SeriesOffset> {
-        let mut segment = self.active_segment_mut();
-        if !segment.can_write(&entry) {
-            self.create_segment().await?;
-            segment = self.active_segment_mut();
+        match self.active_segment_mut().write_log_entry(entry).await {
+            Err(e) if matches!(e.downcast_ref(), Some(SeriesSegmentError::SegmentFull(_))) => {
+                self.create_segment().await?;
+                self.active_segment_mut().write_log_entry(entry).await
+            }
+            r => r,
         }
-
-        segment.write_log_entry(&entry).await
     }
 
     async fn create_segment(&mut self) -> anyhow::Result<()> {
@@ -169,7 +272,8 @@ impl SeriesPartitionInner {
 
         // Generate new empty segment.
         let segment_path = path_join(self.op.path(), filename.as_str());
-        let mut segment = SeriesSegment::create(id, self.op.to_op(segment_path.as_str())).await?;
+        let segment = SeriesSegment::create(id, self.op.to_op(segment_path.as_str())).await?;
+        let mut segment = self.options.apply_max_segment_size(segment);
         segment.init_for_write().await?;
         self.segments.push(segment);
 
@@ -201,8 +305,13 @@ impl SeriesPartitionInner {
         self.index.id_delete(id).await
     }
 
-    /// series_key returns the series key for a given id.
+    /// series_key returns the series key for a given id, None if the series
+    /// does not exist or was deleted.
     pub async fn series_key(&self, id: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.index.id_delete(id).await? {
+            return Ok(None);
+        }
+
         let series_offset = self.index.find_offset_by_id(id).await?;
         if let Some(series_offset) = series_offset {
             let v = self.series_key_by_offset(series_offset).await?;
@@ -219,7 +328,10 @@ impl SeriesPartitionInner {
                 continue;
             }
 
-            let mut itr = segment.series_iterator(pos).await?;
+            // series offsets are absolute, the iterator starts after the segment header.
+            let mut itr = segment
+                .series_iterator(pos - SERIES_SEGMENT_HEADER_SIZE as u32)
+                .await?;
             let (entry, _, _) = itr.try_next().await?.ok_or(anyhow!("key not found"))?;
             return entry.flag.into_key();
         }
@@ -237,8 +349,8 @@ impl SeriesPartitionInner {
         self.index.count()
     }
 
-    /// series_iterator returns a list of all series ids.
-    pub async fn series_iterator(&self) -> anyhow::Result<impl AsyncIterator> {
+    /// series_iterator returns an iterator over the entries of all segments.
+    pub async fn series_iterator(&self) -> anyhow::Result<SeriesFileIterator> {
         let mut itrs = Vec::with_capacity(self.segments.len());
         for segment in &self.segments {
             itrs.push(segment.series_iterator(0).await?);
@@ -246,51 +358,234 @@ impl SeriesPartitionInner {
 
         Ok(AsyncIterators::new(itrs))
     }
+
+    /// compact rewrites the live series into new segments with their on-disk
+    /// index and swaps them in, see `SeriesPartition::compact`.
+    async fn compact(&mut self) -> anyhow::Result<()> {
+        let segments = self.write_compaction().await?;
+        self.commit_compaction(segments).await
+    }
+
+    /// write_compaction writes the live series, in id order, to new segments
+    /// numbered after the active one and their index, all named with
+    /// `COMPACTION_FILE_SUFFIX`, or under their final names after recording the
+    /// pending compaction in the manifest. Returns the new segments.
+    async fn write_compaction(&mut self) -> anyhow::Result<Vec<SeriesSegment>> {
+        let first_id = self.active_segment().id() + 1;
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.pending = Some(first_id);
+            manifest.write(&self.op).await?;
+        }
+
+        // ids grow with the log, so the id order is the log order.
+        let mut live = BTreeMap::new();
+        let mut itr = self.series_iterator().await?;
+        while let Some((entry, _offset, _size)) = itr.try_next().await? {
+            match entry.flag {
+                SeriesEntryFlag::InsertFlag(key) => {
+                    live.insert(entry.id, key);
+                }
+                SeriesEntryFlag::TombstoneFlag => {
+                    live.remove(&entry.id);
+                }
+            }
+        }
+
+        let mut segments = vec![self.create_compaction_segment(first_id).await?];
+        let mut elems = Vec::with_capacity(live.len());
+        for (id, key) in live {
+            let entry = SeriesEntry::new(SeriesEntryFlag::InsertFlag(key), id);
+            let segment = segments.last_mut().unwrap();
+            let offset = match segment.write_log_entry(&entry).await {
+                Err(e) if matches!(e.downcast_ref(), Some(SeriesSegmentError::SegmentFull(_))) => {
+                    segment.close().await?;
+                    let segment_id = segment.id() + 1;
+                    segments.push(self.create_compaction_segment(segment_id).await?);
+                    segments.last_mut().unwrap().write_log_entry(&entry).await?
+                }
+                r => r?,
+            };
+
+            let SeriesEntry { flag, id } = entry;
+            elems.push(SeriesIndexElem {
+                key: flag.into_key()?,
+                id,
+                offset,
+            });
+        }
+        segments.last_mut().unwrap().close().await?;
+
+        // the last assigned id must not be assigned again, even if it was deleted.
+        let max_series_id = self.seq.saturating_sub(self.options.partition_n as u64);
+        let data = encode_series_index(elems.as_slice(), max_series_id).await?;
+        let index_op = self.compaction_index_op(first_id);
+        self.op.operator().write(index_op.path(), data).await?;
+
+        Ok(segments)
+    }
+
+    /// compaction_index_op returns the index written by a compaction starting at
+    /// the segment first_id.
+    fn compaction_index_op(&self, first_id: u16) -> StorageOperator {
+        match self.manifest {
+            Some(_) => {
+                let name = compacted_index_name(first_id);
+                self.op
+                    .to_op(path_join(self.op.path(), name.as_str()).as_str())
+            }
+            None => self
+                .op
+                .to_op(path_join(self.op.path(), INDEX_FILE_NAME).as_str())
+                .to_tmp(COMPACTION_FILE_SUFFIX),
+        }
+    }
+
+    async fn create_compaction_segment(&self, id: u16) -> anyhow::Result<SeriesSegment> {
+        let segment_path = path_join(self.op.path(), format!("{:04}", id).as_str());
+        let op = self.op.to_op(segment_path.as_str());
+        let op = match self.manifest {
+            Some(_) => op,
+            None => op.to_tmp(COMPACTION_FILE_SUFFIX),
+        };
+        let segment = SeriesSegment::create(id, op).await?;
+        let mut segment = self.options.apply_max_segment_size(segment);
+        segment.init_for_write().await?;
+        Ok(segment)
+    }
+
+    /// commit_compaction swaps in the segments and the index written by
+    /// `write_compaction`. Renaming the index, or writing the manifest naming it
+    /// on a backend without atomic rename, is the commit point, the partition
+    /// finishes an interrupted commit when it is opened again.
+    async fn commit_compaction(&mut self, segments: Vec<SeriesSegment>) -> anyhow::Result<()> {
+        let first_id = segments[0].id();
+        let index_op = match self.manifest.as_mut() {
+            Some(manifest) => {
+                let committed = PartitionManifest {
+                    first_segment: first_id,
+                    index: compacted_index_name(first_id),
+                    pending: None,
+                };
+                committed.write(&self.op).await?;
+                *manifest = committed;
+                self.compaction_index_op(first_id)
+            }
+            None => {
+                let index_op = self
+                    .op
+                    .to_op(path_join(self.op.path(), INDEX_FILE_NAME).as_str());
+                index_op
+                    .to_tmp(COMPACTION_FILE_SUFFIX)
+                    .rename(index_op.path())
+                    .await?;
+                index_op
+            }
+        };
+
+        self.active_segment_mut().close().await?;
+        match self.manifest.as_ref() {
+            Some(manifest) => SeriesPartition::finish_committed(&self.op, manifest).await?,
+            None => {
+                let compacted_ids: Vec<u16> = segments.iter().map(|x| x.id()).collect();
+                SeriesPartition::finish_compaction(&self.op, compacted_ids.as_slice()).await?;
+            }
+        }
+
+        let (segments, _) =
+            SeriesPartition::open_segments(self.id, self.op.clone(), &self.options).await?;
+        let mut index = SeriesIndex::new(index_op).await?;
+        index.recover(segments.as_slice()).await?;
+
+        self.segments = segments;
+        self.index = index;
+        Ok(())
+    }
 }
 
 /// SeriesPartition represents a subset of series file data.
+///
+/// The partition `id` of a series file of `n` partitions assigns the ids
+/// `id + 1`, `id + 1 + n`, `id + 1 + 2n`, ..., so the partition of a series id
+/// is `(id - 1) % n`, see `SeriesFile::series_id_partition`.
 pub struct SeriesPartition {
     id: u16,
     op: StorageOperator,
 
     inner: RwLock<SeriesPartitionInner>,
-
-    seq: u64, // series id sequence
 }
 
 impl SeriesPartition {
     pub async fn new(id: u16, op: StorageOperator) -> anyhow::Result<Self> {
+        Self::with_options(id, op, &SeriesFileOptions::default()).await
+    }
+
+    /// with_options opens the partition `id` in the directory `op` and indexes
+    /// the entries of its segments.
+    pub async fn with_options(
+        id: u16,
+        op: StorageOperator,
+        options: &SeriesFileOptions,
+    ) -> anyhow::Result<Self> {
         op.create_dir().await?;
 
+        // finish or roll back an interrupted compaction
+        let manifest = if op.rename_is_atomic() {
+            Self::recover_compaction(&op).await?;
+            None
+        } else {
+            Some(Self::recover_manifest(&op).await?)
+        };
+
         // open all segments
-        let (segments, seq) = Self::open_segments(id, op.clone()).await?;
+        let (segments, seq) = Self::open_segments(id, op.clone(), options).await?;
 
         // Init last segment for writes.
         // noop
 
-        // open index
-        let index_path = path_join(op.path(), "index");
-        let index = SeriesIndex::new(op.to_op(index_path.as_str())).await?;
+        // open index, and index the entries written since
+        let index_name = manifest
+            .as_ref()
+            .map_or(INDEX_FILE_NAME, |x| x.index.as_str());
+        let index_path = path_join(op.path(), index_name);
+        let mut index = SeriesIndex::new(op.to_op(index_path.as_str())).await?;
+        index.recover(segments.as_slice()).await?;
 
-        Ok(Self {
+        // a compaction drops the deleted series, the index keeps their ids.
+        let seq = if index.max_series_id() >= seq {
+            index.max_series_id() + options.partition_n as u64
+        } else {
+            seq
+        };
+
+        let inner = SeriesPartitionInner::new(
             id,
-            op: op.clone(),
-            inner: RwLock::new(SeriesPartitionInner::new(id, op, segments, index, seq)),
+            op.clone(),
+            options.clone(),
+            segments,
+            index,
             seq,
+            manifest,
+        );
+        Ok(Self {
+            id,
+            op,
+            inner: RwLock::new(inner),
         })
     }
 
     async fn open_segments(
         partition_id: u16,
         op: StorageOperator,
+        options: &SeriesFileOptions,
     ) -> anyhow::Result<(Vec<SeriesSegment>, u64)> {
         let mut segments = Vec::new();
 
         let mut lister = op.list().await?;
         while let Some(de) = lister.try_next().await? {
             if let Ok(segment_id) = parse_series_segment_filename(de.name()) {
-                let segment = SeriesSegment::open(segment_id, op.to_op(de.path()), true).await?;
-                segments.push(segment);
+                let segment_op = op.to_op(path_join(op.path(), de.name()).as_str());
+                let segment = SeriesSegment::open(segment_id, segment_op, true).await?;
+                segments.push(options.apply_max_segment_size(segment));
             }
         }
 
@@ -302,7 +597,7 @@ impl SeriesPartition {
             let max_series_id = segment.max_series_id().await?;
             if max_series_id >= seq {
                 // Reset our sequence num to the next one to assign
-                seq = max_series_id + SERIES_FILE_PARTITION_N as u64;
+                seq = max_series_id + options.partition_n as u64;
                 break;
             }
         }
@@ -311,7 +606,7 @@ impl SeriesPartition {
         if segments.len() == 0 {
             let op = op.to_op(path_join(op.path(), "0000").as_str());
             let segment = SeriesSegment::create(0, op).await?;
-            segments.push(segment);
+            segments.push(options.apply_max_segment_size(segment));
         }
 
         let active = segments.len() - 1;
@@ -319,17 +614,165 @@ impl SeriesPartition {
         Ok((segments, seq))
     }
 
+    /// recover_compaction removes the files of a compaction which was not
+    /// committed, or finishes the commit of a compaction which was.
+    async fn recover_compaction(op: &StorageOperator) -> anyhow::Result<()> {
+        let suffix = format!(".{}", COMPACTION_FILE_SUFFIX);
+
+        let mut names = vec![];
+        let mut lister = op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            if de.name().contains(suffix.as_str()) {
+                names.push(de.name().to_string());
+            }
+        }
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let index_tmp = format!("index{}", suffix);
+        if names.contains(&index_tmp) {
+            // not committed, the old segments and index are intact.
+            for name in names {
+                op.to_op(path_join(op.path(), name.as_str()).as_str())
+                    .delete()
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        let compacted_ids: Vec<u16> = names
+            .iter()
+            .filter_map(|x| x.strip_suffix(suffix.as_str()))
+            .filter_map(|x| parse_series_segment_filename(x).ok())
+            .collect();
+        Self::finish_compaction(op, compacted_ids.as_slice()).await
+    }
+
+    /// recover_manifest rolls back the compaction pending in the manifest of the
+    /// partition and removes the files replaced by the committed one, on a
+    /// backend without atomic rename. Returns the manifest.
+    async fn recover_manifest(op: &StorageOperator) -> anyhow::Result<PartitionManifest> {
+        let mut manifest = PartitionManifest::read(op).await?;
+
+        if let Some(pending) = manifest.pending.take() {
+            // not committed, the old segments and index are intact.
+            let index_name = compacted_index_name(pending);
+            let mut names = vec![];
+            let mut lister = op.list().await?;
+            while let Some(de) = lister.try_next().await? {
+                let compacted = match parse_series_segment_filename(de.name()) {
+                    Ok(segment_id) => segment_id >= pending,
+                    Err(_) => de.name() == index_name,
+                };
+                if compacted {
+                    names.push(de.name().to_string());
+                }
+            }
+            for name in names {
+                op.to_op(path_join(op.path(), name.as_str()).as_str())
+                    .delete()
+                    .await?;
+            }
+            manifest.write(op).await?;
+        }
+
+        Self::finish_committed(op, &manifest).await?;
+        Ok(manifest)
+    }
+
+    /// finish_committed deletes the segments and the indexes replaced by the
+    /// compaction committed in manifest.
+    async fn finish_committed(
+        op: &StorageOperator,
+        manifest: &PartitionManifest,
+    ) -> anyhow::Result<()> {
+        let index_prefix = format!("{}.", INDEX_FILE_NAME);
+
+        let mut names = vec![];
+        let mut lister = op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            let replaced = match parse_series_segment_filename(de.name()) {
+                Ok(segment_id) => segment_id < manifest.first_segment,
+                Err(_) => {
+                    (de.name() == INDEX_FILE_NAME || de.name().starts_with(index_prefix.as_str()))
+                        && de.name() != manifest.index
+                }
+            };
+            if replaced {
+                names.push(de.name().to_string());
+            }
+        }
+        for name in names {
+            op.to_op(path_join(op.path(), name.as_str()).as_str())
+                .delete()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// finish_compaction deletes the segments preceding the compacted ones and
+    /// renames the compacted segments to their final names.
+    ///
+    /// The renames go from the last segment to the first so that the smallest
+    /// compacted id which is not renamed yet still tells the old segments apart
+    /// after a crash.
+    async fn finish_compaction(op: &StorageOperator, compacted_ids: &[u16]) -> anyhow::Result<()> {
+        let first_id = match compacted_ids.iter().min() {
+            Some(id) => *id,
+            None => return Ok(()),
+        };
+
+        let mut old_segments = vec![];
+        let mut lister = op.list().await?;
+        while let Some(de) = lister.try_next().await? {
+            if let Ok(segment_id) = parse_series_segment_filename(de.name()) {
+                if segment_id < first_id {
+                    old_segments.push(de.name().to_string());
+                }
+            }
+        }
+        for name in old_segments {
+            op.to_op(path_join(op.path(), name.as_str()).as_str())
+                .delete()
+                .await?;
+        }
+
+        let mut compacted_ids = compacted_ids.to_vec();
+        compacted_ids.sort();
+        for id in compacted_ids.iter().rev() {
+            let segment_op = op.to_op(path_join(op.path(), format!("{:04}", id).as_str()).as_str());
+            segment_op
+                .to_tmp(COMPACTION_FILE_SUFFIX)
+                .rename(segment_op.path())
+                .await?;
+        }
+
+        Ok(())
+    }
+
     /// id returns the partition id.
     pub fn id(&self) -> u16 {
         self.id
     }
 
+    pub fn path(&self) -> &str {
+        self.op.path()
+    }
+
     /// file_size returns the size of all partitions, in bytes.
     pub async fn file_size(&self) -> u64 {
         let inner = self.inner.read().await;
         inner.file_size().await
     }
 
+    /// segments returns the segments, ordered by id. Writes wait until the guard
+    /// is dropped.
+    pub async fn segments(&self) -> RwLockReadGuard<'_, [SeriesSegment]> {
+        RwLockReadGuard::map(self.inner.read().await, |x| x.segments.as_slice())
+    }
+
     /// create_series_list_if_not_exists creates a list of series in bulk if they don't exist.
     /// The ids parameter is modified to contain series IDs for all keys belonging to this partition.
     pub async fn create_series_list_if_not_exists(
@@ -351,12 +794,249 @@ impl SeriesPartition {
         inner.insert_series(keys, key_partition_ids, ids).await
     }
 
-    pub async fn iterator(&self) -> anyhow::Result<impl AsyncIterator> {
+    /// create_series_if_not_exists returns the id of the series key, which must
+    /// belong to this partition, creating the series if it does not exist.
+    pub async fn create_series_if_not_exists(&self, key: &[u8]) -> anyhow::Result<u64> {
+        let mut ids = [0];
+        self.create_series_list_if_not_exists(&[key], &[self.id], &mut ids)
+            .await?;
+        Ok(ids[0])
+    }
+
+    /// series_id returns the id of the series key, None if the series does not
+    /// exist or was deleted.
+    pub async fn series_id(&self, key: &[u8]) -> anyhow::Result<Option<u64>> {
+        let inner = self.inner.read().await;
+        let id = inner.find_id_by_series_key(key).await?;
+        Ok(if id == 0 { None } else { Some(id) })
+    }
+
+    /// delete_series_id flags a series as permanently deleted.
+    /// If the series is reintroduced later then it must create a new id.
+    pub async fn delete_series_id(&self, id: u64) -> anyhow::Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.delete_series_id(id).await
+    }
+
+    /// is_deleted returns true if the id is not the id of a live series.
+    pub async fn is_deleted(&self, id: u64) -> anyhow::Result<bool> {
+        let inner = self.inner.read().await;
+        inner.is_delete(id).await
+    }
+
+    /// series_key returns the series key for a given id.
+    pub async fn series_key(&self, id: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        let inner = self.inner.read().await;
+        inner.series_key(id).await
+    }
+
+    /// series_count returns the number of series.
+    pub async fn series_count(&self) -> u64 {
+        let inner = self.inner.read().await;
+        inner.series_count()
+    }
+
+    /// series_iterator returns an iterator over the entries of all segments.
+    pub async fn series_iterator(&self) -> anyhow::Result<SeriesFileIterator> {
         let inner = self.inner.read().await;
         inner.series_iterator().await
     }
+
+    /// close flushes and releases the write handle of the active segment.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.active_segment_mut().close().await
+    }
+
+    /// compact reclaims the entries of the deleted series. The live series are
+    /// rewritten to new segments with an on-disk index of their keys and ids, so
+    /// that opening the partition only indexes the entries written since.
+    ///
+    /// The new segments and index are written next to the old ones and swapped
+    /// in when complete, a crash before the swap leaves the old ones intact. On
+    /// a backend without atomic rename the swap is recorded in a manifest
+    /// instead, see `PartitionManifest`.
+    pub async fn compact(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.compact().await
+    }
 }
 
-/// SeriesPartitionCompactor represents an object reindex a series partition
-/// and optionally compacts segments.
-pub struct SeriesPartitionCompactor {}
+#[cfg(test)]
+mod tests {
+    use influxdb_storage::StorageOperator;
+
+    use crate::series::series_file::SeriesFileOptions;
+    use crate::series::series_partition::SeriesPartition;
+
+    fn keys() -> Vec<Vec<u8>> {
+        (0..2000)
+            .map(|i| format!("cpu,host=server{:04}", i).into_bytes())
+            .collect()
+    }
+
+    /// create_partition creates the keys in the partition 0 at path, deletes
+    /// every other series and returns their ids.
+    async fn create_partition(path: &str, options: &SeriesFileOptions) -> Vec<u64> {
+        let op = StorageOperator::root(path).unwrap();
+        let partition = SeriesPartition::with_options(0, op, options).await.unwrap();
+        let mut ids = vec![];
+        for key in keys() {
+            ids.push(partition.create_series_if_not_exists(&key).await.unwrap());
+        }
+        for id in ids.iter().step_by(2) {
+            partition.delete_series_id(*id).await.unwrap();
+        }
+        partition.close().await.unwrap();
+        ids
+    }
+
+    async fn assert_series(partition: &SeriesPartition, ids: &[u64]) {
+        for (i, (key, id)) in keys().iter().zip(ids.iter()).enumerate() {
+            if i % 2 == 0 {
+                assert!(partition.series_id(key).await.unwrap().is_none());
+                assert!(partition.series_key(*id).await.unwrap().is_none());
+                assert!(partition.is_deleted(*id).await.unwrap());
+            } else {
+                assert_eq!(partition.series_id(key).await.unwrap(), Some(*id));
+                assert_eq!(partition.series_key(*id).await.unwrap().as_ref(), Some(key));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_series_partition_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/00/", dir.path().to_str().unwrap());
+        let options = SeriesFileOptions {
+            max_segment_size: Some(16 * 1024),
+            ..Default::default()
+        };
+        let ids = create_partition(path.as_str(), &options).await;
+
+        let op = StorageOperator::root(path.as_str()).unwrap();
+        let partition = SeriesPartition::with_options(0, op.clone(), &options)
+            .await
+            .unwrap();
+        let size = partition.file_size().await;
+        let segment_n = partition.segments().await.len();
+
+        partition.compact().await.unwrap();
+        assert!(partition.file_size().await < size);
+        assert!(partition.segments().await.len() < segment_n);
+        assert_series(&partition, ids.as_slice()).await;
+        partition.close().await.unwrap();
+
+        // the reopened partition reads the series from the on-disk index
+        let partition = SeriesPartition::with_options(0, op, &options)
+            .await
+            .unwrap();
+        assert_series(&partition, ids.as_slice()).await;
+        let names: Vec<String> = std::fs::read_dir(path.as_str())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(names.iter().all(|x| !x.contains(".compacting")));
+
+        // the ids of the deleted series are not assigned again
+        let id = partition
+            .create_series_if_not_exists(b"mem,host=a")
+            .await
+            .unwrap();
+        assert!(id > *ids.iter().max().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_series_partition_compact_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/00/", dir.path().to_str().unwrap());
+        let options = SeriesFileOptions {
+            max_segment_size: Some(16 * 1024),
+            ..Default::default()
+        };
+        let ids = create_partition(path.as_str(), &options).await;
+
+        let op = StorageOperator::root(path.as_str()).unwrap();
+        let size = {
+            let partition = SeriesPartition::with_options(0, op.clone(), &options)
+                .await
+                .unwrap();
+            let size = partition.file_size().await;
+
+            // crash after writing the compaction, before it is committed
+            let mut inner = partition.inner.write().await;
+            inner.write_compaction().await.unwrap();
+            inner.active_segment_mut().close().await.unwrap();
+            size
+        };
+
+        let partition = SeriesPartition::with_options(0, op, &options)
+            .await
+            .unwrap();
+        assert_eq!(partition.file_size().await, size);
+        assert_series(&partition, ids.as_slice()).await;
+        let names: Vec<String> = std::fs::read_dir(path.as_str())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(names.iter().all(|x| !x.contains(".compacting")));
+    }
+
+    #[tokio::test]
+    async fn test_series_partition_compact_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/00/", dir.path().to_str().unwrap());
+        let options = SeriesFileOptions {
+            max_segment_size: Some(16 * 1024),
+            ..Default::default()
+        };
+        let ids = create_partition(path.as_str(), &options).await;
+        let names = || -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(path.as_str())
+                .unwrap()
+                .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        let old_names = names();
+
+        // the compaction is committed by the manifest, nothing is renamed
+        let op = StorageOperator::root(path.as_str())
+            .unwrap()
+            .with_rename_is_atomic(false);
+        {
+            let partition = SeriesPartition::with_options(0, op.clone(), &options)
+                .await
+                .unwrap();
+
+            // crash after writing the compaction, before it is committed
+            let mut inner = partition.inner.write().await;
+            inner.write_compaction().await.unwrap();
+            inner.active_segment_mut().close().await.unwrap();
+            assert!(names().iter().all(|x| !x.contains(".compacting")));
+        }
+
+        let partition = SeriesPartition::with_options(0, op.clone(), &options)
+            .await
+            .unwrap();
+        assert_series(&partition, ids.as_slice()).await;
+        let mut rolled_back = old_names;
+        rolled_back.push("MANIFEST".to_string());
+        rolled_back.sort();
+        assert_eq!(names(), rolled_back);
+
+        partition.compact().await.unwrap();
+        assert_series(&partition, ids.as_slice()).await;
+        partition.close().await.unwrap();
+
+        let partition = SeriesPartition::with_options(0, op, &options)
+            .await
+            .unwrap();
+        assert_series(&partition, ids.as_slice()).await;
+        let names = names();
+        assert!(!names.contains(&"index".to_string()));
+        assert!(names.iter().any(|x| x.starts_with("index.")));
+        assert!(names.iter().all(|x| !x.contains(".compacting")));
+    }
+}
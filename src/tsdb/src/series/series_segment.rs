@@ -1,16 +1,19 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::io::{Cursor, SeekFrom};
 
 use bytes::Buf;
 use common_base::iterator::AsyncIterator;
+use common_base::point::MAX_KEY_LENGTH;
 use crc32fast::Hasher;
+use influxdb_storage::opendal::Appender;
 use influxdb_storage::opendal::Reader;
-use influxdb_storage::opendal::Writer;
 use influxdb_storage::StorageOperator;
 use regex::Regex;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
-use crate::series::series_key::{read_series_key, SeriesKeyDecoder};
+use crate::engine::tsm1::codec::varint::{VarInt, MAX_VARINT_LEN64};
+use crate::series::series_key::{parse_series_key_name, read_series_key, SeriesKeyDecoder};
 
 const TMP_FILE_SUFFIX: &'static str = ".initializing";
 
@@ -24,6 +27,7 @@ pub(crate) const SERIES_ENTRY_HEADER_SIZE: usize = 1 + 8;
 const SERIES_ENTRY_INSERT_FLAG: u8 = 0x01;
 const SERIES_ENTRY_TOMBSTONE_FLAG: u8 = 0x02;
 
+#[derive(PartialEq)]
 pub enum SeriesEntryFlag {
     InsertFlag(Vec<u8>),
     TombstoneFlag,
@@ -49,6 +53,11 @@ impl SeriesEntryFlag {
         }
     }
 
+    /// is_tombstone returns true if the entry deletes a series.
+    pub fn is_tombstone(&self) -> bool {
+        matches!(self, Self::TombstoneFlag)
+    }
+
     pub fn into_key(self) -> anyhow::Result<Vec<u8>> {
         match self {
             Self::InsertFlag(key) => Ok(key),
@@ -57,7 +66,7 @@ impl SeriesEntryFlag {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct SeriesEntry {
     pub(crate) flag: SeriesEntryFlag,
     /// segment_id
@@ -70,9 +79,28 @@ impl SeriesEntry {
         Self { flag, id }
     }
 
+    /// flag returns whether the entry inserts a series, with its key, or deletes it.
+    pub fn flag(&self) -> &SeriesEntryFlag {
+        &self.flag
+    }
+
+    /// id returns the series id the entry inserts or deletes.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// measurement returns the measurement of the series key the entry inserts,
+    /// None for a tombstone or a malformed key.
+    pub fn measurement(&self) -> Option<&[u8]> {
+        match &self.flag {
+            SeriesEntryFlag::InsertFlag(key) => parse_series_key_name(key.as_slice()),
+            SeriesEntryFlag::TombstoneFlag => None,
+        }
+    }
+
     pub fn len(&self) -> usize {
         let key_len = match &self.flag {
-            SeriesEntryFlag::InsertFlag(key) => key.len(),
+            SeriesEntryFlag::InsertFlag(key) => key.len().required_space() + key.len(),
             SeriesEntryFlag::TombstoneFlag => 0,
         };
 
@@ -86,7 +114,17 @@ impl SeriesEntry {
 
         match &self.flag {
             SeriesEntryFlag::InsertFlag(key) => {
-                w.write(key).await?;
+                // series keys are checked against the max series key length on write
+                debug_assert!(
+                    key.len() <= MAX_KEY_LENGTH,
+                    "series entry of a key of {} bytes",
+                    key.len()
+                );
+                // The key is length prefixed, see `read_series_key`.
+                let mut buf = [0; MAX_VARINT_LEN64];
+                let n = key.len().encode_var(&mut buf);
+                w.write_all(&buf[..n]).await?;
+                w.write_all(key).await?;
             }
             SeriesEntryFlag::TombstoneFlag => {}
         };
@@ -208,12 +246,35 @@ impl SeriesSegmentHeader {
     }
 }
 
+/// SeriesSegmentError are the errors returned when writing a segment.
+#[derive(Debug, thiserror::Error)]
+pub enum SeriesSegmentError {
+    /// The entry does not fit in the segment, the caller should roll over to a new segment.
+    #[error("series segment {0} is full")]
+    SegmentFull(u16),
+    #[error("series segment {0} is not open for writing")]
+    NotWritable(u16),
+}
+
+/// SeriesSegmentStats counts the entries of a segment.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SeriesSegmentStats {
+    pub inserts: u64,
+    pub tombstones: u64,
+}
+
+impl SeriesSegmentStats {
+    pub fn entries(&self) -> u64 {
+        self.inserts + self.tombstones
+    }
+}
+
 pub struct SeriesSegment {
     segment_id: u16,
     header: SeriesSegmentHeader,
 
     op: StorageOperator,
-    writer: Option<Writer>,
+    writer: Option<Appender>,
     write_offset: u32,
     max_file_size: u32,
 }
@@ -258,27 +319,35 @@ impl SeriesSegment {
     }
 
     pub async fn create(id: u16, op: StorageOperator) -> anyhow::Result<Self> {
-        // Generate segment in temp location.
-        let tmp_op = op.to_tmp(TMP_FILE_SUFFIX);
-        {
-            let mut writer = tmp_op.writer().await?;
-
-            let hdr = SeriesSegmentHeader::new();
-            hdr.write_to(&mut writer).await?;
-
-            writer.close().await?;
+        let mut hdr = vec![];
+        SeriesSegmentHeader::new().write_to(&mut hdr).await?;
+
+        if op.rename_is_atomic() {
+            // Generate segment in temp location.
+            let tmp_op = op.to_tmp(TMP_FILE_SUFFIX);
+            tmp_op.operator().write(tmp_op.path(), hdr).await?;
+            tmp_op.rename(op.path()).await?;
+        } else {
+            // a single write, readers see the whole header or no segment.
+            op.operator().write(op.path(), hdr).await?;
         }
-        op.rename(op.path()).await?;
 
         // todo truncate file: f.Truncate(int64(series_segment_size(id)))
 
         Self::open(id, op, false).await
     }
 
+    /// with_max_file_size sets the size the segment may grow to before it is
+    /// full, instead of `series_segment_size` of its id.
+    pub fn with_max_file_size(mut self, max_file_size: u32) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
     /// InitForWrite initializes a write handle for the segment.
     /// This is only used for the last segment in the series file.
     pub async fn init_for_write(&mut self) -> anyhow::Result<()> {
-        let writer = self.op.writer().await?;
+        let writer = self.op.appender().await?;
         self.writer = Some(writer);
         Ok(())
     }
@@ -296,10 +365,14 @@ impl SeriesSegment {
     }
 
     /// write_log_entry writes entry data into the segment.
-    /// Returns the offset of the beginning of the entry.
+    /// Returns the offset of the beginning of the entry, or `SeriesSegmentError::SegmentFull`
+    /// if there is no room left for the entry.
     pub async fn write_log_entry(&mut self, entry: &SeriesEntry) -> anyhow::Result<SeriesOffset> {
+        if self.writer.is_none() {
+            return Err(SeriesSegmentError::NotWritable(self.segment_id).into());
+        }
         if !self.can_write(entry) {
-            return Err(anyhow!("series segment not writable"));
+            return Err(SeriesSegmentError::SegmentFull(self.segment_id).into());
         }
 
         let series_offset = SeriesOffset::join(self.segment_id, self.write_offset);
@@ -311,6 +384,17 @@ impl SeriesSegment {
         Ok(series_offset)
     }
 
+    /// append writes entry into the segment, opening the segment for writing if needed.
+    /// Returns the offset of the beginning of the entry.
+    pub async fn append(&mut self, entry: &SeriesEntry) -> anyhow::Result<u64> {
+        if self.writer.is_none() {
+            self.init_for_write().await?;
+        }
+
+        let offset = self.write_log_entry(entry).await?;
+        Ok(offset.0)
+    }
+
     pub fn can_write(&self, entry: &SeriesEntry) -> bool {
         self.writer.is_some()
             && (self.write_offset as u64 + entry.len() as u64) < self.max_file_size as u64
@@ -324,7 +408,13 @@ impl SeriesSegment {
         Ok(())
     }
 
-    /// create series iterator
+    /// close flushes and releases the write handle of the segment.
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        self.close_for_write().await
+    }
+
+    /// create series iterator, series_pos is relative to the end of the segment header.
     pub async fn series_iterator(&self, series_pos: u32) -> anyhow::Result<SeriesEntryIterator> {
         let reader = self.op.reader().await?;
         let itr = SeriesEntryIterator::new(
@@ -366,6 +456,42 @@ impl SeriesSegment {
         Ok(max)
     }
 
+    /// live_series walks the entries in log order and returns the series which
+    /// are inserted and not subsequently tombstoned, as `(id, key)` ordered by id.
+    pub async fn live_series(&self) -> anyhow::Result<Vec<(u64, Vec<u8>)>> {
+        let mut itr = self.series_iterator(0).await?;
+
+        let mut live = BTreeMap::new();
+        while let Some((entry, _offset, _size)) = itr.next().await? {
+            match entry.flag {
+                SeriesEntryFlag::InsertFlag(key) => {
+                    live.insert(entry.id, key);
+                }
+                SeriesEntryFlag::TombstoneFlag => {
+                    live.remove(&entry.id);
+                }
+            }
+        }
+
+        Ok(live.into_iter().collect())
+    }
+
+    /// stats returns the number of insert and tombstone entries in the segment.
+    pub async fn stats(&self) -> anyhow::Result<SeriesSegmentStats> {
+        let mut itr = self.series_iterator(0).await?;
+
+        let mut stats = SeriesSegmentStats::default();
+        while let Some((entry, _offset, _size)) = itr.next().await? {
+            if entry.flag.is_tombstone() {
+                stats.tombstones += 1;
+            } else {
+                stats.inserts += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+
     pub fn id(&self) -> u16 {
         self.segment_id
     }
@@ -494,7 +620,7 @@ pub async fn read_series_key_from_segments(
 ) -> anyhow::Result<Option<Vec<u8>>> {
     let (segment_id, pos) = split_series_offset(offset);
     if let Some(segment) = find_segment(segments, segment_id) {
-        let pos = pos - SERIES_ENTRY_HEADER_SIZE as u32;
+        let pos = pos - SERIES_ENTRY_HEADER_SIZE as u32 - SERIES_SEGMENT_HEADER_SIZE as u32;
         let mut itr = segment.series_iterator(pos).await?;
         if let Some((entry, _len, _size)) = itr.next().await? {
             return match entry.flag {
@@ -510,9 +636,14 @@ pub async fn read_series_key_from_segments(
 #[cfg(test)]
 mod tests {
     use common_base::iterator::AsyncIterator;
+    use common_base::point::Tag;
     use influxdb_storage::{operator, StorageOperator};
 
-    use crate::series::series_segment::SeriesSegment;
+    use crate::series::series_key::encode_series_key;
+    use crate::series::series_segment::{
+        SeriesEntry, SeriesEntryFlag, SeriesSegment, SeriesSegmentError, SeriesSegmentStats,
+        SERIES_SEGMENT_HEADER_SIZE,
+    };
 
     #[tokio::test]
     async fn test_segment_read() -> anyhow::Result<()> {
@@ -527,4 +658,131 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_segment_append_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0000");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let entries = vec![
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=a".to_vec()), 1),
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=b".to_vec()), 2),
+            SeriesEntry::new(SeriesEntryFlag::TombstoneFlag, 1),
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"mem,host=a".to_vec()), 3),
+        ];
+
+        let mut offsets = vec![];
+        {
+            let mut segment = SeriesSegment::create(0, op.clone()).await.unwrap();
+            for entry in entries.iter() {
+                offsets.push(segment.append(entry).await.unwrap());
+            }
+            segment.close().await.unwrap();
+        }
+        assert_eq!(offsets[0], SERIES_SEGMENT_HEADER_SIZE as u64);
+
+        let segment = SeriesSegment::open(0, op, true).await.unwrap();
+        let mut itr = segment.series_iterator(0).await.unwrap();
+        let mut i = 0;
+        while let Some((entry, offset, size)) = itr.try_next().await.unwrap() {
+            assert_eq!(entry, entries[i]);
+            assert_eq!(offset, offsets[i]);
+            assert_eq!(size, entries[i].len());
+            i += 1;
+        }
+        assert_eq!(i, entries.len());
+    }
+
+    #[tokio::test]
+    async fn test_segment_live_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0000");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let entries = vec![
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=a".to_vec()), 1),
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=b".to_vec()), 2),
+            SeriesEntry::new(SeriesEntryFlag::TombstoneFlag, 1),
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu,host=a".to_vec()), 3),
+            SeriesEntry::new(SeriesEntryFlag::TombstoneFlag, 2),
+        ];
+
+        let mut segment = SeriesSegment::create(0, op.clone()).await.unwrap();
+        for entry in entries.iter() {
+            segment.append(entry).await.unwrap();
+        }
+        segment.close().await.unwrap();
+
+        let segment = SeriesSegment::open(0, op, true).await.unwrap();
+        let mut itr = segment.series_iterator(0).await.unwrap();
+        let mut tombstones = vec![];
+        while let Some((entry, _offset, _size)) = itr.try_next().await.unwrap() {
+            if entry.flag().is_tombstone() {
+                tombstones.push(entry.id());
+            }
+        }
+        assert_eq!(tombstones, vec![1, 2]);
+
+        let live = segment.live_series().await.unwrap();
+        assert_eq!(live, vec![(3, b"cpu,host=a".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn test_segment_measurement_and_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0000");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let cpu = encode_series_key(b"cpu", &[Tag::new(b"host".to_vec(), b"a".to_vec())]);
+        let mem = encode_series_key(b"mem", &[]);
+        let entries = vec![
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(cpu), 1),
+            SeriesEntry::new(SeriesEntryFlag::InsertFlag(mem), 2),
+            SeriesEntry::new(SeriesEntryFlag::TombstoneFlag, 1),
+        ];
+        assert_eq!(entries[0].measurement(), Some(b"cpu".as_slice()));
+        assert_eq!(entries[1].measurement(), Some(b"mem".as_slice()));
+        assert_eq!(entries[2].measurement(), None);
+
+        // a key too short for the measurement it announces
+        let truncated = SeriesEntry::new(SeriesEntryFlag::InsertFlag(vec![0, 9, b'c']), 3);
+        assert_eq!(truncated.measurement(), None);
+
+        let mut segment = SeriesSegment::create(0, op.clone()).await.unwrap();
+        for entry in entries.iter() {
+            segment.append(entry).await.unwrap();
+        }
+        segment.close().await.unwrap();
+
+        let segment = SeriesSegment::open(0, op, true).await.unwrap();
+        let stats = segment.stats().await.unwrap();
+        assert_eq!(
+            stats,
+            SeriesSegmentStats {
+                inserts: 2,
+                tombstones: 1
+            }
+        );
+        assert_eq!(stats.entries(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_segment_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0000");
+        let op = StorageOperator::root(path.to_str().unwrap()).unwrap();
+
+        let mut segment = SeriesSegment::create(0, op).await.unwrap();
+        segment.max_file_size = 24;
+
+        let entry = SeriesEntry::new(SeriesEntryFlag::InsertFlag(b"cpu".to_vec()), 1);
+        segment.append(&entry).await.unwrap();
+
+        let err = segment.append(&entry).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SeriesSegmentError::SegmentFull(0))
+        ));
+    }
 }
@@ -1,44 +1,664 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+
 use anyhow::anyhow;
-use hyperloglogplus::{HyperLogLog, HyperLogLogError, HyperLogLogPlus};
-use twox_hash::RandomXxHashBuilder64;
+use twox_hash::XxHash64;
 
 use crate::estimator::Sketch;
 
 /// DEFAULT_PRECISION is the default precision.
 const DEFAULT_PRECISION: u8 = 16;
 
+/// MIN_PRECISION and MAX_PRECISION bound the number of registers to [2^4, 2^18].
+const MIN_PRECISION: u8 = 4;
+const MAX_PRECISION: u8 = 18;
+
+/// SPARSE_PRECISION is the precision of the hashes in the sparse representation.
+const SPARSE_PRECISION: u8 = 25;
+
+/// VERSION is the version byte of the encoded sketch, the one of influxd.
+const VERSION: u8 = 2;
+
+/// HASH_SEED is fixed so that sketches built by different processes can be merged.
+const HASH_SEED: u64 = 0;
+
+/// Registers holds the register values either as the sparse encoded hashes of
+/// the touched registers, one per register sorted by register index, or as the
+/// full array.
+#[derive(Clone, Debug)]
+enum Registers {
+    Sparse(Vec<u32>),
+    Dense(Vec<u8>),
+}
+
+/// Plus implements the HyperLogLog++ cardinality estimator, compatible with
+/// the `hll.Plus` sketches of influxd.
+///
+/// A new sketch starts in sparse mode and is promoted to dense once the sparse
+/// list holds m/4 registers, the point at which it uses as much memory as the
+/// dense array.
+///
+/// The sparse representation keeps the hashes encoded with a precision of 25
+/// bits, see `encode_hash`, which hold the register index and value at any
+/// precision up to 25.
+///
+/// Encoded layout, dense:
+///
+/// ┌─────────┬───────────┬────────┬─────────┬───────────────────────┐
+/// │ version │ precision │ sparse │ size m  │ registers (m bytes)   │
+/// │ 1 byte  │ 1 byte    │ 0      │ 4 bytes │                       │
+/// └─────────┴───────────┴────────┴─────────┴───────────────────────┘
+///
+/// sparse:
+///
+/// ┌─────────┬───────────┬────────┬─────────┬────────────────────┐
+/// │ version │ precision │ sparse │ set N   │ set (N * 4 bytes)  │
+/// │ 1 byte  │ 1 byte    │ 1      │ 4 bytes │ hashes, unsorted   │
+/// ├─────────┼─────────┬─┴────────┴─────────┴────────────────────┤
+/// │ count   │ last    │ size S  │ list (S bytes)                 │
+/// │ 4 bytes │ 4 bytes │ 4 bytes │ varint deltas of sorted hashes │
+/// └─────────┴─────────┴─────────┴────────────────────────────────┘
+///
+/// All integers are big endian. influxd buffers new hashes in the set before
+/// merging them into the list, the sketches encoded here have an empty set.
+#[derive(Clone, Debug)]
 pub struct Plus {
-    hllp: HyperLogLogPlus<u8, RandomXxHashBuilder64>,
+    p: u8,
+    registers: Registers,
 }
 
 impl Plus {
-    pub fn new() -> Result<Self, HyperLogLogError> {
+    pub fn new() -> anyhow::Result<Self> {
         Self::with_p(DEFAULT_PRECISION)
     }
 
-    pub fn with_p(p: u8) -> Result<Self, HyperLogLogError> {
-        let hllp = HyperLogLogPlus::new(p, RandomXxHashBuilder64::default())?;
-        Ok(Self { hllp })
+    pub fn with_p(p: u8) -> anyhow::Result<Self> {
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(&p) {
+            return Err(anyhow!(
+                "precision must be between {} and {}, got {}",
+                MIN_PRECISION,
+                MAX_PRECISION,
+                p
+            ));
+        }
+
+        Ok(Self {
+            p,
+            registers: Registers::Sparse(vec![]),
+        })
+    }
+
+    /// precision returns the number of bits used to select a register.
+    pub fn precision(&self) -> u8 {
+        self.p
+    }
+
+    /// is_sparse returns true if the sketch is still using the sparse representation.
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.registers, Registers::Sparse(_))
+    }
+
+    /// sparse_threshold returns the number of touched registers at which the
+    /// sketch is promoted to the dense representation.
+    pub fn sparse_threshold(&self) -> usize {
+        self.m() / 4
+    }
+
+    fn m(&self) -> usize {
+        1 << self.p
+    }
+
+    fn hash(v: &[u8]) -> u64 {
+        let mut h = XxHash64::with_seed(HASH_SEED);
+        h.write(v);
+        h.finish()
+    }
+
+    /// alpha returns the bias correction constant for m registers.
+    fn alpha(m: usize) -> f64 {
+        match m {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m as f64),
+        }
+    }
+
+    fn set_register(registers: &mut [u8], idx: u32, rho: u8) {
+        let r = &mut registers[idx as usize];
+        if rho > *r {
+            *r = rho;
+        }
+    }
+
+    /// insert_sparse adds the sparse encoded hash k to the sketch, keeping the
+    /// hash of the highest value of its register.
+    fn insert_sparse(&mut self, k: u32) {
+        let p = self.p;
+        let threshold = self.sparse_threshold();
+        let promote = match &mut self.registers {
+            Registers::Dense(registers) => {
+                let (idx, rho) = decode_hash(k, p);
+                Self::set_register(registers, idx, rho);
+                false
+            }
+            Registers::Sparse(entries) => {
+                let (idx, rho) = decode_hash(k, p);
+                match entries.binary_search_by_key(&idx, |e| sparse_index(*e, p)) {
+                    Ok(i) => {
+                        if rho > decode_hash(entries[i], p).1 {
+                            entries[i] = k;
+                        }
+                    }
+                    Err(i) => entries.insert(i, k),
+                }
+                entries.len() >= threshold
+            }
+        };
+
+        if promote {
+            self.promote_to_dense();
+        }
+    }
+
+    /// promote_to_dense promotes the sketch to the dense representation.
+    fn promote_to_dense(&mut self) {
+        if let Registers::Sparse(entries) = &self.registers {
+            let mut registers = vec![0; self.m()];
+            for k in entries {
+                let (idx, rho) = decode_hash(*k, self.p);
+                Self::set_register(&mut registers, idx, rho);
+            }
+            self.registers = Registers::Dense(registers);
+        }
     }
 }
 
 impl Sketch for Plus {
-    fn add(&mut self, values: &[u8]) {
-        for v in values {
-            self.hllp.insert(v);
+    fn add(&mut self, v: &[u8]) {
+        let x = Self::hash(v);
+        match &mut self.registers {
+            Registers::Dense(registers) => {
+                let idx = (x >> (64 - self.p)) as u32;
+                // The guard bit caps the run of leading zeros at 64 - p.
+                let w = (x << self.p) | (1 << (self.p - 1));
+                let rho = w.leading_zeros() as u8 + 1;
+                Self::set_register(registers, idx, rho);
+            }
+            Registers::Sparse(_) => self.insert_sparse(encode_hash(x, self.p)),
         }
     }
 
     fn count(&mut self) -> u64 {
-        self.hllp.count() as u64
+        let m = self.m() as f64;
+
+        let (mut sum, zeros) = match &self.registers {
+            Registers::Dense(registers) => {
+                let mut sum = 0_f64;
+                let mut zeros = 0_usize;
+                for r in registers {
+                    if *r == 0 {
+                        zeros += 1;
+                    } else {
+                        sum += 1.0 / (1_u64 << *r) as f64;
+                    }
+                }
+                (sum, zeros)
+            }
+            Registers::Sparse(entries) => {
+                let sum = entries
+                    .iter()
+                    .map(|k| 1.0 / (1_u64 << decode_hash(*k, self.p).1) as f64)
+                    .sum();
+                (sum, self.m() - entries.len())
+            }
+        };
+        // Untouched registers contribute 2^-0 each.
+        sum += zeros as f64;
+
+        let estimate = Self::alpha(self.m()) * m * m / sum;
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Small range correction: linear counting.
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
     }
 
     fn merge(&mut self, s: &Self) -> anyhow::Result<()> {
-        self.hllp.merge(&s.hllp).map_err(|e| anyhow!(e))
+        if self.p != s.p {
+            return Err(anyhow!(
+                "cannot merge sketches with different precision: {} != {}",
+                self.p,
+                s.p
+            ));
+        }
+
+        match &s.registers {
+            Registers::Sparse(entries) => {
+                for k in entries {
+                    self.insert_sparse(*k);
+                }
+            }
+            Registers::Dense(src) => {
+                self.promote_to_dense();
+                if let Registers::Dense(dst) = &mut self.registers {
+                    for (dst, src) in dst.iter_mut().zip(src.iter()) {
+                        if *src > *dst {
+                            *dst = *src;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
     fn encode(&self) -> anyhow::Result<Vec<u8>> {
-        // serde_json::to_vec(&self.hllp).map_err(|e| anyhow!(e))
-        todo!()
+        match &self.registers {
+            Registers::Dense(registers) => {
+                let mut buf = Vec::with_capacity(3 + 4 + registers.len());
+                buf.extend_from_slice(&[VERSION, self.p, 0]);
+                buf.extend_from_slice(&(registers.len() as u32).to_be_bytes());
+                buf.extend_from_slice(registers.as_slice());
+                Ok(buf)
+            }
+            Registers::Sparse(entries) => {
+                let mut sorted = entries.clone();
+                sorted.sort_unstable();
+
+                let mut list = Vec::with_capacity(sorted.len() * 2);
+                let mut last = 0_u32;
+                for k in sorted.iter() {
+                    put_uvarint32(&mut list, *k - last);
+                    last = *k;
+                }
+
+                let mut buf = Vec::with_capacity(3 + 4 + 12 + list.len());
+                buf.extend_from_slice(&[VERSION, self.p, 1]);
+                // the set is empty, all the hashes are in the list.
+                buf.extend_from_slice(&0_u32.to_be_bytes());
+                buf.extend_from_slice(&(sorted.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&last.to_be_bytes());
+                buf.extend_from_slice(&(list.len() as u32).to_be_bytes());
+                buf.extend_from_slice(list.as_slice());
+                Ok(buf)
+            }
+        }
+    }
+
+    fn decode(buf: &[u8]) -> anyhow::Result<Self> {
+        if buf.len() < 3 {
+            return Err(anyhow!("hll: short buffer: {} < 3", buf.len()));
+        }
+
+        let (version, p, sparse) = (buf[0], buf[1], buf[2]);
+        if version != VERSION {
+            return Err(anyhow!("hll: unsupported version {}", version));
+        }
+        let mut sketch = Self::with_p(p)?;
+        let mut r = Reader(&buf[3..]);
+
+        match sparse {
+            0 => {
+                let n = r.read_u32()? as usize;
+                if n != sketch.m() {
+                    return Err(anyhow!(
+                        "hll: register count mismatch: {} != {}",
+                        n,
+                        sketch.m()
+                    ));
+                }
+                sketch.registers = Registers::Dense(r.read_bytes(n)?.to_vec());
+            }
+            1 => {
+                let mut hashes = vec![];
+
+                let set_n = r.read_u32()? as usize;
+                for _ in 0..set_n {
+                    hashes.push(r.read_u32()?);
+                }
+
+                let count = r.read_u32()? as usize;
+                let last = r.read_u32()?;
+                let size = r.read_u32()? as usize;
+                let mut list = Reader(r.read_bytes(size)?);
+                let mut k = 0_u32;
+                for _ in 0..count {
+                    k = k
+                        .checked_add(list.read_uvarint32()?)
+                        .ok_or_else(|| anyhow!("hll: sparse list overflow"))?;
+                    hashes.push(k);
+                }
+                if k != last || !list.0.is_empty() {
+                    return Err(anyhow!("hll: sparse list size mismatch"));
+                }
+
+                // keep the hash of the highest value of each register.
+                let mut registers = HashMap::new();
+                for k in hashes {
+                    let (idx, rho) = decode_hash(k, p);
+                    let e = registers.entry(idx).or_insert(k);
+                    if rho > decode_hash(*e, p).1 {
+                        *e = k;
+                    }
+                }
+                let mut entries: Vec<u32> = registers.into_values().collect();
+                entries.sort_unstable_by_key(|k| sparse_index(*k, p));
+                let promote = entries.len() >= sketch.sparse_threshold();
+                sketch.registers = Registers::Sparse(entries);
+                if promote {
+                    sketch.promote_to_dense();
+                }
+            }
+            _ => return Err(anyhow!("hll: invalid sparse flag {}", sparse)),
+        }
+
+        if !r.0.is_empty() {
+            return Err(anyhow!("hll: {} trailing bytes", r.0.len()));
+        }
+
+        Ok(sketch)
+    }
+}
+
+/// encode_hash encodes the hash x for the sparse representation: the index of
+/// its register at `SPARSE_PRECISION`, followed by the run of leading zeros
+/// after it if that is longer than the index bits beyond precision p.
+///
+/// ```text
+/// idx << 7 | rho' << 1 | 1  if the bits of idx beyond p are 0
+/// idx << 1                  otherwise
+/// ```
+fn encode_hash(x: u64, p: u8) -> u32 {
+    let pp = SPARSE_PRECISION;
+    let idx = bextr(x, 64 - pp, pp) as u32;
+    if bextr(x, 64 - pp, pp - p) == 0 {
+        let zeros = ((bextr(x, 0, 64 - pp) << pp) | ((1 << pp) - 1)).leading_zeros() + 1;
+        return (idx << 7) | (zeros << 1) | 1;
+    }
+    idx << 1
+}
+
+/// decode_hash returns the register index at precision p and the register
+/// value of the sparse encoded hash k.
+fn decode_hash(k: u32, p: u8) -> (u32, u8) {
+    let pp = SPARSE_PRECISION;
+    let rho = if k & 1 == 1 {
+        bextr32(k, 1, 6) as u8 + pp - p
+    } else {
+        // the bits of idx beyond p, at the top of the word.
+        (k << (32 - pp + p - 1)).leading_zeros() as u8 + 1
+    };
+    (sparse_index(k, p), rho)
+}
+
+/// sparse_index returns the register index at precision p of the sparse
+/// encoded hash k.
+fn sparse_index(k: u32, p: u8) -> u32 {
+    if k & 1 == 1 {
+        bextr32(k, 32 - p, p)
+    } else {
+        bextr32(k, SPARSE_PRECISION - p + 1, p)
+    }
+}
+
+/// bextr extracts the length bits of v starting at bit start.
+fn bextr(v: u64, start: u8, length: u8) -> u64 {
+    (v >> start) & ((1 << length) - 1)
+}
+
+fn bextr32(v: u32, start: u8, length: u8) -> u32 {
+    (v >> start) & ((1 << length) - 1)
+}
+
+fn put_uvarint32(buf: &mut Vec<u8>, mut x: u32) {
+    while x & 0xFFFF_FF80 != 0 {
+        buf.push((x & 0x7F) as u8 | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+/// Reader reads the big endian integers of an encoded sketch.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(anyhow!("hll: short buffer: {} < {}", self.0.len(), n));
+        }
+        let (b, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        let b = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn read_uvarint32(&mut self) -> anyhow::Result<u32> {
+        let mut x = 0_u32;
+        let mut shift = 0;
+        loop {
+            let b = self.read_bytes(1)?[0];
+            // the 5th byte holds the 4 high bits.
+            if shift == 28 && b > 0x0F {
+                return Err(anyhow!("hll: varint overflow"));
+            }
+            x |= ((b & 0x7F) as u32) << shift;
+            if b & 0x80 == 0 {
+                return Ok(x);
+            }
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::estimator::hll::Plus;
+    use crate::estimator::Sketch;
+
+    /// 1.04/sqrt(2^16) is ~0.4%, allow a generous margin.
+    const MAX_ERROR: f64 = 0.02;
+
+    fn sketch_of(keys: std::ops::Range<u64>) -> Plus {
+        let mut s = Plus::new().unwrap();
+        for k in keys {
+            s.add(format!("cpu,host=server-{}", k).as_bytes());
+        }
+        s
+    }
+
+    fn assert_within(count: u64, expected: u64) {
+        let err = (count as f64 - expected as f64).abs() / expected as f64;
+        assert!(
+            err <= MAX_ERROR,
+            "count {} expected {} error {}",
+            count,
+            expected,
+            err
+        );
+    }
+
+    #[test]
+    fn test_merge_disjoint() {
+        let mut a = sketch_of(0..50_000);
+        let b = sketch_of(50_000..100_000);
+        a.merge(&b).unwrap();
+        assert_within(a.count(), 100_000);
+    }
+
+    #[test]
+    fn test_merge_overlapping() {
+        let mut a = sketch_of(0..60_000);
+        let b = sketch_of(40_000..100_000);
+        a.merge(&b).unwrap();
+        assert_within(a.count(), 100_000);
+    }
+
+    #[test]
+    fn test_merge_sparse_into_dense() {
+        let mut a = sketch_of(0..1_000);
+        let mut b = sketch_of(1_000..100_000);
+        assert!(a.is_sparse());
+        assert!(!b.is_sparse());
+
+        b.merge(&a).unwrap();
+        assert_within(b.count(), 100_000);
+
+        a.merge(&sketch_of(1_000..100_000)).unwrap();
+        assert!(!a.is_sparse());
+        assert_eq!(a.count(), b.count());
+    }
+
+    #[test]
+    fn test_merge_precision_mismatch() {
+        let mut a = Plus::with_p(14).unwrap();
+        let b = Plus::with_p(16).unwrap();
+        assert!(a.merge(&b).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut a = sketch_of(0..50_000);
+        assert!(!a.is_sparse());
+        let buf = a.encode().unwrap();
+        assert_eq!(buf[0], 2);
+        assert_eq!(buf[1], 16);
+        assert_eq!(buf[2], 0);
+        assert_eq!(buf.len(), 3 + 4 + (1 << 16));
+
+        let mut b = Plus::decode(buf.as_slice()).unwrap();
+        assert!(!b.is_sparse());
+        assert_eq!(a.count(), b.count());
+        assert_within(b.count(), 50_000);
+
+        assert!(Plus::decode(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode_sparse() {
+        let mut a = sketch_of(0..1_000);
+        assert!(a.is_sparse());
+        let buf = a.encode().unwrap();
+        assert_eq!(buf[0], 2);
+        assert_eq!(buf[1], 16);
+        assert_eq!(buf[2], 1);
+
+        let mut b = Plus::decode(buf.as_slice()).unwrap();
+        assert!(b.is_sparse());
+        assert_eq!(a.count(), b.count());
+
+        assert!(Plus::decode(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_sparse_matches_dense() {
+        for n in [10, 100, 1_000, 10_000] {
+            let mut sparse = sketch_of(0..n);
+            assert!(sparse.is_sparse());
+
+            let mut dense = sparse.clone();
+            dense.promote_to_dense();
+            assert_eq!(sparse.count(), dense.count());
+            assert_within(sparse.count(), n);
+        }
+    }
+
+    #[test]
+    fn test_sparse_promotion() {
+        let mut s = Plus::new().unwrap();
+        let threshold = s.sparse_threshold();
+
+        let mut i = 0;
+        while s.is_sparse() {
+            s.add(format!("cpu,host=server-{}", i).as_bytes());
+            i += 1;
+        }
+
+        match &s.registers {
+            super::Registers::Dense(registers) => {
+                let touched = registers.iter().filter(|r| **r != 0).count();
+                assert_eq!(touched, threshold);
+            }
+            super::Registers::Sparse(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_count_accuracy() {
+        let mut s = sketch_of(0..100_000);
+        assert!(!s.is_sparse());
+        assert_within(s.count(), 100_000);
+    }
+
+    #[test]
+    fn test_encode_decode_merge() {
+        let a = sketch_of(0..1_000);
+        let b = sketch_of(500..100_000);
+
+        let mut merged = Plus::decode(a.encode().unwrap().as_slice()).unwrap();
+        merged
+            .merge(&Plus::decode(b.encode().unwrap().as_slice()).unwrap())
+            .unwrap();
+        assert!(!merged.is_sparse());
+        assert_within(merged.count(), 100_000);
+
+        let mut expected = a.clone();
+        expected.merge(&b).unwrap();
+        assert_eq!(merged.encode().unwrap(), expected.encode().unwrap());
+    }
+
+    #[test]
+    fn test_decode_influxd_sparse() {
+        // register 5 from the set and the list, register 7 from the list.
+        let buf = [
+            2, 16, 1, // version, precision, sparse
+            0, 0, 0, 1, 0, 5, 0, 3, // set: 1 hash
+            0, 0, 0, 2, 0, 7, 0, 7, // list: count, last
+            0, 0, 0, 5, 0x82, 0x28, 0x85, 0xd8, 0x1b, // list: varint deltas
+        ];
+
+        let mut s = Plus::decode(buf.as_slice()).unwrap();
+        assert!(s.is_sparse());
+        assert_eq!(s.count(), 2);
+
+        s.promote_to_dense();
+        match &s.registers {
+            super::Registers::Dense(registers) => {
+                assert_eq!(registers[5], 10);
+                assert_eq!(registers[7], 12);
+                assert_eq!(registers.iter().filter(|r| **r != 0).count(), 2);
+            }
+            super::Registers::Sparse(_) => unreachable!(),
+        }
+
+        // the list count does not match the deltas
+        let mut corrupt = buf;
+        corrupt[14] = 3;
+        assert!(Plus::decode(corrupt.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let buf = sketch_of(0..100).encode().unwrap();
+
+        let mut version = buf.clone();
+        version[0] = 1;
+        assert!(Plus::decode(version.as_slice()).is_err());
+
+        let mut precision = buf.clone();
+        precision[1] = 19;
+        assert!(Plus::decode(precision.as_slice()).is_err());
+
+        let mut flag = buf.clone();
+        flag[2] = 2;
+        assert!(Plus::decode(flag.as_slice()).is_err());
+
+        let mut trailing = buf;
+        trailing.push(0);
+        assert!(Plus::decode(trailing.as_slice()).is_err());
     }
 }
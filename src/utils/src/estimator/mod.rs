@@ -5,11 +5,18 @@ pub trait Sketch {
     /// Add adds a single value to the sketch.
     fn add(&mut self, v: &[u8]);
 
-    /// Count returns a cardinality estimate for the sketch.
+    /// Count returns a cardinality estimate for the sketch. It takes `&mut self`
+    /// so implementations may cache the estimate between calls.
     fn count(&mut self) -> u64;
 
     /// Merge merges another sketch into this one.
     fn merge(&mut self, s: &Self) -> anyhow::Result<()>;
 
+    /// Encode encodes the sketch into a stable binary representation.
     fn encode(&self) -> anyhow::Result<Vec<u8>>;
+
+    /// Decode decodes a sketch previously produced by `encode`.
+    fn decode(buf: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized;
 }